@@ -1,4 +1,4 @@
-use std::env;
+use std::{env, fs, path::Path, process::Command};
 
 fn main() {
     // Export defaults as compile time environment variables.
@@ -10,4 +10,72 @@ fn main() {
             println!("cargo:rustc-env={variable}={val}");
         }
     }
+
+    // Describe the build, for `sozu status --verbose`. Package managers building
+    // outside of a git repository can set SOZU_GIT_COMMIT themselves.
+    println!("cargo:rerun-if-env-changed=SOZU_GIT_COMMIT");
+    for git_path in ["../.git/HEAD", "../.git/refs/heads"] {
+        if Path::new(git_path).exists() {
+            println!("cargo:rerun-if-changed={git_path}");
+        }
+    }
+    let commit = env::var("SOZU_GIT_COMMIT").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout)
+            .ok()
+            .map(|commit| commit.trim().to_owned())
+    });
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=SOZU_GIT_COMMIT={commit}");
+    }
+
+    // the version of rustls picked by cargo is only written in the lock file
+    println!("cargo:rerun-if-changed=../Cargo.lock");
+    if let Some(rustls_version) = fs::read_to_string("../Cargo.lock")
+        .ok()
+        .and_then(|lock| rustls_version(&lock))
+    {
+        println!("cargo:rustc-env=SOZU_RUSTLS_VERSION={rustls_version}");
+    }
+
+    // the messages are the ones of sozu-command-lib, only the service is generated
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .extern_path(".command", "::sozu_command_lib::proto::command")
+        .compile(&["command.proto"], &["../command/src"])
+        .expect("Could not compile the gRPC service in command.proto");
+}
+
+/// version of the rustls package used by sozu-lib. The lock file names the version
+/// in the dependencies of sozu-lib only when several versions of rustls are locked
+fn rustls_version(lock: &str) -> Option<String> {
+    let packages: Vec<&str> = lock.split("[[package]]").collect();
+    let field = |package: &str, name: &str| {
+        package
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{name} = \"")))
+            .map(|value| value.trim_end_matches('"').to_owned())
+    };
+
+    let sozu_lib = packages
+        .iter()
+        .find(|package| field(package, "name").as_deref() == Some("sozu-lib"))?;
+    let dependency = sozu_lib
+        .lines()
+        .map(|line| line.trim().trim_end_matches(',').trim_matches('"'))
+        .find(|dependency| *dependency == "rustls" || dependency.starts_with("rustls "))?;
+    if let Some(version) = dependency.strip_prefix("rustls ") {
+        return Some(version.split(' ').next()?.to_owned());
+    }
+    packages
+        .iter()
+        .find(|package| field(package, "name").as_deref() == Some("rustls"))
+        .and_then(|package| field(package, "version"))
 }
@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    process::Command,
+    time::Duration,
+};
+
+use libc::pid_t;
+use mio::{
+    net::{UnixListener, UnixStream},
+    Events, Interest, Poll, Token,
+};
+use nix::{
+    errno::Errno,
+    unistd::{fork, setgid, setuid, ForkResult, Gid, Group, Uid, User},
+};
+
+use sozu_command_lib::{
+    channel::{Channel, ChannelError},
+    config::Config,
+    proto::command::{BrokerRequest, BrokerResponse, Request, Response, ServerConfig},
+    ready::Ready,
+};
+
+use sozu_lib::embedded::setup_worker_logging;
+
+use crate::{
+    command::sessions::{extract_messages, wants_to_tick},
+    util::{self, UtilError},
+};
+
+const MAIN_TOKEN: Token = Token(0);
+const LISTENER_TOKEN: Token = Token(1);
+const FIRST_CLIENT_ID: u32 = 2;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BrokerError {
+    #[error("could not read on the channel")]
+    ReadChannel(ChannelError),
+    #[error("could not create MIO pair of unix stream: {0}")]
+    CreateUnixStream(IoError),
+    #[error("could not disable cloexec on the broker-to-main channel: {0}")]
+    DisableCloexec(UtilError),
+    #[error("could not send config to the broker: {0}")]
+    SendConfig(ChannelError),
+    #[error("unix fork failed: {0}")]
+    Fork(Errno),
+    #[error("Could not set the broker-to-main channel to {state}: {channel_err}")]
+    SetChannel {
+        state: String,
+        channel_err: ChannelError,
+    },
+    #[error("unknown user {0}")]
+    UnknownUser(String),
+    #[error("unknown group {0}")]
+    UnknownGroup(String),
+    #[error("could not drop the privileges of the broker: {0}")]
+    DropPrivileges(Errno),
+    #[error("could not create Poll with MIO: {0}")]
+    CreatePoll(IoError),
+    #[error("could not register in the MIO registry: {0}")]
+    Register(IoError),
+}
+
+/// unix-forks the main process into the command broker
+///
+/// - Parent: sends the config to the broker
+/// - Child: calls the sozu executable path like so: `sozu broker --fd <fd> [...]`,
+///   with the command socket as only other file descriptor
+///
+/// returns the child process pid, and a channel to talk to it.
+pub fn fork_main_into_broker(
+    config: &Config,
+    executable_path: String,
+    command_socket_fd: RawFd,
+) -> Result<(pid_t, Channel<BrokerResponse, BrokerRequest>), BrokerError> {
+    let broker_config = config.command_broker.clone().unwrap_or_default();
+    // fail in the main process rather than in a broker relaunched over and over
+    if let Some(user) = &broker_config.user {
+        find_user(user)?;
+    }
+    if let Some(group) = &broker_config.group {
+        find_group(group)?;
+    }
+
+    let (main_to_broker, broker_to_main) =
+        UnixStream::pair().map_err(BrokerError::CreateUnixStream)?;
+    util::disable_close_on_exec(broker_to_main.as_raw_fd()).map_err(BrokerError::DisableCloexec)?;
+
+    let server_config = ServerConfig::from(config);
+
+    // the responses relayed to the clients can be as large as the whole state
+    let mut main_to_broker_channel: Channel<ServerConfig, BrokerRequest> =
+        Channel::new(main_to_broker, config.command_buffer_size, u64::MAX);
+
+    if let Err(e) = main_to_broker_channel.blocking() {
+        error!("Could not block the main-to-broker channel: {}", e);
+    }
+
+    info!("launching the command broker");
+    debug!("executable path is {}", executable_path);
+
+    match unsafe { fork().map_err(BrokerError::Fork)? } {
+        ForkResult::Parent { child: broker_pid } => {
+            info!("launching the command broker with pid {}", broker_pid);
+            main_to_broker_channel
+                .write_message(&server_config)
+                .map_err(BrokerError::SendConfig)?;
+
+            main_to_broker_channel
+                .nonblocking()
+                .map_err(|channel_err| BrokerError::SetChannel {
+                    state: "nonblocking".to_string(),
+                    channel_err,
+                })?;
+
+            Ok((broker_pid.into(), main_to_broker_channel.into()))
+        }
+        ForkResult::Child => {
+            trace!("child({}):\twill spawn a child", unsafe { libc::getpid() });
+            // only the broker inherits the command socket, not the workers
+            if let Err(e) = util::disable_close_on_exec(command_socket_fd) {
+                error!("could not disable cloexec on the command socket: {}", e);
+            }
+
+            let mut command = Command::new(executable_path);
+            command
+                .arg("broker")
+                .arg("--fd")
+                .arg(broker_to_main.as_raw_fd().to_string())
+                .arg("--command-socket-fd")
+                .arg(command_socket_fd.to_string())
+                .arg("--command-buffer-size")
+                .arg(config.command_buffer_size.to_string());
+            if let Some(user) = broker_config.user {
+                command.arg("--user").arg(user);
+            }
+            if let Some(group) = broker_config.group {
+                command.arg("--group").arg(group);
+            }
+            command.exec();
+
+            unreachable!();
+        }
+    }
+}
+
+/// called within the broker process, this accepts the clients of the command socket
+/// and relays their requests to the main process
+pub fn begin_broker_process(
+    broker_to_main_channel_fd: i32,
+    command_socket_fd: i32,
+    command_buffer_size: u64,
+    user: Option<String>,
+    group: Option<String>,
+) -> Result<(), BrokerError> {
+    // the broker must not reach the workers through a descriptor of the main process
+    close_inherited_fds(&[broker_to_main_channel_fd, command_socket_fd]);
+
+    let mut broker_to_main_channel: Channel<BrokerRequest, ServerConfig> = Channel::new(
+        unsafe { UnixStream::from_raw_fd(broker_to_main_channel_fd) },
+        command_buffer_size,
+        u64::MAX,
+    );
+
+    broker_to_main_channel
+        .blocking()
+        .map_err(|channel_err| BrokerError::SetChannel {
+            state: "blocking".to_string(),
+            channel_err,
+        })?;
+
+    let server_config = broker_to_main_channel
+        .read_message()
+        .map_err(BrokerError::ReadChannel)?;
+
+    // do not try to log anything before this, or the logger will panic
+    setup_worker_logging(&server_config, "BRK");
+
+    drop_privileges(user.as_deref(), group.as_deref())?;
+    info!("command broker starting...");
+
+    broker_to_main_channel
+        .nonblocking()
+        .map_err(|channel_err| BrokerError::SetChannel {
+            state: "nonblocking".to_string(),
+            channel_err,
+        })?;
+
+    let listener = unsafe { UnixListener::from_raw_fd(command_socket_fd) };
+    let mut broker = Broker::new(broker_to_main_channel.into(), listener)?;
+
+    info!("starting event loop");
+    broker.run()?;
+    info!("ending event loop");
+    Ok(())
+}
+
+/// Accepts the clients of the command socket, and relays their requests to the main
+/// process, and the responses of the main process to them
+struct Broker {
+    poll: Poll,
+    main: Channel<BrokerRequest, BrokerResponse>,
+    listener: UnixListener,
+    clients: HashMap<Token, Channel<Response, Request>>,
+    next_client_id: u32,
+}
+
+impl Broker {
+    fn new(
+        mut main: Channel<BrokerRequest, BrokerResponse>,
+        mut listener: UnixListener,
+    ) -> Result<Self, BrokerError> {
+        let poll = Poll::new().map_err(BrokerError::CreatePoll)?;
+        poll.registry()
+            .register(
+                &mut main.sock,
+                MAIN_TOKEN,
+                Interest::READABLE | Interest::WRITABLE,
+            )
+            .map_err(BrokerError::Register)?;
+        poll.registry()
+            .register(&mut listener, LISTENER_TOKEN, Interest::READABLE)
+            .map_err(BrokerError::Register)?;
+        main.interest = Ready::READABLE | Ready::ERROR | Ready::HUP;
+
+        Ok(Self {
+            poll,
+            main,
+            listener,
+            clients: HashMap::new(),
+            next_client_id: FIRST_CLIENT_ID,
+        })
+    }
+
+    /// runs until the main process closes its channel
+    fn run(&mut self) -> Result<(), BrokerError> {
+        let mut events = Events::with_capacity(100);
+
+        loop {
+            let to_tick = self
+                .clients
+                .iter()
+                .filter(|(_, channel)| wants_to_tick(channel))
+                .map(|(token, _)| *token)
+                .chain(wants_to_tick(&self.main).then_some(MAIN_TOKEN))
+                .collect::<Vec<_>>();
+
+            let timeout = if to_tick.is_empty() {
+                None
+            } else {
+                Some(Duration::default())
+            };
+
+            events.clear();
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => error!("Error while polling: {:?}", error),
+            }
+
+            let events = to_tick
+                .into_iter()
+                .map(|token| (token, Ready::EMPTY))
+                .chain(
+                    events
+                        .iter()
+                        .map(|event| (event.token(), Ready::from(event))),
+                )
+                .collect::<Vec<_>>();
+
+            for (token, ready) in events {
+                match token {
+                    LISTENER_TOKEN => self.accept_clients(),
+                    MAIN_TOKEN => {
+                        if !self.relay_responses(ready) {
+                            info!("the main process closed its channel, stopping the broker");
+                            return Ok(());
+                        }
+                    }
+                    token => self.relay_requests(token, ready),
+                }
+            }
+        }
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, _addr)) => {
+                    let token = Token(self.next_client_id as usize);
+                    self.next_client_id = self
+                        .next_client_id
+                        .checked_add(1)
+                        .unwrap_or(FIRST_CLIENT_ID);
+
+                    if let Err(e) = self.poll.registry().register(
+                        &mut stream,
+                        token,
+                        Interest::READABLE | Interest::WRITABLE,
+                    ) {
+                        error!("could not register client: {}", e);
+                        continue;
+                    }
+                    let mut channel = Channel::new(stream, 4096, u64::MAX);
+                    channel.interest = Ready::READABLE | Ready::ERROR | Ready::HUP;
+                    debug!("accepted client {}", token.0);
+                    self.clients.insert(token, channel);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("could not accept client: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// returns false if the main process closed its channel
+    fn relay_responses(&mut self, ready: Ready) -> bool {
+        self.main.handle_events(ready);
+        if self.main.readiness.is_error() || self.main.readiness.is_hup() {
+            return false;
+        }
+
+        let status = self.main.writable();
+        trace!("main channel writable: {:?}", status);
+
+        for BrokerResponse {
+            client_id,
+            response,
+        } in extract_messages(&mut self.main)
+        {
+            let token = Token(client_id as usize);
+            let Some(client) = self.clients.get_mut(&token) else {
+                debug!("client {} is gone, dropping its response", client_id);
+                continue;
+            };
+            match response {
+                Some(response) => {
+                    if let Err(e) = client.write_message(&response) {
+                        error!(
+                            "could not write on the channel of client {}: {}",
+                            client_id, e
+                        );
+                        self.close_client(token);
+                        continue;
+                    }
+                    client.interest.insert(Ready::WRITABLE);
+                }
+                None => self.close_client(token),
+            }
+        }
+        true
+    }
+
+    fn relay_requests(&mut self, token: Token, ready: Ready) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+        client.handle_events(ready);
+        if client.readiness.is_error() || client.readiness.is_hup() {
+            self.close_client(token);
+            return;
+        }
+
+        let status = client.writable();
+        trace!("client writable: {:?}", status);
+
+        for request in extract_messages(client) {
+            self.send_to_main(BrokerRequest {
+                client_id: token.0 as u32,
+                request: Some(request),
+            });
+        }
+    }
+
+    /// forget the client, and tell the main process it left
+    fn close_client(&mut self, token: Token) {
+        if let Some(mut channel) = self.clients.remove(&token) {
+            debug!("closing client {}", token.0);
+            if let Err(e) = self.poll.registry().deregister(&mut channel.sock) {
+                error!("could not deregister client {}: {}", token.0, e);
+            }
+            self.send_to_main(BrokerRequest {
+                client_id: token.0 as u32,
+                request: None,
+            });
+        }
+    }
+
+    fn send_to_main(&mut self, request: BrokerRequest) {
+        if let Err(e) = self.main.write_message(&request) {
+            error!("could not write on the channel of the main process: {}", e);
+            return;
+        }
+        self.main.interest.insert(Ready::WRITABLE);
+    }
+}
+
+fn find_user(name: &str) -> Result<User, BrokerError> {
+    User::from_name(name)
+        .ok()
+        .flatten()
+        .ok_or_else(|| BrokerError::UnknownUser(name.to_owned()))
+}
+
+fn find_group(name: &str) -> Result<Group, BrokerError> {
+    Group::from_name(name)
+        .ok()
+        .flatten()
+        .ok_or_else(|| BrokerError::UnknownGroup(name.to_owned()))
+}
+
+/// switch to the configured user and group, then forbid regaining privileges
+fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), BrokerError> {
+    let user = user.map(find_user).transpose()?;
+    let gid: Option<Gid> = match group {
+        Some(group) => Some(find_group(group)?.gid),
+        None => user.as_ref().map(|user| user.gid),
+    };
+
+    if let Some(gid) = gid {
+        #[cfg(not(target_os = "macos"))]
+        nix::unistd::setgroups(&[gid]).map_err(BrokerError::DropPrivileges)?;
+        setgid(gid).map_err(BrokerError::DropPrivileges)?;
+    }
+    if let Some(user) = user {
+        setuid(user.uid).map_err(BrokerError::DropPrivileges)?;
+    }
+    if Uid::effective().is_root() {
+        warn!("the command broker runs as root, set command_broker.user to drop privileges");
+    }
+
+    #[cfg(target_os = "linux")]
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(BrokerError::DropPrivileges(Errno::last()));
+    }
+    Ok(())
+}
+
+/// close the file descriptors inherited from the main process, except the standard ones
+fn close_inherited_fds(keep: &[RawFd]) {
+    let Ok(entries) = std::fs::read_dir("/dev/fd") else {
+        return;
+    };
+    // list them all first, the directory is itself an open file descriptor
+    let fds = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .collect::<Vec<_>>();
+    for fd in fds {
+        if fd > 2 && !keep.contains(&fd) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
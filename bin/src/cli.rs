@@ -1,9 +1,18 @@
-use std::{collections::BTreeMap, net::SocketAddr};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use clap::{Parser, Subcommand};
 
 use sozu_command_lib::{
-    proto::command::{LoadBalancingAlgorithms, TlsVersion},
+    config::is_valid_listener_name,
+    proto::command::{
+        DeviceClass, DuplicateHeaderPolicy, ExpectedClusterHash, HashKey, HealthOverride,
+        LoadBalancingAlgorithms, LoadMetric, PipelineStep, ProxyStatusHeader, TlsVersion,
+        WeightedCluster,
+    },
     state::ClusterId as StateClusterId,
 };
 
@@ -21,9 +30,15 @@ pub struct Args {
         short = 't',
         long = "timeout",
         global = true,
-        help = "Sets a custom timeout for commands (in milliseconds). 0 disables the timeout"
+        help = "Sets a custom timeout for commands (in milliseconds), including the time spent waiting for the main process with --wait. 0 disables the timeout"
     )]
     pub timeout: Option<u64>,
+    #[clap(
+        long = "wait",
+        global = true,
+        help = "retry connecting to the main process until it is up, or until the timeout. With 'backend drain', also wait for the connections of the backend to close"
+    )]
+    pub wait: bool,
     #[clap(
         short = 'j',
         long = "json",
@@ -31,6 +46,25 @@ pub struct Args {
         help = "display responses to queries in a JSON format"
     )]
     pub json: bool,
+    #[clap(
+        long = "dry-run",
+        global = true,
+        help = "validate a state-changing request against the state of the main process and show what it would change, without applying it"
+    )]
+    pub dry_run: bool,
+    #[clap(
+        long = "if-epoch",
+        global = true,
+        help = "apply the request only if the state is still at this epoch (see `state changes`), fail with a conflict otherwise"
+    )]
+    pub if_epoch: Option<u64>,
+    #[clap(
+        long = "if-cluster-hash",
+        global = true,
+        help = "apply the request only if the hash of the cluster did not change (see `query clusters`), as CLUSTER_ID=HASH, or CLUSTER_ID= if the cluster should not exist",
+        value_parser = parse_expected_cluster_hash
+    )]
+    pub if_cluster_hash: Option<ExpectedClusterHash>,
     #[clap(subcommand)]
     pub cmd: SubCmd,
 }
@@ -102,8 +136,46 @@ pub enum SubCmd {
         )]
         max_command_buffer_size: Option<u64>,
     },
+    #[clap(
+        name = "broker",
+        about = "start the command broker (internal command, should not be used directly)"
+    )]
+    Broker {
+        #[clap(
+            long = "fd",
+            help = "IPC file descriptor of the broker to main channel"
+        )]
+        fd: i32,
+        #[clap(
+            long = "command-socket-fd",
+            help = "file descriptor of the command socket"
+        )]
+        command_socket_fd: i32,
+        #[clap(
+            long = "command-buffer-size",
+            help = "Broker's channel buffer size",
+            default_value = "1000000"
+        )]
+        command_buffer_size: u64,
+        #[clap(long = "user", help = "user the broker switches to")]
+        user: Option<String>,
+        #[clap(long = "group", help = "group the broker switches to")]
+        group: Option<String>,
+    },
+    #[clap(
+        name = "validate-upgrade",
+        about = "check that this executable can take over the state of the running main process, given on the standard input (internal command, should not be used directly)"
+    )]
+    ValidateUpgrade,
 
     // sozu command line
+    #[clap(
+        name = "check",
+        about = "validate the configuration file, its certificates, keys, addresses, ciphers and timeouts, \
+        and report all the errors. Exits with 0 if it is valid, 1 if it has errors, 2 if it can not be parsed. \
+        Does not need a running Sōzu"
+    )]
+    Check,
     #[clap(name = "shutdown", about = "shuts down the proxy")]
     Shutdown {
         #[clap(long = "hard", help = "do not wait for connections to finish")]
@@ -119,7 +191,14 @@ pub enum SubCmd {
     },
 
     #[clap(name = "status", about = "gets information on the running workers")]
-    Status,
+    Status {
+        #[clap(
+            short = 'v',
+            long = "verbose",
+            help = "also show the version and build of the main process and of each worker"
+        )]
+        verbose: bool,
+    },
     #[clap(
         name = "metrics",
         about = "gets statistics on the main process and its workers"
@@ -128,10 +207,20 @@ pub enum SubCmd {
         #[clap(subcommand)]
         cmd: MetricsCmd,
     },
-    #[clap(name = "logging", about = "change logging level")]
+    #[clap(
+        name = "logging",
+        about = "change the logging filter and target at runtime",
+        args_conflicts_with_subcommands = true,
+        arg_required_else_help = true
+    )]
     Logging {
-        #[clap(name = "filter")]
-        filter: String,
+        #[clap(
+            name = "filter",
+            help = "logging filter of the main process and all workers, like \"info\" or \"info,sozu_lib::https=debug\""
+        )]
+        filter: Option<String>,
+        #[clap(subcommand)]
+        cmd: Option<LoggingCmd>,
     },
     #[clap(name = "state", about = "state management")]
     State {
@@ -180,8 +269,217 @@ pub enum SubCmd {
         #[clap(subcommand)]
         cmd: ConfigCmd,
     },
-    #[clap(name = "events", about = "receive sozu events about the status of backends")]
-    Events,
+    #[clap(
+        name = "events",
+        about = "receive sozu events about the status of backends, or list past events"
+    )]
+    Events {
+        #[clap(subcommand)]
+        cmd: Option<EventsCmd>,
+    },
+    #[clap(
+        name = "schedule",
+        about = "batches of requests applied by the main process at a given time"
+    )]
+    Schedule {
+        #[clap(subcommand)]
+        cmd: ScheduleCmd,
+    },
+    #[clap(name = "debug", about = "debugging tools")]
+    Debug {
+        #[clap(subcommand)]
+        cmd: DebugCmd,
+    },
+    #[clap(
+        name = "signing-key",
+        about = "keys signing the sticky session cookies"
+    )]
+    SigningKey {
+        #[clap(subcommand)]
+        cmd: SigningKeyCmd,
+    },
+    #[clap(
+        name = "completion",
+        about = "print a shell completion script, completing cluster ids, backend ids and addresses with the ones of the running Sōzu"
+    )]
+    Completion {
+        #[clap(help = "bash, zsh or fish", value_parser = parse_shell)]
+        shell: Shell,
+    },
+    #[clap(
+        name = "__complete",
+        hide = true,
+        about = "list the completions of a command line (used by the completion scripts)"
+    )]
+    Complete {
+        #[clap(
+            allow_hyphen_values = true,
+            trailing_var_arg = true,
+            help = "words of the command line after the executable, the last one is completed"
+        )]
+        words: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum EventsCmd {
+    #[clap(name = "watch", about = "receive events as they happen (default)")]
+    Watch,
+    #[clap(
+        name = "list",
+        about = "list the recent events kept by the main process"
+    )]
+    List {
+        #[clap(
+            long = "since",
+            help = "only list events younger than this duration (example: 30m, 1h)",
+            value_parser = parse_duration
+        )]
+        since: Option<Duration>,
+        #[clap(long = "cluster", help = "only list events of this cluster")]
+        cluster_id: Option<String>,
+        #[clap(long = "backend", help = "only list events of this backend")]
+        backend_id: Option<String>,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum SigningKeyCmd {
+    #[clap(
+        name = "rotate",
+        about = "sign with a new key, the previous one is still accepted until the next rotation"
+    )]
+    Rotate {
+        #[clap(
+            long = "secret-file",
+            help = "file holding the secret of the new key, generated by the main process if not set"
+        )]
+        secret_file: Option<String>,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum ScheduleCmd {
+    #[clap(name = "add", about = "schedule a batch of requests")]
+    Add {
+        #[clap(short = 'i', long = "id", help = "identifier of the scheduled change")]
+        id: String,
+        #[clap(
+            long = "at",
+            help = "when to apply the requests: a unix timestamp, or HH:MM for the next occurrence of this time (UTC)",
+            value_parser = parse_schedule_date
+        )]
+        at: Option<u64>,
+        #[clap(
+            long = "in",
+            help = "apply the requests after this duration (example: 30m, 2h)",
+            value_parser = parse_duration,
+            conflicts_with = "at"
+        )]
+        delay: Option<Duration>,
+        #[clap(
+            long = "every",
+            help = "apply the requests again at this interval (example: 24h)",
+            value_parser = parse_duration
+        )]
+        every: Option<Duration>,
+        #[clap(
+            short = 'f',
+            long = "file",
+            help = "JSON file containing an array of requests, in the format of saved states"
+        )]
+        file: String,
+    },
+    #[clap(name = "remove", about = "remove a scheduled change")]
+    Remove {
+        #[clap(short = 'i', long = "id")]
+        id: String,
+    },
+    #[clap(name = "list", about = "list the scheduled changes")]
+    List,
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum DebugCmd {
+    #[clap(
+        name = "capture",
+        about = "record the metadata of the requests of a cluster on the workers (timings, status, backend, headers, no bodies) and write it to a JSON file"
+    )]
+    Capture {
+        #[clap(long = "cluster", help = "cluster whose requests are recorded")]
+        cluster_id: String,
+        #[clap(
+            long = "duration",
+            help = "how long to record the requests (example: 30s, 5m)",
+            default_value = "30s",
+            value_parser = parse_duration
+        )]
+        duration: Duration,
+        #[clap(
+            long = "header",
+            help = "name of a request or response header to record, can be repeated"
+        )]
+        headers: Vec<String>,
+        #[clap(
+            long = "max-requests",
+            help = "maximum number of requests recorded by each worker (default: 10000)"
+        )]
+        max_requests: Option<u32>,
+        #[clap(short = 'f', long = "file", help = "JSON file to write the capture to")]
+        file: String,
+    },
+    #[clap(
+        name = "audit",
+        about = "check the session slab, the connection count and the timer of the workers for entries left behind by closed sessions"
+    )]
+    Audit {
+        #[clap(
+            long = "worker",
+            help = "id of the worker to audit, all workers if not set"
+        )]
+        worker_id: Option<u32>,
+        #[clap(
+            long = "reclaim",
+            help = "remove the orphans found and correct the connection count"
+        )]
+        reclaim: bool,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum LoggingCmd {
+    #[clap(
+        name = "set",
+        about = "change the logging filter and/or the log target, without restarting"
+    )]
+    Set {
+        #[clap(
+            long = "level",
+            help = "logging filter, like \"debug\" or \"info,sozu_lib::https=debug\""
+        )]
+        level: Option<String>,
+        #[clap(
+            long = "target",
+            help = "log target: stdout, udp://<address>, tcp://<address>, unix://<path> or file://<path>"
+        )]
+        target: Option<String>,
+        #[clap(
+            long = "worker",
+            help = "id of the worker to change, the main process and all workers if not set"
+        )]
+        worker_id: Option<u32>,
+    },
+    #[clap(
+        name = "reset",
+        about = "revert the logging filter and the log target to the values of the configuration file"
+    )]
+    Reset {
+        #[clap(
+            long = "worker",
+            help = "id of the worker to reset, the main process and all workers if not set"
+        )]
+        worker_id: Option<u32>,
+    },
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
@@ -248,8 +546,80 @@ pub enum StateCmd {
         about = "show the counts of requests that were received since startup"
     )]
     Stats,
+    #[clap(
+        name = "changes",
+        about = "list the state changes applied after an epoch, or the whole state if some of them are no longer kept"
+    )]
+    Changes {
+        #[clap(
+            long = "since-epoch",
+            default_value_t = 0,
+            help = "epoch of the last change already known"
+        )]
+        since_epoch: u64,
+    },
+    #[clap(
+        name = "query",
+        about = "query the state with a SQL like language, like: SELECT hostname, cluster FROM frontends WHERE tag.team = 'payments'"
+    )]
+    Query {
+        #[clap(
+            help = "tables: frontends, clusters, backends, listeners, certificates. See doc/configure_cli.md for their columns"
+        )]
+        query: String,
+    },
+    #[clap(
+        name = "export",
+        about = "export the state as declarative resources, to compare it with an infrastructure-as-code repository, or whole, to back it up"
+    )]
+    Export {
+        #[clap(
+            long = "format",
+            default_value = "terraform",
+            help = "terraform for the Terraform language, terraform-json for its JSON syntax, json or pb (protobuf) for the whole state, private keys included",
+            value_parser = parse_export_format
+        )]
+        format: ExportFormat,
+        #[clap(
+            short = 'o',
+            long = "output",
+            short_alias = 'f',
+            visible_alias = "file",
+            help = "where to write the export, defaults to stdout"
+        )]
+        output: Option<String>,
+    },
+    #[clap(
+        name = "import",
+        about = "restore a state written by export in json or pb, applying only the requests that differ. With --dry-run, only list them"
+    )]
+    Import {
+        #[clap(short = 'f', long = "file", help = "the exported state")]
+        file: String,
+        #[clap(
+            long = "format",
+            default_value = "json",
+            help = "json or pb, the format of the export",
+            value_parser = parse_export_format
+        )]
+        format: ExportFormat,
+    },
+    #[clap(
+        name = "apply",
+        about = "converge to the state serialized in a JSON file, applying only the requests that differ. With --dry-run, only list them"
+    )]
+    Apply {
+        #[clap(
+            short = 'f',
+            long = "file",
+            help = "JSON serialization of the desired state"
+        )]
+        file: String,
+    },
 }
 
+// parsed once from the command line, the size of the variants does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum ClusterCmd {
     #[clap(
@@ -262,6 +632,14 @@ pub enum ClusterCmd {
         #[clap(short = 'd', long = "domain", help = "cluster domain name")]
         domain: Option<String>,
     },
+    #[clap(
+        name = "inspect",
+        about = "Show a cluster as seen by each worker, with the connections still open on its removed backends"
+    )]
+    Inspect {
+        #[clap(short = 'i', long = "id", help = "cluster id")]
+        id: String,
+    },
     #[clap(name = "remove", about = "Remove a cluster")]
     Remove {
         #[clap(short = 'i', long = "id", help = "cluster id")]
@@ -287,9 +665,296 @@ pub enum ClusterCmd {
         expect_proxy: bool,
         #[clap(
             long = "load-balancing-policy",
-            help = "Configures the load balancing policy. Possible values are 'roundrobin', 'random' or 'leastconnections'"
+            help = "Configures the load balancing policy. Possible values are 'round_robin', 'random', 'least_loaded', 'power_of_two', 'peak_ewma' or 'consistent_hash'"
         )]
         load_balancing_policy: LoadBalancingAlgorithms,
+        #[clap(
+            long = "hash-key",
+            help = "value of the requests hashed by the consistent_hash policy: 'source_ip' (the default), 'header:<name>' or 'cookie:<name>'"
+        )]
+        hash_key: Option<HashKey>,
+        #[clap(
+            long = "source-address",
+            help = "local IP address used to connect to the backends of the cluster"
+        )]
+        source_address: Option<IpAddr>,
+        #[clap(
+            long = "transparent",
+            help = "connect to the backends from the IP address of the client (needs CAP_NET_ADMIN and policy routing)"
+        )]
+        transparent: bool,
+        #[clap(
+            long = "expires-in",
+            help = "remove the cluster, with its frontends and backends, after this duration (example: 30m, 72h, 7d)",
+            value_parser = parse_duration
+        )]
+        expires_in: Option<Duration>,
+        #[clap(
+            long = "max-request-header-size",
+            help = "maximum size in bytes of the request headers sent to the backends, once edited by the proxy. Larger requests are answered with a 413"
+        )]
+        max_request_header_size: Option<u32>,
+        #[clap(
+            long = "max-response-body-size",
+            help = "maximum size in bytes of the response bodies sent by the backends. Larger responses are answered with a 502, or cut if already forwarded, and counted in http.response_body_too_large"
+        )]
+        max_response_body_size: Option<u64>,
+        #[clap(
+            long = "response-flush-delay",
+            help = "delay in milliseconds the response bodies may be held to write them to the clients in fewer writes. Without it, they are written as soon as they are read"
+        )]
+        response_flush_delay: Option<u32>,
+        #[clap(
+            long = "filter-time-budget",
+            help = "time in microseconds the proxy may spend editing the headers and running the filters of a request, before counting it in http.budget.filter_time_exceeded"
+        )]
+        filter_time_budget: Option<u64>,
+        #[clap(
+            long = "slow-log-threshold",
+            help = "response time in milliseconds from which the requests of the cluster are written to the slow log, with the breakdown of their response time",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        slow_log_threshold: Option<u32>,
+        #[clap(
+            long = "slow-log-file",
+            help = "file the slow requests are appended to",
+            requires = "slow_log_threshold",
+            conflicts_with = "slow_log_socket"
+        )]
+        slow_log_file: Option<String>,
+        #[clap(
+            long = "slow-log-socket",
+            help = "unix datagram socket the slow requests are sent to, one request per datagram",
+            requires = "slow_log_threshold"
+        )]
+        slow_log_socket: Option<String>,
+        #[clap(
+            long = "slow-log-max-per-second",
+            help = "log at most this many slow requests per second and per worker (default: 10)",
+            requires = "slow_log_threshold",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        slow_log_max_per_second: Option<u32>,
+        #[clap(
+            long = "client-rate-limit",
+            help = "requests per second each client IP may send to the cluster, answered with a 429 beyond it",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        client_rate_limit: Option<u32>,
+        #[clap(
+            long = "client-rate-limit-burst",
+            help = "requests a client IP may send at once to the cluster (default: the requests per second)",
+            requires = "client_rate_limit",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        client_rate_limit_burst: Option<u32>,
+        #[clap(
+            long = "sticky-table",
+            help = "remember the backend chosen for each client IP and share it between workers, to keep clients on their backend without a sticky cookie"
+        )]
+        sticky_table: bool,
+        #[clap(
+            long = "srv-record",
+            help = "DNS SRV record listing the backends of the cluster, resolved periodically by the main process (example: _http._tcp.web.service.consul)"
+        )]
+        srv_record: Option<String>,
+        #[clap(
+            long = "dscp",
+            help = "DSCP value (0 to 63) of the packets sent to the backends, so that the network can prioritize the traffic of the cluster (example: 46 for expedited forwarding)",
+            value_parser = clap::value_parser!(u8).range(0..=63)
+        )]
+        dscp: Option<u8>,
+        #[clap(
+            long = "dscp-on-clients",
+            help = "also mark the packets sent to the clients of the cluster with the DSCP value",
+            requires = "dscp"
+        )]
+        dscp_on_clients: bool,
+        #[clap(
+            long = "outlier-detection",
+            help = "eject from load balancing, for a while, the backends answering with much more 5xx or timeouts than the rest of the cluster (with the default settings, see doc/configure.md)"
+        )]
+        outlier_detection: bool,
+        #[clap(
+            long = "health-check",
+            help = "probe the backends periodically from the main process, and remove from load balancing the ones failing the probes (with the default settings, see doc/configure.md)"
+        )]
+        health_check: bool,
+        #[clap(
+            long = "health-check-interval",
+            help = "time between two probes of a backend, in seconds (default: 10)",
+            requires = "health_check"
+        )]
+        health_check_interval: Option<u32>,
+        #[clap(
+            long = "health-check-timeout",
+            help = "time a probe may take, in seconds (default: 2)",
+            requires = "health_check"
+        )]
+        health_check_timeout: Option<u32>,
+        #[clap(
+            long = "health-check-path",
+            help = "path of an HTTP GET request the backends must answer with a 2xx or 3xx status. Without it, the probes only open a TCP connection",
+            requires = "health_check"
+        )]
+        health_check_path: Option<String>,
+        #[clap(
+            long = "healthy-threshold",
+            help = "successful probes in a row putting an unhealthy backend back in rotation (default: 2)",
+            requires = "health_check"
+        )]
+        healthy_threshold: Option<u32>,
+        #[clap(
+            long = "unhealthy-threshold",
+            help = "failed probes in a row removing a backend from rotation (default: 3)",
+            requires = "health_check"
+        )]
+        unhealthy_threshold: Option<u32>,
+        #[clap(
+            long = "enforce-https",
+            help = "redirect the requests received on HTTP listeners to HTTPS, and send a Strict-Transport-Security header on HTTPS responses, replacing the one of the backends"
+        )]
+        enforce_https: bool,
+        #[clap(
+            long = "hsts-max-age",
+            help = "max-age of the Strict-Transport-Security header, in seconds (default: one year)",
+            requires = "enforce_https"
+        )]
+        hsts_max_age: Option<u64>,
+        #[clap(
+            long = "hsts-include-subdomains",
+            help = "apply the Strict-Transport-Security header to the subdomains",
+            requires = "enforce_https"
+        )]
+        hsts_include_subdomains: bool,
+        #[clap(
+            long = "hsts-preload",
+            help = "ask for the domain to be included in the HSTS preload lists of browsers (requires --hsts-include-subdomains and a max-age of at least one year)",
+            requires = "enforce_https"
+        )]
+        hsts_preload: bool,
+        #[clap(
+            long = "body-read-timeout",
+            help = "maximum time of inactivity of the client while the request body is received, in seconds. Overrides the listeners, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        body_read_timeout: Option<u32>,
+        #[clap(
+            long = "backend-connect-timeout",
+            help = "maximum time to connect to a backend, in seconds. Overrides the listeners, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        backend_connect_timeout: Option<u32>,
+        #[clap(
+            long = "backend-response-timeout",
+            help = "maximum time of inactivity of the backend while the response is awaited and received, in seconds. Overrides the listeners, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        backend_response_timeout: Option<u32>,
+        #[clap(
+            long = "request-deadline",
+            help = "maximum time to answer a request once its headers are received, in seconds. Overrides the listeners, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        request_deadline: Option<u32>,
+    },
+    #[clap(
+        name = "healthcheck",
+        about = "Show the results of the active health checks of the backends, as probed by the main process"
+    )]
+    Healthcheck {
+        #[clap(short = 'i', long = "id", help = "only the backends of this cluster")]
+        id: Option<String>,
+    },
+    #[clap(
+        name = "pipeline",
+        about = "Set the ordered filters applied to the requests of a cluster"
+    )]
+    Pipeline {
+        #[clap(short = 'i', long = "id", help = "cluster id")]
+        id: String,
+        #[clap(
+            long = "step",
+            help = "filter step, as filter[:action] where filter is 'https_redirect', 'rate_limit', 'fault_injection', 'mirroring', 'header_edits' or 'backend_pinning', and action is 'stop' (default) or 'continue'. Steps run in the order given, the filters left out after them (example: --step https_redirect)",
+            required_unless_present = "reset",
+            conflicts_with = "reset"
+        )]
+        steps: Vec<PipelineStep>,
+        #[clap(
+            long = "reset",
+            help = "drop the explicit pipeline, going back to the default order of the filters"
+        )]
+        reset: bool,
+    },
+    #[clap(
+        name = "set-load-balancing",
+        about = "Change how the backends of a cluster are chosen, keeping its backends, their connections and the open sessions"
+    )]
+    SetLoadBalancing {
+        #[clap(short = 'i', long = "id", help = "cluster id")]
+        id: String,
+        #[clap(
+            long = "load-balancing-policy",
+            help = "load balancing algorithm: 'round_robin', 'random', 'least_loaded', 'power_of_two', 'peak_ewma' or 'consistent_hash'",
+            required_unless_present_any = ["load_metric", "sticky_session", "sticky_table", "hash_key"]
+        )]
+        load_balancing_policy: Option<LoadBalancingAlgorithms>,
+        #[clap(
+            long = "load-metric",
+            help = "metric compared by the least_loaded and power_of_two algorithms: 'connections', 'requests', 'connection_time' or 'response_time'",
+            value_parser = parse_load_metric
+        )]
+        load_metric: Option<LoadMetric>,
+        #[clap(
+            long = "sticky-session",
+            help = "send the clients with a sticky cookie back to their backend (true or false)"
+        )]
+        sticky_session: Option<bool>,
+        #[clap(
+            long = "sticky-table",
+            help = "remember the backend chosen for each client IP and share it between workers (true or false)"
+        )]
+        sticky_table: Option<bool>,
+        #[clap(
+            long = "hash-key",
+            help = "value of the requests hashed by the consistent_hash algorithm: 'source_ip', 'header:<name>' or 'cookie:<name>'"
+        )]
+        hash_key: Option<HashKey>,
+    },
+    #[clap(
+        name = "fault-injection",
+        about = "Delay or abort a share of the requests of a cluster, to run chaos experiments. The faults show in 'sozu cluster inspect'"
+    )]
+    FaultInjection {
+        #[clap(short = 'i', long = "id", help = "cluster id")]
+        id: String,
+        #[clap(
+            long = "delay",
+            help = "time a delayed request is held before it is sent to the backend, in milliseconds",
+            requires = "delay_percent",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        delay: Option<u32>,
+        #[clap(
+            long = "delay-percent",
+            help = "share of the requests delayed, between 0 and 100",
+            requires = "delay",
+            value_parser = clap::value_parser!(u32).range(0..=100)
+        )]
+        delay_percent: Option<u32>,
+        #[clap(
+            long = "abort-percent",
+            help = "share of the requests answered with a 503 without reaching a backend, between 0 and 100",
+            value_parser = clap::value_parser!(u32).range(0..=100)
+        )]
+        abort_percent: Option<u32>,
+        #[clap(
+            long = "disable",
+            help = "stop injecting faults in the requests of the cluster",
+            required_unless_present_any = ["delay", "abort_percent"],
+            conflicts_with_all = ["delay", "delay_percent", "abort_percent"]
+        )]
+        disable: bool,
     },
 }
 
@@ -328,6 +993,85 @@ pub enum BackendCmd {
         sticky_id: Option<String>,
         #[clap(short = 'b', long = "backup", help = "set backend as a backup backend")]
         backup: Option<bool>,
+        #[clap(
+            long = "expires-in",
+            help = "remove the backend after this duration (example: 30m, 72h, 7d)",
+            value_parser = parse_duration
+        )]
+        expires_in: Option<Duration>,
+        #[clap(
+            long = "slow-start-seconds",
+            help = "ramp the traffic sent to the backend up over this many seconds, when it is added or comes back up"
+        )]
+        slow_start_seconds: Option<u32>,
+    },
+    #[clap(
+        name = "replace",
+        about = "Replace all backends of a cluster at once, removing the ones not listed"
+    )]
+    Replace {
+        #[clap(short = 'i', long = "id")]
+        id: String,
+        #[clap(
+            short = 'b',
+            long = "backend",
+            help = "backend to keep or add, format: backend_id=IP:port (can be repeated)",
+            value_parser = parse_backend
+        )]
+        backends: Vec<(String, SocketAddr)>,
+    },
+    #[clap(
+        name = "set-weight",
+        about = "Change the load balancing weight of a backend in place, keeping its connections and sticky sessions"
+    )]
+    SetWeight {
+        #[clap(short = 'i', long = "id", alias = "cluster")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+        #[clap(
+            short = 'w',
+            long = "weight",
+            help = "new load balancing weight of the backend (backends added from the configuration file default to 100)",
+            value_parser = clap::value_parser!(i32).range(0..)
+        )]
+        weight: i32,
+    },
+    #[clap(
+        name = "drain",
+        about = "Stop routing new sessions to a backend at all its addresses, letting its open connections finish. With --wait, block until they are closed"
+    )]
+    Drain {
+        #[clap(short = 'i', long = "id", alias = "cluster")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+    },
+    #[clap(
+        name = "undrain",
+        about = "Route new sessions to a drained backend again"
+    )]
+    Undrain {
+        #[clap(short = 'i', long = "id", alias = "cluster")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+    },
+    #[clap(
+        name = "set-health",
+        about = "Force a backend in or out of rotation whatever its health checks say, or let them decide again"
+    )]
+    SetHealth {
+        #[clap(short = 'i', long = "id", alias = "cluster")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+        #[clap(
+            long = "state",
+            help = "down (out of rotation), up (in rotation) or auto (the health checks decide)",
+            value_parser = parse_health_override
+        )]
+        state: HealthOverride,
     },
 }
 
@@ -363,6 +1107,14 @@ pub enum FrontendCmd {
         )]
         domain: Option<String>,
     },
+    #[clap(
+        name = "timeouts",
+        about = "Show the timeouts applied to the requests of each HTTP and HTTPS frontend, and whether the listener, the cluster or the frontend sets them"
+    )]
+    Timeouts {
+        #[clap(short = 'd', long = "domain", help = "filter by domain name")]
+        domain: Option<String>,
+    },
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
@@ -386,6 +1138,7 @@ impl std::convert::Into<Option<StateClusterId>> for ClusterId {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum HttpFrontendCmd {
     #[clap(name = "add")]
@@ -393,9 +1146,10 @@ pub enum HttpFrontendCmd {
         #[clap(
             short = 'a',
             long = "address",
-            help = "frontend address, format: IP:port"
+            help = "address of the listener of the frontend, format: IP:port or [IPv6]:port, or name of the listener",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
         #[clap(subcommand, name = "cluster_id")]
         cluster_id: ClusterId,
         #[clap(long = "hostname", aliases = &["host"])]
@@ -408,23 +1162,158 @@ pub enum HttpFrontendCmd {
         )]
         path_regex: Option<String>,
         #[clap(
-            long = "path-equals",
-            help = "the frontend URL path should equal this regex"
+            long = "path-equals",
+            help = "the frontend URL path should equal this regex"
+        )]
+        path_equals: Option<String>,
+        #[clap(short = 'm', long = "method", help = "HTTP method")]
+        method: Option<String>,
+        #[clap(long = "tags", help = "Specify tag (key-value pair) to apply on front-end (example: 'key=value, other-key=other-value')", value_parser = parse_tags)]
+        tags: Option<BTreeMap<String, String>>,
+        #[clap(
+            long = "expires-in",
+            help = "remove the frontend after this duration (example: 30m, 72h, 7d)",
+            value_parser = parse_duration
+        )]
+        expires_in: Option<Duration>,
+        #[clap(
+            long = "client-tls-version",
+            help = "HTTPS only: match the clients that negotiated this TLS version (TLS_V12, TLS_V13), can be repeated",
+            value_parser = parse_tls_versions
+        )]
+        client_tls_versions: Vec<TlsVersion>,
+        #[clap(
+            long = "client-cipher-suite",
+            help = "HTTPS only: match the clients that negotiated this cipher suite (example: TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256), can be repeated"
+        )]
+        client_cipher_suites: Vec<String>,
+        #[clap(
+            long = "device",
+            help = "only match the requests of this class of devices (mobile, desktop)",
+            value_parser = parse_device_class
+        )]
+        device: Option<DeviceClass>,
+        #[clap(
+            long = "mobile-user-agent",
+            requires = "device",
+            help = "regular expression matching the User-Agent of the mobile devices, replacing the default ones, can be repeated"
+        )]
+        mobile_user_agents: Vec<String>,
+        #[clap(
+            long = "client-rate-limit",
+            help = "requests per second each client IP may send to the frontend, on top of the limit of the cluster",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        client_rate_limit: Option<u32>,
+        #[clap(
+            long = "client-rate-limit-burst",
+            help = "requests a client IP may send at once to the frontend (default: the requests per second)",
+            requires = "client_rate_limit",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        client_rate_limit_burst: Option<u32>,
+        #[clap(
+            long = "body-read-timeout",
+            help = "maximum time of inactivity of the client while the request body is received, in seconds. Overrides the cluster, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        body_read_timeout: Option<u32>,
+        #[clap(
+            long = "backend-connect-timeout",
+            help = "maximum time to connect to a backend, in seconds. Overrides the cluster, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        backend_connect_timeout: Option<u32>,
+        #[clap(
+            long = "backend-response-timeout",
+            help = "maximum time of inactivity of the backend while the response is awaited and received, in seconds. Overrides the cluster, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        backend_response_timeout: Option<u32>,
+        #[clap(
+            long = "request-deadline",
+            help = "maximum time to answer a request once its headers are received, in seconds. Overrides the cluster, see 'sozu frontend timeouts'",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        request_deadline: Option<u32>,
+        #[clap(
+            long = "mirror-file",
+            help = "append a sample of the raw requests of the frontend to this file, for offline debugging or replay",
+            conflicts_with = "mirror_socket"
+        )]
+        mirror_file: Option<String>,
+        #[clap(
+            long = "mirror-socket",
+            help = "send a sample of the raw requests of the frontend to this unix datagram socket, one request per datagram"
+        )]
+        mirror_socket: Option<String>,
+        #[clap(
+            long = "mirror-sample-one-in",
+            help = "mirror one request in this many, chosen at random (default: 100)",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        mirror_sample_one_in: Option<u32>,
+        #[clap(
+            long = "mirror-max-per-second",
+            help = "mirror at most this many requests per second and per worker (default: 10)",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        mirror_max_per_second: Option<u32>,
+        #[clap(
+            long = "mirror-body-prefix",
+            help = "bytes of the request body mirrored after the headers, at most 65536 (default: 0)"
+        )]
+        mirror_body_prefix: Option<u32>,
+        #[clap(
+            long = "weight",
+            help = "split the requests between clusters for canary deployments, as CLUSTER_ID=WEIGHT, can be repeated. The cluster of the frontend must be one of them (example: --weight v1=95 --weight v2=5 id v1)",
+            value_parser = parse_weighted_cluster
+        )]
+        split: Vec<WeightedCluster>,
+        #[clap(
+            long = "add-request-header",
+            help = "add a header to the requests sent to the backends, as NAME=VALUE, can be repeated. %REQUEST_ID is replaced with the id of the request",
+            value_parser = parse_header
+        )]
+        add_request_headers: Vec<(String, String)>,
+        #[clap(
+            long = "set-request-header",
+            help = "replace a header of the requests sent to the backends, as NAME=VALUE, can be repeated",
+            value_parser = parse_header
+        )]
+        set_request_headers: Vec<(String, String)>,
+        #[clap(
+            long = "remove-request-header",
+            help = "remove a header from the requests sent to the backends, can be repeated"
+        )]
+        remove_request_headers: Vec<String>,
+        #[clap(
+            long = "add-response-header",
+            help = "add a header to the responses sent to the clients, as NAME=VALUE, can be repeated",
+            value_parser = parse_header
+        )]
+        add_response_headers: Vec<(String, String)>,
+        #[clap(
+            long = "set-response-header",
+            help = "replace a header of the responses sent to the clients, as NAME=VALUE, can be repeated",
+            value_parser = parse_header
+        )]
+        set_response_headers: Vec<(String, String)>,
+        #[clap(
+            long = "remove-response-header",
+            help = "remove a header from the responses sent to the clients (example: Server), can be repeated"
         )]
-        path_equals: Option<String>,
-        #[clap(short = 'm', long = "method", help = "HTTP method")]
-        method: Option<String>,
-        #[clap(long = "tags", help = "Specify tag (key-value pair) to apply on front-end (example: 'key=value, other-key=other-value')", value_parser = parse_tags)]
-        tags: Option<BTreeMap<String, String>>,
+        remove_response_headers: Vec<String>,
     },
     #[clap(name = "remove")]
     Remove {
         #[clap(
             short = 'a',
             long = "address",
-            help = "frontend address, format: IP:port"
+            help = "address of the listener of the frontend, format: IP:port or [IPv6]:port, or name of the listener",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
         #[clap(subcommand, name = "cluster_id")]
         cluster_id: ClusterId,
         #[clap(long = "hostname", aliases = &["host"])]
@@ -443,6 +1332,29 @@ pub enum HttpFrontendCmd {
         path_equals: Option<String>,
         #[clap(short = 'm', long = "method", help = "HTTP method")]
         method: Option<String>,
+        #[clap(
+            long = "client-tls-version",
+            help = "HTTPS only: match the clients that negotiated this TLS version (TLS_V12, TLS_V13), can be repeated",
+            value_parser = parse_tls_versions
+        )]
+        client_tls_versions: Vec<TlsVersion>,
+        #[clap(
+            long = "client-cipher-suite",
+            help = "HTTPS only: match the clients that negotiated this cipher suite (example: TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256), can be repeated"
+        )]
+        client_cipher_suites: Vec<String>,
+        #[clap(
+            long = "device",
+            help = "only match the requests of this class of devices (mobile, desktop)",
+            value_parser = parse_device_class
+        )]
+        device: Option<DeviceClass>,
+        #[clap(
+            long = "mobile-user-agent",
+            requires = "device",
+            help = "regular expression matching the User-Agent of the mobile devices, replacing the default ones, can be repeated"
+        )]
+        mobile_user_agents: Vec<String>,
     },
 }
 
@@ -459,15 +1371,27 @@ pub enum TcpFrontendCmd {
         #[clap(
             short = 'a',
             long = "address",
-            help = "frontend address, format: IP:port"
+            help = "address of the listener of the frontend, format: IP:port or [IPv6]:port, or name of the listener",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
         #[clap(
             long = "tags",
             help = "Specify tag (key-value pair) to apply on front-end (example: 'key=value, other-key=other-value')",
             value_parser = parse_tags
         )]
         tags: Option<BTreeMap<String, String>>,
+        #[clap(
+            long = "expires-in",
+            help = "remove the frontend after this duration (example: 30m, 72h, 7d)",
+            value_parser = parse_duration
+        )]
+        expires_in: Option<Duration>,
+        #[clap(
+            long = "sni",
+            help = "route only the TLS connections asking for this server name, without terminating TLS"
+        )]
+        sni: Option<String>,
     },
     #[clap(name = "remove")]
     Remove {
@@ -480,9 +1404,15 @@ pub enum TcpFrontendCmd {
         #[clap(
             short = 'a',
             long = "address",
-            help = "frontend address, format: IP:port"
+            help = "address of the listener of the frontend, format: IP:port or [IPv6]:port, or name of the listener",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
+        #[clap(
+            long = "sni",
+            help = "server name of the frontend, if it routes by SNI"
+        )]
+        sni: Option<String>,
     },
 }
 
@@ -505,8 +1435,17 @@ pub enum ListenerCmd {
     },
     #[clap(name = "list", about = "List all listeners")]
     List,
+    #[clap(
+        name = "check",
+        about = "Check that an address can be bound by a listener, naming the process using it otherwise. Does not need a running Sōzu"
+    )]
+    Check {
+        #[clap(short = 'a')]
+        address: SocketAddr,
+    },
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum HttpListenerCmd {
     #[clap(name = "add")]
@@ -518,6 +1457,11 @@ pub enum HttpListenerCmd {
             help = "a different IP than the one the socket sees, for logs and forwarded headers"
         )]
         public_address: Option<SocketAddr>,
+        #[clap(
+            long = "name",
+            help = "name of the listener, unique among the listeners, that the commands can use instead of its address"
+        )]
+        name: Option<String>,
         #[clap(
             long = "answer-404",
             help = "path to file of the 404 answer sent to the client when a frontend is not found"
@@ -533,6 +1477,21 @@ pub enum HttpListenerCmd {
             help = "Configures the client socket to receive a PROXY protocol header"
         )]
         expect_proxy: bool,
+        #[clap(
+            long = "detect-proxy",
+            help = "accept the clients with and without a PROXY protocol header"
+        )]
+        detect_proxy: bool,
+        #[clap(
+            long = "ipv6-only",
+            help = "for an IPv6 address, accept only IPv6 clients (true) or IPv4 clients too (false). The system default applies if unset"
+        )]
+        ipv6_only: Option<bool>,
+        #[clap(
+            long = "normalize-ipv4-mapped",
+            help = "see the IPv4 clients of a dual-stack listener with their IPv4 address instead of an IPv4-mapped IPv6 one"
+        )]
+        normalize_ipv4_mapped: bool,
         #[clap(long = "sticky-name", help = "sticky session cookie name")]
         sticky_name: Option<String>,
         #[clap(
@@ -555,36 +1514,130 @@ pub enum HttpListenerCmd {
             help = "maximum time to connect to a backend server"
         )]
         connect_timeout: Option<u32>,
+        #[clap(
+            long = "proxy-status",
+            help = "add a header describing what the proxy did with the request to the responses. Possible values are 'proxy-status' (RFC 9209) or 'x-sozu-status'",
+            value_parser = parse_proxy_status
+        )]
+        proxy_status: Option<ProxyStatusHeader>,
+        #[clap(
+            long = "rate-limit",
+            help = "maximum number of requests of a client IP in the rate limit window, answered with a 429 beyond it"
+        )]
+        rate_limit: Option<u32>,
+        #[clap(
+            long = "rate-limit-window",
+            help = "length of the rate limit window, in seconds (default: 60)",
+            requires = "rate_limit"
+        )]
+        rate_limit_window: Option<u32>,
+        #[clap(
+            long = "rate-limit-exempt",
+            help = "network of clients that are not rate limited, in CIDR notation. Can be repeated",
+            requires = "rate_limit"
+        )]
+        rate_limit_exempt: Vec<String>,
+        #[clap(
+            long = "backend-pinning-from",
+            help = "network of clients allowed to send their requests to the backend named in the backend pinning header, in CIDR notation. Can be repeated"
+        )]
+        backend_pinning_from: Vec<String>,
+        #[clap(
+            long = "backend-pinning-header",
+            help = "request header naming the backend a trusted client pins its request to (default: X-Sozu-Backend)",
+            requires = "backend_pinning_from"
+        )]
+        backend_pinning_header: Option<String>,
+        #[clap(
+            long = "http10-keep-open",
+            help = "keep the connections of HTTP/1.0 clients open after the response, like HTTP/1.1 ones, instead of closing them unless the client asks for keep-alive"
+        )]
+        http10_keep_open: bool,
+        #[clap(
+            long = "http10-no-keep-alive",
+            help = "close the connections of HTTP/1.0 clients after the response, even if they ask for keep-alive"
+        )]
+        http10_no_keep_alive: bool,
+        #[clap(
+            long = "http10-default-host",
+            help = "hostname used to route the HTTP/1.0 requests without a Host header"
+        )]
+        http10_default_host: Option<String>,
+        #[clap(
+            long = "duplicate-header",
+            help = "what to do with a header repeated in a request, like content-length=reject. The policy is merge, first or reject. Can be repeated",
+            value_parser = parse_duplicate_header
+        )]
+        duplicate_headers: Vec<(String, DuplicateHeaderPolicy)>,
+        #[clap(
+            long = "request-deadline",
+            help = "maximum time to answer a request once its headers are received, in seconds. Clusters and frontends can override it",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        request_deadline: Option<u32>,
+        #[clap(
+            long = "websocket-idle-timeout",
+            help = "inactive time of the connections upgraded to WebSocket, in seconds, instead of the front and back timeouts",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        websocket_idle_timeout: Option<u32>,
     },
     #[clap(name = "remove")]
     Remove {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
     },
     #[clap(name = "activate")]
     Activate {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
     },
     #[clap(name = "deactivate")]
     Deactivate {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
+    },
+    #[clap(
+        name = "update-answers",
+        about = "replace custom answers of the listener, leaving its other parameters untouched"
+    )]
+    UpdateAnswers {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
+        )]
+        address: ListenerRef,
+        #[clap(
+            long = "answer-404",
+            help = "path to file of the 404 answer sent to the client when a frontend is not found"
+        )]
+        answer_404: Option<String>,
+        #[clap(
+            long = "answer-503",
+            help = "path to file of the 503 answer sent to the client when a cluster has no backends available"
+        )]
+        answer_503: Option<String>,
     },
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum HttpsListenerCmd {
     #[clap(name = "add")]
@@ -596,6 +1649,11 @@ pub enum HttpsListenerCmd {
             help = "a different IP than the one the socket sees, for logs and forwarded headers"
         )]
         public_address: Option<SocketAddr>,
+        #[clap(
+            long = "name",
+            help = "name of the listener, unique among the listeners, that the commands can use instead of its address"
+        )]
+        name: Option<String>,
         #[clap(
             long = "answer-404",
             help = "path to file of the 404 answer sent to the client when a frontend is not found"
@@ -618,6 +1676,31 @@ pub enum HttpsListenerCmd {
             help = "Configures the client socket to receive a PROXY protocol header"
         )]
         expect_proxy: bool,
+        #[clap(
+            long = "detect-proxy",
+            help = "accept the clients with and without a PROXY protocol header"
+        )]
+        detect_proxy: bool,
+        #[clap(
+            long = "ipv6-only",
+            help = "for an IPv6 address, accept only IPv6 clients (true) or IPv4 clients too (false). The system default applies if unset"
+        )]
+        ipv6_only: Option<bool>,
+        #[clap(
+            long = "normalize-ipv4-mapped",
+            help = "see the IPv4 clients of a dual-stack listener with their IPv4 address instead of an IPv4-mapped IPv6 one"
+        )]
+        normalize_ipv4_mapped: bool,
+        #[clap(
+            long = "early-data",
+            help = "accept TLS 1.3 early data (0-RTT) for idempotent requests"
+        )]
+        early_data: bool,
+        #[clap(
+            long = "http2",
+            help = "offer HTTP/2 to the clients, the requests are proxied over HTTP/1.1"
+        )]
+        http2: bool,
         #[clap(long = "sticky-name", help = "sticky session cookie name")]
         sticky_name: Option<String>,
         #[clap(
@@ -640,33 +1723,153 @@ pub enum HttpsListenerCmd {
             help = "maximum time to connect to a backend server"
         )]
         connect_timeout: Option<u32>,
+        #[clap(
+            long = "handshake-timeout",
+            help = "maximum time to complete the TLS handshake"
+        )]
+        handshake_timeout: Option<u32>,
+        #[clap(
+            long = "proxy-status",
+            help = "add a header describing what the proxy did with the request to the responses. Possible values are 'proxy-status' (RFC 9209) or 'x-sozu-status'",
+            value_parser = parse_proxy_status
+        )]
+        proxy_status: Option<ProxyStatusHeader>,
+        #[clap(
+            long = "rate-limit",
+            help = "maximum number of requests of a client IP in the rate limit window, answered with a 429 beyond it"
+        )]
+        rate_limit: Option<u32>,
+        #[clap(
+            long = "rate-limit-window",
+            help = "length of the rate limit window, in seconds (default: 60)",
+            requires = "rate_limit"
+        )]
+        rate_limit_window: Option<u32>,
+        #[clap(
+            long = "rate-limit-exempt",
+            help = "network of clients that are not rate limited, in CIDR notation. Can be repeated",
+            requires = "rate_limit"
+        )]
+        rate_limit_exempt: Vec<String>,
+        #[clap(
+            long = "backend-pinning-from",
+            help = "network of clients allowed to send their requests to the backend named in the backend pinning header, in CIDR notation. Can be repeated"
+        )]
+        backend_pinning_from: Vec<String>,
+        #[clap(
+            long = "backend-pinning-header",
+            help = "request header naming the backend a trusted client pins its request to (default: X-Sozu-Backend)",
+            requires = "backend_pinning_from"
+        )]
+        backend_pinning_header: Option<String>,
+        #[clap(
+            long = "http10-keep-open",
+            help = "keep the connections of HTTP/1.0 clients open after the response, like HTTP/1.1 ones, instead of closing them unless the client asks for keep-alive"
+        )]
+        http10_keep_open: bool,
+        #[clap(
+            long = "http10-no-keep-alive",
+            help = "close the connections of HTTP/1.0 clients after the response, even if they ask for keep-alive"
+        )]
+        http10_no_keep_alive: bool,
+        #[clap(
+            long = "http10-default-host",
+            help = "hostname used to route the HTTP/1.0 requests without a Host header"
+        )]
+        http10_default_host: Option<String>,
+        #[clap(
+            long = "duplicate-header",
+            help = "what to do with a header repeated in a request, like content-length=reject. The policy is merge, first or reject. Can be repeated",
+            value_parser = parse_duplicate_header
+        )]
+        duplicate_headers: Vec<(String, DuplicateHeaderPolicy)>,
+        #[clap(
+            long = "client-ca",
+            help = "path to the PEM bundle of the authorities signing the client certificates. The clients must send a certificate signed by one of them (mutual TLS)"
+        )]
+        client_ca: Option<String>,
+        #[clap(
+            long = "client-crl",
+            help = "path to the PEM revocation lists of the client certificate authorities",
+            requires = "client_ca"
+        )]
+        client_crl: Option<String>,
+        #[clap(
+            long = "client-certificate-optional",
+            help = "accept the clients sending no certificate. The backends see it in the X-SSL-Client-Verify header",
+            requires = "client_ca"
+        )]
+        client_certificate_optional: bool,
+        #[clap(
+            long = "unknown-sni-cluster",
+            help = "TCP cluster receiving the TLS connections whose server name has no certificate, relayed without terminating TLS"
+        )]
+        unknown_sni_cluster: Option<String>,
+        #[clap(
+            long = "request-deadline",
+            help = "maximum time to answer a request once its headers are received, in seconds. Clusters and frontends can override it",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        request_deadline: Option<u32>,
+        #[clap(
+            long = "websocket-idle-timeout",
+            help = "inactive time of the connections upgraded to WebSocket, in seconds, instead of the front and back timeouts",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        websocket_idle_timeout: Option<u32>,
     },
     #[clap(name = "remove")]
     Remove {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
     },
     #[clap(name = "activate")]
     Activate {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
     },
     #[clap(name = "deactivate")]
     Deactivate {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
+    },
+    #[clap(
+        name = "update-answers",
+        about = "replace custom answers of the listener, leaving its other parameters untouched"
+    )]
+    UpdateAnswers {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
+        )]
+        address: ListenerRef,
+        #[clap(
+            long = "answer-404",
+            help = "path to file of the 404 answer sent to the client when a frontend is not found"
+        )]
+        answer_404: Option<String>,
+        #[clap(
+            long = "answer-503",
+            help = "path to file of the 503 answer sent to the client when a cluster has no backends available"
+        )]
+        answer_503: Option<String>,
     },
 }
 
@@ -677,7 +1880,7 @@ pub enum TcpListenerCmd {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port"
         )]
         address: SocketAddr,
         #[clap(
@@ -685,38 +1888,56 @@ pub enum TcpListenerCmd {
             help = "a different IP than the one the socket sees, for logs and forwarded headers"
         )]
         public_address: Option<SocketAddr>,
+        #[clap(
+            long = "name",
+            help = "name of the listener, unique among the listeners, that the commands can use instead of its address"
+        )]
+        name: Option<String>,
         #[clap(
             long = "expect-proxy",
             help = "Configures the client socket to receive a PROXY protocol header"
         )]
         expect_proxy: bool,
+        #[clap(
+            long = "ipv6-only",
+            help = "for an IPv6 address, accept only IPv6 clients (true) or IPv4 clients too (false). The system default applies if unset"
+        )]
+        ipv6_only: Option<bool>,
+        #[clap(
+            long = "normalize-ipv4-mapped",
+            help = "see the IPv4 clients of a dual-stack listener with their IPv4 address instead of an IPv4-mapped IPv6 one"
+        )]
+        normalize_ipv4_mapped: bool,
     },
     #[clap(name = "remove")]
     Remove {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
     },
     #[clap(name = "activate")]
     Activate {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
     },
     #[clap(name = "deactivate")]
     Deactivate {
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
     },
 }
 
@@ -724,8 +1945,10 @@ pub enum TcpListenerCmd {
 pub enum CertificateCmd {
     #[clap(
         name = "list",
+        alias = "query",
         about = "Query all certificates, or filtered by fingerprint or domain name.
 This command queries the state of Sōzu by default, but can show results for all workers.
+Queries by domain name always ask the workers, to show the certificate chosen for that name.
 Use the --json option to get a much more verbose result, with certificate contents."
     )]
     List {
@@ -738,7 +1961,7 @@ Use the --json option to get a much more verbose result, with certificate conten
         #[clap(
             short = 'd',
             long = "domain",
-            help = "list certificates for a domain name"
+            help = "show the listeners serving a domain name, and details of the selected certificate"
         )]
         domain: Option<String>,
         #[clap(
@@ -753,9 +1976,10 @@ Use the --json option to get a much more verbose result, with certificate conten
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
         #[clap(long = "certificate", help = "path to the certificate")]
         certificate: String,
         #[clap(long = "certificate-chain", help = "path to the certificate chain")]
@@ -771,9 +1995,10 @@ Use the --json option to get a much more verbose result, with certificate conten
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
         #[clap(aliases = &["cert"], long = "certificate", help = "path to the certificate")]
         certificate: Option<String>,
         #[clap(short = 'f', long = "fingerprint", help = "certificate fingerprint")]
@@ -784,9 +2009,10 @@ Use the --json option to get a much more verbose result, with certificate conten
         #[clap(
             short = 'a',
             long = "address",
-            help = "listener address, format: IP:port"
+            help = "listener address, format: IP:port or [IPv6]:port, or listener name",
+            value_parser = parse_listener
         )]
-        address: SocketAddr,
+        address: ListenerRef,
         #[clap(long = "new-certificate", help = "path to the new certificate")]
         certificate: String,
         #[clap(
@@ -812,12 +2038,113 @@ Use the --json option to get a much more verbose result, with certificate conten
                 value_parser = parse_tls_versions)]
         tls_versions: Vec<TlsVersion>,
     },
+    #[clap(
+        name = "acme",
+        about = "certificates ordered from the ACME server of the acme section"
+    )]
+    Acme {
+        #[clap(subcommand)]
+        cmd: AcmeCmd,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum AcmeCmd {
+    #[clap(
+        name = "order",
+        about = "Order a certificate now for a domain of the acme section, and install it. \
+Waits until the ACME server validated the names and issued the certificate"
+    )]
+    Order {
+        #[clap(help = "one of the names of the domain")]
+        domain: String,
+    },
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum ConfigCmd {
-    #[clap(name = "check", about = "check configuration file syntax and exit")]
+    #[clap(
+        name = "check",
+        about = "validate the configuration file, like sozu check"
+    )]
     Check,
+    #[clap(
+        name = "import",
+        about = "generate a Sōzu configuration from a HAProxy or nginx configuration file, \
+        and report the directives that could not be translated"
+    )]
+    Import {
+        #[clap(
+            long = "from",
+            help = "format of the file to import: haproxy or nginx",
+            value_parser = parse_import_format
+        )]
+        from: ImportFormat,
+        #[clap(help = "path of the HAProxy or nginx configuration file")]
+        file: String,
+        #[clap(
+            short = 'o',
+            long = "output",
+            help = "where to write the generated config.toml, defaults to stdout"
+        )]
+        output: Option<String>,
+    },
+}
+
+/// a listener, given by its address or its name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenerRef {
+    Address(SocketAddr),
+    Name(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    Haproxy,
+    Nginx,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Terraform,
+    TerraformJson,
+    /// the whole state, that `state import` reads back
+    Json,
+    Protobuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+fn parse_shell(shell: &str) -> Result<Shell, String> {
+    match shell {
+        "bash" => Ok(Shell::Bash),
+        "zsh" => Ok(Shell::Zsh),
+        "fish" => Ok(Shell::Fish),
+        s => Err(format!("unsupported shell: {s}")),
+    }
+}
+
+fn parse_import_format(format: &str) -> Result<ImportFormat, String> {
+    match format {
+        "haproxy" => Ok(ImportFormat::Haproxy),
+        "nginx" => Ok(ImportFormat::Nginx),
+        s => Err(format!("unrecognized configuration format: {s}")),
+    }
+}
+
+fn parse_export_format(format: &str) -> Result<ExportFormat, String> {
+    match format {
+        "terraform" => Ok(ExportFormat::Terraform),
+        "terraform-json" => Ok(ExportFormat::TerraformJson),
+        "json" => Ok(ExportFormat::Json),
+        "pb" => Ok(ExportFormat::Protobuf),
+        s => Err(format!("unrecognized export format: {s}")),
+    }
 }
 
 fn parse_tls_versions(i: &str) -> Result<TlsVersion, String> {
@@ -825,11 +2152,164 @@ fn parse_tls_versions(i: &str) -> Result<TlsVersion, String> {
         "TLSv1" => Ok(TlsVersion::TlsV10),
         "TLS_V11" => Ok(TlsVersion::TlsV11),
         "TLS_V12" => Ok(TlsVersion::TlsV12),
-        "TLS_V13" => Ok(TlsVersion::TlsV12),
+        "TLS_V13" => Ok(TlsVersion::TlsV13),
         s => Err(format!("unrecognized TLS version: {s}")),
     }
 }
 
+fn parse_header(i: &str) -> Result<(String, String), String> {
+    i.split_once('=')
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .ok_or(format!("expected NAME=VALUE, got: {i}"))
+}
+
+fn parse_weighted_cluster(i: &str) -> Result<WeightedCluster, String> {
+    let (cluster_id, weight) = i
+        .split_once('=')
+        .ok_or(format!("expected CLUSTER_ID=WEIGHT, got: {i}"))?;
+    let weight = weight
+        .parse()
+        .map_err(|error| format!("invalid weight {weight}: {error}"))?;
+    Ok(WeightedCluster {
+        cluster_id: cluster_id.to_owned(),
+        weight,
+    })
+}
+
+fn parse_load_metric(metric: &str) -> Result<LoadMetric, String> {
+    LoadMetric::from_str_name(&metric.to_uppercase())
+        .ok_or(format!("unrecognized load metric: {metric}"))
+}
+
+fn parse_device_class(class: &str) -> Result<DeviceClass, String> {
+    DeviceClass::from_str_name(&class.to_uppercase()).ok_or(format!(
+        "unrecognized device class: {class}, expected mobile or desktop"
+    ))
+}
+
+fn parse_health_override(state: &str) -> Result<HealthOverride, String> {
+    HealthOverride::from_str_name(&format!("HEALTH_OVERRIDE_{}", state.to_uppercase())).ok_or(
+        format!("unrecognized health state: {state}, expected down, up or auto"),
+    )
+}
+
+fn parse_duplicate_header(i: &str) -> Result<(String, DuplicateHeaderPolicy), String> {
+    let (name, policy) = i
+        .split_once('=')
+        .ok_or(format!("expected NAME=POLICY, got: {i}"))?;
+    let policy = DuplicateHeaderPolicy::from_str_name(&policy.to_uppercase()).ok_or(format!(
+        "unrecognized duplicate header policy: {policy}, expected merge, first or reject"
+    ))?;
+    Ok((name.to_owned(), policy))
+}
+
+fn parse_proxy_status(header: &str) -> Result<ProxyStatusHeader, String> {
+    match header {
+        "proxy-status" => Ok(ProxyStatusHeader::ProxyStatus),
+        "x-sozu-status" => Ok(ProxyStatusHeader::XSozuStatus),
+        s => Err(format!("unrecognized proxy status header: {s}")),
+    }
+}
+
+fn parse_backend(string_to_parse: &str) -> Result<(String, SocketAddr), String> {
+    let (backend_id, address) = string_to_parse.split_once('=').ok_or(format!(
+        "could not parse backend '{string_to_parse}', expected format: backend_id=IP:port or backend_id=[IPv6]:port"
+    ))?;
+    let address = address.trim().parse().map_err(|e| {
+        if address.matches(':').count() > 1 && !address.contains('[') {
+            format!("could not parse backend address '{address}': {e}, IPv6 addresses should be written between brackets, like [2001:db8::1]:8080")
+        } else {
+            format!("could not parse backend address '{address}': {e}")
+        }
+    })?;
+    Ok((backend_id.trim().to_owned(), address))
+}
+
+fn parse_listener(string_to_parse: &str) -> Result<ListenerRef, String> {
+    let string_to_parse = string_to_parse.trim();
+    if let Ok(address) = string_to_parse.parse() {
+        return Ok(ListenerRef::Address(address));
+    }
+    if !is_valid_listener_name(string_to_parse) {
+        return Err(format!(
+            "could not parse listener '{string_to_parse}', expected an address (IP:port or [IPv6]:port) or a listener name"
+        ));
+    }
+    Ok(ListenerRef::Name(string_to_parse.to_owned()))
+}
+
+fn parse_expected_cluster_hash(string_to_parse: &str) -> Result<ExpectedClusterHash, String> {
+    let (cluster_id, hash) = string_to_parse.split_once('=').ok_or(format!(
+        "could not parse cluster hash '{string_to_parse}', expected format: cluster_id=hash"
+    ))?;
+    let hash = match hash.trim() {
+        "" => None,
+        hash => Some(
+            hash.parse()
+                .map_err(|e| format!("could not parse cluster hash '{hash}': {e}"))?,
+        ),
+    };
+    Ok(ExpectedClusterHash {
+        cluster_id: cluster_id.trim().to_owned(),
+        hash,
+    })
+}
+
+fn parse_duration(string_to_parse: &str) -> Result<Duration, String> {
+    let string_to_parse = string_to_parse.trim();
+    let split = string_to_parse
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(string_to_parse.len());
+    let (value, unit) = string_to_parse.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .map_err(|e| format!("could not parse duration '{string_to_parse}': {e}"))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "unknown unit in duration '{string_to_parse}', expected s, m, h or d"
+            ))
+        }
+    };
+    Ok(Duration::from_secs(value * seconds))
+}
+
+fn parse_schedule_date(string_to_parse: &str) -> Result<u64, String> {
+    const DAY: u64 = 24 * 60 * 60;
+
+    let Some((hours, minutes)) = string_to_parse.trim().split_once(':') else {
+        return string_to_parse
+            .trim()
+            .parse()
+            .map_err(|e| format!("could not parse date '{string_to_parse}': {e}"));
+    };
+
+    let hours: u64 = hours
+        .parse()
+        .map_err(|e| format!("could not parse hours in '{string_to_parse}': {e}"))?;
+    let minutes: u64 = minutes
+        .parse()
+        .map_err(|e| format!("could not parse minutes in '{string_to_parse}': {e}"))?;
+    if hours > 23 || minutes > 59 {
+        return Err(format!("invalid time of day '{string_to_parse}'"));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?
+        .as_secs();
+    let mut date = now - now % DAY + hours * 3600 + minutes * 60;
+    if date <= now {
+        date += DAY;
+    }
+    Ok(date)
+}
+
 fn parse_tags(string_to_parse: &str) -> Result<BTreeMap<String, String>, String> {
     let mut tags: BTreeMap<String, String> = BTreeMap::new();
 
@@ -867,4 +2347,55 @@ mod tests {
             parse_tags(tags_to_parse)
         );
     }
+
+    #[test]
+    fn parse_duration_from_string() {
+        use super::*;
+
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("72h"), Ok(Duration::from_secs(72 * 3600)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert!(parse_duration("3w").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn parse_ipv6_backend_from_string() {
+        use super::*;
+
+        assert_eq!(
+            parse_backend("app-0=[2001:db8::1]:8080"),
+            Ok(("app-0".to_owned(), "[2001:db8::1]:8080".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_backend("app-0 = 192.0.2.1:8080"),
+            Ok(("app-0".to_owned(), "192.0.2.1:8080".parse().unwrap()))
+        );
+        assert!(parse_backend("app-0=2001:db8::1:8080")
+            .unwrap_err()
+            .contains("between brackets"));
+    }
+
+    #[test]
+    fn parse_expected_cluster_hash_from_string() {
+        use super::*;
+
+        assert_eq!(
+            parse_expected_cluster_hash("app=1234"),
+            Ok(ExpectedClusterHash {
+                cluster_id: "app".to_owned(),
+                hash: Some(1234)
+            })
+        );
+        assert_eq!(
+            parse_expected_cluster_hash("app="),
+            Ok(ExpectedClusterHash {
+                cluster_id: "app".to_owned(),
+                hash: None
+            })
+        );
+        assert!(parse_expected_cluster_hash("app").is_err());
+        assert!(parse_expected_cluster_hash("app=abc").is_err());
+    }
 }
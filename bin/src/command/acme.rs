@@ -0,0 +1,1043 @@
+//! Certificates ordered and renewed from an ACME server, like Let's Encrypt
+//!
+//! With an `acme` section in the configuration, the main process checks every hour the
+//! certificates of the configured domains, and orders a new one from the ACME server
+//! when a domain has none, or when it expires within `renew_before` days. An order
+//! can also be started with `sozu certificate acme order <domain>`.
+//!
+//! The orders run in separate threads. The HTTP-01 challenges are answered by a thread
+//! of the main process, behind the `sozu-acme` cluster: its frontends route the paths
+//! under `/.well-known/acme-challenge/` of each name, on the `http_listener`, before
+//! any other frontend. The issued certificates are kept in the `storage` directory,
+//! with their keys and the key of the ACME account, and installed on the
+//! `https_listener` of their domain.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use mio::Token;
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, EcdsaSigningAlgorithm, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+        ECDSA_P256_SHA256_FIXED_SIGNING,
+    },
+};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde_json::{json, Value};
+
+use sozu_command_lib::{
+    certificate::split_certificate_chain,
+    config::{AcmeConfig, AcmeDomainConfig, ACME_CHALLENGE_PATH},
+};
+
+/// how often the certificates of the domains are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// delay before ordering again the certificate of a domain, after a failed order
+const RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// delay before binding the challenge address again
+const BIND_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// how long the ACME server, or a client of the challenges, may take to answer
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// delay between two checks of an authorization or an order
+const POLL_DELAY: Duration = Duration::from_secs(2);
+
+/// an authorization or an order failing to progress for this many checks is abandoned
+const POLL_ATTEMPTS: usize = 90;
+
+/// larger answers of the ACME server are cut
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// requests longer than this are cut
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+/// roots trusted to reach the ACME server, without a `ca_file`
+const SYSTEM_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+
+// DER encoded object identifiers of the certificate signing request
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+#[derive(thiserror::Error, Debug)]
+pub enum AcmeError {
+    #[error("invalid URL {0}")]
+    InvalidUrl(String),
+    #[error("could not reach {url}: {error}")]
+    Connection { url: String, error: IoError },
+    #[error("could not load the roots trusted to reach the ACME server from {path}: {error}")]
+    Roots { path: String, error: String },
+    #[error("invalid answer from {url}: {reason}")]
+    InvalidResponse { url: String, reason: String },
+    #[error("the ACME server answered {url} with status {status}: {detail}")]
+    Status {
+        url: String,
+        status: u16,
+        detail: String,
+    },
+    #[error("the validation of {name} failed: {detail}")]
+    Validation { name: String, detail: String },
+    #[error("the order of {0} did not progress in time")]
+    TimedOut(String),
+    #[error("could not use the key {0}")]
+    Key(String),
+    #[error("could not write {path}: {error}")]
+    Storage { path: PathBuf, error: IoError },
+}
+
+/// a certificate issued by the ACME server, with its key, in PEM
+#[derive(Debug, Clone)]
+pub struct Issued {
+    pub certificate: String,
+    pub chain: Vec<String>,
+    pub key: String,
+}
+
+/// the end of an order
+#[derive(Debug)]
+pub struct AcmeOutcome {
+    pub domain: AcmeDomainConfig,
+    /// the client that started the order, if any
+    pub client: Option<Token>,
+    pub result: Result<Issued, AcmeError>,
+}
+
+/// the certificate ordered for a domain, or why it could not be
+type OrderResult = (AcmeDomainConfig, Result<Issued, AcmeError>);
+
+/// token -> key authorization, answered to the ACME server
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+/// Orders of certificates, and the answers to their challenges
+#[derive(Debug)]
+pub struct Acme {
+    challenges: Challenges,
+    /// where the challenges are answered, once started
+    challenge_address: Option<SocketAddr>,
+    next_check: Instant,
+    /// first name of the domains being ordered, with the client waiting for each
+    ordering: HashMap<String, Option<Token>>,
+    /// first name of the domains whose last order failed -> date of the next order
+    retry_at: HashMap<String, Instant>,
+    /// each order sends its outcome once over
+    results: (Sender<OrderResult>, Receiver<OrderResult>),
+}
+
+impl Default for Acme {
+    fn default() -> Self {
+        Self {
+            challenges: Arc::new(Mutex::new(HashMap::new())),
+            challenge_address: None,
+            next_check: Instant::now(),
+            ordering: HashMap::new(),
+            retry_at: HashMap::new(),
+            results: mpsc::channel(),
+        }
+    }
+}
+
+impl Acme {
+    /// answer the challenges in a separate thread, and return the address to route them to
+    pub fn start(&mut self, config: &AcmeConfig) -> Option<SocketAddr> {
+        let challenges = self.challenges.clone();
+        let address = match config.challenge_address {
+            // the previous main process may still listen, during an upgrade
+            Some(address) => {
+                thread::spawn(move || {
+                    let listener = loop {
+                        match TcpListener::bind(address) {
+                            Ok(listener) => break listener,
+                            Err(error) => {
+                                warn!(
+                                    "could not answer the ACME challenges on {}: {}",
+                                    address, error
+                                );
+                                thread::sleep(BIND_RETRY_DELAY);
+                            }
+                        }
+                    };
+                    answer_challenges(listener, challenges)
+                });
+                address
+            }
+            None => {
+                let listener = match TcpListener::bind("127.0.0.1:0") {
+                    Ok(listener) => listener,
+                    Err(error) => {
+                        error!(
+                            "could not bind a port to answer the ACME challenges: {}",
+                            error
+                        );
+                        return None;
+                    }
+                };
+                let address = listener.local_addr().ok()?;
+                thread::spawn(move || answer_challenges(listener, challenges));
+                address
+            }
+        };
+        info!("answering the ACME challenges on {}", address);
+        self.challenge_address = Some(address);
+        Some(address)
+    }
+
+    pub fn challenge_address(&self) -> Option<SocketAddr> {
+        self.challenge_address
+    }
+
+    /// returns true, and plans the next one, if a check of the certificates is due
+    pub fn check_due(&mut self, now: Instant) -> bool {
+        if self.challenge_address.is_none() || self.next_check > now {
+            return false;
+        }
+        self.next_check = now + CHECK_INTERVAL;
+        true
+    }
+
+    pub fn is_ordering(&self, name: &str) -> bool {
+        self.ordering.contains_key(name)
+    }
+
+    /// false while the last order of the domain failed recently
+    pub fn may_order(&self, name: &str, now: Instant) -> bool {
+        !self.is_ordering(name) && self.retry_at.get(name).map_or(true, |at| *at <= now)
+    }
+
+    /// order a certificate for the domain, in a separate thread
+    pub fn order(&mut self, config: &AcmeConfig, domain: &AcmeDomainConfig, client: Option<Token>) {
+        let Some(name) = domain.names.first() else {
+            return;
+        };
+        info!(
+            "ordering a certificate for {} from {}",
+            domain.names.join(", "),
+            config.directory
+        );
+        self.ordering.insert(name.to_owned(), client);
+        let config = config.clone();
+        let domain = domain.clone();
+        let challenges = self.challenges.clone();
+        let sender = self.results.0.clone();
+        thread::spawn(move || {
+            let result = order_certificate(&config, &domain.names, &challenges);
+            let _ = sender.send((domain, result));
+        });
+    }
+
+    /// the orders that ended since the last call
+    pub fn take_outcomes(&mut self, now: Instant) -> Vec<AcmeOutcome> {
+        let finished: Vec<_> = self.results.1.try_iter().collect();
+        finished
+            .into_iter()
+            .map(|(domain, result)| {
+                let name = domain.names.first().cloned().unwrap_or_default();
+                let client = self.ordering.remove(&name).flatten();
+                if result.is_err() {
+                    self.retry_at.insert(name, now + RETRY_DELAY);
+                } else {
+                    self.retry_at.remove(&name);
+                }
+                AcmeOutcome {
+                    domain,
+                    client,
+                    result,
+                }
+            })
+            .collect()
+    }
+}
+
+fn answer_challenges(listener: TcpListener, challenges: Challenges) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                warn!(
+                    "could not accept a connection for the ACME challenges: {}",
+                    error
+                );
+                continue;
+            }
+        };
+        if let Err(error) = answer_challenge(stream, &challenges) {
+            debug!("could not answer an ACME challenge: {}", error);
+        }
+    }
+}
+
+fn answer_challenge(mut stream: TcpStream, challenges: &Challenges) -> Result<(), IoError> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let key_authorization = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => path
+            .strip_prefix(ACME_CHALLENGE_PATH)
+            .and_then(|token| challenges.lock().ok()?.get(token).cloned()),
+        _ => None,
+    };
+    let (status, body) = match key_authorization {
+        Some(key_authorization) => ("200 OK", key_authorization),
+        None => ("404 Not Found", "no such challenge\n".to_owned()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// go through an order with the ACME server, from the account to the certificate
+fn order_certificate(
+    config: &AcmeConfig,
+    names: &[String],
+    challenges: &Challenges,
+) -> Result<Issued, AcmeError> {
+    let storage = Path::new(&config.storage);
+    let account_key = load_account_key(storage)?;
+    let mut client = AcmeClient::new(config, &account_key)?;
+
+    let mut account = json!({ "termsOfServiceAgreed": true });
+    if let Some(contact) = &config.contact {
+        account["contact"] = json!([contact]);
+    }
+    let new_account = client.directory.new_account.clone();
+    let response = client.post(&new_account, Some(&account))?;
+    client.kid = Some(response.location(&new_account)?);
+
+    let identifiers: Vec<Value> = names
+        .iter()
+        .map(|name| json!({ "type": "dns", "value": name }))
+        .collect();
+    let new_order = client.directory.new_order.clone();
+    let response = client.post(&new_order, Some(&json!({ "identifiers": identifiers })))?;
+    let order_url = response.location(&new_order)?;
+    let order = response.json(&new_order)?;
+
+    let authorizations: Vec<String> = order["authorizations"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|url| url.as_str().map(str::to_owned))
+        .collect();
+    for authorization in authorizations {
+        client.validate(&authorization, challenges)?;
+    }
+
+    let order = client.poll(&order_url, &["ready", "valid"], &names[0])?;
+    let (csr, key) = certificate_request(names)?;
+    if order["status"] == "ready" {
+        let finalize = string_field(&order, "finalize", &order_url)?;
+        client.post(
+            &finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+        )?;
+    }
+    let order = client.poll(&order_url, &["valid"], &names[0])?;
+    let certificate_url = string_field(&order, "certificate", &order_url)?;
+
+    let response = client.post(&certificate_url, None)?;
+    let mut pems = split_certificate_chain(String::from_utf8_lossy(&response.body).into_owned());
+    if pems.is_empty() {
+        return Err(AcmeError::InvalidResponse {
+            url: certificate_url,
+            reason: "no certificate in the answer".to_owned(),
+        });
+    }
+    let certificate = pems.remove(0);
+    Ok(Issued {
+        certificate,
+        chain: pems,
+        key: pem("PRIVATE KEY", &key),
+    })
+}
+
+/// the PKCS#8 key of the account, generated on the first order
+fn load_account_key(storage: &Path) -> Result<Vec<u8>, AcmeError> {
+    let path = storage.join(ACCOUNT_KEY_FILE);
+    if let Ok(pem) = fs::read(&path) {
+        return match rustls_pemfile::private_key(&mut pem.as_slice()) {
+            Ok(Some(rustls::pki_types::PrivateKeyDer::Pkcs8(key))) => {
+                Ok(key.secret_pkcs8_der().to_vec())
+            }
+            _ => Err(AcmeError::Key(format!(
+                "{}, it is not a PKCS#8 key in PEM",
+                path.display()
+            ))),
+        };
+    }
+
+    let key = generate_key(&ECDSA_P256_SHA256_FIXED_SIGNING)?;
+    write_private(&path, pem("PRIVATE KEY", &key).as_bytes())?;
+    info!(
+        "generated the key of the ACME account in {}",
+        path.display()
+    );
+    Ok(key)
+}
+
+fn generate_key(algorithm: &'static EcdsaSigningAlgorithm) -> Result<Vec<u8>, AcmeError> {
+    EcdsaKeyPair::generate_pkcs8(algorithm, &SystemRandom::new())
+        .map(|key| key.as_ref().to_vec())
+        .map_err(|_| AcmeError::Key("generated by ring".to_owned()))
+}
+
+/// write a file only the user of Sōzu can read, creating its directory
+pub fn write_private(path: &Path, content: &[u8]) -> Result<(), AcmeError> {
+    let storage_error = |error| AcmeError::Storage {
+        path: path.to_owned(),
+        error,
+    };
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory).map_err(storage_error)?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(content))
+        .map_err(storage_error)
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+/// URLs of the directory of the ACME server
+#[derive(Debug)]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// requests to the ACME server, signed with the key of the account
+struct AcmeClient {
+    tls: Arc<ClientConfig>,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    thumbprint: String,
+    directory: Directory,
+    nonce: Option<String>,
+    /// URL of the account, once created
+    kid: Option<String>,
+}
+
+impl AcmeClient {
+    fn new(config: &AcmeConfig, account_key: &[u8]) -> Result<Self, AcmeError> {
+        let tls = client_config(config.ca_file.as_deref().unwrap_or(SYSTEM_CA_FILE))?;
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, account_key, &rng)
+            .map_err(|error| AcmeError::Key(format!("of the account: {error}")))?;
+        let jwk = jwk(key.public_key().as_ref());
+        let thumbprint = thumbprint(&jwk);
+
+        let response = http_request(&tls, "GET", &config.directory, None)?;
+        let directory = response.json(&config.directory)?;
+        let directory = Directory {
+            new_nonce: string_field(&directory, "newNonce", &config.directory)?,
+            new_account: string_field(&directory, "newAccount", &config.directory)?,
+            new_order: string_field(&directory, "newOrder", &config.directory)?,
+        };
+
+        Ok(Self {
+            tls,
+            key,
+            rng,
+            jwk,
+            thumbprint,
+            directory,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    /// a POST signed with the key of the account, a POST-as-GET without payload
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<HttpResponse, AcmeError> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => {
+                    let new_nonce = self.directory.new_nonce.clone();
+                    http_request(&self.tls, "HEAD", &new_nonce, None)?
+                        .header("replay-nonce")
+                        .map(str::to_owned)
+                        .ok_or(AcmeError::InvalidResponse {
+                            url: new_nonce,
+                            reason: "no Replay-Nonce header".to_owned(),
+                        })?
+                }
+            };
+            let body = self.sign(url, &nonce, payload)?;
+            let response = http_request(&self.tls, "POST", url, Some(body.as_bytes()))?;
+            self.nonce = response.header("replay-nonce").map(str::to_owned);
+            if response.status < 400 {
+                return Ok(response);
+            }
+
+            let problem = response.json(url).unwrap_or_default();
+            // a nonce may be refused by the server at any time, the request is sent again
+            if !retried && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Status {
+                url: url.to_owned(),
+                status: response.status,
+                detail: problem["detail"]
+                    .as_str()
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| String::from_utf8_lossy(&response.body).into_owned()),
+            });
+        }
+    }
+
+    /// the request as a JWS in the flattened JSON serialization
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String, AcmeError> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+            .map_err(|_| AcmeError::Key("of the account, to sign a request".to_owned()))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        })
+        .to_string())
+    }
+
+    /// answer the HTTP-01 challenge of an authorization, and wait for its validation
+    fn validate(&mut self, url: &str, challenges: &Challenges) -> Result<(), AcmeError> {
+        let authorization = self.post(url, None)?.json(url)?;
+        let name = authorization["identifier"]["value"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+
+        let challenge = authorization["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|challenge| challenge["type"] == "http-01")
+            .ok_or_else(|| AcmeError::Validation {
+                name: name.clone(),
+                detail: "the ACME server offers no HTTP-01 challenge".to_owned(),
+            })?;
+        let token = string_field(challenge, "token", url)?;
+        let challenge_url = string_field(challenge, "url", url)?;
+
+        let key_authorization = format!("{token}.{}", self.thumbprint);
+        if let Ok(mut challenges) = challenges.lock() {
+            challenges.insert(token.clone(), key_authorization);
+        }
+        let result = self
+            .post(&challenge_url, Some(&json!({})))
+            .and_then(|_| self.poll(url, &["valid"], &name));
+        if let Ok(mut challenges) = challenges.lock() {
+            challenges.remove(&token);
+        }
+        result.map(|_| ())
+    }
+
+    /// check an authorization or an order until it reaches one of these statuses
+    fn poll(&mut self, url: &str, statuses: &[&str], name: &str) -> Result<Value, AcmeError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object = self.post(url, None)?.json(url)?;
+            let status = object["status"].as_str().unwrap_or_default();
+            if statuses.contains(&status) {
+                return Ok(object);
+            }
+            if status == "invalid" {
+                let detail = object["challenges"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .chain(std::iter::once(&object))
+                    .find_map(|object| object["error"]["detail"].as_str())
+                    .unwrap_or("the ACME server gave no detail");
+                return Err(AcmeError::Validation {
+                    name: name.to_owned(),
+                    detail: detail.to_owned(),
+                });
+            }
+            thread::sleep(POLL_DELAY);
+        }
+        Err(AcmeError::TimedOut(name.to_owned()))
+    }
+}
+
+/// the JSON web key of a P-256 public key, in uncompressed form
+fn jwk(public_key: &[u8]) -> Value {
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": URL_SAFE_NO_PAD.encode(&public_key[1..33]),
+        "y": URL_SAFE_NO_PAD.encode(&public_key[33..65]),
+    })
+}
+
+/// the thumbprint of a JSON web key (RFC 7638), over its members in lexicographic order
+fn thumbprint(jwk: &Value) -> String {
+    let canonical = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        jwk["x"].as_str().unwrap_or_default(),
+        jwk["y"].as_str().unwrap_or_default()
+    );
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()))
+}
+
+fn string_field(object: &Value, field: &str, url: &str) -> Result<String, AcmeError> {
+    object[field]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| AcmeError::InvalidResponse {
+            url: url.to_owned(),
+            reason: format!("no {field} in the answer"),
+        })
+}
+
+/// a PKCS#10 certificate signing request for these names, with a new P-256 key,
+/// returned with the key in PKCS#8
+fn certificate_request(names: &[String]) -> Result<(Vec<u8>, Vec<u8>), AcmeError> {
+    let rng = SystemRandom::new();
+    let pkcs8 = generate_key(&ECDSA_P256_SHA256_ASN1_SIGNING)?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &pkcs8, &rng)
+        .map_err(|error| AcmeError::Key(format!("of the certificate: {error}")))?;
+
+    let subject = der(
+        0x30,
+        &der(
+            0x31,
+            &der(
+                0x30,
+                &[der(0x06, OID_COMMON_NAME), der(0x0c, names[0].as_bytes())].concat(),
+            ),
+        ),
+    );
+    let public_key_info = der(
+        0x30,
+        &[
+            der(
+                0x30,
+                &[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, OID_PRIME256V1)].concat(),
+            ),
+            der(0x03, &[&[0], key.public_key().as_ref()].concat()),
+        ]
+        .concat(),
+    );
+    let general_names: Vec<u8> = names
+        .iter()
+        .flat_map(|name| der(0x82, name.as_bytes()))
+        .collect();
+    let subject_alt_name = der(
+        0x30,
+        &[
+            der(0x06, OID_SUBJECT_ALT_NAME),
+            der(0x04, &der(0x30, &general_names)),
+        ]
+        .concat(),
+    );
+    let extension_request = der(
+        0x30,
+        &[
+            der(0x06, OID_EXTENSION_REQUEST),
+            der(0x31, &der(0x30, &subject_alt_name)),
+        ]
+        .concat(),
+    );
+    let request_info = der(
+        0x30,
+        &[
+            der(0x02, &[0]),
+            subject,
+            public_key_info,
+            der(0xa0, &extension_request),
+        ]
+        .concat(),
+    );
+
+    let signature = key
+        .sign(&rng, &request_info)
+        .map_err(|_| AcmeError::Key("of the certificate, to sign the request".to_owned()))?;
+    let request = der(
+        0x30,
+        &[
+            request_info,
+            der(0x30, &der(0x06, OID_ECDSA_WITH_SHA256)),
+            der(0x03, &[&[0], signature.as_ref()].concat()),
+        ]
+        .concat(),
+    );
+    Ok((request, pkcs8))
+}
+
+/// a DER element, with a definite length
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    let length = content.len();
+    if length < 0x80 {
+        element.push(length as u8);
+    } else {
+        let bytes: Vec<u8> = length
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        element.push(0x80 | bytes.len() as u8);
+        element.extend(bytes);
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+fn client_config(ca_file: &str) -> Result<Arc<ClientConfig>, AcmeError> {
+    let roots_error = |error: String| AcmeError::Roots {
+        path: ca_file.to_owned(),
+        error,
+    };
+    let pem = fs::read(ca_file).map_err(|error| roots_error(error.to_string()))?;
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut pem.as_slice()) {
+        let certificate = certificate.map_err(|error| roots_error(error.to_string()))?;
+        roots
+            .add(certificate)
+            .map_err(|error| roots_error(error.to_string()))?;
+    }
+
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|error| roots_error(error.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+#[derive(Debug)]
+struct HttpResponse {
+    status: u16,
+    /// names in lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn location(&self, url: &str) -> Result<String, AcmeError> {
+        self.header("location")
+            .map(str::to_owned)
+            .ok_or_else(|| AcmeError::InvalidResponse {
+                url: url.to_owned(),
+                reason: "no Location header".to_owned(),
+            })
+    }
+
+    fn json(&self, url: &str) -> Result<Value, AcmeError> {
+        serde_json::from_slice(&self.body).map_err(|error| AcmeError::InvalidResponse {
+            url: url.to_owned(),
+            reason: error.to_string(),
+        })
+    }
+}
+
+/// a request on its own connection, closed after the answer
+fn http_request(
+    tls: &Arc<ClientConfig>,
+    method: &str,
+    url: &str,
+    body: Option<&[u8]>,
+) -> Result<HttpResponse, AcmeError> {
+    let invalid_url = || AcmeError::InvalidUrl(url.to_owned());
+    let (secure, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (None, Some(rest)) => (false, rest),
+        (None, None) => return Err(invalid_url()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| invalid_url())?),
+        None => (authority, if secure { 443 } else { 80 }),
+    };
+
+    let connection_error = |error| AcmeError::Connection {
+        url: url.to_owned(),
+        error,
+    };
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(connection_error)?
+        .next()
+        .ok_or_else(|| connection_error(IoError::new(ErrorKind::NotFound, "no address")))?;
+    let stream = TcpStream::connect_timeout(&address, IO_TIMEOUT).map_err(connection_error)?;
+    stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+        .map_err(connection_error)?;
+
+    let mut request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: sozu-acme\r\nAccept: */*\r\nConnection: close\r\n"
+    )
+    .into_bytes();
+    if let Some(body) = body {
+        request.extend_from_slice(
+            format!(
+                "Content-Type: application/jose+json\r\nContent-Length: {}\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        );
+    }
+    request.extend_from_slice(b"\r\n");
+    request.extend_from_slice(body.unwrap_or_default());
+
+    let mut raw = Vec::new();
+    if secure {
+        let server_name = ServerName::try_from(host.to_owned()).map_err(|_| invalid_url())?;
+        let connection = ClientConnection::new(tls.clone(), server_name)
+            .map_err(|error| connection_error(IoError::new(ErrorKind::Other, error.to_string())))?;
+        exchange(StreamOwned::new(connection, stream), &request, &mut raw)
+    } else {
+        exchange(stream, &request, &mut raw)
+    }
+    .map_err(connection_error)?;
+
+    parse_response(&raw).map_err(|reason| AcmeError::InvalidResponse {
+        url: url.to_owned(),
+        reason: reason.to_owned(),
+    })
+}
+
+fn exchange(
+    mut stream: impl Read + Write,
+    request: &[u8],
+    raw: &mut Vec<u8>,
+) -> Result<(), IoError> {
+    stream.write_all(request)?;
+    stream.flush()?;
+    match stream.take(MAX_RESPONSE_SIZE).read_to_end(raw) {
+        Ok(_) => Ok(()),
+        // some servers close the connection without a TLS close_notify
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof && !raw.is_empty() => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+fn parse_response(raw: &[u8]) -> Result<HttpResponse, &'static str> {
+    let head_end = find(raw, b"\r\n\r\n").ok_or("incomplete headers")?;
+    let head = std::str::from_utf8(&raw[..head_end]).map_err(|_| "headers are not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or("invalid status line")?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+        .collect();
+
+    let mut response = HttpResponse {
+        status,
+        headers,
+        body: raw[head_end + 4..].to_vec(),
+    };
+    if response
+        .header("transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        response.body = decode_chunked(&response.body)?;
+    }
+    Ok(response)
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = find(body, b"\r\n").ok_or("incomplete chunk size")?;
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or("invalid chunk size")?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size + 2 {
+            return Err("incomplete chunk");
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+    use x509_parser::{
+        certification_request::X509CertificationRequest, extensions::ParsedExtension,
+        prelude::FromDer,
+    };
+
+    use super::*;
+
+    #[test]
+    fn certificate_request_for_several_names() {
+        let names = vec!["example.com".to_owned(), "www.example.com".to_owned()];
+        let (der, _key) = certificate_request(&names).expect("could not make the request");
+
+        let (rest, request) =
+            X509CertificationRequest::from_der(&der).expect("could not parse the request");
+        assert!(rest.is_empty());
+        let info = &request.certification_request_info;
+        assert_eq!(
+            info.subject.to_string(),
+            "CN=example.com",
+            "the subject is the first name"
+        );
+
+        let requested_names: Vec<String> = request
+            .requested_extensions()
+            .into_iter()
+            .flatten()
+            .filter_map(|extension| match extension {
+                ParsedExtension::SubjectAlternativeName(san) => Some(
+                    san.general_names
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(
+            requested_names,
+            vec!["DNSName(example.com)", "DNSName(www.example.com)"]
+        );
+
+        let public_key = info.subject_pki.subject_public_key.data.to_vec();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+            .verify(info.raw, &request.signature_value.data)
+            .expect("the request is not signed by its key");
+    }
+
+    #[test]
+    fn long_der_lengths() {
+        assert_eq!(der(0x04, &[1, 2]), vec![0x04, 2, 1, 2]);
+        let long = der(0x04, &[0; 300]);
+        assert_eq!(&long[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+    }
+
+    #[test]
+    fn jwk_thumbprint() {
+        let mut public_key = vec![4];
+        public_key.extend(1..=64);
+        let jwk = jwk(&public_key);
+        assert_eq!(
+            jwk["x"],
+            URL_SAFE_NO_PAD.encode((1..=32).collect::<Vec<u8>>())
+        );
+        assert_eq!(
+            jwk["y"],
+            URL_SAFE_NO_PAD.encode((33..=64).collect::<Vec<u8>>())
+        );
+        // the members of the key, without whitespace, in lexicographic order
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            URL_SAFE_NO_PAD.encode((1..=32).collect::<Vec<u8>>()),
+            URL_SAFE_NO_PAD.encode((33..=64).collect::<Vec<u8>>())
+        );
+        assert_eq!(
+            thumbprint(&jwk),
+            URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()))
+        );
+    }
+
+    #[test]
+    fn chunked_response() {
+        let response = parse_response(
+            b"HTTP/1.1 201 Created\r\nReplay-Nonce: abc\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3;ext\r\n:1}\r\n0\r\n\r\n",
+        )
+        .expect("could not parse the response");
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("replay-nonce"), Some("abc"));
+        assert_eq!(response.body, b"{\"a\":1}");
+    }
+}
@@ -0,0 +1,519 @@
+//! Threshold rules on metrics, evaluated by the main process
+//!
+//! Every `alert_check_interval`, the main process gathers the cluster metrics of the
+//! workers, computes the measure of each rule for each cluster, and emits an
+//! `ALERT_FIRED` event when the threshold is breached. The alert is resolved, with
+//! an `ALERT_RESOLVED` event, once the measure gets back to the recover threshold.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sozu_command_lib::{
+    config::{AlertConfig, AlertMetric},
+    proto::command::{
+        filtered_metrics::Inner, response_content::ContentType, ClusterMetrics, Event, EventKind,
+        FilteredMetrics, ResponseContent,
+    },
+    state::ConfigState,
+};
+
+/// how long a webhook may take to connect, receive the event and answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(thiserror::Error, Debug)]
+pub enum WebhookError {
+    #[error("invalid webhook URL {0}, expected http://host:port/path")]
+    InvalidUrl(String),
+    #[error("could not resolve {host}: {error}")]
+    Resolve { host: String, error: String },
+    #[error("could not connect to {address}: {error}")]
+    Connect {
+        address: SocketAddr,
+        error: std::io::Error,
+    },
+    #[error("could not send the request: {0}")]
+    Write(std::io::Error),
+    #[error("could not read the response: {0}")]
+    Read(std::io::Error),
+    #[error("the webhook answered {0}")]
+    Status(String),
+}
+
+/// the URL of a webhook, and why it could not be called
+type WebhookFailure = (String, WebhookError);
+
+/// Measures of a cluster, summed over all workers
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClusterMeasures {
+    /// requests sent to the backends since the workers started
+    pub requests: i64,
+    /// responses with a 5xx status since the workers started
+    pub errors: i64,
+    /// highest 99th percentile of the backend response times, in milliseconds
+    pub p99_latency: Option<u64>,
+}
+
+/// State of the alert rules between evaluations
+#[derive(Debug)]
+pub struct Alerts {
+    next_check: Instant,
+    /// (alert name, cluster id) of the alerts currently firing
+    firing: HashSet<(String, String)>,
+    /// cumulated requests and errors per cluster, at the previous evaluation
+    previous_counts: HashMap<String, (i64, i64)>,
+    /// backends marked as down by the workers: (backend id, address)
+    down_backends: HashSet<(String, SocketAddr)>,
+    /// events of the last evaluation, to be sent to subscribers by the command hub
+    pending_events: Vec<Event>,
+    /// webhooks are called in separate threads, which report their failures here
+    webhook_errors: (Sender<WebhookFailure>, Receiver<WebhookFailure>),
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self {
+            next_check: Instant::now(),
+            firing: HashSet::new(),
+            previous_counts: HashMap::new(),
+            down_backends: HashSet::new(),
+            pending_events: Vec::new(),
+            webhook_errors: mpsc::channel(),
+        }
+    }
+}
+
+impl Alerts {
+    /// returns true, and plans the next one, if an evaluation is due
+    pub fn check_due(&mut self, now: Instant, interval: u64) -> bool {
+        if self.next_check > now {
+            return false;
+        }
+        self.next_check = now + Duration::from_secs(interval.max(1));
+        true
+    }
+
+    /// keep track of the backends marked down or up by the workers
+    pub fn observe_event(&mut self, event: &Event) {
+        let (Some(backend_id), Some(address)) = (&event.backend_id, &event.address) else {
+            return;
+        };
+        let backend = (backend_id.to_owned(), address.clone().into());
+        match event.kind() {
+            EventKind::BackendDown | EventKind::BackendEjected => {
+                self.down_backends.insert(backend);
+            }
+            EventKind::BackendUp
+            | EventKind::BackendReinstated
+            | EventKind::RemovedBackendHasNoConnections => {
+                self.down_backends.remove(&backend);
+            }
+            _ => {}
+        }
+    }
+
+    /// events produced by the evaluations since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        for (url, error) in self.webhook_errors.1.try_iter() {
+            error!("could not call the alert webhook {}: {}", url, error);
+        }
+        std::mem::take(&mut self.pending_events)
+    }
+
+    /// compute the measures of each rule for each cluster, and fire or resolve alerts
+    pub fn evaluate(
+        &mut self,
+        rules: &[AlertConfig],
+        state: &ConfigState,
+        measures: &BTreeMap<String, ClusterMeasures>,
+    ) {
+        let error_rates = self.error_rates(measures);
+
+        for rule in rules {
+            let cluster_ids: Vec<&String> = match &rule.cluster_id {
+                Some(cluster_id) => vec![cluster_id],
+                None => state.clusters.keys().collect(),
+            };
+
+            for cluster_id in cluster_ids {
+                let value = match rule.metric {
+                    AlertMetric::ErrorRate => error_rates.get(cluster_id).copied(),
+                    AlertMetric::P99Latency => measures
+                        .get(cluster_id)
+                        .and_then(|measures| measures.p99_latency),
+                    AlertMetric::BackendAvailability => self.availability(state, cluster_id),
+                };
+                if let Some(value) = value {
+                    self.apply_rule(rule, cluster_id, value);
+                }
+            }
+        }
+    }
+
+    /// percentage of 5xx responses per cluster since the previous evaluation,
+    /// for clusters that received requests in the meantime
+    fn error_rates(
+        &mut self,
+        measures: &BTreeMap<String, ClusterMeasures>,
+    ) -> HashMap<String, u64> {
+        let mut error_rates = HashMap::new();
+
+        for (cluster_id, measures) in measures {
+            let (previous_requests, previous_errors) = self
+                .previous_counts
+                .insert(cluster_id.to_owned(), (measures.requests, measures.errors))
+                .unwrap_or_default();
+
+            // the counters start over when workers are restarted
+            let (requests, errors) = if measures.requests < previous_requests {
+                (measures.requests, measures.errors)
+            } else {
+                (
+                    measures.requests - previous_requests,
+                    (measures.errors - previous_errors).max(0),
+                )
+            };
+
+            if requests > 0 {
+                error_rates.insert(
+                    cluster_id.to_owned(),
+                    (errors.min(requests) * 100 / requests) as u64,
+                );
+            }
+        }
+        error_rates
+    }
+
+    /// percentage of the backends of a cluster that are not marked as down
+    fn availability(&self, state: &ConfigState, cluster_id: &str) -> Option<u64> {
+        let backends = state.backends.get(cluster_id)?;
+        if backends.is_empty() {
+            return None;
+        }
+        let up = backends
+            .iter()
+            .filter(|backend| {
+                !self
+                    .down_backends
+                    .contains(&(backend.backend_id.to_owned(), backend.address))
+            })
+            .count();
+
+        Some((up * 100 / backends.len()) as u64)
+    }
+
+    fn apply_rule(&mut self, rule: &AlertConfig, cluster_id: &str, value: u64) {
+        let key = (rule.name.to_owned(), cluster_id.to_owned());
+        let firing = self.firing.contains(&key);
+
+        let (breached, recovered) = if rule.metric.fires_below() {
+            (value < rule.threshold, value >= rule.recover_threshold())
+        } else {
+            (value > rule.threshold, value <= rule.recover_threshold())
+        };
+
+        let kind = match (firing, breached, recovered) {
+            (false, true, _) => {
+                warn!(
+                    "alert {} fired for cluster {}: {:?} is {} (threshold: {})",
+                    rule.name, cluster_id, rule.metric, value, rule.threshold
+                );
+                self.firing.insert(key);
+                EventKind::AlertFired
+            }
+            (true, _, true) => {
+                info!(
+                    "alert {} resolved for cluster {}: {:?} is {}",
+                    rule.name, cluster_id, rule.metric, value
+                );
+                self.firing.remove(&key);
+                EventKind::AlertResolved
+            }
+            _ => return,
+        };
+
+        if let Some(url) = &rule.webhook {
+            self.call_webhook(url.to_owned(), rule, cluster_id, kind, value);
+        }
+
+        self.pending_events.push(Event {
+            kind: kind.into(),
+            cluster_id: Some(cluster_id.to_owned()),
+            backend_id: None,
+            address: None,
+            alert: Some(rule.name.to_owned()),
+            value: Some(value),
+        });
+    }
+
+    fn call_webhook(
+        &self,
+        url: String,
+        rule: &AlertConfig,
+        cluster_id: &str,
+        kind: EventKind,
+        value: u64,
+    ) {
+        let body = serde_json::json!({
+            "alert": rule.name,
+            "status": if kind == EventKind::AlertFired { "fired" } else { "resolved" },
+            "cluster_id": cluster_id,
+            "metric": rule.metric,
+            "value": value,
+            "threshold": rule.threshold,
+            "timestamp": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })
+        .to_string();
+
+        let errors = self.webhook_errors.0.clone();
+        thread::spawn(move || {
+            if let Err(error) = post_json(&url, &body) {
+                let _ = errors.send((url, error));
+            }
+        });
+    }
+}
+
+/// sum the cluster metrics of the workers, as returned by a `QueryMetrics` request
+pub fn measures_from_responses(
+    responses: impl IntoIterator<Item = Option<ResponseContent>>,
+) -> BTreeMap<String, ClusterMeasures> {
+    let mut measures: BTreeMap<String, ClusterMeasures> = BTreeMap::new();
+
+    for content in responses {
+        let Some(ResponseContent {
+            content_type: Some(ContentType::WorkerMetrics(worker_metrics)),
+        }) = content
+        else {
+            continue;
+        };
+
+        for (cluster_id, ClusterMetrics { backends, .. }) in worker_metrics.clusters {
+            let cluster_measures = measures.entry(cluster_id).or_default();
+
+            for backend in backends {
+                for (name, metric) in backend.metrics {
+                    match (name.as_str(), metric) {
+                        (
+                            "requests",
+                            FilteredMetrics {
+                                inner: Some(Inner::Count(count)),
+                            },
+                        ) => cluster_measures.requests += count,
+                        (
+                            "http.status.5xx",
+                            FilteredMetrics {
+                                inner: Some(Inner::Count(count)),
+                            },
+                        ) => cluster_measures.errors += count,
+                        (
+                            "backend_response_time",
+                            FilteredMetrics {
+                                inner: Some(Inner::Percentiles(percentiles)),
+                            },
+                        ) => {
+                            cluster_measures.p99_latency = Some(
+                                cluster_measures
+                                    .p99_latency
+                                    .unwrap_or_default()
+                                    .max(percentiles.p_99),
+                            )
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    measures
+}
+
+/// the metrics the workers need to send for the alert rules
+pub fn alert_metric_names() -> Vec<String> {
+    vec![
+        "requests".to_owned(),
+        "http.status.5xx".to_owned(),
+        "backend_response_time".to_owned(),
+    ]
+}
+
+/// send a JSON body to a plain HTTP URL, and check that the answer is a 2xx
+fn post_json(url: &str, body: &str) -> Result<(), WebhookError> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or(WebhookError::InvalidUrl(url.to_owned()))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(WebhookError::InvalidUrl(url.to_owned()));
+    }
+    let host_and_port = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{host}:80")
+    };
+
+    let address = host_and_port
+        .to_socket_addrs()
+        .map_err(|error| WebhookError::Resolve {
+            host: host.to_owned(),
+            error: error.to_string(),
+        })?
+        .next()
+        .ok_or(WebhookError::Resolve {
+            host: host.to_owned(),
+            error: "no address found".to_owned(),
+        })?;
+
+    let mut stream = TcpStream::connect_timeout(&address, WEBHOOK_TIMEOUT)
+        .map_err(|error| WebhookError::Connect { address, error })?;
+    stream
+        .set_write_timeout(Some(WEBHOOK_TIMEOUT))
+        .and_then(|_| stream.set_read_timeout(Some(WEBHOOK_TIMEOUT)))
+        .map_err(WebhookError::Write)?;
+
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(WebhookError::Write)?;
+
+    let mut buffer = [0; 64];
+    let size = stream.read(&mut buffer).map_err(WebhookError::Read)?;
+    let status_line = String::from_utf8_lossy(&buffer[..size]);
+    let status_line = status_line.lines().next().unwrap_or_default();
+
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(WebhookError::Status(status_line.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sozu_command_lib::proto::command::{request::RequestType, AddBackend, Cluster};
+
+    fn error_rate_rule() -> AlertConfig {
+        AlertConfig {
+            name: "errors".to_owned(),
+            metric: AlertMetric::ErrorRate,
+            cluster_id: None,
+            threshold: 10,
+            recover_threshold: Some(5),
+            webhook: None,
+        }
+    }
+
+    fn measures(requests: i64, errors: i64) -> BTreeMap<String, ClusterMeasures> {
+        let mut measures = BTreeMap::new();
+        measures.insert(
+            "cluster_1".to_owned(),
+            ClusterMeasures {
+                requests,
+                errors,
+                p99_latency: None,
+            },
+        );
+        measures
+    }
+
+    fn kinds(alerts: &mut Alerts) -> Vec<EventKind> {
+        alerts
+            .take_events()
+            .iter()
+            .map(|event| event.kind())
+            .collect()
+    }
+
+    #[test]
+    fn error_rate_with_hysteresis() {
+        let mut state = ConfigState::new();
+        state
+            .dispatch(
+                &RequestType::AddCluster(Cluster {
+                    cluster_id: "cluster_1".to_owned(),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .unwrap();
+        let rules = [error_rate_rule()];
+        let mut alerts = Alerts::default();
+
+        // 20% of errors
+        alerts.evaluate(&rules, &state, &measures(100, 20));
+        assert_eq!(kinds(&mut alerts), vec![EventKind::AlertFired]);
+
+        // 8% of errors on the new requests: still above the recover threshold
+        alerts.evaluate(&rules, &state, &measures(200, 28));
+        assert!(kinds(&mut alerts).is_empty());
+
+        // no new request, nothing to evaluate
+        alerts.evaluate(&rules, &state, &measures(200, 28));
+        assert!(kinds(&mut alerts).is_empty());
+
+        // 2% of errors
+        alerts.evaluate(&rules, &state, &measures(300, 30));
+        assert_eq!(kinds(&mut alerts), vec![EventKind::AlertResolved]);
+    }
+
+    #[test]
+    fn backend_availability() {
+        let mut state = ConfigState::new();
+        for backend_id in ["backend_1", "backend_2"] {
+            state
+                .dispatch(
+                    &RequestType::AddBackend(AddBackend {
+                        cluster_id: "cluster_1".to_owned(),
+                        backend_id: backend_id.to_owned(),
+                        address: SocketAddr::from(([127, 0, 0, 1], 1026)).into(),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+        let rules = [AlertConfig {
+            name: "availability".to_owned(),
+            metric: AlertMetric::BackendAvailability,
+            cluster_id: Some("cluster_1".to_owned()),
+            threshold: 60,
+            recover_threshold: None,
+            webhook: None,
+        }];
+        let mut alerts = Alerts::default();
+        let backend_event = |kind: EventKind| Event {
+            kind: kind.into(),
+            cluster_id: None,
+            backend_id: Some("backend_1".to_owned()),
+            address: Some(SocketAddr::from(([127, 0, 0, 1], 1026)).into()),
+            alert: None,
+            value: None,
+        };
+
+        alerts.evaluate(&rules, &state, &BTreeMap::new());
+        assert!(kinds(&mut alerts).is_empty());
+
+        alerts.observe_event(&backend_event(EventKind::BackendDown));
+        alerts.evaluate(&rules, &state, &BTreeMap::new());
+        assert_eq!(kinds(&mut alerts), vec![EventKind::AlertFired]);
+
+        alerts.observe_event(&backend_event(EventKind::BackendUp));
+        alerts.evaluate(&rules, &state, &BTreeMap::new());
+        assert_eq!(kinds(&mut alerts), vec![EventKind::AlertResolved]);
+    }
+}
@@ -0,0 +1,165 @@
+//! gRPC endpoint of the main process
+//!
+//! With a `grpc` section in the configuration, the main process serves the
+//! `CommandService` of command.proto, so that orchestration tools manage Sōzu
+//! remotely with the same `Request` and `Response` messages as the unix socket.
+//! The clients authenticate with a certificate signed by the authority of the section.
+//!
+//! A thread runs the gRPC server, and relays each request on the unix socket of the
+//! main process, like `sozu` on the command line. The requests go through the same
+//! checks (dry run, expected epoch) and are answered with the final response.
+
+use std::{fs, io::Error as IoError, net::SocketAddr, thread, time::Duration};
+
+use tonic::{
+    transport::{
+        server::{Router, TcpIncoming},
+        Certificate, Identity, Server, ServerTlsConfig,
+    },
+    Status,
+};
+
+use sozu_command_lib::{
+    channel::{Channel, ChannelError},
+    config::{Config, GrpcConfig},
+    proto::command::{Request, Response, ResponseStatus},
+};
+
+mod service {
+    tonic::include_proto!("command");
+}
+
+use service::command_service_server::{CommandService, CommandServiceServer};
+
+/// delay before binding again
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(thiserror::Error, Debug)]
+pub enum GrpcError {
+    #[error("could not read {path}: {error}")]
+    ReadFile { path: String, error: IoError },
+    #[error("could not get the path of the command socket: {0}")]
+    CommandSocket(String),
+    #[error("TLS configuration error: {0}")]
+    Tls(tonic::transport::Error),
+    #[error("could not create the runtime of the gRPC server: {0}")]
+    Runtime(IoError),
+}
+
+/// relays the gRPC requests on the unix socket of the main process
+struct Relay {
+    command_socket_path: String,
+    buffer_size: u64,
+    max_buffer_size: u64,
+}
+
+#[tonic::async_trait]
+impl CommandService for Relay {
+    async fn execute(
+        &self,
+        request: tonic::Request<Request>,
+    ) -> Result<tonic::Response<Response>, Status> {
+        let peer = request
+            .remote_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        let request = request.into_inner();
+        info!(
+            "gRPC client {} sent a {} request",
+            peer,
+            request.short_name()
+        );
+
+        let path = self.command_socket_path.clone();
+        let (buffer_size, max_buffer_size) = (self.buffer_size, self.max_buffer_size);
+        tokio::task::spawn_blocking(move || relay(&path, buffer_size, max_buffer_size, request))
+            .await
+            .map_err(|error| Status::internal(error.to_string()))?
+            .map(tonic::Response::new)
+    }
+}
+
+/// send the request on the unix socket and wait for its final response,
+/// skipping the processing ones
+fn relay(
+    path: &str,
+    buffer_size: u64,
+    max_buffer_size: u64,
+    request: Request,
+) -> Result<Response, Status> {
+    let unavailable = |error: ChannelError| {
+        Status::unavailable(format!("could not reach the main process: {error}"))
+    };
+    let mut channel: Channel<Request, Response> =
+        Channel::from_path(path, buffer_size, max_buffer_size).map_err(unavailable)?;
+    channel.blocking().map_err(unavailable)?;
+    channel.write_message(&request).map_err(unavailable)?;
+
+    loop {
+        let response = channel
+            .read_message_blocking_timeout(None)
+            .map_err(unavailable)?;
+        if response.status() != ResponseStatus::Processing {
+            return Ok(response);
+        }
+    }
+}
+
+/// serve the gRPC endpoint in a separate thread
+pub fn start(grpc: &GrpcConfig, config: &Config) -> Result<(), GrpcError> {
+    let read = |path: &str| {
+        fs::read(path).map_err(|error| GrpcError::ReadFile {
+            path: path.to_owned(),
+            error,
+        })
+    };
+    let tls = ServerTlsConfig::new()
+        .identity(Identity::from_pem(
+            read(&grpc.certificate)?,
+            read(&grpc.key)?,
+        ))
+        .client_ca_root(Certificate::from_pem(read(&grpc.ca_certificate)?));
+
+    let relay = Relay {
+        command_socket_path: config
+            .command_socket_path()
+            .map_err(|error| GrpcError::CommandSocket(error.to_string()))?,
+        buffer_size: config.command_buffer_size,
+        max_buffer_size: config.max_command_buffer_size,
+    };
+    let server = Server::builder()
+        .tls_config(tls)
+        .map_err(GrpcError::Tls)?
+        .add_service(CommandServiceServer::new(relay));
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(GrpcError::Runtime)?;
+
+    let address = grpc.address;
+    thread::spawn(move || runtime.block_on(serve(server, address)));
+    Ok(())
+}
+
+async fn serve(server: Router, address: SocketAddr) {
+    // the previous main process may still listen, during an upgrade
+    let incoming = loop {
+        match TcpIncoming::new(address, true, None) {
+            Ok(incoming) => break incoming,
+            Err(error) => {
+                warn!(
+                    "could not serve the gRPC endpoint on {}: {}",
+                    address, error
+                );
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    };
+    info!("serving the gRPC endpoint on {}", address);
+
+    if let Err(error) = server.serve_with_incoming(incoming).await {
+        error!("the gRPC endpoint on {} stopped: {}", address, error);
+    }
+}
@@ -0,0 +1,655 @@
+//! Active health checks of the backends
+//!
+//! A cluster with a `health_check` gets its backends probed by the main process, every
+//! `interval` seconds, in separate threads. A probe opens a TCP connection to the
+//! backend and, if the health check has an HTTP path, sends a GET request that must be
+//! answered with a 2xx or 3xx status.
+//!
+//! A backend failing `unhealthy_threshold` probes in a row is removed from load
+//! balancing on all workers, with a `BACKEND_DOWN` event. It gets back in rotation,
+//! with a `BACKEND_UP` event, after `healthy_threshold` successful probes in a row.
+//!
+//! The health of a backend can be overridden manually, to pull it out of rotation before a
+//! maintenance, whether its cluster has health checks or not. The override takes precedence
+//! over the probes until it is set back to `AUTO`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sozu_command_lib::{
+    proto::command::{
+        BackendHealthCheck, Event, EventKind, HealthCheck, HealthOverride, SetBackendHealth,
+        SetBackendHealthOverride,
+    },
+    state::{ClusterId, ConfigState},
+};
+
+/// probes run at the same time by a probing thread
+const MAX_PARALLEL_PROBES: usize = 32;
+
+/// bytes of the answer of a backend read after its status line
+const MAX_DRAINED_SIZE: u64 = 16384;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProbeError {
+    #[error("could not connect: {0}")]
+    Connect(std::io::Error),
+    #[error("could not send the request: {0}")]
+    Write(std::io::Error),
+    #[error("could not read the response: {0}")]
+    Read(std::io::Error),
+    #[error("the backend answered {0}")]
+    Status(String),
+}
+
+/// a backend is identified by its cluster, its id and its address
+type BackendKey = (ClusterId, String, SocketAddr);
+
+/// a backend to probe, and how
+#[derive(Debug, Clone)]
+pub struct Probe {
+    backend: BackendKey,
+    timeout: Duration,
+    http_path: Option<String>,
+}
+
+type ProbeResult = (BackendKey, Result<(), ProbeError>);
+
+/// what the main process knows of a backend from its probes
+#[derive(Debug)]
+struct BackendCheck {
+    healthy: bool,
+    consecutive_successes: u32,
+    consecutive_failures: u32,
+    /// unix timestamp of the last probe, in seconds
+    last_check: Option<u64>,
+    last_error: Option<String>,
+    next_check: Instant,
+    /// a probe of the backend is running
+    probing: bool,
+}
+
+/// Health of the backends of the clusters with health checks
+#[derive(Debug)]
+pub struct HealthChecks {
+    backends: BTreeMap<BackendKey, BackendCheck>,
+    /// (cluster id, backend id) -> health forced manually, on all the addresses of the backend
+    overrides: BTreeMap<(ClusterId, String), HealthOverride>,
+    pending_events: Vec<Event>,
+    /// each probing thread sends all its results at once
+    results: (Sender<Vec<ProbeResult>>, Receiver<Vec<ProbeResult>>),
+}
+
+impl Default for HealthChecks {
+    fn default() -> Self {
+        Self {
+            backends: BTreeMap::new(),
+            overrides: BTreeMap::new(),
+            pending_events: Vec::new(),
+            results: mpsc::channel(),
+        }
+    }
+}
+
+impl HealthChecks {
+    /// the backends of the state due for a probe, that are planned for the next one.
+    /// New backends are healthy until they fail their first probes
+    pub fn due_probes(&mut self, state: &ConfigState, now: Instant) -> Vec<Probe> {
+        let mut probes = Vec::new();
+        for (cluster_id, health_check) in checked_clusters(state) {
+            for backend in state.backends.get(cluster_id).into_iter().flatten() {
+                let key = (
+                    cluster_id.to_owned(),
+                    backend.backend_id.to_owned(),
+                    backend.address,
+                );
+                let check = self
+                    .backends
+                    .entry(key.clone())
+                    .or_insert_with(|| BackendCheck {
+                        healthy: true,
+                        consecutive_successes: 0,
+                        consecutive_failures: 0,
+                        last_check: None,
+                        last_error: None,
+                        next_check: now,
+                        probing: false,
+                    });
+                if check.probing || check.next_check > now {
+                    continue;
+                }
+                check.probing = true;
+                check.next_check = now + Duration::from_secs(health_check.interval.into());
+                probes.push(Probe {
+                    backend: key,
+                    timeout: Duration::from_secs(health_check.timeout.into()),
+                    http_path: health_check.http_path.clone(),
+                });
+            }
+        }
+        probes
+    }
+
+    /// run the probes in a separate thread
+    pub fn probe(&mut self, probes: Vec<Probe>) {
+        if probes.is_empty() {
+            return;
+        }
+        let sender = self.results.0.clone();
+        thread::spawn(move || {
+            let mut results = Vec::with_capacity(probes.len());
+            for chunk in probes.chunks(MAX_PARALLEL_PROBES) {
+                thread::scope(|scope| {
+                    let handles: Vec<_> = chunk
+                        .iter()
+                        .map(|probe| scope.spawn(move || run_probe(probe)))
+                        .collect();
+                    for (probe, handle) in chunk.iter().zip(handles) {
+                        let result = handle.join().unwrap_or_else(|_| {
+                            Err(ProbeError::Status(
+                                "no answer, the probe panicked".to_owned(),
+                            ))
+                        });
+                        results.push((probe.backend.clone(), result));
+                    }
+                });
+            }
+            let _ = sender.send(results);
+        });
+    }
+
+    /// count the results of the finished probes, and return the backends that
+    /// crossed a threshold, for the workers
+    pub fn take_results(&mut self, state: &ConfigState) -> Vec<SetBackendHealth> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut changes = Vec::new();
+
+        for (key, result) in self.results.1.try_iter().flatten() {
+            // the backend or its health check may have been removed during the probe
+            let (Some(check), Some(health_check)) = (
+                self.backends.get_mut(&key),
+                state
+                    .clusters
+                    .get(&key.0)
+                    .and_then(|cluster| cluster.health_check.as_ref()),
+            ) else {
+                continue;
+            };
+            check.probing = false;
+            check.last_check = Some(now);
+
+            let (cluster_id, backend_id, address) = &key;
+            match result {
+                Ok(()) => {
+                    check.consecutive_failures = 0;
+                    check.consecutive_successes = check.consecutive_successes.saturating_add(1);
+                    check.last_error = None;
+                    if check.healthy || check.consecutive_successes < health_check.healthy_threshold
+                    {
+                        continue;
+                    }
+                    info!(
+                        "backend {} of cluster {} at {} passes its health checks again",
+                        backend_id, cluster_id, address
+                    );
+                    check.healthy = true;
+                }
+                Err(error) => {
+                    check.consecutive_successes = 0;
+                    check.consecutive_failures = check.consecutive_failures.saturating_add(1);
+                    check.last_error = Some(error.to_string());
+                    if !check.healthy
+                        || check.consecutive_failures < health_check.unhealthy_threshold
+                    {
+                        continue;
+                    }
+                    warn!(
+                        "backend {} of cluster {} at {} fails its health checks: {}",
+                        backend_id, cluster_id, address, error
+                    );
+                    check.healthy = false;
+                }
+            }
+
+            self.pending_events.push(Event {
+                kind: if check.healthy {
+                    EventKind::BackendUp
+                } else {
+                    EventKind::BackendDown
+                }
+                .into(),
+                cluster_id: Some(cluster_id.to_owned()),
+                backend_id: Some(backend_id.to_owned()),
+                address: Some((*address).into()),
+                alert: None,
+                value: None,
+            });
+            // the workers keep the health forced manually
+            if self
+                .overrides
+                .contains_key(&(cluster_id.to_owned(), backend_id.to_owned()))
+            {
+                continue;
+            }
+            changes.push(SetBackendHealth {
+                cluster_id: cluster_id.to_owned(),
+                backend_id: backend_id.to_owned(),
+                address: (*address).into(),
+                healthy: check.healthy,
+            });
+        }
+        changes
+    }
+
+    /// force the health of all the addresses of a backend, or let the health checks
+    /// decide again. Returns the backends whose health changes, for the workers,
+    /// or an error if the backend does not exist
+    pub fn set_override(
+        &mut self,
+        state: &ConfigState,
+        cluster_id: &str,
+        backend_id: &str,
+        health_override: HealthOverride,
+    ) -> Result<Vec<SetBackendHealth>, String> {
+        let addresses: Vec<SocketAddr> = state
+            .backends
+            .get(cluster_id)
+            .into_iter()
+            .flatten()
+            .filter(|backend| backend.backend_id == backend_id)
+            .map(|backend| backend.address)
+            .collect();
+        if addresses.is_empty() {
+            return Err(format!(
+                "no backend {} in cluster {}",
+                backend_id, cluster_id
+            ));
+        }
+
+        let before: Vec<bool> = addresses
+            .iter()
+            .map(|address| self.health_of(cluster_id, backend_id, address))
+            .collect();
+        let key = (cluster_id.to_owned(), backend_id.to_owned());
+        match health_override {
+            HealthOverride::Auto => self.overrides.remove(&key),
+            state => self.overrides.insert(key, state),
+        };
+
+        let mut changes = Vec::new();
+        for (address, healthy_before) in addresses.into_iter().zip(before) {
+            self.pending_events.push(Event {
+                kind: EventKind::BackendHealthOverridden.into(),
+                cluster_id: Some(cluster_id.to_owned()),
+                backend_id: Some(backend_id.to_owned()),
+                address: Some(address.into()),
+                alert: None,
+                value: Some(health_override as u64),
+            });
+            let healthy = self.health_of(cluster_id, backend_id, &address);
+            if healthy != healthy_before {
+                changes.push(SetBackendHealth {
+                    cluster_id: cluster_id.to_owned(),
+                    backend_id: backend_id.to_owned(),
+                    address: address.into(),
+                    healthy,
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    /// the overrides, to carry them over to a new main process
+    pub fn overrides(&self) -> Vec<SetBackendHealthOverride> {
+        self.overrides
+            .iter()
+            .map(
+                |((cluster_id, backend_id), state)| SetBackendHealthOverride {
+                    cluster_id: cluster_id.to_owned(),
+                    backend_id: backend_id.to_owned(),
+                    state: (*state).into(),
+                },
+            )
+            .collect()
+    }
+
+    /// restore the overrides of a previous main process, the workers already apply them
+    pub fn restore_overrides(&mut self, overrides: Vec<SetBackendHealthOverride>) {
+        for set in overrides {
+            if let Ok(state @ (HealthOverride::Up | HealthOverride::Down)) =
+                HealthOverride::try_from(set.state)
+            {
+                self.overrides
+                    .insert((set.cluster_id, set.backend_id), state);
+            }
+        }
+    }
+
+    /// the health applied by the workers: the override, or the result of the probes
+    fn health_of(&self, cluster_id: &str, backend_id: &str, address: &SocketAddr) -> bool {
+        match self
+            .overrides
+            .get(&(cluster_id.to_owned(), backend_id.to_owned()))
+        {
+            Some(HealthOverride::Up) => true,
+            Some(HealthOverride::Down) => false,
+            _ => self
+                .backends
+                .get(&(cluster_id.to_owned(), backend_id.to_owned(), *address))
+                .map_or(true, |check| check.healthy),
+        }
+    }
+
+    /// forget the backends removed from the state, or whose cluster has no health
+    /// check anymore. Returns the unhealthy backends to put back in rotation
+    pub fn prune(&mut self, state: &ConfigState) -> Vec<SetBackendHealth> {
+        self.overrides.retain(|(cluster_id, backend_id), _| {
+            state.backends.get(cluster_id).is_some_and(|backends| {
+                backends
+                    .iter()
+                    .any(|backend| &backend.backend_id == backend_id)
+            })
+        });
+
+        let mut reinstated = Vec::new();
+        let overrides = &self.overrides;
+        self.backends
+            .retain(|(cluster_id, backend_id, address), check| {
+                let backend_exists = state.backends.get(cluster_id).is_some_and(|backends| {
+                    backends.iter().any(|backend| {
+                        &backend.backend_id == backend_id && &backend.address == address
+                    })
+                });
+                let checked = state
+                    .clusters
+                    .get(cluster_id)
+                    .is_some_and(|cluster| cluster.health_check.is_some());
+                let overridden =
+                    overrides.contains_key(&(cluster_id.to_owned(), backend_id.to_owned()));
+                if backend_exists && !checked && !check.healthy && !overridden {
+                    reinstated.push(SetBackendHealth {
+                        cluster_id: cluster_id.to_owned(),
+                        backend_id: backend_id.to_owned(),
+                        address: (*address).into(),
+                        healthy: true,
+                    });
+                }
+                backend_exists && checked
+            });
+        reinstated
+    }
+
+    /// the health of all checked or overridden backends, to bring a worker up to date
+    pub fn backend_health(&self, state: &ConfigState) -> Vec<SetBackendHealth> {
+        self.statuses(state, None)
+            .into_iter()
+            .map(|status| SetBackendHealth {
+                healthy: match status.health_override.map(HealthOverride::try_from) {
+                    Some(Ok(HealthOverride::Up)) => true,
+                    Some(Ok(HealthOverride::Down)) => false,
+                    _ => status.healthy,
+                },
+                cluster_id: status.cluster_id,
+                backend_id: status.backend_id,
+                address: status.address,
+            })
+            .collect()
+    }
+
+    /// the results of the probes of all backends, or of the ones of a cluster,
+    /// along with the backends whose health is overridden
+    pub fn statuses(
+        &self,
+        state: &ConfigState,
+        cluster_id: Option<&str>,
+    ) -> Vec<BackendHealthCheck> {
+        let mut statuses: BTreeMap<BackendKey, BackendHealthCheck> = self
+            .backends
+            .iter()
+            .filter(|((id, _, _), _)| cluster_id.map_or(true, |cluster_id| cluster_id == id))
+            .map(|(key, check)| {
+                let (cluster_id, backend_id, address) = key;
+                let status = BackendHealthCheck {
+                    cluster_id: cluster_id.to_owned(),
+                    backend_id: backend_id.to_owned(),
+                    address: (*address).into(),
+                    healthy: check.healthy,
+                    consecutive_successes: check.consecutive_successes,
+                    consecutive_failures: check.consecutive_failures,
+                    last_check: check.last_check,
+                    last_error: check.last_error.clone(),
+                    health_override: None,
+                };
+                (key.clone(), status)
+            })
+            .collect();
+
+        for ((id, backend_id), health_override) in &self.overrides {
+            if cluster_id.is_some_and(|cluster_id| cluster_id != id) {
+                continue;
+            }
+            let backends = state.backends.get(id).into_iter().flatten();
+            for backend in backends.filter(|backend| &backend.backend_id == backend_id) {
+                let key = (id.to_owned(), backend_id.to_owned(), backend.address);
+                statuses
+                    .entry(key)
+                    // the cluster has no health checks
+                    .or_insert_with(|| BackendHealthCheck {
+                        cluster_id: id.to_owned(),
+                        backend_id: backend_id.to_owned(),
+                        address: backend.address.into(),
+                        healthy: true,
+                        consecutive_successes: 0,
+                        consecutive_failures: 0,
+                        last_check: None,
+                        last_error: None,
+                        health_override: None,
+                    })
+                    .health_override = Some((*health_override).into());
+            }
+        }
+        statuses.into_values().collect()
+    }
+
+    /// events produced by the probes since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.pending_events)
+    }
+}
+
+fn checked_clusters(state: &ConfigState) -> impl Iterator<Item = (&ClusterId, &HealthCheck)> {
+    state.clusters.iter().filter_map(|(cluster_id, cluster)| {
+        cluster
+            .health_check
+            .as_ref()
+            .map(|health_check| (cluster_id, health_check))
+    })
+}
+
+/// connect to the backend and, with an HTTP path, check the status of its answer
+fn run_probe(probe: &Probe) -> Result<(), ProbeError> {
+    let address = probe.backend.2;
+    let mut stream =
+        TcpStream::connect_timeout(&address, probe.timeout).map_err(ProbeError::Connect)?;
+    let Some(path) = &probe.http_path else {
+        return Ok(());
+    };
+    stream
+        .set_write_timeout(Some(probe.timeout))
+        .and_then(|_| stream.set_read_timeout(Some(probe.timeout)))
+        .map_err(ProbeError::Write)?;
+
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {address}\r\nUser-Agent: sozu-health-check\r\nConnection: close\r\n\r\n"
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(ProbeError::Write)?;
+
+    let mut buffer = [0; 64];
+    let size = stream.read(&mut buffer).map_err(ProbeError::Read)?;
+    let status_line = String::from_utf8_lossy(&buffer[..size]);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    // read the rest of a short answer, so that closing the connection does not reset it
+    let _ = io::copy(&mut (&mut stream).take(MAX_DRAINED_SIZE), &mut io::sink());
+
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') || status.starts_with('3') => Ok(()),
+        Some(status) => Err(ProbeError::Status(status.to_owned())),
+        None => Err(ProbeError::Status(format!("{status_line:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use sozu_command_lib::proto::command::{
+        request::RequestType, AddBackend, Cluster, LoadBalancingParams,
+    };
+
+    use super::*;
+
+    fn state_with_health_check(
+        address: SocketAddr,
+        health_check: Option<HealthCheck>,
+    ) -> ConfigState {
+        let mut state = ConfigState::new();
+        state
+            .dispatch(
+                &RequestType::AddCluster(Cluster {
+                    cluster_id: "app".to_owned(),
+                    health_check,
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .unwrap();
+        state
+            .dispatch(
+                &RequestType::AddBackend(AddBackend {
+                    cluster_id: "app".to_owned(),
+                    backend_id: "app-0".to_owned(),
+                    address: address.into(),
+                    load_balancing_parameters: Some(LoadBalancingParams::default()),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .unwrap();
+        state
+    }
+
+    fn wait_for_results(checks: &mut HealthChecks, state: &ConfigState) -> Vec<SetBackendHealth> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while checks.backends.values().any(|check| check.probing) && Instant::now() < deadline {
+            let changes = checks.take_results(state);
+            if !changes.is_empty() {
+                return changes;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Vec::new()
+    }
+
+    #[test]
+    fn mark_a_backend_unhealthy_then_healthy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let state = state_with_health_check(
+            address,
+            Some(HealthCheck {
+                interval: 1,
+                timeout: 1,
+                healthy_threshold: 1,
+                unhealthy_threshold: 1,
+                ..Default::default()
+            }),
+        );
+        let mut checks = HealthChecks::default();
+
+        let now = Instant::now();
+        let probes = checks.due_probes(&state, now);
+        assert_eq!(probes.len(), 1);
+        assert!(checks.due_probes(&state, now).is_empty());
+        checks.probe(probes);
+        let changes = wait_for_results(&mut checks, &state);
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].healthy);
+        let events = checks.take_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind(), EventKind::BackendDown);
+
+        let listener = TcpListener::bind(address).unwrap();
+        let probes = checks.due_probes(&state, now + Duration::from_secs(1));
+        checks.probe(probes);
+        let changes = wait_for_results(&mut checks, &state);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].healthy);
+        assert_eq!(checks.take_events()[0].kind(), EventKind::BackendUp);
+        assert!(checks.statuses(&state, Some("app"))[0].healthy);
+        drop(listener);
+
+        checks
+            .backends
+            .values_mut()
+            .for_each(|check| check.healthy = false);
+        let unchecked = state_with_health_check(address, None);
+        let reinstated = checks.prune(&unchecked);
+        assert_eq!(reinstated.len(), 1);
+        assert!(reinstated[0].healthy);
+        assert!(checks.statuses(&unchecked, None).is_empty());
+    }
+
+    #[test]
+    fn override_the_health_of_a_backend() {
+        let address = "127.0.0.1:1026".parse().unwrap();
+        let state = state_with_health_check(address, None);
+        let mut checks = HealthChecks::default();
+
+        assert!(checks
+            .set_override(&state, "app", "unknown", HealthOverride::Down)
+            .is_err());
+
+        let changes = checks
+            .set_override(&state, "app", "app-0", HealthOverride::Down)
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].healthy);
+        let events = checks.take_events();
+        assert_eq!(events[0].kind(), EventKind::BackendHealthOverridden);
+        assert_eq!(events[0].value, Some(HealthOverride::Down as u64));
+
+        let statuses = checks.statuses(&state, Some("app"));
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(
+            statuses[0].health_override,
+            Some(HealthOverride::Down.into())
+        );
+        assert!(!checks.backend_health(&state)[0].healthy);
+
+        // already out of rotation
+        assert!(checks
+            .set_override(&state, "app", "app-0", HealthOverride::Down)
+            .unwrap()
+            .is_empty());
+
+        let changes = checks
+            .set_override(&state, "app", "app-0", HealthOverride::Auto)
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].healthy);
+        assert!(checks.statuses(&state, None).is_empty());
+    }
+}
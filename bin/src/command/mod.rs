@@ -1,6 +1,16 @@
+mod acme;
+mod alerts;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod health_checks;
+mod prometheus;
+mod readiness;
+mod remote_write;
+mod replication;
 mod requests;
 pub mod server;
 pub mod sessions;
+mod srv;
 pub mod upgrade;
 
 use std::{
@@ -11,12 +21,17 @@ use mio::net::UnixListener;
 
 use sozu_command_lib::{
     config::{Config, ConfigError},
-    logging::setup_logging_with_config,
+    logging::{generation_tag, setup_logging_with_config},
 };
 
 use crate::{
     cli::Args,
-    command::{requests::load_static_config, server::CommandHub},
+    command::{
+        readiness::Readiness,
+        replication::{ReplicationError, ReplicationSetup},
+        requests::load_static_config,
+        server::CommandHub,
+    },
     util::{get_config_file_path, get_executable_path, setup_metrics, write_pid_file, UtilError},
 };
 
@@ -52,6 +67,8 @@ pub enum StartError {
     SetPermissions(IoError),
     #[error("could not launch new worker: {0}")]
     LaunchWorker(ServerError),
+    #[error("could not set up the replication of the state: {0}")]
+    SetupReplication(ReplicationError),
 }
 
 pub fn begin_main_process(args: &Args) -> Result<(), StartError> {
@@ -59,9 +76,10 @@ pub fn begin_main_process(args: &Args) -> Result<(), StartError> {
 
     let config = Config::load_from_path(config_file_path).map_err(StartError::LoadConfig)?;
 
-    setup_logging_with_config(&config, "MAIN");
+    // a cold start is the first generation, each upgrade of the main process increments it
+    setup_logging_with_config(&config, &generation_tag("MAIN", 0));
     info!("Starting up");
-    setup_metrics(&config).map_err(StartError::SetupMetrics)?;
+    setup_metrics(&config, 0).map_err(StartError::SetupMetrics)?;
     write_pid_file(&config).map_err(StartError::WritePidFile)?;
 
     update_process_limits(&config)?;
@@ -84,6 +102,13 @@ pub fn begin_main_process(args: &Args) -> Result<(), StartError> {
     fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
         .map_err(StartError::SetPermissions)?;
 
+    let replication = config
+        .replication
+        .as_ref()
+        .map(ReplicationSetup::new)
+        .transpose()
+        .map_err(StartError::SetupReplication)?;
+
     // Create a copy of the state path to load state later
     let saved_state_path = config.saved_state.clone();
     let worker_count = config.worker_count;
@@ -99,6 +124,11 @@ pub fn begin_main_process(args: &Args) -> Result<(), StartError> {
             .map_err(StartError::LaunchWorker)?;
     }
 
+    if let Some(readiness) = &command_hub.server.config.readiness {
+        info!("Listeners will be activated once the proxy is ready");
+        command_hub.server.readiness = Readiness::gate(readiness);
+    }
+
     info!("Load static configuration");
     load_static_config(&mut command_hub.server, None, None);
 
@@ -106,6 +136,16 @@ pub fn begin_main_process(args: &Args) -> Result<(), StartError> {
         requests::load_state(&mut command_hub.server, None, &path);
     }
 
+    if let Some(replication) = replication {
+        let server = &mut command_hub.server;
+        server.replication.start(replication, &server.state);
+    }
+
+    command_hub.server.start_prometheus_endpoint();
+    command_hub.server.start_remote_write();
+    command_hub.server.start_grpc_endpoint();
+    requests::start_acme(&mut command_hub.server);
+
     command_hub.run();
 
     info!("main process stopped");
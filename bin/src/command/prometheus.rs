@@ -0,0 +1,380 @@
+//! Prometheus endpoint of the main process
+//!
+//! With a `prometheus_address` in the `metrics` section, the main process answers
+//! `GET /metrics` with the metrics of the main process and of all workers, in the
+//! Prometheus text exposition format. A thread accepts the scrapers and passes each
+//! scrape to the main process, which queries the workers at its next periodic check,
+//! and sends the rendered metrics back to the thread.
+//!
+//! Counts become counters, gauges and times become gauges, and the time and size
+//! distributions become summaries. The series are labeled with the `process` they come
+//! from, and with the `cluster_id` and `backend_id` they measure.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Error as IoError, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use sozu_command_lib::proto::command::{
+    filtered_metrics::Inner, AggregatedMetrics, FilteredMetrics,
+};
+
+/// how long a scraper may take to send its request, or to read the response
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how long a scraper waits for the main process and the workers
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// delay before binding again
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// requests longer than this are cut
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// a scrape waiting for the main process, which sends the rendered metrics on it
+pub type Scrape = Sender<String>;
+
+/// Scrapes received by the thread serving the endpoint
+#[derive(Debug, Default)]
+pub struct PrometheusEndpoint {
+    scrapes: Option<Receiver<Scrape>>,
+}
+
+impl PrometheusEndpoint {
+    /// serve the endpoint on this address, in a separate thread
+    pub fn start(&mut self, address: SocketAddr) {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || accept_scrapers(address, sender));
+        self.scrapes = Some(receiver);
+    }
+
+    /// the scrapes received since the last call
+    pub fn take_scrapes(&mut self) -> Vec<Scrape> {
+        match &self.scrapes {
+            Some(scrapes) => scrapes.try_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+fn accept_scrapers(address: SocketAddr, scrapes: Sender<Scrape>) {
+    // the previous main process may still listen, during an upgrade
+    let listener = loop {
+        match TcpListener::bind(address) {
+            Ok(listener) => break listener,
+            Err(error) => {
+                warn!(
+                    "could not serve the Prometheus metrics on {}: {}",
+                    address, error
+                );
+                thread::sleep(RETRY_DELAY);
+            }
+        }
+    };
+    info!("serving the Prometheus metrics on {}", address);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                warn!("could not accept a Prometheus scraper: {}", error);
+                continue;
+            }
+        };
+        if let Err(error) = serve_scraper(stream, &scrapes) {
+            debug!("could not answer a Prometheus scraper: {}", error);
+        }
+    }
+}
+
+fn serve_scraper(mut stream: TcpStream, scrapes: &Sender<Scrape>) -> Result<(), IoError> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let (reply, rendered) = mpsc::channel();
+            if scrapes.send(reply).is_err() {
+                return Ok(());
+            }
+            match rendered.recv_timeout(SCRAPE_TIMEOUT) {
+                Ok(metrics) => ("200 OK", metrics),
+                Err(_) => (
+                    "503 Service Unavailable",
+                    "the workers did not send their metrics in time\n".to_owned(),
+                ),
+            }
+        }
+        (Some("GET"), _) => ("404 Not Found", "the metrics are on /metrics\n".to_owned()),
+        _ => ("405 Method Not Allowed", "only GET is allowed\n".to_owned()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// the samples of a metric, under a single TYPE line
+#[derive(Debug)]
+struct Family {
+    kind: &'static str,
+    samples: String,
+}
+
+/// a sample of the metrics, as exposed to Prometheus
+#[derive(Debug)]
+pub struct Sample<'a> {
+    /// name of the metric, like "sozu_backend_response_time"
+    pub family: &'a str,
+    pub kind: &'static str,
+    /// name of the series, like "sozu_backend_response_time_sum" for a summary
+    pub name: &'a str,
+    pub labels: &'a [(&'a str, &'a str)],
+    pub value: f64,
+}
+
+/// render the metrics of the main process and of the workers in the Prometheus text
+/// format, with metric names starting with the prefix
+pub fn render(metrics: &AggregatedMetrics, prefix: &str) -> String {
+    let mut families = BTreeMap::new();
+
+    visit_samples(metrics, prefix, &mut |sample| {
+        let family = families
+            .entry(sample.family.to_owned())
+            .or_insert_with(|| Family {
+                kind: sample.kind,
+                samples: String::new(),
+            });
+        // the same key may not have the same type in all processes
+        if family.kind == sample.kind {
+            write_sample(
+                &mut family.samples,
+                sample.name,
+                sample.labels,
+                sample.value,
+            );
+        }
+    });
+
+    let mut rendered = String::new();
+    for (name, family) in families {
+        let _ = writeln!(rendered, "# TYPE {name} {}", family.kind);
+        rendered.push_str(&family.samples);
+    }
+    rendered
+}
+
+/// call the visitor with every sample of the main process and of the workers
+pub fn visit_samples(metrics: &AggregatedMetrics, prefix: &str, visit: &mut dyn FnMut(Sample<'_>)) {
+    for (key, value) in &metrics.main {
+        visit_metric(prefix, key, &[("process", "main")], value, visit);
+    }
+
+    for (worker_id, worker) in &metrics.workers {
+        let process = format!("worker-{worker_id}");
+        for (key, value) in &worker.proxy {
+            visit_metric(prefix, key, &[("process", &process)], value, visit);
+        }
+        for (cluster_id, cluster) in &worker.clusters {
+            let labels = [("process", process.as_str()), ("cluster_id", cluster_id)];
+            for (key, value) in &cluster.cluster {
+                visit_metric(prefix, key, &labels, value, visit);
+            }
+            for backend in &cluster.backends {
+                let labels = [
+                    ("process", process.as_str()),
+                    ("cluster_id", cluster_id),
+                    ("backend_id", &backend.backend_id),
+                ];
+                for (key, value) in &backend.metrics {
+                    visit_metric(prefix, key, &labels, value, visit);
+                }
+            }
+        }
+    }
+}
+
+fn visit_metric(
+    prefix: &str,
+    key: &str,
+    labels: &[(&str, &str)],
+    value: &FilteredMetrics,
+    visit: &mut dyn FnMut(Sample<'_>),
+) {
+    let Some(inner) = &value.inner else {
+        return;
+    };
+    let family = metric_name(prefix, key);
+    let kind = match inner {
+        Inner::Count(_) => "counter",
+        Inner::Gauge(_) | Inner::Time(_) | Inner::TimeSerie(_) => "gauge",
+        Inner::Percentiles(_) => "summary",
+    };
+    let mut sample = |name: &str, labels: &[(&str, &str)], value: f64| {
+        visit(Sample {
+            family: &family,
+            kind,
+            name,
+            labels,
+            value,
+        })
+    };
+
+    match inner {
+        Inner::Count(count) => sample(&family, labels, *count as f64),
+        Inner::Gauge(gauge) => sample(&family, labels, *gauge as f64),
+        Inner::Time(time) => sample(&family, labels, *time as f64),
+        // requests of the last second
+        Inner::TimeSerie(serie) => sample(&family, labels, serie.last_second as f64),
+        Inner::Percentiles(percentiles) => {
+            for (quantile, value) in [
+                ("0.5", percentiles.p_50),
+                ("0.9", percentiles.p_90),
+                ("0.99", percentiles.p_99),
+                ("0.999", percentiles.p_99_9),
+                ("0.9999", percentiles.p_99_99),
+                ("0.99999", percentiles.p_99_999),
+                ("1", percentiles.p_100),
+            ] {
+                let mut quantile_labels = labels.to_vec();
+                quantile_labels.push(("quantile", quantile));
+                sample(&family, &quantile_labels, value as f64);
+            }
+            sample(&format!("{family}_sum"), labels, percentiles.sum as f64);
+            sample(
+                &format!("{family}_count"),
+                labels,
+                percentiles.samples as f64,
+            );
+        }
+    }
+}
+
+fn write_sample(samples: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    samples.push_str(name);
+    for (index, (label, label_value)) in labels.iter().enumerate() {
+        samples.push(if index == 0 { '{' } else { ',' });
+        let _ = write!(samples, "{label}=\"{}\"", escape_label_value(label_value));
+    }
+    if !labels.is_empty() {
+        samples.push('}');
+    }
+    let _ = writeln!(samples, " {value}");
+}
+
+/// "http.status.2xx" becomes "sozu_http_status_2xx"
+fn metric_name(prefix: &str, key: &str) -> String {
+    format!("{prefix}_{key}")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use sozu_command_lib::proto::command::{
+        BackendMetrics, ClusterMetrics, Percentiles, WorkerMetrics,
+    };
+
+    use super::*;
+
+    fn metric(inner: Inner) -> FilteredMetrics {
+        FilteredMetrics { inner: Some(inner) }
+    }
+
+    #[test]
+    fn render_the_text_format() {
+        let backend = BackendMetrics {
+            backend_id: "app-0".to_owned(),
+            metrics: BTreeMap::from([(
+                "backend_response_time".to_owned(),
+                metric(Inner::Percentiles(Percentiles {
+                    samples: 4,
+                    p_50: 10,
+                    p_90: 20,
+                    p_99: 30,
+                    p_99_9: 30,
+                    p_99_99: 30,
+                    p_99_999: 30,
+                    p_100: 30,
+                    sum: 70,
+                })),
+            )]),
+        };
+        let worker = WorkerMetrics {
+            proxy: BTreeMap::from([("http.requests".to_owned(), metric(Inner::Count(12)))]),
+            clusters: BTreeMap::from([(
+                "app".to_owned(),
+                ClusterMetrics {
+                    cluster: BTreeMap::from([(
+                        "http.status.2xx".to_owned(),
+                        metric(Inner::Count(3)),
+                    )]),
+                    backends: vec![backend],
+                },
+            )]),
+        };
+        let metrics = AggregatedMetrics {
+            main: BTreeMap::from([("workers.running".to_owned(), metric(Inner::Gauge(2)))]),
+            workers: BTreeMap::from([("0".to_owned(), worker)]),
+        };
+
+        let rendered = render(&metrics, "sozu");
+        assert_eq!(
+            rendered.lines().collect::<Vec<_>>(),
+            vec![
+                "# TYPE sozu_backend_response_time summary",
+                r#"sozu_backend_response_time{process="worker-0",cluster_id="app",backend_id="app-0",quantile="0.5"} 10"#,
+                r#"sozu_backend_response_time{process="worker-0",cluster_id="app",backend_id="app-0",quantile="0.9"} 20"#,
+                r#"sozu_backend_response_time{process="worker-0",cluster_id="app",backend_id="app-0",quantile="0.99"} 30"#,
+                r#"sozu_backend_response_time{process="worker-0",cluster_id="app",backend_id="app-0",quantile="0.999"} 30"#,
+                r#"sozu_backend_response_time{process="worker-0",cluster_id="app",backend_id="app-0",quantile="0.9999"} 30"#,
+                r#"sozu_backend_response_time{process="worker-0",cluster_id="app",backend_id="app-0",quantile="0.99999"} 30"#,
+                r#"sozu_backend_response_time{process="worker-0",cluster_id="app",backend_id="app-0",quantile="1"} 30"#,
+                r#"sozu_backend_response_time_sum{process="worker-0",cluster_id="app",backend_id="app-0"} 70"#,
+                r#"sozu_backend_response_time_count{process="worker-0",cluster_id="app",backend_id="app-0"} 4"#,
+                "# TYPE sozu_http_requests counter",
+                r#"sozu_http_requests{process="worker-0"} 12"#,
+                "# TYPE sozu_http_status_2xx counter",
+                r#"sozu_http_status_2xx{process="worker-0",cluster_id="app"} 3"#,
+                "# TYPE sozu_workers_running gauge",
+                r#"sozu_workers_running{process="main"} 2"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+        assert_eq!(metric_name("sozu-proxy", "bytes.in"), "sozu_proxy_bytes_in");
+    }
+}
@@ -0,0 +1,254 @@
+//! Activation of the listeners once the proxy is ready to serve
+//!
+//! With a `readiness` section in the configuration, the main process holds back the
+//! activations of listeners found in the configuration and in the saved state at
+//! startup. It activates all of them at once when every worker acknowledged the
+//! configuration and the saved state, and each critical cluster has a backend that
+//! accepts connections, or when the readiness timeout is over.
+//!
+//! The backends of the critical clusters are probed with a TCP connection, in a
+//! separate thread, whether their clusters have health checks or not.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{SocketAddr, TcpStream},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use sozu_command_lib::{
+    config::ReadinessConfig,
+    proto::command::{request::RequestType, Readiness as ReadinessStatus, Request},
+    state::ClusterId,
+};
+
+use crate::command::server::TaskId;
+
+/// how long a backend may take to accept the connection of a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// what the activation of the listeners waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waiting {
+    /// the workers did not acknowledge the whole initial state yet
+    Workers,
+    /// some critical clusters have no live backend yet
+    Backends,
+}
+
+impl fmt::Display for Waiting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Waiting::Workers => write!(f, "the workers to acknowledge the initial state"),
+            Waiting::Backends => write!(f, "a live backend in each critical cluster"),
+        }
+    }
+}
+
+/// Progress towards the activation of the listeners held back at startup
+#[derive(Debug)]
+pub struct Readiness {
+    /// the listeners were gated at startup
+    enabled: bool,
+    /// false if the listeners are not gated, and once they are activated
+    gating: bool,
+    /// the listeners are activated anyway after this instant
+    deadline: Instant,
+    /// tasks loading the configuration and the saved state, until all workers answered
+    loading: HashSet<TaskId>,
+    /// activations of listeners, sent to the workers once ready
+    held_activations: Vec<Request>,
+    critical_clusters: Vec<ClusterId>,
+    /// critical clusters where a probe reached a backend
+    live_clusters: HashSet<ClusterId>,
+    /// a probe runs in a separate thread
+    probing: bool,
+    /// the thread sends the clusters with a live backend, all at once
+    results: (Sender<Vec<ClusterId>>, Receiver<Vec<ClusterId>>),
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gating: false,
+            deadline: Instant::now(),
+            loading: HashSet::new(),
+            held_activations: Vec::new(),
+            critical_clusters: Vec::new(),
+            live_clusters: HashSet::new(),
+            probing: false,
+            results: mpsc::channel(),
+        }
+    }
+}
+
+impl Readiness {
+    /// hold back the activations of listeners until the proxy is ready
+    pub fn gate(config: &ReadinessConfig) -> Self {
+        Self {
+            enabled: true,
+            gating: true,
+            deadline: Instant::now() + Duration::from_secs(config.timeout),
+            critical_clusters: config.critical_clusters.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_gating(&self) -> bool {
+        self.gating
+    }
+
+    /// keeps the activation of a listener while gating, returns the other requests
+    pub fn hold(&mut self, request: Request) -> Option<Request> {
+        match request.request_type {
+            Some(RequestType::ActivateListener(_)) if self.gating => {
+                self.held_activations.push(request);
+                None
+            }
+            _ => Some(request),
+        }
+    }
+
+    /// wait for all workers to answer this task before activating the listeners
+    pub fn track_loading(&mut self, task_id: TaskId) {
+        if self.gating {
+            self.loading.insert(task_id);
+        }
+    }
+
+    pub fn loading_finished(&mut self, task_id: TaskId) {
+        self.loading.remove(&task_id);
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    pub fn clusters_without_backend(&self) -> Vec<ClusterId> {
+        self.critical_clusters
+            .iter()
+            .filter(|cluster_id| !self.live_clusters.contains(*cluster_id))
+            .cloned()
+            .collect()
+    }
+
+    /// None once nothing prevents the activation of the listeners
+    pub fn waiting_for(&self) -> Option<Waiting> {
+        if !self.loading.is_empty() {
+            Some(Waiting::Workers)
+        } else if !self.clusters_without_backend().is_empty() {
+            Some(Waiting::Backends)
+        } else {
+            None
+        }
+    }
+
+    /// try to connect to the backends of each cluster in a separate thread,
+    /// unless the previous probe is still running
+    pub fn probe(&mut self, backends: Vec<(ClusterId, Vec<SocketAddr>)>) {
+        if self.probing || backends.is_empty() {
+            return;
+        }
+        self.probing = true;
+        let sender = self.results.0.clone();
+        thread::spawn(move || {
+            let live = backends
+                .into_iter()
+                .filter(|(_, addresses)| {
+                    addresses
+                        .iter()
+                        .any(|address| TcpStream::connect_timeout(address, PROBE_TIMEOUT).is_ok())
+                })
+                .map(|(cluster_id, _)| cluster_id)
+                .collect();
+            let _ = sender.send(live);
+        });
+    }
+
+    /// record the clusters found live by the last probe, once it is over
+    pub fn take_probe_results(&mut self) {
+        if let Ok(live) = self.results.1.try_recv() {
+            self.probing = false;
+            self.live_clusters.extend(live);
+        }
+    }
+
+    /// stop gating, and return the activations held back until now
+    pub fn take_activations(&mut self) -> Vec<Request> {
+        self.gating = false;
+        self.loading.clear();
+        std::mem::take(&mut self.held_activations)
+    }
+
+    /// the status of the gating, None if the listeners were not gated
+    pub fn status(&self) -> Option<ReadinessStatus> {
+        if !self.enabled {
+            return None;
+        }
+        Some(ReadinessStatus {
+            ready: !self.gating,
+            waiting_for: self
+                .waiting_for()
+                .filter(|_| self.gating)
+                .map(|waiting| waiting.to_string()),
+            clusters_without_backend: if self.gating {
+                self.clusters_without_backend()
+            } else {
+                Vec::new()
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sozu_command_lib::proto::command::{ActivateListener, ListenerType, SocketAddress};
+
+    use super::*;
+
+    fn activation() -> Request {
+        RequestType::ActivateListener(ActivateListener {
+            address: SocketAddress::new_v4(127, 0, 0, 1, 8080),
+            proxy: ListenerType::Http.into(),
+            from_scm: false,
+        })
+        .into()
+    }
+
+    #[test]
+    fn hold_activations_until_ready() {
+        let mut readiness = Readiness::gate(&ReadinessConfig {
+            critical_clusters: vec!["app".to_owned()],
+            timeout: 30,
+        });
+        assert!(readiness.hold(activation()).is_none());
+        assert!(readiness
+            .hold(RequestType::Status(Default::default()).into())
+            .is_some());
+
+        readiness.track_loading(1);
+        assert_eq!(readiness.waiting_for(), Some(Waiting::Workers));
+        readiness.loading_finished(1);
+        assert_eq!(readiness.waiting_for(), Some(Waiting::Backends));
+        assert_eq!(readiness.clusters_without_backend(), vec!["app".to_owned()]);
+
+        readiness.live_clusters.insert("app".to_owned());
+        assert_eq!(readiness.waiting_for(), None);
+        assert_eq!(readiness.take_activations(), vec![activation()]);
+        assert!(!readiness.is_gating());
+        assert!(readiness.hold(activation()).is_some());
+        assert_eq!(readiness.status().map(|status| status.ready), Some(true));
+    }
+
+    #[test]
+    fn no_gating_by_default() {
+        let mut readiness = Readiness::default();
+        assert!(readiness.hold(activation()).is_some());
+        readiness.track_loading(1);
+        assert_eq!(readiness.waiting_for(), None);
+        assert_eq!(readiness.status(), None);
+    }
+}
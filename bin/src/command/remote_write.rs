@@ -0,0 +1,726 @@
+//! Prometheus remote write from the main process
+//!
+//! With a `remote_write_url` in the `metrics` section, the main process gathers the
+//! metrics of all processes every `remote_write_interval` seconds, like for a scrape,
+//! and pushes them to the URL with the Prometheus remote write protocol: a `WriteRequest`
+//! in protobuf, compressed with snappy, in a POST request.
+//!
+//! A thread sends the pushes. When the endpoint is unreachable, or answers with a 5xx
+//! or 429 status, the thread retries with an exponential backoff, while the next pushes
+//! wait in a queue of `remote_write_queue_size` pushes, the oldest being dropped beyond.
+//! Once the endpoint is back, the waiting pushes are sent together. Pushes rejected with
+//! another status are dropped, they would never be accepted.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{Error as IoError, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use prost::Message;
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use sozu_command_lib::{config::MetricsConfig, proto::command::AggregatedMetrics};
+
+use crate::command::prometheus::visit_samples;
+
+/// how long the endpoint may take to accept the connection, receive a push and answer
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// first delay before sending a push again
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// the delay doubles at each failure, up to this one
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// waiting pushes sent together in a single request
+const MAX_PUSHES_PER_REQUEST: usize = 10;
+
+/// only the start of the responses is read, for the status and the error message
+const MAX_RESPONSE_SIZE: u64 = 4096;
+
+const SYSTEM_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+#[derive(thiserror::Error, Debug)]
+pub enum PushError {
+    #[error("could not resolve {host}: {error}")]
+    Resolve { host: String, error: String },
+    #[error("could not connect to {address}: {error}")]
+    Connect { address: SocketAddr, error: IoError },
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("could not send the metrics: {0}")]
+    Io(IoError),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("the endpoint answered {status}: {message}")]
+    Status { status: u16, message: String },
+}
+
+impl PushError {
+    /// the endpoint could accept the same push later
+    fn is_retryable(&self) -> bool {
+        match self {
+            PushError::Status { status, .. } => *status == 429 || *status >= 500,
+            _ => true,
+        }
+    }
+}
+
+/// what happened to a request of the sending thread, reported to the main process
+#[derive(Debug)]
+enum Report {
+    Sent {
+        samples: usize,
+    },
+    Failed {
+        error: PushError,
+        dropped_samples: Option<usize>,
+    },
+}
+
+/// the labels of a series, starting with its name, and its value at a time
+type Point = (Vec<Label>, Sample);
+
+/// the samples of all processes at a time
+type Push = Vec<Point>;
+
+#[derive(Debug)]
+struct Queue {
+    pushes: Mutex<VecDeque<Push>>,
+    available: Condvar,
+    capacity: usize,
+}
+
+impl Queue {
+    /// add a push, and return the samples dropped to make room for it
+    fn add(&self, push: Push) -> usize {
+        let mut pushes = self.pushes.lock().unwrap_or_else(|e| e.into_inner());
+        let mut dropped = 0;
+        while pushes.len() >= self.capacity {
+            dropped += pushes
+                .pop_front()
+                .map(|push| push.len())
+                .unwrap_or_default();
+        }
+        pushes.push_back(push);
+        self.available.notify_one();
+        dropped
+    }
+
+    fn len(&self) -> usize {
+        self.pushes
+            .lock()
+            .map(|pushes| pushes.len())
+            .unwrap_or_default()
+    }
+
+    /// wait for pushes, and take the oldest ones
+    fn take(&self) -> Vec<Push> {
+        let mut pushes = self.pushes.lock().unwrap_or_else(|e| e.into_inner());
+        while pushes.is_empty() {
+            pushes = self
+                .available
+                .wait(pushes)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        let count = pushes.len().min(MAX_PUSHES_PER_REQUEST);
+        pushes.drain(..count).collect()
+    }
+}
+
+/// Pushes of the metrics to a Prometheus remote write endpoint
+#[derive(Debug)]
+pub struct RemoteWrite {
+    next_push: Instant,
+    interval: Duration,
+    queue: Option<Arc<Queue>>,
+    reports: (Sender<Report>, Receiver<Report>),
+}
+
+impl Default for RemoteWrite {
+    fn default() -> Self {
+        Self {
+            next_push: Instant::now(),
+            interval: Duration::ZERO,
+            queue: None,
+            reports: mpsc::channel(),
+        }
+    }
+}
+
+impl RemoteWrite {
+    /// start the thread sending the pushes, if the configuration has a remote write URL
+    pub fn start(&mut self, config: &MetricsConfig) {
+        let Some(url) = &config.remote_write_url else {
+            return;
+        };
+        let endpoint = match Endpoint::new(url, config) {
+            Ok(endpoint) => endpoint,
+            Err(error) => {
+                error!("could not set up the remote write to {}: {}", url, error);
+                return;
+            }
+        };
+        info!("pushing the metrics to {}", url);
+
+        let queue = Arc::new(Queue {
+            pushes: Mutex::new(VecDeque::new()),
+            available: Condvar::new(),
+            capacity: config.remote_write_queue_size.max(1),
+        });
+        let reports = self.reports.0.clone();
+        let thread_queue = queue.clone();
+        thread::spawn(move || send_pushes(endpoint, thread_queue, reports));
+
+        self.queue = Some(queue);
+        self.interval = Duration::from_secs(config.remote_write_interval.max(1));
+        self.next_push = Instant::now() + self.interval;
+    }
+
+    /// returns true, and plans the next one, if a push is due
+    pub fn push_due(&mut self, now: Instant) -> bool {
+        if self.queue.is_none() || self.next_push > now {
+            return false;
+        }
+        self.next_push = now + self.interval;
+        true
+    }
+
+    /// queue the metrics for the sending thread
+    pub fn push(&mut self, metrics: &AggregatedMetrics, prefix: &str) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let dropped = queue.add(points(metrics, prefix, timestamp));
+        if dropped > 0 {
+            warn!(
+                "the remote write queue is full, dropped the {} samples of the oldest push",
+                dropped
+            );
+            count!("prometheus.remote_write.dropped", dropped as i64);
+        }
+    }
+
+    /// log and count what happened to the pushes since the last call
+    pub fn check_reports(&mut self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        for report in self.reports.1.try_iter() {
+            match report {
+                Report::Sent { samples } => {
+                    incr!("prometheus.remote_write.requests");
+                    count!("prometheus.remote_write.samples", samples as i64);
+                }
+                Report::Failed {
+                    error,
+                    dropped_samples: None,
+                } => {
+                    warn!("could not push the metrics, retrying: {}", error);
+                    incr!("prometheus.remote_write.errors");
+                }
+                Report::Failed {
+                    error,
+                    dropped_samples: Some(dropped),
+                } => {
+                    error!(
+                        "could not push the metrics, dropped {} samples: {}",
+                        dropped, error
+                    );
+                    incr!("prometheus.remote_write.errors");
+                    count!("prometheus.remote_write.dropped", dropped as i64);
+                }
+            }
+        }
+        gauge!("prometheus.remote_write.queue", queue.len());
+    }
+}
+
+/// the samples of the metrics, with their labels sorted by name as the protocol expects
+fn points(metrics: &AggregatedMetrics, prefix: &str, timestamp: i64) -> Push {
+    let mut points = Vec::new();
+    visit_samples(metrics, prefix, &mut |sample| {
+        let mut labels: Vec<Label> = sample
+            .labels
+            .iter()
+            .map(|(name, value)| Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect();
+        labels.push(Label {
+            name: "__name__".to_owned(),
+            value: sample.name.to_owned(),
+        });
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+        points.push((
+            labels,
+            Sample {
+                value: sample.value,
+                timestamp,
+            },
+        ));
+    });
+    points
+}
+
+/// a single request for several pushes, with one series per set of labels
+fn write_request(pushes: Vec<Push>) -> WriteRequest {
+    let mut series: BTreeMap<Vec<Label>, Vec<Sample>> = BTreeMap::new();
+    for (labels, sample) in pushes.into_iter().flatten() {
+        series.entry(labels).or_default().push(sample);
+    }
+    WriteRequest {
+        timeseries: series
+            .into_iter()
+            .map(|(labels, samples)| TimeSeries { labels, samples })
+            .collect(),
+    }
+}
+
+fn send_pushes(endpoint: Endpoint, queue: Arc<Queue>, reports: Sender<Report>) {
+    loop {
+        let request = write_request(queue.take());
+        let samples = request
+            .timeseries
+            .iter()
+            .map(|series| series.samples.len())
+            .sum();
+        let body = snappy_compress(&request.encode_to_vec());
+
+        let mut delay = MIN_RETRY_DELAY;
+        loop {
+            let report = match endpoint.post(&body) {
+                Ok(()) => Report::Sent { samples },
+                Err(error) if error.is_retryable() => Report::Failed {
+                    error,
+                    dropped_samples: None,
+                },
+                Err(error) => Report::Failed {
+                    error,
+                    dropped_samples: Some(samples),
+                },
+            };
+            let retry = matches!(
+                report,
+                Report::Failed {
+                    dropped_samples: None,
+                    ..
+                }
+            );
+            if reports.send(report).is_err() {
+                return;
+            }
+            if !retry {
+                break;
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+/// where the pushes are sent
+struct Endpoint {
+    host: String,
+    port: u16,
+    /// host and port, as in the URL
+    authority: String,
+    path: String,
+    tenant: Option<String>,
+    /// set for https URLs
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl Endpoint {
+    fn new(url: &str, config: &MetricsConfig) -> Result<Self, String> {
+        let (secure, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err("expected a http:// or https:// URL".to_owned()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("invalid port {port}"))?,
+            ),
+            None => (authority, if secure { 443 } else { 80 }),
+        };
+        let tls = match secure {
+            true => Some(client_config(
+                config
+                    .remote_write_ca_file
+                    .as_deref()
+                    .unwrap_or(SYSTEM_CA_FILE),
+            )?),
+            false => None,
+        };
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            authority: authority.to_owned(),
+            path: path.to_owned(),
+            tenant: config.remote_write_tenant.clone(),
+            tls,
+        })
+    }
+
+    /// send a compressed write request on its own connection, and check that the answer is a 2xx
+    fn post(&self, body: &[u8]) -> Result<(), PushError> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|error| PushError::Resolve {
+                host: self.host.to_owned(),
+                error: error.to_string(),
+            })?
+            .next()
+            .ok_or(PushError::Resolve {
+                host: self.host.to_owned(),
+                error: "no address found".to_owned(),
+            })?;
+        let stream = TcpStream::connect_timeout(&address, IO_TIMEOUT)
+            .map_err(|error| PushError::Connect { address, error })?;
+        stream
+            .set_read_timeout(Some(IO_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+            .map_err(PushError::Io)?;
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: sozu/{}\r\nContent-Type: application/x-protobuf\r\nContent-Encoding: snappy\r\nX-Prometheus-Remote-Write-Version: 0.1.0\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.authority,
+            env!("CARGO_PKG_VERSION"),
+            body.len()
+        )
+        .into_bytes();
+        if let Some(tenant) = &self.tenant {
+            request.extend_from_slice(format!("X-Scope-OrgID: {tenant}\r\n").as_bytes());
+        }
+        request.extend_from_slice(b"\r\n");
+        request.extend_from_slice(body);
+
+        let mut response = Vec::new();
+        match &self.tls {
+            Some(tls) => {
+                let server_name = ServerName::try_from(self.host.to_owned())
+                    .map_err(|error| PushError::Tls(error.to_string()))?;
+                let connection = ClientConnection::new(tls.clone(), server_name)
+                    .map_err(|error| PushError::Tls(error.to_string()))?;
+                exchange(
+                    StreamOwned::new(connection, stream),
+                    &request,
+                    &mut response,
+                )
+            }
+            None => exchange(stream, &request, &mut response),
+        }
+        .map_err(PushError::Io)?;
+
+        let response = String::from_utf8_lossy(&response);
+        let (head, message) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| {
+                PushError::InvalidResponse(head.lines().next().unwrap_or_default().to_owned())
+            })?;
+        match status {
+            200..=299 => Ok(()),
+            _ => Err(PushError::Status {
+                status,
+                message: message.trim().to_owned(),
+            }),
+        }
+    }
+}
+
+fn exchange(
+    mut stream: impl Read + Write,
+    request: &[u8],
+    response: &mut Vec<u8>,
+) -> Result<(), IoError> {
+    stream.write_all(request)?;
+    stream.flush()?;
+    match stream.take(MAX_RESPONSE_SIZE).read_to_end(response) {
+        Ok(_) => Ok(()),
+        // some servers close the connection without a TLS close_notify
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof && !response.is_empty() => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+fn client_config(ca_file: &str) -> Result<Arc<ClientConfig>, String> {
+    let pem = fs::read(ca_file).map_err(|error| format!("could not read {ca_file}: {error}"))?;
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut pem.as_slice()) {
+        let certificate = certificate.map_err(|error| error.to_string())?;
+        roots.add(certificate).map_err(|error| error.to_string())?;
+    }
+
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|error| error.to_string())?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// compress with the block format of snappy, as expected by remote write endpoints.
+/// Repeated sequences of 4 bytes or more, within 64KiB, become copies of the previous ones
+fn snappy_compress(input: &[u8]) -> Vec<u8> {
+    const HASH_BITS: u32 = 14;
+    const MAX_OFFSET: usize = u16::MAX as usize;
+
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    let mut length = input.len() as u64;
+    while length >= 0x80 {
+        output.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    output.push(length as u8);
+
+    // last position of each hash of 4 bytes
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut position = 0;
+    while position + 4 <= input.len() {
+        let bytes = &input[position..position + 4];
+        let key = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let hash = (key.wrapping_mul(0x1e35_a7bd) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash];
+        table[hash] = position;
+
+        if candidate == usize::MAX
+            || position - candidate > MAX_OFFSET
+            || input[candidate..candidate + 4] != *bytes
+        {
+            position += 1;
+            continue;
+        }
+
+        let mut length = 4;
+        while position + length < input.len()
+            && input[candidate + length] == input[position + length]
+        {
+            length += 1;
+        }
+        snappy_literal(&mut output, &input[literal_start..position]);
+        snappy_copy(&mut output, position - candidate, length);
+        position += length;
+        literal_start = position;
+    }
+    snappy_literal(&mut output, &input[literal_start..]);
+    output
+}
+
+fn snappy_literal(output: &mut Vec<u8>, literal: &[u8]) {
+    let Some(length) = literal.len().checked_sub(1) else {
+        return;
+    };
+    if length < 60 {
+        output.push((length << 2) as u8);
+    } else {
+        let bytes = (length as u32).to_le_bytes();
+        let size = 4 - (length as u32).leading_zeros() as usize / 8;
+        output.push(((59 + size) << 2) as u8);
+        output.extend_from_slice(&bytes[..size]);
+    }
+    output.extend_from_slice(literal);
+}
+
+/// copies with a 2 bytes offset, of 64 bytes at most each
+fn snappy_copy(output: &mut Vec<u8>, offset: usize, mut length: usize) {
+    while length > 0 {
+        let chunk = length.min(64);
+        output.push((((chunk - 1) << 2) | 0b10) as u8);
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        length -= chunk;
+    }
+}
+
+// messages of the remote write protocol, version 1
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    /// milliseconds since the epoch
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use sozu_command_lib::proto::command::{
+        filtered_metrics::Inner, FilteredMetrics, WorkerMetrics,
+    };
+
+    use super::*;
+
+    /// decompress the block format of snappy, with the literals and the copies of
+    /// [snappy_compress]
+    fn snappy_decompress(mut input: &[u8]) -> Vec<u8> {
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            let byte = input[0];
+            input = &input[1..];
+            length |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut output: Vec<u8> = Vec::with_capacity(length);
+        while let Some((&tag, rest)) = input.split_first() {
+            input = rest;
+            match tag & 0b11 {
+                0b00 => {
+                    let mut literal_length = (tag >> 2) as usize;
+                    if literal_length >= 60 {
+                        let size = literal_length - 59;
+                        let mut bytes = [0; 4];
+                        bytes[..size].copy_from_slice(&input[..size]);
+                        literal_length = u32::from_le_bytes(bytes) as usize;
+                        input = &input[size..];
+                    }
+                    output.extend_from_slice(&input[..literal_length + 1]);
+                    input = &input[literal_length + 1..];
+                }
+                0b10 => {
+                    let copy_length = (tag >> 2) as usize + 1;
+                    let offset = u16::from_le_bytes([input[0], input[1]]) as usize;
+                    input = &input[2..];
+                    for _ in 0..copy_length {
+                        output.push(output[output.len() - offset]);
+                    }
+                }
+                _ => panic!("unexpected tag {tag:#x}"),
+            }
+        }
+        assert_eq!(output.len(), length);
+        output
+    }
+
+    #[test]
+    fn snappy_round_trip() {
+        assert_eq!(snappy_compress(b""), vec![0]);
+        assert_eq!(snappy_compress(b"abc"), vec![3, 2 << 2, b'a', b'b', b'c']);
+
+        let repeated = b"sozu_http_requests{process=\"worker-0\"} ".repeat(200);
+        let compressed = snappy_compress(&repeated);
+        assert!(compressed.len() < repeated.len() / 10);
+        assert_eq!(snappy_decompress(&compressed), repeated);
+
+        let mixed: Vec<u8> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> (i % 13)) as u8)
+            .collect();
+        assert_eq!(snappy_decompress(&snappy_compress(&mixed)), mixed);
+    }
+
+    #[test]
+    fn pushes_are_merged_by_series() {
+        let metrics = AggregatedMetrics {
+            main: BTreeMap::from([(
+                "workers.running".to_owned(),
+                FilteredMetrics {
+                    inner: Some(Inner::Gauge(2)),
+                },
+            )]),
+            workers: BTreeMap::from([(
+                "0".to_owned(),
+                WorkerMetrics {
+                    proxy: BTreeMap::from([(
+                        "http.requests".to_owned(),
+                        FilteredMetrics {
+                            inner: Some(Inner::Count(12)),
+                        },
+                    )]),
+                    clusters: BTreeMap::new(),
+                },
+            )]),
+        };
+
+        let first = points(&metrics, "sozu", 1_000);
+        let second = points(&metrics, "sozu", 16_000);
+        let request = write_request(vec![first, second]);
+        let decoded = WriteRequest::decode(request.encode_to_vec().as_slice())
+            .expect("could not decode the write request");
+
+        assert_eq!(decoded.timeseries.len(), 2);
+        let requests = &decoded.timeseries[0];
+        assert_eq!(
+            requests
+                .labels
+                .iter()
+                .map(|label| (label.name.as_str(), label.value.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("__name__", "sozu_http_requests"), ("process", "worker-0")]
+        );
+        assert_eq!(
+            requests
+                .samples
+                .iter()
+                .map(|sample| (sample.value, sample.timestamp))
+                .collect::<Vec<_>>(),
+            vec![(12.0, 1_000), (12.0, 16_000)]
+        );
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let status = |status| PushError::Status {
+            status,
+            message: String::new(),
+        };
+        assert!(status(503).is_retryable());
+        assert!(status(429).is_retryable());
+        assert!(!status(400).is_retryable());
+        assert!(PushError::InvalidResponse(String::new()).is_retryable());
+    }
+}
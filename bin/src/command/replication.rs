@@ -0,0 +1,517 @@
+//! Replication of the state between main processes
+//!
+//! A primary main process sends its state changes to stand-by peers, so that a hot
+//! spare on another host routes the same way and can take over, through a VIP
+//! failover for instance. The peers talk over TCP with TLS, and authenticate each
+//! other with certificates signed by the same authority.
+//!
+//! A stand-by peer that connects first receives a snapshot of the state of the
+//! primary, then each change sent to the workers of the primary, as JSON lines.
+//! It applies the difference between its state and the snapshot, then the changes.
+//! Listeners are not replicated, each peer keeps the ones of its configuration.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Error as IoError, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+
+use sozu_command_lib::{
+    config::ReplicationConfig,
+    proto::command::{request::RequestType, Request},
+    state::ConfigState,
+};
+
+/// how often the primary writes to idle peers, for both sides to detect a broken connection
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// a stand-by peer reconnects when the primary stays silent that long
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// how long connecting to a peer, or writing to it, may take
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// delay before binding or connecting again
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReplicationError {
+    #[error("could not read {path}: {error}")]
+    ReadFile { path: String, error: IoError },
+    #[error("no certificate found in {0}")]
+    NoCertificate(String),
+    #[error("no private key found in {0}")]
+    NoKey(String),
+    #[error("invalid name of the primary: {0}")]
+    InvalidName(String),
+    #[error("could not build the verifier of client certificates: {0}")]
+    Verifier(String),
+    #[error("TLS configuration error: {0}")]
+    Tls(rustls::Error),
+}
+
+/// what the primary sends to the stand-by peers, one JSON object per line
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", content = "content", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReplicationMessage {
+    /// the requests recreating the state of the primary
+    Snapshot(Vec<Request>),
+    /// a request applied on the state and the workers of the primary
+    Change(Request),
+    /// sent when nothing changed for a while
+    Heartbeat,
+}
+
+impl ReplicationMessage {
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap_or_default();
+        line.push('\n');
+        line
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(line.trim_end())
+    }
+}
+
+/// true for the requests that change the routing, sent to the stand-by peers
+pub fn is_replicated(request: &Request) -> bool {
+    matches!(
+        request.request_type,
+        Some(
+            RequestType::AddCluster(_)
+                | RequestType::RemoveCluster(_)
+                | RequestType::AddHttpFrontend(_)
+                | RequestType::RemoveHttpFrontend(_)
+                | RequestType::AddHttpsFrontend(_)
+                | RequestType::RemoveHttpsFrontend(_)
+                | RequestType::AddTcpFrontend(_)
+                | RequestType::RemoveTcpFrontend(_)
+                | RequestType::AddCertificate(_)
+                | RequestType::RemoveCertificate(_)
+                | RequestType::ReplaceCertificate(_)
+                | RequestType::AddBackend(_)
+                | RequestType::RemoveBackend(_)
+                | RequestType::DrainBackend(_)
+                | RequestType::ReplaceBackends(_)
+                | RequestType::SetRequestPipeline(_)
+                | RequestType::SetBackendWeight(_)
+                | RequestType::SetLoadBalancing(_)
+                | RequestType::SetFaultInjection(_)
+        )
+    )
+}
+
+/// The requests bringing the routing of `state` to the one of the snapshot.
+/// The listeners of `state` are left untouched
+pub fn snapshot_diff(state: &ConfigState, snapshot: &[Request]) -> Vec<Request> {
+    let mut target = ConfigState::new();
+    for request in snapshot.iter().filter(|request| is_replicated(request)) {
+        if let Err(error) = target.dispatch(request) {
+            warn!("invalid request in the replicated snapshot: {}", error);
+        }
+    }
+    state
+        .diff(&target)
+        .into_iter()
+        .filter(is_replicated)
+        .collect()
+}
+
+/// The role of the main process, with its TLS configuration, loaded when it starts
+pub enum ReplicationSetup {
+    Primary {
+        listen_address: SocketAddr,
+        tls: Arc<ServerConfig>,
+    },
+    Standby {
+        primary_address: SocketAddr,
+        primary_name: ServerName<'static>,
+        tls: Arc<ClientConfig>,
+    },
+}
+
+impl ReplicationSetup {
+    pub fn new(config: &ReplicationConfig) -> Result<Self, ReplicationError> {
+        let provider = Arc::new(ring::default_provider());
+        let certificates = load_certificates(&config.certificate)?;
+        let key = load_key(&config.key)?;
+        let mut roots = RootCertStore::empty();
+        for ca_certificate in load_certificates(&config.ca_certificate)? {
+            roots.add(ca_certificate).map_err(ReplicationError::Tls)?;
+        }
+
+        match (config.listen_address, config.primary_address) {
+            (_, Some(primary_address)) => {
+                let name = config.primary_name.clone().unwrap_or_default();
+                let primary_name = ServerName::try_from(name.clone())
+                    .map_err(|_| ReplicationError::InvalidName(name))?;
+                let tls = ClientConfig::builder_with_provider(provider)
+                    .with_safe_default_protocol_versions()
+                    .map_err(ReplicationError::Tls)?
+                    .with_root_certificates(roots)
+                    .with_client_auth_cert(certificates, key)
+                    .map_err(ReplicationError::Tls)?;
+                Ok(Self::Standby {
+                    primary_address,
+                    primary_name,
+                    tls: Arc::new(tls),
+                })
+            }
+            (listen_address, None) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                        .build()
+                        .map_err(|error| ReplicationError::Verifier(error.to_string()))?;
+                let tls = server_config(provider, certificates, key, verifier)?;
+                Ok(Self::Primary {
+                    // the configuration makes sure one of the addresses is set
+                    listen_address: listen_address
+                        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))),
+                    tls: Arc::new(tls),
+                })
+            }
+        }
+    }
+}
+
+fn server_config(
+    provider: Arc<CryptoProvider>,
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    verifier: Arc<dyn rustls::server::danger::ClientCertVerifier>,
+) -> Result<ServerConfig, ReplicationError> {
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(ReplicationError::Tls)?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certificates, key)
+        .map_err(ReplicationError::Tls)
+}
+
+fn open(path: &str) -> Result<BufReader<File>, ReplicationError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| ReplicationError::ReadFile {
+            path: path.to_owned(),
+            error,
+        })
+}
+
+fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, ReplicationError> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| ReplicationError::ReadFile {
+            path: path.to_owned(),
+            error,
+        })?;
+    if certificates.is_empty() {
+        return Err(ReplicationError::NoCertificate(path.to_owned()));
+    }
+    Ok(certificates)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, ReplicationError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|error| ReplicationError::ReadFile {
+            path: path.to_owned(),
+            error,
+        })?
+        .ok_or_else(|| ReplicationError::NoKey(path.to_owned()))
+}
+
+/// what the thread serving the stand-by peers receives
+enum PrimaryEvent {
+    Change(Request),
+    Peer(SocketAddr, Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl std::fmt::Debug for PrimaryEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Change(request) => write!(f, "Change({request:?})"),
+            Self::Peer(address, _) => write!(f, "Peer({address})"),
+        }
+    }
+}
+
+/// Replication threads of the main process, if any
+#[derive(Debug, Default)]
+pub struct Replication {
+    /// on the primary, feeds the thread serving the stand-by peers
+    changes: Option<Sender<PrimaryEvent>>,
+    /// on a stand-by peer, what the primary sent
+    messages: Option<Receiver<ReplicationMessage>>,
+}
+
+impl Replication {
+    /// start the threads of the role. The primary replicates from this state on
+    pub fn start(&mut self, setup: ReplicationSetup, state: &ConfigState) {
+        match setup {
+            ReplicationSetup::Primary {
+                listen_address,
+                tls,
+            } => {
+                let (sender, receiver) = mpsc::channel();
+                let peers = sender.clone();
+                thread::spawn(move || accept_peers(listen_address, tls, peers));
+                let mirror = state.clone();
+                thread::spawn(move || serve_peers(receiver, mirror));
+                self.changes = Some(sender);
+            }
+            ReplicationSetup::Standby {
+                primary_address,
+                primary_name,
+                tls,
+            } => {
+                let (sender, receiver) = mpsc::channel();
+                thread::spawn(move || follow_primary(primary_address, primary_name, tls, sender));
+                self.messages = Some(receiver);
+            }
+        }
+    }
+
+    /// on the primary, send a request applied on the workers to the stand-by peers
+    pub fn publish(&self, request: &Request) {
+        if let Some(changes) = &self.changes {
+            if is_replicated(request) {
+                let _ = changes.send(PrimaryEvent::Change(request.clone()));
+            }
+        }
+    }
+
+    /// on a stand-by peer, the messages received from the primary since the last call
+    pub fn take_messages(&mut self) -> Vec<ReplicationMessage> {
+        match &self.messages {
+            Some(messages) => messages.try_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// accept the stand-by peers, and pass them to the thread serving them once
+/// their certificate is verified
+fn accept_peers(listen_address: SocketAddr, tls: Arc<ServerConfig>, peers: Sender<PrimaryEvent>) {
+    // the previous main process may still listen, during an upgrade
+    let listener = loop {
+        match TcpListener::bind(listen_address) {
+            Ok(listener) => break listener,
+            Err(error) => {
+                warn!(
+                    "could not listen for stand-by peers on {}: {}",
+                    listen_address, error
+                );
+                thread::sleep(RETRY_DELAY);
+            }
+        }
+    };
+    info!("accepting stand-by peers on {}", listen_address);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                warn!("could not accept a stand-by peer: {}", error);
+                continue;
+            }
+        };
+        let address = match stream.peer_addr() {
+            Ok(address) => address,
+            Err(_) => continue,
+        };
+        match handshake_peer(stream, tls.clone()) {
+            Ok(peer) => {
+                if peers
+                    .send(PrimaryEvent::Peer(address, Box::new(peer)))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(error) => warn!("TLS handshake with {} failed: {}", address, error),
+        }
+    }
+}
+
+fn handshake_peer(
+    mut stream: TcpStream,
+    tls: Arc<ServerConfig>,
+) -> Result<StreamOwned<ServerConnection, TcpStream>, IoError> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut connection =
+        ServerConnection::new(tls).map_err(|error| IoError::other(error.to_string()))?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// send a snapshot to each new stand-by peer, then the changes
+fn serve_peers(events: Receiver<PrimaryEvent>, mut mirror: ConfigState) {
+    let mut peers: Vec<(SocketAddr, Box<StreamOwned<ServerConnection, TcpStream>>)> = Vec::new();
+
+    loop {
+        let (message, new_peer) = match events.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(PrimaryEvent::Change(request)) => {
+                if let Err(error) = mirror.dispatch(&request) {
+                    debug!(
+                        "replicated request refused by the mirrored state: {}",
+                        error
+                    );
+                }
+                (ReplicationMessage::Change(request), None)
+            }
+            Ok(PrimaryEvent::Peer(address, peer)) => {
+                info!("stand-by peer {} connected", address);
+                let snapshot = mirror
+                    .produce_initial_state()
+                    .requests
+                    .into_iter()
+                    .map(|request| request.content)
+                    .filter(is_replicated)
+                    .collect();
+                (
+                    ReplicationMessage::Snapshot(snapshot),
+                    Some((address, peer)),
+                )
+            }
+            Err(RecvTimeoutError::Timeout) => (ReplicationMessage::Heartbeat, None),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let line = message.to_line();
+        match new_peer {
+            Some((address, mut peer)) => match write_line(&mut peer, &line) {
+                Ok(()) => peers.push((address, peer)),
+                Err(error) => warn!("could not send the snapshot to {}: {}", address, error),
+            },
+            None => peers.retain_mut(|(address, peer)| match write_line(peer, &line) {
+                Ok(()) => true,
+                Err(error) => {
+                    warn!("stand-by peer {} disconnected: {}", address, error);
+                    false
+                }
+            }),
+        }
+    }
+}
+
+fn write_line<W: Write>(peer: &mut W, line: &str) -> Result<(), IoError> {
+    peer.write_all(line.as_bytes())?;
+    peer.flush()
+}
+
+/// connect to the primary and pass on what it sends, reconnecting when needed
+fn follow_primary(
+    primary_address: SocketAddr,
+    primary_name: ServerName<'static>,
+    tls: Arc<ClientConfig>,
+    messages: Sender<ReplicationMessage>,
+) {
+    loop {
+        match read_primary(primary_address, &primary_name, tls.clone(), &messages) {
+            // the main process stopped listening
+            Ok(()) => return,
+            Err(error) => warn!(
+                "lost the replication connection to the primary {}: {}",
+                primary_address, error
+            ),
+        }
+        thread::sleep(RETRY_DELAY);
+    }
+}
+
+fn read_primary(
+    primary_address: SocketAddr,
+    primary_name: &ServerName<'static>,
+    tls: Arc<ClientConfig>,
+    messages: &Sender<ReplicationMessage>,
+) -> Result<(), IoError> {
+    let stream = TcpStream::connect_timeout(&primary_address, IO_TIMEOUT)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let connection = ClientConnection::new(tls, primary_name.clone())
+        .map_err(|error| IoError::other(error.to_string()))?;
+    let mut reader = BufReader::new(StreamOwned::new(connection, stream));
+    info!("replicating the state of the primary {}", primary_address);
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(IoError::other("the primary closed the connection"));
+        }
+        let message = ReplicationMessage::from_line(&line)
+            .map_err(|error| IoError::other(format!("invalid message: {error}")))?;
+        if message == ReplicationMessage::Heartbeat {
+            continue;
+        }
+        if messages.send(message).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sozu_command_lib::proto::command::{Cluster, RemoveBackend, SocketAddress};
+
+    fn cluster(cluster_id: &str) -> Request {
+        RequestType::AddCluster(Cluster {
+            cluster_id: cluster_id.to_owned(),
+            ..Default::default()
+        })
+        .into()
+    }
+
+    #[test]
+    fn messages_fit_on_a_line() {
+        let message = ReplicationMessage::Snapshot(vec![cluster("api")]);
+        let line = message.to_line();
+        assert_eq!(line.matches('\n').count(), 1);
+        assert_eq!(ReplicationMessage::from_line(&line).unwrap(), message);
+        assert_eq!(
+            ReplicationMessage::from_line(&ReplicationMessage::Heartbeat.to_line()).unwrap(),
+            ReplicationMessage::Heartbeat
+        );
+    }
+
+    #[test]
+    fn snapshot_replaces_the_routing() {
+        let mut state = ConfigState::new();
+        state.dispatch(&cluster("local")).unwrap();
+        state.dispatch(&cluster("api")).unwrap();
+
+        let requests = snapshot_diff(&state, &[cluster("api"), cluster("web")]);
+        assert_eq!(requests.len(), 2);
+        assert!(requests.contains(&cluster("web")));
+        assert!(requests.contains(&RequestType::RemoveCluster("local".to_owned()).into()));
+
+        let remove_backend: Request = RequestType::RemoveBackend(RemoveBackend {
+            cluster_id: "api".to_owned(),
+            backend_id: "api-0".to_owned(),
+            address: SocketAddress::new_v4(127, 0, 0, 1, 8080),
+        })
+        .into();
+        assert!(is_replicated(&remove_backend));
+        assert!(!is_replicated(
+            &RequestType::SoftStop(Default::default()).into()
+        ));
+    }
+}
@@ -1,8 +1,11 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     env,
-    fs::File,
+    fs::{self, File},
     io::{ErrorKind, Read},
+    net::SocketAddr,
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use mio::Token;
@@ -10,37 +13,70 @@ use nom::{HexDisplay, Offset};
 
 use sozu_command_lib::{
     buffer::fixed::Buffer,
-    config::Config,
+    certificate::{get_cn_and_san_attributes, parse_pem, parse_x509, split_certificate_chain},
+    config::{
+        AcmeConfig, AcmeDomainConfig, AlertMetric, Config, ACME_CHALLENGE_PATH, ACME_CLUSTER_ID,
+    },
     logging,
     parser::parse_several_requests,
     proto::command::{
-        request::RequestType, response_content::ContentType, AggregatedMetrics, AvailableMetrics,
-        CertificatesWithFingerprints, ClusterHashes, ClusterInformations, FrontendFilters,
-        HardStop, QueryCertificatesFilters, QueryMetricsOptions, Request, ResponseContent,
-        ResponseStatus, RunState, SoftStop, Status, WorkerInfo, WorkerInfos, WorkerRequest,
-        WorkerResponses,
+        request::RequestType, response_content::ContentType, AcmeOrder, AddBackend, AddCertificate,
+        AggregatedMetrics, ApplyState, AuditSessions, AvailableMetrics, BuildInfos, CaptureBundle,
+        CertificateAndKey, CertificatesWithFingerprints, Cluster, ClusterHashes,
+        ClusterInformations, CollectCapture, DrainBackend, ErrorCode, ErrorSubsystem, Event,
+        EventHistory, EventKind, FrontendFilters, GetChanges, HardStop, HealthChecks,
+        HealthOverride, PathRule, QueryBuildInfo, QueryCertificatesFilters, QueryEvents,
+        QueryHealthChecks, QueryMetricsOptions, QueryState, Readiness, ReplaceBackends,
+        ReplaceCertificate, Request, RequestHttpFrontend, ResponseContent, ResponseError,
+        ResponseStatus, RotateSigningKey, RulePosition, RunState, ScheduledChanges, SequenceGap,
+        SessionAudits, SetBackendHealthOverride, SetLogging, SigningKey, SoftStop, StartCapture,
+        StateChanges, Status, StickyEntry, WorkerInfo, WorkerInfos, WorkerMetrics, WorkerRequest,
+        WorkerResponse, WorkerResponses,
     },
+    proto::display::format_request_type,
+    state::ConfigState,
+    ObjectKind,
+};
+use sozu_lib::{
+    backends::MAX_STICKY_ENTRIES,
+    metrics::METRICS,
+    socket::{check_listener_address, AddressCheckError},
 };
-use sozu_lib::metrics::METRICS;
 
-use crate::command::{
-    server::{
-        DefaultGatherer, Gatherer, GatheringTask, MessageClient, Server, ServerState, Timeout,
-        WorkerId,
+use crate::{
+    command::{
+        acme::{write_private, AcmeError, Issued},
+        alerts::{alert_metric_names, measures_from_responses},
+        prometheus::{self, Scrape},
+        readiness::Waiting,
+        replication::{snapshot_diff, ReplicationMessage},
+        server::{
+            DefaultGatherer, Gatherer, GatheringTask, MessageClient, Server, ServerState, Timeout,
+            WorkerId,
+        },
+        sessions::{ClientSession, OptionalClient},
+        upgrade::{upgrade_main, upgrade_worker},
     },
-    sessions::{ClientSession, OptionalClient},
-    upgrade::{upgrade_main, upgrade_worker},
+    util::build_info,
 };
 
 impl Server {
-    pub fn handle_client_request(&mut self, client: &mut ClientSession, request: Request) {
-        let request_type = match request.request_type {
+    pub fn handle_client_request(&mut self, client: &mut ClientSession, mut request: Request) {
+        let request_type = match request.request_type.take() {
             Some(req) => req,
             None => {
                 error!("empty request sent by client {:?}", client);
                 return;
             }
         };
+        self.count_request(&request_type);
+        if let Err(error) = self.state.check_expected_state(&request, self.epoch) {
+            client.finish_failure_with_error(error.to_string(), error.response_error());
+            return;
+        }
+        if request.dry_run.unwrap_or(false) {
+            return dry_run(self, client, request_type);
+        }
         match request_type {
             RequestType::SaveState(path) => save_state(self, client, &path),
             RequestType::LoadState(path) => load_state(self, Some(client), &path),
@@ -54,16 +90,17 @@ impl Server {
                 load_static_config(self, Some(client), Some(&path))
             }
             RequestType::Status(_) => status(self, client),
+            RequestType::QueryBuildInfo(_) => query_build_info(self, client),
+            RequestType::StartCapture(start) => start_capture(self, client, start),
+            RequestType::CollectCapture(collect) => collect_capture(self, client, collect),
+            RequestType::AuditSessions(audit) => audit_sessions(self, client, audit),
             RequestType::AddCluster(_)
             | RequestType::ActivateListener(_)
             | RequestType::AddBackend(_)
             | RequestType::AddCertificate(_)
             | RequestType::AddHttpFrontend(_)
-            | RequestType::AddHttpListener(_)
             | RequestType::AddHttpsFrontend(_)
-            | RequestType::AddHttpsListener(_)
             | RequestType::AddTcpFrontend(_)
-            | RequestType::AddTcpListener(_)
             | RequestType::ConfigureMetrics(_)
             | RequestType::DeactivateListener(_)
             | RequestType::RemoveBackend(_)
@@ -73,9 +110,19 @@ impl Server {
             | RequestType::RemoveHttpsFrontend(_)
             | RequestType::RemoveListener(_)
             | RequestType::RemoveTcpFrontend(_)
-            | RequestType::ReplaceCertificate(_) => {
+            | RequestType::ReplaceBackends(_)
+            | RequestType::ReplaceCertificate(_)
+            | RequestType::SetBackendWeight(_)
+            | RequestType::SetFaultInjection(_)
+            | RequestType::SetLoadBalancing(_)
+            | RequestType::SetRequestPipeline(_)
+            | RequestType::UpdateListenerAnswers(_) => {
                 worker_request(self, client, request_type);
             }
+            RequestType::DrainBackend(drain) => drain_backend(self, client, drain),
+            RequestType::AddHttpListener(_)
+            | RequestType::AddHttpsListener(_)
+            | RequestType::AddTcpListener(_) => add_listener(self, client, request_type),
             RequestType::QueryClustersHashes(_)
             | RequestType::QueryClustersByDomain(_)
             | RequestType::QueryCertificatesFromWorkers(_)
@@ -86,13 +133,33 @@ impl Server {
             RequestType::SoftStop(_) => stop(self, client, false),
             RequestType::HardStop(_) => stop(self, client, true),
             RequestType::Logging(logging_filter) => set_logging_level(self, client, logging_filter),
+            RequestType::SetLogging(set) => set_logging(self, client, set),
             RequestType::QueryCertificatesFromTheState(filters) => {
                 query_certificates_from_main(self, client, filters)
             }
             RequestType::CountRequests(_) => count_requests(self, client),
+            RequestType::AddScheduledChange(_) | RequestType::RemoveScheduledChange(_) => {
+                update_scheduled_changes(self, client, request_type)
+            }
+            RequestType::ListScheduledChanges(_) => list_scheduled_changes(self, client),
+            RequestType::QueryEvents(filters) => query_events(self, client, filters),
+            RequestType::QueryHealthChecks(query) => query_health_checks(self, client, query),
+            RequestType::SetBackendHealthOverride(set) => {
+                set_backend_health_override(self, client, set)
+            }
+            RequestType::GetChanges(since) => get_changes(self, client, since),
+            RequestType::QueryState(query) => query_state(self, client, query),
+            RequestType::ApplyState(apply) => apply_state(self, client, apply, false),
 
             RequestType::LaunchWorker(_) => {} // not yet implemented, nor used, anywhere
             RequestType::ReturnListenSockets(_) => {} // This is only implemented by workers,
+            RequestType::SetStickyEntry(_) => {} // only sent by the main process to the workers
+            RequestType::SetSigningKeys(_) => {} // only sent by the main process to the workers
+            RequestType::SetBackendHealth(_) => {} // only sent by the main process to the workers
+            RequestType::SetAcceptShare(_) => {} // only sent by the main process to the workers
+            RequestType::RotateSigningKey(rotate) => rotate_signing_key(self, client, rotate),
+            RequestType::Resync(_) => {} // only sent by the main process to the workers
+            RequestType::AcmeOrder(order) => order_acme_certificate(self, client, order),
         }
     }
 
@@ -157,6 +224,123 @@ fn count_requests(server: &mut Server, client: &mut ClientSession) {
     );
 }
 
+fn update_scheduled_changes(
+    server: &mut Server,
+    client: &mut ClientSession,
+    request_type: RequestType,
+) {
+    let message = match &request_type {
+        RequestType::AddScheduledChange(change) => format!("Scheduled change {}", change.id),
+        RequestType::RemoveScheduledChange(id) => format!("Removed scheduled change {id}"),
+        _ => String::from("Updated scheduled changes"),
+    };
+    match server.state.dispatch(&request_type.into()) {
+        Ok(()) => client.finish_ok(message),
+        Err(error) => client.finish_failure_with_error(
+            format!("could not update the scheduled changes: {error}"),
+            error.response_error(),
+        ),
+    }
+}
+
+fn list_scheduled_changes(server: &mut Server, client: &mut ClientSession) {
+    let changes = server.state.scheduled_changes.values().cloned().collect();
+
+    client.finish_ok_with_content(
+        ContentType::ScheduledChanges(ScheduledChanges { changes }).into(),
+        "Successfully listed scheduled changes",
+    );
+}
+
+fn query_events(server: &mut Server, client: &mut ClientSession, filters: QueryEvents) {
+    let records = server
+        .event_history
+        .iter()
+        .filter(|record| {
+            filters
+                .since
+                .map_or(true, |since| record.timestamp >= since)
+        })
+        .filter(|record| {
+            filters.cluster_id.is_none() || record.event.cluster_id == filters.cluster_id
+        })
+        .filter(|record| {
+            filters.backend_id.is_none() || record.event.backend_id == filters.backend_id
+        })
+        .cloned()
+        .collect();
+
+    client.finish_ok_with_content(
+        ContentType::EventHistory(EventHistory { records }).into(),
+        "Successfully queried the event history",
+    );
+}
+
+fn query_health_checks(server: &mut Server, client: &mut ClientSession, query: QueryHealthChecks) {
+    let backends = server
+        .health_checks
+        .statuses(&server.state, query.cluster_id.as_deref());
+    client.finish_ok_with_content(
+        ContentType::HealthChecks(HealthChecks { backends }).into(),
+        "Successfully queried the health checks",
+    );
+}
+
+fn get_changes(server: &mut Server, client: &mut ClientSession, get_changes: GetChanges) {
+    let since_epoch = get_changes.since_epoch;
+    let first_kept = server
+        .change_history
+        .front()
+        .map_or(server.epoch + 1, |change| change.epoch);
+
+    // the client missed changes that are no longer kept, or knows an epoch of another process
+    let state_changes = if since_epoch.saturating_add(1) < first_kept || since_epoch > server.epoch
+    {
+        StateChanges {
+            epoch: server.epoch,
+            is_snapshot: true,
+            snapshot: server
+                .state
+                .produce_initial_state()
+                .requests
+                .into_iter()
+                .map(|request| request.content)
+                .collect(),
+            changes: Vec::new(),
+        }
+    } else {
+        StateChanges {
+            epoch: server.epoch,
+            is_snapshot: false,
+            snapshot: Vec::new(),
+            changes: server
+                .change_history
+                .iter()
+                .filter(|change| change.epoch > since_epoch)
+                .cloned()
+                .collect(),
+        }
+    };
+
+    client.finish_ok_with_content(
+        ContentType::StateChanges(state_changes).into(),
+        "Successfully queried the state changes",
+    );
+}
+
+fn query_state(server: &mut Server, client: &mut ClientSession, query: QueryState) {
+    match server.state.query(&query.query) {
+        Ok(result) => client.finish_ok_with_content(
+            ContentType::StateQueryResult(result).into(),
+            "Successfully queried the state",
+        ),
+        Err(error) => client.finish_failure_with_error(
+            format!("could not query the state: {error}"),
+            ResponseError::new(ErrorCode::InvalidRequest, ErrorSubsystem::State),
+        ),
+    }
+}
+
 pub fn list_frontend_command(
     server: &mut Server,
     client: &mut ClientSession,
@@ -176,12 +360,20 @@ fn list_workers(server: &mut Server, client: &mut ClientSession) {
             id: worker.id,
             pid: worker.pid,
             run_state: worker.run_state as i32,
+            lag: Some(worker.lag(Instant::now()).as_millis() as u64),
+            queued_requests: Some(worker.queued_requests() as u32),
+            generation: Some(worker.generation),
         })
         .collect();
 
     debug!("workers: {:?}", vec);
     client.finish_ok_with_content(
-        ContentType::Workers(WorkerInfos { vec }).into(),
+        ContentType::Workers(WorkerInfos {
+            vec,
+            readiness: None,
+            main_generation: Some(server.generation),
+        })
+        .into(),
         "Successfully listed workers",
     );
 }
@@ -244,6 +436,84 @@ fn set_logging_level(server: &mut Server, client: &mut ClientSession, logging_fi
     worker_request(server, client, RequestType::Logging(logging_filter));
 }
 
+/// change the logging filter and target of the main process and all workers, or of a
+/// single worker. A reset reverts them to the values of the configuration file
+fn set_logging(server: &mut Server, client: &mut ClientSession, mut set: SetLogging) {
+    let reset = set.reset.unwrap_or(false);
+    if reset {
+        set.filter = Some(server.config.log_level.clone());
+        set.target = Some(server.config.log_target.clone());
+    }
+    if set.filter.is_none() && set.target.is_none() {
+        return client.finish_failure("Set a logging filter, a log target, or reset them");
+    }
+
+    let mut directives = None;
+    if let Some(filter) = &set.filter {
+        let (parsed, errors) = logging::parse_logging_spec(filter);
+        if !errors.is_empty() {
+            return client.finish_failure(format!(
+                "Error parsing logging filter:\n- {}",
+                errors
+                    .iter()
+                    .map(logging::LogSpecParseError::to_string)
+                    .collect::<Vec<String>>()
+                    .join("\n- ")
+            ));
+        }
+        directives = Some(parsed);
+    }
+
+    match set.worker_id {
+        Some(worker_id) => {
+            if server.get_active_worker_by_id(worker_id).is_none() {
+                return client.finish_failure_with_error(
+                    format!("worker {worker_id} does not exist, or is stopping / stopped"),
+                    ResponseError::new(ErrorCode::NotFound, ErrorSubsystem::MainProcess),
+                );
+            }
+        }
+        None => {
+            let backend = match set.target.as_deref().map(logging::try_target_to_backend) {
+                Some(Err(error)) => {
+                    return client.finish_failure(format!("Invalid log target: {error}"));
+                }
+                Some(Ok(backend)) => Some(backend),
+                None => None,
+            };
+            debug!("Changing main process logging to {:?}", set);
+            logging::LOGGER.with(|logger| {
+                let mut logger = logger.borrow_mut();
+                if let Some(backend) = backend {
+                    logger.set_backend(backend, server.config.log_colored);
+                }
+                if let Some(directives) = directives {
+                    logger.set_directives(directives);
+                }
+            });
+
+            // workers started later read their logging filter from RUST_LOG
+            match (&set.filter, reset) {
+                (_, true) => env::remove_var("RUST_LOG"),
+                (Some(filter), false) => env::set_var("RUST_LOG", filter),
+                (None, false) => {}
+            }
+        }
+    }
+
+    client.return_processing("Changing the logging of workers...");
+    let target = set.worker_id;
+    server.scatter(
+        RequestType::SetLogging(set).into(),
+        Box::new(WorkerTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        target,
+    );
+}
+
 fn subscribe_client_to_events(server: &mut Server, client: &mut ClientSession) {
     info!("Subscribing client {:?} to listen to events", client.token);
     server.event_subscribers.insert(client.token);
@@ -337,6 +607,7 @@ pub fn load_static_config(server: &mut Server, mut client: OptionalClient, path:
         }),
         Timeout::None,
     );
+    server.readiness.track_loading(task_id);
 
     let new_config;
 
@@ -367,7 +638,9 @@ pub fn load_static_config(server: &mut Server, mut client: OptionalClient, path:
     };
 
     for (request_index, message) in config_messages.into_iter().enumerate() {
-        let request = message.content;
+        let Some(request) = server.readiness.hold(message.content) else {
+            continue;
+        };
         if let Err(error) = server.state.dispatch(&request) {
             client.return_processing(format!("Could not execute request on state: {:#}", error));
             continue;
@@ -436,6 +709,148 @@ struct WorkerTask {
     pub gatherer: DefaultGatherer,
 }
 
+/// Check that the address of a new listener can be bound, before sending it to the workers,
+/// to tell the client which process holds the address instead of failing on activation.
+fn add_listener(server: &mut Server, client: &mut ClientSession, request_type: RequestType) {
+    if let Err(error) = check_new_listener_address(&server.state, &request_type) {
+        client.finish_failure_with_error(
+            format!("could not add listener: {error}"),
+            address_check_error(&error),
+        );
+        return;
+    }
+
+    worker_request(server, client, request_type);
+}
+
+fn check_new_listener_address(
+    state: &ConfigState,
+    request_type: &RequestType,
+) -> Result<(), AddressCheckError> {
+    let address = match request_type {
+        RequestType::AddHttpListener(listener) => listener.address.clone(),
+        RequestType::AddHttpsListener(listener) => listener.address.clone(),
+        RequestType::AddTcpListener(listener) => listener.address.clone(),
+        _ => return Ok(()),
+    };
+    let address = address.into();
+
+    // a listener already in the state is rejected by the dispatch
+    if state.http_listeners.contains_key(&address)
+        || state.https_listeners.contains_key(&address)
+        || state.tcp_listeners.contains_key(&address)
+    {
+        return Ok(());
+    }
+
+    check_listener_address(address)
+}
+
+fn address_check_error(error: &AddressCheckError) -> ResponseError {
+    let (address, hint) = match error {
+        AddressCheckError::AddressInUse { address, .. } => (
+            address,
+            "stop the process using it, or choose another address",
+        ),
+        AddressCheckError::PermissionDenied { address } => (
+            address,
+            "give Sōzu the CAP_NET_BIND_SERVICE capability, or use a port above 1023",
+        ),
+        AddressCheckError::AddressNotAvailable { address } => {
+            (address, "use the address of a local interface")
+        }
+        AddressCheckError::Bind { address, .. } => (address, "choose another address"),
+    };
+    ResponseError::new(ErrorCode::AddressUnavailable, ErrorSubsystem::MainProcess)
+        .with_entity(&ObjectKind::Listener, address)
+        .with_hint(hint)
+}
+
+/// Validate a state-changing request against the state of the main process,
+/// and tell the client what it would change, without applying it
+fn dry_run(server: &mut Server, client: &mut ClientSession, request_type: RequestType) {
+    match request_type {
+        RequestType::ApplyState(apply) => return apply_state(server, client, apply, true),
+        RequestType::AddCluster(_)
+        | RequestType::ActivateListener(_)
+        | RequestType::AddBackend(_)
+        | RequestType::AddCertificate(_)
+        | RequestType::AddHttpFrontend(_)
+        | RequestType::AddHttpsFrontend(_)
+        | RequestType::AddTcpFrontend(_)
+        | RequestType::AddHttpListener(_)
+        | RequestType::AddHttpsListener(_)
+        | RequestType::AddTcpListener(_)
+        | RequestType::DeactivateListener(_)
+        | RequestType::RemoveBackend(_)
+        | RequestType::DrainBackend(_)
+        | RequestType::RemoveCertificate(_)
+        | RequestType::RemoveCluster(_)
+        | RequestType::RemoveHttpFrontend(_)
+        | RequestType::RemoveHttpsFrontend(_)
+        | RequestType::RemoveListener(_)
+        | RequestType::RemoveTcpFrontend(_)
+        | RequestType::ReplaceBackends(_)
+        | RequestType::ReplaceCertificate(_)
+        | RequestType::UpdateListenerAnswers(_) => {}
+        _ => {
+            client.finish_failure_with_error(
+                format!(
+                    "dry run is not supported for {} requests",
+                    format_request_type(&request_type)
+                ),
+                ResponseError::new(ErrorCode::UnsupportedRequest, ErrorSubsystem::MainProcess),
+            );
+            return;
+        }
+    }
+
+    if let Err(error) = check_new_listener_address(&server.state, &request_type) {
+        client.finish_failure_with_error(
+            format!("dry run: could not add listener: {error}"),
+            address_check_error(&error),
+        );
+        return;
+    }
+
+    let changes = match server.state.dry_run(&request_type.into()) {
+        Ok(changes) => changes,
+        Err(error) => {
+            client.finish_failure_with_error(
+                format!("dry run: the request would fail: {error}"),
+                error.response_error(),
+            );
+            return;
+        }
+    };
+
+    if changes.is_empty() {
+        client.finish_ok("dry run: the request is valid and would not change the state");
+        return;
+    }
+
+    client.finish_ok(format!(
+        "dry run: the request is valid and would apply {} change(s):{}",
+        changes.len(),
+        describe_changes(&changes)
+    ));
+}
+
+/// one line per change, with its type and its content in JSON
+fn describe_changes(changes: &[Request]) -> String {
+    let mut description = String::new();
+    for change in changes {
+        if let Some(request_type) = &change.request_type {
+            let details = serde_json::to_string(request_type).unwrap_or_default();
+            description.push_str(&format!(
+                "\n- {}: {details}",
+                format_request_type(request_type)
+            ));
+        }
+    }
+    description
+}
+
 pub fn worker_request(
     server: &mut Server,
     client: &mut ClientSession,
@@ -444,9 +859,10 @@ pub fn worker_request(
     let request = request_content.into();
 
     if let Err(error) = server.state.dispatch(&request) {
-        client.finish_failure(format!(
-            "could not dispatch request on the main process state: {error}",
-        ));
+        client.finish_failure_with_error(
+            format!("could not dispatch request on the main process state: {error}"),
+            error.response_error(),
+        );
         return;
     }
     client.return_processing("Processing worker request...");
@@ -478,24 +894,241 @@ impl GatheringTask for WorkerTask {
         timed_out: bool,
     ) {
         let mut messages = vec![];
+        let mut draining_connections = 0;
+        // the workers fail on the same requests, the first error stands for all of them
+        let mut worker_error = None;
 
         for (worker_id, response) in self.gatherer.responses {
             match ResponseStatus::try_from(response.status) {
                 Ok(ResponseStatus::Ok) => messages.push(format!("{worker_id}: OK")),
                 Ok(ResponseStatus::Failure) | Ok(ResponseStatus::Processing) | Err(_) => {
-                    messages.push(format!("{worker_id}: {}", response.message))
+                    messages.push(format!("{worker_id}: {}", response.message));
+                    if worker_error.is_none() {
+                        worker_error = response.error;
+                    }
                 }
             }
+            if let Some(ResponseContent {
+                content_type: Some(ContentType::DrainingBackends(draining)),
+            }) = response.content
+            {
+                draining_connections += draining
+                    .backends
+                    .iter()
+                    .map(|backend| backend.connections)
+                    .sum::<u64>();
+            }
         }
 
         if self.gatherer.errors > 0 || timed_out {
-            client.finish_failure(messages.join(", "));
+            let error = match worker_error {
+                Some(error) => error,
+                None if timed_out => ResponseError::new(ErrorCode::Timeout, ErrorSubsystem::Worker),
+                None => ResponseError::new(ErrorCode::WorkerFailure, ErrorSubsystem::Worker),
+            };
+            client.finish_failure_with_error(messages.join(", "), error);
+        } else if draining_connections > 0 {
+            client.finish_ok(format!(
+                "Successfully applied request to all workers, \
+                {draining_connections} connections are still open on removed backends"
+            ));
         } else {
             client.finish_ok("Successfully applied request to all workers");
         }
     }
 }
 
+// =========================================================
+// Drain a backend
+
+/// a client waiting for the connections of a drained backend to close
+#[derive(Debug)]
+pub struct DrainWaiter {
+    client_token: Token,
+    cluster_id: String,
+    backend_id: String,
+    /// the workers and the addresses of the backend that still have open connections
+    pending: HashSet<(WorkerId, SocketAddr)>,
+}
+
+fn drain_backend(server: &mut Server, client: &mut ClientSession, drain: DrainBackend) {
+    let request = RequestType::DrainBackend(drain.clone()).into();
+
+    if let Err(error) = server.state.dispatch(&request) {
+        client.finish_failure_with_error(
+            format!("could not dispatch request on the main process state: {error}"),
+            error.response_error(),
+        );
+        return;
+    }
+    if drain.undrain == Some(true) {
+        client.return_processing(format!("Undraining backend {}...", drain.backend_id));
+    } else {
+        client.return_processing(format!("Draining backend {}...", drain.backend_id));
+    }
+
+    server.scatter(
+        request,
+        Box::new(DrainTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+            drain,
+        }),
+        Timeout::Default,
+        None,
+    )
+}
+
+#[derive(Debug)]
+struct DrainTask {
+    client_token: Token,
+    gatherer: DefaultGatherer,
+    drain: DrainBackend,
+}
+
+impl GatheringTask for DrainTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        let mut messages = vec![];
+        let mut worker_error = None;
+        let mut pending = HashSet::new();
+        let mut connections = 0;
+
+        for (worker_id, response) in self.gatherer.responses {
+            match ResponseStatus::try_from(response.status) {
+                Ok(ResponseStatus::Ok) => {}
+                Ok(ResponseStatus::Failure) | Ok(ResponseStatus::Processing) | Err(_) => {
+                    messages.push(format!("{worker_id}: {}", response.message));
+                    if worker_error.is_none() {
+                        worker_error = response.error;
+                    }
+                }
+            }
+            if let Some(ResponseContent {
+                content_type: Some(ContentType::DrainingBackends(draining)),
+            }) = response.content
+            {
+                for backend in draining
+                    .backends
+                    .into_iter()
+                    .filter(|backend| backend.backend_id == self.drain.backend_id)
+                {
+                    connections += backend.connections;
+                    pending.insert((worker_id, backend.address.into()));
+                }
+            }
+        }
+
+        if self.gatherer.errors > 0 || timed_out {
+            let error = match worker_error {
+                Some(error) => error,
+                None if timed_out => ResponseError::new(ErrorCode::Timeout, ErrorSubsystem::Worker),
+                None => ResponseError::new(ErrorCode::WorkerFailure, ErrorSubsystem::Worker),
+            };
+            client.finish_failure_with_error(messages.join(", "), error);
+            return;
+        }
+
+        let backend_id = &self.drain.backend_id;
+        if self.drain.undrain == Some(true) {
+            client.finish_ok(format!("Backend {backend_id} is not draining anymore"));
+        } else if pending.is_empty() {
+            client.finish_ok(format!("Backend {backend_id} is drained"));
+        } else if self.drain.wait {
+            client.return_processing(format!(
+                "Backend {backend_id} is draining, waiting for {connections} connections to close"
+            ));
+            server.drain_waiters.push(DrainWaiter {
+                client_token: self.client_token,
+                cluster_id: self.drain.cluster_id,
+                backend_id: self.drain.backend_id,
+                pending,
+            });
+        } else {
+            client.finish_ok(format!(
+                "Backend {backend_id} is draining, {connections} connections are still open"
+            ));
+        }
+    }
+}
+
+/// Settle the clients waiting for drained backends. A worker reporting that a
+/// drained or removed backend has no connection left is no longer waited for,
+/// nor are the workers that stopped. A backend undrained in the meantime is not
+/// waited for anymore. Returns the clients to answer, with their message
+pub fn settle_drain_waiters(
+    server: &mut Server,
+    event: Option<(WorkerId, &Event)>,
+) -> Vec<(Token, String)> {
+    if let Some((worker_id, event)) = event {
+        if event.kind == EventKind::RemovedBackendHasNoConnections as i32 {
+            for waiter in &mut server.drain_waiters {
+                if event.backend_id.as_ref() != Some(&waiter.backend_id) {
+                    continue;
+                }
+                if let Some(address) = &event.address {
+                    waiter.pending.remove(&(worker_id, address.clone().into()));
+                }
+            }
+        }
+    }
+
+    let running_workers: HashSet<WorkerId> = server
+        .workers
+        .values()
+        .filter(|worker| worker.run_state != RunState::Stopped)
+        .map(|worker| worker.id)
+        .collect();
+    let mut settled = Vec::new();
+    let state = &server.state;
+    server.drain_waiters.retain_mut(|waiter| {
+        let undrained = state
+            .backends
+            .get(&waiter.cluster_id)
+            .into_iter()
+            .flatten()
+            .any(|backend| backend.backend_id == waiter.backend_id && backend.draining.is_none());
+        if undrained {
+            settled.push((
+                waiter.client_token,
+                format!(
+                    "Backend {} was undrained before its connections closed",
+                    waiter.backend_id
+                ),
+            ));
+            return false;
+        }
+        waiter
+            .pending
+            .retain(|(worker_id, _)| running_workers.contains(worker_id));
+        if !waiter.pending.is_empty() {
+            return true;
+        }
+        info!(
+            "backend {} of cluster {} has no connection left",
+            waiter.backend_id, waiter.cluster_id
+        );
+        settled.push((
+            waiter.client_token,
+            format!("Backend {} is drained", waiter.backend_id),
+        ));
+        false
+    });
+    settled
+}
+
 // =========================================================
 // Query Metrics
 
@@ -565,24 +1198,10 @@ impl GatheringTask for QueryMetricsTask {
             );
         }
 
-        let workers_metrics = self
-            .gatherer
-            .responses
-            .into_iter()
-            .filter_map(
-                |(worker_id, worker_response)| match worker_response.content {
-                    Some(ResponseContent {
-                        content_type: Some(ContentType::WorkerMetrics(worker_metrics)),
-                    }) => Some((worker_id.to_string(), worker_metrics)),
-                    _ => None,
-                },
-            )
-            .collect();
-
         client.finish_ok_with_content(
             ContentType::Metrics(AggregatedMetrics {
                 main: main_metrics,
-                workers: workers_metrics,
+                workers: workers_metrics(self.gatherer.responses),
             })
             .into(),
             "Successfully aggregated all metrics",
@@ -590,33 +1209,163 @@ impl GatheringTask for QueryMetricsTask {
     }
 }
 
+/// the metrics sent by each worker, by worker id
+fn workers_metrics(responses: Vec<(WorkerId, WorkerResponse)>) -> BTreeMap<String, WorkerMetrics> {
+    responses
+        .into_iter()
+        .filter_map(
+            |(worker_id, worker_response)| match worker_response.content {
+                Some(ResponseContent {
+                    content_type: Some(ContentType::WorkerMetrics(worker_metrics)),
+                }) => Some((worker_id.to_string(), worker_metrics)),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
 // =========================================================
-// Load state
+// Prometheus endpoint
 
 #[derive(Debug)]
-struct LoadStateTask {
-    /// this task may be called by the main process, without a client
-    pub client_token: Option<Token>,
-    pub gatherer: DefaultGatherer,
-    path: String,
+struct PrometheusScrapeTask {
+    gatherer: DefaultGatherer,
+    scrape: Scrape,
 }
 
-pub fn load_state(server: &mut Server, mut client: OptionalClient, path: &str) {
-    info!("loading state at path {}", path);
+/// query the metrics of the workers for each scrape of the Prometheus endpoint
+pub fn serve_prometheus_scrapes(server: &mut Server) {
+    for scrape in server.prometheus.take_scrapes() {
+        server.scatter(
+            RequestType::QueryMetrics(QueryMetricsOptions::default()).into(),
+            Box::new(PrometheusScrapeTask {
+                gatherer: DefaultGatherer::default(),
+                scrape,
+            }),
+            Timeout::Default,
+            None,
+        );
+    }
+}
 
-    let mut file = match File::open(path) {
-        Ok(file) => file,
-        Err(err) if matches!(err.kind(), ErrorKind::NotFound) => {
-            client.finish_failure(format!("Cannot find file at path {path}"));
-            return;
-        }
-        Err(error) => {
-            client.finish_failure(format!("Cannot open file at path {path}: {error}"));
-            return;
-        }
-    };
+impl GatheringTask for PrometheusScrapeTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
 
-    client.return_processing(format!("Parsing state file from {path}..."));
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out {
+            warn!(
+                "not all workers sent their metrics to Prometheus: {} ok, {} errors",
+                self.gatherer.ok, self.gatherer.errors
+            );
+        }
+        let metrics = AggregatedMetrics {
+            main: METRICS.with(|metrics| (*metrics.borrow_mut()).dump_local_proxy_metrics()),
+            workers: workers_metrics(self.gatherer.responses),
+        };
+        let prefix = server
+            .config
+            .metrics
+            .as_ref()
+            .and_then(|metrics| metrics.prefix.as_deref())
+            .unwrap_or("sozu");
+        // the scraper may have given up
+        let _ = self.scrape.send(prometheus::render(&metrics, prefix));
+    }
+}
+
+// =========================================================
+// Prometheus remote write
+
+#[derive(Debug)]
+struct RemoteWriteTask {
+    gatherer: DefaultGatherer,
+}
+
+/// query the metrics of the workers, to push them to the remote write endpoint
+pub fn push_remote_write(server: &mut Server) {
+    server.scatter(
+        RequestType::QueryMetrics(QueryMetricsOptions::default()).into(),
+        Box::new(RemoteWriteTask {
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+impl GatheringTask for RemoteWriteTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out {
+            warn!(
+                "not all workers sent their metrics for the remote write: {} ok, {} errors",
+                self.gatherer.ok, self.gatherer.errors
+            );
+        }
+        let metrics = AggregatedMetrics {
+            main: METRICS.with(|metrics| (*metrics.borrow_mut()).dump_local_proxy_metrics()),
+            workers: workers_metrics(self.gatherer.responses),
+        };
+        let prefix = server
+            .config
+            .metrics
+            .as_ref()
+            .and_then(|metrics| metrics.prefix.clone())
+            .unwrap_or_else(|| "sozu".to_owned());
+        server.remote_write.push(&metrics, &prefix);
+    }
+}
+
+// =========================================================
+// Load state
+
+#[derive(Debug)]
+struct LoadStateTask {
+    /// this task may be called by the main process, without a client
+    pub client_token: Option<Token>,
+    pub gatherer: DefaultGatherer,
+    path: String,
+}
+
+pub fn load_state(server: &mut Server, mut client: OptionalClient, path: &str) {
+    info!("loading state at path {}", path);
+
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if matches!(err.kind(), ErrorKind::NotFound) => {
+            client.finish_failure(format!("Cannot find file at path {path}"));
+            return;
+        }
+        Err(error) => {
+            client.finish_failure(format!("Cannot open file at path {path}: {error}"));
+            return;
+        }
+    };
+
+    client.return_processing(format!("Parsing state file from {path}..."));
 
     let task_id = server.new_task(
         Box::new(LoadStateTask {
@@ -626,6 +1375,7 @@ pub fn load_state(server: &mut Server, mut client: OptionalClient, path: &str) {
         }),
         Timeout::None,
     );
+    server.readiness.track_loading(task_id);
 
     let mut buffer = Buffer::with_capacity(200000);
     let mut scatter_request_counter = 0usize;
@@ -655,9 +1405,12 @@ pub fn load_state(server: &mut Server, mut client: OptionalClient, path: &str) {
                 offset = buffer.data().offset(i);
 
                 for request in requests {
-                    if server.state.dispatch(&request.content).is_ok() {
+                    let Some(request) = server.readiness.hold(request.content) else {
+                        continue;
+                    };
+                    if server.state.dispatch(&request).is_ok() {
                         scatter_request_counter += 1;
-                        server.scatter_on(request.content, task_id, scatter_request_counter, None);
+                        server.scatter_on(request, task_id, scatter_request_counter, None);
                     }
                 }
             }
@@ -714,6 +1467,205 @@ impl GatheringTask for LoadStateTask {
     }
 }
 
+// =========================================================
+// Apply a desired state
+
+#[derive(Debug)]
+struct ApplyStateTask {
+    client_token: Token,
+    gatherer: DefaultGatherer,
+    /// the changes sent to the workers, listed to the client
+    description: String,
+    change_count: usize,
+}
+
+/// Compare a desired state with the one of the main process, and apply only
+/// the requests converging to it. A dry run lists them without applying them
+fn apply_state(server: &mut Server, client: &mut ClientSession, apply: ApplyState, dry_run: bool) {
+    let mut desired = ConfigState::new();
+    for request in &apply.requests {
+        if let Err(error) = desired.dispatch(request) {
+            client.finish_failure_with_error(
+                format!("invalid desired state: {error}"),
+                error.response_error(),
+            );
+            return;
+        }
+    }
+
+    let changes = server.state.diff(&desired);
+    if changes.is_empty() {
+        client.finish_ok("The state already matches the desired one, nothing to apply");
+        return;
+    }
+
+    for request_type in changes
+        .iter()
+        .filter_map(|change| change.request_type.as_ref())
+    {
+        if let Err(error) = check_new_listener_address(&server.state, request_type) {
+            client.finish_failure_with_error(
+                format!("could not add listener: {error}"),
+                address_check_error(&error),
+            );
+            return;
+        }
+    }
+
+    let description = describe_changes(&changes);
+    if dry_run {
+        client.finish_ok(format!(
+            "dry run: the desired state would be reached with {} change(s):{description}",
+            changes.len()
+        ));
+        return;
+    }
+    client.return_processing(format!("Applying {} change(s)...", changes.len()));
+
+    let task_id = server.new_task(
+        Box::new(ApplyStateTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+            description,
+            change_count: changes.len(),
+        }),
+        Timeout::Default,
+    );
+    for (request_index, change) in changes.into_iter().enumerate() {
+        // the changes come from a diff of the state, they should all apply
+        if let Err(error) = server.state.dispatch(&change) {
+            error!(
+                "could not apply {} to converge to the desired state: {}",
+                change.short_name(),
+                error
+            );
+            continue;
+        }
+        server.scatter_on(change, task_id, request_index, None);
+    }
+}
+
+impl GatheringTask for ApplyStateTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        server.update_counts();
+        let DefaultGatherer { ok, errors, .. } = self.gatherer;
+        if errors > 0 || timed_out {
+            client.finish_failure_with_error(
+                format!(
+                    "the workers did not all apply the {} change(s): {ok} ok, {errors} errors, timed out: {timed_out}",
+                    self.change_count
+                ),
+                ResponseError::new(ErrorCode::WorkerFailure, ErrorSubsystem::Worker),
+            );
+            return;
+        }
+        client.finish_ok(format!(
+            "Successfully applied {} change(s):{}",
+            self.change_count, self.description
+        ));
+    }
+}
+
+// ==========================================================
+// Readiness gating
+
+#[derive(Debug)]
+struct ActivateListenersTask {
+    gatherer: DefaultGatherer,
+}
+
+/// Activate the listeners held back at startup, once the workers acknowledged the
+/// initial state and the critical clusters have a live backend, or on timeout
+pub fn check_readiness(server: &mut Server, now: Instant) {
+    if !server.readiness.is_gating() {
+        return;
+    }
+    server.readiness.take_probe_results();
+
+    match server.readiness.waiting_for() {
+        None => info!("the proxy is ready, activating the listeners"),
+        Some(waiting) if server.readiness.timed_out(now) => warn!(
+            "the proxy is still waiting for {} after the readiness timeout, activating the listeners anyway",
+            waiting
+        ),
+        Some(Waiting::Workers) => return,
+        Some(Waiting::Backends) => {
+            let backends = server
+                .readiness
+                .clusters_without_backend()
+                .into_iter()
+                .map(|cluster_id| {
+                    let addresses = server
+                        .state
+                        .backends
+                        .get(&cluster_id)
+                        .into_iter()
+                        .flatten()
+                        .map(|backend| backend.address)
+                        .collect();
+                    (cluster_id, addresses)
+                })
+                .collect();
+            server.readiness.probe(backends);
+            return;
+        }
+    }
+
+    let task_id = server.new_task(
+        Box::new(ActivateListenersTask {
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+    );
+    for (request_index, request) in server.readiness.take_activations().into_iter().enumerate() {
+        if let Err(error) = server.state.dispatch(&request) {
+            error!("could not activate listener {:?}: {}", request, error);
+            continue;
+        }
+        server.scatter_on(request, task_id, request_index, None);
+    }
+}
+
+impl GatheringTask for ActivateListenersTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.errors > 0 {
+            error!(
+                "workers did not all activate the listeners: {} ok, {} errors, timed out: {}",
+                self.gatherer.ok, self.gatherer.errors, timed_out
+            );
+        } else {
+            info!("listeners activated on all workers");
+        }
+        server.update_counts();
+    }
+}
+
 // ==========================================================
 // status
 
@@ -722,11 +1674,15 @@ struct StatusTask {
     pub client_token: Token,
     pub gatherer: DefaultGatherer,
     worker_infos: HashMap<WorkerId, WorkerInfo>,
+    readiness: Option<Readiness>,
+    main_generation: u32,
 }
 
 fn status(server: &mut Server, client: &mut ClientSession) {
     client.return_processing("Querying status of workers...");
 
+    let readiness = server.readiness.status();
+    let main_generation = server.generation;
     let worker_infos = server
         .workers
         .values()
@@ -739,6 +1695,8 @@ fn status(server: &mut Server, client: &mut ClientSession) {
             client_token: client.token,
             gatherer: DefaultGatherer::default(),
             worker_infos,
+            readiness,
+            main_generation,
         }),
         Timeout::Default,
         None,
@@ -778,6 +1736,8 @@ impl GatheringTask for StatusTask {
 
         let worker_info_vec = WorkerInfos {
             vec: self.worker_infos.into_values().collect(),
+            readiness: self.readiness,
+            main_generation: Some(self.main_generation),
         };
 
         client.finish_ok_with_content(
@@ -788,44 +1748,29 @@ impl GatheringTask for StatusTask {
 }
 
 // ==========================================================
-// Soft stop and hard stop
+// build info
 
 #[derive(Debug)]
-struct StopTask {
+struct BuildInfoTask {
     pub client_token: Token,
     pub gatherer: DefaultGatherer,
-    pub hardness: bool,
 }
 
-/// stop the main process and workers, true for hard stop
-fn stop(server: &mut Server, client: &mut ClientSession, hardness: bool) {
-    let task = Box::new(StopTask {
-        client_token: client.token,
-        gatherer: DefaultGatherer::default(),
-        hardness,
-    });
+fn query_build_info(server: &mut Server, client: &mut ClientSession) {
+    client.return_processing("Querying the builds of workers...");
 
-    server.run_state = ServerState::WorkersStopping;
-    if hardness {
-        client.return_processing("Performing hard stop...");
-        server.scatter(
-            RequestType::HardStop(HardStop {}).into(),
-            task,
-            Timeout::Default,
-            None,
-        );
-    } else {
-        client.return_processing("Performing soft stop...");
-        server.scatter(
-            RequestType::SoftStop(SoftStop {}).into(),
-            task,
-            Timeout::None,
-            None,
-        );
-    }
+    server.scatter(
+        RequestType::QueryBuildInfo(QueryBuildInfo {}).into(),
+        Box::new(BuildInfoTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        None,
+    );
 }
 
-impl GatheringTask for StopTask {
+impl GatheringTask for BuildInfoTask {
     fn client_token(&self) -> Option<Token> {
         Some(self.client_token)
     }
@@ -836,20 +1781,1550 @@ impl GatheringTask for StopTask {
 
     fn on_finish(
         self: Box<Self>,
-        server: &mut Server,
+        _server: &mut Server,
         client: &mut OptionalClient,
-        timed_out: bool,
+        _timed_out: bool,
     ) {
-        if timed_out && self.hardness {
-            client.finish_failure(format!(
-                "Workers take too long to stop ({} ok, {} errors), stopping the main process to sever the link",
-                self.gatherer.ok, self.gatherer.errors
-            ));
-        }
-        server.run_state = ServerState::Stopping;
-        client.finish_ok(format!(
-            "Successfully closed {} workers, {} errors, stopping the main process...",
-            self.gatherer.ok, self.gatherer.errors
-        ));
+        // workers that do not answer, or are too old to know this request, are left out
+        let workers = self
+            .gatherer
+            .responses
+            .into_iter()
+            .filter_map(|(worker_id, response)| match response.content {
+                Some(ResponseContent {
+                    content_type: Some(ContentType::BuildInfo(build_info)),
+                }) => Some((worker_id.to_string(), build_info)),
+                _ => None,
+            })
+            .collect();
+
+        client.finish_ok_with_content(
+            ContentType::BuildInfos(BuildInfos {
+                main: build_info(),
+                workers,
+            })
+            .into(),
+            "Successfully collected the builds of the main process and workers",
+        );
+    }
+}
+
+// ==========================================================
+// debug capture
+
+#[derive(Debug)]
+struct StartCaptureTask {
+    pub client_token: Token,
+    pub gatherer: DefaultGatherer,
+    pub cluster_id: String,
+}
+
+fn start_capture(server: &mut Server, client: &mut ClientSession, start: StartCapture) {
+    if !server.state.clusters.contains_key(&start.cluster_id) {
+        return client.finish_failure_with_error(
+            format!("cluster {} does not exist", start.cluster_id),
+            ResponseError::new(ErrorCode::NotFound, ErrorSubsystem::State),
+        );
+    }
+    if start.duration == 0 {
+        return client.finish_failure_with_error(
+            "the duration of a capture must be at least one second",
+            ResponseError::new(ErrorCode::InvalidRequest, ErrorSubsystem::MainProcess),
+        );
+    }
+
+    client.return_processing(format!(
+        "Starting the capture of cluster {} on workers...",
+        start.cluster_id
+    ));
+    let cluster_id = start.cluster_id.clone();
+    server.scatter(
+        RequestType::StartCapture(start).into(),
+        Box::new(StartCaptureTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+            cluster_id,
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+impl GatheringTask for StartCaptureTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        _timed_out: bool,
+    ) {
+        if self.gatherer.ok == 0 {
+            return client.finish_failure_with_error(
+                format!(
+                    "no worker could start the capture of cluster {}",
+                    self.cluster_id
+                ),
+                ResponseError::new(ErrorCode::WorkerFailure, ErrorSubsystem::Worker),
+            );
+        }
+        client.finish_ok(format!(
+            "Capturing the requests of cluster {} on {} workers",
+            self.cluster_id, self.gatherer.ok
+        ));
+    }
+}
+
+#[derive(Debug)]
+struct CollectCaptureTask {
+    pub client_token: Token,
+    pub gatherer: DefaultGatherer,
+    pub cluster_id: String,
+}
+
+fn collect_capture(server: &mut Server, client: &mut ClientSession, collect: CollectCapture) {
+    client.return_processing(format!(
+        "Collecting the capture of cluster {} from workers...",
+        collect.cluster_id
+    ));
+    let cluster_id = collect.cluster_id.clone();
+    server.scatter(
+        RequestType::CollectCapture(collect).into(),
+        Box::new(CollectCaptureTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+            cluster_id,
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+impl GatheringTask for CollectCaptureTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        _timed_out: bool,
+    ) {
+        // the records of the workers that do not answer are lost
+        let workers = self
+            .gatherer
+            .responses
+            .into_iter()
+            .filter_map(|(worker_id, response)| match response.content {
+                Some(ResponseContent {
+                    content_type: Some(ContentType::CapturedRequests(captured)),
+                }) => Some((worker_id.to_string(), captured)),
+                _ => None,
+            })
+            .collect();
+
+        client.finish_ok_with_content(
+            ContentType::CaptureBundle(CaptureBundle {
+                cluster_id: self.cluster_id,
+                workers,
+            })
+            .into(),
+            "Successfully collected the capture of the workers",
+        );
+    }
+}
+
+// ==========================================================
+// debug audit
+
+#[derive(Debug)]
+struct AuditSessionsTask {
+    pub client_token: Token,
+    pub gatherer: DefaultGatherer,
+}
+
+fn audit_sessions(server: &mut Server, client: &mut ClientSession, audit: AuditSessions) {
+    if let Some(worker_id) = audit.worker_id {
+        if server.get_active_worker_by_id(worker_id).is_none() {
+            return client.finish_failure_with_error(
+                format!("worker {worker_id} does not exist, or is stopping / stopped"),
+                ResponseError::new(ErrorCode::NotFound, ErrorSubsystem::MainProcess),
+            );
+        }
+    }
+
+    client.return_processing("Auditing the sessions of workers...");
+    let target = audit.worker_id;
+    server.scatter(
+        RequestType::AuditSessions(audit).into(),
+        Box::new(AuditSessionsTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        target,
+    );
+}
+
+impl GatheringTask for AuditSessionsTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        _timed_out: bool,
+    ) {
+        let workers = self
+            .gatherer
+            .responses
+            .into_iter()
+            .filter_map(|(worker_id, response)| match response.content {
+                Some(ResponseContent {
+                    content_type: Some(ContentType::SessionAudit(audit)),
+                }) => Some((worker_id.to_string(), audit)),
+                _ => None,
+            })
+            .collect();
+
+        client.finish_ok_with_content(
+            ContentType::SessionAudits(SessionAudits { workers }).into(),
+            "Successfully audited the sessions of the workers",
+        );
+    }
+}
+
+// ==========================================================
+// Soft stop and hard stop
+
+#[derive(Debug)]
+struct StopTask {
+    pub client_token: Token,
+    pub gatherer: DefaultGatherer,
+    pub hardness: bool,
+}
+
+/// stop the main process and workers, true for hard stop
+fn stop(server: &mut Server, client: &mut ClientSession, hardness: bool) {
+    let task = Box::new(StopTask {
+        client_token: client.token,
+        gatherer: DefaultGatherer::default(),
+        hardness,
+    });
+
+    server.run_state = ServerState::WorkersStopping;
+    if hardness {
+        client.return_processing("Performing hard stop...");
+        server.scatter(
+            RequestType::HardStop(HardStop {}).into(),
+            task,
+            Timeout::Default,
+            None,
+        );
+    } else {
+        client.return_processing("Performing soft stop...");
+        server.scatter(
+            RequestType::SoftStop(SoftStop {}).into(),
+            task,
+            Timeout::None,
+            None,
+        );
+    }
+}
+
+impl GatheringTask for StopTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out && self.hardness {
+            client.finish_failure(format!(
+                "Workers take too long to stop ({} ok, {} errors), stopping the main process to sever the link",
+                self.gatherer.ok, self.gatherer.errors
+            ));
+        }
+        server.run_state = ServerState::Stopping;
+        client.finish_ok(format!(
+            "Successfully closed {} workers, {} errors, stopping the main process...",
+            self.gatherer.ok, self.gatherer.errors
+        ));
+    }
+}
+
+// ==========================================================
+// Alert rules
+
+#[derive(Debug)]
+struct AlertMetricsTask {
+    gatherer: DefaultGatherer,
+}
+
+/// Evaluate the alert rules of the configuration, querying the workers for
+/// metrics if a rule needs them
+pub fn evaluate_alerts(server: &mut Server) {
+    if server.config.alerts.is_empty() {
+        return;
+    }
+
+    let needs_metrics = server
+        .config
+        .alerts
+        .iter()
+        .any(|alert| alert.metric != AlertMetric::BackendAvailability);
+    if !needs_metrics {
+        server
+            .alerts
+            .evaluate(&server.config.alerts, &server.state, &BTreeMap::new());
+        return;
+    }
+
+    server.scatter(
+        RequestType::QueryMetrics(QueryMetricsOptions {
+            metric_names: alert_metric_names(),
+            ..Default::default()
+        })
+        .into(),
+        Box::new(AlertMetricsTask {
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+impl GatheringTask for AlertMetricsTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out {
+            warn!("workers took too long to send metrics for the alert rules");
+        }
+        let measures = measures_from_responses(
+            self.gatherer
+                .responses
+                .into_iter()
+                .map(|(_, response)| response.content),
+        );
+        server
+            .alerts
+            .evaluate(&server.config.alerts, &server.state, &measures);
+    }
+}
+
+// ==========================================================
+// Expiration of frontends, backends and clusters
+
+#[derive(Debug)]
+struct RemoveExpiredTask {
+    gatherer: DefaultGatherer,
+}
+
+/// Remove the frontends, backends and clusters whose expiration date has passed,
+/// from the state and the workers, and return the events to send to subscribers
+pub fn remove_expired_objects(server: &mut Server) -> Vec<Event> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let expired = server.state.expired_objects(now);
+    if expired.is_empty() {
+        return Vec::new();
+    }
+
+    let task_id = server.new_task(
+        Box::new(RemoveExpiredTask {
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+    );
+
+    let mut events = Vec::new();
+    for (request_index, request) in expired.into_iter().enumerate() {
+        if let Err(error) = server.state.dispatch(&request) {
+            error!("could not remove expired object {:?}: {}", request, error);
+            continue;
+        }
+        if let Some(event) = request.request_type.as_ref().and_then(expiration_event) {
+            info!("removing expired object: {}", event);
+            events.push(event);
+        }
+        server.scatter_on(request, task_id, request_index, None);
+    }
+    events
+}
+
+fn expiration_event(request_type: &RequestType) -> Option<Event> {
+    let event = match request_type {
+        RequestType::RemoveCluster(cluster_id) => Event {
+            kind: EventKind::ClusterExpired.into(),
+            cluster_id: Some(cluster_id.to_owned()),
+            backend_id: None,
+            address: None,
+            alert: None,
+            value: None,
+        },
+        RequestType::RemoveHttpFrontend(front) | RequestType::RemoveHttpsFrontend(front) => Event {
+            kind: EventKind::FrontendExpired.into(),
+            cluster_id: front.cluster_id.clone(),
+            backend_id: None,
+            address: Some(front.address.clone()),
+            alert: None,
+            value: None,
+        },
+        RequestType::RemoveTcpFrontend(front) => Event {
+            kind: EventKind::FrontendExpired.into(),
+            cluster_id: Some(front.cluster_id.to_owned()),
+            backend_id: None,
+            address: Some(front.address.clone()),
+            alert: None,
+            value: None,
+        },
+        RequestType::RemoveBackend(backend) => Event {
+            kind: EventKind::BackendExpired.into(),
+            cluster_id: Some(backend.cluster_id.to_owned()),
+            backend_id: Some(backend.backend_id.to_owned()),
+            address: Some(backend.address.clone()),
+            alert: None,
+            value: None,
+        },
+        _ => return None,
+    };
+    Some(event)
+}
+
+impl GatheringTask for RemoveExpiredTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.errors > 0 {
+            error!(
+                "workers did not all remove the expired objects: {} ok, {} errors, timed out: {}",
+                self.gatherer.ok, self.gatherer.errors, timed_out
+            );
+        }
+        server.update_counts();
+    }
+}
+
+// ==========================================================
+// Sticky tables
+
+#[derive(Debug)]
+struct StickyEntryTask {
+    gatherer: DefaultGatherer,
+}
+
+/// Record the backend a worker chose for a client, and send it to all workers.
+/// The worker that made the choice receives it too: when two workers choose
+/// different backends for the same client, the last choice received by the
+/// main process wins everywhere.
+pub fn share_sticky_entry(server: &mut Server, entry: StickyEntry) {
+    let enabled = server
+        .state
+        .clusters
+        .get(&entry.cluster_id)
+        .is_some_and(|cluster| cluster.sticky_table);
+    if !enabled {
+        return;
+    }
+
+    let table = server
+        .sticky_tables
+        .entry(entry.cluster_id.clone())
+        .or_default();
+    if table.len() >= MAX_STICKY_ENTRIES && !table.contains_key(&entry.key) {
+        return;
+    }
+    table.insert(entry.key.clone(), entry.backend_id.clone());
+
+    server.scatter(
+        RequestType::SetStickyEntry(entry).into(),
+        Box::new(StickyEntryTask {
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+/// Send the sticky tables to a new worker
+pub fn send_sticky_tables(server: &mut Server, worker_id: WorkerId) {
+    let entries: Vec<StickyEntry> = server
+        .sticky_tables
+        .iter()
+        .flat_map(|(cluster_id, table)| {
+            table.iter().map(|(key, backend_id)| StickyEntry {
+                cluster_id: cluster_id.to_owned(),
+                key: key.to_owned(),
+                backend_id: backend_id.to_owned(),
+            })
+        })
+        .collect();
+    if entries.is_empty() {
+        return;
+    }
+
+    let task_id = server.new_task(
+        Box::new(StickyEntryTask {
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+    );
+    for (request_index, entry) in entries.into_iter().enumerate() {
+        server.scatter_on(
+            RequestType::SetStickyEntry(entry).into(),
+            task_id,
+            request_index,
+            Some(worker_id),
+        );
+    }
+}
+
+/// Forget the sticky tables of removed clusters, and the clients of removed backends
+pub fn prune_sticky_tables(server: &mut Server) {
+    let state = &server.state;
+    server.sticky_tables.retain(|cluster_id, table| {
+        if !state
+            .clusters
+            .get(cluster_id)
+            .is_some_and(|cluster| cluster.sticky_table)
+        {
+            return false;
+        }
+        let backends = state.backends.get(cluster_id);
+        table.retain(|_, backend_id| {
+            backends.is_some_and(|backends| {
+                backends
+                    .iter()
+                    .any(|backend| &backend.backend_id == backend_id)
+            })
+        });
+        !table.is_empty()
+    });
+}
+
+impl GatheringTask for StickyEntryTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.errors > 0 {
+            warn!(
+                "workers did not all record the sticky entry: {} ok, {} errors, timed out: {}",
+                self.gatherer.ok, self.gatherer.errors, timed_out
+            );
+        }
+    }
+}
+
+// ==========================================================
+// Active health checks
+
+#[derive(Debug)]
+struct BackendHealthTask {
+    /// set when the health of a backend is overridden by a client
+    client_token: Option<Token>,
+    gatherer: DefaultGatherer,
+}
+
+/// Count the results of the finished probes, send the backends that passed or failed
+/// their health checks to the workers, and start the probes that are due
+pub fn run_health_checks(server: &mut Server, now: Instant) {
+    let mut changes = server.health_checks.take_results(&server.state);
+    changes.extend(server.health_checks.prune(&server.state));
+    for change in changes {
+        server.scatter(
+            RequestType::SetBackendHealth(change).into(),
+            Box::new(BackendHealthTask {
+                client_token: None,
+                gatherer: DefaultGatherer::default(),
+            }),
+            Timeout::Default,
+            None,
+        );
+    }
+
+    let probes = server.health_checks.due_probes(&server.state, now);
+    server.health_checks.probe(probes);
+}
+
+/// Send the health of the checked backends to a new or resynchronized worker
+pub fn send_backend_health(server: &mut Server, worker_id: WorkerId) {
+    let backend_health = server.health_checks.backend_health(&server.state);
+    if backend_health.is_empty() {
+        return;
+    }
+
+    let task_id = server.new_task(
+        Box::new(BackendHealthTask {
+            client_token: None,
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+    );
+    for (request_index, set) in backend_health.into_iter().enumerate() {
+        server.scatter_on(
+            RequestType::SetBackendHealth(set).into(),
+            task_id,
+            request_index,
+            Some(worker_id),
+        );
+    }
+}
+
+/// Force the health of a backend, or let its health checks decide again,
+/// and send the addresses of the backend whose health changes to the workers
+fn set_backend_health_override(
+    server: &mut Server,
+    client: &mut ClientSession,
+    set: SetBackendHealthOverride,
+) {
+    let Ok(health_override) = HealthOverride::try_from(set.state) else {
+        client.finish_failure_with_error(
+            format!("unknown health override {}", set.state),
+            ResponseError::new(ErrorCode::InvalidRequest, ErrorSubsystem::MainProcess),
+        );
+        return;
+    };
+    let changes = match server.health_checks.set_override(
+        &server.state,
+        &set.cluster_id,
+        &set.backend_id,
+        health_override,
+    ) {
+        Ok(changes) => changes,
+        Err(error) => {
+            client.finish_failure_with_error(
+                error,
+                ResponseError::new(ErrorCode::NotFound, ErrorSubsystem::MainProcess),
+            );
+            return;
+        }
+    };
+    info!(
+        "health of backend {} of cluster {} set to {}",
+        set.backend_id, set.cluster_id, health_override
+    );
+    if changes.is_empty() {
+        client.finish_ok(format!(
+            "Health of backend {} set to {}, the workers already apply it",
+            set.backend_id, health_override
+        ));
+        return;
+    }
+
+    client.return_processing("Sending the health of the backend to the workers...");
+    let task_id = server.new_task(
+        Box::new(BackendHealthTask {
+            client_token: Some(client.token),
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+    );
+    for (request_index, change) in changes.into_iter().enumerate() {
+        server.scatter_on(
+            RequestType::SetBackendHealth(change).into(),
+            task_id,
+            request_index,
+            None,
+        );
+    }
+}
+
+impl GatheringTask for BackendHealthTask {
+    fn client_token(&self) -> Option<Token> {
+        self.client_token
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.errors > 0 {
+            let message = format!(
+                "workers did not all apply the health of the backend: {} ok, {} errors, timed out: {}",
+                self.gatherer.ok, self.gatherer.errors, timed_out
+            );
+            warn!("{}", message);
+            client.finish_failure_with_error(
+                message,
+                ResponseError::new(ErrorCode::WorkerFailure, ErrorSubsystem::Worker),
+            );
+            return;
+        }
+        client.finish_ok("The workers apply the health of the backend");
+    }
+}
+
+// ==========================================================
+// Signing keys
+
+#[derive(Debug)]
+struct SigningKeysTask {
+    client_token: Option<Token>,
+    gatherer: DefaultGatherer,
+    key_ids: Vec<String>,
+}
+
+/// Make a new key, given by the client or generated, the current signing key,
+/// and send the keys to all workers
+fn rotate_signing_key(server: &mut Server, client: &mut ClientSession, rotate: RotateSigningKey) {
+    let key = match rotate.secret {
+        Some(secret) => match SigningKey::new(secret) {
+            Ok(key) => key,
+            Err(error) => {
+                client.finish_failure_with_error(
+                    error.to_string(),
+                    ResponseError::new(ErrorCode::InvalidRequest, ErrorSubsystem::MainProcess),
+                );
+                return;
+            }
+        },
+        None => SigningKey::generate(),
+    };
+
+    if !server.signing_keys.rotate(key) {
+        client.finish_failure_with_error(
+            "this key already is the current signing key",
+            ResponseError::new(ErrorCode::NoChange, ErrorSubsystem::MainProcess),
+        );
+        return;
+    }
+    client.return_processing("Sending the signing keys to the workers...");
+
+    let key_ids = server
+        .signing_keys
+        .key_ids()
+        .into_iter()
+        .map(ToOwned::to_owned)
+        .collect();
+    server.scatter(
+        RequestType::SetSigningKeys(server.signing_keys.clone()).into(),
+        Box::new(SigningKeysTask {
+            client_token: Some(client.token),
+            gatherer: DefaultGatherer::default(),
+            key_ids,
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+/// Send the signing keys to a new worker
+pub fn send_signing_keys(server: &mut Server, worker_id: WorkerId) {
+    server.scatter(
+        RequestType::SetSigningKeys(server.signing_keys.clone()).into(),
+        Box::new(SigningKeysTask {
+            client_token: None,
+            gatherer: DefaultGatherer::default(),
+            key_ids: Vec::new(),
+        }),
+        Timeout::Default,
+        Some(worker_id),
+    );
+}
+
+impl GatheringTask for SigningKeysTask {
+    fn client_token(&self) -> Option<Token> {
+        self.client_token
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.errors > 0 {
+            client.finish_failure_with_error(
+                format!(
+                    "workers did not all record the signing keys: {} ok, {} errors, timed out: {}",
+                    self.gatherer.ok, self.gatherer.errors, timed_out
+                ),
+                ResponseError::new(ErrorCode::WorkerFailure, ErrorSubsystem::Worker),
+            );
+            return;
+        }
+        match self.key_ids.as_slice() {
+            [] => {}
+            [current] => client.finish_ok(format!("Signing with key {current}")),
+            [current, previous, ..] => client.finish_ok(format!(
+                "Signing with key {current}, key {previous} is still accepted until the next rotation"
+            )),
+        }
+    }
+}
+
+// ==========================================================
+// Resynchronize a worker that missed a request
+
+#[derive(Debug)]
+struct ResyncTask {
+    gatherer: DefaultGatherer,
+    worker_id: WorkerId,
+}
+
+/// Send the whole state to a worker that received a request out of order,
+/// it applies the difference with its own state
+pub fn resync_worker(server: &mut Server, worker_id: WorkerId, gap: SequenceGap) {
+    warn!(
+        "worker {} expected the request number {} and received {}, resynchronizing it",
+        worker_id, gap.expected, gap.received
+    );
+    incr!("worker.resyncs");
+    server.scatter(
+        RequestType::Resync(server.state.produce_initial_state()).into(),
+        Box::new(ResyncTask {
+            gatherer: DefaultGatherer::default(),
+            worker_id,
+        }),
+        Timeout::Default,
+        Some(worker_id),
+    );
+}
+
+impl GatheringTask for ResyncTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.ok == 0 {
+            error!(
+                "could not resynchronize worker {}, timed out: {}",
+                self.worker_id, timed_out
+            );
+        } else {
+            info!("worker {} is resynchronized", self.worker_id);
+            // the worker may have missed changes of the health of backends
+            send_backend_health(server, self.worker_id);
+        }
+    }
+}
+
+// ==========================================================
+// Backends listed by SRV records
+
+#[derive(Debug)]
+struct SrvBackendsTask {
+    gatherer: DefaultGatherer,
+    cluster_id: String,
+}
+
+/// Replace the backends of the clusters whose SRV record changed since the
+/// last resolution, and start a new resolution when it is due
+pub fn refresh_srv_backends(server: &mut Server, now: Instant) {
+    for (cluster_id, result) in server.srv_discovery.take_results() {
+        match result {
+            Ok(backends) => replace_srv_backends(server, cluster_id, backends),
+            // keep the current backends, the DNS server may be unavailable for a while
+            Err(error) => warn!(
+                "could not resolve the SRV record of cluster {}: {}",
+                cluster_id, error
+            ),
+        }
+    }
+
+    if server
+        .srv_discovery
+        .check_due(now, server.config.srv_refresh_interval)
+    {
+        let records = server
+            .state
+            .clusters
+            .values()
+            .filter_map(|cluster| {
+                cluster
+                    .backend_srv_record
+                    .clone()
+                    .map(|record| (cluster.cluster_id.clone(), record))
+            })
+            .collect();
+        server
+            .srv_discovery
+            .resolve(records, server.config.dns_resolver);
+    }
+}
+
+fn replace_srv_backends(server: &mut Server, cluster_id: String, backends: Vec<AddBackend>) {
+    // the record may have been removed while it was resolved
+    let still_listed = server
+        .state
+        .clusters
+        .get(&cluster_id)
+        .is_some_and(|cluster| cluster.backend_srv_record.is_some());
+    if !still_listed {
+        return;
+    }
+
+    // (backend id, address, weight, backup)
+    let mut current: Vec<(String, SocketAddr, Option<i32>, bool)> = server
+        .state
+        .backends
+        .get(&cluster_id)
+        .into_iter()
+        .flatten()
+        .map(|backend| {
+            (
+                backend.backend_id.clone(),
+                backend.address,
+                backend.load_balancing_parameters.as_ref().map(|p| p.weight),
+                backend.backup.unwrap_or(false),
+            )
+        })
+        .collect();
+    let mut resolved: Vec<(String, SocketAddr, Option<i32>, bool)> = backends
+        .iter()
+        .map(|backend| {
+            (
+                backend.backend_id.clone(),
+                backend.address.clone().into(),
+                backend.load_balancing_parameters.as_ref().map(|p| p.weight),
+                backend.backup.unwrap_or(false),
+            )
+        })
+        .collect();
+    current.sort();
+    resolved.sort();
+    if current == resolved {
+        return;
+    }
+
+    info!(
+        "SRV record of cluster {} changed, replacing its backends with {} addresses",
+        cluster_id,
+        backends.len()
+    );
+    let request: Request = RequestType::ReplaceBackends(ReplaceBackends {
+        cluster_id: cluster_id.clone(),
+        backends,
+    })
+    .into();
+    if let Err(error) = server.state.dispatch(&request) {
+        error!(
+            "could not replace the backends of cluster {} from its SRV record: {}",
+            cluster_id, error
+        );
+        return;
+    }
+    server.scatter(
+        request,
+        Box::new(SrvBackendsTask {
+            gatherer: DefaultGatherer::default(),
+            cluster_id,
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+impl GatheringTask for SrvBackendsTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.errors > 0 {
+            error!(
+                "workers did not all replace the backends of cluster {}: {} ok, {} errors, timed out: {}",
+                self.cluster_id, self.gatherer.ok, self.gatherer.errors, timed_out
+            );
+        }
+        server.update_counts();
+    }
+}
+
+// ==========================================================
+// State replicated from a primary main process
+
+#[derive(Debug)]
+struct ReplicationTask {
+    gatherer: DefaultGatherer,
+    /// how many requests of the primary were applied
+    request_count: usize,
+}
+
+/// On a stand-by peer, apply what the primary sent since the last check,
+/// on the state and the workers
+pub fn apply_replicated_state(server: &mut Server) {
+    let mut requests = Vec::new();
+    for message in server.replication.take_messages() {
+        match message {
+            ReplicationMessage::Snapshot(snapshot) => {
+                requests = snapshot_diff(&server.state, &snapshot);
+                info!(
+                    "received a snapshot of the primary, {} requests to catch up with it",
+                    requests.len()
+                );
+            }
+            ReplicationMessage::Change(request) => requests.push(request),
+            ReplicationMessage::Heartbeat => {}
+        }
+    }
+    if requests.is_empty() {
+        return;
+    }
+
+    let mut accepted = Vec::new();
+    for request in requests {
+        match server.state.dispatch(&request) {
+            Ok(()) => accepted.push(request),
+            Err(error) => warn!(
+                "could not apply the replicated request {}: {}",
+                request.short_name(),
+                error
+            ),
+        }
+    }
+
+    let task_id = server.new_task(
+        Box::new(ReplicationTask {
+            gatherer: DefaultGatherer::default(),
+            request_count: accepted.len(),
+        }),
+        Timeout::Default,
+    );
+    for (request_index, request) in accepted.into_iter().enumerate() {
+        server.scatter_on(request, task_id, request_index, None);
+    }
+}
+
+impl GatheringTask for ReplicationTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if timed_out || self.gatherer.errors > 0 {
+            error!(
+                "workers did not all apply the {} requests replicated from the primary: {} ok, {} errors, timed out: {}",
+                self.request_count, self.gatherer.ok, self.gatherer.errors, timed_out
+            );
+        }
+        server.update_counts();
+    }
+}
+
+// ==========================================================
+// Scheduled changes
+
+#[derive(Debug)]
+struct ScheduledChangeTask {
+    gatherer: DefaultGatherer,
+    change_id: String,
+    /// requests refused by the state of the main process, not sent to the workers
+    state_errors: Vec<String>,
+}
+
+/// Apply the requests of the scheduled changes that are due, on the state
+/// and the workers. The outcome is stored in the scheduled change.
+pub fn apply_scheduled_changes(server: &mut Server) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    for (change_id, requests) in server.state.take_due_scheduled_changes(now) {
+        info!(
+            "applying scheduled change {} ({} requests)",
+            change_id,
+            requests.len()
+        );
+
+        let mut state_errors = Vec::new();
+        let mut accepted = Vec::new();
+        for request in requests {
+            match server.state.dispatch(&request) {
+                Ok(()) => accepted.push(request),
+                Err(error) => state_errors.push(error.to_string()),
+            }
+        }
+
+        let task_id = server.new_task(
+            Box::new(ScheduledChangeTask {
+                gatherer: DefaultGatherer::default(),
+                change_id,
+                state_errors,
+            }),
+            Timeout::Default,
+        );
+        for (request_index, request) in accepted.into_iter().enumerate() {
+            server.scatter_on(request, task_id, request_index, None);
+        }
+    }
+}
+
+impl GatheringTask for ScheduledChangeTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        let mut result = format!(
+            "{} ok, {} errors on workers",
+            self.gatherer.ok, self.gatherer.errors
+        );
+        if timed_out {
+            result.push_str(", timed out");
+        }
+        if !self.state_errors.is_empty() {
+            result.push_str(&format!(
+                ", refused by the main process: {}",
+                self.state_errors.join(", ")
+            ));
+        }
+
+        if timed_out || self.gatherer.errors > 0 || !self.state_errors.is_empty() {
+            error!("scheduled change {} failed: {}", self.change_id, result);
+        } else {
+            info!("scheduled change {} applied: {}", self.change_id, result);
+        }
+
+        if let Some(change) = server.state.scheduled_changes.get_mut(&self.change_id) {
+            change.last_result = Some(result);
+        }
+        server.update_counts();
+    }
+}
+
+// ==========================================================
+// ACME certificates
+
+#[derive(Debug)]
+struct AcmeTask {
+    gatherer: DefaultGatherer,
+    /// the client that ordered the certificate, if any
+    client_token: Option<Token>,
+    /// what the requests do, for the logs and the client
+    action: String,
+    /// why the order failed or the state refused a request
+    error: Option<String>,
+}
+
+/// Route the HTTP-01 challenges of the names of the acme section to the main process,
+/// which answers them
+pub fn start_acme(server: &mut Server) {
+    let Some(config) = server.config.acme.clone() else {
+        return;
+    };
+    let Some(address) = server.acme.start(&config) else {
+        return;
+    };
+
+    let mut requests: Vec<Request> = Vec::new();
+    if !server.state.clusters.contains_key(ACME_CLUSTER_ID) {
+        requests.push(
+            RequestType::AddCluster(Cluster {
+                cluster_id: ACME_CLUSTER_ID.to_owned(),
+                ..Default::default()
+            })
+            .into(),
+        );
+    }
+    // the port of the challenges changes across upgrades of the main process
+    requests.push(
+        RequestType::ReplaceBackends(ReplaceBackends {
+            cluster_id: ACME_CLUSTER_ID.to_owned(),
+            backends: vec![AddBackend {
+                cluster_id: ACME_CLUSTER_ID.to_owned(),
+                backend_id: format!("{ACME_CLUSTER_ID}-0"),
+                address: address.into(),
+                ..Default::default()
+            }],
+        })
+        .into(),
+    );
+    for name in config.domains.iter().flat_map(|domain| &domain.names) {
+        let frontend = RequestHttpFrontend {
+            cluster_id: Some(ACME_CLUSTER_ID.to_owned()),
+            address: config.http_listener.into(),
+            hostname: name.to_owned(),
+            path: PathRule::prefix(ACME_CHALLENGE_PATH),
+            position: RulePosition::Pre.into(),
+            ..Default::default()
+        };
+        if !server.state.http_fronts.contains_key(&frontend.to_string()) {
+            requests.push(RequestType::AddHttpFrontend(frontend).into());
+        }
+    }
+
+    apply_acme_requests(
+        server,
+        requests,
+        format!("route the ACME challenges to {address}"),
+        None,
+    );
+}
+
+/// Install the certificates of the orders that ended, and, when a check is due,
+/// order the missing certificates and the ones about to expire
+pub fn check_acme(server: &mut Server, now: Instant) {
+    let Some(config) = server.config.acme.clone() else {
+        return;
+    };
+
+    for outcome in server.acme.take_outcomes(now) {
+        let names = outcome.domain.names.join(", ");
+        match outcome.result.and_then(|issued| {
+            store_acme_certificate(&config, &outcome.domain, &issued).map(|_| issued)
+        }) {
+            Ok(issued) => install_acme_certificate(server, &outcome.domain, issued, outcome.client),
+            // the task answers the client, or logs the error
+            Err(error) => {
+                server.new_task(
+                    Box::new(AcmeTask {
+                        gatherer: DefaultGatherer::default(),
+                        client_token: outcome.client,
+                        action: format!("order a certificate for {names}"),
+                        error: Some(error.to_string()),
+                    }),
+                    Timeout::Default,
+                );
+            }
+        }
+    }
+
+    if !server.acme.check_due(now) {
+        return;
+    }
+    let renew_after = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+        + config.renew_before as i64 * 24 * 3600;
+    for domain in &config.domains {
+        let name = &domain.names[0];
+        if installed_acme_certificate(server, domain)
+            .is_some_and(|(_, not_after)| not_after > renew_after)
+        {
+            continue;
+        }
+        // a certificate ordered before a restart, or by another main process
+        if let Some(issued) = stored_acme_certificate(&config, domain)
+            .filter(|(_, not_after)| *not_after > renew_after)
+            .map(|(issued, _)| issued)
+        {
+            install_acme_certificate(server, domain, issued, None);
+            continue;
+        }
+        if server.acme.may_order(name, now) {
+            server.acme.order(&config, domain, None);
+        }
+    }
+}
+
+/// Order a certificate for a domain of the acme section, the client waits for it
+fn order_acme_certificate(server: &mut Server, client: &mut ClientSession, order: AcmeOrder) {
+    let Some(config) = server.config.acme.clone() else {
+        return client.finish_failure("there is no acme section in the configuration");
+    };
+    let Some(domain) = config.domain(&order.domain).cloned() else {
+        return client.finish_failure(format!(
+            "{} is not in the domains of the acme section",
+            order.domain
+        ));
+    };
+    if server.acme.challenge_address().is_none() {
+        return client.finish_failure(
+            "the ACME challenges cannot be answered, see the logs of the main process",
+        );
+    }
+    if server.acme.is_ordering(&domain.names[0]) {
+        return client.finish_failure(format!(
+            "a certificate for {} is being ordered already",
+            domain.names[0]
+        ));
+    }
+
+    server.acme.order(&config, &domain, Some(client.token));
+    client.return_processing(format!(
+        "Ordering a certificate for {} from {}...",
+        domain.names.join(", "),
+        config.directory
+    ));
+}
+
+/// the fingerprint and expiration date of the certificate installed for the first name
+/// of the domain, the one expiring last if there are several
+fn installed_acme_certificate(server: &Server, domain: &AcmeDomainConfig) -> Option<(String, i64)> {
+    server
+        .state
+        .certificates
+        .get(&domain.https_listener)?
+        .iter()
+        .filter_map(|(fingerprint, certificate)| {
+            let pem = parse_pem(certificate.certificate.as_bytes()).ok()?;
+            let x509 = parse_x509(&pem.contents).ok()?;
+            let names = if certificate.names.is_empty() {
+                get_cn_and_san_attributes(&x509)
+            } else {
+                certificate.names.clone()
+            };
+            names.contains(&domain.names[0]).then(|| {
+                (
+                    fingerprint.to_string(),
+                    x509.validity().not_after.timestamp(),
+                )
+            })
+        })
+        .max_by_key(|(_, not_after)| *not_after)
+}
+
+/// `<storage>/<first name>.pem`, with the chain, and `<storage>/<first name>.key`
+fn acme_certificate_paths(config: &AcmeConfig, domain: &AcmeDomainConfig) -> (String, String) {
+    let storage = Path::new(&config.storage);
+    let name = &domain.names[0];
+    (
+        storage.join(format!("{name}.pem")).display().to_string(),
+        storage.join(format!("{name}.key")).display().to_string(),
+    )
+}
+
+fn store_acme_certificate(
+    config: &AcmeConfig,
+    domain: &AcmeDomainConfig,
+    issued: &Issued,
+) -> Result<(), AcmeError> {
+    let (certificate_path, key_path) = acme_certificate_paths(config, domain);
+    let mut pem = issued.certificate.clone();
+    for certificate in &issued.chain {
+        pem.push('\n');
+        pem.push_str(certificate);
+    }
+    write_private(Path::new(&key_path), issued.key.as_bytes())?;
+    write_private(Path::new(&certificate_path), pem.as_bytes())
+}
+
+/// the certificate kept in the storage for the domain, with its expiration date
+fn stored_acme_certificate(
+    config: &AcmeConfig,
+    domain: &AcmeDomainConfig,
+) -> Option<(Issued, i64)> {
+    let (certificate_path, key_path) = acme_certificate_paths(config, domain);
+    let mut pems = split_certificate_chain(fs::read_to_string(certificate_path).ok()?);
+    let key = fs::read_to_string(key_path).ok()?;
+    if pems.is_empty() {
+        return None;
+    }
+    let certificate = pems.remove(0);
+    let pem = parse_pem(certificate.as_bytes()).ok()?;
+    let not_after = parse_x509(&pem.contents)
+        .ok()?
+        .validity()
+        .not_after
+        .timestamp();
+    Some((
+        Issued {
+            certificate,
+            chain: pems,
+            key,
+        },
+        not_after,
+    ))
+}
+
+/// Add the certificate of the domain on its HTTPS listener, in place of the one
+/// installed for its first name, if any
+fn install_acme_certificate(
+    server: &mut Server,
+    domain: &AcmeDomainConfig,
+    issued: Issued,
+    client_token: Option<Token>,
+) {
+    let address = domain.https_listener.into();
+    let certificate = CertificateAndKey {
+        certificate: issued.certificate,
+        certificate_chain: issued.chain,
+        key: issued.key,
+        versions: Vec::new(),
+        names: Vec::new(),
+    };
+    let request_type = match installed_acme_certificate(server, domain) {
+        Some((old_fingerprint, _)) => RequestType::ReplaceCertificate(ReplaceCertificate {
+            address,
+            new_certificate: certificate,
+            old_fingerprint,
+            new_expired_at: None,
+        }),
+        None => RequestType::AddCertificate(AddCertificate {
+            address,
+            certificate,
+            expired_at: None,
+        }),
+    };
+    apply_acme_requests(
+        server,
+        vec![request_type.into()],
+        format!(
+            "install the certificate ordered for {}",
+            domain.names.join(", ")
+        ),
+        client_token,
+    );
+}
+
+/// apply the requests on the state and send them to the workers, the task answers the
+/// client once the workers are done
+fn apply_acme_requests(
+    server: &mut Server,
+    requests: Vec<Request>,
+    action: String,
+    client_token: Option<Token>,
+) {
+    let mut error = None;
+    let mut accepted = Vec::new();
+    for request in requests {
+        match server.state.dispatch(&request) {
+            Ok(()) => accepted.push(request),
+            Err(state_error) => error = Some(state_error.to_string()),
+        }
+    }
+
+    let task_id = server.new_task(
+        Box::new(AcmeTask {
+            gatherer: DefaultGatherer::default(),
+            client_token,
+            action,
+            error,
+        }),
+        Timeout::Default,
+    );
+    for (request_index, request) in accepted.into_iter().enumerate() {
+        server.scatter_on(request, task_id, request_index, None);
+    }
+}
+
+impl GatheringTask for AcmeTask {
+    fn client_token(&self) -> Option<Token> {
+        self.client_token
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if let Some(error) = self.error {
+            client.finish_failure(format!("could not {}: {}", self.action, error));
+        } else if timed_out || self.gatherer.errors > 0 {
+            client.finish_failure(format!(
+                "workers did not all {}: {} ok, {} errors, timed out: {}",
+                self.action, self.gatherer.ok, self.gatherer.errors, timed_out
+            ));
+        } else {
+            client.finish_ok(format!("Successfully applied requests to {}", self.action));
+        }
+        server.update_counts();
     }
 }
@@ -1,10 +1,10 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::{self, Debug},
     io::Error as IoError,
     ops::{Deref, DerefMut},
     os::fd::{AsRawFd, FromRawFd},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use libc::pid_t;
@@ -21,28 +21,52 @@ use sozu_command_lib::{
     channel::Channel,
     config::Config,
     proto::command::{
-        request::RequestType, response_content::ContentType, Request, ResponseContent,
-        ResponseStatus, RunState, Status, WorkerRequest, WorkerResponse,
+        request::RequestType, response_content::ContentType, BrokerRequest, BrokerResponse, Event,
+        EventRecord, Request, ResponseContent, ResponseError, ResponseStatus, RunState, SigningKey,
+        SigningKeys, StateChange, Status, WorkerRequest, WorkerResponse,
     },
+    proto::display::format_request_type,
     ready::Ready,
     scm_socket::{Listeners, ScmSocket, ScmSocketError},
-    state::ConfigState,
+    signing::SigningKeyError,
+    state::{ClusterId, ConfigState},
 };
 
 use crate::{
+    broker::{fork_main_into_broker, BrokerError},
     command::{
+        acme::Acme,
+        alerts::Alerts,
+        health_checks::HealthChecks,
+        prometheus::PrometheusEndpoint,
+        readiness::Readiness,
+        remote_write::RemoteWrite,
+        replication::{Replication, ReplicationSetup},
+        requests::{
+            apply_replicated_state, apply_scheduled_changes, check_acme, check_readiness,
+            evaluate_alerts, prune_sticky_tables, push_remote_write, refresh_srv_backends,
+            remove_expired_objects, resync_worker, run_health_checks, send_backend_health,
+            send_signing_keys, send_sticky_tables, serve_prometheus_scrapes, settle_drain_waiters,
+            share_sticky_entry, start_acme, DrainWaiter,
+        },
         sessions::{
-            wants_to_tick, ClientResult, ClientSession, OptionalClient, WorkerResult, WorkerSession,
+            wants_to_tick, BrokerResult, BrokerSession, ClientResult, ClientSession,
+            ClientTransport, OptionalClient, WorkerResult, WorkerSession,
         },
-        upgrade::UpgradeData,
+        srv::SrvDiscovery,
+        upgrade::{shift_accept_shares, AcceptShift, SerializedBroker, UpgradeData},
     },
     util::{disable_close_on_exec, enable_close_on_exec, get_executable_path, UtilError},
-    worker::{fork_main_into_worker, WorkerError},
+    worker::{fork_main_into_worker, StateSnapshot, WorkerError},
 };
 
 use super::upgrade::SerializedWorkerSession;
 
 pub type ClientId = u32;
+
+/// how often the main process removes expired frontends, backends and clusters,
+/// and applies the scheduled changes that are due
+const PERIODIC_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 pub type SessionId = usize;
 pub type TaskId = usize;
 pub type WorkerId = u32;
@@ -97,6 +121,9 @@ pub trait MessageClient {
     /// return failure to the client
     fn finish_failure<T: Into<String>>(&mut self, message: T);
 
+    /// return failure to the client, with a structured description of the error
+    fn finish_failure_with_error<T: Into<String>>(&mut self, message: T, error: ResponseError);
+
     /// notify the client about an ongoing task
     fn return_processing<T: Into<String>>(&mut self, message: T);
 
@@ -186,6 +213,8 @@ pub struct CommandHub {
     clients: HashMap<Token, ClientSession>,
     /// register tasks, for parallel execution
     tasks: HashMap<TaskId, TaskContainer>,
+    /// when to remove expired objects and apply scheduled changes
+    next_periodic_check: Instant,
 }
 
 impl Deref for CommandHub {
@@ -212,6 +241,7 @@ impl CommandHub {
                 .map_err(HubError::CreateServer)?,
             clients: HashMap::new(),
             tasks: HashMap::new(),
+            next_periodic_check: Instant::now(),
         })
     }
 
@@ -245,6 +275,12 @@ impl CommandHub {
             next_session_id,
             next_task_id,
             next_worker_id,
+            epoch,
+            change_history,
+            signing_keys,
+            generation,
+            health_overrides,
+            broker,
         } = upgrade_data;
 
         let executable_path =
@@ -260,10 +296,28 @@ impl CommandHub {
 
         server.state = state;
         server.update_counts();
+        if let Some(replication) = &server.config.replication {
+            match ReplicationSetup::new(replication) {
+                Ok(setup) => server.replication.start(setup, &server.state),
+                Err(error) => error!("could not set up the replication of the state: {}", error),
+            }
+        }
+        server.start_prometheus_endpoint();
+        server.start_remote_write();
+        server.start_grpc_endpoint();
         server.next_client_id = next_client_id;
         server.next_session_id = next_session_id;
         server.next_task_id = next_task_id;
         server.next_worker_id = next_worker_id;
+        // keep the epoch of the new process, if the clock went backwards
+        server.epoch = server.epoch.max(epoch);
+        server.change_history = change_history.into();
+        server.generation = generation;
+        // the workers still sign with the keys of the previous main process
+        if !signing_keys.keys.is_empty() {
+            server.signing_keys = signing_keys;
+        }
+        server.health_checks.restore_overrides(health_overrides);
 
         for worker in workers
             .iter()
@@ -276,15 +330,29 @@ impl CommandHub {
             let scm_socket = ScmSocket::new(worker.scm_fd)
                 .map_err(|scm_err| HubError::CreateScmSocket(worker.id, scm_err))?;
 
-            if let Err(err) = server.register_worker(worker.id, worker.pid, channel, scm_socket) {
-                error!("could not register worker: {}", err);
+            match server.register_worker(worker.id, worker.pid, channel, scm_socket) {
+                Ok(session) => {
+                    session.set_sequence(worker.sequence);
+                    session.generation = worker.generation;
+                }
+                Err(err) => error!("could not register worker: {}", err),
+            }
+        }
+        if let Some(broker) = broker {
+            let broker_stream = unsafe { UnixStream::from_raw_fd(broker.channel_fd) };
+            // the responses relayed to the clients can be as large as the whole state
+            let channel = Channel::new(broker_stream, command_buffer_size, u64::MAX);
+            if let Err(err) = server.register_broker(broker.pid, channel) {
+                error!("could not register the command broker: {}", err);
             }
         }
+        start_acme(&mut server);
 
         Ok(CommandHub {
             server,
             clients: HashMap::new(),
             tasks: HashMap::new(),
+            next_periodic_check: Instant::now(),
         })
     }
 
@@ -301,6 +369,45 @@ impl CommandHub {
             let run_state = self.run_state;
             let now = Instant::now();
 
+            if self.next_periodic_check <= now && self.run_state != ServerState::Stopping {
+                for event in remove_expired_objects(&mut self.server) {
+                    self.broadcast_event("main", event);
+                }
+                apply_scheduled_changes(&mut self.server);
+                prune_sticky_tables(&mut self.server);
+                refresh_srv_backends(&mut self.server, now);
+                run_health_checks(&mut self.server, now);
+                for event in self.server.health_checks.take_events() {
+                    self.broadcast_event("main", event);
+                }
+                check_readiness(&mut self.server, now);
+                shift_accept_shares(&mut self.server, now);
+                serve_prometheus_scrapes(&mut self.server);
+                if self.server.remote_write.push_due(now) {
+                    push_remote_write(&mut self.server);
+                }
+                self.server.remote_write.check_reports();
+                check_acme(&mut self.server, now);
+                apply_replicated_state(&mut self.server);
+                if self
+                    .server
+                    .alerts
+                    .check_due(now, self.server.config.alert_check_interval)
+                {
+                    evaluate_alerts(&mut self.server);
+                }
+                for event in self.server.alerts.take_events() {
+                    self.broadcast_event("main", event);
+                }
+                self.check_worker_lag(now);
+                let settled = settle_drain_waiters(&mut self.server, None);
+                self.finish_drain_waiters(settled);
+                self.server.ensure_broker();
+                self.server.update_activity_metrics();
+                gauge!("command.clients", self.clients.len());
+                self.next_periodic_check = now + PERIODIC_CHECK_INTERVAL;
+            }
+
             let mut tasks = std::mem::take(&mut self.tasks);
             let mut queued_tasks = std::mem::take(&mut self.server.queued_tasks);
             self.tasks = tasks
@@ -324,12 +431,22 @@ impl CommandHub {
             let next_timeout = self.tasks.values().filter_map(|t| t.timeout).max();
             let mut poll_timeout = next_timeout.map(|t| t.saturating_duration_since(now));
 
+            let until_periodic_check = self.next_periodic_check.saturating_duration_since(now);
+            poll_timeout = Some(poll_timeout.map_or(until_periodic_check, |timeout| {
+                timeout.min(until_periodic_check)
+            }));
+
+            self.relay_broker_responses();
+
             if self.run_state == ServerState::Stopping {
                 // when closing, close all ClientSession which are not transfering data
-                self.clients
-                    .retain(|_, s| s.channel.back_buf.available_data() > 0);
+                self.clients.retain(|_, s| s.has_pending_responses());
+                let broker_flushed = self
+                    .broker
+                    .as_ref()
+                    .map_or(true, |broker| broker.channel.back_buf.available_data() == 0);
                 // when all ClientSession are closed, the CommandServer stops
-                if self.clients.is_empty() {
+                if self.clients.is_empty() && broker_flushed {
                     break;
                 }
             }
@@ -338,12 +455,19 @@ impl CommandHub {
                 .clients
                 .iter()
                 .filter_map(|(t, s)| {
-                    if wants_to_tick(&s.channel) {
+                    if s.wants_to_tick() {
                         Some((*t, Ready::EMPTY, None))
                     } else {
                         None
                     }
                 })
+                .chain(self.broker.iter().filter_map(|broker| {
+                    if wants_to_tick(&broker.channel) {
+                        Some((broker.token, Ready::EMPTY, None))
+                    } else {
+                        None
+                    }
+                }))
                 .chain(self.workers.iter().filter_map(|(token, session)| {
                     if session.run_state != RunState::Stopped && wants_to_tick(&session.channel) {
                         Some((*token, Ready::EMPTY, None))
@@ -420,8 +544,17 @@ impl CommandHub {
                                         self.handle_worker_response(worker_id, response);
                                     }
                                 }
-                                WorkerResult::CloseSession => self.handle_worker_close(&token),
+                                WorkerResult::CloseSession => {
+                                    self.fail_worker_requests(&token, "closed its channel");
+                                    self.handle_worker_close(&token);
+                                }
                             }
+                        } else if self
+                            .broker
+                            .as_ref()
+                            .is_some_and(|broker| broker.token == token)
+                        {
+                            self.handle_broker_ready(ready, run_state);
                         }
                     }
                 }
@@ -429,20 +562,233 @@ impl CommandHub {
         }
     }
 
+    fn handle_broker_ready(&mut self, ready: Ready, run_state: ServerState) {
+        let Some(broker) = self.server.broker.as_mut() else {
+            return;
+        };
+        broker.update_readiness(ready);
+        // when stopping, the requests are left to the main process taking over
+        match broker.ready(run_state != ServerState::Stopping) {
+            BrokerResult::NothingToDo => {}
+            BrokerResult::NewRequests(requests) => {
+                for request in requests {
+                    self.handle_broker_request(request);
+                }
+            }
+            BrokerResult::CloseSession if run_state == ServerState::Stopping => {
+                self.server.broker = None;
+            }
+            BrokerResult::CloseSession => self.close_broker(),
+        }
+    }
+
+    /// a request of a client of the command broker, or its departure
+    fn handle_broker_request(&mut self, broker_request: BrokerRequest) {
+        let BrokerRequest { client_id, request } = broker_request;
+        let known_token = self
+            .broker
+            .as_ref()
+            .and_then(|broker| broker.clients.get(&client_id).copied());
+
+        let token = match (known_token, &request) {
+            (Some(token), _) => token,
+            (None, Some(_)) => self.register_brokered_client(client_id),
+            (None, None) => return,
+        };
+
+        match request {
+            Some(request) => {
+                if let Some((server, client)) = self.get_client_mut(&token) {
+                    debug!("Received new request: {:?}", request);
+                    server.handle_client_request(client, request);
+                }
+            }
+            None => {
+                info!("Closing client {:?} of the command broker", token);
+                self.event_subscribers.remove(&token);
+                self.clients.remove(&token);
+                if let Some(broker) = self.server.broker.as_mut() {
+                    broker.clients.remove(&client_id);
+                }
+            }
+        }
+    }
+
+    fn register_brokered_client(&mut self, broker_client_id: u32) -> Token {
+        let token = self.next_session_token();
+        let id = self.next_client_id();
+        let session = ClientSession::new_brokered(broker_client_id, id, token);
+        info!("Register new client of the command broker: {}", id);
+        debug!("{:#?}", session);
+        self.clients.insert(token, session);
+        if let Some(broker) = self.server.broker.as_mut() {
+            broker.clients.insert(broker_client_id, token);
+        }
+        token
+    }
+
+    /// write the responses to the clients of the command broker on its channel
+    fn relay_broker_responses(&mut self) {
+        let Some(broker) = self.server.broker.as_mut() else {
+            return;
+        };
+        for client in self.clients.values_mut() {
+            if let ClientTransport::Broker {
+                client_id,
+                responses,
+            } = &mut client.transport
+            {
+                for response in responses.drain(..) {
+                    broker.send(&BrokerResponse {
+                        client_id: *client_id,
+                        response: Some(response),
+                    });
+                }
+            }
+        }
+    }
+
+    /// forget the command broker and its clients, a new broker is launched
+    /// at the next periodic check
+    fn close_broker(&mut self) {
+        let Some(mut broker) = self.server.broker.take() else {
+            return;
+        };
+        warn!("the command broker closed its channel, closing its clients");
+        if let Err(e) = self
+            .server
+            .poll
+            .registry()
+            .deregister(&mut broker.channel.sock)
+        {
+            error!("could not deregister the command broker: {}", e);
+        }
+        if kill(Pid::from_raw(broker.pid), Signal::SIGKILL).is_ok() {
+            info!("the command broker {} was killed", broker.pid);
+        }
+        for token in broker.clients.values() {
+            self.server.event_subscribers.remove(token);
+            self.clients.remove(token);
+        }
+    }
+
+    /// Workers that do not answer in time get their pending requests failed,
+    /// so that the tasks waiting for them end, and are marked as not answering.
+    /// Workers whose queue is full are closed.
+    fn check_worker_lag(&mut self, now: Instant) {
+        if self.run_state != ServerState::Running {
+            return;
+        }
+        let Some(deadline) =
+            now.checked_sub(Duration::from_secs(self.config.worker_timeout as u64))
+        else {
+            return;
+        };
+
+        let mut late_requests = Vec::new();
+        let mut overflowed_workers = Vec::new();
+        for (token, worker) in self.server.workers.iter_mut() {
+            if worker.run_state != RunState::Running && worker.run_state != RunState::NotAnswering {
+                continue;
+            }
+            if worker.has_overflowed() {
+                overflowed_workers.push(*token);
+                continue;
+            }
+            let late = worker.take_late_requests(deadline);
+            if !late.is_empty() {
+                warn!(
+                    "worker {} did not answer {} requests in {} seconds, marking it as not answering",
+                    worker.id,
+                    late.len(),
+                    self.server.config.worker_timeout
+                );
+                worker.run_state = RunState::NotAnswering;
+                late_requests.push((worker.id, late));
+            }
+        }
+
+        for (worker_id, request_ids) in late_requests {
+            self.fail_requests(worker_id, request_ids, "did not answer in time");
+        }
+        for token in overflowed_workers {
+            self.fail_worker_requests(&token, "has too many requests waiting for it");
+            self.close_worker(&token);
+        }
+    }
+
+    /// fail the requests a worker did not answer, when closing it
+    fn fail_worker_requests(&mut self, token: &Token, reason: &str) {
+        let Some(worker) = self.server.workers.get_mut(token) else {
+            return;
+        };
+        let worker_id = worker.id;
+        let request_ids = worker.abandon_requests();
+        self.fail_requests(worker_id, request_ids, reason);
+    }
+
+    fn fail_requests(&mut self, worker_id: WorkerId, request_ids: Vec<String>, reason: &str) {
+        for request_id in request_ids {
+            if !self.in_flight.contains_key(&request_id) {
+                continue;
+            }
+            let response =
+                WorkerResponse::error(&request_id, format!("worker {worker_id} {reason}"));
+            self.handle_worker_response(worker_id, response);
+        }
+    }
+
+    /// answer the clients that waited for a backend to be drained
+    fn finish_drain_waiters(&mut self, settled: Vec<(Token, String)>) {
+        for (client_token, message) in settled {
+            if let Some(client) = self.clients.get_mut(&client_token) {
+                client.finish_ok(message);
+            }
+        }
+    }
+
+    /// transmit an event to the clients that subscribed to events
+    fn broadcast_event<T: fmt::Display>(&mut self, origin: T, event: Event) {
+        self.server.alerts.observe_event(&event);
+        self.server.record_event(origin.to_string(), event.clone());
+
+        for client_token in &self.server.event_subscribers {
+            if let Some(client) = self.clients.get_mut(client_token) {
+                client.return_processing_with_content(
+                    origin.to_string(),
+                    ContentType::Event(event.clone()).into(),
+                );
+            }
+        }
+    }
+
     fn handle_worker_response(&mut self, worker_id: WorkerId, response: WorkerResponse) {
         // transmit backend events to subscribing clients
         if let Some(ResponseContent {
             content_type: Some(ContentType::Event(event)),
         }) = response.content
         {
-            for client_token in &self.server.event_subscribers {
-                if let Some(client) = self.clients.get_mut(client_token) {
-                    client.return_processing_with_content(
-                        format!("{worker_id}"),
-                        ContentType::Event(event.clone()).into(),
-                    );
-                }
-            }
+            let settled = settle_drain_waiters(&mut self.server, Some((worker_id, &event)));
+            self.finish_drain_waiters(settled);
+            self.broadcast_event(worker_id, event);
+            return;
+        }
+
+        // share the backends chosen for sticky clients with the other workers
+        if let Some(ResponseContent {
+            content_type: Some(ContentType::StickyEntry(entry)),
+        }) = response.content
+        {
+            share_sticky_entry(&mut self.server, entry);
+            return;
+        }
+
+        // a worker received a request out of order
+        if let Some(ResponseContent {
+            content_type: Some(ContentType::SequenceGap(gap)),
+        }) = response.content
+        {
+            resync_worker(&mut self.server, worker_id, gap);
             return;
         }
 
@@ -479,6 +825,7 @@ impl CommandHub {
             .client_token()
             .and_then(|token| self.clients.get_mut(&token));
         task.job.on_finish(&mut self.server, client, false);
+        self.server.readiness.loading_finished(task_id);
         self.in_flight
             .retain(|_, in_flight_task_id| *in_flight_task_id != task_id);
     }
@@ -492,12 +839,18 @@ pub enum ServerError {
     RegisterChannel(IoError),
     #[error("Could not fork the main into a new worker: {0}")]
     ForkMain(WorkerError),
+    #[error("Could not serialize the state for new workers: {0}")]
+    SnapshotState(WorkerError),
     #[error("Did not find worker. This should NOT happen.")]
     WorkerNotFound,
     #[error("could not enable cloexec: {0}")]
     EnableCloexec(UtilError),
     #[error("could not disable cloexec: {0}")]
     DisableCloexec(UtilError),
+    #[error("could not load the signing key: {0}")]
+    SigningKey(SigningKeyError),
+    #[error("could not launch the command broker: {0}")]
+    LaunchBroker(BrokerError),
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -513,9 +866,21 @@ pub enum ServerState {
 /// - gather worker responses
 /// - trigger a finishing function when all responses are gathered
 pub struct Server {
+    /// upgraded workers handing their new connections over to the workers replacing them
+    pub accept_shifts: Vec<AcceptShift>,
+    /// state of the alert rules of the configuration
+    pub alerts: Alerts,
     pub config: Config,
+    /// clients waiting for the connections of drained backends to close
+    pub drain_waiters: Vec<DrainWaiter>,
     /// Sōzu clients that subscribed to events
     pub event_subscribers: HashSet<Token>,
+    /// recent events, oldest first, bounded by `config.event_history_size`
+    pub event_history: VecDeque<EventRecord>,
+    /// number of the last state change applied, queried with `sozu state changes`
+    pub epoch: u64,
+    /// recent state changes, oldest first, bounded by `config.change_history_size`
+    pub change_history: VecDeque<StateChange>,
     /// path to the executable binary of Sōzu (for upgrading)
     pub executable_path: String,
     /// keep track of the tasks
@@ -530,14 +895,44 @@ pub struct Server {
     queued_tasks: HashMap<TaskId, TaskContainer>,
     /// contains all business logic of Sōzu (frontends, backends, routing, etc.)
     pub state: ConfigState,
+    /// the state serialized for the last launched workers, reused while it does not change
+    state_snapshot: Option<StateSnapshot>,
     /// used to shut down gracefully
     pub run_state: ServerState,
     /// the UNIX socket on which to receive clients
     unix_listener: UnixListener,
+    /// the process accepting the clients on `unix_listener` instead of the main process,
+    /// when `command_broker` is configured
+    pub broker: Option<BrokerSession>,
     /// the Sōzu processes running parallel to the main process.
     /// The workers perform the whole business of proxying and must be
     /// synchronized at all times.
     pub workers: HashMap<Token, WorkerSession>,
+    /// request type -> key of the metric counting these requests
+    request_metric_keys: HashMap<&'static str, &'static str>,
+    /// cluster id -> client IP -> backend id, for clusters with a sticky table.
+    /// Not kept across upgrades of the main process
+    pub sticky_tables: HashMap<ClusterId, HashMap<String, String>>,
+    /// keys signing the sticky session cookies, read from `signing_key_file` or
+    /// generated at startup, and kept across upgrades of the main process
+    pub signing_keys: SigningKeys,
+    /// resolution of the SRV records listing the backends of clusters
+    pub srv_discovery: SrvDiscovery,
+    /// active health checks of the backends
+    pub health_checks: HealthChecks,
+    /// replication of the state to, or from, other main processes
+    pub replication: Replication,
+    /// activation of the listeners held back at startup
+    pub readiness: Readiness,
+    /// HTTP endpoint scraped by Prometheus
+    pub prometheus: PrometheusEndpoint,
+    /// pushes of the metrics to a Prometheus remote write endpoint
+    pub remote_write: RemoteWrite,
+    /// certificates ordered from an ACME server
+    pub acme: Acme,
+    /// generation of the main process, 0 at a cold start, incremented at each upgrade
+    /// of the main process. Each worker is tagged with the generation that launched it
+    pub generation: u32,
 }
 
 impl Server {
@@ -547,17 +942,35 @@ impl Server {
         executable_path: String,
     ) -> Result<Self, ServerError> {
         let poll = mio::Poll::new().map_err(ServerError::CreatePoll)?;
-        poll.registry()
-            .register(
-                &mut unix_listener,
-                Token(0),
-                Interest::READABLE | Interest::WRITABLE,
-            )
-            .map_err(ServerError::RegisterChannel)?;
+        // with a command broker, the main process does not accept clients itself
+        if config.command_broker.is_none() {
+            poll.registry()
+                .register(
+                    &mut unix_listener,
+                    Token(0),
+                    Interest::READABLE | Interest::WRITABLE,
+                )
+                .map_err(ServerError::RegisterChannel)?;
+        }
+
+        let signing_key = match &config.signing_key_file {
+            Some(path) => SigningKey::from_file(path).map_err(ServerError::SigningKey)?,
+            None => SigningKey::generate(),
+        };
 
         Ok(Self {
+            accept_shifts: Vec::new(),
+            alerts: Alerts::default(),
             config,
+            drain_waiters: Vec::new(),
             event_subscribers: HashSet::new(),
+            event_history: VecDeque::new(),
+            // a new main process goes on from a later epoch than the previous ones
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_micros() as u64)
+                .unwrap_or_default(),
+            change_history: VecDeque::new(),
             executable_path,
             in_flight: HashMap::new(),
             next_client_id: 0,
@@ -567,9 +980,24 @@ impl Server {
             poll,
             queued_tasks: HashMap::new(),
             state: ConfigState::new(),
+            state_snapshot: None,
             run_state: ServerState::Running,
             unix_listener,
+            broker: None,
             workers: HashMap::new(),
+            request_metric_keys: HashMap::new(),
+            sticky_tables: HashMap::new(),
+            signing_keys: SigningKeys {
+                keys: vec![signing_key],
+            },
+            srv_discovery: SrvDiscovery::default(),
+            health_checks: HealthChecks::default(),
+            replication: Replication::default(),
+            readiness: Readiness::default(),
+            prometheus: PrometheusEndpoint::default(),
+            remote_write: RemoteWrite::default(),
+            acme: Acme::default(),
+            generation: 0,
         })
     }
 
@@ -581,30 +1009,52 @@ impl Server {
         listeners: Option<Listeners>,
     ) -> Result<&mut WorkerSession, ServerError> {
         let worker_id = self.next_worker_id();
-        let (worker_pid, main_to_worker_channel, main_to_worker_scm) = fork_main_into_worker(
+        let state_snapshot = self.take_state_snapshot()?;
+        let forked = fork_main_into_worker(
             &worker_id.to_string(),
             &self.config,
+            self.generation,
             self.executable_path.clone(),
-            &self.state,
+            &state_snapshot,
             Some(listeners.unwrap_or_default()),
-        )
-        .map_err(ServerError::ForkMain)?;
+        );
+        self.state_snapshot = Some(state_snapshot);
+        let (worker_pid, main_to_worker_channel, main_to_worker_scm) =
+            forked.map_err(ServerError::ForkMain)?;
 
+        let generation = self.generation;
         let worker_session = self.register_worker(
             worker_id,
             worker_pid,
             main_to_worker_channel,
             main_to_worker_scm,
         )?;
+        worker_session.generation = generation;
 
         // TODO: make sure the worker is registered as NotAnswering,
         // and create a task that will pass it to Running when it respond OK to this request:
         worker_session.send(&WorkerRequest {
             id: format!("INITIAL-STATUS-{worker_id}"),
             content: RequestType::Status(Status {}).into(),
+            sequence: None,
         });
+        let token = worker_session.token;
 
-        Ok(worker_session)
+        send_signing_keys(self, worker_id);
+        send_sticky_tables(self, worker_id);
+        send_backend_health(self, worker_id);
+
+        self.workers
+            .get_mut(&token)
+            .ok_or(ServerError::WorkerNotFound)
+    }
+
+    /// serialize the state for new workers, unless it did not change since the last time
+    fn take_state_snapshot(&mut self) -> Result<StateSnapshot, ServerError> {
+        match self.state_snapshot.take() {
+            Some(snapshot) if snapshot.generation() == self.state.generation() => Ok(snapshot),
+            _ => StateSnapshot::new(&self.state).map_err(ServerError::SnapshotState),
+        }
     }
 
     /// count backends and frontends in the cache, update gauge metrics
@@ -612,6 +1062,78 @@ impl Server {
         gauge!("configuration.clusters", self.state.clusters.len());
         gauge!("configuration.backends", self.state.count_backends());
         gauge!("configuration.frontends", self.state.count_frontends());
+        gauge!("configuration.listeners", self.state.count_listeners());
+        gauge!(
+            "configuration.certificates",
+            self.state.count_certificates()
+        );
+        gauge!(
+            "configuration.scheduled_changes",
+            self.state.scheduled_changes.len()
+        );
+    }
+
+    /// count a request received from a client, by type
+    pub fn count_request(&mut self, request_type: &RequestType) {
+        let name = format_request_type(request_type);
+        // metric keys are static, so the key of each request type is leaked once
+        let key = *self
+            .request_metric_keys
+            .entry(name)
+            .or_insert_with(|| Box::leak(format!("command.requests.{name}").into_boxed_str()));
+        incr!(key);
+    }
+
+    /// gauges on the activity of the main process: workers, channels, pending tasks
+    pub fn update_activity_metrics(&self) {
+        gauge!("command.event_subscribers", self.event_subscribers.len());
+        gauge!("command.in_flight", self.in_flight.len());
+        gauge!("command.event_history", self.event_history.len());
+
+        let count_workers = |run_state: RunState| {
+            self.workers
+                .values()
+                .filter(|w| w.run_state == run_state)
+                .count()
+        };
+        gauge!("workers.running", count_workers(RunState::Running));
+        gauge!("workers.stopping", count_workers(RunState::Stopping));
+        gauge!("workers.stopped", count_workers(RunState::Stopped));
+        gauge!(
+            "workers.not_answering",
+            count_workers(RunState::NotAnswering)
+        );
+
+        let now = Instant::now();
+        gauge!(
+            "workers.max_lag",
+            self.workers
+                .values()
+                .map(|worker| worker.lag(now).as_millis() as usize)
+                .max()
+                .unwrap_or_default()
+        );
+        gauge!(
+            "workers.queued_requests",
+            self.workers
+                .values()
+                .map(|worker| worker.queued_requests())
+                .sum::<usize>()
+        );
+
+        // bytes written to the worker channels, that the workers did not read yet
+        let queued_bytes = self
+            .workers
+            .values()
+            .map(|worker| worker.channel.back_buf.available_data());
+        gauge!(
+            "workers.channel.queued_bytes",
+            queued_bytes.clone().sum::<usize>()
+        );
+        gauge!(
+            "workers.channel.max_queued_bytes",
+            queued_bytes.max().unwrap_or_default()
+        );
     }
 
     fn next_session_token(&mut self) -> Token {
@@ -644,6 +1166,36 @@ impl Server {
             .map_err(ServerError::RegisterChannel)
     }
 
+    /// launch the command broker if it is configured and does not run
+    pub fn ensure_broker(&mut self) {
+        if self.config.command_broker.is_none() || self.broker.is_some() {
+            return;
+        }
+        let forked = fork_main_into_broker(
+            &self.config,
+            self.executable_path.clone(),
+            self.unix_listener.as_raw_fd(),
+        );
+        let result = forked
+            .map_err(ServerError::LaunchBroker)
+            .and_then(|(pid, channel)| self.register_broker(pid, channel));
+        if let Err(err) = result {
+            error!("{}", err);
+        }
+    }
+
+    /// register the command broker in the server
+    pub fn register_broker(
+        &mut self,
+        pid: pid_t,
+        mut channel: Channel<BrokerResponse, BrokerRequest>,
+    ) -> Result<(), ServerError> {
+        let token = self.next_session_token();
+        self.register(token, &mut channel.sock)?;
+        self.broker = Some(BrokerSession::new(channel, pid, token));
+        Ok(())
+    }
+
     /// returns None if the worker is not alive
     pub fn get_active_worker_by_id(&self, id: WorkerId) -> Option<&WorkerSession> {
         self.workers
@@ -663,7 +1215,14 @@ impl Server {
         self.register(token, &mut channel.sock)?;
         self.workers.insert(
             token,
-            WorkerSession::new(channel, worker_id, pid, token, scm_socket),
+            WorkerSession::new(
+                channel,
+                worker_id,
+                pid,
+                token,
+                scm_socket,
+                self.config.worker_queue_size as usize,
+            ),
         );
         self.workers
             .get_mut(&token)
@@ -671,6 +1230,43 @@ impl Server {
     }
 
     /// Add a task in a queue to make it accessible until the next tick
+    /// serve the Prometheus endpoint, if the configuration has one
+    pub fn start_prometheus_endpoint(&mut self) {
+        if let Some(address) = self
+            .config
+            .metrics
+            .as_ref()
+            .and_then(|metrics| metrics.prometheus_address)
+        {
+            self.prometheus.start(address);
+        }
+    }
+
+    /// push the metrics to a Prometheus remote write endpoint, if the configuration has one
+    pub fn start_remote_write(&mut self) {
+        if let Some(metrics) = &self.config.metrics {
+            self.remote_write.start(metrics);
+        }
+    }
+
+    pub fn start_grpc_endpoint(&self) {
+        let Some(grpc) = &self.config.grpc else {
+            return;
+        };
+        #[cfg(feature = "grpc")]
+        if let Err(error) = super::grpc::start(grpc, &self.config) {
+            error!(
+                "could not start the gRPC endpoint on {}: {}",
+                grpc.address, error
+            );
+        }
+        #[cfg(not(feature = "grpc"))]
+        warn!(
+            "Sōzu was built without the grpc feature, the endpoint on {} is not served",
+            grpc.address
+        );
+    }
+
     pub fn new_task(&mut self, job: Box<dyn GatheringTask>, timeout: Timeout) -> TaskId {
         let task_id = self.next_task_id();
         let timeout = match timeout {
@@ -715,6 +1311,7 @@ impl Server {
         let mut worker_request = WorkerRequest {
             id: String::new(),
             content: request,
+            sequence: None,
         };
 
         for worker in self.workers.values_mut().filter(|w| {
@@ -735,10 +1332,16 @@ impl Server {
             self.in_flight.insert(worker_request.id, task_id);
         }
         task.job.get_gatherer().inc_expected_responses(worker_count);
+
+        if target.is_none() {
+            self.record_change(&worker_request.content);
+            self.replication.publish(&worker_request.content);
+        }
     }
 
     pub fn cancel_task(&mut self, task_id: TaskId) {
         self.queued_tasks.remove(&task_id);
+        self.readiness.loading_finished(task_id);
     }
 
     /// Called when the main cannot communicate anymore with a worker (it's channel closed)
@@ -815,6 +1418,9 @@ impl Server {
             self.unix_listener.as_raw_fd()
         );
 
+        if let Some(broker) = &self.broker {
+            disable_close_on_exec(broker.channel.fd()).map_err(ServerError::DisableCloexec)?;
+        }
         disable_close_on_exec(self.unix_listener.as_raw_fd()).map_err(ServerError::DisableCloexec)
     }
 
@@ -830,6 +1436,14 @@ impl Server {
                 });
             }
         }
+        if let Some(broker) = &self.broker {
+            let _ = enable_close_on_exec(broker.channel.fd()).map_err(|e| {
+                error!(
+                    "could not enable close on exec for the command broker: {}",
+                    e
+                );
+            });
+        }
         enable_close_on_exec(self.unix_listener.as_raw_fd()).map_err(ServerError::EnableCloexec)
     }
 
@@ -854,15 +1468,71 @@ impl Server {
             next_session_id: self.next_session_id,
             next_task_id: self.next_task_id,
             next_worker_id: self.next_worker_id,
+            epoch: self.epoch,
+            change_history: self.change_history.iter().cloned().collect(),
+            signing_keys: self.signing_keys.clone(),
+            generation: self.generation + 1,
+            health_overrides: self.health_checks.overrides(),
+            broker: self.broker.as_ref().map(|broker| SerializedBroker {
+                channel_fd: broker.channel.fd(),
+                pid: broker.pid,
+            }),
         }
     }
 }
 
+impl Server {
+    /// number a state change sent to all workers, and keep it in the history
+    fn record_change(&mut self, request: &Request) {
+        if !request.is_state_change() {
+            return;
+        }
+        self.epoch += 1;
+
+        let max_size = self.config.change_history_size as usize;
+        if max_size == 0 {
+            return;
+        }
+        while self.change_history.len() >= max_size {
+            self.change_history.pop_front();
+        }
+        self.change_history.push_back(StateChange {
+            epoch: self.epoch,
+            request: request.clone(),
+        });
+    }
+
+    /// keep an event in the history, dropping the oldest ones past the configured size
+    pub fn record_event(&mut self, origin: String, event: Event) {
+        let max_size = self.config.event_history_size as usize;
+        if max_size == 0 {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        while self.event_history.len() >= max_size {
+            self.event_history.pop_front();
+        }
+        self.event_history.push_back(EventRecord {
+            timestamp,
+            origin,
+            event,
+        });
+    }
+}
+
 impl Debug for Server {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Server")
+            .field("alerts", &self.alerts)
             .field("config", &self.config)
             .field("event_subscribers", &self.event_subscribers)
+            .field("event_history", &self.event_history.len())
+            .field("epoch", &self.epoch)
+            .field("change_history", &self.change_history.len())
             .field("executable_path", &self.executable_path)
             .field("in_flight", &self.in_flight)
             .field("next_client_id", &self.next_client_id)
@@ -873,7 +1543,444 @@ impl Debug for Server {
             .field("queued_tasks", &self.queued_tasks)
             .field("run_state", &self.run_state)
             .field("unix_listener", &self.unix_listener)
+            .field("broker", &self.broker)
             .field("workers", &self.workers)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::{fd::IntoRawFd, unix::process::ExitStatusExt},
+        process::{Child, Command},
+    };
+
+    use sozu_command_lib::{
+        config::{ConfigBuilder, FileConfig},
+        proto::command::{
+            filtered_metrics::Inner, BuildInfo, Cluster, CountRequests, EventKind, GetChanges,
+            QueryBuildInfo, QueryEvents, Request, Response, StateChanges,
+        },
+    };
+    use sozu_lib::metrics::METRICS;
+
+    use super::*;
+    use crate::util::build_info;
+
+    fn command_hub(name: &str) -> CommandHub {
+        let path = std::env::temp_dir().join(format!("sozu-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix_listener = UnixListener::bind(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let config = ConfigBuilder::new(FileConfig::default(), "")
+            .into_config()
+            .unwrap();
+        CommandHub::new(unix_listener, config, String::new()).unwrap()
+    }
+
+    /// register a worker whose channel buffer holds at most `buffer_size` bytes,
+    /// standing for the process `pid`
+    fn add_worker(
+        hub: &mut CommandHub,
+        pid: pid_t,
+        buffer_size: u64,
+    ) -> (Token, Channel<WorkerResponse, WorkerRequest>) {
+        let (worker_channel, main_channel) =
+            Channel::<WorkerResponse, WorkerRequest>::generate(buffer_size, buffer_size).unwrap();
+        let (scm, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let scm_socket = ScmSocket::new(scm.into_raw_fd()).unwrap();
+        let worker = hub
+            .register_worker(0, pid, main_channel, scm_socket)
+            .unwrap();
+        (worker.token, worker_channel)
+    }
+
+    fn add_client(hub: &mut CommandHub) -> (Token, Channel<Request, Response>) {
+        let (client_channel, hub_channel) =
+            Channel::<Request, Response>::generate(10_000, 100_000).unwrap();
+        let token = hub.next_session_token();
+        let id = hub.next_client_id();
+        hub.clients
+            .insert(token, ClientSession::new(hub_channel, id, token));
+        (token, client_channel)
+    }
+
+    fn send_request(hub: &mut CommandHub, client_token: Token, request_type: RequestType) {
+        let (server, client) = hub.get_client_mut(&client_token).unwrap();
+        server.handle_client_request(client, request_type.into());
+        let queued_tasks: Vec<_> = hub.server.queued_tasks.drain().collect();
+        hub.tasks.extend(queued_tasks);
+    }
+
+    /// send a cluster large enough to fill a channel buffer of 1500 bytes
+    fn add_cluster(hub: &mut CommandHub, client_token: Token, index: usize) {
+        let cluster = Cluster {
+            cluster_id: format!("cluster_{index}_{}", "x".repeat(1000)),
+            ..Default::default()
+        };
+        send_request(hub, client_token, RequestType::AddCluster(cluster));
+    }
+
+    /// what the event loop does with the tasks that got all their responses
+    fn finish_tasks(hub: &mut CommandHub) {
+        let finished: Vec<TaskId> = hub
+            .tasks
+            .iter_mut()
+            .filter_map(|(task_id, task)| {
+                task.job.get_gatherer().has_finished().then_some(*task_id)
+            })
+            .collect();
+        for task_id in finished {
+            let task = hub.tasks.remove(&task_id).unwrap();
+            hub.handle_finishing_task(task_id, task, false);
+        }
+    }
+
+    /// the final responses written to a client, skipping the processing ones
+    fn client_responses(
+        hub: &mut CommandHub,
+        client_token: Token,
+        client_channel: &mut Channel<Request, Response>,
+    ) -> Vec<Response> {
+        let client = hub.clients.get_mut(&client_token).unwrap();
+        client.update_readiness(Ready::WRITABLE);
+        client.ready();
+        let mut responses = Vec::new();
+        while let Ok(response) =
+            client_channel.read_message_blocking_timeout(Some(Duration::from_millis(100)))
+        {
+            if response.status != ResponseStatus::Processing as i32 {
+                responses.push(response);
+            }
+        }
+        responses
+    }
+
+    fn sleeping_process() -> Child {
+        Command::new("sleep").arg("30").spawn().unwrap()
+    }
+
+    #[test]
+    fn close_a_worker_whose_queue_overflows() {
+        let mut hub = command_hub("overflow");
+        hub.config.worker_queue_size = 1;
+        let mut process = sleeping_process();
+        let (worker_token, _worker_channel) = add_worker(&mut hub, process.id() as pid_t, 1500);
+        let (client_token, mut client_channel) = add_client(&mut hub);
+
+        // one request in the channel buffer, one in the queue, the last one overflows
+        for index in 0..3 {
+            add_cluster(&mut hub, client_token, index);
+        }
+        assert!(hub.workers[&worker_token].has_overflowed());
+
+        hub.check_worker_lag(Instant::now());
+        finish_tasks(&mut hub);
+
+        let responses = client_responses(&mut hub, client_token, &mut client_channel);
+        assert_eq!(responses.len(), 3, "{responses:?}");
+        for response in responses {
+            assert_eq!(response.status, ResponseStatus::Failure as i32);
+            assert!(
+                response
+                    .message
+                    .contains("worker 0 has too many requests waiting for it"),
+                "{}",
+                response.message
+            );
+        }
+        assert!(hub.in_flight.is_empty());
+        assert_eq!(hub.workers[&worker_token].run_state, RunState::Stopped);
+        assert_eq!(
+            process.wait().unwrap().signal(),
+            Some(Signal::SIGKILL as i32)
+        );
+    }
+
+    #[test]
+    fn fail_the_late_requests_of_a_worker_until_it_answers() {
+        let mut hub = command_hub("lag");
+        let mut process = sleeping_process();
+        let (worker_token, mut worker_channel) =
+            add_worker(&mut hub, process.id() as pid_t, 10_000);
+        let (client_token, mut client_channel) = add_client(&mut hub);
+
+        add_cluster(&mut hub, client_token, 0);
+        let timeout = Duration::from_secs(hub.config.worker_timeout as u64);
+        hub.check_worker_lag(Instant::now() + timeout / 2);
+        assert_eq!(hub.workers[&worker_token].run_state, RunState::Running);
+
+        hub.check_worker_lag(Instant::now() + timeout + Duration::from_secs(1));
+        assert_eq!(hub.workers[&worker_token].run_state, RunState::NotAnswering);
+        finish_tasks(&mut hub);
+        let responses = client_responses(&mut hub, client_token, &mut client_channel);
+        assert_eq!(responses.len(), 1, "{responses:?}");
+        assert_eq!(responses[0].status, ResponseStatus::Failure as i32);
+        assert!(
+            responses[0].message.contains("did not answer in time"),
+            "{}",
+            responses[0].message
+        );
+
+        // the worker answers the next request, and is running again
+        add_cluster(&mut hub, client_token, 1);
+        let worker = hub.workers.get_mut(&worker_token).unwrap();
+        worker.update_readiness(Ready::WRITABLE);
+        worker.ready();
+        let late = worker_channel.read_message().unwrap();
+        let next = worker_channel.read_message().unwrap();
+        assert_ne!(late.id, next.id);
+        worker_channel
+            .write_message(&WorkerResponse::ok(next.id.to_owned()))
+            .unwrap();
+        worker.update_readiness(Ready::READABLE);
+        let WorkerResult::NewResponses(worker_responses) = worker.ready() else {
+            panic!("the worker response was not read");
+        };
+        assert_eq!(worker.run_state, RunState::Running);
+        for response in worker_responses {
+            hub.handle_worker_response(0, response);
+        }
+        finish_tasks(&mut hub);
+        let responses = client_responses(&mut hub, client_token, &mut client_channel);
+        assert_eq!(responses.len(), 1, "{responses:?}");
+        assert_eq!(responses[0].status, ResponseStatus::Ok as i32);
+        process.kill().unwrap();
+    }
+
+    #[test]
+    fn report_the_activity_of_the_main_process() {
+        let mut hub = command_hub("activity");
+        let mut process = sleeping_process();
+        let (_worker_token, _worker_channel) = add_worker(&mut hub, process.id() as pid_t, 10_000);
+        let (client_token, _client_channel) = add_client(&mut hub);
+
+        for _ in 0..2 {
+            send_request(
+                &mut hub,
+                client_token,
+                RequestType::CountRequests(CountRequests {}),
+            );
+        }
+        add_cluster(&mut hub, client_token, 0);
+        hub.server.update_counts();
+        hub.server.update_activity_metrics();
+
+        let metrics = METRICS.with(|metrics| metrics.borrow_mut().dump_local_proxy_metrics());
+        let value = |key: &str| match metrics.get(key).and_then(|metric| metric.inner.as_ref()) {
+            Some(Inner::Gauge(value)) => *value as i64,
+            Some(Inner::Count(value)) => *value,
+            other => panic!("unexpected value of {key}: {other:?}"),
+        };
+        assert_eq!(value("command.requests.CountRequests"), 2);
+        assert_eq!(value("command.requests.AddCluster"), 1);
+        assert_eq!(value("configuration.clusters"), 1);
+        assert_eq!(value("workers.running"), 1);
+        assert_eq!(value("workers.stopped"), 0);
+        // the cluster was written to the worker channel, it did not read it
+        assert_eq!(value("command.in_flight"), 1);
+        assert!(value("workers.channel.queued_bytes") > 1000);
+        process.kill().unwrap();
+    }
+
+    #[test]
+    fn report_the_builds_of_the_main_process_and_workers() {
+        let mut hub = command_hub("builds");
+        let mut process = sleeping_process();
+        let (worker_token, mut worker_channel) =
+            add_worker(&mut hub, process.id() as pid_t, 10_000);
+        let (client_token, mut client_channel) = add_client(&mut hub);
+
+        send_request(
+            &mut hub,
+            client_token,
+            RequestType::QueryBuildInfo(QueryBuildInfo {}),
+        );
+        let worker = hub.workers.get_mut(&worker_token).unwrap();
+        worker.update_readiness(Ready::WRITABLE);
+        worker.ready();
+        let request = worker_channel.read_message().unwrap();
+        // a worker running an older build
+        let worker_build = BuildInfo {
+            version: String::from("0.15.0"),
+            protocol_version: 0,
+            ..Default::default()
+        };
+        worker_channel
+            .write_message(&WorkerResponse::ok_with_content(
+                request.id,
+                ContentType::BuildInfo(worker_build.clone()).into(),
+            ))
+            .unwrap();
+        worker.update_readiness(Ready::READABLE);
+        let WorkerResult::NewResponses(worker_responses) = worker.ready() else {
+            panic!("the worker response was not read");
+        };
+        for response in worker_responses {
+            hub.handle_worker_response(0, response);
+        }
+        finish_tasks(&mut hub);
+
+        let mut responses = client_responses(&mut hub, client_token, &mut client_channel);
+        let builds = match responses.pop().and_then(|response| response.content) {
+            Some(ResponseContent {
+                content_type: Some(ContentType::BuildInfos(builds)),
+            }) => builds,
+            other => panic!("unexpected response content: {other:?}"),
+        };
+        assert_eq!(builds.main, build_info());
+        assert_eq!(builds.main.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(builds.workers.len(), 1);
+        assert_eq!(builds.workers["0"], worker_build);
+        process.kill().unwrap();
+    }
+
+    fn get_changes(
+        hub: &mut CommandHub,
+        client_token: Token,
+        client_channel: &mut Channel<Request, Response>,
+        since_epoch: u64,
+    ) -> StateChanges {
+        send_request(
+            hub,
+            client_token,
+            RequestType::GetChanges(GetChanges { since_epoch }),
+        );
+        let mut responses = client_responses(hub, client_token, client_channel);
+        match responses.pop().and_then(|response| response.content) {
+            Some(ResponseContent {
+                content_type: Some(ContentType::StateChanges(changes)),
+            }) => changes,
+            other => panic!("unexpected response content: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn keep_the_recent_state_changes() {
+        let mut hub = command_hub("changes");
+        hub.config.change_history_size = 2;
+        let (client_token, mut client_channel) = add_client(&mut hub);
+        let start = hub.epoch;
+
+        for index in 0..3 {
+            add_cluster(&mut hub, client_token, index);
+        }
+        // queries do not change the state
+        send_request(
+            &mut hub,
+            client_token,
+            RequestType::CountRequests(CountRequests {}),
+        );
+        finish_tasks(&mut hub);
+        client_responses(&mut hub, client_token, &mut client_channel);
+
+        assert_eq!(hub.epoch, start + 3);
+        let epochs: Vec<u64> = hub
+            .change_history
+            .iter()
+            .map(|change| change.epoch)
+            .collect();
+        assert_eq!(epochs, vec![start + 2, start + 3]);
+
+        // the changes following a kept epoch
+        let changes = get_changes(&mut hub, client_token, &mut client_channel, start + 2);
+        assert!(!changes.is_snapshot);
+        assert_eq!(changes.epoch, start + 3);
+        assert_eq!(changes.changes.len(), 1);
+        assert_eq!(changes.changes[0].epoch, start + 3);
+        let changes = get_changes(&mut hub, client_token, &mut client_channel, start + 1);
+        assert!(!changes.is_snapshot);
+        assert_eq!(changes.changes.len(), 2);
+        let changes = get_changes(&mut hub, client_token, &mut client_channel, start + 3);
+        assert!(!changes.is_snapshot);
+        assert!(changes.changes.is_empty());
+
+        // the change following `start` was dropped from the history
+        let changes = get_changes(&mut hub, client_token, &mut client_channel, start);
+        assert!(changes.is_snapshot);
+        assert!(changes.changes.is_empty());
+        let clusters = changes
+            .snapshot
+            .iter()
+            .filter(|request| matches!(request.request_type, Some(RequestType::AddCluster(_))))
+            .count();
+        assert_eq!(clusters, 3);
+
+        // an epoch ahead of this main process comes from another one
+        let changes = get_changes(&mut hub, client_token, &mut client_channel, start + 10);
+        assert!(changes.is_snapshot);
+        assert_eq!(changes.epoch, start + 3);
+    }
+
+    #[test]
+    fn query_the_recent_events() {
+        let mut hub = command_hub("events");
+        hub.config.event_history_size = 3;
+        let (client_token, mut client_channel) = add_client(&mut hub);
+
+        for (kind, cluster_id) in [
+            (EventKind::BackendDown, "cluster_1"),
+            (EventKind::BackendDown, "cluster_2"),
+            (EventKind::BackendUp, "cluster_1"),
+            (EventKind::BackendUp, "cluster_2"),
+        ] {
+            let event = Event {
+                kind: kind as i32,
+                cluster_id: Some(String::from(cluster_id)),
+                backend_id: Some(format!("{cluster_id}-0")),
+                ..Default::default()
+            };
+            hub.broadcast_event("worker 0", event);
+        }
+        // the oldest event was dropped
+        assert_eq!(hub.event_history.len(), 3);
+
+        let mut query_events = |filters: QueryEvents| {
+            send_request(&mut hub, client_token, RequestType::QueryEvents(filters));
+            let mut responses = client_responses(&mut hub, client_token, &mut client_channel);
+            match responses.pop().and_then(|response| response.content) {
+                Some(ResponseContent {
+                    content_type: Some(ContentType::EventHistory(history)),
+                }) => history.records,
+                other => panic!("unexpected response content: {other:?}"),
+            }
+        };
+
+        let records = query_events(QueryEvents {
+            cluster_id: Some(String::from("cluster_1")),
+            ..Default::default()
+        });
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].origin, "worker 0");
+        assert_eq!(records[0].event.kind, EventKind::BackendUp as i32);
+
+        let records = query_events(QueryEvents {
+            backend_id: Some(String::from("cluster_2-0")),
+            ..Default::default()
+        });
+        let kinds: Vec<i32> = records.iter().map(|record| record.event.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![EventKind::BackendDown as i32, EventKind::BackendUp as i32]
+        );
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(
+            query_events(QueryEvents {
+                since: Some(now - 60),
+                ..Default::default()
+            })
+            .len(),
+            3
+        );
+        assert!(query_events(QueryEvents {
+            since: Some(now + 60),
+            ..Default::default()
+        })
+        .is_empty());
+    }
+}
@@ -1,14 +1,18 @@
-use std::fmt::Debug;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    time::{Duration, Instant},
+};
 
 use libc::pid_t;
 use mio::Token;
 use prost::Message;
 
 use sozu_command_lib::{
-    channel::Channel,
+    channel::{Channel, ChannelError},
     proto::command::{
-        Request, Response, ResponseContent, ResponseStatus, RunState, WorkerInfo, WorkerRequest,
-        WorkerResponse,
+        BrokerRequest, BrokerResponse, Request, Response, ResponseContent, ResponseError,
+        ResponseStatus, RunState, WorkerInfo, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
     scm_socket::ScmSocket,
@@ -16,10 +20,23 @@ use sozu_command_lib::{
 
 use crate::command::server::{ClientId, MessageClient, WorkerId};
 
+/// How the main process talks to a client
+#[derive(Debug)]
+pub enum ClientTransport {
+    /// the client connected to the command socket of the main process
+    Channel(Channel<Response, Request>),
+    /// the client connected to the command broker, that relays its requests.
+    /// The responses wait here until the command hub writes them to the broker
+    Broker {
+        client_id: u32,
+        responses: VecDeque<Response>,
+    },
+}
+
 /// Track a client from start to finish
 #[derive(Debug)]
 pub struct ClientSession {
-    pub channel: Channel<Response, Request>,
+    pub transport: ClientTransport,
     pub id: ClientId,
     pub token: Token,
 }
@@ -35,32 +52,58 @@ pub enum ClientResult {
 impl ClientSession {
     pub fn new(mut channel: Channel<Response, Request>, id: ClientId, token: Token) -> Self {
         channel.interest = Ready::READABLE | Ready::ERROR | Ready::HUP;
-        Self { channel, id, token }
+        Self {
+            transport: ClientTransport::Channel(channel),
+            id,
+            token,
+        }
+    }
+
+    /// a client of the command broker, known by the broker as `broker_client_id`
+    pub fn new_brokered(broker_client_id: u32, id: ClientId, token: Token) -> Self {
+        Self {
+            transport: ClientTransport::Broker {
+                client_id: broker_client_id,
+                responses: VecDeque::new(),
+            },
+            id,
+            token,
+        }
     }
 
     /// queue a response for the client (the event loop does the send)
     fn send(&mut self, response: Response) {
-        if let Err(e) = self.channel.write_message(&response) {
-            error!("error writing on channel: {}", e);
-            self.channel.readiness = Ready::ERROR;
-            return;
+        match &mut self.transport {
+            ClientTransport::Channel(channel) => {
+                if let Err(e) = channel.write_message(&response) {
+                    error!("error writing on channel: {}", e);
+                    channel.readiness = Ready::ERROR;
+                    return;
+                }
+                channel.interest.insert(Ready::WRITABLE);
+            }
+            ClientTransport::Broker { responses, .. } => responses.push_back(response),
         }
-        self.channel.interest.insert(Ready::WRITABLE);
     }
 
     pub fn update_readiness(&mut self, events: Ready) {
-        self.channel.handle_events(events);
+        if let ClientTransport::Channel(channel) = &mut self.transport {
+            channel.handle_events(events);
+        }
     }
 
     /// drive the channel read and write
     pub fn ready(&mut self) -> ClientResult {
-        if self.channel.readiness.is_error() || self.channel.readiness.is_hup() {
+        let ClientTransport::Channel(channel) = &mut self.transport else {
+            return ClientResult::NothingToDo;
+        };
+        if channel.readiness.is_error() || channel.readiness.is_hup() {
             return ClientResult::CloseSession;
         }
 
-        let status = self.channel.writable();
+        let status = channel.writable();
         trace!("client writable: {:?}", status);
-        let mut requests = extract_messages(&mut self.channel);
+        let mut requests = extract_messages(channel);
         match requests.pop() {
             Some(request) => {
                 if !requests.is_empty() {
@@ -71,6 +114,22 @@ impl ClientSession {
             None => ClientResult::NothingToDo,
         }
     }
+
+    /// the event loop should tick the channel of the client, without waiting for events
+    pub fn wants_to_tick(&self) -> bool {
+        match &self.transport {
+            ClientTransport::Channel(channel) => wants_to_tick(channel),
+            ClientTransport::Broker { .. } => false,
+        }
+    }
+
+    /// some responses were not sent yet
+    pub fn has_pending_responses(&self) -> bool {
+        match &self.transport {
+            ClientTransport::Channel(channel) => channel.back_buf.available_data() > 0,
+            ClientTransport::Broker { responses, .. } => !responses.is_empty(),
+        }
+    }
 }
 
 impl MessageClient for ClientSession {
@@ -81,6 +140,7 @@ impl MessageClient for ClientSession {
             status: ResponseStatus::Ok.into(),
             message,
             content: None,
+            error: None,
         })
     }
 
@@ -91,6 +151,7 @@ impl MessageClient for ClientSession {
             status: ResponseStatus::Ok.into(),
             message,
             content: Some(content),
+            error: None,
         })
     }
 
@@ -101,9 +162,16 @@ impl MessageClient for ClientSession {
             status: ResponseStatus::Failure.into(),
             message,
             content: None,
+            error: None,
         })
     }
 
+    fn finish_failure_with_error<T: Into<String>>(&mut self, message: T, error: ResponseError) {
+        let message = message.into();
+        error!("{}: {}", message, error);
+        self.send(Response::failure(message, error))
+    }
+
     fn return_processing<S: Into<String>>(&mut self, message: S) {
         let message = message.into();
         info!("{}", message);
@@ -111,6 +179,7 @@ impl MessageClient for ClientSession {
             status: ResponseStatus::Processing.into(),
             message,
             content: None,
+            error: None,
         });
     }
 
@@ -125,6 +194,7 @@ impl MessageClient for ClientSession {
             status: ResponseStatus::Processing.into(),
             message,
             content: Some(content),
+            error: None,
         });
     }
 }
@@ -153,6 +223,13 @@ impl MessageClient for OptionalClient<'_> {
         }
     }
 
+    fn finish_failure_with_error<T: Into<String>>(&mut self, message: T, error: ResponseError) {
+        match self {
+            None => error!("{}: {}", message.into(), error),
+            Some(client) => client.finish_failure_with_error(message, error),
+        }
+    }
+
     fn return_processing<T: Into<String>>(&mut self, message: T) {
         match self {
             None => info!("{}", message.into()),
@@ -182,6 +259,17 @@ pub struct WorkerSession {
     /// meant to send listeners to the worker upon start
     pub scm_socket: ScmSocket,
     pub token: Token,
+    /// requests waiting for room in the channel, when the worker reads slowly
+    queue: VecDeque<WorkerRequest>,
+    max_queued_requests: usize,
+    /// set when a request did not fit in the queue, the worker should be closed
+    overflowed: bool,
+    /// requests sent to the worker and not answered yet, with the date they were sent
+    in_flight: HashMap<String, Instant>,
+    /// sequence number of the last request sent, the worker checks that none is missing
+    sequence: u64,
+    /// generation of the main process that launched the worker
+    pub generation: u32,
 }
 
 /// The return type of the ready method
@@ -199,6 +287,7 @@ impl WorkerSession {
         pid: pid_t,
         token: Token,
         scm_socket: ScmSocket,
+        max_queued_requests: usize,
     ) -> Self {
         channel.interest = Ready::READABLE | Ready::ERROR | Ready::HUP;
         Self {
@@ -208,18 +297,129 @@ impl WorkerSession {
             run_state: RunState::Running,
             scm_socket,
             token,
+            queue: VecDeque::new(),
+            max_queued_requests,
+            overflowed: false,
+            in_flight: HashMap::new(),
+            sequence: 0,
+            generation: 0,
         }
     }
 
     /// queue a request for the worker (the event loop does the send)
+    ///
+    /// Once the channel buffer is full, requests wait in a bounded queue,
+    /// so that a slow worker does not fail the requests sent to it.
+    /// Each request is numbered, in the order it is sent.
     pub fn send(&mut self, request: &WorkerRequest) {
+        self.sequence += 1;
+        let request = WorkerRequest {
+            sequence: Some(self.sequence),
+            ..request.to_owned()
+        };
+        let request = &request;
         trace!("Sending to worker: {:?}", request);
-        if let Err(e) = self.channel.write_message(request) {
-            error!("Could not send request to worker: {}", e);
-            self.channel.readiness = Ready::ERROR;
+        self.in_flight.insert(request.id.to_owned(), Instant::now());
+
+        if self.queue.is_empty() && self.write(request) {
+            return;
+        }
+        if self.queue.len() >= self.max_queued_requests {
+            error!(
+                "worker {} does not read its channel, its queue of {} requests is full",
+                self.id,
+                self.queue.len()
+            );
+            self.overflowed = true;
             return;
         }
+        self.queue.push_back(request.to_owned());
+    }
+
+    /// write a request in the channel buffer, returns false if there is no room for it yet
+    fn write(&mut self, request: &WorkerRequest) -> bool {
+        match self.channel.write_message(request) {
+            Ok(()) => {}
+            Err(ChannelError::MessageTooLarge(_)) if self.channel.back_buf.available_data() > 0 => {
+                return false
+            }
+            Err(e) => {
+                error!("Could not send request to worker: {}", e);
+                self.channel.readiness = Ready::ERROR;
+                return true;
+            }
+        }
         self.channel.interest.insert(Ready::WRITABLE);
+        true
+    }
+
+    /// move queued requests to the channel buffer, as long as they fit
+    fn flush_queue(&mut self) {
+        while let Some(request) = self.queue.pop_front() {
+            if !self.write(&request) {
+                self.queue.push_front(request);
+                break;
+            }
+        }
+    }
+
+    /// stop waiting for a request once the worker sent its final response
+    fn acknowledge(&mut self, response: &WorkerResponse) {
+        if response.status == ResponseStatus::Processing as i32 {
+            return;
+        }
+        if self.in_flight.remove(&response.id).is_some() && self.run_state == RunState::NotAnswering
+        {
+            info!("worker {} answers again", self.id);
+            self.run_state = RunState::Running;
+        }
+    }
+
+    /// time elapsed since the oldest unanswered request was sent
+    pub fn lag(&self, now: Instant) -> Duration {
+        self.in_flight
+            .values()
+            .min()
+            .map(|sent_at| now.saturating_duration_since(*sent_at))
+            .unwrap_or_default()
+    }
+
+    /// sequence number of the last request sent
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// continue the numbering of a worker inherited from a previous main process
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+    }
+
+    pub fn queued_requests(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn has_overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// stop waiting for the requests sent before `deadline`, and return their ids
+    pub fn take_late_requests(&mut self, deadline: Instant) -> Vec<String> {
+        let late: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, sent_at)| **sent_at < deadline)
+            .map(|(id, _)| id.to_owned())
+            .collect();
+        for id in &late {
+            self.in_flight.remove(id);
+        }
+        late
+    }
+
+    /// drop the queue and return the ids of all unanswered requests, when closing the worker
+    pub fn abandon_requests(&mut self) -> Vec<String> {
+        self.queue.clear();
+        self.in_flight.drain().map(|(id, _)| id).collect()
     }
 
     pub fn update_readiness(&mut self, events: Ready) {
@@ -230,7 +430,11 @@ impl WorkerSession {
     pub fn ready(&mut self) -> WorkerResult {
         let status = self.channel.writable();
         trace!("Worker writable: {:?}", status);
+        self.flush_queue();
         let responses = extract_messages(&mut self.channel);
+        for response in &responses {
+            self.acknowledge(response);
+        }
         if !responses.is_empty() {
             return WorkerResult::NewResponses(responses);
         }
@@ -254,6 +458,9 @@ impl WorkerSession {
             id: self.id,
             pid: self.pid,
             run_state: run_state as i32,
+            lag: Some(self.lag(Instant::now()).as_millis() as u64),
+            queued_requests: Some(self.queue.len() as u32),
+            generation: Some(self.generation),
         }
     }
 
@@ -262,6 +469,73 @@ impl WorkerSession {
     }
 }
 
+/// Follow the command broker, the process accepting the clients of the command socket
+#[derive(Debug)]
+pub struct BrokerSession {
+    pub channel: Channel<BrokerResponse, BrokerRequest>,
+    pub pid: pid_t,
+    pub token: Token,
+    /// id of a client in the broker -> token of its session in the command hub
+    pub clients: HashMap<u32, Token>,
+}
+
+/// The return type of the ready method
+#[derive(Debug)]
+pub enum BrokerResult {
+    NothingToDo,
+    NewRequests(Vec<BrokerRequest>),
+    CloseSession,
+}
+
+impl BrokerSession {
+    pub fn new(
+        mut channel: Channel<BrokerResponse, BrokerRequest>,
+        pid: pid_t,
+        token: Token,
+    ) -> Self {
+        channel.interest = Ready::READABLE | Ready::ERROR | Ready::HUP;
+        Self {
+            channel,
+            pid,
+            token,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// queue a response for a client of the broker (the event loop does the send)
+    pub fn send(&mut self, response: &BrokerResponse) {
+        if let Err(e) = self.channel.write_message(response) {
+            error!("error writing on the channel of the broker: {}", e);
+            self.channel.readiness = Ready::ERROR;
+            return;
+        }
+        self.channel.interest.insert(Ready::WRITABLE);
+    }
+
+    pub fn update_readiness(&mut self, events: Ready) {
+        self.channel.handle_events(events);
+    }
+
+    /// drive the channel write, and the read unless `read` is false
+    pub fn ready(&mut self, read: bool) -> BrokerResult {
+        let status = self.channel.writable();
+        trace!("broker writable: {:?}", status);
+        if read {
+            let requests = extract_messages(&mut self.channel);
+            if !requests.is_empty() {
+                return BrokerResult::NewRequests(requests);
+            }
+        }
+
+        if self.channel.readiness.is_error() || self.channel.readiness.is_hup() {
+            debug!("the command broker is unresponsive, closing the session");
+            return BrokerResult::CloseSession;
+        }
+
+        BrokerResult::NothingToDo
+    }
+}
+
 /// read and parse messages (Requests or Responses) from the channel
 pub fn extract_messages<Tx, Rx>(channel: &mut Channel<Tx, Rx>) -> Vec<Rx>
 where
@@ -291,3 +565,146 @@ pub fn wants_to_tick<Tx, Rx>(channel: &Channel<Tx, Rx>) -> bool {
     (channel.readiness.is_writable() && channel.back_buf.available_data() > 0)
         || (channel.readiness.is_hup() || channel.readiness.is_error())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::{fd::IntoRawFd, unix::net::UnixStream};
+
+    use sozu_command_lib::proto::command::{request::RequestType, Status};
+
+    use super::*;
+
+    #[test]
+    fn number_the_requests_sent_to_a_worker() {
+        let (mut worker_channel, main_channel) =
+            Channel::<WorkerResponse, WorkerRequest>::generate(1000, 10000).unwrap();
+        let (scm, _) = UnixStream::pair().unwrap();
+        let scm_socket = ScmSocket::new(scm.into_raw_fd()).unwrap();
+        let mut session = WorkerSession::new(main_channel, 0, 0, Token(1), scm_socket, 10);
+
+        for id in ["FIRST", "SECOND"] {
+            session.send(&WorkerRequest::new(
+                id.to_owned(),
+                RequestType::Status(Status {}).into(),
+            ));
+        }
+        session.update_readiness(Ready::WRITABLE);
+        session.ready();
+
+        let first = worker_channel.read_message().unwrap();
+        let second = worker_channel.read_message().unwrap();
+        assert_eq!((first.id.as_str(), first.sequence), ("FIRST", Some(1)));
+        assert_eq!((second.id.as_str(), second.sequence), ("SECOND", Some(2)));
+        assert_eq!(session.sequence(), 2);
+    }
+
+    /// a worker session whose channel buffer holds at most `buffer_size` bytes
+    fn worker_session(
+        buffer_size: u64,
+        max_queued_requests: usize,
+    ) -> (Channel<WorkerResponse, WorkerRequest>, WorkerSession) {
+        let (worker_channel, main_channel) =
+            Channel::<WorkerResponse, WorkerRequest>::generate(buffer_size, buffer_size).unwrap();
+        let (scm, _) = UnixStream::pair().unwrap();
+        let scm_socket = ScmSocket::new(scm.into_raw_fd()).unwrap();
+        let session = WorkerSession::new(
+            main_channel,
+            0,
+            0,
+            Token(1),
+            scm_socket,
+            max_queued_requests,
+        );
+        (worker_channel, session)
+    }
+
+    /// a request of about 60 bytes once written in the channel
+    fn status_request(id: &str) -> WorkerRequest {
+        WorkerRequest::new(format!("{id:-<40}"), RequestType::Status(Status {}).into())
+    }
+
+    #[test]
+    fn queue_the_requests_while_the_channel_is_full() {
+        let (mut worker_channel, mut session) = worker_session(100, 10);
+
+        session.send(&status_request("FIRST"));
+        session.send(&status_request("SECOND"));
+        assert_eq!(session.queued_requests(), 1);
+        assert!(!session.has_overflowed());
+
+        // the first request leaves the buffer, the second one takes its place
+        session.update_readiness(Ready::WRITABLE);
+        session.ready();
+        assert_eq!(session.queued_requests(), 0);
+        session.ready();
+
+        let first = worker_channel.read_message().unwrap();
+        let second = worker_channel.read_message().unwrap();
+        assert!(first.id.starts_with("FIRST"));
+        assert!(second.id.starts_with("SECOND"));
+        assert_eq!(second.sequence, Some(2));
+    }
+
+    #[test]
+    fn overflow_when_the_queue_is_full() {
+        let (_worker_channel, mut session) = worker_session(100, 1);
+
+        for id in ["FIRST", "SECOND", "THIRD"] {
+            session.send(&status_request(id));
+        }
+        assert!(session.has_overflowed());
+        assert_eq!(session.queued_requests(), 1);
+
+        // closing the worker gives up on every request sent to it, written or not
+        let mut abandoned = session.abandon_requests();
+        abandoned.sort();
+        assert_eq!(abandoned.len(), 3);
+        assert!(abandoned[0].starts_with("FIRST"));
+        assert_eq!(session.queued_requests(), 0);
+        assert_eq!(session.lag(Instant::now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn recover_a_late_worker_on_its_next_answer() {
+        let (mut worker_channel, mut session) = worker_session(1000, 10);
+
+        let late = status_request("LATE");
+        session.send(&late);
+        assert!(session
+            .take_late_requests(Instant::now() - Duration::from_secs(1))
+            .is_empty());
+        assert_eq!(
+            session.take_late_requests(Instant::now() + Duration::from_secs(1)),
+            vec![late.id.to_owned()]
+        );
+        session.run_state = RunState::NotAnswering;
+        assert_eq!(
+            session.querying_info().run_state,
+            RunState::NotAnswering as i32
+        );
+
+        let next = status_request("NEXT");
+        session.send(&next);
+        session.update_readiness(Ready::WRITABLE);
+        session.ready();
+
+        // a processing response is not an answer yet
+        let mut processing = WorkerResponse::ok(next.id.to_owned());
+        processing.status = ResponseStatus::Processing as i32;
+        worker_channel.write_message(&processing).unwrap();
+        session.update_readiness(Ready::READABLE);
+        session.ready();
+        assert_eq!(session.run_state, RunState::NotAnswering);
+
+        worker_channel
+            .write_message(&WorkerResponse::ok(next.id.to_owned()))
+            .unwrap();
+        session.update_readiness(Ready::READABLE);
+        assert!(matches!(
+            session.ready(),
+            WorkerResult::NewResponses(responses) if responses.len() == 1
+        ));
+        assert_eq!(session.run_state, RunState::Running);
+        assert_eq!(session.lag(Instant::now()), Duration::ZERO);
+    }
+}
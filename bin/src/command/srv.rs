@@ -0,0 +1,495 @@
+//! Backends discovered through DNS SRV records
+//!
+//! A cluster with a `backend_srv_record` gets its backends from this record, like the
+//! ones served by Consul DNS or for Kubernetes headless services. Every
+//! `srv_refresh_interval`, the main process resolves the records in a separate thread,
+//! and replaces the backends of the clusters whose records changed.
+//!
+//! The targets with the lowest priority become the backends of the cluster, the
+//! others are backups. The SRV weight is used as load balancing weight.
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sozu_command_lib::{
+    proto::command::{AddBackend, LoadBalancingParams},
+    state::ClusterId,
+};
+
+/// how long the DNS server may take to answer
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// size of the UDP answers accepted, announced with EDNS
+const MAX_ANSWER_SIZE: u16 = 4096;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SrvError {
+    #[error("no DNS server set with dns_resolver, and none found in /etc/resolv.conf")]
+    NoResolver,
+    #[error("invalid record name {0}")]
+    InvalidName(String),
+    #[error("could not query {resolver}: {error}")]
+    Query {
+        resolver: SocketAddr,
+        error: std::io::Error,
+    },
+    #[error("the DNS server answered with error code {0}")]
+    Rcode(u8),
+    #[error("the answer is truncated, it does not fit in {MAX_ANSWER_SIZE} bytes")]
+    Truncated,
+    #[error("invalid DNS answer: {0}")]
+    InvalidAnswer(&'static str),
+    #[error("the record has no target with an address")]
+    NoTarget,
+}
+
+/// a target of an SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// host name, without the final dot
+    pub target: String,
+}
+
+/// the targets of an SRV answer, and the addresses of the targets it contains
+type SrvAnswer = (Vec<SrvTarget>, HashMap<String, Vec<IpAddr>>);
+
+/// the backends found for a cluster, or why they could not be
+type SrvResult = (ClusterId, Result<Vec<AddBackend>, SrvError>);
+
+/// Resolution of the SRV records between two refreshes
+#[derive(Debug)]
+pub struct SrvDiscovery {
+    next_check: Instant,
+    /// a resolution runs in a separate thread
+    resolving: bool,
+    /// the thread sends all its results at once
+    results: (Sender<Vec<SrvResult>>, Receiver<Vec<SrvResult>>),
+}
+
+impl Default for SrvDiscovery {
+    fn default() -> Self {
+        Self {
+            next_check: Instant::now(),
+            resolving: false,
+            results: mpsc::channel(),
+        }
+    }
+}
+
+impl SrvDiscovery {
+    /// returns true, and plans the next one, if a resolution is due
+    pub fn check_due(&mut self, now: Instant, interval: u64) -> bool {
+        if self.resolving || self.next_check > now {
+            return false;
+        }
+        self.next_check = now + Duration::from_secs(interval.max(1));
+        true
+    }
+
+    /// resolve the SRV records of the clusters in a separate thread
+    pub fn resolve(&mut self, records: Vec<(ClusterId, String)>, resolver: Option<SocketAddr>) {
+        if records.is_empty() {
+            return;
+        }
+        self.resolving = true;
+        let sender = self.results.0.clone();
+        thread::spawn(move || {
+            let results = records
+                .into_iter()
+                .map(|(cluster_id, record)| {
+                    let backends = resolver
+                        .or_else(system_resolver)
+                        .ok_or(SrvError::NoResolver)
+                        .and_then(|resolver| resolve_backends(&cluster_id, &record, resolver));
+                    (cluster_id, backends)
+                })
+                .collect();
+            let _ = sender.send(results);
+        });
+    }
+
+    /// results of the last resolution, once it is over
+    pub fn take_results(&mut self) -> Vec<SrvResult> {
+        match self.results.1.try_recv() {
+            Ok(results) => {
+                self.resolving = false;
+                results
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// the first nameserver of /etc/resolv.conf
+fn system_resolver() -> Option<SocketAddr> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(address)) => address
+                .parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, 53)),
+            _ => None,
+        }
+    })
+}
+
+/// query the SRV record, and turn its targets into backends of the cluster
+fn resolve_backends(
+    cluster_id: &str,
+    record: &str,
+    resolver: SocketAddr,
+) -> Result<Vec<AddBackend>, SrvError> {
+    let (targets, addresses) = query_srv(record, resolver)?;
+
+    let targets = targets
+        .into_iter()
+        .map(|target| {
+            let ips = match addresses.get(&target.target) {
+                Some(ips) => ips.clone(),
+                // the server did not send the addresses with the record
+                None => (target.target.as_str(), target.port)
+                    .to_socket_addrs()
+                    .map(|addresses| addresses.map(|address| address.ip()).collect())
+                    .unwrap_or_else(|error| {
+                        warn!("could not resolve SRV target {}: {}", target.target, error);
+                        Vec::new()
+                    }),
+            };
+            (target, ips)
+        })
+        .collect::<Vec<_>>();
+
+    let backends = srv_backends(cluster_id, &targets);
+    if backends.is_empty() {
+        return Err(SrvError::NoTarget);
+    }
+    Ok(backends)
+}
+
+/// Backends of a cluster from the targets of its SRV record, and their addresses.
+/// The targets with the lowest priority are the main backends, the others are backups
+pub fn srv_backends(cluster_id: &str, targets: &[(SrvTarget, Vec<IpAddr>)]) -> Vec<AddBackend> {
+    let Some(main_priority) = targets
+        .iter()
+        .filter(|(_, ips)| !ips.is_empty())
+        .map(|(target, _)| target.priority)
+        .min()
+    else {
+        return Vec::new();
+    };
+
+    let mut backends = Vec::new();
+    for (target, ips) in targets {
+        for ip in ips {
+            backends.push(AddBackend {
+                cluster_id: cluster_id.to_owned(),
+                backend_id: format!("{}-{}-{}", cluster_id, target.target, target.port),
+                address: SocketAddr::new(*ip, target.port).into(),
+                sticky_id: None,
+                // a weight of 0 means "rarely", not "never", in SRV records
+                load_balancing_parameters: Some(LoadBalancingParams {
+                    weight: i32::from(target.weight.max(1)),
+                    slow_start_seconds: None,
+                }),
+                backup: Some(target.priority > main_priority),
+                expires_at: None,
+                draining: None,
+            });
+        }
+    }
+    backends
+}
+
+/// send an SRV query, and return the targets of the answer, with the
+/// addresses the server added for them
+pub fn query_srv(record: &str, resolver: SocketAddr) -> Result<SrvAnswer, SrvError> {
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u16;
+    let query = build_query(id, record)?;
+
+    let query_error = |error| SrvError::Query { resolver, error };
+    let bind_address: SocketAddr = match resolver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_address).map_err(query_error)?;
+    socket
+        .set_read_timeout(Some(DNS_TIMEOUT))
+        .map_err(query_error)?;
+    socket.connect(resolver).map_err(query_error)?;
+    socket.send(&query).map_err(query_error)?;
+
+    let mut buffer = vec![0; MAX_ANSWER_SIZE as usize];
+    loop {
+        let size = socket.recv(&mut buffer).map_err(query_error)?;
+        // ignore late answers to previous queries
+        if size >= 2 && u16::from_be_bytes([buffer[0], buffer[1]]) == id {
+            return parse_answer(&buffer[..size]);
+        }
+    }
+}
+
+/// a recursive query for the SRV record of a name, announcing the size of the answers accepted
+pub fn build_query(id: u16, name: &str) -> Result<Vec<u8>, SrvError> {
+    let mut query = Vec::with_capacity(name.len() + 30);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired
+    query.extend_from_slice(&0x0100u16.to_be_bytes());
+    // one question, no answer, no authority, one additional record (EDNS)
+    for count in [1u16, 0, 0, 1] {
+        query.extend_from_slice(&count.to_be_bytes());
+    }
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(SrvError::InvalidName(name.to_owned()));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    // EDNS: root name, OPT type, accepted size in the class field, no extended flags
+    query.push(0);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&MAX_ANSWER_SIZE.to_be_bytes());
+    query.extend_from_slice(&[0; 6]);
+    Ok(query)
+}
+
+/// the SRV records of an answer, and the A and AAAA records of its additional section
+pub fn parse_answer(answer: &[u8]) -> Result<SrvAnswer, SrvError> {
+    let header = answer
+        .get(..12)
+        .ok_or(SrvError::InvalidAnswer("header too short"))?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    if flags & 0x8000 == 0 {
+        return Err(SrvError::InvalidAnswer("not an answer"));
+    }
+    if flags & 0x0200 != 0 {
+        return Err(SrvError::Truncated);
+    }
+    let rcode = (flags & 0x000f) as u8;
+    if rcode != 0 {
+        return Err(SrvError::Rcode(rcode));
+    }
+    let count = |index: usize| u16::from_be_bytes([header[index], header[index + 1]]) as usize;
+    let (questions, records) = (count(4), count(6) + count(8) + count(10));
+
+    let mut position = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(answer, position)?;
+        // type and class
+        position = next + 4;
+    }
+
+    let mut targets = Vec::new();
+    let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for _ in 0..records {
+        let (name, next) = read_name(answer, position)?;
+        let fields = answer
+            .get(next..next + 10)
+            .ok_or(SrvError::InvalidAnswer("record too short"))?;
+        let record_type = u16::from_be_bytes([fields[0], fields[1]]);
+        let data_length = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        let data_start = next + 10;
+        let data = answer
+            .get(data_start..data_start + data_length)
+            .ok_or(SrvError::InvalidAnswer("record data too short"))?;
+
+        match (record_type, data.len()) {
+            (TYPE_SRV, length) if length >= 7 => {
+                let (target, _) = read_name(answer, data_start + 6)?;
+                targets.push(SrvTarget {
+                    priority: u16::from_be_bytes([data[0], data[1]]),
+                    weight: u16::from_be_bytes([data[2], data[3]]),
+                    port: u16::from_be_bytes([data[4], data[5]]),
+                    target,
+                });
+            }
+            (TYPE_A, 4) => {
+                let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+                addresses.entry(name).or_default().push(ip.into());
+            }
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addresses
+                    .entry(name)
+                    .or_default()
+                    .push(Ipv6Addr::from(octets).into());
+            }
+            _ => {}
+        }
+        position = data_start + data_length;
+    }
+    Ok((targets, addresses))
+}
+
+/// read a possibly compressed name, returns it in lower case without the final dot,
+/// and the position following it in the message
+fn read_name(message: &[u8], mut position: usize) -> Result<(String, usize), SrvError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // bounds the number of compression pointers followed, to avoid loops
+    for _ in 0..128 {
+        let length = *message
+            .get(position)
+            .ok_or(SrvError::InvalidAnswer("name out of the message"))?
+            as usize;
+        match length {
+            0 => {
+                return Ok((labels.join("."), end.unwrap_or(position + 1)));
+            }
+            length if length & 0xc0 == 0xc0 => {
+                let low = *message
+                    .get(position + 1)
+                    .ok_or(SrvError::InvalidAnswer("name out of the message"))?
+                    as usize;
+                end.get_or_insert(position + 2);
+                position = ((length & 0x3f) << 8) | low;
+            }
+            length => {
+                let label = message
+                    .get(position + 1..position + 1 + length)
+                    .ok_or(SrvError::InvalidAnswer("name out of the message"))?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                position += 1 + length;
+            }
+        }
+    }
+    Err(SrvError::InvalidAnswer("too many compression pointers"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an answer with two SRV targets of different priorities, and the address of the first
+    fn answer() -> Vec<u8> {
+        let mut message = build_query(42, "_http._tcp.web.service.consul").unwrap();
+        // answer, recursion desired and available, one question, three records
+        message[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        message[6..8].copy_from_slice(&2u16.to_be_bytes());
+        message[10..12].copy_from_slice(&1u16.to_be_bytes());
+        // drop the EDNS record of the query
+        message.truncate(message.len() - 11);
+
+        let srv = |message: &mut Vec<u8>, priority: u16, weight: u16, target: &[u8]| {
+            // pointer to the question name
+            message.extend_from_slice(&[0xc0, 12]);
+            message.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&[0, 0, 0, 30]);
+            message.extend_from_slice(&((6 + target.len()) as u16).to_be_bytes());
+            message.extend_from_slice(&priority.to_be_bytes());
+            message.extend_from_slice(&weight.to_be_bytes());
+            message.extend_from_slice(&8080u16.to_be_bytes());
+            message.extend_from_slice(target);
+        };
+        let first_target = message.len() + 18;
+        srv(&mut message, 1, 10, b"\x05node1\x04Web1\x00");
+        srv(&mut message, 2, 0, b"\x05node2\xc0\x12");
+
+        message.extend_from_slice(&[0xc0, first_target as u8]);
+        message.extend_from_slice(&TYPE_A.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&[0, 0, 0, 30, 0, 4, 10, 0, 0, 1]);
+        message
+    }
+
+    #[test]
+    fn parse_srv_answer() {
+        let (targets, addresses) = parse_answer(&answer()).unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                SrvTarget {
+                    priority: 1,
+                    weight: 10,
+                    port: 8080,
+                    target: "node1.web1".to_owned(),
+                },
+                SrvTarget {
+                    priority: 2,
+                    weight: 0,
+                    port: 8080,
+                    target: "node2._tcp.web.service.consul".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            addresses.get("node1.web1"),
+            Some(&vec!["10.0.0.1".parse().unwrap()])
+        );
+    }
+
+    #[test]
+    fn reject_error_answers() {
+        let mut message = answer();
+        message[3] |= 3;
+        assert!(matches!(parse_answer(&message), Err(SrvError::Rcode(3))));
+
+        let mut message = answer();
+        message[2] |= 0x02;
+        assert!(matches!(parse_answer(&message), Err(SrvError::Truncated)));
+    }
+
+    #[test]
+    fn lowest_priority_targets_are_the_main_backends() {
+        let target = |priority, weight, name: &str| SrvTarget {
+            priority,
+            weight,
+            port: 8080,
+            target: name.to_owned(),
+        };
+        let backends = srv_backends(
+            "web",
+            &[
+                (target(10, 5, "a"), vec!["10.0.0.1".parse().unwrap()]),
+                (target(20, 0, "b"), vec!["10.0.0.2".parse().unwrap()]),
+                // no address, ignored for the priorities
+                (target(1, 5, "c"), vec![]),
+            ],
+        );
+
+        let summary: Vec<_> = backends
+            .iter()
+            .map(|backend| {
+                (
+                    backend.backend_id.as_str(),
+                    backend.load_balancing_parameters.as_ref().unwrap().weight,
+                    backend.backup,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("web-a-8080", 5, Some(false)),
+                ("web-b-8080", 1, Some(true))
+            ]
+        );
+    }
+}
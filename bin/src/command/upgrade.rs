@@ -1,4 +1,7 @@
-use std::os::fd::AsRawFd;
+use std::{
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
 
 use libc::pid_t;
 use mio::Token;
@@ -7,8 +10,9 @@ use serde::{Deserialize, Serialize};
 use sozu_command_lib::{
     config::Config,
     proto::command::{
-        request::RequestType, ResponseStatus, ReturnListenSockets, RunState, SoftStop,
-        WorkerResponse,
+        request::RequestType, ErrorCode, ErrorSubsystem, ResponseError, ResponseStatus,
+        ReturnListenSockets, RunState, SetAcceptShare, SetBackendHealthOverride, SigningKeys,
+        SoftStop, StateChange, WorkerResponse,
     },
     state::ConfigState,
 };
@@ -16,12 +20,12 @@ use sozu_command_lib::{
 use crate::{
     command::{
         server::{
-            ClientId, Gatherer, GatheringTask, MessageClient, Server, ServerState, SessionId,
-            TaskId, Timeout, WorkerId,
+            ClientId, DefaultGatherer, Gatherer, GatheringTask, MessageClient, Server, ServerState,
+            SessionId, TaskId, Timeout, WorkerId,
         },
         sessions::{ClientSession, OptionalClient},
     },
-    upgrade::{fork_main_into_new_main, UpgradeError},
+    upgrade::{fork_main_into_new_main, validate_with_new_executable, UpgradeError},
     util::disable_close_on_exec,
 };
 
@@ -41,6 +45,18 @@ enum UpgradeWorkerProgress {
         old_worker_id: WorkerId,
         new_worker_id: WorkerId,
     },
+    /// with an `upgrade_shift_duration`:
+    /// 3. activate the listeners of the new worker
+    /// 4. lower the share of the new connections accepted by the old worker, step by step
+    ActivateNew {
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+    },
+    /// 5. soft stop the old worker, once it accepts no new connection
+    StopOld {
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+    },
 }
 
 #[derive(Debug)]
@@ -71,11 +87,16 @@ pub fn upgrade_worker(server: &mut Server, client: &mut ClientSession, old_worke
         }
     };
 
+    incr!("upgrade.worker");
     client.return_processing(format!(
         "Requesting listen sockets from worker {old_worker_id}"
     ));
     server.scatter(
-        RequestType::ReturnListenSockets(ReturnListenSockets {}).into(),
+        RequestType::ReturnListenSockets(ReturnListenSockets {
+            // the old worker accepts along the new one until the connections are shifted
+            keep_accepting: Some(server.config.upgrade_shift_duration > 0),
+        })
+        .into(),
         Box::new(UpgradeWorkerTask {
             client_token: client.token,
             progress: UpgradeWorkerProgress::RequestingListenSockets {
@@ -139,6 +160,33 @@ impl UpgradeWorkerTask {
         client.return_processing(format!("Launched a new worker with id {}", new_worker.id));
         let new_worker_id = new_worker.id;
 
+        if server.config.upgrade_shift_duration > 0 {
+            // both workers accept on the same sockets until the old one is stopped
+            let activate_task = server.new_task(
+                Box::new(UpgradeWorkerTask {
+                    client_token: self.client_token,
+                    progress: UpgradeWorkerProgress::ActivateNew {
+                        old_worker_id,
+                        new_worker_id,
+                    },
+                    ok: 0,
+                    errors: 0,
+                    responses: Vec::new(),
+                    expected_responses: 0,
+                }),
+                Timeout::None,
+            );
+            for (count, request) in server
+                .state
+                .generate_activate_requests()
+                .into_iter()
+                .enumerate()
+            {
+                server.scatter_on(request, activate_task, count, Some(new_worker_id));
+            }
+            return;
+        }
+
         let finish_task = server.new_task(
             Box::new(UpgradeWorkerTask {
                 client_token: self.client_token,
@@ -216,6 +264,34 @@ impl GatheringTask for UpgradeWorkerTask {
                     )
                 );
             }
+            UpgradeWorkerProgress::ActivateNew {
+                old_worker_id,
+                new_worker_id,
+            } => {
+                let duration = Duration::from_secs(server.config.upgrade_shift_duration);
+                client.return_processing(format!(
+                    "Activated worker {}, shifting the new connections from worker {} over {}s",
+                    new_worker_id,
+                    old_worker_id,
+                    duration.as_secs()
+                ));
+                server.accept_shifts.push(AcceptShift::new(
+                    self.client_token,
+                    old_worker_id,
+                    new_worker_id,
+                    duration,
+                    Instant::now(),
+                ));
+            }
+            UpgradeWorkerProgress::StopOld {
+                old_worker_id,
+                new_worker_id,
+            } => {
+                client.finish_ok(format!(
+                    "Upgrade successful:\n- shifted the new connections to worker {:?}\n- finished soft stop of worker {:?}",
+                    new_worker_id, old_worker_id
+                ));
+            }
         }
     }
 }
@@ -241,12 +317,12 @@ impl Gatherer for UpgradeWorkerTask {
                 self.ok += 1;
                 match self.progress {
                     UpgradeWorkerProgress::RequestingListenSockets { .. } => {}
-                    UpgradeWorkerProgress::StopOldActivateNew { .. } => {
-                        client.return_processing(format!(
-                            "Worker {} answered OK to {}. {}",
-                            worker_id, message.id, message.message
-                        ))
-                    }
+                    UpgradeWorkerProgress::StopOldActivateNew { .. }
+                    | UpgradeWorkerProgress::ActivateNew { .. }
+                    | UpgradeWorkerProgress::StopOld { .. } => client.return_processing(format!(
+                        "Worker {} answered OK to {}. {}",
+                        worker_id, message.id, message.message
+                    )),
                 }
             }
             Ok(ResponseStatus::Failure) => self.errors += 1,
@@ -260,6 +336,151 @@ impl Gatherer for UpgradeWorkerTask {
     }
 }
 
+/// shares of the new connections accepted by the old worker, one step after the other
+const ACCEPT_SHARE_STEPS: [u32; 4] = [75, 50, 25, 0];
+
+#[derive(Debug, PartialEq)]
+enum ShiftStep {
+    Share(u32),
+    Stop,
+}
+
+/// An upgraded worker handing its new connections over to the worker replacing it.
+/// Not kept across upgrades of the main process
+#[derive(Debug)]
+pub struct AcceptShift {
+    client_token: Token,
+    old_worker_id: WorkerId,
+    new_worker_id: WorkerId,
+    /// index in [ACCEPT_SHARE_STEPS], the old worker is stopped after the last step
+    next_step: usize,
+    next_step_at: Instant,
+    step_interval: Duration,
+}
+
+impl AcceptShift {
+    fn new(
+        client_token: Token,
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+        duration: Duration,
+        now: Instant,
+    ) -> Self {
+        Self {
+            client_token,
+            old_worker_id,
+            new_worker_id,
+            next_step: 0,
+            next_step_at: now,
+            step_interval: duration / ACCEPT_SHARE_STEPS.len() as u32,
+        }
+    }
+
+    /// the step due at this instant, if any
+    fn due_step(&mut self, now: Instant) -> Option<ShiftStep> {
+        if now < self.next_step_at {
+            return None;
+        }
+        let step = match ACCEPT_SHARE_STEPS.get(self.next_step) {
+            Some(share) => ShiftStep::Share(*share),
+            None => ShiftStep::Stop,
+        };
+        self.next_step += 1;
+        self.next_step_at = now + self.step_interval;
+        Some(step)
+    }
+}
+
+#[derive(Debug)]
+struct AcceptShareTask {
+    client_token: Token,
+    old_worker_id: WorkerId,
+    share: u32,
+    gatherer: DefaultGatherer,
+}
+
+impl GatheringTask for AcceptShareTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        _timed_out: bool,
+    ) {
+        if self.gatherer.ok == 1 {
+            client.return_processing(format!(
+                "Worker {} accepts {}% of the new connections",
+                self.old_worker_id, self.share
+            ));
+        } else {
+            client.return_processing(format!(
+                "Worker {} did not lower its share of the new connections to {}%: {:?}",
+                self.old_worker_id, self.share, self.gatherer.responses
+            ));
+        }
+    }
+}
+
+/// Lower the share of the new connections accepted by the upgraded workers,
+/// and soft stop them once the workers replacing them accept all the connections
+pub fn shift_accept_shares(server: &mut Server, now: Instant) {
+    for mut shift in std::mem::take(&mut server.accept_shifts) {
+        let old_worker_running = server.workers.values().any(|worker| {
+            worker.id == shift.old_worker_id && worker.run_state != RunState::Stopped
+        });
+        if !old_worker_running {
+            warn!(
+                "worker {} stopped while handing its connections over to worker {}",
+                shift.old_worker_id, shift.new_worker_id
+            );
+            continue;
+        }
+
+        match shift.due_step(now) {
+            None => server.accept_shifts.push(shift),
+            Some(ShiftStep::Share(share)) => {
+                server.scatter(
+                    RequestType::SetAcceptShare(SetAcceptShare { percent: share }).into(),
+                    Box::new(AcceptShareTask {
+                        client_token: shift.client_token,
+                        old_worker_id: shift.old_worker_id,
+                        share,
+                        gatherer: DefaultGatherer::default(),
+                    }),
+                    Timeout::Default,
+                    Some(shift.old_worker_id),
+                );
+                server.accept_shifts.push(shift);
+            }
+            Some(ShiftStep::Stop) => {
+                server.scatter(
+                    RequestType::SoftStop(SoftStop {}).into(),
+                    Box::new(UpgradeWorkerTask {
+                        client_token: shift.client_token,
+                        progress: UpgradeWorkerProgress::StopOld {
+                            old_worker_id: shift.old_worker_id,
+                            new_worker_id: shift.new_worker_id,
+                        },
+                        ok: 0,
+                        errors: 0,
+                        responses: Vec::new(),
+                        expected_responses: 0,
+                    }),
+                    Timeout::None,
+                    Some(shift.old_worker_id),
+                );
+            }
+        }
+    }
+}
+
 //===============================================
 // Upgrade the main process
 
@@ -274,6 +495,12 @@ pub struct SerializedWorkerSession {
     pub run_state: RunState,
     /// file descriptor of the SCM socket
     pub scm_fd: i32,
+    /// sequence number of the last request sent to the worker
+    #[serde(default)]
+    pub sequence: u64,
+    /// generation of the main process that launched the worker
+    #[serde(default)]
+    pub generation: u32,
 }
 
 impl TryFrom<&WorkerSession> for SerializedWorkerSession {
@@ -293,10 +520,20 @@ impl TryFrom<&WorkerSession> for SerializedWorkerSession {
             id: worker.id,
             run_state: worker.run_state,
             scm_fd: worker.scm_socket.raw_fd(),
+            sequence: worker.sequence(),
+            generation: worker.generation,
         })
     }
 }
 
+/// The command broker, kept across upgrades of the main process with its clients
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SerializedBroker {
+    /// file descriptor of the UNIX channel
+    pub channel_fd: i32,
+    pub pid: pid_t,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct UpgradeData {
     /// file descriptor of the unix command socket
@@ -309,17 +546,59 @@ pub struct UpgradeData {
     /// JSON serialized workers
     pub workers: Vec<SerializedWorkerSession>,
     pub state: ConfigState,
+    /// epoch of the last state change, and the changes kept
+    #[serde(default)]
+    pub epoch: u64,
+    #[serde(default)]
+    pub change_history: Vec<StateChange>,
+    /// keys signing the sticky session cookies, current first
+    #[serde(default)]
+    pub signing_keys: SigningKeys,
+    /// generation of the new main process
+    #[serde(default)]
+    pub generation: u32,
+    /// health of the backends forced manually
+    #[serde(default)]
+    pub health_overrides: Vec<SetBackendHealthOverride>,
+    /// the command broker, if it runs
+    #[serde(default)]
+    pub broker: Option<SerializedBroker>,
 }
 
 pub fn upgrade_main(server: &mut Server, client: &mut ClientSession) {
+    let upgrade_data = server.generate_upgrade_data();
+
+    // the new executable checks that it can take over before anything changes,
+    // so that a broken build or configuration leaves the current processes running
+    client.return_processing("Validating the upgrade with the new executable...");
+    match validate_with_new_executable(&server.executable_path, &upgrade_data) {
+        Ok(validation) if validation.is_valid() => {
+            info!("upgrade validated by the {}", validation);
+        }
+        Ok(validation) => {
+            client.finish_failure_with_error(
+                format!("Upgrade aborted, the {validation}"),
+                ResponseError::new(ErrorCode::UpgradeRejected, ErrorSubsystem::MainProcess)
+                    .with_hint("fix the configuration file, or use a compatible executable"),
+            );
+            return;
+        }
+        Err(error) => {
+            client.finish_failure_with_error(
+                format!("Upgrade aborted: {error}"),
+                ResponseError::new(ErrorCode::UpgradeRejected, ErrorSubsystem::MainProcess),
+            );
+            return;
+        }
+    }
+
     if let Err(err) = server.disable_cloexec_before_upgrade() {
         client.finish_failure(err.to_string());
     }
 
+    incr!("upgrade.main");
     client.return_processing("Upgrading the main process...");
 
-    let upgrade_data = server.generate_upgrade_data();
-
     let (new_main_pid, mut fork_confirmation_channel) =
         match fork_main_into_new_main(server.executable_path.clone(), upgrade_data) {
             Ok(tuple) => tuple,
@@ -349,3 +628,28 @@ pub fn upgrade_main(server: &mut Server, client: &mut ClientSession) {
         server.run_state = ServerState::Stopping;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_shift_steps() {
+        let start = Instant::now();
+        let mut shift = AcceptShift::new(Token(1), 0, 1, Duration::from_secs(20), start);
+
+        assert_eq!(shift.due_step(start), Some(ShiftStep::Share(75)));
+        assert_eq!(shift.due_step(start + Duration::from_secs(4)), None);
+
+        let mut now = start;
+        for share in [50, 25, 0] {
+            now += Duration::from_secs(5);
+            assert_eq!(shift.due_step(now), Some(ShiftStep::Share(share)));
+        }
+        assert_eq!(shift.due_step(now + Duration::from_secs(1)), None);
+        assert_eq!(
+            shift.due_step(now + Duration::from_secs(5)),
+            Some(ShiftStep::Stop)
+        );
+    }
+}
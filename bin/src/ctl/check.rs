@@ -0,0 +1,293 @@
+//! Validation of a configuration file without a running Sōzu.
+//!
+//! The file is built like on startup, the certificates and keys are loaded like the
+//! workers load them, and all the errors are reported at once. No socket is bound and
+//! no worker is forked.
+
+use std::{
+    net::SocketAddr,
+    process::exit,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sozu_command_lib::{
+    config::{
+        ClusterConfig, Config, ConfigBuilder, FileConfig, ListenerBuilder,
+        DEFAULT_RUSTLS_CIPHER_LIST,
+    },
+    logging::setup_logging,
+    proto::command::{AddCertificate, CertificateAndKey, HttpsListenerConfig},
+};
+use sozu_lib::{
+    https::HttpsListener,
+    tls::{CertifiedKeyWrapper, MutexCertificateResolver},
+};
+
+/// exit codes of `sozu check`
+const EXIT_VALID: i32 = 0;
+const EXIT_INVALID: i32 = 1;
+const EXIT_UNREADABLE: i32 = 2;
+
+/// print the errors found in the configuration file, and exit with 0 if there
+/// are none, 1 if there are, 2 if the file could not be read or parsed
+pub fn check_config(path: &str) -> ! {
+    // the errors logged while building the configuration are all reported below
+    setup_logging("stdout", false, None, None, None, None, "off", "CHECK");
+
+    let file_config = match FileConfig::load_from_path(path) {
+        Ok(file_config) => file_config,
+        Err(error) => {
+            println!("could not load configuration file {path}: {error}");
+            exit(EXIT_UNREADABLE);
+        }
+    };
+
+    let errors = config_errors(file_config, path);
+    if errors.is_empty() {
+        println!("Configuration file {path} is valid");
+        exit(EXIT_VALID);
+    }
+    for error in &errors {
+        println!("error: {error}");
+    }
+    println!("{} error(s) in configuration file {path}", errors.len());
+    exit(EXIT_INVALID);
+}
+
+fn config_errors(file_config: FileConfig, path: &str) -> Vec<String> {
+    // the listeners are built without the files they can not read
+    let mut errors: Vec<String> = file_config
+        .listeners
+        .iter()
+        .flatten()
+        .flat_map(listener_file_errors)
+        .collect();
+
+    let (config, config_errors) = ConfigBuilder::new(file_config, path).check();
+    errors.extend(config_errors.iter().map(ToString::to_string));
+
+    if let Err(error) = config.command_socket_path() {
+        errors.push(error.to_string());
+    }
+    errors.extend(timeout_errors(&config));
+    errors.extend(address_errors(&config));
+    for listener in &config.https_listeners {
+        errors.extend(tls_errors(listener));
+    }
+    errors.extend(certificate_errors(&config));
+    errors
+}
+
+fn listener_file_errors(listener: &ListenerBuilder) -> Vec<String> {
+    [
+        &listener.certificate,
+        &listener.certificate_chain,
+        &listener.key,
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(|path| Config::load_file(path).err())
+    .map(|error| format!("listener {}: {error}", listener.address))
+    .collect()
+}
+
+/// a timeout of 0 would expire right away
+fn timeout_errors(config: &Config) -> Vec<String> {
+    let mut errors = zero_timeouts(
+        "the configuration",
+        &[
+            ("front_timeout", config.front_timeout),
+            ("back_timeout", config.back_timeout),
+            ("connect_timeout", config.connect_timeout),
+            ("request_timeout", config.request_timeout),
+        ],
+    );
+    for listener in &config.http_listeners {
+        errors.extend(zero_timeouts(
+            &format!("listener {}", SocketAddr::from(listener.address.clone())),
+            &[
+                ("front_timeout", listener.front_timeout),
+                ("back_timeout", listener.back_timeout),
+                ("connect_timeout", listener.connect_timeout),
+                ("request_timeout", listener.request_timeout),
+            ],
+        ));
+    }
+    for listener in &config.https_listeners {
+        errors.extend(zero_timeouts(
+            &format!("listener {}", SocketAddr::from(listener.address.clone())),
+            &[
+                ("front_timeout", listener.front_timeout),
+                ("back_timeout", listener.back_timeout),
+                ("connect_timeout", listener.connect_timeout),
+                ("request_timeout", listener.request_timeout),
+                ("handshake_timeout", listener.handshake_timeout),
+            ],
+        ));
+    }
+    for listener in &config.tcp_listeners {
+        errors.extend(zero_timeouts(
+            &format!("listener {}", SocketAddr::from(listener.address.clone())),
+            &[
+                ("front_timeout", listener.front_timeout),
+                ("back_timeout", listener.back_timeout),
+                ("connect_timeout", listener.connect_timeout),
+            ],
+        ));
+    }
+    errors
+}
+
+fn zero_timeouts(owner: &str, timeouts: &[(&str, u32)]) -> Vec<String> {
+    timeouts
+        .iter()
+        .filter(|(_, timeout)| *timeout == 0)
+        .map(|(name, _)| format!("{owner}: {name} should be at least 1 second"))
+        .collect()
+}
+
+/// port 0 would make the listeners bind a random port, and the backends unreachable
+fn address_errors(config: &Config) -> Vec<String> {
+    let listeners = config
+        .http_listeners
+        .iter()
+        .map(|l| (&l.address, &l.public_address))
+        .chain(
+            config
+                .https_listeners
+                .iter()
+                .map(|l| (&l.address, &l.public_address)),
+        )
+        .chain(
+            config
+                .tcp_listeners
+                .iter()
+                .map(|l| (&l.address, &l.public_address)),
+        );
+
+    let mut errors = Vec::new();
+    for (address, public_address) in listeners {
+        let address = SocketAddr::from(address.clone());
+        if address.port() == 0 {
+            errors.push(format!("listener {address}: the port should not be 0"));
+        }
+        if let Some(public_address) = public_address {
+            let public_address = SocketAddr::from(public_address.clone());
+            if public_address.port() == 0 {
+                errors.push(format!(
+                    "listener {address}: the port of the public address {public_address} should not be 0"
+                ));
+            }
+        }
+    }
+
+    for cluster in config.clusters.values() {
+        let (cluster_id, backends) = match cluster {
+            ClusterConfig::Http(http) => (&http.cluster_id, &http.backends),
+            ClusterConfig::Tcp(tcp) => (&tcp.cluster_id, &tcp.backends),
+        };
+        for backend in backends {
+            if backend.address.port() == 0 || backend.address.ip().is_unspecified() {
+                errors.push(format!(
+                    "cluster {cluster_id}: backend address {} can not be connected to",
+                    backend.address
+                ));
+            }
+        }
+    }
+    errors
+}
+
+/// the ciphers, TLS versions and client authorities, as the workers build them
+fn tls_errors(listener: &HttpsListenerConfig) -> Vec<String> {
+    let address = SocketAddr::from(listener.address.clone());
+    let mut errors: Vec<String> = listener
+        .cipher_list
+        .iter()
+        .filter(|cipher| !DEFAULT_RUSTLS_CIPHER_LIST.contains(&cipher.as_str()))
+        .map(|cipher| {
+            format!(
+                "listener {address}: unknown cipher {cipher}, the supported ciphers are {}",
+                DEFAULT_RUSTLS_CIPHER_LIST.join(", ")
+            )
+        })
+        .collect();
+
+    if let Err(error) = HttpsListener::create_rustls_context(
+        listener,
+        Arc::new(MutexCertificateResolver::default()),
+    ) {
+        errors.push(format!("listener {address}: {error}"));
+    }
+
+    if let (Some(certificate), Some(key)) = (&listener.certificate, &listener.key) {
+        let add = AddCertificate {
+            address: listener.address.clone(),
+            certificate: CertificateAndKey {
+                certificate: certificate.to_owned(),
+                certificate_chain: listener.certificate_chain.clone(),
+                key: key.to_owned(),
+                ..Default::default()
+            },
+            expired_at: None,
+        };
+        errors.extend(
+            load_certificate(&add)
+                .err()
+                .map(|error| format!("listener {address}: {error}")),
+        );
+    }
+    errors
+}
+
+/// the certificates of the frontends
+fn certificate_errors(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    for cluster in config.clusters.values() {
+        let ClusterConfig::Http(http) = cluster else {
+            continue;
+        };
+        for frontend in &http.frontends {
+            let (Some(certificate), Some(key)) = (&frontend.certificate, &frontend.key) else {
+                continue;
+            };
+            let add = AddCertificate {
+                address: frontend.address.into(),
+                certificate: CertificateAndKey {
+                    certificate: certificate.to_owned(),
+                    certificate_chain: frontend.certificate_chain.clone().unwrap_or_default(),
+                    key: key.to_owned(),
+                    ..Default::default()
+                },
+                expired_at: None,
+            };
+            if let Err(error) = load_certificate(&add) {
+                errors.push(format!(
+                    "frontend {} of cluster {}: {error}",
+                    frontend.hostname, http.cluster_id
+                ));
+            }
+        }
+    }
+    errors
+}
+
+/// parse the certificate, its chain and its key, and check its expiration
+fn load_certificate(add: &AddCertificate) -> Result<(), String> {
+    let details = CertifiedKeyWrapper::try_from(add)
+        .and_then(|certified_key| certified_key.details())
+        .map_err(|error| error.to_string())?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
+    if details.not_after < now {
+        return Err(format!(
+            "the certificate expired {} day(s) ago",
+            (now - details.not_after) / 86_400
+        ));
+    }
+    Ok(())
+}
@@ -8,7 +8,7 @@ use sozu_command_lib::{
     },
 };
 
-use crate::ctl::{create_channel, CommandManager, CtlError};
+use crate::ctl::{create_channel, CommandManager, ConnectRetry, CtlError};
 
 impl CommandManager {
     fn write_request_on_channel(&mut self, request: Request) -> Result<(), CtlError> {
@@ -23,11 +23,16 @@ impl CommandManager {
             .map_err(CtlError::ReadBlocking)
     }
 
-    fn send_request_get_response(
+    pub fn send_request_get_response(
         &mut self,
-        request: Request,
+        mut request: Request,
         timeout: bool,
     ) -> Result<Response, CtlError> {
+        if self.dry_run {
+            request.dry_run = Some(true);
+        }
+        request.expected_epoch = self.expected_epoch;
+        request.expected_cluster_hash = self.expected_cluster_hash.clone();
         self.channel
             .write_message(&request)
             .map_err(CtlError::WriteRequest)?;
@@ -53,7 +58,15 @@ impl CommandManager {
                         info!("{}, {}", response.message, event);
                     }
                 }
-                ResponseStatus::Failure => return Err(CtlError::Failure(response.message)),
+                ResponseStatus::Failure => {
+                    return Err(match response.error {
+                        Some(error) => CtlError::FailureWithError {
+                            message: response.message,
+                            error,
+                        },
+                        None => CtlError::Failure(response.message),
+                    })
+                }
                 ResponseStatus::Ok => return Ok(response),
             }
         }
@@ -64,9 +77,15 @@ impl CommandManager {
         request: Request,
         timeout: bool,
     ) -> Result<(), CtlError> {
-        self.send_request_get_response(request, timeout)?
-            .display(self.json)
-            .map_err(CtlError::Display)
+        let response = match self.send_request_get_response(request, timeout) {
+            Ok(response) => response,
+            // tools driving Sōzu get the structured error in JSON
+            Err(CtlError::FailureWithError { message, error }) if self.json => {
+                Response::failure(message, error)
+            }
+            Err(error) => return Err(error),
+        };
+        response.display(self.json).map_err(CtlError::Display)
     }
 
     pub fn send_request(&mut self, request: Request) -> Result<(), CtlError> {
@@ -138,7 +157,8 @@ impl CommandManager {
         self.send_request(RequestType::UpgradeMain(UpgradeMain {}).into())?;
 
         info!("recreating a channel to reconnect with the new main process...");
-        self.channel = create_channel(&self.config)?;
+        // the new main process may not listen on the command socket yet
+        self.channel = create_channel(&self.config, ConnectRetry::from_config(&self.config))?;
 
         info!("requesting the list of workers from the new main");
         let response =
@@ -163,7 +183,7 @@ impl CommandManager {
                 setup_logging_with_config(&config, &format!("UPGRADE-WRK-{}", worker.id));
 
                 info!("creating channel to upgrade worker {}", worker.id);
-                let channel = match create_channel(&config) {
+                let channel = match create_channel(&config, ConnectRetry::from_config(&config)) {
                     Ok(channel) => channel,
                     Err(e) => {
                         error!(
@@ -181,6 +201,10 @@ impl CommandManager {
                     timeout: Duration::from_secs(60), // overriden by upgrade_timeout anyway
                     config,
                     json: false,
+                    dry_run: false,
+                    wait: false,
+                    expected_epoch: None,
+                    expected_cluster_hash: None,
                 };
 
                 match command_manager.upgrade_worker(worker.id) {
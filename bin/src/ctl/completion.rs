@@ -0,0 +1,336 @@
+//! Shell completion, with the values of the running Sōzu
+//!
+//! The scripts printed by `sozu completion <shell>` call `sozu __complete -- <words>`
+//! with the words of the command line, the last one being the word to complete.
+//! Subcommands and options come from the definition of the command line, while
+//! cluster ids, backend ids and addresses are queried from the main process.
+
+use std::{net::SocketAddr, time::Duration};
+
+use clap::{Arg, Command, CommandFactory};
+
+use sozu_command_lib::{
+    config::Config,
+    proto::command::{
+        request::RequestType, response_content::ContentType, ListListeners, QueryClustersHashes,
+        Request, Response, ResponseContent,
+    },
+};
+
+use crate::{
+    cli::{Args, Shell},
+    ctl::{create_channel, CommandManager, ConnectRetry, CtlError},
+    util::UtilError,
+};
+
+/// values of an option that are only known by the running Sōzu
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LiveValues {
+    ClusterIds,
+    /// backends of the cluster given earlier on the command line, if any
+    BackendIds(Option<String>),
+    BackendAddresses(Option<String>),
+    /// addresses and names of the listeners
+    ListenerAddresses,
+}
+
+pub fn completion_script(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => {
+            r#"_sozu() {
+    local line="${COMP_LINE:0:COMP_POINT}"
+    local -a words
+    read -ra words <<< "$line"
+    [[ "$line" == *[[:space:]] ]] && words+=("")
+    local IFS=$'\n'
+    COMPREPLY=($("${words[0]}" __complete -- "${words[@]:1}" 2>/dev/null))
+    # bash splits the word to complete on colons, as in listener addresses
+    local cur="${words[-1]}"
+    if [[ "$cur" == *:* && "$COMP_WORDBREAKS" == *:* ]]; then
+        local prefix="${cur%"${cur##*:}"}"
+        COMPREPLY=("${COMPREPLY[@]#"$prefix"}")
+    fi
+}
+complete -o default -F _sozu sozu
+"#
+        }
+        Shell::Zsh => {
+            r#"#compdef sozu
+_sozu() {
+    local -a candidates
+    candidates=("${(@f)$("${words[1]}" __complete -- "${(@)words[2,CURRENT]}" 2>/dev/null)}")
+    compadd -a candidates
+}
+compdef _sozu sozu
+"#
+        }
+        Shell::Fish => {
+            r#"function __sozu_complete
+    set -l tokens (commandline -opc)
+    $tokens[1] __complete -- $tokens[2..-1] (commandline -ct) 2>/dev/null
+end
+complete -c sozu -f -a '(__sozu_complete)'
+"#
+        }
+    }
+}
+
+/// Candidates for the last word of the command line. The values only known by
+/// the running Sōzu are asked to `fetch`
+pub fn complete(words: &[String], fetch: &mut dyn FnMut(LiveValues) -> Vec<String>) -> Vec<String> {
+    let (current, previous) = match words.split_last() {
+        Some((current, previous)) => (current.as_str(), previous),
+        None => ("", words),
+    };
+
+    let mut root = Args::command();
+    root.build();
+
+    let mut command = &root;
+    // names of the subcommands, from the root
+    let mut path: Vec<&str> = Vec::new();
+    // long option -> last value given on the command line
+    let mut given: Vec<(&str, &str)> = Vec::new();
+    let mut expecting_value: Option<&Arg> = None;
+
+    for word in previous {
+        if let Some(arg) = expecting_value.take() {
+            if let Some(long) = arg.get_long() {
+                given.push((long, word));
+            }
+            continue;
+        }
+        if let Some(arg) = find_option(command, word) {
+            if arg.get_action().takes_values() && !word.contains('=') {
+                expecting_value = Some(arg);
+            }
+            continue;
+        }
+        if let Some(subcommand) = command.find_subcommand(word) {
+            command = subcommand;
+            path.push(subcommand.get_name());
+        }
+    }
+
+    let candidates = match expecting_value {
+        Some(arg) => {
+            let possible_values = arg.get_possible_values();
+            if !possible_values.is_empty() {
+                possible_values
+                    .iter()
+                    .map(|value| value.get_name().to_owned())
+                    .collect()
+            } else {
+                match live_values(&path, arg.get_long().unwrap_or_default(), &given) {
+                    Some(live_values) => fetch(live_values),
+                    None => Vec::new(),
+                }
+            }
+        }
+        None if current.starts_with('-') => long_options(command),
+        None => {
+            let subcommands: Vec<String> = command
+                .get_subcommands()
+                .filter(|subcommand| !subcommand.is_hide_set())
+                .map(|subcommand| subcommand.get_name().to_owned())
+                .collect();
+            if subcommands.is_empty() {
+                long_options(command)
+            } else {
+                subcommands
+            }
+        }
+    };
+
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(current))
+        .collect()
+}
+
+fn find_option<'a>(command: &'a Command, word: &str) -> Option<&'a Arg> {
+    if let Some(long) = word.strip_prefix("--") {
+        let long = long.split('=').next().unwrap_or_default();
+        return command.get_arguments().find(|arg| {
+            arg.get_long() == Some(long)
+                || arg
+                    .get_all_aliases()
+                    .is_some_and(|aliases| aliases.contains(&long))
+        });
+    }
+    let mut chars = word.strip_prefix('-')?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(short), None) => command
+            .get_arguments()
+            .find(|arg| arg.get_short() == Some(short)),
+        _ => None,
+    }
+}
+
+fn long_options(command: &Command) -> Vec<String> {
+    command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .filter_map(|arg| arg.get_long())
+        .map(|long| format!("--{long}"))
+        .collect()
+}
+
+/// which values of the running Sōzu complete an option of a subcommand
+fn live_values(path: &[&str], long: &str, given: &[(&str, &str)]) -> Option<LiveValues> {
+    let cluster_id = given
+        .iter()
+        .rev()
+        .find(|(long, _)| *long == "id" || *long == "cluster")
+        .map(|(_, value)| value.to_string());
+
+    match (path, long) {
+        // a new cluster, a scheduled change or a worker
+        (["cluster", "add"], _) | (["schedule", ..], _) | (["worker", ..], _) => None,
+        // the --backend of `backend replace` is written id=address
+        (["backend", "replace"], "backend") => None,
+        (_, "id" | "cluster" | "clusters") => Some(LiveValues::ClusterIds),
+        (_, "backend-id" | "backend" | "backends") => Some(LiveValues::BackendIds(cluster_id)),
+        (["backend", ..], "address") => Some(LiveValues::BackendAddresses(cluster_id)),
+        (["listener", _, "add"], "address") => None,
+        (["listener" | "frontend" | "certificate", ..], "address") => {
+            Some(LiveValues::ListenerAddresses)
+        }
+        _ => None,
+    }
+}
+
+/// Query the main process for the values. Without a configuration or a running
+/// Sōzu, the completion goes on without them
+pub fn fetch_live_values(words: &[String], live_values: LiveValues) -> Vec<String> {
+    query_live_values(words, live_values).unwrap_or_default()
+}
+
+fn query_live_values(words: &[String], live_values: LiveValues) -> Result<Vec<String>, CtlError> {
+    // the configuration given on the command line being completed
+    let config_path = words
+        .windows(2)
+        .find(|pair| pair[0] == "--config" || pair[0] == "-c")
+        .map(|pair| pair[1].as_str())
+        .or(option_env!("SOZU_CONFIG"))
+        .ok_or(CtlError::GetConfig(UtilError::GetConfigFilePath))?;
+    let config = Config::load_from_path(config_path).map_err(CtlError::LoadConfig)?;
+
+    let request: Request = match &live_values {
+        LiveValues::ClusterIds | LiveValues::BackendIds(None) => {
+            RequestType::QueryClustersHashes(QueryClustersHashes {})
+        }
+        LiveValues::BackendIds(Some(cluster_id))
+        | LiveValues::BackendAddresses(Some(cluster_id)) => {
+            RequestType::QueryClusterById(cluster_id.to_owned())
+        }
+        LiveValues::BackendAddresses(None) => return Ok(Vec::new()),
+        LiveValues::ListenerAddresses => RequestType::ListListeners(ListListeners {}),
+    }
+    .into();
+
+    let mut command_manager = CommandManager {
+        // completions should not wait for a Sōzu that is not running
+        channel: create_channel(&config, ConnectRetry::once())?,
+        timeout: Duration::from_millis(config.ctl_command_timeout),
+        config,
+        json: true,
+        dry_run: false,
+        wait: false,
+        expected_epoch: None,
+        expected_cluster_hash: None,
+    };
+    let response = command_manager.send_request_get_response(request, true)?;
+    Ok(values_from_response(response, &live_values))
+}
+
+fn values_from_response(response: Response, live_values: &LiveValues) -> Vec<String> {
+    let content_type = match response.content {
+        Some(ResponseContent {
+            content_type: Some(ContentType::WorkerResponses(mut responses)),
+        }) => responses
+            .map
+            .remove("main")
+            .and_then(|response| response.content_type),
+        Some(content) => content.content_type,
+        None => None,
+    };
+
+    match (content_type, live_values) {
+        // backend ids without a cluster are not listed, that would take a query per cluster
+        (Some(ContentType::ClusterHashes(hashes)), LiveValues::ClusterIds) => {
+            hashes.map.into_keys().collect()
+        }
+        (Some(ContentType::Clusters(clusters)), _) => clusters
+            .vec
+            .into_iter()
+            .flat_map(|cluster| cluster.backends)
+            .map(|backend| match live_values {
+                LiveValues::BackendAddresses(_) => SocketAddr::from(backend.address).to_string(),
+                _ => backend.backend_id,
+            })
+            .collect(),
+        // a listener can be given by its address or its name
+        (Some(ContentType::ListenersList(listeners)), _) => listeners
+            .http_listeners
+            .into_iter()
+            .map(|(address, listener)| (address, listener.name))
+            .chain(
+                listeners
+                    .https_listeners
+                    .into_iter()
+                    .map(|(address, listener)| (address, listener.name)),
+            )
+            .chain(
+                listeners
+                    .tcp_listeners
+                    .into_iter()
+                    .map(|(address, listener)| (address, listener.name)),
+            )
+            .flat_map(|(address, name)| std::iter::once(address).chain(name))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete_line(line: &str) -> Vec<String> {
+        let mut words: Vec<String> = line.split(' ').map(str::to_owned).collect();
+        if words.is_empty() {
+            words.push(String::new());
+        }
+        complete(&words, &mut |live_values| match live_values {
+            LiveValues::ClusterIds => vec!["api".to_owned(), "web".to_owned()],
+            LiveValues::BackendIds(Some(cluster_id)) => vec![format!("{cluster_id}-0")],
+            LiveValues::ListenerAddresses => vec!["0.0.0.0:80".to_owned()],
+            _ => Vec::new(),
+        })
+    }
+
+    #[test]
+    fn complete_subcommands_and_options() {
+        assert_eq!(complete_line("back"), vec!["backend"]);
+        assert!(complete_line("backend ").contains(&"set-weight".to_owned()));
+        assert!(complete_line("backend add --back").contains(&"--backend-id".to_owned()));
+        assert!(!complete_line("").contains(&"__complete".to_owned()));
+    }
+
+    #[test]
+    fn complete_with_live_values() {
+        assert_eq!(complete_line("cluster remove --id "), vec!["api", "web"]);
+        assert_eq!(complete_line("backend remove -i w"), vec!["web"]);
+        assert_eq!(
+            complete_line("backend remove --id web --backend-id "),
+            vec!["web-0"]
+        );
+        assert_eq!(
+            complete_line("-c config.toml frontend http add --address "),
+            vec!["0.0.0.0:80"]
+        );
+        // a new cluster has no existing id to complete
+        assert!(complete_line("cluster add --id ").is_empty());
+    }
+}
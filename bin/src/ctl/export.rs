@@ -0,0 +1,620 @@
+//! Rendering of the running state as declarative resources, to compare the live proxy
+//! with the configuration kept in an infrastructure-as-code repository.
+//!
+//! Each listener, cluster, frontend, backend and certificate becomes a `sozu_*` resource,
+//! written in the Terraform language or in its JSON syntax. Resources and attributes are
+//! sorted, so that the same state always gives the same output and can be diffed.
+//! Certificates are exported as their fingerprint, never with their private key.
+//!
+//! To back up and restore the state, it can instead be serialized whole, private keys
+//! included, in JSON or in protobuf, and read back by `state import`.
+
+use std::{collections::BTreeMap, fmt::Write, fs, io, net::SocketAddr};
+
+use prost::Message;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sozu_command_lib::{
+    certificate::{calculate_fingerprint, Fingerprint},
+    proto::command::{request::RequestType, InitialState, Request, SocketAddress},
+    state::ConfigState,
+};
+
+use crate::cli::ExportFormat;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExportError {
+    #[error("could not write file {path}: {error}")]
+    WriteFile { path: String, error: std::io::Error },
+    #[error("could not write to stdout: {0}")]
+    WriteStdout(std::io::Error),
+    #[error("invalid state: {0}")]
+    State(String),
+}
+
+/// resources by type then by name, with their attributes
+#[derive(Debug, Default)]
+struct Resources(BTreeMap<&'static str, BTreeMap<String, Map<String, Value>>>);
+
+impl Resources {
+    fn from_requests(requests: &[Request]) -> Self {
+        let mut resources = Resources::default();
+        for request in requests {
+            let Some(request_type) = &request.request_type else {
+                continue;
+            };
+            match request_type {
+                RequestType::AddHttpListener(listener) => {
+                    let mut attributes = attributes(listener);
+                    set_address(&mut attributes, "address", &listener.address);
+                    set_address_opt(&mut attributes, "public_address", &listener.public_address);
+                    if listener.proxy_status.is_some() {
+                        set_enum(&mut attributes, "proxy_status", listener.proxy_status());
+                    }
+                    let name = listener_name(&listener.name, &listener.address);
+                    resources.insert("sozu_http_listener", &name, attributes);
+                }
+                RequestType::AddHttpsListener(listener) => {
+                    let mut attributes = attributes(listener);
+                    set_address(&mut attributes, "address", &listener.address);
+                    set_address_opt(&mut attributes, "public_address", &listener.public_address);
+                    if listener.proxy_status.is_some() {
+                        set_enum(&mut attributes, "proxy_status", listener.proxy_status());
+                    }
+                    set_enums(&mut attributes, "versions", listener.versions());
+                    attributes.remove("certificate_chain");
+                    attributes.remove("key");
+                    if let Some(certificate) = &listener.certificate {
+                        attributes.insert("certificate".to_owned(), fingerprint(certificate));
+                    }
+                    let name = listener_name(&listener.name, &listener.address);
+                    resources.insert("sozu_https_listener", &name, attributes);
+                }
+                RequestType::AddTcpListener(listener) => {
+                    let mut attributes = attributes(listener);
+                    set_address(&mut attributes, "address", &listener.address);
+                    set_address_opt(&mut attributes, "public_address", &listener.public_address);
+                    let name = listener_name(&listener.name, &listener.address);
+                    resources.insert("sozu_tcp_listener", &name, attributes);
+                }
+                RequestType::AddCluster(cluster) => {
+                    let mut attributes = attributes(cluster);
+                    set_enum(&mut attributes, "load_balancing", cluster.load_balancing());
+                    if cluster.load_metric.is_some() {
+                        set_enum(&mut attributes, "load_metric", cluster.load_metric());
+                    }
+                    if let Some(hash_key) = &cluster.hash_key {
+                        attributes.insert("hash_key".to_owned(), hash_key.to_string().into());
+                    }
+                    resources.insert("sozu_cluster", &cluster.cluster_id, attributes);
+                }
+                RequestType::AddHttpFrontend(frontend)
+                | RequestType::AddHttpsFrontend(frontend) => {
+                    let mut attributes = attributes(frontend);
+                    set_address(&mut attributes, "address", &frontend.address);
+                    set_enum(&mut attributes, "position", frontend.position());
+                    set_enums(
+                        &mut attributes,
+                        "client_tls_versions",
+                        frontend.client_tls_versions(),
+                    );
+                    let mut path = Map::new();
+                    path.insert("kind".to_owned(), frontend.path.kind().as_str_name().into());
+                    path.insert("value".to_owned(), frontend.path.value.clone().into());
+                    attributes.insert("path".to_owned(), path.into());
+
+                    let kind = match request_type {
+                        RequestType::AddHttpFrontend(_) => "sozu_http_frontend",
+                        _ => "sozu_https_frontend",
+                    };
+                    let name = format!(
+                        "{}_{}{}",
+                        frontend.cluster_id.as_deref().unwrap_or("deny"),
+                        frontend.hostname,
+                        frontend.path.value
+                    );
+                    resources.insert(kind, &name, attributes);
+                }
+                RequestType::AddTcpFrontend(frontend) => {
+                    let mut attributes = attributes(frontend);
+                    set_address(&mut attributes, "address", &frontend.address);
+                    let name = format!(
+                        "{}_{}",
+                        frontend.cluster_id,
+                        SocketAddr::from(frontend.address.clone())
+                    );
+                    resources.insert("sozu_tcp_frontend", &name, attributes);
+                }
+                RequestType::AddBackend(backend) => {
+                    let mut attributes = attributes(backend);
+                    set_address(&mut attributes, "address", &backend.address);
+                    if let Some(parameters) = &backend.load_balancing_parameters {
+                        attributes.remove("load_balancing_parameters");
+                        attributes.insert("weight".to_owned(), parameters.weight.into());
+                        if let Some(slow_start_seconds) = parameters.slow_start_seconds {
+                            attributes
+                                .insert("slow_start_seconds".to_owned(), slow_start_seconds.into());
+                        }
+                    }
+                    let name = format!("{}_{}", backend.cluster_id, backend.backend_id);
+                    resources.insert("sozu_backend", &name, attributes);
+                }
+                RequestType::AddCertificate(add) => {
+                    let mut attributes = Map::new();
+                    let certificate = &add.certificate;
+                    let fingerprint = fingerprint(&certificate.certificate);
+                    set_address(&mut attributes, "address", &add.address);
+                    attributes.insert("fingerprint".to_owned(), fingerprint.clone());
+                    if !certificate.names.is_empty() {
+                        attributes.insert("names".to_owned(), certificate.names.clone().into());
+                    }
+                    set_enums(&mut attributes, "versions", certificate.versions());
+                    if let Some(expired_at) = add.expired_at {
+                        attributes.insert("expired_at".to_owned(), expired_at.into());
+                    }
+                    let name = match certificate.names.first() {
+                        Some(name) => format!("{name}_{}", SocketAddr::from(add.address.clone())),
+                        None => fingerprint.as_str().unwrap_or("certificate").to_owned(),
+                    };
+                    resources.insert("sozu_certificate", &name, attributes);
+                }
+                // listeners are exported with their activation status
+                _ => {}
+            }
+        }
+        resources
+    }
+
+    /// add a resource, with a name usable as a Terraform identifier and unique
+    /// among the resources of its type
+    fn insert(&mut self, kind: &'static str, name: &str, attributes: Map<String, Value>) {
+        let resources = self.0.entry(kind).or_default();
+        let name = identifier(name);
+        let mut unique_name = name.clone();
+        let mut counter = 1;
+        while resources.contains_key(&unique_name) {
+            counter += 1;
+            unique_name = format!("{name}_{counter}");
+        }
+        resources.insert(unique_name, attributes);
+    }
+
+    fn count(&self) -> usize {
+        self.0.values().map(BTreeMap::len).sum()
+    }
+
+    /// the Terraform JSON syntax, in a `.tf.json` file
+    fn to_json(&self) -> String {
+        let mut resources = Map::new();
+        for (kind, by_name) in &self.0 {
+            let by_name: Map<String, Value> = by_name
+                .iter()
+                .map(|(name, attributes)| (name.to_owned(), attributes.clone().into()))
+                .collect();
+            resources.insert(kind.to_string(), by_name.into());
+        }
+        let mut root = Map::new();
+        root.insert("resource".to_owned(), resources.into());
+        let mut json = serde_json::to_string_pretty(&Value::Object(root)).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+
+    /// the Terraform language, in a `.tf` file
+    fn to_hcl(&self) -> String {
+        let mut hcl = String::new();
+        for (kind, by_name) in &self.0 {
+            for (name, attributes) in by_name {
+                if !hcl.is_empty() {
+                    hcl.push('\n');
+                }
+                let _ = writeln!(hcl, "resource \"{kind}\" \"{name}\" {{");
+                write_hcl_attributes(&mut hcl, attributes, 1);
+                hcl.push_str("}\n");
+            }
+        }
+        hcl
+    }
+}
+
+/// the fields of a message, without the unset and empty ones
+fn attributes<T: Serialize>(message: &T) -> Map<String, Value> {
+    match serde_json::to_value(message) {
+        Ok(Value::Object(map)) => prune(map),
+        _ => Map::new(),
+    }
+}
+
+fn prune(map: Map<String, Value>) -> Map<String, Value> {
+    map.into_iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                Value::Object(map) => Value::Object(prune(map)),
+                value => value,
+            };
+            let empty = match &value {
+                Value::Null => true,
+                Value::Array(array) => array.is_empty(),
+                Value::Object(map) => map.is_empty(),
+                _ => false,
+            };
+            (!empty).then_some((key, value))
+        })
+        .collect()
+}
+
+fn set_address(attributes: &mut Map<String, Value>, key: &str, address: &SocketAddress) {
+    attributes.insert(
+        key.to_owned(),
+        SocketAddr::from(address.clone()).to_string().into(),
+    );
+}
+
+fn set_address_opt(
+    attributes: &mut Map<String, Value>,
+    key: &str,
+    address: &Option<SocketAddress>,
+) {
+    if let Some(address) = address {
+        set_address(attributes, key, address);
+    }
+}
+
+/// enumerations are serialized as integers, they are exported with their name
+trait EnumName {
+    fn name(&self) -> &'static str;
+}
+
+macro_rules! enum_names {
+    ($($enumeration:ident),*) => {
+        $(
+            impl EnumName for sozu_command_lib::proto::command::$enumeration {
+                fn name(&self) -> &'static str {
+                    self.as_str_name()
+                }
+            }
+        )*
+    };
+}
+
+enum_names!(
+    LoadBalancingAlgorithms,
+    LoadMetric,
+    ProxyStatusHeader,
+    RulePosition,
+    TlsVersion
+);
+
+fn set_enum<E: EnumName>(attributes: &mut Map<String, Value>, key: &str, value: E) {
+    attributes.insert(key.to_owned(), value.name().into());
+}
+
+fn set_enums<E: EnumName>(
+    attributes: &mut Map<String, Value>,
+    key: &str,
+    values: impl Iterator<Item = E>,
+) {
+    let names: Vec<Value> = values.map(|value| value.name().into()).collect();
+    if !names.is_empty() {
+        attributes.insert(key.to_owned(), names.into());
+    }
+}
+
+fn fingerprint(certificate: &str) -> Value {
+    match calculate_fingerprint(certificate.as_bytes()) {
+        Ok(fingerprint) => Fingerprint(fingerprint).to_string().into(),
+        Err(_) => Value::Null,
+    }
+}
+
+fn listener_name(name: &Option<String>, address: &SocketAddress) -> String {
+    match name {
+        Some(name) => name.to_owned(),
+        None => SocketAddr::from(address.clone()).to_string(),
+    }
+}
+
+/// Terraform names start with a letter or an underscore, followed by letters,
+/// digits, underscores and dashes
+fn identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    if !identifier.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+fn write_hcl_attributes(hcl: &mut String, attributes: &Map<String, Value>, depth: usize) {
+    let keys: Vec<String> = attributes.keys().map(|key| hcl_key(key)).collect();
+    let width = keys.iter().map(String::len).max().unwrap_or(0);
+    for (key, value) in keys.iter().zip(attributes.values()) {
+        let _ = write!(hcl, "{}{key:width$} = ", "  ".repeat(depth));
+        write_hcl_value(hcl, value, depth);
+        hcl.push('\n');
+    }
+}
+
+fn write_hcl_value(hcl: &mut String, value: &Value, depth: usize) {
+    match value {
+        Value::Null => hcl.push_str("null"),
+        Value::Bool(boolean) => {
+            let _ = write!(hcl, "{boolean}");
+        }
+        Value::Number(number) => {
+            let _ = write!(hcl, "{number}");
+        }
+        Value::String(string) => hcl.push_str(&hcl_string(string)),
+        Value::Array(values) => {
+            if values
+                .iter()
+                .all(|value| !value.is_array() && !value.is_object())
+            {
+                hcl.push('[');
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        hcl.push_str(", ");
+                    }
+                    write_hcl_value(hcl, value, depth);
+                }
+                hcl.push(']');
+            } else {
+                hcl.push_str("[\n");
+                for value in values {
+                    hcl.push_str(&"  ".repeat(depth + 1));
+                    write_hcl_value(hcl, value, depth + 1);
+                    hcl.push_str(",\n");
+                }
+                let _ = write!(hcl, "{}]", "  ".repeat(depth));
+            }
+        }
+        Value::Object(map) => {
+            hcl.push_str("{\n");
+            write_hcl_attributes(hcl, map, depth + 1);
+            let _ = write!(hcl, "{}}}", "  ".repeat(depth));
+        }
+    }
+}
+
+/// object keys are quoted unless they are identifiers
+fn hcl_key(key: &str) -> String {
+    if !key.is_empty() && identifier(key) == key {
+        key.to_owned()
+    } else {
+        hcl_string(key)
+    }
+}
+
+/// a quoted string, where the template sequences are escaped too
+fn hcl_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '$' | '%' if chars.peek() == Some(&'{') => {
+                quoted.push(c);
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn state_from_requests<'a>(
+    requests: impl IntoIterator<Item = &'a Request>,
+) -> Result<ConfigState, String> {
+    let mut state = ConfigState::new();
+    for request in requests {
+        state.dispatch(request).map_err(|error| error.to_string())?;
+    }
+    Ok(state)
+}
+
+/// read a state written by `state export` in JSON or in protobuf
+pub fn deserialize_state(bytes: &[u8], format: ExportFormat) -> Result<ConfigState, String> {
+    match format {
+        ExportFormat::Json => serde_json::from_slice(bytes).map_err(|error| error.to_string()),
+        ExportFormat::Protobuf => {
+            let initial_state = InitialState::decode(bytes).map_err(|error| error.to_string())?;
+            state_from_requests(
+                initial_state
+                    .requests
+                    .iter()
+                    .map(|request| &request.content),
+            )
+        }
+        ExportFormat::Terraform | ExportFormat::TerraformJson => Err(
+            "Terraform resources can not be imported, export the state in json or pb".to_owned(),
+        ),
+    }
+}
+
+/// render the requests recreating the state in the given format, to stdout
+/// or to a file
+pub fn export_state(
+    requests: &[Request],
+    format: ExportFormat,
+    output: Option<String>,
+) -> Result<(), ExportError> {
+    let (rendered, summary) = match format {
+        ExportFormat::Terraform | ExportFormat::TerraformJson => {
+            let resources = Resources::from_requests(requests);
+            let rendered = match format {
+                ExportFormat::Terraform => resources.to_hcl(),
+                _ => resources.to_json(),
+            };
+            (
+                rendered.into_bytes(),
+                format!("{} resources", resources.count()),
+            )
+        }
+        ExportFormat::Json | ExportFormat::Protobuf => {
+            let state = state_from_requests(requests).map_err(ExportError::State)?;
+            let rendered = match format {
+                ExportFormat::Json => {
+                    let mut json = serde_json::to_vec_pretty(&state)
+                        .map_err(|error| ExportError::State(error.to_string()))?;
+                    json.push(b'\n');
+                    json
+                }
+                _ => state.produce_initial_state().encode_to_vec(),
+            };
+            (
+                rendered,
+                format!("the state of {} clusters", state.clusters.len()),
+            )
+        }
+    };
+
+    match output {
+        Some(path) => {
+            fs::write(&path, rendered).map_err(|error| ExportError::WriteFile {
+                path: path.to_owned(),
+                error,
+            })?;
+            println!("{summary} written to {path}");
+        }
+        None => {
+            io::Write::write_all(&mut io::stdout(), &rendered).map_err(ExportError::WriteStdout)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use sozu_command_lib::proto::command::{
+        AddBackend, Cluster, LoadBalancingAlgorithms, LoadBalancingParams, PathRule,
+        RequestHttpFrontend, RulePosition,
+    };
+
+    use super::*;
+
+    fn requests() -> Vec<Request> {
+        let address: SocketAddress = "127.0.0.1:8080".parse::<SocketAddr>().unwrap().into();
+        vec![
+            RequestType::AddCluster(Cluster {
+                cluster_id: "my-app".to_owned(),
+                sticky_session: false,
+                https_redirect: true,
+                load_balancing: LoadBalancingAlgorithms::LeastLoaded as i32,
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddHttpFrontend(RequestHttpFrontend {
+                cluster_id: Some("my-app".to_owned()),
+                address: address.clone(),
+                hostname: "app.example.com".to_owned(),
+                path: PathRule::prefix("/api".to_owned()),
+                position: RulePosition::Tree as i32,
+                tags: BTreeMap::from([("owner".to_owned(), "team ${a}".to_owned())]),
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddBackend(AddBackend {
+                cluster_id: "my-app".to_owned(),
+                backend_id: "my-app-0".to_owned(),
+                address: "10.0.0.1:1026".parse::<SocketAddr>().unwrap().into(),
+                load_balancing_parameters: Some(LoadBalancingParams {
+                    weight: 10,
+                    slow_start_seconds: None,
+                }),
+                ..Default::default()
+            })
+            .into(),
+        ]
+    }
+
+    #[test]
+    fn export_terraform() {
+        let hcl = Resources::from_requests(&requests()).to_hcl();
+        assert_eq!(
+            hcl,
+            r#"resource "sozu_backend" "my-app_my-app-0" {
+  address    = "10.0.0.1:1026"
+  backend_id = "my-app-0"
+  cluster_id = "my-app"
+  weight     = 10
+}
+
+resource "sozu_cluster" "my-app" {
+  cluster_id      = "my-app"
+  dscp_on_clients = false
+  https_redirect  = true
+  load_balancing  = "LEAST_LOADED"
+  sticky_session  = false
+  sticky_table    = false
+  transparent     = false
+}
+
+resource "sozu_http_frontend" "my-app_app_example_com_api" {
+  address    = "127.0.0.1:8080"
+  cluster_id = "my-app"
+  hostname   = "app.example.com"
+  path       = {
+    kind  = "PREFIX"
+    value = "/api"
+  }
+  position   = "TREE"
+  tags       = {
+    owner = "team $${a}"
+  }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn export_json() {
+        let json = Resources::from_requests(&requests()).to_json();
+        let value: Value = serde_json::from_str(&json).expect("invalid JSON");
+        assert_eq!(
+            value["resource"]["sozu_backend"]["my-app_my-app-0"]["address"],
+            "10.0.0.1:1026"
+        );
+        assert_eq!(
+            value["resource"]["sozu_cluster"]["my-app"]["load_balancing"],
+            "LEAST_LOADED"
+        );
+    }
+
+    #[test]
+    fn unique_identifiers() {
+        let mut resources = Resources::default();
+        resources.insert("sozu_cluster", "1.app", Map::new());
+        resources.insert("sozu_cluster", "1:app", Map::new());
+        let names: Vec<&String> = resources.0["sozu_cluster"].keys().collect();
+        assert_eq!(names, vec!["_1_app", "_1_app_2"]);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let state = state_from_requests(&requests()).unwrap();
+
+        let json = serde_json::to_vec(&state).unwrap();
+        let restored = deserialize_state(&json, ExportFormat::Json).unwrap();
+        assert!(state.diff(&restored).is_empty());
+
+        let protobuf = state.produce_initial_state().encode_to_vec();
+        let restored = deserialize_state(&protobuf, ExportFormat::Protobuf).unwrap();
+        assert!(state.diff(&restored).is_empty());
+        assert_eq!(restored.clusters, state.clusters);
+
+        assert!(deserialize_state(&json, ExportFormat::Terraform).is_err());
+    }
+}
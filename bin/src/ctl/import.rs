@@ -0,0 +1,1125 @@
+//! Conversion of HAProxy and nginx configuration files to a Sōzu `config.toml`.
+//!
+//! Only the common proxying constructs are translated: listening addresses,
+//! host and path routing rules, upstream servers, load balancing and HTTPS redirections.
+//! Everything else is listed in a report, with line numbers, for manual review.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    fs,
+    net::SocketAddr,
+};
+
+use crate::cli::ImportFormat;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ImportError {
+    #[error("could not read file {path}: {error}")]
+    ReadFile { path: String, error: std::io::Error },
+    #[error("could not write file {path}: {error}")]
+    WriteFile { path: String, error: std::io::Error },
+    #[error("nothing to import: no cluster could be generated from {0}")]
+    Empty(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ImportedFrontend {
+    address: SocketAddr,
+    hostname: Option<String>,
+    path: Option<String>,
+    path_type: Option<&'static str>,
+    certificate: Option<String>,
+    key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ImportedBackend {
+    address: SocketAddr,
+    backend_id: String,
+    weight: Option<u8>,
+    backup: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct ImportedCluster {
+    tcp: bool,
+    load_balancing: Option<&'static str>,
+    sticky_session: bool,
+    https_redirect: bool,
+    frontends: Vec<ImportedFrontend>,
+    backends: Vec<ImportedBackend>,
+}
+
+impl ImportedCluster {
+    fn add_frontend(&mut self, frontend: ImportedFrontend) {
+        if !self.frontends.contains(&frontend) {
+            self.frontends.push(frontend);
+        }
+    }
+}
+
+/// The result of an import: the clusters that could be translated,
+/// and the directives that could not
+#[derive(Debug, Default)]
+pub struct ImportedConfig {
+    clusters: BTreeMap<String, ImportedCluster>,
+    unsupported: Vec<String>,
+}
+
+impl ImportedConfig {
+    fn unsupported(&mut self, line: usize, message: impl AsRef<str>) {
+        self.unsupported
+            .push(format!("line {line}: {}", message.as_ref()));
+    }
+
+    /// write the clusters in the format of the `[clusters]` section of `config.toml`
+    pub fn to_toml(&self, source: &str) -> String {
+        let mut toml = String::new();
+        let _ = writeln!(toml, "# generated by `sozu config import` from {source}");
+        let _ = writeln!(
+            toml,
+            "# listeners are created with default options for each frontend address"
+        );
+        let _ = writeln!(toml, "\n[clusters]");
+
+        for (cluster_id, cluster) in &self.clusters {
+            let _ = writeln!(toml, "\n[clusters.{}]", toml_key(cluster_id));
+            let protocol = if cluster.tcp { "tcp" } else { "http" };
+            let _ = writeln!(toml, "protocol = \"{protocol}\"");
+            if let Some(load_balancing) = cluster.load_balancing {
+                let _ = writeln!(toml, "load_balancing = \"{load_balancing}\"");
+            }
+            if cluster.sticky_session {
+                let _ = writeln!(toml, "sticky_session = true");
+            }
+            if cluster.https_redirect {
+                let _ = writeln!(toml, "https_redirect = true");
+            }
+
+            let _ = writeln!(toml, "frontends = [");
+            for frontend in &cluster.frontends {
+                let mut fields = vec![format!("address = \"{}\"", frontend.address)];
+                if let Some(hostname) = &frontend.hostname {
+                    fields.push(format!("hostname = {}", toml_string(hostname)));
+                }
+                if let Some(path) = &frontend.path {
+                    fields.push(format!("path = {}", toml_string(path)));
+                }
+                if let Some(path_type) = frontend.path_type {
+                    fields.push(format!("path_type = \"{path_type}\""));
+                }
+                if let Some(certificate) = &frontend.certificate {
+                    fields.push(format!("certificate = {}", toml_string(certificate)));
+                }
+                if let Some(key) = &frontend.key {
+                    fields.push(format!("key = {}", toml_string(key)));
+                }
+                let _ = writeln!(toml, "    {{ {} }},", fields.join(", "));
+            }
+            let _ = writeln!(toml, "]");
+
+            let _ = writeln!(toml, "backends = [");
+            for backend in &cluster.backends {
+                let mut fields = vec![
+                    format!("address = \"{}\"", backend.address),
+                    format!("backend_id = {}", toml_string(&backend.backend_id)),
+                ];
+                if let Some(weight) = backend.weight {
+                    fields.push(format!("weight = {weight}"));
+                }
+                if backend.backup {
+                    fields.push("backup = true".to_owned());
+                }
+                let _ = writeln!(toml, "    {{ {} }},", fields.join(", "));
+            }
+            let _ = writeln!(toml, "]");
+        }
+        toml
+    }
+
+    /// human readable list of what could not be translated
+    pub fn report(&self) -> String {
+        let mut report = format!("imported {} cluster(s)", self.clusters.len());
+        if self.unsupported.is_empty() {
+            report.push_str(", every directive was translated");
+        } else {
+            let _ = write!(
+                report,
+                ", {} directive(s) need a manual review:",
+                self.unsupported.len()
+            );
+            for line in &self.unsupported {
+                let _ = write!(report, "\n  - {line}");
+            }
+        }
+        report
+    }
+}
+
+/// read a HAProxy or nginx configuration file, write the Sōzu equivalent
+/// to `output` (or stdout), and print the report of unsupported directives
+pub fn import_config(
+    format: ImportFormat,
+    path: &str,
+    output: Option<String>,
+) -> Result<(), ImportError> {
+    let input = fs::read_to_string(path).map_err(|error| ImportError::ReadFile {
+        path: path.to_owned(),
+        error,
+    })?;
+
+    let imported = match format {
+        ImportFormat::Haproxy => parse_haproxy(&input),
+        ImportFormat::Nginx => parse_nginx(&input),
+    };
+
+    if imported.clusters.is_empty() {
+        eprintln!("{}", imported.report());
+        return Err(ImportError::Empty(path.to_owned()));
+    }
+
+    let toml = imported.to_toml(path);
+    match output {
+        Some(output_path) => {
+            fs::write(&output_path, toml).map_err(|error| ImportError::WriteFile {
+                path: output_path.to_owned(),
+                error,
+            })?;
+            println!("configuration written to {output_path}");
+            println!("{}", imported.report());
+        }
+        None => {
+            print!("{toml}");
+            // keep stdout clean so that it can be redirected to a file
+            eprintln!("{}", imported.report());
+        }
+    }
+    Ok(())
+}
+
+fn toml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn toml_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_owned()
+    } else {
+        toml_string(key)
+    }
+}
+
+/// parses `*:80`, `:80`, `80`, `127.0.0.1:80` and `[::]:80`, with a default address
+fn parse_listen_address(address: &str) -> Option<SocketAddr> {
+    let address = address
+        .trim_start_matches("ipv4@")
+        .trim_start_matches("ipv6@");
+    if let Ok(port) = address.parse::<u16>() {
+        return Some(SocketAddr::from(([0, 0, 0, 0], port)));
+    }
+    if let Some(port) = address
+        .strip_prefix("*:")
+        .or_else(|| address.strip_prefix(':'))
+    {
+        return port
+            .parse::<u16>()
+            .ok()
+            .map(|port| SocketAddr::from(([0, 0, 0, 0], port)));
+    }
+    address.parse().ok()
+}
+
+/// parses a backend address, with a default port if there is none
+fn parse_server_address(address: &str, default_port: u16) -> Option<SocketAddr> {
+    address.parse().ok().or_else(|| {
+        address
+            .parse::<std::net::IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, default_port))
+    })
+}
+
+fn parse_weight(weight: &str) -> Option<u8> {
+    weight
+        .parse::<u32>()
+        .ok()
+        .map(|weight| weight.min(u8::MAX as u32) as u8)
+}
+
+//===============================================
+// HAProxy
+
+#[derive(Debug, Clone)]
+enum AclCondition {
+    Host(Vec<String>),
+    Path(String, &'static str),
+}
+
+#[derive(Debug, Default)]
+struct HaproxyFrontend {
+    line: usize,
+    /// `listen` sections are both a frontend and a backend
+    is_listen: bool,
+    tcp: Option<bool>,
+    binds: Vec<(SocketAddr, Option<String>)>,
+    acls: HashMap<String, Vec<AclCondition>>,
+    /// backend name, alternatives of ACL names that must all match
+    use_backends: Vec<(usize, String, Vec<Vec<String>>)>,
+    default_backend: Option<String>,
+    https_redirect: bool,
+}
+
+#[derive(Debug, Default)]
+struct HaproxyBackend {
+    tcp: Option<bool>,
+    load_balancing: Option<&'static str>,
+    sticky_session: bool,
+    servers: Vec<ImportedBackend>,
+}
+
+pub fn parse_haproxy(input: &str) -> ImportedConfig {
+    let mut imported = ImportedConfig::default();
+    let mut default_tcp = false;
+    let mut default_load_balancing = None;
+    let mut frontends: BTreeMap<String, HaproxyFrontend> = BTreeMap::new();
+    let mut backends: BTreeMap<String, HaproxyBackend> = BTreeMap::new();
+
+    // (section kind, section name)
+    let mut section: Option<(String, String)> = None;
+
+    for (index, raw_line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let line = raw_line.split('#').next().unwrap_or_default().trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(&keyword) = words.first() else {
+            continue;
+        };
+
+        match keyword {
+            "global" | "defaults" | "frontend" | "backend" | "listen" => {
+                let name = words.get(1).unwrap_or(&"").to_string();
+                if keyword == "frontend" || keyword == "listen" {
+                    let frontend = frontends.entry(name.clone()).or_default();
+                    frontend.line = line_number;
+                    frontend.is_listen = keyword == "listen";
+                }
+                if keyword == "backend" || keyword == "listen" {
+                    backends.entry(name.clone()).or_default();
+                }
+                section = Some((keyword.to_owned(), name));
+                continue;
+            }
+            "userlist" | "peers" | "resolvers" | "mailers" | "program" | "cache"
+            | "http-errors" | "ring" => {
+                imported.unsupported(line_number, format!("section `{line}` is ignored"));
+                section = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let Some((kind, name)) = &section else {
+            continue;
+        };
+
+        match (kind.as_str(), keyword) {
+            ("global", _) => imported.unsupported(line_number, format!("global `{line}`")),
+            ("defaults", "mode") => default_tcp = words.get(1) == Some(&"tcp"),
+            ("defaults", "balance") => {
+                default_load_balancing = haproxy_balance(&words, line_number, &mut imported)
+            }
+            ("defaults", _) => imported.unsupported(line_number, format!("defaults `{line}`")),
+            (_, "mode") => {
+                let tcp = words.get(1) == Some(&"tcp");
+                if let Some(frontend) = frontends.get_mut(name) {
+                    frontend.tcp = Some(tcp);
+                }
+                if let Some(backend) = backends.get_mut(name) {
+                    backend.tcp = Some(tcp);
+                }
+            }
+            ("frontend" | "listen", "bind") => {
+                let Some(address) = words.get(1).and_then(|a| parse_listen_address(a)) else {
+                    imported.unsupported(line_number, format!("unparsable bind `{line}`"));
+                    continue;
+                };
+                let certificate = words
+                    .iter()
+                    .position(|word| *word == "crt")
+                    .and_then(|position| words.get(position + 1))
+                    .map(|path| path.to_string());
+                let has_other_options = words[2..].iter().any(|word| {
+                    !matches!(*word, "ssl" | "crt") && Some(*word) != certificate.as_deref()
+                });
+                if has_other_options {
+                    imported.unsupported(line_number, format!("bind options in `{line}`"));
+                }
+                frontends
+                    .get_mut(name)
+                    .unwrap()
+                    .binds
+                    .push((address, certificate));
+            }
+            ("frontend" | "listen", "acl") => {
+                if let Some(condition) = haproxy_acl(&words) {
+                    frontends
+                        .get_mut(name)
+                        .unwrap()
+                        .acls
+                        .entry(words[1].to_owned())
+                        .or_default()
+                        .push(condition);
+                } else {
+                    imported.unsupported(line_number, format!("acl `{line}`"));
+                }
+            }
+            ("frontend" | "listen", "use_backend") => {
+                let Some(backend_name) = words.get(1) else {
+                    continue;
+                };
+                match words.get(2) {
+                    Some(&"if") => {
+                        let conditions = &words[3..];
+                        if conditions
+                            .iter()
+                            .any(|word| word.starts_with('!') || word.starts_with('{'))
+                        {
+                            imported.unsupported(
+                                line_number,
+                                format!("negated or anonymous ACL in `{line}`"),
+                            );
+                            continue;
+                        }
+                        let alternatives = conditions
+                            .split(|word| *word == "||" || *word == "or")
+                            .map(|acls| acls.iter().map(|acl| acl.to_string()).collect())
+                            .collect();
+                        frontends.get_mut(name).unwrap().use_backends.push((
+                            line_number,
+                            backend_name.to_string(),
+                            alternatives,
+                        ));
+                    }
+                    _ => imported.unsupported(line_number, format!("use_backend `{line}`")),
+                }
+            }
+            ("frontend" | "listen", "default_backend") => {
+                frontends.get_mut(name).unwrap().default_backend =
+                    words.get(1).map(|backend| backend.to_string());
+            }
+            ("frontend" | "listen", "redirect")
+                if words.get(1) == Some(&"scheme") && words.get(2) == Some(&"https") =>
+            {
+                frontends.get_mut(name).unwrap().https_redirect = true;
+            }
+            ("frontend" | "listen", "http-request")
+                if words.get(1) == Some(&"redirect")
+                    && words.get(2) == Some(&"scheme")
+                    && words.get(3) == Some(&"https") =>
+            {
+                frontends.get_mut(name).unwrap().https_redirect = true;
+            }
+            ("backend" | "listen", "balance") => {
+                backends.get_mut(name).unwrap().load_balancing =
+                    haproxy_balance(&words, line_number, &mut imported);
+            }
+            ("backend" | "listen", "cookie") => {
+                backends.get_mut(name).unwrap().sticky_session = true;
+                imported.unsupported(
+                    line_number,
+                    format!("`{line}`: sticky sessions are enabled, but use Sōzu's own cookie"),
+                );
+            }
+            ("backend" | "listen", "server") => {
+                let (Some(server_name), Some(address)) = (words.get(1), words.get(2)) else {
+                    imported.unsupported(line_number, format!("server `{line}`"));
+                    continue;
+                };
+                let Some(address) = parse_server_address(address, 80) else {
+                    imported.unsupported(
+                        line_number,
+                        format!("server `{line}`: the address must be an IP address"),
+                    );
+                    continue;
+                };
+                let weight = words
+                    .iter()
+                    .position(|word| *word == "weight")
+                    .and_then(|position| words.get(position + 1))
+                    .and_then(|weight| parse_weight(weight));
+                backends
+                    .get_mut(name)
+                    .unwrap()
+                    .servers
+                    .push(ImportedBackend {
+                        address,
+                        backend_id: format!("{name}-{server_name}"),
+                        weight,
+                        backup: words.contains(&"backup"),
+                    });
+            }
+            _ => imported.unsupported(line_number, format!("{kind} {name}: `{line}`")),
+        }
+    }
+
+    for (backend_name, backend) in &backends {
+        imported.clusters.insert(
+            backend_name.to_owned(),
+            ImportedCluster {
+                tcp: backend.tcp.unwrap_or(default_tcp),
+                load_balancing: backend.load_balancing.or(default_load_balancing),
+                sticky_session: backend.sticky_session,
+                backends: backend.servers.clone(),
+                ..Default::default()
+            },
+        );
+    }
+
+    for (frontend_name, frontend) in &frontends {
+        let tcp = frontend.tcp.unwrap_or(default_tcp);
+
+        for (line_number, backend_name, alternatives) in &frontend.use_backends {
+            for acl_names in alternatives {
+                let mut hostnames = Vec::new();
+                let mut path = None;
+                for acl_name in acl_names {
+                    match frontend.acls.get(acl_name) {
+                        Some(conditions) => {
+                            for condition in conditions {
+                                match condition {
+                                    AclCondition::Host(hosts) => hostnames.extend(hosts.clone()),
+                                    AclCondition::Path(p, t) => path = Some((p.clone(), *t)),
+                                }
+                            }
+                        }
+                        None => {
+                            imported.unsupported(*line_number, format!("unknown acl `{acl_name}`"))
+                        }
+                    }
+                }
+                if hostnames.is_empty() {
+                    imported.unsupported(
+                        *line_number,
+                        format!(
+                            "frontend {frontend_name}: a routing rule to {backend_name} has no hostname"
+                        ),
+                    );
+                    continue;
+                }
+                haproxy_route(
+                    &mut imported,
+                    frontend,
+                    backend_name,
+                    *line_number,
+                    hostnames.into_iter().map(Some).collect(),
+                    path,
+                );
+            }
+        }
+
+        if let Some(backend_name) = &frontend.default_backend {
+            if tcp {
+                haproxy_route(
+                    &mut imported,
+                    frontend,
+                    backend_name,
+                    frontend.line,
+                    vec![None],
+                    None,
+                );
+            } else {
+                imported.unsupported(
+                    frontend.line,
+                    format!(
+                        "frontend {frontend_name}: default_backend {backend_name} has no hostname, \
+                        Sōzu routes HTTP traffic by hostname"
+                    ),
+                );
+            }
+        }
+
+        // listen sections route directly to their own servers
+        if frontend.is_listen
+            && frontend.use_backends.is_empty()
+            && frontend.default_backend.is_none()
+        {
+            if tcp {
+                haproxy_route(
+                    &mut imported,
+                    frontend,
+                    frontend_name,
+                    frontend.line,
+                    vec![None],
+                    None,
+                );
+            } else {
+                imported.unsupported(
+                    frontend.line,
+                    format!(
+                        "listen {frontend_name}: no hostname to route HTTP traffic to its servers"
+                    ),
+                );
+            }
+        }
+    }
+
+    imported
+        .clusters
+        .retain(|_, cluster| !cluster.frontends.is_empty());
+    imported
+}
+
+fn haproxy_route(
+    imported: &mut ImportedConfig,
+    frontend: &HaproxyFrontend,
+    backend_name: &str,
+    line_number: usize,
+    hostnames: Vec<Option<String>>,
+    path: Option<(String, &'static str)>,
+) {
+    let Some(cluster) = imported.clusters.get_mut(backend_name) else {
+        imported.unsupported(line_number, format!("unknown backend {backend_name}"));
+        return;
+    };
+    if frontend.https_redirect {
+        cluster.https_redirect = true;
+    }
+    for (address, certificate) in &frontend.binds {
+        for hostname in &hostnames {
+            cluster.add_frontend(ImportedFrontend {
+                address: *address,
+                hostname: hostname.clone(),
+                path: path.as_ref().map(|(path, _)| path.clone()),
+                path_type: path.as_ref().map(|(_, path_type)| *path_type),
+                // HAProxy certificates are PEM files containing both the certificate and the key
+                certificate: certificate.clone(),
+                key: certificate.clone(),
+            });
+        }
+    }
+}
+
+fn haproxy_balance(
+    words: &[&str],
+    line_number: usize,
+    imported: &mut ImportedConfig,
+) -> Option<&'static str> {
+    match words.get(1) {
+        Some(&"roundrobin") | Some(&"static-rr") => Some("ROUND_ROBIN"),
+        Some(&"leastconn") => Some("LEAST_LOADED"),
+        Some(&"random") => Some("RANDOM"),
+        _ => {
+            imported.unsupported(line_number, format!("balance `{}`", words.join(" ")));
+            None
+        }
+    }
+}
+
+fn haproxy_acl(words: &[&str]) -> Option<AclCondition> {
+    let fetch = *words.get(2)?;
+    let values: Vec<String> = words[3..]
+        .iter()
+        .filter(|word| !word.starts_with('-'))
+        .map(|word| word.to_string())
+        .collect();
+    if values.is_empty() {
+        return None;
+    }
+    match fetch {
+        "hdr(host)" | "req.hdr(host)" | "hdr_dom(host)" | "hdr_end(host)" | "req.hdr_dom(host)" => {
+            Some(AclCondition::Host(values))
+        }
+        "path_beg" | "req.path_beg" => Some(AclCondition::Path(values[0].clone(), "PREFIX")),
+        "path" | "req.path" => Some(AclCondition::Path(values[0].clone(), "EQUALS")),
+        "path_reg" | "req.path_reg" => Some(AclCondition::Path(values[0].clone(), "REGEX")),
+        _ => None,
+    }
+}
+
+//===============================================
+// nginx
+
+#[derive(Debug, Clone)]
+struct NginxDirective {
+    line: usize,
+    name: String,
+    args: Vec<String>,
+    block: Option<Vec<NginxDirective>>,
+}
+
+fn nginx_tokenize(input: &str) -> Vec<(usize, String)> {
+    let mut tokens = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        let mut chars = line.chars().peekable();
+        let mut current = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '#' => break,
+                '"' | '\'' => {
+                    for quoted in chars.by_ref() {
+                        if quoted == c {
+                            break;
+                        }
+                        current.push(quoted);
+                    }
+                }
+                '{' | '}' | ';' => {
+                    if !current.is_empty() {
+                        tokens.push((index + 1, std::mem::take(&mut current)));
+                    }
+                    tokens.push((index + 1, c.to_string()));
+                }
+                c if c.is_whitespace() => {
+                    if !current.is_empty() {
+                        tokens.push((index + 1, std::mem::take(&mut current)));
+                    }
+                }
+                c => current.push(c),
+            }
+        }
+        if !current.is_empty() {
+            tokens.push((index + 1, current));
+        }
+    }
+    tokens
+}
+
+fn nginx_parse_block(tokens: &mut std::vec::IntoIter<(usize, String)>) -> Vec<NginxDirective> {
+    let mut directives = Vec::new();
+    let mut current: Option<NginxDirective> = None;
+
+    while let Some((line, token)) = tokens.next() {
+        match token.as_str() {
+            ";" => directives.extend(current.take()),
+            "{" => {
+                let mut directive = current.take().unwrap_or(NginxDirective {
+                    line,
+                    name: String::new(),
+                    args: Vec::new(),
+                    block: None,
+                });
+                directive.block = Some(nginx_parse_block(tokens));
+                directives.push(directive);
+            }
+            "}" => break,
+            _ => match &mut current {
+                Some(directive) => directive.args.push(token),
+                None => {
+                    current = Some(NginxDirective {
+                        line,
+                        name: token,
+                        args: Vec::new(),
+                        block: None,
+                    })
+                }
+            },
+        }
+    }
+    directives
+}
+
+pub fn parse_nginx(input: &str) -> ImportedConfig {
+    let mut imported = ImportedConfig::default();
+    let directives = nginx_parse_block(&mut nginx_tokenize(input).into_iter());
+
+    for directive in &directives {
+        match (directive.name.as_str(), &directive.block) {
+            ("http", Some(block)) => nginx_context(&mut imported, block, false),
+            ("stream", Some(block)) => nginx_context(&mut imported, block, true),
+            ("events", _) => {}
+            _ => imported.unsupported(directive.line, format!("`{}`", directive.name)),
+        }
+    }
+
+    imported
+        .clusters
+        .retain(|_, cluster| !cluster.frontends.is_empty());
+    imported
+}
+
+/// an `http` or `stream` block
+fn nginx_context(imported: &mut ImportedConfig, block: &[NginxDirective], tcp: bool) {
+    // upstreams first, since servers refer to them
+    for directive in block.iter().filter(|d| d.name == "upstream") {
+        let (Some(name), Some(upstream)) = (directive.args.first(), &directive.block) else {
+            continue;
+        };
+        let mut cluster = ImportedCluster {
+            tcp,
+            ..Default::default()
+        };
+        for (index, server) in upstream.iter().enumerate() {
+            match server.name.as_str() {
+                "server" => {
+                    let Some(address) = server
+                        .args
+                        .first()
+                        .and_then(|address| parse_server_address(address, 80))
+                    else {
+                        imported.unsupported(
+                            server.line,
+                            format!(
+                                "server {:?}: the address must be an IP address",
+                                server.args
+                            ),
+                        );
+                        continue;
+                    };
+                    cluster.backends.push(ImportedBackend {
+                        address,
+                        backend_id: format!("{name}-{index}"),
+                        weight: server
+                            .args
+                            .iter()
+                            .find_map(|arg| arg.strip_prefix("weight="))
+                            .and_then(parse_weight),
+                        backup: server.args.iter().any(|arg| arg == "backup"),
+                    });
+                }
+                "least_conn" => cluster.load_balancing = Some("LEAST_LOADED"),
+                "random" => cluster.load_balancing = Some("RANDOM"),
+                other => imported.unsupported(server.line, format!("upstream {name}: `{other}`")),
+            }
+        }
+        imported.clusters.insert(name.to_owned(), cluster);
+    }
+
+    let mut redirected_hostnames = Vec::new();
+    for directive in block {
+        match (directive.name.as_str(), &directive.block) {
+            ("upstream", _) => {}
+            ("server", Some(server)) => {
+                nginx_server(imported, server, tcp, &mut redirected_hostnames)
+            }
+            _ => imported.unsupported(directive.line, format!("`{}`", directive.name)),
+        }
+    }
+
+    for cluster in imported.clusters.values_mut() {
+        if cluster.frontends.iter().any(|frontend| {
+            frontend
+                .hostname
+                .as_ref()
+                .is_some_and(|hostname| redirected_hostnames.contains(hostname))
+        }) {
+            cluster.https_redirect = true;
+        }
+    }
+}
+
+fn nginx_server(
+    imported: &mut ImportedConfig,
+    server: &[NginxDirective],
+    tcp: bool,
+    redirected_hostnames: &mut Vec<String>,
+) {
+    let mut listens = Vec::new();
+    let mut hostnames = Vec::new();
+    let mut certificate = None;
+    let mut key = None;
+    let mut redirects_to_https = false;
+
+    for directive in server {
+        match directive.name.as_str() {
+            "listen" => match directive.args.first().and_then(|a| parse_listen_address(a)) {
+                Some(address) => listens.push(address),
+                None => imported.unsupported(
+                    directive.line,
+                    format!("unparsable listen {:?}", directive.args),
+                ),
+            },
+            "server_name" => hostnames.extend(
+                directive
+                    .args
+                    .iter()
+                    .filter(|name| *name != "_" && !name.starts_with('~'))
+                    .cloned(),
+            ),
+            "ssl_certificate" => certificate = directive.args.first().cloned(),
+            "ssl_certificate_key" => key = directive.args.first().cloned(),
+            "return" if directive.args.iter().any(|arg| arg.starts_with("https://")) => {
+                redirects_to_https = true
+            }
+            _ => {}
+        }
+    }
+
+    if redirects_to_https {
+        redirected_hostnames.extend(hostnames);
+        return;
+    }
+
+    let frontends = |path: Option<String>, path_type: Option<&'static str>| {
+        let hostnames: Vec<Option<String>> = if tcp {
+            vec![None]
+        } else {
+            hostnames.iter().cloned().map(Some).collect()
+        };
+        let mut frontends = Vec::new();
+        for address in &listens {
+            for hostname in &hostnames {
+                frontends.push(ImportedFrontend {
+                    address: *address,
+                    hostname: hostname.clone(),
+                    path: path.clone(),
+                    path_type,
+                    certificate: certificate.clone(),
+                    key: key.clone(),
+                });
+            }
+        }
+        frontends
+    };
+
+    if !tcp && hostnames.is_empty() {
+        if let Some(directive) = server.first() {
+            imported.unsupported(
+                directive.line,
+                "server block without server_name, Sōzu routes HTTP traffic by hostname",
+            );
+        }
+        return;
+    }
+
+    for directive in server {
+        match (directive.name.as_str(), &directive.block) {
+            ("listen" | "server_name" | "ssl_certificate" | "ssl_certificate_key", _) => {}
+            ("proxy_pass", _) => {
+                let Some(cluster_id) = nginx_proxy_pass(imported, directive, tcp, &hostnames)
+                else {
+                    continue;
+                };
+                for frontend in frontends(None, None) {
+                    imported
+                        .clusters
+                        .get_mut(&cluster_id)
+                        .unwrap()
+                        .add_frontend(frontend);
+                }
+            }
+            ("location", Some(location)) => {
+                let (path, path_type) = match directive.args.as_slice() {
+                    [path] => (path.to_owned(), "PREFIX"),
+                    [modifier, path] if modifier == "=" => (path.to_owned(), "EQUALS"),
+                    [modifier, path] if modifier == "^~" => (path.to_owned(), "PREFIX"),
+                    [modifier, path] if modifier == "~" => (path.to_owned(), "REGEX"),
+                    _ => {
+                        imported
+                            .unsupported(directive.line, format!("location {:?}", directive.args));
+                        continue;
+                    }
+                };
+                for location_directive in location {
+                    if location_directive.name != "proxy_pass" {
+                        imported.unsupported(
+                            location_directive.line,
+                            format!("location {path}: `{}`", location_directive.name),
+                        );
+                        continue;
+                    }
+                    let Some(cluster_id) =
+                        nginx_proxy_pass(imported, location_directive, tcp, &hostnames)
+                    else {
+                        continue;
+                    };
+                    // the catch-all location is the default for the hostname
+                    let (path, path_type) = if path == "/" && path_type == "PREFIX" {
+                        (None, None)
+                    } else {
+                        (Some(path.clone()), Some(path_type))
+                    };
+                    for frontend in frontends(path, path_type) {
+                        imported
+                            .clusters
+                            .get_mut(&cluster_id)
+                            .unwrap()
+                            .add_frontend(frontend);
+                    }
+                }
+            }
+            (name, _) => imported.unsupported(directive.line, format!("server: `{name}`")),
+        }
+    }
+}
+
+/// returns the cluster id targeted by a `proxy_pass`, creating a cluster
+/// if it points directly to an address
+fn nginx_proxy_pass(
+    imported: &mut ImportedConfig,
+    directive: &NginxDirective,
+    tcp: bool,
+    hostnames: &[String],
+) -> Option<String> {
+    let target = directive.args.first()?;
+    let target = target
+        .trim_start_matches("http://")
+        .trim_start_matches("https://")
+        .trim_end_matches('/');
+
+    if imported.clusters.contains_key(target) {
+        return Some(target.to_owned());
+    }
+
+    let Some(address) = parse_server_address(target, 80) else {
+        imported.unsupported(
+            directive.line,
+            format!("proxy_pass {target}: unknown upstream or non IP address"),
+        );
+        return None;
+    };
+
+    let cluster_id = hostnames
+        .first()
+        .cloned()
+        .unwrap_or_else(|| address.to_string());
+    imported
+        .clusters
+        .entry(cluster_id.clone())
+        .or_insert_with(|| ImportedCluster {
+            tcp,
+            backends: vec![ImportedBackend {
+                address,
+                backend_id: format!("{cluster_id}-0"),
+                weight: None,
+                backup: false,
+            }],
+            ..Default::default()
+        });
+    Some(cluster_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use sozu_command_lib::config::{ClusterConfig, ConfigBuilder, FileConfig};
+
+    use super::*;
+
+    fn build(imported: &ImportedConfig) -> BTreeMap<String, ClusterConfig> {
+        let mut file = tempfile::NamedTempFile::new().expect("could not create temporary file");
+        file.write_all(imported.to_toml("test").as_bytes())
+            .expect("could not write temporary file");
+        let path = file.path().to_str().unwrap();
+        let file_config = FileConfig::load_from_path(path).expect("invalid generated toml");
+        let config = ConfigBuilder::new(file_config, path)
+            .into_config()
+            .expect("invalid generated config");
+        config.clusters.into_iter().collect()
+    }
+
+    #[test]
+    fn import_haproxy() {
+        let imported = parse_haproxy(
+            r#"
+global
+    maxconn 4096
+
+defaults
+    mode http
+    balance roundrobin
+
+frontend www
+    bind *:80
+    acl is_api hdr(host) -i api.example.com
+    acl is_app hdr(host) -i app.example.com
+    acl is_admin path_beg /admin
+    use_backend api if is_api
+    use_backend admin if is_app is_admin
+    default_backend app
+
+backend api
+    balance leastconn
+    server api1 10.0.0.1:8080 weight 10
+    server api2 10.0.0.2:8080 backup
+
+backend admin
+    server admin1 10.0.0.3:8080
+
+listen database
+    mode tcp
+    bind :5432
+    server db1 10.0.0.4:5432
+"#,
+        );
+
+        let clusters = build(&imported);
+        assert_eq!(
+            clusters.keys().collect::<Vec<_>>(),
+            vec!["admin", "api", "database"]
+        );
+
+        match clusters.get("api") {
+            Some(ClusterConfig::Http(api)) => {
+                assert_eq!(api.frontends.len(), 1);
+                assert_eq!(api.frontends[0].hostname, "api.example.com");
+                assert_eq!(api.backends.len(), 2);
+                assert_eq!(api.backends[0].weight, Some(10));
+                assert_eq!(api.backends[1].backup, Some(true));
+            }
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+        assert!(matches!(
+            clusters.get("database"),
+            Some(ClusterConfig::Tcp(_))
+        ));
+
+        // maxconn, and the default backend without hostname
+        assert_eq!(imported.unsupported.len(), 2);
+    }
+
+    #[test]
+    fn import_nginx() {
+        let imported = parse_nginx(
+            r#"
+events {}
+http {
+    upstream app {
+        least_conn;
+        server 10.0.0.1:8080 weight=3;
+        server 10.0.0.2:8080;
+    }
+
+    server {
+        listen 80;
+        server_name app.example.com;
+        return 301 https://$host$request_uri;
+    }
+
+    server {
+        listen 8443 ssl;
+        server_name app.example.com;
+        location / {
+            proxy_pass http://app;
+        }
+        location /static {
+            proxy_pass http://10.0.0.5:80;
+            proxy_set_header Host $host;
+        }
+    }
+}
+"#,
+        );
+
+        assert_eq!(imported.clusters.len(), 2);
+        let app = imported.clusters.get("app").unwrap();
+        assert!(app.https_redirect);
+        assert_eq!(app.load_balancing, Some("LEAST_LOADED"));
+        assert_eq!(app.backends.len(), 2);
+        assert_eq!(
+            app.frontends[0].hostname.as_deref(),
+            Some("app.example.com")
+        );
+
+        let static_cluster = imported.clusters.get("app.example.com").unwrap();
+        assert_eq!(static_cluster.frontends[0].path.as_deref(), Some("/static"));
+
+        // proxy_set_header
+        assert_eq!(imported.unsupported.len(), 1);
+    }
+}
@@ -1,7 +1,15 @@
+mod check;
 mod command;
+mod completion;
+mod export;
+mod import;
 mod request_builder;
 
-use std::time::Duration;
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
 
 use sozu_command_lib::{
     certificate::CertificateError,
@@ -9,13 +17,21 @@ use sozu_command_lib::{
     config::{Config, ConfigError},
     logging::setup_logging_with_config,
     proto::{
-        command::{Request, Response},
+        command::{ExpectedClusterHash, Request, Response, ResponseError},
         DisplayError,
     },
+    signing::SigningKeyError,
 };
+use sozu_lib::socket::{check_listener_address, AddressCheckError};
 
 use crate::{
     cli::{self, *},
+    ctl::{
+        check::check_config,
+        completion::{complete, completion_script, fetch_live_values},
+        export::ExportError,
+        import::{import_config, ImportError},
+    },
     util::{get_config_file_path, UtilError},
 };
 
@@ -37,6 +53,11 @@ pub enum CtlError {
     ReadBlocking(ChannelError),
     #[error("Request failed: {0}")]
     Failure(String),
+    #[error("Request failed: {message}\n{error}")]
+    FailureWithError {
+        message: String,
+        error: ResponseError,
+    },
     #[error("could not write request on channel: {0}")]
     WriteRequest(ChannelError),
     #[error("could not get certificate fingerprint")]
@@ -53,6 +74,32 @@ pub enum CtlError {
     NeedClusterDomain,
     #[error("wrong response from Sōzu: {0:?}")]
     WrongResponse(Response),
+    #[error("could not import configuration: {0}")]
+    Import(ImportError),
+    #[error("could not export the state: {0}")]
+    Export(ExportError),
+    #[error("could not read answer file: {0}")]
+    ReadAnswerFile(ConfigError),
+    #[error("{0}")]
+    CheckListener(AddressCheckError),
+    #[error("{0}")]
+    HealthCheck(ConfigError),
+    #[error("{0}")]
+    HttpsPolicy(ConfigError),
+    #[error("{0}")]
+    Mirror(ConfigError),
+    #[error("{0}")]
+    SlowLog(ConfigError),
+    #[error("could not read requests from file {path}: {error}")]
+    ReadRequestsFile { path: String, error: String },
+    #[error("could not read the state from file {path}: {error}")]
+    ReadStateFile { path: String, error: String },
+    #[error("could not write the capture to file {path}: {error}")]
+    WriteCaptureFile { path: String, error: String },
+    #[error("{0}")]
+    SigningKey(SigningKeyError),
+    #[error("no listener is named {0}, see 'sozu listener list'")]
+    UnknownListener(String),
 }
 
 pub struct CommandManager {
@@ -61,39 +108,95 @@ pub struct CommandManager {
     config: Config,
     /// wether to display the response in JSON
     json: bool,
+    /// wether the main process should only validate state-changing requests
+    dry_run: bool,
+    /// wether to wait for the backends drained to have no connection left
+    wait: bool,
+    /// the state epoch and the cluster hash the requests expect
+    expected_epoch: Option<u64>,
+    expected_cluster_hash: Option<ExpectedClusterHash>,
 }
 
 pub fn ctl(args: cli::Args) -> Result<(), CtlError> {
-    let config_path = get_config_file_path(&args).map_err(CtlError::GetConfig)?;
+    // importing a foreign configuration does not need a Sōzu configuration file
+    if let SubCmd::Config {
+        cmd: ConfigCmd::Import { from, file, output },
+    } = args.cmd
+    {
+        return import_config(from, &file, output).map_err(CtlError::Import);
+    }
 
-    let config = Config::load_from_path(config_path).map_err(CtlError::LoadConfig)?;
+    // completion scripts do not need a configuration, and completions are
+    // given without the values of the running Sōzu when it can not be reached
+    match &args.cmd {
+        SubCmd::Completion { shell } => {
+            print!("{}", completion_script(*shell));
+            return Ok(());
+        }
+        SubCmd::Complete { words } => {
+            for candidate in complete(words, &mut |live_values| {
+                fetch_live_values(words, live_values)
+            }) {
+                println!("{candidate}");
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    // prevent logging for json responses for a clean output
-    if !args.json {
-        setup_logging_with_config(&config, "CTL");
+    // checking a listener address does not need a running Sōzu
+    if let SubCmd::Listener {
+        cmd: ListenerCmd::Check { address },
+    } = args.cmd
+    {
+        return check_listener(address);
     }
 
-    // If the command is `config check` then exit because if we are here, the configuration is valid
-    if let SubCmd::Config {
+    let config_path = get_config_file_path(&args).map_err(CtlError::GetConfig)?;
+
+    // checking the configuration reports all its errors, instead of the first one
+    if let SubCmd::Check
+    | SubCmd::Config {
         cmd: ConfigCmd::Check,
     } = args.cmd
     {
-        println!("Configuration file is valid");
-        std::process::exit(0);
+        check_config(config_path);
     }
 
-    let channel = create_channel(&config)?;
+    let config = Config::load_from_path(config_path).map_err(CtlError::LoadConfig)?;
+
+    // prevent logging for json responses for a clean output
+    if !args.json {
+        setup_logging_with_config(&config, "CTL");
+    }
 
     let timeout = Duration::from_millis(args.timeout.unwrap_or(config.ctl_command_timeout));
     if !args.json {
         debug!("applying timeout {:?}", timeout);
     }
+    // the timeout covers the wait for the main process, then the command
+    let deadline = (!timeout.is_zero()).then(|| Instant::now() + timeout);
+
+    let mut retry = ConnectRetry::from_config(&config);
+    if args.wait {
+        retry = retry.until(deadline);
+    }
+    let channel = create_channel(&config, retry)?;
+
+    let timeout = match deadline {
+        Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+        None => timeout,
+    };
 
     let mut command_manager = CommandManager {
         channel,
         timeout,
         config,
         json: args.json,
+        dry_run: args.dry_run,
+        wait: args.wait,
+        expected_epoch: args.if_epoch,
+        expected_cluster_hash: args.if_cluster_hash,
     };
 
     command_manager.handle_command(args.cmd)
@@ -114,7 +217,7 @@ impl CommandManager {
                 None => self.upgrade_main(),
                 Some(worker_id) => self.upgrade_worker(worker_id),
             },
-            SubCmd::Status {} => self.status(),
+            SubCmd::Status { verbose } => self.status(verbose),
             SubCmd::Metrics { cmd } => match cmd {
                 MetricsCmd::Get {
                     list,
@@ -126,11 +229,16 @@ impl CommandManager {
                 } => self.get_metrics(list, refresh, names, clusters, backends, no_clusters),
                 _ => self.configure_metrics(cmd),
             },
-            SubCmd::Logging { filter } => self.logging_filter(filter),
+            SubCmd::Logging { filter, cmd } => self.logging_command(filter, cmd),
             SubCmd::State { cmd } => match cmd {
                 StateCmd::Save { file } => self.save_state(file),
                 StateCmd::Load { file } => self.load_state(file),
                 StateCmd::Stats => self.count_requests(),
+                StateCmd::Changes { since_epoch } => self.get_changes(since_epoch),
+                StateCmd::Query { query } => self.query_state(query),
+                StateCmd::Export { format, output } => self.export_state(format, output),
+                StateCmd::Apply { file } => self.apply_state(file),
+                StateCmd::Import { file, format } => self.import_state(file, format),
             },
             SubCmd::Reload { file } => self.reload_configuration(file),
             SubCmd::Cluster { cmd } => self.cluster_command(cmd),
@@ -145,12 +253,14 @@ impl CommandManager {
                     tcp,
                     domain,
                 } => self.list_frontends(http, https, tcp, domain),
+                FrontendCmd::Timeouts { domain } => self.frontend_timeouts(domain),
             },
             SubCmd::Listener { cmd } => match cmd {
                 ListenerCmd::Http { cmd } => self.http_listener_command(cmd),
                 ListenerCmd::Https { cmd } => self.https_listener_command(cmd),
                 ListenerCmd::Tcp { cmd } => self.tcp_listener_command(cmd),
                 ListenerCmd::List => self.list_listeners(),
+                ListenerCmd::Check { address } => check_listener(address),
             },
             SubCmd::Certificate { cmd } => match cmd {
                 CertificateCmd::Add {
@@ -159,16 +269,14 @@ impl CommandManager {
                     key,
                     address,
                     tls_versions,
-                } => self.add_certificate(address.into(), &certificate, &chain, &key, tls_versions),
+                } => self.add_certificate(address, &certificate, &chain, &key, tls_versions),
                 CertificateCmd::Remove {
                     certificate,
                     address,
                     fingerprint,
-                } => self.remove_certificate(
-                    address.into(),
-                    certificate.as_deref(),
-                    fingerprint.as_deref(),
-                ),
+                } => {
+                    self.remove_certificate(address, certificate.as_deref(), fingerprint.as_deref())
+                }
                 CertificateCmd::Replace {
                     certificate,
                     chain,
@@ -178,7 +286,7 @@ impl CommandManager {
                     old_fingerprint,
                     tls_versions,
                 } => self.replace_certificate(
-                    address.into(),
+                    address,
                     &certificate,
                     &chain,
                     &key,
@@ -191,9 +299,15 @@ impl CommandManager {
                     domain,
                     query_workers,
                 } => self.query_certificates(fingerprint, domain, query_workers),
+                CertificateCmd::Acme {
+                    cmd: AcmeCmd::Order { domain },
+                } => self.order_acme_certificate(domain),
             },
             SubCmd::Config { cmd: _ } => Ok(()), // noop, handled at the beginning of the method
-            SubCmd::Events => self.events(),
+            SubCmd::Events { cmd } => self.events(cmd),
+            SubCmd::Schedule { cmd } => self.schedule_command(cmd),
+            SubCmd::Debug { cmd } => self.debug_command(cmd),
+            SubCmd::SigningKey { cmd } => self.signing_key_command(cmd),
             rest => {
                 panic!("that command should have been handled earlier: {rest:x?}")
             }
@@ -202,18 +316,140 @@ impl CommandManager {
 }
 
 /// creates a blocking channel
-pub fn create_channel(config: &Config) -> Result<Channel<Request, Response>, CtlError> {
+/// Bind and release a listener address, to find out if a listener could use it
+fn check_listener(address: SocketAddr) -> Result<(), CtlError> {
+    check_listener_address(address).map_err(CtlError::CheckListener)?;
+    println!("Address {address} is available for a listener");
+    Ok(())
+}
+
+/// the delay between two connection attempts stops doubling at this value
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How the command line retries to connect to the command socket, while the
+/// main process starts or upgrades
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectRetry {
+    /// retries after the first attempt, unlimited if None
+    retries: Option<u32>,
+    /// delay before the first retry, doubled after each retry
+    backoff: Duration,
+    /// no attempt is made past this instant
+    deadline: Option<Instant>,
+}
+
+impl ConnectRetry {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            retries: Some(config.ctl_connect_retries),
+            backoff: Duration::from_millis(config.ctl_connect_backoff),
+            deadline: None,
+        }
+    }
+
+    /// a single attempt
+    pub fn once() -> Self {
+        Self {
+            retries: Some(0),
+            backoff: Duration::ZERO,
+            deadline: None,
+        }
+    }
+
+    /// retry until the main process is up, or until the deadline
+    pub fn until(self, deadline: Option<Instant>) -> Self {
+        Self {
+            retries: None,
+            // waiting without delay would spin on the socket
+            backoff: self.backoff.max(Duration::from_millis(10)),
+            deadline,
+        }
+    }
+
+    /// the delay before the next attempt, None to give up
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if self.retries.is_some_and(|retries| attempt >= retries) {
+            return None;
+        }
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_CONNECT_BACKOFF);
+        match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                (!remaining.is_zero()).then(|| delay.min(remaining))
+            }
+            None => Some(delay),
+        }
+    }
+}
+
+pub fn create_channel(
+    config: &Config,
+    retry: ConnectRetry,
+) -> Result<Channel<Request, Response>, CtlError> {
     let command_socket_path = &config
         .command_socket_path()
         .map_err(CtlError::GetCommandSocketPath)?;
 
-    let mut channel = Channel::from_path(
-        command_socket_path,
-        config.command_buffer_size,
-        config.max_command_buffer_size,
-    )
-    .map_err(CtlError::CreateChannel)?;
+    let mut attempt = 0;
+    let mut channel = loop {
+        match Channel::from_path(
+            command_socket_path,
+            config.command_buffer_size,
+            config.max_command_buffer_size,
+        ) {
+            Ok(channel) => break channel,
+            Err(error) => match retry.next_delay(attempt) {
+                Some(delay) => {
+                    debug!(
+                        "could not connect to {}: {}, retrying in {:?}",
+                        command_socket_path, error, delay
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                None => return Err(CtlError::CreateChannel(error)),
+            },
+        }
+    };
 
     channel.blocking().map_err(CtlError::BlockChannel)?;
     Ok(channel)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_retries_back_off_until_the_limit() {
+        let retry = ConnectRetry {
+            retries: Some(5),
+            backoff: Duration::from_millis(500),
+            deadline: None,
+        };
+        assert_eq!(retry.next_delay(0), Some(Duration::from_millis(500)));
+        assert_eq!(retry.next_delay(1), Some(Duration::from_millis(1000)));
+        assert_eq!(retry.next_delay(2), Some(MAX_CONNECT_BACKOFF));
+        assert_eq!(retry.next_delay(4), Some(MAX_CONNECT_BACKOFF));
+        assert_eq!(retry.next_delay(5), None);
+
+        assert_eq!(ConnectRetry::once().next_delay(0), None);
+    }
+
+    #[test]
+    fn waiting_for_the_main_process_stops_at_the_deadline() {
+        let retry = ConnectRetry::once().until(None);
+        assert_eq!(retry.next_delay(1000), Some(MAX_CONNECT_BACKOFF));
+
+        let retry = ConnectRetry::once().until(Some(Instant::now() + Duration::from_millis(50)));
+        assert!(retry
+            .next_delay(1000)
+            .is_some_and(|delay| delay <= Duration::from_millis(50)));
+
+        let retry = ConnectRetry::once().until(Some(Instant::now()));
+        assert_eq!(retry.next_delay(0), None);
+    }
+}
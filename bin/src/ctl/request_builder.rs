@@ -1,27 +1,48 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use sozu_command_lib::{
     certificate::{
         decode_fingerprint, get_fingerprint_from_certificate_path, load_full_certificate,
     },
-    config::ListenerBuilder,
+    config::{
+        read_http_answer_file, BackendPinningConfig, ClientAuthenticationConfig, HealthCheckConfig,
+        Http10Config, HttpsPolicyConfig, ListenerBuilder, RequestMirrorConfig,
+        RequestRateLimitConfig, SlowLogConfig,
+    },
     proto::command::{
-        request::RequestType, ActivateListener, AddBackend, AddCertificate, Cluster, CountRequests,
-        DeactivateListener, FrontendFilters, HardStop, ListListeners, ListenerType,
-        LoadBalancingParams, MetricsConfiguration, PathRule, ProxyProtocolConfig,
-        QueryCertificatesFilters, QueryClusterByDomain, QueryClustersHashes, RemoveBackend,
-        RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
-        RequestTcpFrontend, RulePosition, SocketAddress, SoftStop, Status, SubscribeEvents,
-        TlsVersion,
+        request::RequestType, response_content::ContentType, AcmeOrder, ActivateListener,
+        AddBackend, AddCertificate, ApplyState, AuditSessions, ClientAuthenticationMode,
+        ClientRateLimit, Cluster, CollectCapture, CountRequests, CustomHttpAnswers,
+        DeactivateListener, DeviceClass, DeviceMatch, DrainBackend, FaultInjection,
+        FrontendFilters, GetChanges, HardStop, HeaderEdit, HeaderEditKind, HeaderPosition,
+        ListListeners, ListScheduledChanges, ListenerType, LoadBalancingParams,
+        MetricsConfiguration, OutlierDetection, PathRule, ProxyProtocolConfig, QueryBuildInfo,
+        QueryCertificatesFilters, QueryClusterByDomain, QueryClustersHashes, QueryEvents,
+        QueryHealthChecks, QueryState, RemoveBackend, RemoveCertificate, RemoveListener,
+        ReplaceBackends, ReplaceCertificate, Request, RequestHttpFrontend, RequestMirror,
+        RequestPipeline, RequestTcpFrontend, ResponseContent, RotateSigningKey, RulePosition,
+        ScheduledChange, SetBackendHealthOverride, SetBackendWeight, SetFaultInjection,
+        SetLoadBalancing, SetLogging, SetRequestPipeline, SigningKey, SoftStop, StartCapture,
+        Status, SubscribeEvents, Timeouts, TlsVersion, UpdateListenerAnswers,
     },
+    state::ConfigState,
 };
 
 use crate::{
     cli::{
-        BackendCmd, ClusterCmd, HttpFrontendCmd, HttpListenerCmd, HttpsListenerCmd, MetricsCmd,
-        TcpFrontendCmd, TcpListenerCmd,
+        BackendCmd, ClusterCmd, DebugCmd, EventsCmd, ExportFormat, HttpFrontendCmd,
+        HttpListenerCmd, HttpsListenerCmd, ListenerRef, LoggingCmd, MetricsCmd, ScheduleCmd,
+        SigningKeyCmd, TcpFrontendCmd, TcpListenerCmd,
+    },
+    ctl::{
+        export::{deserialize_state, export_state},
+        CommandManager,
     },
-    ctl::CommandManager,
 };
 
 use super::CtlError;
@@ -43,6 +64,63 @@ impl CommandManager {
         self.send_request(RequestType::CountRequests(CountRequests {}).into())
     }
 
+    pub fn get_changes(&mut self, since_epoch: u64) -> Result<(), CtlError> {
+        self.send_request(RequestType::GetChanges(GetChanges { since_epoch }).into())
+    }
+
+    pub fn query_state(&mut self, query: String) -> Result<(), CtlError> {
+        self.send_request(RequestType::QueryState(QueryState { query }).into())
+    }
+
+    pub fn export_state(
+        &mut self,
+        format: ExportFormat,
+        output: Option<String>,
+    ) -> Result<(), CtlError> {
+        // an epoch past the current one is answered with a snapshot of the whole state
+        let response = self.send_request_get_response(
+            RequestType::GetChanges(GetChanges {
+                since_epoch: u64::MAX,
+            })
+            .into(),
+            true,
+        )?;
+        let snapshot = match &response.content {
+            Some(ResponseContent {
+                content_type: Some(ContentType::StateChanges(changes)),
+            }) if changes.is_snapshot => &changes.snapshot,
+            _ => return Err(CtlError::WrongResponse(response)),
+        };
+        export_state(snapshot, format, output).map_err(CtlError::Export)
+    }
+
+    pub fn apply_state(&mut self, path: String) -> Result<(), CtlError> {
+        debug!("Applying the desired state of file {}", path);
+
+        let desired = read_state_file(&path, ExportFormat::Json)?;
+        self.converge_to_state(desired)
+    }
+
+    pub fn import_state(&mut self, path: String, format: ExportFormat) -> Result<(), CtlError> {
+        debug!("Importing the state of file {}", path);
+
+        let state = read_state_file(&path, format)?;
+        self.converge_to_state(state)
+    }
+
+    /// send the requests recreating the state, the main process applies
+    /// only those that differ from its own
+    fn converge_to_state(&mut self, state: ConfigState) -> Result<(), CtlError> {
+        let requests = state
+            .produce_initial_state()
+            .requests
+            .into_iter()
+            .map(|request| request.content)
+            .collect();
+
+        self.send_request(RequestType::ApplyState(ApplyState { requests }).into())
+    }
+
     pub fn soft_stop(&mut self) -> Result<(), CtlError> {
         debug!("shutting down proxy softly");
 
@@ -55,10 +133,14 @@ impl CommandManager {
         self.send_request(RequestType::HardStop(HardStop {}).into())
     }
 
-    pub fn status(&mut self) -> Result<(), CtlError> {
+    pub fn status(&mut self, verbose: bool) -> Result<(), CtlError> {
         debug!("Requesting status…");
 
-        self.send_request(RequestType::Status(Status {}).into())
+        self.send_request(RequestType::Status(Status {}).into())?;
+        if verbose {
+            self.send_request(RequestType::QueryBuildInfo(QueryBuildInfo {}).into())?;
+        }
+        Ok(())
     }
 
     pub fn configure_metrics(&mut self, cmd: MetricsCmd) -> Result<(), CtlError> {
@@ -103,8 +185,41 @@ impl CommandManager {
         )
     }
 
-    pub fn events(&mut self) -> Result<(), CtlError> {
-        self.send_request_no_timeout(RequestType::SubscribeEvents(SubscribeEvents {}).into())
+    /// the timeouts of the frontends, queried from the state of the main process
+    pub fn frontend_timeouts(&mut self, domain: Option<String>) -> Result<(), CtlError> {
+        let mut query = String::from("SELECT * FROM timeouts");
+        if let Some(domain) = domain {
+            query.push_str(&format!(
+                " WHERE hostname = '{}'",
+                domain.replace('\'', "''")
+            ));
+        }
+        self.query_state(query)
+    }
+
+    pub fn events(&mut self, cmd: Option<EventsCmd>) -> Result<(), CtlError> {
+        match cmd {
+            None | Some(EventsCmd::Watch) => self
+                .send_request_no_timeout(RequestType::SubscribeEvents(SubscribeEvents {}).into()),
+            Some(EventsCmd::List {
+                since,
+                cluster_id,
+                backend_id,
+            }) => self.send_request(
+                RequestType::QueryEvents(QueryEvents {
+                    since: since.map(|since| {
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .saturating_sub(since)
+                            .as_secs()
+                    }),
+                    cluster_id,
+                    backend_id,
+                })
+                .into(),
+            ),
+        }
     }
 
     pub fn backend_command(&mut self, cmd: BackendCmd) -> Result<(), CtlError> {
@@ -115,14 +230,21 @@ impl CommandManager {
                 address,
                 sticky_id,
                 backup,
+                expires_in,
+                slow_start_seconds,
             } => self.send_request(
                 RequestType::AddBackend(AddBackend {
                     cluster_id: id,
                     address: address.into(),
                     backend_id,
-                    load_balancing_parameters: Some(LoadBalancingParams::default()),
+                    load_balancing_parameters: Some(LoadBalancingParams {
+                        slow_start_seconds,
+                        ..Default::default()
+                    }),
                     sticky_id,
                     backup,
+                    expires_at: expiration_date(expires_in),
+                    draining: None,
                 })
                 .into(),
             ),
@@ -138,6 +260,73 @@ impl CommandManager {
                 })
                 .into(),
             ),
+            BackendCmd::Replace { id, backends } => self.send_request(
+                RequestType::ReplaceBackends(ReplaceBackends {
+                    backends: backends
+                        .into_iter()
+                        .map(|(backend_id, address)| AddBackend {
+                            cluster_id: id.clone(),
+                            address: address.into(),
+                            backend_id,
+                            load_balancing_parameters: Some(LoadBalancingParams::default()),
+                            sticky_id: None,
+                            backup: None,
+                            expires_at: None,
+                            draining: None,
+                        })
+                        .collect(),
+                    cluster_id: id,
+                })
+                .into(),
+            ),
+            BackendCmd::SetWeight {
+                id,
+                backend_id,
+                weight,
+            } => self.send_request(
+                RequestType::SetBackendWeight(SetBackendWeight {
+                    cluster_id: id,
+                    backend_id,
+                    weight,
+                })
+                .into(),
+            ),
+            BackendCmd::Drain { id, backend_id } => {
+                let request = RequestType::DrainBackend(DrainBackend {
+                    cluster_id: id,
+                    backend_id,
+                    wait: self.wait,
+                    undrain: None,
+                })
+                .into();
+                // the connections of the backend may take long to close
+                if self.wait {
+                    self.send_request_no_timeout(request)
+                } else {
+                    self.send_request(request)
+                }
+            }
+            BackendCmd::Undrain { id, backend_id } => self.send_request(
+                RequestType::DrainBackend(DrainBackend {
+                    cluster_id: id,
+                    backend_id,
+                    wait: false,
+                    undrain: Some(true),
+                })
+                .into(),
+            ),
+            BackendCmd::SetHealth {
+                id,
+                backend_id,
+                state,
+            } => self.send_request(
+                RequestType::SetBackendHealthOverride(SetBackendHealthOverride {
+                    cluster_id: id,
+                    backend_id,
+                    state: state.into(),
+                })
+                .into(),
+            ),
         }
     }
 
@@ -150,7 +339,76 @@ impl CommandManager {
                 send_proxy,
                 expect_proxy,
                 load_balancing_policy,
+                hash_key,
+                source_address,
+                transparent,
+                expires_in,
+                max_request_header_size,
+                max_response_body_size,
+                response_flush_delay,
+                filter_time_budget,
+                slow_log_threshold,
+                slow_log_file,
+                slow_log_socket,
+                slow_log_max_per_second,
+                client_rate_limit,
+                client_rate_limit_burst,
+                sticky_table,
+                srv_record,
+                dscp,
+                dscp_on_clients,
+                outlier_detection,
+                health_check,
+                health_check_interval,
+                health_check_timeout,
+                health_check_path,
+                healthy_threshold,
+                unhealthy_threshold,
+                enforce_https,
+                hsts_max_age,
+                hsts_include_subdomains,
+                hsts_preload,
+                body_read_timeout,
+                backend_connect_timeout,
+                backend_response_timeout,
+                request_deadline,
             } => {
+                let https_policy = enforce_https
+                    .then(|| {
+                        HttpsPolicyConfig {
+                            max_age: hsts_max_age,
+                            include_subdomains: Some(hsts_include_subdomains),
+                            preload: Some(hsts_preload),
+                        }
+                        .to_https_policy(&id)
+                        .map_err(CtlError::HttpsPolicy)
+                    })
+                    .transpose()?;
+                let health_check = health_check
+                    .then(|| {
+                        HealthCheckConfig {
+                            interval: health_check_interval,
+                            timeout: health_check_timeout,
+                            http_path: health_check_path,
+                            healthy_threshold,
+                            unhealthy_threshold,
+                        }
+                        .to_health_check(&id)
+                        .map_err(CtlError::HealthCheck)
+                    })
+                    .transpose()?;
+                let slow_log = slow_log_threshold
+                    .map(|threshold| {
+                        SlowLogConfig {
+                            threshold,
+                            file: slow_log_file,
+                            unix_socket: slow_log_socket,
+                            max_per_second: slow_log_max_per_second,
+                        }
+                        .to_slow_log(&id)
+                        .map_err(CtlError::SlowLog)
+                    })
+                    .transpose()?;
                 let proxy_protocol = match (send_proxy, expect_proxy) {
                     (true, true) => Some(ProxyProtocolConfig::RelayHeader),
                     (true, false) => Some(ProxyProtocolConfig::SendHeader),
@@ -164,12 +422,83 @@ impl CommandManager {
                         https_redirect,
                         proxy_protocol: proxy_protocol.map(|pp| pp as i32),
                         load_balancing: load_balancing_policy as i32,
+                        hash_key,
+                        source_address: source_address.map(Into::into),
+                        transparent,
+                        expires_at: expiration_date(expires_in),
+                        max_request_header_size,
+                        max_response_body_size,
+                        response_flush_delay,
+                        filter_time_budget,
+                        sticky_table,
+                        backend_srv_record: srv_record,
+                        dscp: dscp.map(u32::from),
+                        dscp_on_clients,
+                        outlier_detection: outlier_detection.then(OutlierDetection::default),
+                        https_policy,
+                        health_check,
+                        slow_log,
+                        rate_limit: token_bucket(client_rate_limit, client_rate_limit_burst),
+                        timeouts: timeouts(
+                            body_read_timeout,
+                            backend_connect_timeout,
+                            backend_response_timeout,
+                            request_deadline,
+                        ),
                         ..Default::default()
                     })
                     .into(),
                 )
             }
             ClusterCmd::Remove { id } => self.send_request(RequestType::RemoveCluster(id).into()),
+            ClusterCmd::Inspect { id } => {
+                self.send_request(RequestType::QueryClusterById(id).into())
+            }
+            ClusterCmd::Healthcheck { id } => self.send_request(
+                RequestType::QueryHealthChecks(QueryHealthChecks { cluster_id: id }).into(),
+            ),
+            ClusterCmd::Pipeline { id, steps, reset } => self.send_request(
+                RequestType::SetRequestPipeline(SetRequestPipeline {
+                    cluster_id: id,
+                    pipeline: (!reset).then_some(RequestPipeline { steps }),
+                })
+                .into(),
+            ),
+            ClusterCmd::SetLoadBalancing {
+                id,
+                load_balancing_policy,
+                load_metric,
+                sticky_session,
+                sticky_table,
+                hash_key,
+            } => self.send_request(
+                RequestType::SetLoadBalancing(SetLoadBalancing {
+                    cluster_id: id,
+                    load_balancing: load_balancing_policy.map(|policy| policy as i32),
+                    load_metric: load_metric.map(|metric| metric as i32),
+                    sticky_session,
+                    sticky_table,
+                    hash_key,
+                })
+                .into(),
+            ),
+            ClusterCmd::FaultInjection {
+                id,
+                delay,
+                delay_percent,
+                abort_percent,
+                disable,
+            } => self.send_request(
+                RequestType::SetFaultInjection(SetFaultInjection {
+                    cluster_id: id,
+                    fault_injection: (!disable).then(|| FaultInjection {
+                        delay: delay.unwrap_or_default(),
+                        delay_percent: delay_percent.unwrap_or_default(),
+                        abort_percent: abort_percent.unwrap_or_default(),
+                    }),
+                })
+                .into(),
+            ),
             ClusterCmd::List {
                 id: cluster_id,
                 domain,
@@ -208,22 +537,37 @@ impl CommandManager {
 
     pub fn tcp_frontend_command(&mut self, cmd: TcpFrontendCmd) -> Result<(), CtlError> {
         match cmd {
-            TcpFrontendCmd::Add { id, address, tags } => self.send_request(
-                RequestType::AddTcpFrontend(RequestTcpFrontend {
-                    cluster_id: id,
-                    address: address.into(),
-                    tags: tags.unwrap_or(BTreeMap::new()),
-                })
-                .into(),
-            ),
-            TcpFrontendCmd::Remove { id, address } => self.send_request(
-                RequestType::RemoveTcpFrontend(RequestTcpFrontend {
-                    cluster_id: id,
-                    address: address.into(),
-                    ..Default::default()
-                })
-                .into(),
-            ),
+            TcpFrontendCmd::Add {
+                id,
+                address,
+                tags,
+                expires_in,
+                sni,
+            } => {
+                let address = self.listener_address(address)?;
+                self.send_request(
+                    RequestType::AddTcpFrontend(RequestTcpFrontend {
+                        cluster_id: id,
+                        address: address.into(),
+                        tags: tags.unwrap_or(BTreeMap::new()),
+                        expires_at: expiration_date(expires_in),
+                        sni,
+                    })
+                    .into(),
+                )
+            }
+            TcpFrontendCmd::Remove { id, address, sni } => {
+                let address = self.listener_address(address)?;
+                self.send_request(
+                    RequestType::RemoveTcpFrontend(RequestTcpFrontend {
+                        cluster_id: id,
+                        address: address.into(),
+                        sni,
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+            }
         }
     }
 
@@ -238,21 +582,83 @@ impl CommandManager {
                 method,
                 cluster_id: route,
                 tags,
-            } => self.send_request(
-                RequestType::AddHttpFrontend(RequestHttpFrontend {
-                    cluster_id: route.into(),
-                    address: address.into(),
-                    hostname,
-                    path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
-                    method: method.map(String::from),
-                    position: RulePosition::Tree.into(),
-                    tags: match tags {
-                        Some(tags) => tags,
-                        None => BTreeMap::new(),
-                    },
-                })
-                .into(),
-            ),
+                expires_in,
+                client_tls_versions,
+                client_cipher_suites,
+                device,
+                mobile_user_agents,
+                client_rate_limit,
+                client_rate_limit_burst,
+                body_read_timeout,
+                backend_connect_timeout,
+                backend_response_timeout,
+                request_deadline,
+                mirror_file,
+                mirror_socket,
+                mirror_sample_one_in,
+                mirror_max_per_second,
+                mirror_body_prefix,
+                split,
+                add_request_headers,
+                set_request_headers,
+                remove_request_headers,
+                add_response_headers,
+                set_response_headers,
+                remove_response_headers,
+            } => {
+                let address = self.listener_address(address)?;
+                self.send_request(
+                    RequestType::AddHttpFrontend(RequestHttpFrontend {
+                        cluster_id: route.into(),
+                        address: address.into(),
+                        hostname,
+                        path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
+                        method: method.map(String::from),
+                        position: RulePosition::Tree.into(),
+                        tags: match tags {
+                            Some(tags) => tags,
+                            None => BTreeMap::new(),
+                        },
+                        expires_at: expiration_date(expires_in),
+                        client_tls_versions: client_tls_versions
+                            .into_iter()
+                            .map(|version| version as i32)
+                            .collect(),
+                        client_cipher_suites,
+                        timeouts: timeouts(
+                            body_read_timeout,
+                            backend_connect_timeout,
+                            backend_response_timeout,
+                            request_deadline,
+                        ),
+                        mirror: request_mirror(RequestMirrorConfig {
+                            file: mirror_file,
+                            unix_socket: mirror_socket,
+                            sample_one_in: mirror_sample_one_in,
+                            max_per_second: mirror_max_per_second,
+                            max_body_prefix: mirror_body_prefix,
+                        })?,
+                        split,
+                        headers: header_edits(
+                            HeaderPosition::Request,
+                            remove_request_headers,
+                            set_request_headers,
+                            add_request_headers,
+                        )
+                        .into_iter()
+                        .chain(header_edits(
+                            HeaderPosition::Response,
+                            remove_response_headers,
+                            set_response_headers,
+                            add_response_headers,
+                        ))
+                        .collect(),
+                        device: device_match(device, mobile_user_agents),
+                        rate_limit: token_bucket(client_rate_limit, client_rate_limit_burst),
+                    })
+                    .into(),
+                )
+            }
             HttpFrontendCmd::Remove {
                 hostname,
                 path_prefix,
@@ -261,17 +667,30 @@ impl CommandManager {
                 address,
                 method,
                 cluster_id: route,
-            } => self.send_request(
-                RequestType::RemoveHttpFrontend(RequestHttpFrontend {
-                    cluster_id: route.into(),
-                    address: address.into(),
-                    hostname,
-                    path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
-                    method: method.map(String::from),
-                    ..Default::default()
-                })
-                .into(),
-            ),
+                client_tls_versions,
+                client_cipher_suites,
+                device,
+                mobile_user_agents,
+            } => {
+                let address = self.listener_address(address)?;
+                self.send_request(
+                    RequestType::RemoveHttpFrontend(RequestHttpFrontend {
+                        cluster_id: route.into(),
+                        address: address.into(),
+                        hostname,
+                        path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
+                        method: method.map(String::from),
+                        client_tls_versions: client_tls_versions
+                            .into_iter()
+                            .map(|version| version as i32)
+                            .collect(),
+                        client_cipher_suites,
+                        device: device_match(device, mobile_user_agents),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+            }
         }
     }
 
@@ -286,21 +705,83 @@ impl CommandManager {
                 method,
                 cluster_id: route,
                 tags,
-            } => self.send_request(
-                RequestType::AddHttpsFrontend(RequestHttpFrontend {
-                    cluster_id: route.into(),
-                    address: address.into(),
-                    hostname,
-                    path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
-                    method: method.map(String::from),
-                    position: RulePosition::Tree.into(),
-                    tags: match tags {
-                        Some(tags) => tags,
-                        None => BTreeMap::new(),
-                    },
-                })
-                .into(),
-            ),
+                expires_in,
+                client_tls_versions,
+                client_cipher_suites,
+                device,
+                mobile_user_agents,
+                client_rate_limit,
+                client_rate_limit_burst,
+                body_read_timeout,
+                backend_connect_timeout,
+                backend_response_timeout,
+                request_deadline,
+                mirror_file,
+                mirror_socket,
+                mirror_sample_one_in,
+                mirror_max_per_second,
+                mirror_body_prefix,
+                split,
+                add_request_headers,
+                set_request_headers,
+                remove_request_headers,
+                add_response_headers,
+                set_response_headers,
+                remove_response_headers,
+            } => {
+                let address = self.listener_address(address)?;
+                self.send_request(
+                    RequestType::AddHttpsFrontend(RequestHttpFrontend {
+                        cluster_id: route.into(),
+                        address: address.into(),
+                        hostname,
+                        path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
+                        method: method.map(String::from),
+                        position: RulePosition::Tree.into(),
+                        tags: match tags {
+                            Some(tags) => tags,
+                            None => BTreeMap::new(),
+                        },
+                        expires_at: expiration_date(expires_in),
+                        client_tls_versions: client_tls_versions
+                            .into_iter()
+                            .map(|version| version as i32)
+                            .collect(),
+                        client_cipher_suites,
+                        timeouts: timeouts(
+                            body_read_timeout,
+                            backend_connect_timeout,
+                            backend_response_timeout,
+                            request_deadline,
+                        ),
+                        mirror: request_mirror(RequestMirrorConfig {
+                            file: mirror_file,
+                            unix_socket: mirror_socket,
+                            sample_one_in: mirror_sample_one_in,
+                            max_per_second: mirror_max_per_second,
+                            max_body_prefix: mirror_body_prefix,
+                        })?,
+                        split,
+                        headers: header_edits(
+                            HeaderPosition::Request,
+                            remove_request_headers,
+                            set_request_headers,
+                            add_request_headers,
+                        )
+                        .into_iter()
+                        .chain(header_edits(
+                            HeaderPosition::Response,
+                            remove_response_headers,
+                            set_response_headers,
+                            add_response_headers,
+                        ))
+                        .collect(),
+                        device: device_match(device, mobile_user_agents),
+                        rate_limit: token_bucket(client_rate_limit, client_rate_limit_burst),
+                    })
+                    .into(),
+                )
+            }
             HttpFrontendCmd::Remove {
                 hostname,
                 path_prefix,
@@ -309,17 +790,30 @@ impl CommandManager {
                 address,
                 method,
                 cluster_id: route,
-            } => self.send_request(
-                RequestType::RemoveHttpsFrontend(RequestHttpFrontend {
-                    cluster_id: route.into(),
-                    address: address.into(),
-                    hostname,
-                    path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
-                    method: method.map(String::from),
-                    ..Default::default()
-                })
-                .into(),
-            ),
+                client_tls_versions,
+                client_cipher_suites,
+                device,
+                mobile_user_agents,
+            } => {
+                let address = self.listener_address(address)?;
+                self.send_request(
+                    RequestType::RemoveHttpsFrontend(RequestHttpFrontend {
+                        cluster_id: route.into(),
+                        address: address.into(),
+                        hostname,
+                        path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
+                        method: method.map(String::from),
+                        client_tls_versions: client_tls_versions
+                            .into_iter()
+                            .map(|version| version as i32)
+                            .collect(),
+                        client_cipher_suites,
+                        device: device_match(device, mobile_user_agents),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+            }
         }
     }
 
@@ -328,43 +822,112 @@ impl CommandManager {
             HttpsListenerCmd::Add {
                 address,
                 public_address,
+                name,
                 answer_404,
                 answer_503,
                 tls_versions,
                 cipher_list,
                 expect_proxy,
+                detect_proxy,
+                ipv6_only,
+                normalize_ipv4_mapped,
+                early_data,
+                http2,
                 sticky_name,
                 front_timeout,
                 back_timeout,
                 request_timeout,
                 connect_timeout,
+                handshake_timeout,
+                proxy_status,
+                rate_limit,
+                rate_limit_window,
+                rate_limit_exempt,
+                backend_pinning_from,
+                backend_pinning_header,
+                http10_keep_open,
+                http10_no_keep_alive,
+                http10_default_host,
+                duplicate_headers,
+                client_ca,
+                client_crl,
+                client_certificate_optional,
+                unknown_sni_cluster,
+                request_deadline,
+                websocket_idle_timeout,
             } => {
                 let https_listener = ListenerBuilder::new_https(address.into())
+                    .with_name(name)
                     .with_public_address(public_address)
                     .with_answer_404_path(answer_404)
                     .with_answer_503_path(answer_503)
                     .with_tls_versions(tls_versions)
                     .with_cipher_list(cipher_list)
                     .with_expect_proxy(expect_proxy)
+                    .with_detect_proxy(detect_proxy)
+                    .with_early_data(early_data)
+                    .with_http2(http2)
+                    .with_ipv6_only(ipv6_only)
+                    .with_normalize_ipv4_mapped(normalize_ipv4_mapped)
                     .with_sticky_name(sticky_name)
                     .with_front_timeout(front_timeout)
                     .with_back_timeout(back_timeout)
                     .with_request_timeout(request_timeout)
                     .with_connect_timeout(connect_timeout)
+                    .with_handshake_timeout(handshake_timeout)
+                    .with_proxy_status(proxy_status)
+                    .with_request_rate_limit(rate_limit.map(|requests| RequestRateLimitConfig {
+                        requests,
+                        window: rate_limit_window,
+                        exempt: rate_limit_exempt,
+                    }))
+                    .with_backend_pinning(backend_pinning_config(
+                        backend_pinning_from,
+                        backend_pinning_header,
+                    ))
+                    .with_http10(http10_config(
+                        http10_keep_open,
+                        http10_no_keep_alive,
+                        http10_default_host,
+                    ))
+                    .with_duplicate_headers(
+                        (!duplicate_headers.is_empty())
+                            .then(|| duplicate_headers.into_iter().collect()),
+                    )
+                    .with_client_authentication(client_ca.map(|ca| {
+                        ClientAuthenticationConfig {
+                            mode: client_certificate_optional
+                                .then_some(ClientAuthenticationMode::Optional),
+                            ca,
+                            crl: client_crl,
+                            subject_header: None,
+                            issuer_header: None,
+                            serial_header: None,
+                            verify_header: None,
+                        }
+                    }))
+                    .with_unknown_sni_cluster(unknown_sni_cluster)
+                    .with_request_deadline(request_deadline)
+                    .with_websocket_idle_timeout(websocket_idle_timeout)
                     .to_tls(Some(&self.config))
                     .map_err(CtlError::CreateListener)?;
 
                 self.send_request(RequestType::AddHttpsListener(https_listener).into())
             }
             HttpsListenerCmd::Remove { address } => {
-                self.remove_listener(address.into(), ListenerType::Https)
+                self.remove_listener(address, ListenerType::Https)
             }
             HttpsListenerCmd::Activate { address } => {
-                self.activate_listener(address.into(), ListenerType::Https)
+                self.activate_listener(address, ListenerType::Https)
             }
             HttpsListenerCmd::Deactivate { address } => {
-                self.deactivate_listener(address.into(), ListenerType::Https)
+                self.deactivate_listener(address, ListenerType::Https)
             }
+            HttpsListenerCmd::UpdateAnswers {
+                address,
+                answer_404,
+                answer_503,
+            } => self.update_listener_answers(address, ListenerType::Https, answer_404, answer_503),
         }
     }
 
@@ -373,39 +936,85 @@ impl CommandManager {
             HttpListenerCmd::Add {
                 address,
                 public_address,
+                name,
                 answer_404,
                 answer_503,
                 expect_proxy,
+                detect_proxy,
+                ipv6_only,
+                normalize_ipv4_mapped,
                 sticky_name,
                 front_timeout,
                 back_timeout,
                 request_timeout,
                 connect_timeout,
+                proxy_status,
+                rate_limit,
+                rate_limit_window,
+                rate_limit_exempt,
+                backend_pinning_from,
+                backend_pinning_header,
+                http10_keep_open,
+                http10_no_keep_alive,
+                http10_default_host,
+                duplicate_headers,
+                request_deadline,
+                websocket_idle_timeout,
             } => {
                 let http_listener = ListenerBuilder::new_http(address.into())
+                    .with_name(name)
                     .with_public_address(public_address)
                     .with_answer_404_path(answer_404)
                     .with_answer_503_path(answer_503)
                     .with_expect_proxy(expect_proxy)
+                    .with_detect_proxy(detect_proxy)
+                    .with_ipv6_only(ipv6_only)
+                    .with_normalize_ipv4_mapped(normalize_ipv4_mapped)
                     .with_sticky_name(sticky_name)
                     .with_front_timeout(front_timeout)
                     .with_request_timeout(request_timeout)
                     .with_back_timeout(back_timeout)
                     .with_connect_timeout(connect_timeout)
+                    .with_proxy_status(proxy_status)
+                    .with_request_rate_limit(rate_limit.map(|requests| RequestRateLimitConfig {
+                        requests,
+                        window: rate_limit_window,
+                        exempt: rate_limit_exempt,
+                    }))
+                    .with_backend_pinning(backend_pinning_config(
+                        backend_pinning_from,
+                        backend_pinning_header,
+                    ))
+                    .with_http10(http10_config(
+                        http10_keep_open,
+                        http10_no_keep_alive,
+                        http10_default_host,
+                    ))
+                    .with_duplicate_headers(
+                        (!duplicate_headers.is_empty())
+                            .then(|| duplicate_headers.into_iter().collect()),
+                    )
+                    .with_request_deadline(request_deadline)
+                    .with_websocket_idle_timeout(websocket_idle_timeout)
                     .to_http(Some(&self.config))
                     .map_err(CtlError::CreateListener)?;
 
                 self.send_request(RequestType::AddHttpListener(http_listener).into())
             }
             HttpListenerCmd::Remove { address } => {
-                self.remove_listener(address.into(), ListenerType::Http)
+                self.remove_listener(address, ListenerType::Http)
             }
             HttpListenerCmd::Activate { address } => {
-                self.activate_listener(address.into(), ListenerType::Http)
+                self.activate_listener(address, ListenerType::Http)
             }
             HttpListenerCmd::Deactivate { address } => {
-                self.deactivate_listener(address.into(), ListenerType::Http)
+                self.deactivate_listener(address, ListenerType::Http)
             }
+            HttpListenerCmd::UpdateAnswers {
+                address,
+                answer_404,
+                answer_503,
+            } => self.update_listener_answers(address, ListenerType::Http, answer_404, answer_503),
         }
     }
 
@@ -414,24 +1023,28 @@ impl CommandManager {
             TcpListenerCmd::Add {
                 address,
                 public_address,
+                name,
                 expect_proxy,
+                ipv6_only,
+                normalize_ipv4_mapped,
             } => {
                 let listener = ListenerBuilder::new_tcp(address.into())
+                    .with_name(name)
                     .with_public_address(public_address)
                     .with_expect_proxy(expect_proxy)
+                    .with_ipv6_only(ipv6_only)
+                    .with_normalize_ipv4_mapped(normalize_ipv4_mapped)
                     .to_tcp(Some(&self.config))
                     .map_err(CtlError::CreateListener)?;
 
                 self.send_request(RequestType::AddTcpListener(listener).into())
             }
-            TcpListenerCmd::Remove { address } => {
-                self.remove_listener(address.into(), ListenerType::Tcp)
-            }
+            TcpListenerCmd::Remove { address } => self.remove_listener(address, ListenerType::Tcp),
             TcpListenerCmd::Activate { address } => {
-                self.activate_listener(address.into(), ListenerType::Tcp)
+                self.activate_listener(address, ListenerType::Tcp)
             }
             TcpListenerCmd::Deactivate { address } => {
-                self.deactivate_listener(address.into(), ListenerType::Tcp)
+                self.deactivate_listener(address, ListenerType::Tcp)
             }
         }
     }
@@ -440,11 +1053,38 @@ impl CommandManager {
         self.send_request(RequestType::ListListeners(ListListeners {}).into())
     }
 
+    /// the address of a listener given by the command line, asking the main
+    /// process for the listeners when it is given by its name
+    pub fn listener_address(&mut self, listener: ListenerRef) -> Result<SocketAddr, CtlError> {
+        let name = match listener {
+            ListenerRef::Address(address) => return Ok(address),
+            ListenerRef::Name(name) => name,
+        };
+
+        // the lookup does not change the state, it has to run even for a dry run
+        let dry_run = std::mem::take(&mut self.dry_run);
+        let response = self
+            .send_request_get_response(RequestType::ListListeners(ListListeners {}).into(), true);
+        self.dry_run = dry_run;
+        let response = response?;
+
+        let listeners = match &response.content {
+            Some(ResponseContent {
+                content_type: Some(ContentType::ListenersList(listeners)),
+            }) => listeners,
+            _ => return Err(CtlError::WrongResponse(response)),
+        };
+        listeners
+            .address_by_name(&name)
+            .ok_or(CtlError::UnknownListener(name))
+    }
+
     pub fn remove_listener(
         &mut self,
-        address: SocketAddress,
+        address: ListenerRef,
         listener_type: ListenerType,
     ) -> Result<(), CtlError> {
+        let address = self.listener_address(address)?.into();
         self.send_request(
             RequestType::RemoveListener(RemoveListener {
                 address,
@@ -456,9 +1096,10 @@ impl CommandManager {
 
     pub fn activate_listener(
         &mut self,
-        address: SocketAddress,
+        address: ListenerRef,
         listener_type: ListenerType,
     ) -> Result<(), CtlError> {
+        let address = self.listener_address(address)?.into();
         self.send_request(
             RequestType::ActivateListener(ActivateListener {
                 address,
@@ -469,11 +1110,36 @@ impl CommandManager {
         )
     }
 
+    pub fn update_listener_answers(
+        &mut self,
+        address: ListenerRef,
+        listener_type: ListenerType,
+        answer_404: Option<String>,
+        answer_503: Option<String>,
+    ) -> Result<(), CtlError> {
+        let address = self.listener_address(address)?.into();
+        let http_answers = CustomHttpAnswers {
+            answer_404: read_http_answer_file(&answer_404).map_err(CtlError::ReadAnswerFile)?,
+            answer_503: read_http_answer_file(&answer_503).map_err(CtlError::ReadAnswerFile)?,
+            ..Default::default()
+        };
+
+        self.send_request(
+            RequestType::UpdateListenerAnswers(UpdateListenerAnswers {
+                address,
+                proxy: listener_type.into(),
+                http_answers,
+            })
+            .into(),
+        )
+    }
+
     pub fn deactivate_listener(
         &mut self,
-        address: SocketAddress,
+        address: ListenerRef,
         listener_type: ListenerType,
     ) -> Result<(), CtlError> {
+        let address = self.listener_address(address)?.into();
         self.send_request(
             RequestType::DeactivateListener(DeactivateListener {
                 address,
@@ -484,18 +1150,50 @@ impl CommandManager {
         )
     }
 
-    pub fn logging_filter(&mut self, filter: String) -> Result<(), CtlError> {
-        self.send_request(RequestType::Logging(filter).into())
+    pub fn logging_command(
+        &mut self,
+        filter: Option<String>,
+        cmd: Option<LoggingCmd>,
+    ) -> Result<(), CtlError> {
+        let set = match (filter, cmd) {
+            (Some(filter), _) => return self.send_request(RequestType::Logging(filter).into()),
+            (
+                None,
+                Some(LoggingCmd::Set {
+                    level,
+                    target,
+                    worker_id,
+                }),
+            ) => SetLogging {
+                filter: level,
+                target,
+                worker_id,
+                reset: None,
+            },
+            (None, Some(LoggingCmd::Reset { worker_id })) => SetLogging {
+                worker_id,
+                reset: Some(true),
+                ..Default::default()
+            },
+            (None, None) => {
+                return Err(CtlError::ArgsNeeded(
+                    "a logging filter".to_string(),
+                    "a logging subcommand".to_string(),
+                ))
+            }
+        };
+        self.send_request(RequestType::SetLogging(set).into())
     }
 
     pub fn add_certificate(
         &mut self,
-        address: SocketAddress,
+        address: ListenerRef,
         certificate_path: &str,
         certificate_chain_path: &str,
         key_path: &str,
         versions: Vec<TlsVersion>,
     ) -> Result<(), CtlError> {
+        let address = self.listener_address(address)?.into();
         let new_certificate = load_full_certificate(
             certificate_path,
             certificate_chain_path,
@@ -518,7 +1216,7 @@ impl CommandManager {
     #[allow(clippy::too_many_arguments)]
     pub fn replace_certificate(
         &mut self,
-        address: SocketAddress,
+        address: ListenerRef,
         new_certificate_path: &str,
         new_certificate_chain_path: &str,
         new_key_path: &str,
@@ -526,6 +1224,7 @@ impl CommandManager {
         old_fingerprint: Option<&str>,
         versions: Vec<TlsVersion>,
     ) -> Result<(), CtlError> {
+        let address = self.listener_address(address)?.into();
         let old_fingerprint = match (old_certificate_path, old_fingerprint) {
             (None, None) | (Some(_), Some(_)) => {
                 return Err(CtlError::ArgsNeeded(
@@ -566,10 +1265,11 @@ impl CommandManager {
 
     pub fn remove_certificate(
         &mut self,
-        address: SocketAddress,
+        address: ListenerRef,
         certificate_path: Option<&str>,
         fingerprint: Option<&str>,
     ) -> Result<(), CtlError> {
+        let address = self.listener_address(address)?.into();
         let fingerprint = match (certificate_path, fingerprint) {
             (None, None) | (Some(_), Some(_)) => {
                 return Err(CtlError::ArgsNeeded(
@@ -595,6 +1295,11 @@ impl CommandManager {
         )
     }
 
+    /// the order may take a few minutes, the main process answers once it is over
+    pub fn order_acme_certificate(&mut self, domain: String) -> Result<(), CtlError> {
+        self.send_request_no_timeout(RequestType::AcmeOrder(AcmeOrder { domain }).into())
+    }
+
     pub fn query_certificates(
         &mut self,
         fingerprint: Option<String>,
@@ -606,15 +1311,247 @@ impl CommandManager {
             fingerprint,
         };
 
-        if query_workers {
+        // only the workers know which certificate is selected for a domain name
+        if query_workers || filters.domain.is_some() {
             self.send_request(RequestType::QueryCertificatesFromWorkers(filters).into())
         } else {
             self.send_request(RequestType::QueryCertificatesFromTheState(filters).into())
         }
     }
 
+    pub fn schedule_command(&mut self, cmd: ScheduleCmd) -> Result<(), CtlError> {
+        match cmd {
+            ScheduleCmd::Add {
+                id,
+                at,
+                delay,
+                every,
+                file,
+            } => {
+                let at = match (at, delay) {
+                    (Some(at), _) => at,
+                    (None, Some(delay)) => expiration_date(Some(delay)).unwrap_or_default(),
+                    (None, None) => {
+                        return Err(CtlError::ArgsNeeded("--at".to_owned(), "--in".to_owned()))
+                    }
+                };
+                let requests = std::fs::read_to_string(&file)
+                    .map_err(|e| e.to_string())
+                    .and_then(|content| {
+                        serde_json::from_str::<Vec<Request>>(&content).map_err(|e| e.to_string())
+                    })
+                    .map_err(|error| CtlError::ReadRequestsFile {
+                        path: file.clone(),
+                        error,
+                    })?;
+
+                self.send_request(
+                    RequestType::AddScheduledChange(ScheduledChange {
+                        id,
+                        at,
+                        every: every.map(|every| every.as_secs()),
+                        requests,
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+            }
+            ScheduleCmd::Remove { id } => {
+                self.send_request(RequestType::RemoveScheduledChange(id).into())
+            }
+            ScheduleCmd::List => {
+                self.send_request(RequestType::ListScheduledChanges(ListScheduledChanges {}).into())
+            }
+        }
+    }
+
+    pub fn signing_key_command(&mut self, cmd: SigningKeyCmd) -> Result<(), CtlError> {
+        match cmd {
+            SigningKeyCmd::Rotate { secret_file } => {
+                let secret = match secret_file {
+                    Some(path) => Some(
+                        SigningKey::from_file(&path)
+                            .map_err(CtlError::SigningKey)?
+                            .secret,
+                    ),
+                    None => None,
+                };
+                self.send_request(RequestType::RotateSigningKey(RotateSigningKey { secret }).into())
+            }
+        }
+    }
+
+    pub fn debug_command(&mut self, cmd: DebugCmd) -> Result<(), CtlError> {
+        match cmd {
+            DebugCmd::Capture {
+                cluster_id,
+                duration,
+                headers,
+                max_requests,
+                file,
+            } => {
+                self.send_request(
+                    RequestType::StartCapture(StartCapture {
+                        cluster_id: cluster_id.clone(),
+                        duration: duration.as_secs() as u32,
+                        headers,
+                        max_requests,
+                    })
+                    .into(),
+                )?;
+                if !self.json {
+                    println!("capturing for {} seconds...", duration.as_secs());
+                }
+                thread::sleep(duration);
+
+                let response = self.send_request_get_response(
+                    RequestType::CollectCapture(CollectCapture { cluster_id }).into(),
+                    true,
+                )?;
+                let bundle = match &response.content {
+                    Some(ResponseContent {
+                        content_type: Some(ContentType::CaptureBundle(bundle)),
+                    }) => bundle,
+                    _ => return Err(CtlError::WrongResponse(response)),
+                };
+                serde_json::to_string_pretty(bundle)
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| std::fs::write(&file, json).map_err(|e| e.to_string()))
+                    .map_err(|error| CtlError::WriteCaptureFile {
+                        path: file.clone(),
+                        error,
+                    })?;
+
+                response.display(self.json).map_err(CtlError::Display)?;
+                if !self.json {
+                    println!("capture written to {file}");
+                }
+                Ok(())
+            }
+            DebugCmd::Audit { worker_id, reclaim } => self.send_request(
+                RequestType::AuditSessions(AuditSessions { worker_id, reclaim }).into(),
+            ),
+        }
+    }
+
     pub fn upgrade_worker(&mut self, worker_id: u32) -> Result<(), CtlError> {
         debug!("upgrading worker {}", worker_id);
         self.send_request(RequestType::UpgradeWorker(worker_id).into())
     }
 }
+
+/// unix timestamp, in seconds, at which an object added now with this time to live expires
+fn expiration_date(expires_in: Option<Duration>) -> Option<u64> {
+    expires_in.map(|ttl| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_add(ttl)
+            .as_secs()
+    })
+}
+
+/// timeouts of a cluster or frontend, unset if none overrides the inherited ones
+fn timeouts(
+    body_read: Option<u32>,
+    backend_connect: Option<u32>,
+    backend_response: Option<u32>,
+    request_deadline: Option<u32>,
+) -> Option<Timeouts> {
+    let timeouts = Timeouts {
+        body_read,
+        backend_connect,
+        backend_response,
+        request_deadline,
+    };
+    (timeouts != Timeouts::default()).then_some(timeouts)
+}
+
+/// the mirror of a frontend, unset if no sink is given
+fn request_mirror(config: RequestMirrorConfig) -> Result<Option<RequestMirror>, CtlError> {
+    if config.file.is_none() && config.unix_socket.is_none() {
+        return Ok(None);
+    }
+    config
+        .to_request_mirror("the frontend")
+        .map(Some)
+        .map_err(CtlError::Mirror)
+}
+
+/// the class of devices a frontend matches, any if unset
+fn device_match(
+    class: Option<DeviceClass>,
+    mobile_user_agents: Vec<String>,
+) -> Option<DeviceMatch> {
+    class.map(|class| DeviceMatch {
+        class: class as i32,
+        mobile_user_agents,
+    })
+}
+
+/// token bucket of each client IP of a cluster or a frontend, disabled if unset
+fn token_bucket(requests_per_second: Option<u32>, burst: Option<u32>) -> Option<ClientRateLimit> {
+    requests_per_second.map(|requests_per_second| ClientRateLimit {
+        requests_per_second,
+        burst,
+    })
+}
+
+/// backend pinning of a listener, disabled if no client is trusted with it
+fn backend_pinning_config(
+    trusted: Vec<String>,
+    header: Option<String>,
+) -> Option<BackendPinningConfig> {
+    if trusted.is_empty() {
+        return None;
+    }
+    Some(BackendPinningConfig { header, trusted })
+}
+
+/// header edits of a frontend for one position, in the order of the configuration file:
+/// remove, set, then add
+fn header_edits(
+    position: HeaderPosition,
+    remove: Vec<String>,
+    set: Vec<(String, String)>,
+    add: Vec<(String, String)>,
+) -> Vec<HeaderEdit> {
+    remove
+        .iter()
+        .map(|key| HeaderEdit::new(position, HeaderEditKind::Remove, key, None))
+        .chain(
+            set.iter()
+                .map(|(key, val)| HeaderEdit::new(position, HeaderEditKind::Set, key, Some(val))),
+        )
+        .chain(
+            add.iter()
+                .map(|(key, val)| HeaderEdit::new(position, HeaderEditKind::Add, key, Some(val))),
+        )
+        .collect()
+}
+
+/// options for HTTP/1.0 clients, unset if they all keep their default
+fn http10_config(
+    keep_open: bool,
+    no_keep_alive: bool,
+    default_host: Option<String>,
+) -> Option<Http10Config> {
+    if !keep_open && !no_keep_alive && default_host.is_none() {
+        return None;
+    }
+    Some(Http10Config {
+        implicit_close: keep_open.then_some(false),
+        keep_alive: no_keep_alive.then_some(false),
+        default_host,
+    })
+}
+
+fn read_state_file(path: &str, format: ExportFormat) -> Result<ConfigState, CtlError> {
+    std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| deserialize_state(&content, format))
+        .map_err(|error| CtlError::ReadStateFile {
+            path: path.to_owned(),
+            error,
+        })
+}
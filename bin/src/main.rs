@@ -34,6 +34,8 @@ extern crate num_cpus;
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+/// Accept the clients of the command socket in an unprivileged process
+mod broker;
 /// the arguments to the sozu command line
 mod cli;
 /// Receives orders from the CLI, transmits to workers
@@ -55,6 +57,7 @@ use libc::{cpu_set_t, pid_t};
 
 use sozu::metrics::METRICS;
 
+use broker::BrokerError;
 use cli::Args;
 use command::{begin_main_process, sessions::WorkerSession, StartError};
 use ctl::CtlError;
@@ -67,8 +70,12 @@ enum MainError {
     StartMain(StartError),
     #[error("failed to start new worker: {0}")]
     BeginWorker(WorkerError),
+    #[error("failed to start the command broker: {0}")]
+    BeginBroker(BrokerError),
     #[error("failed to start new main process: {0}")]
     BeginNewMain(UpgradeError),
+    #[error("failed to validate the upgrade: {0}")]
+    ValidateUpgrade(UpgradeError),
     #[error("{0}")]
     Cli(CtlError),
 }
@@ -100,6 +107,15 @@ fn main(args: Args) {
             )
             .map_err(MainError::BeginWorker)
         }
+        // this is used only by the main process
+        cli::SubCmd::Broker {
+            fd,
+            command_socket_fd,
+            command_buffer_size,
+            user,
+            group,
+        } => broker::begin_broker_process(fd, command_socket_fd, command_buffer_size, user, group)
+            .map_err(MainError::BeginBroker),
         // this is used only by the CLI when upgrading
         cli::SubCmd::Main {
             fd,
@@ -117,6 +133,10 @@ fn main(args: Args) {
             )
             .map_err(MainError::BeginNewMain)
         }
+        // this is used only by the main process when upgrading
+        cli::SubCmd::ValidateUpgrade => {
+            upgrade::validate_upgrade().map_err(MainError::ValidateUpgrade)
+        }
         _ => ctl::ctl(args).map_err(MainError::Cli),
     };
     match result {
@@ -1,24 +1,33 @@
 use std::{
+    fmt,
     fs::File,
     io::{Error as IoError, Write},
     io::{Read, Seek},
     os::unix::io::{AsRawFd, FromRawFd},
     os::unix::process::CommandExt,
-    process::Command,
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
 };
 
 use libc::pid_t;
 use mio::net::UnixStream;
 use nix::{
     errno::Errno,
-    unistd::{fork, ForkResult},
+    sys::signal::{kill, Signal},
+    unistd::{fork, ForkResult, Pid},
 };
+use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use tempfile::tempfile;
 
 use sozu_command_lib::{
     channel::{Channel, ChannelError},
-    logging::setup_logging_with_config,
+    config::Config,
+    logging::{generation_tag, setup_logging_with_config},
+    proto::PROTOCOL_VERSION,
+    state::ConfigState,
 };
 
 use crate::{
@@ -69,6 +78,176 @@ pub enum UpgradeError {
     CreateHub(HubError),
     #[error("could not enable cloexec after upgrade: {0}")]
     EnableCloexec(ServerError),
+    #[error("could not run the new executable to validate the upgrade: {0}")]
+    SpawnValidation(IoError),
+    #[error("the new executable did not validate the upgrade within {0:?}")]
+    ValidationTimeout(Duration),
+    #[error("the new executable did not report on the upgrade ({status}): {output}")]
+    ValidationReport { status: String, output: String },
+}
+
+/// time given to the new executable to validate an upgrade
+const UPGRADE_VALIDATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What the new executable found when checking that it can take over the main process.
+/// Written in JSON on its standard error, since parsing the configuration prints on the
+/// standard output
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpgradeValidation {
+    /// version of the new executable
+    pub version: String,
+    /// version of the messages exchanged between the main process and the workers
+    pub protocol_version: u32,
+    /// the upgrade data of the old main process could not be read
+    pub upgrade_data_error: Option<String>,
+    /// the configuration file could not be parsed, or turned into requests
+    pub config_error: Option<String>,
+    /// requests of the state replayed by the new executable
+    pub state_requests: usize,
+    /// requests of the state the new executable refused
+    pub state_errors: Vec<String>,
+}
+
+impl UpgradeValidation {
+    /// the old workers must understand the new main process
+    pub fn is_valid(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+            && self.upgrade_data_error.is_none()
+            && self.config_error.is_none()
+            && self.state_errors.is_empty()
+    }
+
+    /// check the upgrade data of the old main process, the configuration file it
+    /// was started with, and replay its state
+    fn check(upgrade_data: &str) -> Self {
+        let mut validation = UpgradeValidation {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+
+        let upgrade_data: UpgradeData = match serde_json::from_str(upgrade_data) {
+            Ok(upgrade_data) => upgrade_data,
+            Err(error) => {
+                validation.upgrade_data_error = Some(error.to_string());
+                return validation;
+            }
+        };
+
+        if let Err(error) = Config::load_from_path(&upgrade_data.config.config_path)
+            .and_then(|config| config.generate_config_messages())
+        {
+            validation.config_error = Some(error.to_string());
+        }
+
+        let mut state = ConfigState::new();
+        for request in upgrade_data.state.produce_initial_state().requests {
+            validation.state_requests += 1;
+            if let Err(error) = state.dispatch(&request.content) {
+                validation.state_errors.push(format!(
+                    "{}: {}",
+                    request.content.short_name(),
+                    error
+                ));
+            }
+        }
+
+        validation
+    }
+}
+
+impl fmt::Display for UpgradeValidation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "new executable {}", self.version)?;
+        if self.protocol_version != PROTOCOL_VERSION {
+            write!(
+                f,
+                ", protocol version {} instead of {}",
+                self.protocol_version, PROTOCOL_VERSION
+            )?;
+        }
+        if let Some(error) = &self.upgrade_data_error {
+            write!(f, ", could not read the upgrade data: {error}")?;
+        }
+        if let Some(error) = &self.config_error {
+            write!(f, ", invalid configuration: {error}")?;
+        }
+        if !self.state_errors.is_empty() {
+            write!(
+                f,
+                ", {} of {} requests of the state refused: {}",
+                self.state_errors.len(),
+                self.state_requests,
+                self.state_errors.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the new executable in its validate phase, before forking into it. Its report is
+/// read from its standard error, it is killed if it does not finish in time
+pub fn validate_with_new_executable(
+    executable_path: &str,
+    upgrade_data: &UpgradeData,
+) -> Result<UpgradeValidation, UpgradeError> {
+    let upgrade_data_string =
+        serde_json::to_string(upgrade_data).map_err(UpgradeError::SerdeWriteError)?;
+
+    let mut child = Command::new(executable_path)
+        .arg("validate-upgrade")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(UpgradeError::SpawnValidation)?;
+    let child_pid = Pid::from_raw(child.id() as i32);
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // the child reads all of its input before writing anything
+        stdin
+            .write_all(upgrade_data_string.as_bytes())
+            .map_err(UpgradeError::SpawnValidation)?;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(child.wait_with_output());
+    });
+    let output = match receiver.recv_timeout(UPGRADE_VALIDATION_TIMEOUT) {
+        Ok(output) => output.map_err(UpgradeError::SpawnValidation)?,
+        Err(_) => {
+            let _ = kill(child_pid, Signal::SIGKILL);
+            return Err(UpgradeError::ValidationTimeout(UPGRADE_VALIDATION_TIMEOUT));
+        }
+    };
+
+    let report = String::from_utf8_lossy(&output.stderr);
+    report
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .and_then(|report| serde_json::from_str(report).ok())
+        .ok_or_else(|| UpgradeError::ValidationReport {
+            status: output.status.to_string(),
+            output: report.trim().to_owned(),
+        })
+}
+
+/// Called by the old main process with `sozu validate-upgrade`, before it forks
+/// into this executable. Reports on its standard error, in JSON
+pub fn validate_upgrade() -> Result<(), UpgradeError> {
+    let mut upgrade_data = String::new();
+    std::io::stdin()
+        .read_to_string(&mut upgrade_data)
+        .map_err(UpgradeError::ReadFile)?;
+
+    let validation = UpgradeValidation::check(&upgrade_data);
+    eprintln!(
+        "{}",
+        serde_json::to_string(&validation).map_err(UpgradeError::SerdeWriteError)?
+    );
+    Ok(())
 }
 
 /// unix-forks the main process
@@ -180,8 +359,8 @@ pub fn begin_new_main_process(
 
     println!("Setting up logging");
 
-    setup_logging_with_config(&config, "MAIN");
-    util::setup_metrics(&config).map_err(UpgradeError::SetupMetrics)?;
+    setup_logging_with_config(&config, &generation_tag("MAIN", upgrade_data.generation));
+    util::setup_metrics(&config, upgrade_data.generation).map_err(UpgradeError::SetupMetrics)?;
 
     let mut command_hub =
         CommandHub::from_upgrade_data(upgrade_data).map_err(UpgradeError::CreateHub)?;
@@ -205,3 +384,29 @@ pub fn begin_new_main_process(
     info!("main process stopped");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_upgrades_the_workers_would_not_understand() {
+        let validation = UpgradeValidation::check("{\"not\": \"upgrade data\"}");
+        assert!(validation.upgrade_data_error.is_some());
+        assert!(!validation.is_valid());
+
+        let validation = UpgradeValidation {
+            version: "1.0.2".to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+        assert!(validation.is_valid());
+
+        let newer_protocol = UpgradeValidation {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..validation
+        };
+        assert!(!newer_protocol.is_valid());
+        assert!(newer_protocol.to_string().contains("protocol version"));
+    }
+}
@@ -11,7 +11,10 @@ use nix::{
     fcntl::{fcntl, FcntlArg, FdFlag},
 };
 
-use sozu_command_lib::config::Config;
+use sozu_command_lib::{
+    config::Config,
+    proto::{command::BuildInfo, PROTOCOL_VERSION},
+};
 use sozu_lib::metrics::{self, MetricError};
 
 use crate::cli;
@@ -45,6 +48,32 @@ pub enum UtilError {
     GetConfigFilePath,
 }
 
+/// how this executable was built, reported by the main process and the workers
+pub fn build_info() -> BuildInfo {
+    let features = [
+        ("jemallocator", cfg!(feature = "jemallocator")),
+        (
+            "tolerant-http1-parser",
+            cfg!(feature = "tolerant-http1-parser"),
+        ),
+        ("logs-debug", cfg!(feature = "logs-debug")),
+        ("logs-trace", cfg!(feature = "logs-trace")),
+        ("unstable", cfg!(feature = "unstable")),
+    ];
+
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        commit: option_env!("SOZU_GIT_COMMIT").map(str::to_owned),
+        features: features
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| feature.to_string())
+            .collect(),
+        rustls_version: option_env!("SOZU_RUSTLS_VERSION").map(str::to_owned),
+        protocol_version: PROTOCOL_VERSION,
+    }
+}
+
 /// FD_CLOEXEC is set by default on every fd in Rust standard lib,
 /// so we need to remove the flag on the client, otherwise
 /// it won't be accessible
@@ -73,11 +102,17 @@ pub fn disable_close_on_exec(fd: RawFd) -> Result<i32, UtilError> {
     fcntl(fd, FcntlArg::F_SETFD(new_flags)).map_err(|err_no| UtilError::SetFlags(fd, err_no))
 }
 
-pub fn setup_metrics(config: &Config) -> Result<(), UtilError> {
-    if let Some(metrics) = config.metrics.as_ref() {
+/// set up the metrics of the main process, of the given generation
+pub fn setup_metrics(config: &Config, generation: u32) -> Result<(), UtilError> {
+    let Some(metrics) = config.metrics.as_ref() else {
+        return Ok(());
+    };
+    // the metrics may only be scraped by Prometheus, without a statsd server
+    if let Some(address) = metrics.address {
         return metrics::setup(
-            &metrics.address,
+            &address,
             "MAIN",
+            generation,
             metrics.tagged_metrics,
             metrics.prefix.clone(),
         )
@@ -3,11 +3,14 @@ use std::{ffi::c_void, iter::repeat, mem::size_of};
 use std::{
     fs::File,
     io::Error as IoError,
-    io::Seek,
-    net::SocketAddr,
-    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
+    io::{BufReader, BufWriter, Read},
     os::unix::process::CommandExt,
+    os::unix::{
+        fs::FileExt,
+        io::{AsRawFd, FromRawFd, IntoRawFd},
+    },
     process::Command,
+    time::Instant,
 };
 
 use libc::pid_t;
@@ -22,16 +25,16 @@ use tempfile::tempfile;
 use sozu_command_lib::{
     channel::{Channel, ChannelError},
     config::Config,
-    logging::{setup_logging, AccessLogFormat},
     proto::command::{ServerConfig, WorkerRequest, WorkerResponse},
     ready::Ready,
-    request::{read_initial_state_from_file, RequestError},
     scm_socket::{Listeners, ScmSocket, ScmSocketError},
-    state::{ConfigState, StateError},
+    state::ConfigState,
+    state_transfer::{read_state, write_state, StateTransferError},
 };
 
 use sozu_lib::{
-    metrics::{self, MetricError},
+    embedded::{setup_worker_logging, setup_worker_metrics},
+    metrics::MetricError,
     server::{Server, ServerError as LibServerError},
 };
 
@@ -41,8 +44,8 @@ use crate::util::{self, UtilError};
 pub enum WorkerError {
     #[error("could not read on the channel")]
     ReadChannel(ChannelError),
-    #[error("could not parse configuration from temporary file: {0}")]
-    ReadRequestsFromFile(RequestError),
+    #[error("could not read the state passed by the main process: {0}")]
+    ReadStateFile(StateTransferError),
     #[error("could not setup metrics on new worker: {0}")]
     SetupMetrics(MetricError),
     #[error("could not create new worker from config: {0}")]
@@ -60,9 +63,7 @@ pub enum WorkerError {
         util_err: UtilError,
     },
     #[error("could not write state to temporary file: {0}")]
-    WriteStateFile(StateError),
-    #[error("could not rewind the temporary state file: {0}")]
-    Rewind(IoError),
+    WriteStateFile(StateTransferError),
     #[error("could not create MIO pair of unix stream: {0}")]
     CreateUnixStream(IoError),
     #[error("could not send config to the new worker: {0}")]
@@ -98,7 +99,7 @@ pub fn begin_worker_process(
             channel_err,
         })?;
 
-    let mut configuration_state_file = unsafe { File::from_raw_fd(configuration_state_fd) };
+    let configuration_state_file = unsafe { File::from_raw_fd(configuration_state_fd) };
 
     let worker_config = worker_to_main_channel
         .read_message()
@@ -106,18 +107,8 @@ pub fn begin_worker_process(
 
     let worker_id = format!("{}-{:02}", "WRK", id);
 
-    let access_log_format = AccessLogFormat::from(&worker_config.access_log_format());
-
     // do not try to log anything before this, or the logger will panic
-    setup_logging(
-        &worker_config.log_target,
-        worker_config.log_colored,
-        worker_config.access_logs_target.as_deref(),
-        Some(access_log_format),
-        Some(worker_config.log_colored),
-        &worker_config.log_level,
-        &worker_id,
-    );
+    setup_worker_logging(&worker_config, &worker_id);
 
     trace!(
         "Creating worker {} with config: {:#?}",
@@ -126,8 +117,30 @@ pub fn begin_worker_process(
     );
     info!("worker {} starting...", id);
 
-    let initial_state = read_initial_state_from_file(&mut configuration_state_file)
-        .map_err(WorkerError::ReadRequestsFromFile)?;
+    let start = Instant::now();
+    let mut reported = 0;
+    let (initial_state, summary) = read_state(
+        &mut BufReader::new(PositionalReader {
+            file: &configuration_state_file,
+            offset: 0,
+        }),
+        |loaded, total| {
+            // report every tenth of the state
+            if total > 0 && loaded * 10 >= (reported + 1) * total {
+                reported = loaded * 10 / total;
+                info!("read {}/{} requests of the initial state", loaded, total);
+            }
+        },
+    )
+    .map_err(WorkerError::ReadStateFile)?;
+    info!(
+        "read the initial state in {:?}: {} requests in {} chunks, {} bytes ({} compressed)",
+        start.elapsed(),
+        summary.requests,
+        summary.chunks,
+        summary.raw_bytes,
+        summary.compressed_bytes
+    );
 
     worker_to_main_channel
         .nonblocking()
@@ -140,19 +153,7 @@ pub fn begin_worker_process(
         worker_to_main_channel.into();
     worker_to_main_channel.readiness.insert(Ready::READABLE);
 
-    if let Some(metrics) = worker_config.metrics.as_ref() {
-        let address = metrics
-            .address
-            .parse::<SocketAddr>()
-            .expect("Could not parse metrics address");
-        metrics::setup(
-            &address,
-            worker_id,
-            metrics.tagged_metrics,
-            metrics.prefix.clone(),
-        )
-        .map_err(WorkerError::SetupMetrics)?;
-    }
+    setup_worker_metrics(&worker_config, &worker_id).map_err(WorkerError::SetupMetrics)?;
 
     let worker_to_main_scm_socket =
         ScmSocket::new(worker_to_main_scm_fd).map_err(|scm_err| WorkerError::CreateScmSocket {
@@ -168,6 +169,7 @@ pub fn begin_worker_process(
         true,
     )
     .map_err(WorkerError::NewServerFromConfig)?;
+    server.set_build_info(util::build_info());
 
     info!("starting event loop");
     server.run();
@@ -175,6 +177,61 @@ pub fn begin_worker_process(
     Ok(())
 }
 
+/// The state serialized once for all the workers launched until it changes,
+/// in a read-only file that they inherit
+pub struct StateSnapshot {
+    generation: u64,
+    file: File,
+}
+
+impl StateSnapshot {
+    pub fn new(state: &ConfigState) -> Result<Self, WorkerError> {
+        let mut file = create_state_file().map_err(WorkerError::CreateStateFile)?;
+
+        let start = Instant::now();
+        let summary = write_state(
+            &state.produce_initial_state().requests,
+            &mut BufWriter::new(&mut file),
+        )
+        .map_err(WorkerError::WriteStateFile)?;
+        info!(
+            "serialized the state of generation {} in {:?}: {} requests in {} chunks, {} bytes compressed to {}",
+            state.generation(),
+            start.elapsed(),
+            summary.requests,
+            summary.chunks,
+            summary.raw_bytes,
+            summary.compressed_bytes
+        );
+        seal_state_file(&file);
+
+        Ok(Self {
+            generation: state.generation(),
+            file,
+        })
+    }
+
+    /// the generation of the state that was serialized
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+/// Reads a file without moving its offset, shared by all the workers
+/// that inherited the same state snapshot
+struct PositionalReader<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for PositionalReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
 /// unix-forks the main process
 ///
 /// - Parent: sends config, state and listeners to the new worker
@@ -184,25 +241,17 @@ pub fn begin_worker_process(
 pub fn fork_main_into_worker(
     worker_id: &str,
     config: &Config,
+    main_generation: u32,
     executable_path: String,
-    state: &ConfigState,
+    state: &StateSnapshot,
     listeners: Option<Listeners>,
 ) -> Result<(pid_t, Channel<WorkerRequest, WorkerResponse>, ScmSocket), WorkerError> {
     trace!("parent({})", unsafe { libc::getpid() });
 
-    let mut state_file = tempfile().map_err(WorkerError::CreateStateFile)?;
-    util::disable_close_on_exec(state_file.as_raw_fd()).map_err(|util_err| {
-        WorkerError::DisableCloexec {
-            fd_name: "state_file".to_string(),
-            util_err,
-        }
-    })?;
-
-    state
-        .write_initial_state_to_file(&mut state_file)
-        .map_err(WorkerError::WriteStateFile)?;
-
-    state_file.rewind().map_err(WorkerError::Rewind)?;
+    debug!(
+        "passing the state of generation {} to worker {}",
+        state.generation, worker_id
+    );
 
     let (main_to_worker, worker_to_main) =
         UnixStream::pair().map_err(WorkerError::CreateUnixStream)?;
@@ -229,8 +278,19 @@ pub fn fork_main_into_worker(
             util_err,
         }
     })?;
+    // the snapshot is close-on-exec again once the worker is forked,
+    // so that only the workers launched with it inherit it
+    util::disable_close_on_exec(state.file.as_raw_fd()).map_err(|util_err| {
+        WorkerError::DisableCloexec {
+            fd_name: "state_file".to_string(),
+            util_err,
+        }
+    })?;
 
-    let worker_config = ServerConfig::from(config);
+    let worker_config = ServerConfig {
+        generation: main_generation,
+        ..ServerConfig::from(config)
+    };
 
     let mut main_to_worker_channel: Channel<ServerConfig, WorkerResponse> = Channel::new(
         main_to_worker,
@@ -249,6 +309,9 @@ pub fn fork_main_into_worker(
     match unsafe { fork().map_err(WorkerError::Fork)? } {
         ForkResult::Parent { child: worker_pid } => {
             info!("launching worker {} with pid {}", worker_id, worker_pid);
+            if let Err(e) = util::enable_close_on_exec(state.file.as_raw_fd()) {
+                error!("could not enable cloexec on the state file: {}", e);
+            }
             main_to_worker_channel
                 .write_message(&worker_config)
                 .map_err(WorkerError::SendConfig)?;
@@ -291,7 +354,7 @@ pub fn fork_main_into_worker(
                 .arg("--scm")
                 .arg(worker_to_main_scm.as_raw_fd().to_string())
                 .arg("--configuration-state-fd")
-                .arg(state_file.as_raw_fd().to_string())
+                .arg(state.file.as_raw_fd().to_string())
                 .arg("--command-buffer-size")
                 .arg(config.command_buffer_size.to_string())
                 .arg("--max-command-buffer-size")
@@ -302,3 +365,85 @@ pub fn fork_main_into_worker(
         }
     }
 }
+
+/// the file holding the state passed to a new worker, in memory where possible
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn create_state_file() -> Result<File, IoError> {
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+    use std::ffi::CStr;
+
+    let name = CStr::from_bytes_with_nul(b"sozu-state\0").expect("the name ends with a nul byte");
+    match memfd_create(
+        name,
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    ) {
+        Ok(fd) => Ok(File::from(fd)),
+        Err(errno) => {
+            warn!(
+                "could not create a memfd for the state, using a temporary file: {}",
+                errno
+            );
+            tempfile()
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+fn create_state_file() -> Result<File, IoError> {
+    tempfile()
+}
+
+/// forbid changes to the state file, when it is a memfd
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn seal_state_file(file: &File) {
+    use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+
+    let seals = SealFlag::F_SEAL_SHRINK
+        | SealFlag::F_SEAL_GROW
+        | SealFlag::F_SEAL_WRITE
+        | SealFlag::F_SEAL_SEAL;
+    if let Err(errno) = fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals)) {
+        debug!("could not seal the state file: {}", errno);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+fn seal_state_file(_file: &File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command_lib::proto::command::{request::RequestType, Cluster};
+
+    #[test]
+    fn workers_read_the_same_snapshot() {
+        let mut state = ConfigState::new();
+        for i in 0..3 {
+            state
+                .dispatch(
+                    &RequestType::AddCluster(Cluster {
+                        cluster_id: format!("cluster_{i}"),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+        let snapshot = StateSnapshot::new(&state).unwrap();
+        assert_eq!(snapshot.generation(), state.generation());
+
+        // each worker reads from the start, whatever the others did with the file
+        for _ in 0..2 {
+            let (initial_state, summary) = read_state(
+                &mut PositionalReader {
+                    file: &snapshot.file,
+                    offset: 0,
+                },
+                |_, _| {},
+            )
+            .unwrap();
+            assert_eq!(initial_state, state.produce_initial_state());
+            assert_eq!(summary.requests, 3);
+        }
+    }
+}
@@ -6,8 +6,24 @@ pub fn main() {
         .enum_attribute(".", "#[serde(rename_all = \"SCREAMING_SNAKE_CASE\")]")
         .enum_attribute("Order", "#[derive(Hash, Eq, Ord, PartialOrd)]")
         .enum_attribute("request_type", "#[derive(Hash, Eq, Ord, PartialOrd)]")
+        // the listener configurations are much larger than the other requests
+        .enum_attribute("request_type", "#[allow(clippy::large_enum_variant)]")
         .enum_attribute("inner", "#[derive(Hash, Eq, Ord, PartialOrd)]")
         .enum_attribute("content_type", "#[derive(Hash, Eq, Ord, PartialOrd)]")
+        .field_attribute(
+            "Request.dry_run",
+            "#[serde(default, skip_serializing_if = \"Option::is_none\")]",
+        )
+        .field_attribute(
+            "Request.expected_epoch",
+            "#[serde(default, skip_serializing_if = \"Option::is_none\")]",
+        )
+        .field_attribute(
+            "Request.expected_cluster_hash",
+            "#[serde(default, skip_serializing_if = \"Option::is_none\")]",
+        )
+        // these messages hold secrets, their Debug implementation hides them
+        .skip_debug(["SigningKey", "RotateSigningKey"])
         .out_dir("src/proto")
         .compile_protos(&["command.proto"], &["src"])
         .expect("Could not compile protobuf types in command.proto");
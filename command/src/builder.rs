@@ -0,0 +1,577 @@
+//! Builders of the requests that add clusters, frontends and backends.
+//!
+//! They start from the same defaults as the configuration file, and check the
+//! request before it is sent, so that a program driving Sōzu does not have to
+//! fill every field of the protobuf types:
+//!
+//! ```
+//! use sozu_command_lib::{
+//!     builder::{BackendBuilder, ClusterBuilder, HttpFrontendBuilder},
+//!     proto::command::{LoadBalancingAlgorithms, PathRule},
+//! };
+//!
+//! let address = "127.0.0.1:8080".parse().unwrap();
+//! let requests = vec![
+//!     ClusterBuilder::new("my-cluster")
+//!         .with_load_balancing(LoadBalancingAlgorithms::LeastLoaded)
+//!         .to_request()
+//!         .unwrap(),
+//!     HttpFrontendBuilder::new(address, "example.com")
+//!         .with_cluster_id("my-cluster")
+//!         .with_path(PathRule::prefix("/api"))
+//!         .to_add_http_request()
+//!         .unwrap(),
+//!     BackendBuilder::new("my-cluster", "backend-0", "127.0.0.1:1026".parse().unwrap())
+//!         .with_weight(50)
+//!         .to_request()
+//!         .unwrap(),
+//! ];
+//! assert_eq!(requests.len(), 3);
+//! ```
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use crate::{
+    config::DEFAULT_BACKEND_WEIGHT,
+    proto::command::{
+        request::RequestType, AddBackend, Cluster, HashKey, HashKeyKind, HeaderEdit, HealthCheck,
+        LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric, OutlierDetection, PathRule,
+        ProxyProtocolConfig, Request, RequestHttpFrontend, RequestTcpFrontend, RulePosition,
+        Timeouts, WeightedCluster,
+    },
+    request::RequestError,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum BuilderError {
+    #[error("the field '{0}' can not be empty")]
+    Empty(&'static str),
+    #[error("invalid {field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+    #[error("invalid request: {0}")]
+    Request(#[from] RequestError),
+}
+
+/// an id of cluster or backend, used in the state, the metrics and the access logs
+fn check_id(field: &'static str, id: &str) -> Result<(), BuilderError> {
+    if id.is_empty() {
+        return Err(BuilderError::Empty(field));
+    }
+    if id.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(BuilderError::Invalid {
+            field,
+            reason: format!("{id:?} contains whitespace"),
+        });
+    }
+    Ok(())
+}
+
+/// starts from a cluster with a round robin load balancing,
+/// no sticky session and no HTTPS redirection
+#[derive(Debug, Clone)]
+pub struct ClusterBuilder {
+    cluster: Cluster,
+}
+
+impl ClusterBuilder {
+    pub fn new<S>(cluster_id: S) -> ClusterBuilder
+    where
+        S: ToString,
+    {
+        ClusterBuilder {
+            cluster: Cluster {
+                cluster_id: cluster_id.to_string(),
+                load_balancing: LoadBalancingAlgorithms::RoundRobin as i32,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn with_sticky_session(&mut self, sticky_session: bool) -> &mut Self {
+        self.cluster.sticky_session = sticky_session;
+        self
+    }
+
+    pub fn with_https_redirect(&mut self, https_redirect: bool) -> &mut Self {
+        self.cluster.https_redirect = https_redirect;
+        self
+    }
+
+    pub fn with_proxy_protocol(&mut self, proxy_protocol: ProxyProtocolConfig) -> &mut Self {
+        self.cluster.proxy_protocol = Some(proxy_protocol as i32);
+        self
+    }
+
+    pub fn with_load_balancing(&mut self, load_balancing: LoadBalancingAlgorithms) -> &mut Self {
+        self.cluster.load_balancing = load_balancing as i32;
+        self
+    }
+
+    pub fn with_load_metric(&mut self, load_metric: LoadMetric) -> &mut Self {
+        self.cluster.load_metric = Some(load_metric as i32);
+        self
+    }
+
+    /// key hashed by the CONSISTENT_HASH load balancing
+    pub fn with_hash_key(&mut self, hash_key: HashKey) -> &mut Self {
+        self.cluster.hash_key = Some(hash_key);
+        self
+    }
+
+    pub fn with_answer_503<S>(&mut self, answer_503: S) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.cluster.answer_503 = Some(answer_503.to_string());
+        self
+    }
+
+    /// unix timestamp (in seconds) after which the main process removes the cluster
+    pub fn with_expires_at(&mut self, expires_at: u64) -> &mut Self {
+        self.cluster.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn with_timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.cluster.timeouts = Some(timeouts);
+        self
+    }
+
+    pub fn with_health_check(&mut self, health_check: HealthCheck) -> &mut Self {
+        self.cluster.health_check = Some(health_check);
+        self
+    }
+
+    pub fn with_outlier_detection(&mut self, outlier_detection: OutlierDetection) -> &mut Self {
+        self.cluster.outlier_detection = Some(outlier_detection);
+        self
+    }
+
+    /// DSCP value (0 to 63) of the packets sent to the backends,
+    /// and to the clients if `on_clients` is set
+    pub fn with_dscp(&mut self, dscp: u8, on_clients: bool) -> &mut Self {
+        self.cluster.dscp = Some(u32::from(dscp));
+        self.cluster.dscp_on_clients = on_clients;
+        self
+    }
+
+    pub fn build(&self) -> Result<Cluster, BuilderError> {
+        let cluster = &self.cluster;
+        check_id("cluster_id", &cluster.cluster_id)?;
+
+        if let Some(hash_key) = &cluster.hash_key {
+            if cluster.load_balancing != LoadBalancingAlgorithms::ConsistentHash as i32 {
+                return Err(BuilderError::Invalid {
+                    field: "hash_key",
+                    reason: "it is only used by the CONSISTENT_HASH load balancing".to_owned(),
+                });
+            }
+            if hash_key.kind != HashKeyKind::SourceIp as i32 && hash_key.name.is_none() {
+                return Err(BuilderError::Invalid {
+                    field: "hash_key",
+                    reason: "hashing a header or a cookie needs its name".to_owned(),
+                });
+            }
+        }
+        if cluster.dscp.is_some_and(|dscp| dscp > 63) {
+            return Err(BuilderError::Invalid {
+                field: "dscp",
+                reason: "it should be between 0 and 63".to_owned(),
+            });
+        }
+        Ok(cluster.clone())
+    }
+
+    /// the request adding the cluster
+    pub fn to_request(&self) -> Result<Request, BuilderError> {
+        Ok(RequestType::AddCluster(self.build()?).into())
+    }
+}
+
+/// starts from a frontend matching every path of a hostname, in the tree of
+/// the router, that refuses its requests until it gets a cluster
+#[derive(Debug, Clone)]
+pub struct HttpFrontendBuilder {
+    frontend: RequestHttpFrontend,
+}
+
+impl HttpFrontendBuilder {
+    pub fn new<S>(address: SocketAddr, hostname: S) -> HttpFrontendBuilder
+    where
+        S: ToString,
+    {
+        HttpFrontendBuilder {
+            frontend: RequestHttpFrontend {
+                address: address.into(),
+                hostname: hostname.to_string(),
+                path: PathRule::prefix(""),
+                position: RulePosition::Tree as i32,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn with_cluster_id<S>(&mut self, cluster_id: S) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.frontend.cluster_id = Some(cluster_id.to_string());
+        self
+    }
+
+    pub fn with_path(&mut self, path: PathRule) -> &mut Self {
+        self.frontend.path = path;
+        self
+    }
+
+    pub fn with_method<S>(&mut self, method: S) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.frontend.method = Some(method.to_string());
+        self
+    }
+
+    pub fn with_position(&mut self, position: RulePosition) -> &mut Self {
+        self.frontend.position = position as i32;
+        self
+    }
+
+    /// custom tag identifying the frontend in the access logs
+    pub fn with_tag<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: ToString,
+        V: ToString,
+    {
+        self.frontend
+            .tags
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// unix timestamp (in seconds) after which the main process removes the frontend
+    pub fn with_expires_at(&mut self, expires_at: u64) -> &mut Self {
+        self.frontend.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn with_timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.frontend.timeouts = Some(timeouts);
+        self
+    }
+
+    /// split the requests between weighted clusters, one of them being the cluster of the frontend
+    pub fn with_split(&mut self, split: Vec<WeightedCluster>) -> &mut Self {
+        self.frontend.split = split;
+        self
+    }
+
+    /// edit of the request or response headers, applied after the previous ones
+    pub fn with_header_edit(&mut self, edit: HeaderEdit) -> &mut Self {
+        self.frontend.headers.push(edit);
+        self
+    }
+
+    pub fn build(&self) -> Result<RequestHttpFrontend, BuilderError> {
+        let frontend = &self.frontend;
+        if frontend.hostname.is_empty() {
+            return Err(BuilderError::Empty("hostname"));
+        }
+        if frontend
+            .hostname
+            .chars()
+            .any(|c| c.is_whitespace() || c == '/')
+        {
+            return Err(BuilderError::Invalid {
+                field: "hostname",
+                reason: format!("{:?} is not a hostname", frontend.hostname),
+            });
+        }
+        if let Some(cluster_id) = &frontend.cluster_id {
+            check_id("cluster_id", cluster_id)?;
+        }
+        frontend.clone().to_frontend()?;
+        Ok(frontend.clone())
+    }
+
+    /// the request adding the frontend to an HTTP listener
+    pub fn to_add_http_request(&self) -> Result<Request, BuilderError> {
+        Ok(RequestType::AddHttpFrontend(self.build()?).into())
+    }
+
+    /// the request adding the frontend to an HTTPS listener
+    pub fn to_add_https_request(&self) -> Result<Request, BuilderError> {
+        Ok(RequestType::AddHttpsFrontend(self.build()?).into())
+    }
+}
+
+/// starts from a frontend forwarding every connection of the listener to a cluster
+#[derive(Debug, Clone)]
+pub struct TcpFrontendBuilder {
+    frontend: RequestTcpFrontend,
+}
+
+impl TcpFrontendBuilder {
+    pub fn new<S>(cluster_id: S, address: SocketAddr) -> TcpFrontendBuilder
+    where
+        S: ToString,
+    {
+        TcpFrontendBuilder {
+            frontend: RequestTcpFrontend {
+                cluster_id: cluster_id.to_string(),
+                address: address.into(),
+                tags: BTreeMap::new(),
+                expires_at: None,
+                sni: None,
+            },
+        }
+    }
+
+    /// custom tag identifying the frontend in the access logs
+    pub fn with_tag<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: ToString,
+        V: ToString,
+    {
+        self.frontend
+            .tags
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// unix timestamp (in seconds) after which the main process removes the frontend
+    pub fn with_expires_at(&mut self, expires_at: u64) -> &mut Self {
+        self.frontend.expires_at = Some(expires_at);
+        self
+    }
+
+    /// route only the TLS connections asking for this server name
+    pub fn with_sni<S>(&mut self, sni: S) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.frontend.sni = Some(sni.to_string());
+        self
+    }
+
+    pub fn build(&self) -> Result<RequestTcpFrontend, BuilderError> {
+        check_id("cluster_id", &self.frontend.cluster_id)?;
+        if self.frontend.sni.as_deref() == Some("") {
+            return Err(BuilderError::Empty("sni"));
+        }
+        Ok(self.frontend.clone())
+    }
+
+    /// the request adding the frontend
+    pub fn to_request(&self) -> Result<Request, BuilderError> {
+        Ok(RequestType::AddTcpFrontend(self.build()?).into())
+    }
+}
+
+/// starts from a backend of weight 100 (see [DEFAULT_BACKEND_WEIGHT]),
+/// that is not a backup and without slow start
+#[derive(Debug, Clone)]
+pub struct BackendBuilder {
+    backend: AddBackend,
+}
+
+impl BackendBuilder {
+    pub fn new<C, B>(cluster_id: C, backend_id: B, address: SocketAddr) -> BackendBuilder
+    where
+        C: ToString,
+        B: ToString,
+    {
+        BackendBuilder {
+            backend: AddBackend {
+                cluster_id: cluster_id.to_string(),
+                backend_id: backend_id.to_string(),
+                address: address.into(),
+                sticky_id: None,
+                load_balancing_parameters: Some(LoadBalancingParams {
+                    weight: DEFAULT_BACKEND_WEIGHT,
+                    slow_start_seconds: None,
+                }),
+                backup: None,
+                expires_at: None,
+                draining: None,
+            },
+        }
+    }
+
+    /// value of the sticky session cookie sending the clients to this backend
+    pub fn with_sticky_id<S>(&mut self, sticky_id: S) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.backend.sticky_id = Some(sticky_id.to_string());
+        self
+    }
+
+    pub fn with_weight(&mut self, weight: i32) -> &mut Self {
+        self.backend
+            .load_balancing_parameters
+            .get_or_insert_with(LoadBalancingParams::default)
+            .weight = weight;
+        self
+    }
+
+    /// ramp up the traffic of the backend over this duration when it joins the cluster
+    pub fn with_slow_start_seconds(&mut self, slow_start_seconds: u32) -> &mut Self {
+        self.backend
+            .load_balancing_parameters
+            .get_or_insert_with(LoadBalancingParams::default)
+            .slow_start_seconds = Some(slow_start_seconds);
+        self
+    }
+
+    /// only send traffic to the backend when no other backend is available
+    pub fn with_backup(&mut self, backup: bool) -> &mut Self {
+        self.backend.backup = Some(backup);
+        self
+    }
+
+    /// unix timestamp (in seconds) after which the main process removes the backend
+    pub fn with_expires_at(&mut self, expires_at: u64) -> &mut Self {
+        self.backend.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn build(&self) -> Result<AddBackend, BuilderError> {
+        check_id("cluster_id", &self.backend.cluster_id)?;
+        check_id("backend_id", &self.backend.backend_id)?;
+        if let Some(params) = &self.backend.load_balancing_parameters {
+            if params.weight < 0 {
+                return Err(BuilderError::Invalid {
+                    field: "weight",
+                    reason: format!("{} is negative", params.weight),
+                });
+            }
+        }
+        Ok(self.backend.clone())
+    }
+
+    /// the request adding the backend
+    pub fn to_request(&self) -> Result<Request, BuilderError> {
+        Ok(RequestType::AddBackend(self.build()?).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::command::{HeaderEditKind, HeaderPosition};
+
+    #[test]
+    fn cluster_builder_defaults_and_validation() {
+        let cluster = ClusterBuilder::new("cluster_1").build().unwrap();
+        assert_eq!(cluster.cluster_id, "cluster_1");
+        assert_eq!(
+            cluster.load_balancing,
+            LoadBalancingAlgorithms::RoundRobin as i32
+        );
+        assert!(!cluster.sticky_session);
+        assert!(!cluster.https_redirect);
+
+        assert!(matches!(
+            ClusterBuilder::new("").build(),
+            Err(BuilderError::Empty("cluster_id"))
+        ));
+        assert!(matches!(
+            ClusterBuilder::new("cluster 1").build(),
+            Err(BuilderError::Invalid {
+                field: "cluster_id",
+                ..
+            })
+        ));
+        assert!(ClusterBuilder::new("cluster_1")
+            .with_hash_key(HashKey::default())
+            .build()
+            .is_err());
+        assert!(ClusterBuilder::new("cluster_1")
+            .with_load_balancing(LoadBalancingAlgorithms::ConsistentHash)
+            .with_hash_key(HashKey::default())
+            .build()
+            .is_ok());
+        assert!(ClusterBuilder::new("cluster_1")
+            .with_dscp(64, false)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn http_frontend_builder_defaults_and_validation() {
+        let address = "127.0.0.1:8080".parse().unwrap();
+        let frontend = HttpFrontendBuilder::new(address, "lolcatho.st")
+            .with_cluster_id("cluster_1")
+            .with_tag("owner", "team")
+            .build()
+            .unwrap();
+        assert_eq!(frontend.path, PathRule::prefix(""));
+        assert_eq!(frontend.position, RulePosition::Tree as i32);
+        assert_eq!(frontend.address, address.into());
+        assert_eq!(frontend.tags.get("owner").map(String::as_str), Some("team"));
+
+        assert!(matches!(
+            HttpFrontendBuilder::new(address, "").build(),
+            Err(BuilderError::Empty("hostname"))
+        ));
+        assert!(matches!(
+            HttpFrontendBuilder::new(address, "lolcatho.st")
+                .with_cluster_id("cluster_1")
+                .with_split(vec![WeightedCluster {
+                    cluster_id: "cluster_2".to_owned(),
+                    weight: 1,
+                }])
+                .build(),
+            Err(BuilderError::Request(RequestError::InvalidSplit(_)))
+        ));
+        assert!(matches!(
+            HttpFrontendBuilder::new(address, "lolcatho.st")
+                .with_header_edit(HeaderEdit::new(
+                    HeaderPosition::Request,
+                    HeaderEditKind::Add,
+                    "bad header",
+                    Some("value"),
+                ))
+                .to_add_https_request(),
+            Err(BuilderError::Request(RequestError::InvalidHeaderEdit(_)))
+        ));
+    }
+
+    #[test]
+    fn backend_builder_defaults_and_validation() {
+        let address = "127.0.0.1:1026".parse().unwrap();
+        let backend = BackendBuilder::new("cluster_1", "backend_1", address)
+            .build()
+            .unwrap();
+        assert_eq!(
+            backend.load_balancing_parameters,
+            Some(LoadBalancingParams {
+                weight: DEFAULT_BACKEND_WEIGHT,
+                slow_start_seconds: None,
+            })
+        );
+
+        let backend = BackendBuilder::new("cluster_1", "backend_1", address)
+            .with_weight(10)
+            .with_slow_start_seconds(30)
+            .build()
+            .unwrap();
+        assert_eq!(
+            backend.load_balancing_parameters,
+            Some(LoadBalancingParams {
+                weight: 10,
+                slow_start_seconds: Some(30),
+            })
+        );
+
+        assert!(matches!(
+            BackendBuilder::new("cluster_1", "", address).build(),
+            Err(BuilderError::Empty("backend_id"))
+        ));
+        assert!(BackendBuilder::new("cluster_1", "backend_1", address)
+            .with_weight(-1)
+            .build()
+            .is_err());
+    }
+}
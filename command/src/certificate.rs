@@ -9,11 +9,12 @@ use x509_parser::{
     oid_registry::{OID_X509_COMMON_NAME, OID_X509_EXT_SUBJECT_ALT_NAME},
     parse_x509_certificate,
     pem::{parse_x509_pem, Pem},
+    public_key::PublicKey,
 };
 
 use crate::{
     config::{Config, ConfigError},
-    proto::command::{CertificateAndKey, TlsVersion},
+    proto::command::{CertificateAndKey, CertificateDetails, TlsVersion},
 };
 
 // -----------------------------------------------------------------------------
@@ -84,6 +85,42 @@ pub fn get_cn_and_san_attributes(x509: &X509Certificate) -> Vec<String> {
     names
 }
 
+// -----------------------------------------------------------------------------
+// get_certificate_details
+
+/// Retrieve the subject alternate names, key type and validity of a certificate.
+/// `names` are the names the certificate is served for.
+pub fn get_certificate_details(
+    x509: &X509Certificate,
+    chain_length: u32,
+    names: Vec<String>,
+) -> CertificateDetails {
+    let mut subject_alternative_names = Vec::new();
+    if let Ok(Some(san)) = x509.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                subject_alternative_names.push(name.to_string());
+            }
+        }
+    }
+
+    let public_key = x509.public_key();
+    let key_type = match public_key.parsed() {
+        Ok(PublicKey::RSA(rsa)) => format!("RSA {} bits", rsa.key_size()),
+        Ok(PublicKey::EC(ec)) => format!("ECDSA {} bits", ec.key_size()),
+        _ => public_key.algorithm.algorithm.to_id_string(),
+    };
+
+    CertificateDetails {
+        subject_alternative_names,
+        chain_length,
+        key_type,
+        not_before: x509.validity().not_before.timestamp(),
+        not_after: x509.validity().not_after.timestamp(),
+        names,
+    }
+}
+
 // -----------------------------------------------------------------------------
 // TlsVersion
 
@@ -52,22 +52,28 @@ use std::{
     env, fmt,
     fs::{create_dir_all, metadata, File},
     io::{ErrorKind, Read},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     ops::Range,
     path::PathBuf,
 };
 
 use crate::{
     certificate::split_certificate_chain,
-    logging::AccessLogFormat,
+    logging::{AccessLogFormat, AccessLogTemplate},
     proto::command::{
-        request::RequestType, ActivateListener, AddBackend, AddCertificate, CertificateAndKey,
-        Cluster, CustomHttpAnswers, HttpListenerConfig, HttpsListenerConfig, ListenerType,
-        LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric, MetricsConfiguration, PathRule,
-        ProtobufAccessLogFormat, ProxyProtocolConfig, Request, RequestHttpFrontend,
-        RequestTcpFrontend, RulePosition, ServerConfig, ServerMetricsConfig, SocketAddress,
-        TcpListenerConfig, TlsVersion, WorkerRequest,
+        request::RequestType, ActivateListener, AddBackend, AddCertificate, BackendPinning,
+        CertificateAndKey, ClientAuthentication, ClientAuthenticationMode,
+        ClientCertificateHeaders, ClientRateLimit, Cluster, CustomHttpAnswers, DeviceClass,
+        DeviceMatch, DuplicateHeader, DuplicateHeaderPolicy, HashKey, HashKeyKind, HeaderEdit,
+        HeaderEditKind, HeaderPosition, HealthCheck, Http10Options, HttpListenerConfig,
+        HttpsListenerConfig, HttpsPolicy, ListenerType, LoadBalancingAlgorithms,
+        LoadBalancingParams, LoadMetric, MetricsConfiguration, MirrorSink, OutlierDetection,
+        PathRule, ProtobufAccessLogFormat, ProxyProtocolConfig, ProxyStatusHeader, Request,
+        RequestHttpFrontend, RequestMirror, RequestRateLimit, RequestTcpFrontend, RulePosition,
+        ServerConfig, ServerMetricsConfig, SlowLog, SocketAddress, TcpListenerConfig, Timeouts,
+        TlsVersion, WeightedCluster, WorkerRequest,
     },
+    request::validate_split,
     ObjectKind,
 };
 
@@ -122,15 +128,66 @@ pub const DEFAULT_CONNECT_TIMEOUT: u32 = 3;
 /// maximum time to receive a request since the connection started (10 seconds)
 pub const DEFAULT_REQUEST_TIMEOUT: u32 = 10;
 
+/// maximum time to complete the TLS handshake of an HTTPS connection (10 seconds)
+pub const DEFAULT_HANDSHAKE_TIMEOUT: u32 = 10;
+
 /// maximum time to wait for a worker to respond, until it is deemed NotAnswering (10 seconds)
 pub const DEFAULT_WORKER_TIMEOUT: u32 = 10;
 
+/// maximum number of requests waiting for a worker once its channel is full
+pub const DEFAULT_WORKER_QUEUE_SIZE: u64 = 10_000;
+
 /// a name applied to sticky sessions ("SOZUBALANCEID")
 pub const DEFAULT_STICKY_NAME: &str = "SOZUBALANCEID";
 
+/// load balancing weight of a backend (100)
+pub const DEFAULT_BACKEND_WEIGHT: i32 = 100;
+
 /// Interval between checking for zombie sessions, (30 minutes)
 pub const DEFAULT_ZOMBIE_CHECK_INTERVAL: u32 = 1_800;
 
+/// number of times the command line retries to connect to the command socket
+pub const DEFAULT_CTL_CONNECT_RETRIES: u32 = 3;
+
+/// delay before the first retry to connect to the command socket, in milliseconds
+pub const DEFAULT_CTL_CONNECT_BACKOFF: u64 = 100;
+
+/// Interval between two audits of the sessions of a worker, disabled by default
+pub const DEFAULT_SESSION_AUDIT_INTERVAL: u32 = 0;
+
+/// number of events kept in the history of the main process
+pub const DEFAULT_EVENT_HISTORY_SIZE: u64 = 1_000;
+
+/// number of state changes kept in the history of the main process
+pub const DEFAULT_CHANGE_HISTORY_SIZE: u64 = 1_000;
+
+/// Interval between evaluations of the alert rules, in seconds
+pub const DEFAULT_ALERT_CHECK_INTERVAL: u64 = 30;
+
+/// Interval between resolutions of the SRV records of the clusters, in seconds
+pub const DEFAULT_SRV_REFRESH_INTERVAL: u64 = 30;
+
+/// Delay after which the listeners held back at startup are activated anyway, in seconds
+pub const DEFAULT_READINESS_TIMEOUT: u64 = 30;
+
+/// Interval between two pushes of the metrics to a Prometheus remote write endpoint, in seconds
+pub const DEFAULT_REMOTE_WRITE_INTERVAL: u64 = 15;
+
+/// Pushes of the metrics kept while the remote write endpoint is unreachable
+pub const DEFAULT_REMOTE_WRITE_QUEUE_SIZE: usize = 20;
+
+/// ACME server the certificates are ordered from: Let's Encrypt
+pub const DEFAULT_ACME_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Certificates ordered through ACME are renewed this many days before they expire
+pub const DEFAULT_ACME_RENEW_BEFORE: u32 = 30;
+
+/// The internal cluster routing the HTTP-01 challenges of the ACME server to the main process
+pub const ACME_CLUSTER_ID: &str = "sozu-acme";
+
+/// Path prefix of the HTTP-01 challenges
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
 /// timeout to accept connection events in the accept queue (60 seconds)
 pub const DEFAULT_ACCEPT_QUEUE_TIMEOUT: u32 = 60;
 
@@ -166,6 +223,27 @@ pub const DEFAULT_DISABLE_CLUSTER_METRICS: bool = false;
 
 pub const MAX_LOOP_ITERATIONS: usize = 100000;
 
+/// length in seconds of the window of the request rate limit of a listener
+pub const DEFAULT_RATE_LIMIT_WINDOW: u32 = 60;
+
+/// DSCP values are 6 bits long, the 2 other bits of the TOS byte are for ECN
+pub const MAX_DSCP: u8 = 63;
+
+/// minimum max-age, in seconds, of an HSTS header for the preload lists of browsers
+pub const HSTS_PRELOAD_MIN_AGE: u64 = 31536000;
+
+/// patterns of the User-Agent of the mobile devices, for the clients that do not
+/// send the Sec-CH-UA-Mobile client hint
+pub const DEFAULT_MOBILE_USER_AGENTS: [&str; 7] = [
+    "Mobi",
+    "iPhone",
+    "iPod",
+    "BlackBerry",
+    "Opera Mini",
+    "IEMobile",
+    "Windows Phone",
+];
+
 /// Number of TLS 1.3 tickets to send to a client when establishing a connection.
 /// The tickets allow the client to resume a session. This protects the client
 /// agains session tracking. Increases the number of getrandom syscalls,
@@ -205,8 +283,66 @@ pub enum ConfigError {
         object: ObjectKind,
         id: String,
     },
+    #[error("invalid alert {name}: {reason}")]
+    InvalidAlert { name: String, reason: String },
+    #[error("invalid replication section: {0}")]
+    InvalidReplication(String),
+    #[error("invalid access log template: {0}")]
+    InvalidAccessLogTemplate(String),
+    #[error("invalid acme section: {0}")]
+    InvalidAcme(String),
+    #[error("invalid metrics section: {0}")]
+    InvalidMetrics(String),
+    #[error("invalid request rate limit for listener {address}: {reason}")]
+    InvalidRateLimit { address: SocketAddr, reason: String },
+    #[error("invalid backend pinning for listener {listener}: {reason}")]
+    InvalidBackendPinning { listener: String, reason: String },
+    #[error("invalid unknown SNI cluster for listener {listener}: {reason}")]
+    InvalidUnknownSniCluster { listener: String, reason: String },
+    #[error("invalid client authentication for listener {listener}: {reason}")]
+    InvalidClientAuthentication { listener: String, reason: String },
+    #[error(
+        "invalid duplicate header for listener {listener}: {name:?} is not a valid header name"
+    )]
+    InvalidDuplicateHeader { listener: String, name: String },
+    #[error("ipv6_only is set on listener {0}, which does not have an IPv6 address")]
+    Ipv6OnlyOnIpv4(SocketAddr),
+    #[error("invalid listener name {0:?}, it should only contain alphanumeric characters, '-', '_' and '.'")]
+    InvalidListenerName(String),
+    #[error("listener name {0:?} is already used in the configuration")]
+    ListenerNameAlreadyUsed(String),
+    #[error("no listener is named {0:?}")]
+    UnknownListener(String),
+    #[error("a frontend of cluster {0} should have either an address or a listener")]
+    FrontendListener(String),
+    #[error("invalid DSCP value {dscp} for cluster {cluster_id}, it should be at most 63")]
+    InvalidDscp { cluster_id: String, dscp: u8 },
+    #[error("invalid outlier detection for cluster {cluster_id}: {reason}")]
+    InvalidOutlierDetection { cluster_id: String, reason: String },
+    #[error("invalid health check for cluster {cluster_id}: {reason}")]
+    InvalidHealthCheck { cluster_id: String, reason: String },
+    #[error("invalid slow log for cluster {cluster_id}: {reason}")]
+    InvalidSlowLog { cluster_id: String, reason: String },
+    #[error("invalid hash key for cluster {cluster_id}: {reason}")]
+    InvalidHashKey { cluster_id: String, reason: String },
+    #[error("invalid rate limit for {owner}: {reason}")]
+    InvalidClientRateLimit { owner: String, reason: String },
+    #[error("invalid HTTPS policy for cluster {cluster_id}: {reason}")]
+    InvalidHttpsPolicy { cluster_id: String, reason: String },
+    #[error("invalid timeouts for {route}: {reason}")]
+    InvalidTimeouts { route: String, reason: String },
+    #[error("invalid mirror for {frontend}: {reason}")]
+    InvalidMirror { frontend: String, reason: String },
+    #[error("invalid traffic split for {frontend}: {reason}")]
+    InvalidSplit { frontend: String, reason: String },
+    #[error("invalid header edit for {frontend}: {reason}")]
+    InvalidHeaderEdit { frontend: String, reason: String },
+    #[error("invalid device match for {frontend}: {reason}")]
+    InvalidDeviceMatch { frontend: String, reason: String },
     #[error("Invalid '{0}' field for a TCP frontend")]
     InvalidFrontendConfig(String),
+    #[error("Invalid '{0}' field for an HTTP frontend")]
+    InvalidHttpFrontendConfig(String),
     #[error("invalid path {0:?}")]
     InvalidPath(PathBuf),
     #[error("listening address {0:?} is already used in the configuration")]
@@ -221,6 +357,11 @@ pub enum ConfigError {
     SocketPathError(String),
     #[error("toml decoding error: {0}")]
     DeserializeToml(String),
+    #[error("cluster {cluster_id} refers to an unknown template {template}")]
+    UnknownTemplate {
+        cluster_id: String,
+        template: String,
+    },
     #[error("Can not set this frontend on a {0:?} listener")]
     WrongFrontendProtocol(ListenerProtocol),
     #[error("Can not build a {expected:?} listener from a {found:?} config")]
@@ -247,10 +388,13 @@ pub struct ListenerBuilder {
     pub answer_503: Option<String>,
     pub answer_504: Option<String>,
     pub answer_507: Option<String>,
+    pub answer_429: Option<String>,
     pub tls_versions: Option<Vec<TlsVersion>>,
     pub cipher_list: Option<Vec<String>>,
     pub cipher_suites: Option<Vec<String>>,
     pub expect_proxy: Option<bool>,
+    /// accept the connections with and without a PROXY protocol header
+    pub detect_proxy: Option<bool>,
     #[serde(default = "default_sticky_name")]
     pub sticky_name: String,
     pub certificate: Option<String>,
@@ -264,12 +408,584 @@ pub struct ListenerBuilder {
     pub connect_timeout: Option<u32>,
     /// maximum time to receive a request since the connection started
     pub request_timeout: Option<u32>,
+    /// maximum time to complete the TLS handshake
+    pub handshake_timeout: Option<u32>,
     /// A [Config] to pull defaults from
     pub config: Option<Config>,
     /// Number of TLS 1.3 tickets to send to a client when establishing a connection.
     /// The ticket allow the client to resume a session. This protects the client
     /// agains session tracking. Defaults to 4.
     pub send_tls13_tickets: Option<u64>,
+    /// Accept TLS 1.3 early data (0-RTT) for idempotent requests. Defaults to false.
+    pub early_data: Option<bool>,
+    /// Offer HTTP/2 to the clients in the TLS handshake. Defaults to false.
+    pub http2: Option<bool>,
+    /// header describing what the proxy did with the request, added to the responses
+    pub proxy_status: Option<ProxyStatusHeader>,
+    /// limit of the requests of each client IP, answered with a 429 beyond it
+    pub request_rate_limit: Option<RequestRateLimitConfig>,
+    /// how the requests of HTTP/1.0 clients are treated
+    pub http10: Option<Http10Config>,
+    /// maximum time to answer a request once its headers are received
+    pub request_deadline: Option<u32>,
+    /// for an IPv6 address, accept only IPv6 connections. The system default applies if unset
+    pub ipv6_only: Option<bool>,
+    /// see IPv4 clients of a dual-stack listener with their IPv4 address. Defaults to false.
+    pub normalize_ipv4_mapped: Option<bool>,
+    /// name used to refer to the listener instead of its address, unique among the listeners
+    pub name: Option<String>,
+    /// route the requests of trusted clients to the backend named in a header
+    pub backend_pinning: Option<BackendPinningConfig>,
+    /// how the requests repeating a header are handled, by header name
+    pub duplicate_headers: Option<BTreeMap<String, DuplicateHeaderPolicy>>,
+    /// verify the certificates of the clients of an HTTPS listener
+    pub client_authentication: Option<ClientAuthenticationConfig>,
+    /// TCP cluster relaying the TLS connections whose server name has no certificate
+    pub unknown_sni_cluster: Option<String>,
+    /// maximum inactive time of the connections upgraded to WebSocket
+    pub websocket_idle_timeout: Option<u32>,
+}
+
+/// A listener name is not empty, made of alphanumeric characters, `-`, `_` and `.`, and
+/// can not be parsed as an address, so that it can be told apart from one
+pub fn is_valid_listener_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        && name.parse::<SocketAddr>().is_err()
+}
+
+/// limit of the requests of each client IP on an HTTP or HTTPS listener, as parsed
+/// from the toml
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestRateLimitConfig {
+    /// maximum number of requests of a client IP in the window
+    pub requests: u32,
+    /// length of the sliding window, in seconds
+    pub window: Option<u32>,
+    /// networks of the clients that are not limited, in CIDR notation
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+impl RequestRateLimitConfig {
+    fn to_request_rate_limit(&self, address: SocketAddr) -> Result<RequestRateLimit, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidRateLimit { address, reason };
+
+        let rate_limit = RequestRateLimit {
+            requests: self.requests,
+            window: self.window.unwrap_or(DEFAULT_RATE_LIMIT_WINDOW),
+            exempt: self.exempt.clone(),
+        };
+        if rate_limit.requests == 0 || rate_limit.window == 0 {
+            return Err(invalid(
+                "the requests and the window should be greater than 0".to_owned(),
+            ));
+        }
+        rate_limit
+            .exempt_networks()
+            .map_err(|parse_error| invalid(parse_error.to_string()))?;
+
+        Ok(rate_limit)
+    }
+}
+
+/// routing of the requests of trusted clients to the backend named in a header, on an
+/// HTTP or HTTPS listener, as parsed from the toml
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendPinningConfig {
+    /// request header holding the backend id, `X-Sozu-Backend` if unset
+    pub header: Option<String>,
+    /// networks of the clients whose header is honored, in CIDR notation
+    pub trusted: Vec<String>,
+}
+
+impl BackendPinningConfig {
+    fn to_backend_pinning(&self, address: SocketAddr) -> Result<BackendPinning, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidBackendPinning {
+            listener: address.to_string(),
+            reason,
+        };
+
+        let backend_pinning = BackendPinning {
+            header: self
+                .header
+                .clone()
+                .unwrap_or_else(|| BackendPinning::default().header),
+            trusted: self.trusted.clone(),
+        };
+        if backend_pinning.header.is_empty()
+            || !backend_pinning
+                .header
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_".contains(&b))
+        {
+            return Err(invalid(format!(
+                "{:?} is not a valid header name",
+                backend_pinning.header
+            )));
+        }
+        if backend_pinning.trusted.is_empty() {
+            return Err(invalid("at least one trusted network is needed".to_owned()));
+        }
+        backend_pinning
+            .trusted_networks()
+            .map_err(|parse_error| invalid(parse_error.to_string()))?;
+
+        Ok(backend_pinning)
+    }
+}
+
+/// verification of the client certificates of an HTTPS listener (mutual TLS), as parsed
+/// from the toml. The header names that are not set take the defaults of
+/// [`ClientCertificateHeaders`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthenticationConfig {
+    /// whether the clients must send a certificate, `REQUIRED` if unset
+    pub mode: Option<ClientAuthenticationMode>,
+    /// path to the PEM bundle of the authorities signing the client certificates
+    pub ca: String,
+    /// path to the PEM revocation lists of these authorities
+    pub crl: Option<String>,
+    /// header forwarding the subject of the client certificate
+    pub subject_header: Option<String>,
+    /// header forwarding the issuer of the client certificate
+    pub issuer_header: Option<String>,
+    /// header forwarding the serial number of the client certificate
+    pub serial_header: Option<String>,
+    /// header telling the backends whether the client sent a verified certificate
+    pub verify_header: Option<String>,
+}
+
+impl ClientAuthenticationConfig {
+    fn to_client_authentication(
+        &self,
+        address: SocketAddr,
+    ) -> Result<ClientAuthentication, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidClientAuthentication {
+            listener: address.to_string(),
+            reason,
+        };
+
+        let ca_certificates = Config::load_file(&self.ca)?;
+        if split_certificate_chain(ca_certificates.clone()).is_empty() {
+            return Err(invalid(format!("no certificate found in {}", self.ca)));
+        }
+        let crl = self.crl.as_deref().map(Config::load_file).transpose()?;
+
+        let defaults = ClientCertificateHeaders::default();
+        let headers = ClientCertificateHeaders {
+            subject: self.subject_header.clone().unwrap_or(defaults.subject),
+            issuer: self.issuer_header.clone().unwrap_or(defaults.issuer),
+            serial: self.serial_header.clone().unwrap_or(defaults.serial),
+            verify: self.verify_header.clone().unwrap_or(defaults.verify),
+        };
+        for header in [
+            &headers.subject,
+            &headers.issuer,
+            &headers.serial,
+            &headers.verify,
+        ] {
+            if header.is_empty()
+                || !header
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_".contains(&b))
+            {
+                return Err(invalid(format!("{header:?} is not a valid header name")));
+            }
+        }
+
+        Ok(ClientAuthentication {
+            mode: self.mode.unwrap_or(ClientAuthenticationMode::Required) as i32,
+            ca_certificates,
+            crl,
+            headers,
+        })
+    }
+}
+
+/// how an HTTP or HTTPS listener treats HTTP/1.0 clients, as parsed from the toml.
+/// The options that are not set take the defaults of [`Http10Options`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http10Config {
+    /// close the connection after the response, unless the client asks for keep-alive
+    pub implicit_close: Option<bool>,
+    /// keep the connection open for the clients asking for keep-alive
+    pub keep_alive: Option<bool>,
+    /// hostname used to route the requests without a Host header
+    pub default_host: Option<String>,
+}
+
+impl Http10Config {
+    fn to_http10_options(&self) -> Http10Options {
+        let defaults = Http10Options::default();
+        Http10Options {
+            implicit_close: self.implicit_close.unwrap_or(defaults.implicit_close),
+            keep_alive: self.keep_alive.unwrap_or(defaults.keep_alive),
+            default_host: self.default_host.clone(),
+        }
+    }
+}
+
+/// outlier detection of a cluster, as parsed from the toml. The options that are not
+/// set take the defaults of [`OutlierDetection`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierDetectionConfig {
+    /// length of the sliding window, in seconds
+    pub window: Option<u32>,
+    /// requests a backend must receive in the window to be evaluated
+    pub min_requests: Option<u32>,
+    /// percentage points of 5xx responses above the cluster ratio that eject a backend
+    pub error_threshold: Option<u32>,
+    /// percentage points of timeouts above the cluster ratio that eject a backend
+    pub timeout_threshold: Option<u32>,
+    /// maximum percentage of the backends ejected at once
+    pub max_ejection_percent: Option<u32>,
+    /// time an ejected backend stays out of load balancing, in seconds
+    pub ejection_time: Option<u32>,
+}
+
+impl OutlierDetectionConfig {
+    fn to_outlier_detection(&self, cluster_id: &str) -> Result<OutlierDetection, ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidOutlierDetection {
+            cluster_id: cluster_id.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        let defaults = OutlierDetection::default();
+        let outlier_detection = OutlierDetection {
+            window: self.window.unwrap_or(defaults.window),
+            min_requests: self.min_requests.unwrap_or(defaults.min_requests),
+            error_threshold: self.error_threshold.unwrap_or(defaults.error_threshold),
+            timeout_threshold: self.timeout_threshold.unwrap_or(defaults.timeout_threshold),
+            max_ejection_percent: self
+                .max_ejection_percent
+                .unwrap_or(defaults.max_ejection_percent),
+            ejection_time: self.ejection_time.unwrap_or(defaults.ejection_time),
+        };
+        if outlier_detection.window == 0 || outlier_detection.ejection_time == 0 {
+            return Err(invalid(
+                "the window and the ejection time should be greater than 0",
+            ));
+        }
+        if outlier_detection.error_threshold > 100
+            || outlier_detection.timeout_threshold > 100
+            || outlier_detection.max_ejection_percent > 100
+        {
+            return Err(invalid(
+                "the thresholds and the maximum ejection percentage should be at most 100",
+            ));
+        }
+
+        Ok(outlier_detection)
+    }
+}
+
+/// active health checks of a cluster, as parsed from the toml. The options that are
+/// not set take the defaults of [`HealthCheck`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// time between two probes of a backend, in seconds
+    pub interval: Option<u32>,
+    /// time a probe may take, in seconds
+    pub timeout: Option<u32>,
+    /// path of an HTTP GET request answered with a 2xx or 3xx status,
+    /// the probe only opens a TCP connection if unset
+    pub http_path: Option<String>,
+    /// successful probes in a row marking an unhealthy backend as healthy
+    pub healthy_threshold: Option<u32>,
+    /// failed probes in a row marking a backend as unhealthy
+    pub unhealthy_threshold: Option<u32>,
+}
+
+impl HealthCheckConfig {
+    pub fn to_health_check(&self, cluster_id: &str) -> Result<HealthCheck, ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidHealthCheck {
+            cluster_id: cluster_id.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        let defaults = HealthCheck::default();
+        let health_check = HealthCheck {
+            interval: self.interval.unwrap_or(defaults.interval),
+            timeout: self.timeout.unwrap_or(defaults.timeout),
+            http_path: self.http_path.clone(),
+            healthy_threshold: self.healthy_threshold.unwrap_or(defaults.healthy_threshold),
+            unhealthy_threshold: self
+                .unhealthy_threshold
+                .unwrap_or(defaults.unhealthy_threshold),
+        };
+        if health_check.interval == 0 || health_check.timeout == 0 {
+            return Err(invalid(
+                "the interval and the timeout should be greater than 0",
+            ));
+        }
+        if health_check.timeout > health_check.interval {
+            return Err(invalid("the timeout should not exceed the interval"));
+        }
+        if health_check.healthy_threshold == 0 || health_check.unhealthy_threshold == 0 {
+            return Err(invalid("the thresholds should be greater than 0"));
+        }
+        if let Some(path) = &health_check.http_path {
+            if !path.starts_with('/') || path.chars().any(|c| c.is_ascii_whitespace()) {
+                return Err(invalid(
+                    "the HTTP path should start with '/' and contain no whitespace",
+                ));
+            }
+        }
+
+        Ok(health_check)
+    }
+}
+
+/// log of the slow requests of a cluster, as parsed from the toml.
+/// Exactly one of `file` and `unix_socket` is set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlowLogConfig {
+    /// response time in milliseconds from which a request is logged
+    pub threshold: u32,
+    /// path of the file the records are appended to
+    pub file: Option<String>,
+    /// path of the unix datagram socket the records are sent to
+    pub unix_socket: Option<String>,
+    /// at most this many records per second and per worker (default: 10)
+    pub max_per_second: Option<u32>,
+}
+
+impl SlowLogConfig {
+    pub fn to_slow_log(&self, cluster_id: &str) -> Result<SlowLog, ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidSlowLog {
+            cluster_id: cluster_id.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        let (sink, path) = match (&self.file, &self.unix_socket) {
+            (Some(file), None) => (MirrorSink::File, file.to_owned()),
+            (None, Some(unix_socket)) => (MirrorSink::UnixSocket, unix_socket.to_owned()),
+            _ => return Err(invalid("set either file or unix_socket")),
+        };
+        let slow_log = SlowLog {
+            threshold: self.threshold,
+            sink: sink as i32,
+            path,
+            max_per_second: self
+                .max_per_second
+                .unwrap_or(SlowLog::default().max_per_second),
+        };
+        if slow_log.threshold == 0 || slow_log.max_per_second == 0 {
+            return Err(invalid(
+                "the threshold and max_per_second should be greater than 0",
+            ));
+        }
+
+        Ok(slow_log)
+    }
+}
+
+/// token bucket of each client IP of a cluster or a frontend, as parsed from the toml
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientRateLimitConfig {
+    /// rate at which the bucket of a client refills
+    pub requests_per_second: u32,
+    /// size of the bucket of a client (default: requests_per_second)
+    pub burst: Option<u32>,
+}
+
+impl ClientRateLimitConfig {
+    /// `owner` names the cluster or frontend in the errors
+    pub fn to_client_rate_limit(&self, owner: &str) -> Result<ClientRateLimit, ConfigError> {
+        if self.requests_per_second == 0 || self.burst == Some(0) {
+            return Err(ConfigError::InvalidClientRateLimit {
+                owner: owner.to_owned(),
+                reason: "requests_per_second and burst should be greater than 0".to_owned(),
+            });
+        }
+        Ok(ClientRateLimit {
+            requests_per_second: self.requests_per_second,
+            burst: self.burst,
+        })
+    }
+}
+
+/// HTTPS policy of a cluster, as parsed from the toml. The options that are not
+/// set take the defaults of [`HttpsPolicy`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpsPolicyConfig {
+    /// time in seconds during which browsers only use HTTPS for the domain
+    pub max_age: Option<u64>,
+    /// also apply the policy to the subdomains
+    pub include_subdomains: Option<bool>,
+    /// ask for the domain to be included in the HSTS preload lists
+    pub preload: Option<bool>,
+}
+
+impl HttpsPolicyConfig {
+    pub fn to_https_policy(&self, cluster_id: &str) -> Result<HttpsPolicy, ConfigError> {
+        let defaults = HttpsPolicy::default();
+        let https_policy = HttpsPolicy {
+            max_age: self.max_age.unwrap_or(defaults.max_age),
+            include_subdomains: self
+                .include_subdomains
+                .unwrap_or(defaults.include_subdomains),
+            preload: self.preload.unwrap_or(defaults.preload),
+        };
+        // the requirements of the preload lists, a header that does not meet them
+        // would be refused by the browsers
+        if https_policy.preload
+            && (!https_policy.include_subdomains || https_policy.max_age < HSTS_PRELOAD_MIN_AGE)
+        {
+            return Err(ConfigError::InvalidHttpsPolicy {
+                cluster_id: cluster_id.to_owned(),
+                reason: format!(
+                    "preload requires include_subdomains and a max_age of at least {HSTS_PRELOAD_MIN_AGE} seconds"
+                ),
+            });
+        }
+
+        Ok(https_policy)
+    }
+}
+
+/// timeouts of the requests of a cluster or a frontend, in seconds, as parsed from
+/// the toml. The timeouts that are not set are inherited
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// inactivity of the client while the request body is received
+    pub body_read: Option<u32>,
+    /// time to connect to a backend
+    pub backend_connect: Option<u32>,
+    /// inactivity of the backend while the response is awaited and received
+    pub backend_response: Option<u32>,
+    /// maximum time to answer a request once its headers are received
+    pub request_deadline: Option<u32>,
+}
+
+impl TimeoutsConfig {
+    /// `route` describes the cluster or frontend in the errors
+    pub fn to_timeouts(&self, route: &str) -> Result<Timeouts, ConfigError> {
+        let timeouts = Timeouts {
+            body_read: self.body_read,
+            backend_connect: self.backend_connect,
+            backend_response: self.backend_response,
+            request_deadline: self.request_deadline,
+        };
+        // a timeout of 0 would expire right away, leave it unset to inherit instead
+        if let Some((name, _)) = timeouts
+            .named()
+            .into_iter()
+            .find(|(_, timeout)| *timeout == Some(0))
+        {
+            return Err(ConfigError::InvalidTimeouts {
+                route: route.to_owned(),
+                reason: format!("{name} should be at least 1 second"),
+            });
+        }
+        Ok(timeouts)
+    }
+}
+
+/// copy of a sample of the raw requests of a frontend, as parsed from the toml.
+/// Exactly one of `file` and `unix_socket` is set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestMirrorConfig {
+    /// path of the file the records are appended to
+    pub file: Option<String>,
+    /// path of the unix datagram socket the records are sent to
+    pub unix_socket: Option<String>,
+    /// mirror one request in this many (default: 100)
+    pub sample_one_in: Option<u32>,
+    /// at most this many records per second and per worker (default: 10)
+    pub max_per_second: Option<u32>,
+    /// bytes of the request body copied after the headers (default: 0)
+    pub max_body_prefix: Option<u32>,
+}
+
+impl RequestMirrorConfig {
+    /// `frontend` describes the frontend in the errors
+    pub fn to_request_mirror(&self, frontend: &str) -> Result<RequestMirror, ConfigError> {
+        let (sink, path) = match (&self.file, &self.unix_socket) {
+            (Some(file), None) => (MirrorSink::File, file.to_owned()),
+            (None, Some(unix_socket)) => (MirrorSink::UnixSocket, unix_socket.to_owned()),
+            _ => {
+                return Err(ConfigError::InvalidMirror {
+                    frontend: frontend.to_owned(),
+                    reason: "set either file or unix_socket".to_owned(),
+                })
+            }
+        };
+        let defaults = RequestMirror::default();
+        let mirror = RequestMirror {
+            sink: sink as i32,
+            path,
+            sample_one_in: self.sample_one_in.unwrap_or(defaults.sample_one_in),
+            max_per_second: self.max_per_second.unwrap_or(defaults.max_per_second),
+            max_body_prefix: self.max_body_prefix.unwrap_or(defaults.max_body_prefix),
+        };
+        mirror
+            .validate()
+            .map_err(|error| ConfigError::InvalidMirror {
+                frontend: frontend.to_owned(),
+                reason: error.to_string(),
+            })?;
+        Ok(mirror)
+    }
+}
+
+/// edits of the request or response headers of a frontend, as parsed from the toml.
+/// They are applied in this order: remove, set, add
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderEditsConfig {
+    /// names of the headers to remove
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// name -> value of the headers replacing the ones with the same name
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    /// name -> value of the headers added to the existing ones
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+}
+
+impl HeaderEditsConfig {
+    /// `frontend` describes the frontend in the errors
+    pub fn to_header_edits(
+        &self,
+        position: HeaderPosition,
+        frontend: &str,
+    ) -> Result<Vec<HeaderEdit>, ConfigError> {
+        let edits: Vec<HeaderEdit> =
+            self.remove
+                .iter()
+                .map(|key| HeaderEdit::new(position, HeaderEditKind::Remove, key, None))
+                .chain(self.set.iter().map(|(key, val)| {
+                    HeaderEdit::new(position, HeaderEditKind::Set, key, Some(val))
+                }))
+                .chain(self.add.iter().map(|(key, val)| {
+                    HeaderEdit::new(position, HeaderEditKind::Add, key, Some(val))
+                }))
+                .collect();
+        for edit in &edits {
+            edit.validate()
+                .map_err(|error| ConfigError::InvalidHeaderEdit {
+                    frontend: frontend.to_owned(),
+                    reason: error.to_string(),
+                })?;
+        }
+        Ok(edits)
+    }
 }
 
 pub fn default_sticky_name() -> String {
@@ -309,22 +1025,39 @@ impl ListenerBuilder {
             answer_503: None,
             answer_504: None,
             answer_507: None,
+            answer_429: None,
             back_timeout: None,
+            backend_pinning: None,
+            duplicate_headers: None,
+            client_authentication: None,
+            unknown_sni_cluster: None,
             certificate_chain: None,
             certificate: None,
             cipher_list: None,
             cipher_suites: None,
             config: None,
             connect_timeout: None,
+            early_data: None,
             expect_proxy: None,
+            detect_proxy: None,
             front_timeout: None,
+            handshake_timeout: None,
+            http10: None,
+            http2: None,
+            ipv6_only: None,
             key: None,
+            name: None,
+            normalize_ipv4_mapped: None,
             protocol: Some(protocol),
+            proxy_status: None,
             public_address: None,
+            request_deadline: None,
+            request_rate_limit: None,
             request_timeout: None,
             send_tls13_tickets: None,
             sticky_name: DEFAULT_STICKY_NAME.to_string(),
             tls_versions: None,
+            websocket_idle_timeout: None,
         }
     }
 
@@ -375,6 +1108,96 @@ impl ListenerBuilder {
         self
     }
 
+    pub fn with_detect_proxy(&mut self, detect_proxy: bool) -> &mut Self {
+        self.detect_proxy = Some(detect_proxy);
+        self
+    }
+
+    pub fn with_early_data(&mut self, early_data: bool) -> &mut Self {
+        self.early_data = Some(early_data);
+        self
+    }
+
+    pub fn with_http2(&mut self, http2: bool) -> &mut Self {
+        self.http2 = Some(http2);
+        self
+    }
+
+    pub fn with_ipv6_only(&mut self, ipv6_only: Option<bool>) -> &mut Self {
+        self.ipv6_only = ipv6_only;
+        self
+    }
+
+    pub fn with_normalize_ipv4_mapped(&mut self, normalize_ipv4_mapped: bool) -> &mut Self {
+        self.normalize_ipv4_mapped = Some(normalize_ipv4_mapped);
+        self
+    }
+
+    pub fn with_name(&mut self, name: Option<String>) -> &mut Self {
+        self.name = name;
+        self
+    }
+
+    pub fn with_proxy_status(&mut self, proxy_status: Option<ProxyStatusHeader>) -> &mut Self {
+        self.proxy_status = proxy_status;
+        self
+    }
+
+    pub fn with_request_rate_limit(
+        &mut self,
+        request_rate_limit: Option<RequestRateLimitConfig>,
+    ) -> &mut Self {
+        self.request_rate_limit = request_rate_limit;
+        self
+    }
+
+    pub fn with_backend_pinning(
+        &mut self,
+        backend_pinning: Option<BackendPinningConfig>,
+    ) -> &mut Self {
+        self.backend_pinning = backend_pinning;
+        self
+    }
+
+    pub fn with_client_authentication(
+        &mut self,
+        client_authentication: Option<ClientAuthenticationConfig>,
+    ) -> &mut Self {
+        self.client_authentication = client_authentication;
+        self
+    }
+
+    pub fn with_unknown_sni_cluster(&mut self, unknown_sni_cluster: Option<String>) -> &mut Self {
+        self.unknown_sni_cluster = unknown_sni_cluster;
+        self
+    }
+
+    pub fn with_duplicate_headers(
+        &mut self,
+        duplicate_headers: Option<BTreeMap<String, DuplicateHeaderPolicy>>,
+    ) -> &mut Self {
+        self.duplicate_headers = duplicate_headers;
+        self
+    }
+
+    pub fn with_http10(&mut self, http10: Option<Http10Config>) -> &mut Self {
+        self.http10 = http10;
+        self
+    }
+
+    pub fn with_request_deadline(&mut self, request_deadline: Option<u32>) -> &mut Self {
+        self.request_deadline = request_deadline;
+        self
+    }
+
+    pub fn with_websocket_idle_timeout(
+        &mut self,
+        websocket_idle_timeout: Option<u32>,
+    ) -> &mut Self {
+        self.websocket_idle_timeout = websocket_idle_timeout;
+        self
+    }
+
     pub fn with_sticky_name<S>(&mut self, sticky_name: Option<S>) -> &mut Self
     where
         S: ToString,
@@ -426,6 +1249,11 @@ impl ListenerBuilder {
         self
     }
 
+    pub fn with_handshake_timeout(&mut self, handshake_timeout: Option<u32>) -> &mut Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
     /// Get the custom HTTP answers from the file system using the provided paths
     fn get_http_answers(&self) -> Result<Option<CustomHttpAnswers>, ConfigError> {
         let http_answers = CustomHttpAnswers {
@@ -439,10 +1267,90 @@ impl ListenerBuilder {
             answer_503: read_http_answer_file(&self.answer_503)?,
             answer_504: read_http_answer_file(&self.answer_504)?,
             answer_507: read_http_answer_file(&self.answer_507)?,
+            answer_429: read_http_answer_file(&self.answer_429)?,
         };
         Ok(Some(http_answers))
     }
 
+    fn get_request_deadline(&self) -> Result<Option<u32>, ConfigError> {
+        if self.request_deadline == Some(0) {
+            return Err(ConfigError::InvalidTimeouts {
+                route: format!("listener {}", self.address),
+                reason: "request_deadline should be at least 1 second".to_owned(),
+            });
+        }
+        Ok(self.request_deadline)
+    }
+
+    fn get_websocket_idle_timeout(&self) -> Result<Option<u32>, ConfigError> {
+        if self.websocket_idle_timeout == Some(0) {
+            return Err(ConfigError::InvalidTimeouts {
+                route: format!("listener {}", self.address),
+                reason: "websocket_idle_timeout should be at least 1 second".to_owned(),
+            });
+        }
+        Ok(self.websocket_idle_timeout)
+    }
+
+    fn get_name(&self) -> Result<Option<String>, ConfigError> {
+        match &self.name {
+            Some(name) if !is_valid_listener_name(name) => {
+                Err(ConfigError::InvalidListenerName(name.to_owned()))
+            }
+            name => Ok(name.clone()),
+        }
+    }
+
+    fn get_ipv6_only(&self) -> Result<Option<bool>, ConfigError> {
+        if self.ipv6_only.is_some() && !self.address.is_ipv6() {
+            return Err(ConfigError::Ipv6OnlyOnIpv4(self.address));
+        }
+        Ok(self.ipv6_only)
+    }
+
+    fn get_request_rate_limit(&self) -> Result<Option<RequestRateLimit>, ConfigError> {
+        self.request_rate_limit
+            .as_ref()
+            .map(|rate_limit| rate_limit.to_request_rate_limit(self.address))
+            .transpose()
+    }
+
+    fn get_backend_pinning(&self) -> Result<Option<BackendPinning>, ConfigError> {
+        self.backend_pinning
+            .as_ref()
+            .map(|backend_pinning| backend_pinning.to_backend_pinning(self.address))
+            .transpose()
+    }
+
+    /// the client authentication, with the authorities and revocation lists read from their files
+    fn get_client_authentication(&self) -> Result<Option<ClientAuthentication>, ConfigError> {
+        self.client_authentication
+            .as_ref()
+            .map(|client_authentication| {
+                client_authentication.to_client_authentication(self.address)
+            })
+            .transpose()
+    }
+
+    /// the policies of the repeated headers, with their names in lower case
+    fn get_duplicate_headers(&self) -> Result<Vec<DuplicateHeader>, ConfigError> {
+        let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        let mut duplicate_headers = Vec::new();
+        for (name, policy) in self.duplicate_headers.iter().flatten() {
+            if name.is_empty() || !name.chars().all(is_token) {
+                return Err(ConfigError::InvalidDuplicateHeader {
+                    listener: self.address.to_string(),
+                    name: name.to_owned(),
+                });
+            }
+            duplicate_headers.push(DuplicateHeader {
+                name: name.to_lowercase(),
+                policy: *policy as i32,
+            });
+        }
+        Ok(duplicate_headers)
+    }
+
     /// Assign the timeouts of the config to this listener, only if timeouts did not exist
     fn assign_config_timeouts(&mut self, config: &Config) {
         self.front_timeout = Some(self.front_timeout.unwrap_or(config.front_timeout));
@@ -465,17 +1373,30 @@ impl ListenerBuilder {
         }
 
         let http_answers = self.get_http_answers()?;
+        let request_rate_limit = self.get_request_rate_limit()?;
+        let request_deadline = self.get_request_deadline()?;
 
         let configuration = HttpListenerConfig {
             address: self.address.into(),
             public_address: self.public_address.map(|a| a.into()),
             expect_proxy: self.expect_proxy.unwrap_or(false),
+            detect_proxy: self.detect_proxy.unwrap_or(false),
             sticky_name: self.sticky_name.clone(),
             front_timeout: self.front_timeout.unwrap_or(DEFAULT_FRONT_TIMEOUT),
             back_timeout: self.back_timeout.unwrap_or(DEFAULT_BACK_TIMEOUT),
             connect_timeout: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
             request_timeout: self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
             http_answers,
+            proxy_status: self.proxy_status.map(|header| header as i32),
+            request_rate_limit,
+            http10: self.http10.as_ref().map(Http10Config::to_http10_options),
+            request_deadline,
+            ipv6_only: self.get_ipv6_only()?,
+            normalize_ipv4_mapped: self.normalize_ipv4_mapped.unwrap_or(false),
+            name: self.get_name()?,
+            backend_pinning: self.get_backend_pinning()?,
+            duplicate_headers: self.get_duplicate_headers()?,
+            websocket_idle_timeout: self.get_websocket_idle_timeout()?,
             ..Default::default()
         };
 
@@ -548,6 +1469,7 @@ impl ListenerBuilder {
             .unwrap_or_default();
 
         let http_answers = self.get_http_answers()?;
+        let request_rate_limit = self.get_request_rate_limit()?;
 
         if let Some(config) = config {
             self.assign_config_timeouts(config);
@@ -560,6 +1482,7 @@ impl ListenerBuilder {
             cipher_list,
             versions,
             expect_proxy: self.expect_proxy.unwrap_or(false),
+            detect_proxy: self.detect_proxy.unwrap_or(false),
             key,
             certificate,
             certificate_chain,
@@ -575,6 +1498,21 @@ impl ListenerBuilder {
                 .send_tls13_tickets
                 .unwrap_or(DEFAULT_SEND_TLS_13_TICKETS),
             http_answers,
+            early_data: self.early_data.unwrap_or(false),
+            handshake_timeout: self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT),
+            proxy_status: self.proxy_status.map(|header| header as i32),
+            request_rate_limit,
+            http10: self.http10.as_ref().map(Http10Config::to_http10_options),
+            request_deadline: self.get_request_deadline()?,
+            http2: self.http2.unwrap_or(false),
+            ipv6_only: self.get_ipv6_only()?,
+            normalize_ipv4_mapped: self.normalize_ipv4_mapped.unwrap_or(false),
+            name: self.get_name()?,
+            backend_pinning: self.get_backend_pinning()?,
+            duplicate_headers: self.get_duplicate_headers()?,
+            client_authentication: self.get_client_authentication()?,
+            unknown_sni_cluster: self.unknown_sni_cluster.clone(),
+            websocket_idle_timeout: self.get_websocket_idle_timeout()?,
         };
 
         Ok(https_listener_config)
@@ -601,12 +1539,16 @@ impl ListenerBuilder {
             back_timeout: self.back_timeout.unwrap_or(DEFAULT_BACK_TIMEOUT),
             connect_timeout: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
             active: false,
+            ipv6_only: self.get_ipv6_only()?,
+            normalize_ipv4_mapped: self.normalize_ipv4_mapped.unwrap_or(false),
+            name: self.get_name()?,
         })
     }
 }
 
 /// read a custom HTTP answer from a file
-fn read_http_answer_file(path: &Option<String>) -> Result<Option<String>, ConfigError> {
+/// read the content of a custom HTTP answer, if a path is given
+pub fn read_http_answer_file(path: &Option<String>) -> Result<Option<String>, ConfigError> {
     match path {
         Some(path) => {
             let mut content = String::new();
@@ -630,12 +1572,316 @@ fn read_http_answer_file(path: &Option<String>) -> Result<Option<String>, Config
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct MetricsConfig {
-    pub address: SocketAddr,
+    /// statsd server the metrics are sent to
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
     #[serde(default)]
     pub tagged_metrics: bool,
     #[serde(default)]
     pub prefix: Option<String>,
-}
+    /// address of the HTTP endpoint of the main process, scraped by Prometheus
+    #[serde(default)]
+    pub prometheus_address: Option<SocketAddr>,
+    /// Prometheus remote write endpoint the main process pushes the metrics to,
+    /// like "https://mimir.example.com/api/v1/push"
+    #[serde(default)]
+    pub remote_write_url: Option<String>,
+    /// seconds between two pushes to the remote write endpoint
+    #[serde(default = "default_remote_write_interval")]
+    pub remote_write_interval: u64,
+    /// pushes kept while the remote write endpoint is unreachable, the oldest are dropped beyond
+    #[serde(default = "default_remote_write_queue_size")]
+    pub remote_write_queue_size: usize,
+    /// sent in the X-Scope-OrgID header, for the multi-tenant receivers like Mimir
+    #[serde(default)]
+    pub remote_write_tenant: Option<String>,
+    /// path to the root certificates trusted to reach the remote write endpoint, in PEM.
+    /// Defaults to the bundle of the system
+    #[serde(default)]
+    pub remote_write_ca_file: Option<String>,
+}
+
+fn default_remote_write_interval() -> u64 {
+    DEFAULT_REMOTE_WRITE_INTERVAL
+}
+
+fn default_remote_write_queue_size() -> usize {
+    DEFAULT_REMOTE_WRITE_QUEUE_SIZE
+}
+
+impl MetricsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::InvalidMetrics(reason.to_owned()));
+
+        let Some(url) = &self.remote_write_url else {
+            return Ok(());
+        };
+        let Some(rest) = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+        else {
+            return invalid("the remote write URL must be a http:// or https:// URL");
+        };
+        if rest.split('/').next().unwrap_or_default().is_empty() {
+            return invalid("the remote write URL has no host");
+        }
+        if self.remote_write_interval == 0 {
+            return invalid("the remote write interval must be at least one second");
+        }
+        if self.remote_write_queue_size == 0 {
+            return invalid("the remote write queue must hold at least one push");
+        }
+        Ok(())
+    }
+}
+
+/// the measure an alert rule watches, per cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// percentage of responses with a 5xx status, since the previous evaluation
+    ErrorRate,
+    /// 99th percentile of the backend response time, in milliseconds
+    P99Latency,
+    /// percentage of the backends of the cluster that are not marked as down.
+    /// This alert fires when the value goes below the threshold
+    BackendAvailability,
+}
+
+impl AlertMetric {
+    /// true if the alert fires when the value goes below the threshold
+    pub fn fires_below(&self) -> bool {
+        *self == AlertMetric::BackendAvailability
+    }
+}
+
+/// A threshold rule evaluated by the main process, as parsed from the `alerts` section
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub name: String,
+    pub metric: AlertMetric,
+    /// the rule applies to every cluster if not set
+    #[serde(default)]
+    pub cluster_id: Option<String>,
+    pub threshold: u64,
+    /// the value the measure must get back to for the alert to be resolved,
+    /// defaults to the threshold
+    #[serde(default)]
+    pub recover_threshold: Option<u64>,
+    /// plain HTTP URL that receives a POST with the event, in JSON
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+impl AlertConfig {
+    pub fn recover_threshold(&self) -> u64 {
+        self.recover_threshold.unwrap_or(self.threshold)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidAlert {
+            name: self.name.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        if self.metric != AlertMetric::P99Latency && self.threshold > 100 {
+            return Err(invalid("the threshold is a percentage"));
+        }
+        let recover_threshold = self.recover_threshold();
+        if self.metric.fires_below() && recover_threshold < self.threshold {
+            return Err(invalid(
+                "the recover threshold must be higher than the threshold",
+            ));
+        }
+        if !self.metric.fires_below() && recover_threshold > self.threshold {
+            return Err(invalid(
+                "the recover threshold must be lower than the threshold",
+            ));
+        }
+        if let Some(webhook) = &self.webhook {
+            if !webhook.starts_with("http://") {
+                return Err(invalid("the webhook must be a http:// URL"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replication of the state between main processes, as parsed from the `replication` section.
+/// The primary accepts stand-by peers on `listen_address`, a stand-by connects to
+/// `primary_address`. Both sides authenticate with certificates signed by `ca_certificate`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// on the primary, the address the stand-by peers connect to
+    #[serde(default)]
+    pub listen_address: Option<SocketAddr>,
+    /// on a stand-by peer, the address of the primary
+    #[serde(default)]
+    pub primary_address: Option<SocketAddr>,
+    /// on a stand-by peer, the name the certificate of the primary is issued for
+    #[serde(default)]
+    pub primary_name: Option<String>,
+    /// path to the certificate presented to the other peers, in PEM
+    pub certificate: String,
+    /// path to the key of the certificate, in PEM
+    pub key: String,
+    /// path to the authority that signs the certificates of all peers, in PEM
+    pub ca_certificate: String,
+}
+
+impl ReplicationConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidReplication(reason.to_owned());
+
+        match (self.listen_address, self.primary_address) {
+            (None, None) => Err(invalid(
+                "set listen_address on the primary, or primary_address on a stand-by peer",
+            )),
+            (Some(_), Some(_)) => Err(invalid(
+                "a main process is either the primary or a stand-by peer, not both",
+            )),
+            (None, Some(_)) if self.primary_name.is_none() => Err(invalid(
+                "primary_name is needed to check the certificate of the primary",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Separate process accepting the clients of the command socket, as parsed from the
+/// `command_broker` section. It decodes their requests and relays them to the main
+/// process, without holding the listeners, the workers or the state, so that a flaw
+/// in the decoding does not reach them
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandBrokerConfig {
+    /// user the broker switches to, when Sōzu is started as root
+    #[serde(default)]
+    pub user: Option<String>,
+    /// group the broker switches to, the primary group of `user` if not set
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// gRPC endpoint of the main process, as parsed from the `grpc` section. It accepts
+/// the same requests as the unix socket, from the clients presenting a certificate
+/// signed by `ca_certificate`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// address the gRPC server listens on
+    pub address: SocketAddr,
+    /// path to the certificate of the server, in PEM
+    pub certificate: String,
+    /// path to the key of the certificate, in PEM
+    pub key: String,
+    /// path to the authority that signs the certificates of the clients, in PEM
+    pub ca_certificate: String,
+}
+
+/// Gating of the listeners at startup, as parsed from the `readiness` section.
+/// The listeners are activated once all workers acknowledged the configuration and
+/// the saved state, and each critical cluster has a backend accepting connections,
+/// or once `timeout` seconds have passed
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadinessConfig {
+    /// clusters that need at least one live backend before the listeners are activated,
+    /// defined in the configuration or in the saved state
+    #[serde(default)]
+    pub critical_clusters: Vec<String>,
+    /// seconds after which the listeners are activated, even if the proxy is not ready
+    #[serde(default = "default_readiness_timeout")]
+    pub timeout: u64,
+}
+
+fn default_readiness_timeout() -> u64 {
+    DEFAULT_READINESS_TIMEOUT
+}
+
+/// Certificates ordered and renewed by the main process from an ACME server, as
+/// parsed from the `acme` section. The HTTP-01 challenges are answered by the main
+/// process, through a route it adds on `http_listener` for the names of each domain
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// URL of the directory of the ACME server
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    /// contact of the account, like "mailto:admin@example.com"
+    #[serde(default)]
+    pub contact: Option<String>,
+    /// directory keeping the key of the account, and the certificates with their keys
+    pub storage: String,
+    /// HTTP listener receiving the challenges of the ACME server
+    pub http_listener: SocketAddr,
+    /// local address where the main process answers the challenges, a free port if not set
+    #[serde(default)]
+    pub challenge_address: Option<SocketAddr>,
+    /// certificates are renewed this many days before they expire
+    #[serde(default = "default_acme_renew_before")]
+    pub renew_before: u32,
+    /// path to the root certificates trusted to reach the ACME server, in PEM.
+    /// Defaults to the bundle of the system
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    #[serde(default)]
+    pub domains: Vec<AcmeDomainConfig>,
+}
+
+/// A certificate ordered through ACME
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeDomainConfig {
+    /// names of the certificate, the first one names its files in the storage
+    pub names: Vec<String>,
+    /// HTTPS listener the certificate is installed on
+    pub https_listener: SocketAddr,
+}
+
+fn default_acme_directory() -> String {
+    DEFAULT_ACME_DIRECTORY.to_owned()
+}
+
+fn default_acme_renew_before() -> u32 {
+    DEFAULT_ACME_RENEW_BEFORE
+}
+
+impl AcmeConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| Err(ConfigError::InvalidAcme(reason));
+
+        if !self.directory.starts_with("https://") && !self.directory.starts_with("http://") {
+            return invalid(format!("the directory {} is not a URL", self.directory));
+        }
+        let mut names = HashSet::new();
+        for domain in &self.domains {
+            if domain.names.is_empty() {
+                return invalid("a domain has no names".to_owned());
+            }
+            for name in &domain.names {
+                if name.starts_with("*.") {
+                    return invalid(format!(
+                        "{name} is a wildcard, it cannot be validated with an HTTP challenge"
+                    ));
+                }
+                if !names.insert(name) {
+                    return invalid(format!("{name} is in several domains"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the domain with this name, if any
+    pub fn domain(&self, name: &str) -> Option<&AcmeDomainConfig> {
+        self.domains
+            .iter()
+            .find(|domain| domain.names.iter().any(|n| n == name))
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
@@ -649,7 +1895,12 @@ pub enum PathRuleType {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct FileClusterFrontendConfig {
-    pub address: SocketAddr,
+    /// address of the listener, mandatory unless `listener` is set
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+    /// name of the listener, instead of its address
+    #[serde(default)]
+    pub listener: Option<String>,
     pub hostname: Option<String>,
     /// creates a path routing rule where the request URL path has to match this
     pub path: Option<String>,
@@ -664,9 +1915,69 @@ pub struct FileClusterFrontendConfig {
     #[serde(default)]
     pub position: RulePosition,
     pub tags: Option<BTreeMap<String, String>>,
+    /// only match the HTTPS clients that negotiated one of these TLS versions
+    #[serde(default)]
+    pub client_tls_versions: Vec<TlsVersion>,
+    /// only match the HTTPS clients that negotiated one of these cipher suites
+    #[serde(default)]
+    pub client_cipher_suites: Vec<String>,
+    /// timeouts of the requests of the frontend, overriding the ones of the cluster
+    #[serde(default)]
+    pub timeouts: Option<TimeoutsConfig>,
+    /// copy a sample of the raw requests of the frontend to a local sink
+    #[serde(default)]
+    pub mirror: Option<RequestMirrorConfig>,
+    /// split the requests between several clusters, including this one
+    #[serde(default)]
+    pub split: Vec<WeightedCluster>,
+    /// edits of the headers of the requests sent to the backends
+    #[serde(default)]
+    pub request_headers: Option<HeaderEditsConfig>,
+    /// edits of the headers of the responses sent to the clients
+    #[serde(default)]
+    pub response_headers: Option<HeaderEditsConfig>,
+    /// TCP frontends only: route the TLS connections asking for this server name
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// only match the requests of this class of devices
+    #[serde(default)]
+    pub device: Option<DeviceClass>,
+    /// regular expressions matching the User-Agent of the mobile devices,
+    /// replacing the default ones
+    #[serde(default)]
+    pub mobile_user_agents: Vec<String>,
+    /// limit the requests of each client IP to the frontend
+    #[serde(default)]
+    pub rate_limit: Option<ClientRateLimitConfig>,
 }
 
 impl FileClusterFrontendConfig {
+    /// the address of the listener, once the listener name is resolved
+    fn address(&self) -> Result<SocketAddr, ConfigError> {
+        self.address.ok_or(ConfigError::Missing(MissingKind::Field(
+            "address".to_string(),
+        )))
+    }
+
+    /// replace the listener name with the address of the listener
+    fn resolve_listener(
+        &mut self,
+        cluster_id: &str,
+        listener_names: &HashMap<String, SocketAddr>,
+    ) -> Result<(), ConfigError> {
+        match (&self.address, self.listener.take()) {
+            (Some(_), None) => Ok(()),
+            (None, Some(name)) => match listener_names.get(&name) {
+                Some(address) => {
+                    self.address = Some(*address);
+                    Ok(())
+                }
+                None => Err(ConfigError::UnknownListener(name)),
+            },
+            _ => Err(ConfigError::FrontendListener(cluster_id.to_owned())),
+        }
+    }
+
     pub fn to_tcp_front(&self) -> Result<TcpFrontendConfig, ConfigError> {
         if self.hostname.is_some() {
             return Err(ConfigError::InvalidFrontendConfig("hostname".to_string()));
@@ -689,14 +2000,48 @@ impl FileClusterFrontendConfig {
                 "certificate_chain".to_string(),
             ));
         }
+        if self.timeouts.is_some() {
+            return Err(ConfigError::InvalidFrontendConfig("timeouts".to_string()));
+        }
+        if self.mirror.is_some() {
+            return Err(ConfigError::InvalidFrontendConfig("mirror".to_string()));
+        }
+        if !self.split.is_empty() {
+            return Err(ConfigError::InvalidFrontendConfig("split".to_string()));
+        }
+        if self.request_headers.is_some() {
+            return Err(ConfigError::InvalidFrontendConfig(
+                "request_headers".to_string(),
+            ));
+        }
+        if self.response_headers.is_some() {
+            return Err(ConfigError::InvalidFrontendConfig(
+                "response_headers".to_string(),
+            ));
+        }
+        if self.device.is_some() {
+            return Err(ConfigError::InvalidFrontendConfig("device".to_string()));
+        }
+        if !self.mobile_user_agents.is_empty() {
+            return Err(ConfigError::InvalidFrontendConfig(
+                "mobile_user_agents".to_string(),
+            ));
+        }
+        if self.rate_limit.is_some() {
+            return Err(ConfigError::InvalidFrontendConfig("rate_limit".to_string()));
+        }
 
         Ok(TcpFrontendConfig {
-            address: self.address,
+            address: self.address()?,
             tags: self.tags.clone(),
+            sni: self.sni.clone(),
         })
     }
 
-    pub fn to_http_front(&self, _cluster_id: &str) -> Result<HttpFrontendConfig, ConfigError> {
+    pub fn to_http_front(&self, cluster_id: &str) -> Result<HttpFrontendConfig, ConfigError> {
+        if self.sni.is_some() {
+            return Err(ConfigError::InvalidHttpFrontendConfig("sni".to_string()));
+        }
         let hostname = match &self.hostname {
             Some(hostname) => hostname.to_owned(),
             None => {
@@ -738,8 +2083,60 @@ impl FileClusterFrontendConfig {
             (Some(s), None) => PathRule::prefix(s.clone()),
         };
 
+        let timeouts = self
+            .timeouts
+            .as_ref()
+            .map(|timeouts| {
+                timeouts.to_timeouts(&format!("frontend {hostname} of cluster {cluster_id}"))
+            })
+            .transpose()?;
+
+        let mirror = self
+            .mirror
+            .as_ref()
+            .map(|mirror| {
+                mirror.to_request_mirror(&format!("frontend {hostname} of cluster {cluster_id}"))
+            })
+            .transpose()?;
+
+        let frontend = format!("frontend {hostname} of cluster {cluster_id}");
+        let mut headers = Vec::new();
+        if let Some(edits) = &self.request_headers {
+            headers.extend(edits.to_header_edits(HeaderPosition::Request, &frontend)?);
+        }
+        if let Some(edits) = &self.response_headers {
+            headers.extend(edits.to_header_edits(HeaderPosition::Response, &frontend)?);
+        }
+
+        validate_split(Some(cluster_id), &self.split).map_err(|error| {
+            ConfigError::InvalidSplit {
+                frontend: frontend.to_owned(),
+                reason: error.to_string(),
+            }
+        })?;
+
+        let device = match self.device {
+            Some(class) => Some(DeviceMatch {
+                class: class as i32,
+                mobile_user_agents: self.mobile_user_agents.clone(),
+            }),
+            None if !self.mobile_user_agents.is_empty() => {
+                return Err(ConfigError::InvalidDeviceMatch {
+                    frontend,
+                    reason: "mobile_user_agents needs a device class".to_owned(),
+                })
+            }
+            None => None,
+        };
+
+        let rate_limit = self
+            .rate_limit
+            .as_ref()
+            .map(|rate_limit| rate_limit.to_client_rate_limit(&frontend))
+            .transpose()?;
+
         Ok(HttpFrontendConfig {
-            address: self.address,
+            address: self.address()?,
             hostname,
             certificate: certificate_opt,
             key: key_opt,
@@ -749,6 +2146,14 @@ impl FileClusterFrontendConfig {
             path,
             method: self.method.clone(),
             tags: self.tags.clone(),
+            client_tls_versions: self.client_tls_versions.clone(),
+            client_cipher_suites: self.client_cipher_suites.clone(),
+            timeouts,
+            mirror,
+            split: self.split.clone(),
+            headers,
+            device,
+            rate_limit,
         })
     }
 }
@@ -773,16 +2178,140 @@ pub enum FileClusterProtocolConfig {
 pub struct FileClusterConfig {
     pub frontends: Vec<FileClusterFrontendConfig>,
     pub backends: Vec<BackendConfig>,
-    pub protocol: FileClusterProtocolConfig,
+    /// name of a template in the `cluster_templates` section to inherit options from
+    #[serde(default)]
+    pub template: Option<String>,
+    /// mandatory, unless provided by the template
+    #[serde(default)]
+    pub protocol: Option<FileClusterProtocolConfig>,
     pub sticky_session: Option<bool>,
     pub https_redirect: Option<bool>,
     #[serde(default)]
     pub send_proxy: Option<bool>,
     #[serde(default)]
-    pub load_balancing: LoadBalancingAlgorithms,
+    pub load_balancing: Option<LoadBalancingAlgorithms>,
+    pub answer_503: Option<String>,
+    #[serde(default)]
+    pub load_metric: Option<LoadMetric>,
+    /// local address used to connect to the backends
+    #[serde(default)]
+    pub source_address: Option<IpAddr>,
+    /// connect to the backends from the IP address of the client
+    #[serde(default)]
+    pub transparent: Option<bool>,
+    /// maximum size in bytes of the request headers sent to the backends
+    #[serde(default)]
+    pub max_request_header_size: Option<u32>,
+    /// time in microseconds the proxy may spend in header edits and filters
+    #[serde(default)]
+    pub filter_time_budget: Option<u64>,
+    /// share the backend chosen for each client IP between workers
+    #[serde(default)]
+    pub sticky_table: Option<bool>,
+    /// DNS SRV record listing the backends, resolved periodically by the main process
+    #[serde(default)]
+    pub backend_srv_record: Option<String>,
+    /// DSCP value of the packets sent to the backends
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// also mark the packets sent to the clients with the DSCP value
+    #[serde(default)]
+    pub dscp_on_clients: Option<bool>,
+    /// eject the backends with more errors or timeouts than the rest of the cluster
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    /// probe the backends and remove the failing ones from load balancing
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    /// redirect HTTP requests to HTTPS and send HSTS on HTTPS responses
+    #[serde(default)]
+    pub https_policy: Option<HttpsPolicyConfig>,
+    /// timeouts of the requests of the cluster, overriding the ones of the listeners
+    #[serde(default)]
+    pub timeouts: Option<TimeoutsConfig>,
+    /// maximum size in bytes of the response bodies sent by the backends
+    #[serde(default)]
+    pub max_response_body_size: Option<u64>,
+    /// delay in milliseconds the response bodies may be held to coalesce their writes
+    #[serde(default)]
+    pub response_flush_delay: Option<u32>,
+    /// log the requests slower than a threshold to a dedicated sink
+    #[serde(default)]
+    pub slow_log: Option<SlowLogConfig>,
+    /// limit the requests of each client IP to the cluster
+    #[serde(default)]
+    pub rate_limit: Option<ClientRateLimitConfig>,
+    /// what the consistent hash load balancing hashes: `source_ip`, `header:<name>`
+    /// or `cookie:<name>`
+    #[serde(default)]
+    pub hash_key: Option<String>,
+}
+
+/// Cluster options shared by several clusters, as parsed from the `cluster_templates`
+/// section of the toml. Options set on a cluster override the ones of its template.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileClusterTemplate {
+    #[serde(default)]
+    pub protocol: Option<FileClusterProtocolConfig>,
+    pub sticky_session: Option<bool>,
+    pub https_redirect: Option<bool>,
+    #[serde(default)]
+    pub send_proxy: Option<bool>,
+    #[serde(default)]
+    pub load_balancing: Option<LoadBalancingAlgorithms>,
     pub answer_503: Option<String>,
     #[serde(default)]
     pub load_metric: Option<LoadMetric>,
+    /// local address used to connect to the backends
+    #[serde(default)]
+    pub source_address: Option<IpAddr>,
+    /// connect to the backends from the IP address of the client
+    #[serde(default)]
+    pub transparent: Option<bool>,
+    /// maximum size in bytes of the request headers sent to the backends
+    #[serde(default)]
+    pub max_request_header_size: Option<u32>,
+    /// time in microseconds the proxy may spend in header edits and filters
+    #[serde(default)]
+    pub filter_time_budget: Option<u64>,
+    /// share the backend chosen for each client IP between workers
+    #[serde(default)]
+    pub sticky_table: Option<bool>,
+    /// DSCP value of the packets sent to the backends
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// also mark the packets sent to the clients with the DSCP value
+    #[serde(default)]
+    pub dscp_on_clients: Option<bool>,
+    /// eject the backends with more errors or timeouts than the rest of the cluster
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    /// probe the backends and remove the failing ones from load balancing
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    /// redirect HTTP requests to HTTPS and send HSTS on HTTPS responses
+    #[serde(default)]
+    pub https_policy: Option<HttpsPolicyConfig>,
+    /// timeouts of the requests of the cluster, overriding the ones of the listeners
+    #[serde(default)]
+    pub timeouts: Option<TimeoutsConfig>,
+    /// maximum size in bytes of the response bodies sent by the backends
+    #[serde(default)]
+    pub max_response_body_size: Option<u64>,
+    /// delay in milliseconds the response bodies may be held to coalesce their writes
+    #[serde(default)]
+    pub response_flush_delay: Option<u32>,
+    /// log the requests slower than a threshold to a dedicated sink
+    #[serde(default)]
+    pub slow_log: Option<SlowLogConfig>,
+    /// limit the requests of each client IP to the cluster
+    #[serde(default)]
+    pub rate_limit: Option<ClientRateLimitConfig>,
+    /// what the consistent hash load balancing hashes: `source_ip`, `header:<name>`
+    /// or `cookie:<name>`
+    #[serde(default)]
+    pub hash_key: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -793,20 +2322,177 @@ pub struct BackendConfig {
     pub sticky_id: Option<String>,
     pub backup: Option<bool>,
     pub backend_id: Option<String>,
+    /// seconds over which the traffic sent to the backend ramps up, when it is added
+    /// or comes back up
+    #[serde(default)]
+    pub slow_start_seconds: Option<u32>,
 }
 
 impl FileClusterConfig {
+    /// fill the options that are not set on the cluster with the ones of the template
+    pub fn inherit_from(&mut self, template: &FileClusterTemplate) {
+        if self.protocol.is_none() {
+            self.protocol.clone_from(&template.protocol);
+        }
+        self.sticky_session = self.sticky_session.or(template.sticky_session);
+        self.https_redirect = self.https_redirect.or(template.https_redirect);
+        self.send_proxy = self.send_proxy.or(template.send_proxy);
+        self.load_balancing = self.load_balancing.or(template.load_balancing);
+        if self.answer_503.is_none() {
+            self.answer_503.clone_from(&template.answer_503);
+        }
+        self.load_metric = self.load_metric.or(template.load_metric);
+        self.source_address = self.source_address.or(template.source_address);
+        self.transparent = self.transparent.or(template.transparent);
+        self.max_request_header_size = self
+            .max_request_header_size
+            .or(template.max_request_header_size);
+        self.filter_time_budget = self.filter_time_budget.or(template.filter_time_budget);
+        self.sticky_table = self.sticky_table.or(template.sticky_table);
+        self.dscp = self.dscp.or(template.dscp);
+        self.dscp_on_clients = self.dscp_on_clients.or(template.dscp_on_clients);
+        if self.outlier_detection.is_none() {
+            self.outlier_detection
+                .clone_from(&template.outlier_detection);
+        }
+        if self.health_check.is_none() {
+            self.health_check.clone_from(&template.health_check);
+        }
+        if self.https_policy.is_none() {
+            self.https_policy.clone_from(&template.https_policy);
+        }
+        if self.timeouts.is_none() {
+            self.timeouts.clone_from(&template.timeouts);
+        }
+        self.max_response_body_size = self
+            .max_response_body_size
+            .or(template.max_response_body_size);
+        self.response_flush_delay = self.response_flush_delay.or(template.response_flush_delay);
+        if self.slow_log.is_none() {
+            self.slow_log.clone_from(&template.slow_log);
+        }
+        if self.rate_limit.is_none() {
+            self.rate_limit.clone_from(&template.rate_limit);
+        }
+        if self.hash_key.is_none() {
+            self.hash_key.clone_from(&template.hash_key);
+        }
+    }
+
     pub fn to_cluster_config(
         self,
         cluster_id: &str,
         expect_proxy: &HashSet<SocketAddr>,
     ) -> Result<ClusterConfig, ConfigError> {
-        match self.protocol {
+        let protocol = self
+            .protocol
+            .ok_or(ConfigError::Missing(MissingKind::Protocol))?;
+
+        if let Some(dscp) = self.dscp.filter(|dscp| *dscp > MAX_DSCP) {
+            return Err(ConfigError::InvalidDscp {
+                cluster_id: cluster_id.to_owned(),
+                dscp,
+            });
+        }
+
+        let outlier_detection = self
+            .outlier_detection
+            .map(|outlier_detection| outlier_detection.to_outlier_detection(cluster_id))
+            .transpose()?;
+
+        let health_check = self
+            .health_check
+            .map(|health_check| health_check.to_health_check(cluster_id))
+            .transpose()?;
+
+        let https_policy = self
+            .https_policy
+            .map(|https_policy| https_policy.to_https_policy(cluster_id))
+            .transpose()?;
+
+        let timeouts = self
+            .timeouts
+            .map(|timeouts| timeouts.to_timeouts(&format!("cluster {cluster_id}")))
+            .transpose()?;
+
+        let slow_log = self
+            .slow_log
+            .map(|slow_log| slow_log.to_slow_log(cluster_id))
+            .transpose()?;
+
+        let rate_limit = self
+            .rate_limit
+            .map(|rate_limit| rate_limit.to_client_rate_limit(&format!("cluster {cluster_id}")))
+            .transpose()?;
+
+        let hash_key = self
+            .hash_key
+            .as_deref()
+            .map(|hash_key| {
+                hash_key
+                    .parse::<HashKey>()
+                    .map_err(|e| ConfigError::InvalidHashKey {
+                        cluster_id: cluster_id.to_owned(),
+                        reason: e.to_string(),
+                    })
+            })
+            .transpose()?;
+        if hash_key.is_some()
+            && self.load_balancing != Some(LoadBalancingAlgorithms::ConsistentHash)
+        {
+            return Err(ConfigError::InvalidHashKey {
+                cluster_id: cluster_id.to_owned(),
+                reason: "it is only used by the CONSISTENT_HASH load balancing".to_owned(),
+            });
+        }
+
+        match protocol {
             FileClusterProtocolConfig::Tcp => {
+                if outlier_detection.is_some() {
+                    return Err(ConfigError::InvalidOutlierDetection {
+                        cluster_id: cluster_id.to_owned(),
+                        reason: "it classifies HTTP responses, TCP clusters do not have any"
+                            .to_owned(),
+                    });
+                }
+                if https_policy.is_some() {
+                    return Err(ConfigError::InvalidHttpsPolicy {
+                        cluster_id: cluster_id.to_owned(),
+                        reason: "TCP clusters do not know about HTTP and HTTPS".to_owned(),
+                    });
+                }
+                if timeouts.is_some() {
+                    return Err(ConfigError::InvalidTimeouts {
+                        route: format!("cluster {cluster_id}"),
+                        reason: "TCP clusters use the timeouts of their listeners".to_owned(),
+                    });
+                }
+                if slow_log.is_some() {
+                    return Err(ConfigError::InvalidSlowLog {
+                        cluster_id: cluster_id.to_owned(),
+                        reason: "TCP clusters do not have requests".to_owned(),
+                    });
+                }
+                if rate_limit.is_some() {
+                    return Err(ConfigError::InvalidClientRateLimit {
+                        owner: format!("cluster {cluster_id}"),
+                        reason: "TCP clusters do not have requests".to_owned(),
+                    });
+                }
+                if hash_key
+                    .as_ref()
+                    .is_some_and(|hash_key| hash_key.kind != HashKeyKind::SourceIp as i32)
+                {
+                    return Err(ConfigError::InvalidHashKey {
+                        cluster_id: cluster_id.to_owned(),
+                        reason: "TCP clusters can only hash the client IP".to_owned(),
+                    });
+                }
+
                 let mut has_expect_proxy = None;
                 let mut frontends = Vec::new();
                 for f in self.frontends {
-                    if expect_proxy.contains(&f.address) {
+                    if expect_proxy.contains(&f.address()?) {
                         match has_expect_proxy {
                             Some(true) => {}
                             Some(false) => {
@@ -849,8 +2535,16 @@ impl FileClusterConfig {
                     frontends,
                     backends: self.backends,
                     proxy_protocol,
-                    load_balancing: self.load_balancing,
+                    load_balancing: self.load_balancing.unwrap_or_default(),
                     load_metric: self.load_metric,
+                    source_address: self.source_address,
+                    transparent: self.transparent.unwrap_or(false),
+                    sticky_table: self.sticky_table.unwrap_or(false),
+                    backend_srv_record: self.backend_srv_record,
+                    dscp: self.dscp,
+                    dscp_on_clients: self.dscp_on_clients.unwrap_or(false),
+                    health_check,
+                    hash_key,
                 }))
             }
             FileClusterProtocolConfig::Http => {
@@ -875,9 +2569,26 @@ impl FileClusterConfig {
                     backends: self.backends,
                     sticky_session: self.sticky_session.unwrap_or(false),
                     https_redirect: self.https_redirect.unwrap_or(false),
-                    load_balancing: self.load_balancing,
+                    load_balancing: self.load_balancing.unwrap_or_default(),
                     load_metric: self.load_metric,
                     answer_503,
+                    source_address: self.source_address,
+                    transparent: self.transparent.unwrap_or(false),
+                    max_request_header_size: self.max_request_header_size,
+                    filter_time_budget: self.filter_time_budget,
+                    sticky_table: self.sticky_table.unwrap_or(false),
+                    backend_srv_record: self.backend_srv_record,
+                    dscp: self.dscp,
+                    dscp_on_clients: self.dscp_on_clients.unwrap_or(false),
+                    outlier_detection,
+                    https_policy,
+                    timeouts,
+                    max_response_body_size: self.max_response_body_size,
+                    response_flush_delay: self.response_flush_delay,
+                    health_check,
+                    slow_log,
+                    rate_limit,
+                    hash_key,
                 }))
             }
         }
@@ -899,6 +2610,24 @@ pub struct HttpFrontendConfig {
     #[serde(default)]
     pub position: RulePosition,
     pub tags: Option<BTreeMap<String, String>>,
+    /// only match the HTTPS clients that negotiated one of these TLS versions
+    #[serde(default)]
+    pub client_tls_versions: Vec<TlsVersion>,
+    /// only match the HTTPS clients that negotiated one of these cipher suites
+    #[serde(default)]
+    pub client_cipher_suites: Vec<String>,
+    #[serde(default)]
+    pub timeouts: Option<Timeouts>,
+    #[serde(default)]
+    pub mirror: Option<RequestMirror>,
+    #[serde(default)]
+    pub split: Vec<WeightedCluster>,
+    #[serde(default)]
+    pub headers: Vec<HeaderEdit>,
+    #[serde(default)]
+    pub device: Option<DeviceMatch>,
+    #[serde(default)]
+    pub rate_limit: Option<ClientRateLimit>,
 }
 
 impl HttpFrontendConfig {
@@ -910,7 +2639,8 @@ impl HttpFrontendConfig {
             None => BTreeMap::new(),
         };
 
-        if self.key.is_some() && self.certificate.is_some() {
+        let has_certificate = self.key.is_some() && self.certificate.is_some();
+        if has_certificate {
             v.push(
                 RequestType::AddCertificate(AddCertificate {
                     address: self.address.into(),
@@ -929,33 +2659,35 @@ impl HttpFrontendConfig {
                 })
                 .into(),
             );
+        }
 
-            v.push(
-                RequestType::AddHttpsFrontend(RequestHttpFrontend {
-                    cluster_id: Some(cluster_id.to_string()),
-                    address: self.address.into(),
-                    hostname: self.hostname.clone(),
-                    path: self.path.clone(),
-                    method: self.method.clone(),
-                    position: self.position.into(),
-                    tags,
-                })
-                .into(),
-            );
+        let front = RequestHttpFrontend {
+            cluster_id: Some(cluster_id.to_string()),
+            address: self.address.into(),
+            hostname: self.hostname.clone(),
+            path: self.path.clone(),
+            method: self.method.clone(),
+            position: self.position.into(),
+            tags,
+            expires_at: None,
+            client_tls_versions: self.client_tls_versions.iter().map(|v| *v as i32).collect(),
+            client_cipher_suites: self.client_cipher_suites.clone(),
+            timeouts: self.timeouts.clone(),
+            mirror: self.mirror.clone(),
+            split: self.split.clone(),
+            headers: self.headers.clone(),
+            device: self.device.clone(),
+            rate_limit: self.rate_limit.clone(),
+        };
+
+        // conditions on the client's TLS parameters only make sense for HTTPS
+        if has_certificate
+            || !self.client_tls_versions.is_empty()
+            || !self.client_cipher_suites.is_empty()
+        {
+            v.push(RequestType::AddHttpsFrontend(front).into());
         } else {
-            //create the front both for HTTP and HTTPS if possible
-            v.push(
-                RequestType::AddHttpFrontend(RequestHttpFrontend {
-                    cluster_id: Some(cluster_id.to_string()),
-                    address: self.address.into(),
-                    hostname: self.hostname.clone(),
-                    path: self.path.clone(),
-                    method: self.method.clone(),
-                    position: self.position.into(),
-                    tags,
-                })
-                .into(),
-            );
+            v.push(RequestType::AddHttpFrontend(front).into());
         }
 
         v
@@ -973,6 +2705,40 @@ pub struct HttpClusterConfig {
     pub load_balancing: LoadBalancingAlgorithms,
     pub load_metric: Option<LoadMetric>,
     pub answer_503: Option<String>,
+    #[serde(default)]
+    pub source_address: Option<IpAddr>,
+    #[serde(default)]
+    pub transparent: bool,
+    #[serde(default)]
+    pub max_request_header_size: Option<u32>,
+    #[serde(default)]
+    pub filter_time_budget: Option<u64>,
+    #[serde(default)]
+    pub sticky_table: bool,
+    #[serde(default)]
+    pub backend_srv_record: Option<String>,
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    #[serde(default)]
+    pub dscp_on_clients: bool,
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetection>,
+    #[serde(default)]
+    pub https_policy: Option<HttpsPolicy>,
+    #[serde(default)]
+    pub timeouts: Option<Timeouts>,
+    #[serde(default)]
+    pub max_response_body_size: Option<u64>,
+    #[serde(default)]
+    pub response_flush_delay: Option<u32>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub slow_log: Option<SlowLog>,
+    #[serde(default)]
+    pub rate_limit: Option<ClientRateLimit>,
+    #[serde(default)]
+    pub hash_key: Option<HashKey>,
 }
 
 impl HttpClusterConfig {
@@ -985,6 +2751,26 @@ impl HttpClusterConfig {
             load_balancing: self.load_balancing as i32,
             answer_503: self.answer_503.clone(),
             load_metric: self.load_metric.map(|s| s as i32),
+            source_address: self.source_address.map(Into::into),
+            transparent: self.transparent,
+            expires_at: None,
+            pipeline: None,
+            max_request_header_size: self.max_request_header_size,
+            filter_time_budget: self.filter_time_budget,
+            sticky_table: self.sticky_table,
+            backend_srv_record: self.backend_srv_record.clone(),
+            dscp: self.dscp.map(u32::from),
+            dscp_on_clients: self.dscp_on_clients,
+            outlier_detection: self.outlier_detection.clone(),
+            https_policy: self.https_policy.clone(),
+            timeouts: self.timeouts.clone(),
+            max_response_body_size: self.max_response_body_size,
+            response_flush_delay: self.response_flush_delay,
+            health_check: self.health_check.clone(),
+            slow_log: self.slow_log.clone(),
+            rate_limit: self.rate_limit.clone(),
+            hash_key: self.hash_key.clone(),
+            fault_injection: None,
         })
         .into()];
 
@@ -995,7 +2781,8 @@ impl HttpClusterConfig {
 
         for (backend_count, backend) in self.backends.iter().enumerate() {
             let load_balancing_parameters = Some(LoadBalancingParams {
-                weight: backend.weight.unwrap_or(100) as i32,
+                weight: backend.weight.map_or(DEFAULT_BACKEND_WEIGHT, i32::from),
+                slow_start_seconds: backend.slow_start_seconds,
             });
 
             v.push(
@@ -1008,6 +2795,8 @@ impl HttpClusterConfig {
                     load_balancing_parameters,
                     sticky_id: backend.sticky_id.clone(),
                     backup: backend.backup,
+                    expires_at: None,
+                    draining: None,
                 })
                 .into(),
             );
@@ -1021,6 +2810,8 @@ impl HttpClusterConfig {
 pub struct TcpFrontendConfig {
     pub address: SocketAddr,
     pub tags: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    pub sni: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -1032,6 +2823,22 @@ pub struct TcpClusterConfig {
     pub proxy_protocol: Option<ProxyProtocolConfig>,
     pub load_balancing: LoadBalancingAlgorithms,
     pub load_metric: Option<LoadMetric>,
+    #[serde(default)]
+    pub source_address: Option<IpAddr>,
+    #[serde(default)]
+    pub transparent: bool,
+    #[serde(default)]
+    pub sticky_table: bool,
+    #[serde(default)]
+    pub backend_srv_record: Option<String>,
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    #[serde(default)]
+    pub dscp_on_clients: bool,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub hash_key: Option<HashKey>,
 }
 
 impl TcpClusterConfig {
@@ -1044,6 +2851,26 @@ impl TcpClusterConfig {
             load_balancing: self.load_balancing as i32,
             load_metric: self.load_metric.map(|s| s as i32),
             answer_503: None,
+            source_address: self.source_address.map(Into::into),
+            transparent: self.transparent,
+            expires_at: None,
+            pipeline: None,
+            max_request_header_size: None,
+            filter_time_budget: None,
+            sticky_table: self.sticky_table,
+            backend_srv_record: self.backend_srv_record.clone(),
+            dscp: self.dscp.map(u32::from),
+            dscp_on_clients: self.dscp_on_clients,
+            outlier_detection: None,
+            https_policy: None,
+            timeouts: None,
+            max_response_body_size: None,
+            response_flush_delay: None,
+            health_check: self.health_check.clone(),
+            slow_log: None,
+            rate_limit: None,
+            hash_key: self.hash_key.clone(),
+            fault_injection: None,
         })
         .into()];
 
@@ -1053,6 +2880,8 @@ impl TcpClusterConfig {
                     cluster_id: self.cluster_id.clone(),
                     address: frontend.address.into(),
                     tags: frontend.tags.clone().unwrap_or(BTreeMap::new()),
+                    expires_at: None,
+                    sni: frontend.sni.clone(),
                 })
                 .into(),
             );
@@ -1060,7 +2889,8 @@ impl TcpClusterConfig {
 
         for (backend_count, backend) in self.backends.iter().enumerate() {
             let load_balancing_parameters = Some(LoadBalancingParams {
-                weight: backend.weight.unwrap_or(100) as i32,
+                weight: backend.weight.map_or(DEFAULT_BACKEND_WEIGHT, i32::from),
+                slow_start_seconds: backend.slow_start_seconds,
             });
 
             v.push(
@@ -1073,6 +2903,8 @@ impl TcpClusterConfig {
                     load_balancing_parameters,
                     sticky_id: backend.sticky_id.clone(),
                     backup: backend.backup,
+                    expires_at: None,
+                    draining: None,
                 })
                 .into(),
             );
@@ -1119,6 +2951,8 @@ pub struct FileConfig {
     #[serde(default)]
     pub access_logs_format: Option<AccessLogFormat>,
     #[serde(default)]
+    pub access_logs_template: Option<String>,
+    #[serde(default)]
     pub access_logs_colored: Option<bool>,
     pub worker_count: Option<u16>,
     pub worker_automatic_restart: Option<bool>,
@@ -1126,11 +2960,41 @@ pub struct FileConfig {
     pub disable_cluster_metrics: Option<bool>,
     pub listeners: Option<Vec<ListenerBuilder>>,
     pub clusters: Option<HashMap<String, FileClusterConfig>>,
+    #[serde(default)]
+    pub cluster_templates: Option<HashMap<String, FileClusterTemplate>>,
     pub handle_process_affinity: Option<bool>,
     pub ctl_command_timeout: Option<u64>,
+    #[serde(default)]
+    pub ctl_connect_retries: Option<u32>,
+    #[serde(default)]
+    pub ctl_connect_backoff: Option<u64>,
+    #[serde(default)]
+    pub event_history_size: Option<u64>,
+    #[serde(default)]
+    pub change_history_size: Option<u64>,
+    #[serde(default)]
+    pub alerts: Option<Vec<AlertConfig>>,
+    #[serde(default)]
+    pub alert_check_interval: Option<u64>,
+    #[serde(default)]
+    pub srv_refresh_interval: Option<u64>,
+    #[serde(default)]
+    pub dns_resolver: Option<SocketAddr>,
+    #[serde(default)]
+    pub replication: Option<ReplicationConfig>,
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+    #[serde(default)]
+    pub command_broker: Option<CommandBrokerConfig>,
+    #[serde(default)]
+    pub signing_key_file: Option<String>,
     pub pid_file_path: Option<String>,
     pub activate_listeners: Option<bool>,
     #[serde(default)]
+    pub readiness: Option<ReadinessConfig>,
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
     pub front_timeout: Option<u32>,
     #[serde(default)]
     pub back_timeout: Option<u32>,
@@ -1139,11 +3003,19 @@ pub struct FileConfig {
     #[serde(default)]
     pub zombie_check_interval: Option<u32>,
     #[serde(default)]
+    pub session_audit_interval: Option<u32>,
+    #[serde(default)]
+    pub session_audit_reclaim: Option<bool>,
+    #[serde(default)]
     pub accept_queue_timeout: Option<u32>,
     #[serde(default)]
     pub request_timeout: Option<u32>,
     #[serde(default)]
     pub worker_timeout: Option<u32>,
+    #[serde(default)]
+    pub worker_queue_size: Option<u64>,
+    #[serde(default)]
+    pub upgrade_shift_duration: Option<u64>,
 }
 
 impl FileConfig {
@@ -1198,6 +3070,8 @@ impl FileConfig {
 pub struct ConfigBuilder {
     file: FileConfig,
     known_addresses: HashMap<SocketAddr, ListenerProtocol>,
+    /// listener name -> address
+    listener_names: HashMap<String, SocketAddr>,
     expect_proxy_addresses: HashSet<SocketAddr>,
     built: Config,
 }
@@ -1228,10 +3102,37 @@ impl ConfigBuilder {
                 .connect_timeout
                 .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
             ctl_command_timeout: file_config.ctl_command_timeout.unwrap_or(1_000),
+            ctl_connect_retries: file_config
+                .ctl_connect_retries
+                .unwrap_or(DEFAULT_CTL_CONNECT_RETRIES),
+            ctl_connect_backoff: file_config
+                .ctl_connect_backoff
+                .unwrap_or(DEFAULT_CTL_CONNECT_BACKOFF),
+            event_history_size: file_config
+                .event_history_size
+                .unwrap_or(DEFAULT_EVENT_HISTORY_SIZE),
+            change_history_size: file_config
+                .change_history_size
+                .unwrap_or(DEFAULT_CHANGE_HISTORY_SIZE),
+            alerts: file_config.alerts.clone().unwrap_or_default(),
+            alert_check_interval: file_config
+                .alert_check_interval
+                .unwrap_or(DEFAULT_ALERT_CHECK_INTERVAL),
+            srv_refresh_interval: file_config
+                .srv_refresh_interval
+                .unwrap_or(DEFAULT_SRV_REFRESH_INTERVAL),
+            dns_resolver: file_config.dns_resolver,
+            replication: file_config.replication.clone(),
+            grpc: file_config.grpc.clone(),
+            command_broker: file_config.command_broker.clone(),
+            readiness: file_config.readiness.clone(),
+            acme: file_config.acme.clone(),
+            signing_key_file: file_config.signing_key_file.clone(),
             front_timeout: file_config.front_timeout.unwrap_or(DEFAULT_FRONT_TIMEOUT),
             handle_process_affinity: file_config.handle_process_affinity.unwrap_or(false),
             access_logs_target: file_config.access_logs_target.clone(),
             access_logs_format: file_config.access_logs_format.clone(),
+            access_logs_template: file_config.access_logs_template.clone(),
             access_logs_colored: file_config.access_logs_colored,
             log_level: file_config
                 .log_level
@@ -1269,13 +3170,22 @@ impl ConfigBuilder {
             zombie_check_interval: file_config
                 .zombie_check_interval
                 .unwrap_or(DEFAULT_ZOMBIE_CHECK_INTERVAL),
+            session_audit_interval: file_config
+                .session_audit_interval
+                .unwrap_or(DEFAULT_SESSION_AUDIT_INTERVAL),
+            session_audit_reclaim: file_config.session_audit_reclaim.unwrap_or(false),
             worker_timeout: file_config.worker_timeout.unwrap_or(DEFAULT_WORKER_TIMEOUT),
+            worker_queue_size: file_config
+                .worker_queue_size
+                .unwrap_or(DEFAULT_WORKER_QUEUE_SIZE),
+            upgrade_shift_duration: file_config.upgrade_shift_duration.unwrap_or(0),
             ..Default::default()
         };
 
         Self {
             file: file_config,
             known_addresses: HashMap::new(),
+            listener_names: HashMap::new(),
             expect_proxy_addresses: HashSet::new(),
             built,
         }
@@ -1299,166 +3209,295 @@ impl ConfigBuilder {
         Ok(())
     }
 
-    fn populate_listeners(&mut self, listeners: Vec<ListenerBuilder>) -> Result<(), ConfigError> {
-        for listener in listeners.iter() {
-            if self.known_addresses.contains_key(&listener.address) {
-                return Err(ConfigError::ListenerAddressAlreadyInUse(listener.address));
+    fn populate_listeners(
+        &mut self,
+        listeners: Vec<ListenerBuilder>,
+        errors: &mut Vec<ConfigError>,
+    ) {
+        for listener in listeners {
+            if let Err(error) = self.populate_listener(listener) {
+                errors.push(error);
             }
+        }
+    }
 
-            let protocol = listener
-                .protocol
-                .ok_or(ConfigError::Missing(MissingKind::Protocol))?;
+    fn populate_listener(&mut self, listener: ListenerBuilder) -> Result<(), ConfigError> {
+        if self.known_addresses.contains_key(&listener.address) {
+            return Err(ConfigError::ListenerAddressAlreadyInUse(listener.address));
+        }
 
-            self.known_addresses.insert(listener.address, protocol);
-            if listener.expect_proxy == Some(true) {
-                self.expect_proxy_addresses.insert(listener.address);
+        let protocol = listener
+            .protocol
+            .ok_or(ConfigError::Missing(MissingKind::Protocol))?;
+
+        self.known_addresses.insert(listener.address, protocol);
+        if let Some(name) = &listener.name {
+            if self
+                .listener_names
+                .insert(name.to_owned(), listener.address)
+                .is_some()
+            {
+                return Err(ConfigError::ListenerNameAlreadyUsed(name.to_owned()));
             }
+        }
+        if listener.expect_proxy == Some(true) {
+            self.expect_proxy_addresses.insert(listener.address);
+        }
 
-            if listener.public_address.is_some() && listener.expect_proxy == Some(true) {
-                return Err(ConfigError::Incompatible {
-                    object: ObjectKind::Listener,
-                    id: listener.address.to_string(),
-                    kind: IncompatibilityKind::PublicAddress,
-                });
-            }
+        if listener.public_address.is_some() && listener.expect_proxy == Some(true) {
+            return Err(ConfigError::Incompatible {
+                object: ObjectKind::Listener,
+                id: listener.address.to_string(),
+                kind: IncompatibilityKind::PublicAddress,
+            });
+        }
 
-            match protocol {
-                ListenerProtocol::Https => self.push_tls_listener(listener.clone())?,
-                ListenerProtocol::Http => self.push_http_listener(listener.clone())?,
-                ListenerProtocol::Tcp => self.push_tcp_listener(listener.clone())?,
-            }
+        match protocol {
+            ListenerProtocol::Https => self.push_tls_listener(listener)?,
+            ListenerProtocol::Http => self.push_http_listener(listener)?,
+            ListenerProtocol::Tcp => self.push_tcp_listener(listener)?,
         }
         Ok(())
     }
 
     fn populate_clusters(
         &mut self,
-        mut file_cluster_configs: HashMap<String, FileClusterConfig>,
+        file_cluster_configs: HashMap<String, FileClusterConfig>,
+        errors: &mut Vec<ConfigError>,
+    ) {
+        // sorted, so that the errors are reported in the same order every time
+        let file_cluster_configs: BTreeMap<String, FileClusterConfig> =
+            file_cluster_configs.into_iter().collect();
+        for (id, file_cluster_config) in file_cluster_configs {
+            if let Err(error) = self.populate_cluster(id, file_cluster_config) {
+                errors.push(error);
+            }
+        }
+    }
+
+    fn populate_cluster(
+        &mut self,
+        id: String,
+        mut file_cluster_config: FileClusterConfig,
     ) -> Result<(), ConfigError> {
-        for (id, file_cluster_config) in file_cluster_configs.drain() {
-            let mut cluster_config =
-                file_cluster_config.to_cluster_config(id.as_str(), &self.expect_proxy_addresses)?;
-
-            match cluster_config {
-                ClusterConfig::Http(ref mut http) => {
-                    for frontend in http.frontends.iter_mut() {
-                        match self.known_addresses.get(&frontend.address) {
-                            Some(ListenerProtocol::Tcp) => {
+        if let Some(template_name) = &file_cluster_config.template {
+            let template = self
+                .file
+                .cluster_templates
+                .as_ref()
+                .and_then(|templates| templates.get(template_name))
+                .ok_or(ConfigError::UnknownTemplate {
+                    cluster_id: id.to_owned(),
+                    template: template_name.to_owned(),
+                })?;
+            file_cluster_config.inherit_from(template);
+        }
+
+        for frontend in file_cluster_config.frontends.iter_mut() {
+            frontend.resolve_listener(&id, &self.listener_names)?;
+        }
+
+        let mut cluster_config =
+            file_cluster_config.to_cluster_config(id.as_str(), &self.expect_proxy_addresses)?;
+
+        match cluster_config {
+            ClusterConfig::Http(ref mut http) => {
+                for frontend in http.frontends.iter_mut() {
+                    match self.known_addresses.get(&frontend.address) {
+                        Some(ListenerProtocol::Tcp) => {
+                            return Err(ConfigError::WrongFrontendProtocol(ListenerProtocol::Tcp));
+                        }
+                        Some(ListenerProtocol::Http) => {
+                            if frontend.certificate.is_some() {
                                 return Err(ConfigError::WrongFrontendProtocol(
-                                    ListenerProtocol::Tcp,
+                                    ListenerProtocol::Http,
                                 ));
                             }
-                            Some(ListenerProtocol::Http) => {
-                                if frontend.certificate.is_some() {
-                                    return Err(ConfigError::WrongFrontendProtocol(
-                                        ListenerProtocol::Http,
-                                    ));
+                        }
+                        Some(ListenerProtocol::Https) => {
+                            if frontend.certificate.is_none() {
+                                if let Some(https_listener) =
+                                    self.built.https_listeners.iter().find(|listener| {
+                                        listener.address == frontend.address.into()
+                                            && listener.certificate.is_some()
+                                    })
+                                {
+                                    //println!("using listener certificate for {:}", frontend.address);
+                                    frontend.certificate.clone_from(&https_listener.certificate);
+                                    frontend.certificate_chain =
+                                        Some(https_listener.certificate_chain.clone());
+                                    frontend.key.clone_from(&https_listener.key);
                                 }
-                            }
-                            Some(ListenerProtocol::Https) => {
                                 if frontend.certificate.is_none() {
-                                    if let Some(https_listener) =
-                                        self.built.https_listeners.iter().find(|listener| {
-                                            listener.address == frontend.address.into()
-                                                && listener.certificate.is_some()
-                                        })
-                                    {
-                                        //println!("using listener certificate for {:}", frontend.address);
-                                        frontend
-                                            .certificate
-                                            .clone_from(&https_listener.certificate);
-                                        frontend.certificate_chain =
-                                            Some(https_listener.certificate_chain.clone());
-                                        frontend.key.clone_from(&https_listener.key);
-                                    }
-                                    if frontend.certificate.is_none() {
-                                        debug!("known addresses: {:#?}", self.known_addresses);
-                                        debug!("frontend: {:#?}", frontend);
-                                        return Err(ConfigError::WrongFrontendProtocol(
-                                            ListenerProtocol::Https,
-                                        ));
-                                    }
-                                }
-                            }
-                            None => {
-                                // create a default listener for that front
-                                let file_listener_protocol = if frontend.certificate.is_some() {
-                                    self.push_tls_listener(ListenerBuilder::new(
-                                        frontend.address.into(),
+                                    debug!("known addresses: {:#?}", self.known_addresses);
+                                    debug!("frontend: {:#?}", frontend);
+                                    return Err(ConfigError::WrongFrontendProtocol(
                                         ListenerProtocol::Https,
-                                    ))?;
-
-                                    ListenerProtocol::Https
-                                } else {
-                                    self.push_http_listener(ListenerBuilder::new(
-                                        frontend.address.into(),
-                                        ListenerProtocol::Http,
-                                    ))?;
-
-                                    ListenerProtocol::Http
-                                };
-                                self.known_addresses
-                                    .insert(frontend.address, file_listener_protocol);
+                                    ));
+                                }
                             }
                         }
-                    }
-                }
-                ClusterConfig::Tcp(ref tcp) => {
-                    //FIXME: verify that different TCP clusters do not request the same address
-                    for frontend in &tcp.frontends {
-                        match self.known_addresses.get(&frontend.address) {
-                            Some(ListenerProtocol::Http) | Some(ListenerProtocol::Https) => {
-                                return Err(ConfigError::WrongFrontendProtocol(
-                                    ListenerProtocol::Http,
-                                ));
-                            }
-                            Some(ListenerProtocol::Tcp) => {}
-                            None => {
-                                // create a default listener for that front
-                                self.push_tcp_listener(ListenerBuilder::new(
+                        None => {
+                            // create a default listener for that front
+                            let file_listener_protocol = if frontend.certificate.is_some() {
+                                self.push_tls_listener(ListenerBuilder::new(
                                     frontend.address.into(),
-                                    ListenerProtocol::Tcp,
+                                    ListenerProtocol::Https,
                                 ))?;
-                                self.known_addresses
-                                    .insert(frontend.address, ListenerProtocol::Tcp);
-                            }
+
+                                ListenerProtocol::Https
+                            } else {
+                                self.push_http_listener(ListenerBuilder::new(
+                                    frontend.address.into(),
+                                    ListenerProtocol::Http,
+                                ))?;
+
+                                ListenerProtocol::Http
+                            };
+                            self.known_addresses
+                                .insert(frontend.address, file_listener_protocol);
+                        }
+                    }
+                }
+            }
+            ClusterConfig::Tcp(ref tcp) => {
+                //FIXME: verify that different TCP clusters do not request the same address
+                for frontend in &tcp.frontends {
+                    match self.known_addresses.get(&frontend.address) {
+                        Some(ListenerProtocol::Http) | Some(ListenerProtocol::Https) => {
+                            return Err(ConfigError::WrongFrontendProtocol(ListenerProtocol::Http));
+                        }
+                        Some(ListenerProtocol::Tcp) => {}
+                        None => {
+                            // create a default listener for that front
+                            self.push_tcp_listener(ListenerBuilder::new(
+                                frontend.address.into(),
+                                ListenerProtocol::Tcp,
+                            ))?;
+                            self.known_addresses
+                                .insert(frontend.address, ListenerProtocol::Tcp);
                         }
                     }
                 }
             }
-
-            self.built.clusters.insert(id, cluster_config);
         }
+
+        self.built.clusters.insert(id, cluster_config);
         Ok(())
     }
 
+    fn command_socket_path(&self) -> Result<String, ConfigError> {
+        if let Some(command_socket) = &self.file.command_socket {
+            return Ok(command_socket.to_owned());
+        }
+        let mut path = env::current_dir().map_err(|e| ConfigError::Env(e.to_string()))?;
+        path.push("sozu.sock");
+        let verified_path = path
+            .to_str()
+            .ok_or(ConfigError::InvalidPath(path.clone()))?;
+        Ok(verified_path.to_owned())
+    }
+
     /// Builds a [`Config`], populated with listeners and clusters
     pub fn into_config(&mut self) -> Result<Config, ConfigError> {
-        if let Some(listeners) = &self.file.listeners {
-            self.populate_listeners(listeners.clone())?;
+        let (config, errors) = self.check();
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(config),
         }
+    }
+
+    /// Builds a [`Config`] as far as the errors allow, and returns all of them
+    /// instead of stopping at the first one
+    pub fn check(&mut self) -> (Config, Vec<ConfigError>) {
+        let mut errors = Vec::new();
 
-        if let Some(file_cluster_configs) = &self.file.clusters {
-            self.populate_clusters(file_cluster_configs.clone())?;
+        if let Some(listeners) = self.file.listeners.clone() {
+            self.populate_listeners(listeners, &mut errors);
         }
 
-        let command_socket_path = self.file.command_socket.clone().unwrap_or({
-            let mut path = env::current_dir().map_err(|e| ConfigError::Env(e.to_string()))?;
-            path.push("sozu.sock");
-            let verified_path = path
-                .to_str()
-                .ok_or(ConfigError::InvalidPath(path.clone()))?;
-            verified_path.to_owned()
+        if let Some(file_cluster_configs) = self.file.clusters.clone() {
+            self.populate_clusters(file_cluster_configs, &mut errors);
+        }
+
+        let command_socket = self.command_socket_path().unwrap_or_else(|error| {
+            errors.push(error);
+            String::new()
         });
 
+        self.validate(&mut errors);
+
+        let config = Config {
+            command_socket,
+            ..self.built.clone()
+        };
+        (config, errors)
+    }
+
+    /// the checks of the options that depend on each other
+    fn validate(&self, errors: &mut Vec<ConfigError>) {
         if let (None, Some(true)) = (&self.file.saved_state, &self.file.automatic_state_save) {
-            return Err(ConfigError::Missing(MissingKind::SavedState));
+            errors.push(ConfigError::Missing(MissingKind::SavedState));
         }
 
-        Ok(Config {
-            command_socket: command_socket_path,
-            ..self.built.clone()
-        })
+        if let Some(Err(error)) = self.built.replication.as_ref().map(|r| r.validate()) {
+            errors.push(error);
+        }
+
+        if let Some(Err(error)) = self.built.acme.as_ref().map(|acme| acme.validate()) {
+            errors.push(error);
+        }
+
+        if let Some(Err(error)) = self.built.metrics.as_ref().map(|m| m.validate()) {
+            errors.push(error);
+        }
+
+        for listener in &self.built.https_listeners {
+            let Some(cluster_id) = &listener.unknown_sni_cluster else {
+                continue;
+            };
+            let reason = match self.built.clusters.get(cluster_id) {
+                Some(ClusterConfig::Tcp(_)) => continue,
+                Some(ClusterConfig::Http(_)) => {
+                    format!("cluster {cluster_id} is not a TCP cluster")
+                }
+                None => format!("there is no cluster {cluster_id}"),
+            };
+            errors.push(ConfigError::InvalidUnknownSniCluster {
+                listener: SocketAddr::from(listener.address.clone()).to_string(),
+                reason,
+            });
+        }
+
+        match (
+            &self.built.access_logs_format,
+            &self.built.access_logs_template,
+        ) {
+            (Some(AccessLogFormat::Template), None) => {
+                errors.push(ConfigError::InvalidAccessLogTemplate(
+                    "the template format needs an access_logs_template".to_owned(),
+                ));
+            }
+            (_, Some(template)) => {
+                if let Err(error) = template.parse::<AccessLogTemplate>() {
+                    errors.push(ConfigError::InvalidAccessLogTemplate(error));
+                }
+            }
+            _ => {}
+        }
+
+        let mut alert_names = HashSet::new();
+        for alert in &self.built.alerts {
+            if let Err(error) = alert.validate() {
+                errors.push(error);
+            }
+            if !alert_names.insert(&alert.name) {
+                errors.push(ConfigError::InvalidAlert {
+                    name: alert.name.to_owned(),
+                    reason: "another alert has the same name".to_owned(),
+                });
+            }
+        }
     }
 }
 
@@ -1484,6 +3523,7 @@ pub struct Config {
     #[serde(default)]
     pub access_logs_target: Option<String>,
     pub access_logs_format: Option<AccessLogFormat>,
+    pub access_logs_template: Option<String>,
     pub access_logs_colored: Option<bool>,
     pub worker_count: u16,
     pub worker_automatic_restart: bool,
@@ -1496,8 +3536,50 @@ pub struct Config {
     pub clusters: HashMap<String, ClusterConfig>,
     pub handle_process_affinity: bool,
     pub ctl_command_timeout: u64,
+    /// times the command line retries to connect to the command socket, before giving up
+    #[serde(default = "default_ctl_connect_retries")]
+    pub ctl_connect_retries: u32,
+    /// milliseconds before the first retry to connect to the command socket, doubled
+    /// after each retry
+    #[serde(default = "default_ctl_connect_backoff")]
+    pub ctl_connect_backoff: u64,
+    /// number of events kept by the main process, for `sozu events list`
+    #[serde(default = "default_event_history_size")]
+    pub event_history_size: u64,
+    /// number of state changes kept by the main process, for `sozu state changes`
+    #[serde(default = "default_change_history_size")]
+    pub change_history_size: u64,
+    /// threshold rules evaluated by the main process
+    #[serde(default)]
+    pub alerts: Vec<AlertConfig>,
+    #[serde(default = "default_alert_check_interval")]
+    pub alert_check_interval: u64,
+    /// seconds between two resolutions of the SRV records of the clusters
+    #[serde(default = "default_srv_refresh_interval")]
+    pub srv_refresh_interval: u64,
+    /// DNS server queried for SRV records, the first nameserver of /etc/resolv.conf if not set
+    #[serde(default)]
+    pub dns_resolver: Option<SocketAddr>,
+    /// replication of the state from a primary main process to stand-by peers
+    #[serde(default)]
+    pub replication: Option<ReplicationConfig>,
+    /// gRPC endpoint accepting the requests of remote clients authenticated with TLS
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+    /// accept the clients of the command socket in a separate, unprivileged process
+    #[serde(default)]
+    pub command_broker: Option<CommandBrokerConfig>,
+    /// file holding the secret signing the sticky session cookies, generated at startup if not set
+    #[serde(default)]
+    pub signing_key_file: Option<String>,
     pub pid_file_path: Option<String>,
     pub activate_listeners: bool,
+    /// keep the listeners inactive at startup until the proxy is ready to serve
+    #[serde(default)]
+    pub readiness: Option<ReadinessConfig>,
+    /// certificates ordered and renewed from an ACME server
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
     #[serde(default = "default_front_timeout")]
     pub front_timeout: u32,
     #[serde(default = "default_back_timeout")]
@@ -1506,12 +3588,26 @@ pub struct Config {
     pub connect_timeout: u32,
     #[serde(default = "default_zombie_check_interval")]
     pub zombie_check_interval: u32,
+    /// seconds between two audits of the session slab, the connection count and
+    /// the timer of each worker, 0 to disable them
+    #[serde(default = "default_session_audit_interval")]
+    pub session_audit_interval: u32,
+    /// remove the orphans found by the periodic audits, instead of only reporting them
+    #[serde(default)]
+    pub session_audit_reclaim: bool,
     #[serde(default = "default_accept_queue_timeout")]
     pub accept_queue_timeout: u32,
     #[serde(default = "default_request_timeout")]
     pub request_timeout: u32,
     #[serde(default = "default_worker_timeout")]
     pub worker_timeout: u32,
+    /// requests waiting for a worker whose channel is full, before the worker is closed
+    #[serde(default = "default_worker_queue_size")]
+    pub worker_queue_size: u64,
+    /// seconds during which an upgraded worker hands its new connections over to the
+    /// worker replacing it, 0 stops it as soon as the new one is activated
+    #[serde(default)]
+    pub upgrade_shift_duration: u64,
 }
 
 fn default_front_timeout() -> u32 {
@@ -1530,10 +3626,38 @@ fn default_request_timeout() -> u32 {
     DEFAULT_REQUEST_TIMEOUT
 }
 
+fn default_ctl_connect_retries() -> u32 {
+    DEFAULT_CTL_CONNECT_RETRIES
+}
+
+fn default_ctl_connect_backoff() -> u64 {
+    DEFAULT_CTL_CONNECT_BACKOFF
+}
+
+fn default_alert_check_interval() -> u64 {
+    DEFAULT_ALERT_CHECK_INTERVAL
+}
+
+fn default_srv_refresh_interval() -> u64 {
+    DEFAULT_SRV_REFRESH_INTERVAL
+}
+
+fn default_event_history_size() -> u64 {
+    DEFAULT_EVENT_HISTORY_SIZE
+}
+
+fn default_change_history_size() -> u64 {
+    DEFAULT_CHANGE_HISTORY_SIZE
+}
+
 fn default_zombie_check_interval() -> u32 {
     DEFAULT_ZOMBIE_CHECK_INTERVAL
 }
 
+fn default_session_audit_interval() -> u32 {
+    DEFAULT_SESSION_AUDIT_INTERVAL
+}
+
 fn default_accept_queue_timeout() -> u32 {
     DEFAULT_ACCEPT_QUEUE_TIMEOUT
 }
@@ -1542,6 +3666,10 @@ fn default_disable_cluster_metrics() -> bool {
     DEFAULT_DISABLE_CLUSTER_METRICS
 }
 
+fn default_worker_queue_size() -> u64 {
+    DEFAULT_WORKER_QUEUE_SIZE
+}
+
 fn default_worker_timeout() -> u32 {
     DEFAULT_WORKER_TIMEOUT
 }
@@ -1568,6 +3696,7 @@ impl Config {
             v.push(WorkerRequest {
                 id: format!("CONFIG-{count}"),
                 content: RequestType::AddHttpListener(listener.clone()).into(),
+                sequence: None,
             });
             count += 1;
         }
@@ -1576,6 +3705,7 @@ impl Config {
             v.push(WorkerRequest {
                 id: format!("CONFIG-{count}"),
                 content: RequestType::AddHttpsListener(listener.clone()).into(),
+                sequence: None,
             });
             count += 1;
         }
@@ -1584,6 +3714,7 @@ impl Config {
             v.push(WorkerRequest {
                 id: format!("CONFIG-{count}"),
                 content: RequestType::AddTcpListener(listener.clone()).into(),
+                sequence: None,
             });
             count += 1;
         }
@@ -1594,6 +3725,7 @@ impl Config {
                 v.push(WorkerRequest {
                     id: format!("CONFIG-{count}"),
                     content,
+                    sequence: None,
                 });
                 count += 1;
             }
@@ -1609,6 +3741,7 @@ impl Config {
                         from_scm: false,
                     })
                     .into(),
+                    sequence: None,
                 });
                 count += 1;
             }
@@ -1622,6 +3755,7 @@ impl Config {
                         from_scm: false,
                     })
                     .into(),
+                    sequence: None,
                 });
                 count += 1;
             }
@@ -1635,6 +3769,7 @@ impl Config {
                         from_scm: false,
                     })
                     .into(),
+                    sequence: None,
                 });
                 count += 1;
             }
@@ -1645,6 +3780,7 @@ impl Config {
                 id: format!("CONFIG-{count}"),
                 content: RequestType::ConfigureMetrics(MetricsConfiguration::Disabled.into())
                     .into(),
+                sequence: None,
             });
             // count += 1; // uncomment if code is added below
         }
@@ -1794,21 +3930,40 @@ impl fmt::Debug for Config {
             .field("log_target", &self.log_target)
             .field("access_logs_target", &self.access_logs_target)
             .field("access_logs_format", &self.access_logs_format)
+            .field("access_logs_template", &self.access_logs_template)
             .field("worker_count", &self.worker_count)
             .field("worker_automatic_restart", &self.worker_automatic_restart)
             .field("metrics", &self.metrics)
             .field("disable_cluster_metrics", &self.disable_cluster_metrics)
             .field("handle_process_affinity", &self.handle_process_affinity)
             .field("ctl_command_timeout", &self.ctl_command_timeout)
+            .field("ctl_connect_retries", &self.ctl_connect_retries)
+            .field("ctl_connect_backoff", &self.ctl_connect_backoff)
+            .field("event_history_size", &self.event_history_size)
+            .field("change_history_size", &self.change_history_size)
+            .field("alerts", &self.alerts)
+            .field("alert_check_interval", &self.alert_check_interval)
+            .field("srv_refresh_interval", &self.srv_refresh_interval)
+            .field("dns_resolver", &self.dns_resolver)
+            .field("replication", &self.replication)
+            .field("grpc", &self.grpc)
+            .field("command_broker", &self.command_broker)
+            .field("signing_key_file", &self.signing_key_file)
             .field("pid_file_path", &self.pid_file_path)
             .field("activate_listeners", &self.activate_listeners)
+            .field("readiness", &self.readiness)
+            .field("acme", &self.acme)
             .field("front_timeout", &self.front_timeout)
             .field("back_timeout", &self.back_timeout)
             .field("connect_timeout", &self.connect_timeout)
             .field("zombie_check_interval", &self.zombie_check_interval)
+            .field("session_audit_interval", &self.session_audit_interval)
+            .field("session_audit_reclaim", &self.session_audit_reclaim)
             .field("accept_queue_timeout", &self.accept_queue_timeout)
             .field("request_timeout", &self.request_timeout)
             .field("worker_timeout", &self.worker_timeout)
+            .field("worker_queue_size", &self.worker_queue_size)
+            .field("upgrade_shift_duration", &self.upgrade_shift_duration)
             .finish()
     }
 }
@@ -1830,10 +3985,12 @@ impl ServerConfig {
 /// reduce the config to the bare minimum needed by a worker
 impl From<&Config> for ServerConfig {
     fn from(config: &Config) -> Self {
-        let metrics = config.metrics.clone().map(|m| ServerMetricsConfig {
-            address: m.address.to_string(),
-            tagged_metrics: m.tagged_metrics,
-            prefix: m.prefix,
+        let metrics = config.metrics.clone().and_then(|m| {
+            m.address.map(|address| ServerMetricsConfig {
+                address: address.to_string(),
+                tagged_metrics: m.tagged_metrics,
+                prefix: m.prefix,
+            })
         });
         Self {
             max_connections: config.max_connections as u64,
@@ -1853,6 +4010,11 @@ impl From<&Config> for ServerConfig {
             metrics,
             access_log_format: ProtobufAccessLogFormat::from(&config.access_logs_format) as i32,
             log_colored: config.log_colored,
+            session_audit_interval: config.session_audit_interval,
+            session_audit_reclaim: config.session_audit_reclaim,
+            // set by the main process when it launches the worker
+            generation: 0,
+            access_logs_template: config.access_logs_template.clone(),
         }
     }
 }
@@ -1862,6 +4024,12 @@ mod tests {
     use super::*;
     use toml::to_string;
 
+    /// build the configuration of a TOML file
+    fn build_config(toml: &str) -> Result<Config, ConfigError> {
+        let file_config: FileConfig = toml::from_str(toml).expect("could not parse the toml");
+        ConfigBuilder::new(file_config, "").into_config()
+    }
+
     #[test]
     fn serialize() {
         let http = ListenerBuilder::new(
@@ -1890,9 +4058,15 @@ mod tests {
             max_buffers: Some(500),
             buffer_size: Some(16393),
             metrics: Some(MetricsConfig {
-                address: "127.0.0.1:8125".parse().unwrap(),
+                address: Some("127.0.0.1:8125".parse().unwrap()),
                 tagged_metrics: false,
                 prefix: Some(String::from("sozu-metrics")),
+                prometheus_address: None,
+                remote_write_url: None,
+                remote_write_interval: DEFAULT_REMOTE_WRITE_INTERVAL,
+                remote_write_queue_size: DEFAULT_REMOTE_WRITE_QUEUE_SIZE,
+                remote_write_tenant: None,
+                remote_write_ca_file: None,
             }),
             listeners: Some(listeners),
             ..Default::default()
@@ -1912,4 +4086,1219 @@ mod tests {
         println!("config: {config:#?}");
         //panic!();
     }
+
+    #[test]
+    fn cluster_template() {
+        let config = build_config(
+            r#"
+            [cluster_templates.web]
+            protocol = "http"
+            load_balancing = "ROUND_ROBIN"
+            sticky_session = true
+            https_redirect = true
+
+            [clusters.app]
+            template = "web"
+            https_redirect = false
+            frontends = [{ address = "127.0.0.1:8080", hostname = "app.example.com" }]
+            backends = [{ address = "127.0.0.1:1026" }]
+            "#,
+        )
+        .expect("could not build the config");
+
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => {
+                assert_eq!(http.load_balancing, LoadBalancingAlgorithms::RoundRobin);
+                assert!(http.sticky_session);
+                assert!(!http.https_redirect);
+            }
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        let unknown_template = build_config(
+            r#"
+            [clusters.app]
+            template = "unknown"
+            frontends = [{ address = "127.0.0.1:8080", hostname = "app.example.com" }]
+            backends = [{ address = "127.0.0.1:1026" }]
+            "#,
+        );
+
+        assert!(matches!(
+            unknown_template,
+            Err(ConfigError::UnknownTemplate { .. })
+        ));
+    }
+
+    #[test]
+    fn cluster_dscp() {
+        let build = |dscp: u8| {
+            build_config(&format!(
+                r#"
+                [cluster_templates.voice]
+                protocol = "tcp"
+                dscp = {dscp}
+                dscp_on_clients = true
+
+                [clusters.sip]
+                template = "voice"
+                frontends = [{{ address = "127.0.0.1:5060" }}]
+                backends = [{{ address = "127.0.0.1:5061" }}]
+                "#
+            ))
+        };
+
+        let config = build(46).expect("could not build the config");
+        match config.clusters.get("sip") {
+            Some(ClusterConfig::Tcp(tcp)) => {
+                assert_eq!(tcp.dscp, Some(46));
+                assert!(tcp.dscp_on_clients);
+            }
+            other => panic!("expected a TCP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(build(64), Err(ConfigError::InvalidDscp { .. })));
+    }
+
+    #[test]
+    fn cluster_hash_key() {
+        let build = |protocol: &str, load_balancing: &str, hash_key: &str| {
+            let hostname = match protocol {
+                "tcp" => "",
+                _ => r#", hostname = "app.example.com""#,
+            };
+            build_config(&format!(
+                r#"
+                [clusters.app]
+                protocol = "{protocol}"
+                load_balancing = "{load_balancing}"
+                hash_key = "{hash_key}"
+                frontends = [{{ address = "127.0.0.1:8080"{hostname} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build("http", "CONSISTENT_HASH", "header:X-User-Id")
+            .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.hash_key,
+                Some(HashKey {
+                    kind: HashKeyKind::Header as i32,
+                    name: Some("X-User-Id".to_owned()),
+                })
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(build("tcp", "CONSISTENT_HASH", "source_ip").is_ok());
+        for (protocol, load_balancing, hash_key) in [
+            ("http", "CONSISTENT_HASH", "cookie:"),
+            ("http", "CONSISTENT_HASH", "query:id"),
+            ("http", "ROUND_ROBIN", "source_ip"),
+            ("tcp", "CONSISTENT_HASH", "cookie:session"),
+        ] {
+            assert!(
+                matches!(
+                    build(protocol, load_balancing, hash_key),
+                    Err(ConfigError::InvalidHashKey { .. })
+                ),
+                "{protocol} {load_balancing} {hash_key} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn cluster_outlier_detection() {
+        let build = |protocol: &str, outlier_detection: &str| {
+            build_config(&format!(
+                r#"
+                [cluster_templates.web]
+                protocol = "{protocol}"
+                outlier_detection = {outlier_detection}
+
+                [clusters.app]
+                template = "web"
+                frontends = [{{ address = "127.0.0.1:8080", hostname = "app.example.com" }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build(
+            "http",
+            "{ error_threshold = 20, max_ejection_percent = 34 }",
+        )
+        .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.outlier_detection,
+                Some(OutlierDetection {
+                    error_threshold: 20,
+                    max_ejection_percent: 34,
+                    ..Default::default()
+                })
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build("http", "{ window = 0 }"),
+            Err(ConfigError::InvalidOutlierDetection { .. })
+        ));
+        assert!(matches!(
+            build("http", "{ timeout_threshold = 101 }"),
+            Err(ConfigError::InvalidOutlierDetection { .. })
+        ));
+        assert!(matches!(
+            build("tcp", "{}"),
+            Err(ConfigError::InvalidOutlierDetection { .. })
+        ));
+    }
+
+    #[test]
+    fn cluster_slow_log() {
+        let build = |protocol: &str, slow_log: &str| {
+            let hostname = match protocol {
+                "http" => r#", hostname = "app.example.com""#,
+                _ => "",
+            };
+            build_config(&format!(
+                r#"
+                [clusters.app]
+                protocol = "{protocol}"
+                slow_log = {slow_log}
+                frontends = [{{ address = "127.0.0.1:8080"{hostname} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build("http", r#"{ threshold = 500, file = "/tmp/slow.log" }"#)
+            .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.slow_log,
+                Some(SlowLog {
+                    threshold: 500,
+                    sink: MirrorSink::File as i32,
+                    path: "/tmp/slow.log".to_owned(),
+                    max_per_second: 10,
+                })
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build("http", "{ threshold = 500 }"),
+            Err(ConfigError::InvalidSlowLog { .. })
+        ));
+        assert!(matches!(
+            build(
+                "http",
+                r#"{ threshold = 0, unix_socket = "/tmp/slow.sock" }"#
+            ),
+            Err(ConfigError::InvalidSlowLog { .. })
+        ));
+        assert!(matches!(
+            build("tcp", r#"{ threshold = 500, file = "/tmp/slow.log" }"#),
+            Err(ConfigError::InvalidSlowLog { .. })
+        ));
+    }
+
+    #[test]
+    fn cluster_health_check() {
+        let build = |protocol: &str, health_check: &str| {
+            let hostname = match protocol {
+                "http" => r#", hostname = "app.example.com""#,
+                _ => "",
+            };
+            build_config(&format!(
+                r#"
+                [clusters.app]
+                protocol = "{protocol}"
+                health_check = {health_check}
+                frontends = [{{ address = "127.0.0.1:8080"{hostname} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build("http", r#"{ interval = 5, http_path = "/health" }"#)
+            .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.health_check,
+                Some(HealthCheck {
+                    interval: 5,
+                    http_path: Some("/health".to_owned()),
+                    ..Default::default()
+                })
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        let config = build("tcp", "{}").expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Tcp(tcp)) => {
+                assert_eq!(tcp.health_check, Some(HealthCheck::default()))
+            }
+            other => panic!("expected a TCP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build("http", "{ interval = 1, timeout = 2 }"),
+            Err(ConfigError::InvalidHealthCheck { .. })
+        ));
+        assert!(matches!(
+            build("http", "{ unhealthy_threshold = 0 }"),
+            Err(ConfigError::InvalidHealthCheck { .. })
+        ));
+        assert!(matches!(
+            build("http", r#"{ http_path = "health" }"#),
+            Err(ConfigError::InvalidHealthCheck { .. })
+        ));
+    }
+
+    #[test]
+    fn cluster_https_policy() {
+        let build = |protocol: &str, https_policy: &str| {
+            build_config(&format!(
+                r#"
+                [cluster_templates.web]
+                protocol = "{protocol}"
+                https_policy = {https_policy}
+
+                [clusters.app]
+                template = "web"
+                frontends = [{{ address = "127.0.0.1:8080", hostname = "app.example.com" }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build("http", "{ include_subdomains = true, preload = true }")
+            .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.https_policy,
+                Some(HttpsPolicy {
+                    max_age: HSTS_PRELOAD_MIN_AGE,
+                    include_subdomains: true,
+                    preload: true,
+                })
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build("http", "{ preload = true }"),
+            Err(ConfigError::InvalidHttpsPolicy { .. })
+        ));
+        assert!(matches!(
+            build(
+                "http",
+                "{ max_age = 86400, include_subdomains = true, preload = true }"
+            ),
+            Err(ConfigError::InvalidHttpsPolicy { .. })
+        ));
+        assert!(matches!(
+            build("tcp", "{}"),
+            Err(ConfigError::InvalidHttpsPolicy { .. })
+        ));
+    }
+
+    #[test]
+    fn cluster_timeouts() {
+        let build = |protocol: &str, timeouts: &str, frontend_timeouts: &str| {
+            build_config(&format!(
+                r#"
+                [cluster_templates.web]
+                protocol = "{protocol}"
+                timeouts = {timeouts}
+
+                [clusters.app]
+                template = "web"
+                frontends = [{{ address = "127.0.0.1:8080", hostname = "app.example.com", timeouts = {frontend_timeouts} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build(
+            "http",
+            "{ backend_response = 90, request_deadline = 120 }",
+            "{ body_read = 600 }",
+        )
+        .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => {
+                assert_eq!(
+                    http.timeouts,
+                    Some(Timeouts {
+                        backend_response: Some(90),
+                        request_deadline: Some(120),
+                        ..Default::default()
+                    })
+                );
+                assert_eq!(
+                    http.frontends[0].timeouts,
+                    Some(Timeouts {
+                        body_read: Some(600),
+                        ..Default::default()
+                    })
+                );
+            }
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build("http", "{ request_deadline = 0 }", "{}"),
+            Err(ConfigError::InvalidTimeouts { .. })
+        ));
+        assert!(matches!(
+            build("http", "{}", "{ backend_connect = 0 }"),
+            Err(ConfigError::InvalidTimeouts { .. })
+        ));
+        assert!(matches!(
+            build("tcp", "{ backend_connect = 5 }", "{}"),
+            Err(ConfigError::InvalidTimeouts { .. })
+        ));
+    }
+
+    #[test]
+    fn frontend_mirror() {
+        let build = |protocol: &str, mirror: &str| {
+            build_config(&format!(
+                r#"
+                [clusters.app]
+                protocol = "{protocol}"
+                frontends = [{{ address = "127.0.0.1:8080", hostname = "app.example.com", mirror = {mirror} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build(
+            "http",
+            r#"{ unix_socket = "/run/mirror.sock", max_body_prefix = 1024 }"#,
+        )
+        .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.frontends[0].mirror,
+                Some(RequestMirror {
+                    sink: MirrorSink::UnixSocket as i32,
+                    path: "/run/mirror.sock".to_owned(),
+                    sample_one_in: 100,
+                    max_per_second: 10,
+                    max_body_prefix: 1024,
+                })
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build("http", r#"{ sample_one_in = 10 }"#),
+            Err(ConfigError::InvalidMirror { .. })
+        ));
+        assert!(matches!(
+            build(
+                "http",
+                r#"{ file = "/tmp/mirror", max_body_prefix = 100000 }"#
+            ),
+            Err(ConfigError::InvalidMirror { .. })
+        ));
+        assert!(matches!(
+            build("tcp", r#"{ file = "/tmp/mirror" }"#),
+            Err(ConfigError::InvalidFrontendConfig(_))
+        ));
+    }
+
+    #[test]
+    fn frontend_split() {
+        let build = |protocol: &str, split: &str| {
+            build_config(&format!(
+                r#"
+                [clusters.v1]
+                protocol = "{protocol}"
+                frontends = [{{ address = "127.0.0.1:8080", hostname = "app.example.com", split = {split} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build(
+            "http",
+            r#"[{ cluster_id = "v1", weight = 95 }, { cluster_id = "v2", weight = 5 }]"#,
+        )
+        .expect("could not build the config");
+        match config.clusters.get("v1") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.frontends[0].split,
+                vec![
+                    WeightedCluster {
+                        cluster_id: "v1".to_owned(),
+                        weight: 95
+                    },
+                    WeightedCluster {
+                        cluster_id: "v2".to_owned(),
+                        weight: 5
+                    },
+                ]
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        // the cluster of the frontend is missing, listed twice, or nothing gets a request
+        for split in [
+            r#"[{ cluster_id = "v2", weight = 5 }]"#,
+            r#"[{ cluster_id = "v1", weight = 5 }, { cluster_id = "v1", weight = 5 }]"#,
+            r#"[{ cluster_id = "v1", weight = 0 }, { cluster_id = "v2", weight = 0 }]"#,
+        ] {
+            assert!(matches!(
+                build("http", split),
+                Err(ConfigError::InvalidSplit { .. })
+            ));
+        }
+        assert!(matches!(
+            build("tcp", r#"[{ cluster_id = "v1", weight = 1 }]"#),
+            Err(ConfigError::InvalidFrontendConfig(_))
+        ));
+    }
+
+    #[test]
+    fn frontend_header_edits() {
+        let build = |edits: &str| {
+            build_config(&format!(
+                r#"
+                [clusters.app]
+                protocol = "http"
+                frontends = [{{ address = "127.0.0.1:8080", hostname = "app.example.com", {edits} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build(
+            r#"request_headers = { add = { "X-Request-Id" = "%REQUEST_ID" }, remove = ["X-Debug"] }, response_headers = { set = { "Server" = "sozu" } }"#,
+        )
+        .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.frontends[0].headers,
+                vec![
+                    HeaderEdit::new(
+                        HeaderPosition::Request,
+                        HeaderEditKind::Remove,
+                        "X-Debug",
+                        None
+                    ),
+                    HeaderEdit::new(
+                        HeaderPosition::Request,
+                        HeaderEditKind::Add,
+                        "X-Request-Id",
+                        Some("%REQUEST_ID")
+                    ),
+                    HeaderEdit::new(
+                        HeaderPosition::Response,
+                        HeaderEditKind::Set,
+                        "Server",
+                        Some("sozu")
+                    ),
+                ]
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build(r#"request_headers = { set = { "Bad Name" = "1" } }"#),
+            Err(ConfigError::InvalidHeaderEdit { .. })
+        ));
+        assert!(matches!(
+            build(r#"response_headers = { add = { "X-Two-Lines" = "a\r\nb" } }"#),
+            Err(ConfigError::InvalidHeaderEdit { .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_sni_cluster() {
+        let build = |cluster_id: &str, protocol: &str| {
+            build_config(&format!(
+                r#"
+                [[listeners]]
+                protocol = "https"
+                address = "127.0.0.1:8443"
+                unknown_sni_cluster = "{cluster_id}"
+
+                [clusters.legacy]
+                protocol = "{protocol}"
+                frontends = []
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build("legacy", "tcp").expect("could not build the config");
+        assert_eq!(
+            config.https_listeners[0].unknown_sni_cluster.as_deref(),
+            Some("legacy")
+        );
+        assert!(matches!(
+            build("legacy", "http"),
+            Err(ConfigError::InvalidUnknownSniCluster { .. })
+        ));
+        assert!(matches!(
+            build("missing", "tcp"),
+            Err(ConfigError::InvalidUnknownSniCluster { .. })
+        ));
+    }
+
+    #[test]
+    fn access_log_templates() {
+        let build = |logging: &str| build_config(logging);
+
+        let config = build(
+            r#"
+            access_logs_format = "template"
+            access_logs_template = "{client} {method} {path} {status} {response_time}"
+            "#,
+        )
+        .expect("could not build the config");
+        assert_eq!(config.access_logs_format, Some(AccessLogFormat::Template));
+        assert_eq!(
+            ServerConfig::from(&config).access_log_format(),
+            ProtobufAccessLogFormat::Template
+        );
+
+        assert!(build(r#"access_logs_format = "json""#).is_ok());
+        assert!(matches!(
+            build(r#"access_logs_format = "template""#),
+            Err(ConfigError::InvalidAccessLogTemplate(_))
+        ));
+        assert!(matches!(
+            build(r#"access_logs_template = "{client} {cookie}""#),
+            Err(ConfigError::InvalidAccessLogTemplate(_))
+        ));
+    }
+
+    #[test]
+    fn remote_write() {
+        let build = |metrics: &str| build_config(&format!("[metrics]\n{metrics}"));
+
+        let config = build(r#"remote_write_url = "https://mimir.example.com/api/v1/push""#)
+            .expect("could not build the config");
+        let metrics = config.metrics.expect("no metrics section");
+        assert_eq!(metrics.remote_write_interval, DEFAULT_REMOTE_WRITE_INTERVAL);
+        assert_eq!(
+            metrics.remote_write_queue_size,
+            DEFAULT_REMOTE_WRITE_QUEUE_SIZE
+        );
+
+        assert!(matches!(
+            build(r#"remote_write_url = "mimir.example.com/api/v1/push""#),
+            Err(ConfigError::InvalidMetrics(_))
+        ));
+        assert!(matches!(
+            build(r#"remote_write_url = "http:///api/v1/push""#),
+            Err(ConfigError::InvalidMetrics(_))
+        ));
+        assert!(matches!(
+            build(
+                r#"
+                remote_write_url = "http://127.0.0.1:9009/api/v1/push"
+                remote_write_interval = 0
+                "#
+            ),
+            Err(ConfigError::InvalidMetrics(_))
+        ));
+    }
+
+    #[test]
+    fn client_rate_limits() {
+        let build = |protocol: &str, cluster: &str, frontend: &str| {
+            let hostname = match protocol {
+                "http" => r#", hostname = "app.example.com""#,
+                _ => "",
+            };
+            build_config(&format!(
+                r#"
+                [clusters.app]
+                protocol = "{protocol}"
+                {cluster}
+                frontends = [{{ address = "127.0.0.1:8080"{hostname}{frontend} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build(
+            "http",
+            "rate_limit = { requests_per_second = 10 }",
+            ", rate_limit = { requests_per_second = 1, burst = 5 }",
+        )
+        .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => {
+                assert_eq!(
+                    http.rate_limit,
+                    Some(ClientRateLimit {
+                        requests_per_second: 10,
+                        burst: None,
+                    })
+                );
+                assert_eq!(
+                    http.frontends[0].rate_limit,
+                    Some(ClientRateLimit {
+                        requests_per_second: 1,
+                        burst: Some(5),
+                    })
+                );
+            }
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build("http", "rate_limit = { requests_per_second = 0 }", ""),
+            Err(ConfigError::InvalidClientRateLimit { .. })
+        ));
+        assert!(matches!(
+            build(
+                "http",
+                "",
+                ", rate_limit = { requests_per_second = 1, burst = 0 }"
+            ),
+            Err(ConfigError::InvalidClientRateLimit { .. })
+        ));
+        assert!(matches!(
+            build("tcp", "rate_limit = { requests_per_second = 10 }", ""),
+            Err(ConfigError::InvalidClientRateLimit { .. })
+        ));
+        assert!(matches!(
+            build("tcp", "", ", rate_limit = { requests_per_second = 10 }"),
+            Err(ConfigError::InvalidFrontendConfig(_))
+        ));
+    }
+
+    #[test]
+    fn frontend_device_match() {
+        let build = |protocol: &str, device: &str| {
+            build_config(&format!(
+                r#"
+                [clusters.app]
+                protocol = "{protocol}"
+                frontends = [{{ address = "127.0.0.1:8080", {device} }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+
+        let config = build(
+            "http",
+            r#"hostname = "m.example.com", device = "MOBILE", mobile_user_agents = ["Android"]"#,
+        )
+        .expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(http)) => assert_eq!(
+                http.frontends[0].device,
+                Some(DeviceMatch {
+                    class: DeviceClass::Mobile as i32,
+                    mobile_user_agents: vec!["Android".to_owned()],
+                })
+            ),
+            other => panic!("expected an HTTP cluster, got {other:?}"),
+        }
+
+        assert!(matches!(
+            build(
+                "http",
+                r#"hostname = "m.example.com", mobile_user_agents = ["Android"]"#
+            ),
+            Err(ConfigError::InvalidDeviceMatch { .. })
+        ));
+        assert!(matches!(
+            build("tcp", r#"device = "DESKTOP""#),
+            Err(ConfigError::InvalidFrontendConfig(_))
+        ));
+    }
+
+    #[test]
+    fn listener_request_rate_limit() {
+        let build = |rate_limit: &str| {
+            let mut listener: ListenerBuilder = toml::from_str(&format!(
+                r#"
+                address = "127.0.0.1:8080"
+                protocol = "http"
+                request_rate_limit = {rate_limit}
+                "#
+            ))
+            .expect("could not parse the toml");
+            listener.to_http(None)
+        };
+
+        let listener = build(r#"{ requests = 20, exempt = ["10.0.0.0/8", "::1"] }"#)
+            .expect("could not build the listener");
+        assert_eq!(
+            listener.request_rate_limit,
+            Some(RequestRateLimit {
+                requests: 20,
+                window: DEFAULT_RATE_LIMIT_WINDOW,
+                exempt: vec!["10.0.0.0/8".to_owned(), "::1".to_owned()],
+            })
+        );
+
+        assert!(matches!(
+            build("{ requests = 0 }"),
+            Err(ConfigError::InvalidRateLimit { .. })
+        ));
+        assert!(matches!(
+            build(r#"{ requests = 20, exempt = ["10.0.0.0/33"] }"#),
+            Err(ConfigError::InvalidRateLimit { .. })
+        ));
+    }
+
+    #[test]
+    fn listener_backend_pinning() {
+        let build = |backend_pinning: &str| {
+            let mut listener: ListenerBuilder = toml::from_str(&format!(
+                r#"
+                address = "127.0.0.1:8443"
+                protocol = "https"
+                backend_pinning = {backend_pinning}
+                "#
+            ))
+            .expect("could not parse the toml");
+            listener.to_tls(None)
+        };
+
+        let listener =
+            build(r#"{ trusted = ["10.0.0.0/8", "::1"] }"#).expect("could not build the listener");
+        assert_eq!(
+            listener.backend_pinning,
+            Some(BackendPinning {
+                header: "X-Sozu-Backend".to_owned(),
+                trusted: vec!["10.0.0.0/8".to_owned(), "::1".to_owned()],
+            })
+        );
+
+        assert!(matches!(
+            build("{ trusted = [] }"),
+            Err(ConfigError::InvalidBackendPinning { .. })
+        ));
+        assert!(matches!(
+            build(r#"{ header = "X Backend", trusted = ["10.0.0.0/8"] }"#),
+            Err(ConfigError::InvalidBackendPinning { .. })
+        ));
+        assert!(matches!(
+            build(r#"{ trusted = ["10.0.0.0/33"] }"#),
+            Err(ConfigError::InvalidBackendPinning { .. })
+        ));
+    }
+
+    #[test]
+    fn listener_duplicate_headers() {
+        let build = |duplicate_headers: &str| {
+            let mut listener: ListenerBuilder = toml::from_str(&format!(
+                r#"
+                address = "127.0.0.1:8080"
+                protocol = "http"
+                duplicate_headers = {duplicate_headers}
+                "#
+            ))
+            .expect("could not parse the toml");
+            listener.to_http(None)
+        };
+
+        let listener = build(r#"{ Host = "REJECT", x-forwarded-for = "FIRST" }"#)
+            .expect("could not build the listener");
+        assert_eq!(
+            listener.duplicate_headers,
+            vec![
+                DuplicateHeader {
+                    name: "host".to_owned(),
+                    policy: DuplicateHeaderPolicy::Reject as i32,
+                },
+                DuplicateHeader {
+                    name: "x-forwarded-for".to_owned(),
+                    policy: DuplicateHeaderPolicy::First as i32,
+                },
+            ]
+        );
+
+        assert!(matches!(
+            build(r#"{ "X Forwarded For" = "MERGE" }"#),
+            Err(ConfigError::InvalidDuplicateHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn listener_client_authentication() {
+        let build = |client_authentication: &str| {
+            let mut listener: ListenerBuilder = toml::from_str(&format!(
+                r#"
+                address = "127.0.0.1:8443"
+                protocol = "https"
+                client_authentication = {client_authentication}
+                "#
+            ))
+            .expect("could not parse the toml");
+            listener.to_tls(None)
+        };
+
+        let listener = build(
+            r#"{ mode = "OPTIONAL", ca = "assets/certificate.pem", subject_header = "X-Client-Subject" }"#,
+        )
+        .expect("could not build the listener");
+        let client_authentication = listener
+            .client_authentication
+            .expect("client authentication should be enabled");
+        assert_eq!(
+            client_authentication.mode,
+            ClientAuthenticationMode::Optional as i32
+        );
+        assert!(client_authentication
+            .ca_certificates
+            .contains("BEGIN CERTIFICATE"));
+        assert_eq!(client_authentication.crl, None);
+        let headers = client_authentication.headers;
+        assert_eq!(headers.subject, "X-Client-Subject");
+        assert_eq!(headers.verify, "X-SSL-Client-Verify");
+
+        assert!(matches!(
+            build(r#"{ ca = "assets/key.pem" }"#),
+            Err(ConfigError::InvalidClientAuthentication { .. })
+        ));
+        assert!(matches!(
+            build(r#"{ ca = "assets/certificate.pem", serial_header = "X Serial" }"#),
+            Err(ConfigError::InvalidClientAuthentication { .. })
+        ));
+        assert!(matches!(
+            build(r#"{ ca = "assets/missing.pem" }"#),
+            Err(ConfigError::FileRead { .. })
+        ));
+    }
+
+    #[test]
+    fn listener_http10_options() {
+        let mut listener: ListenerBuilder = toml::from_str(
+            r#"
+            address = "127.0.0.1:8080"
+            protocol = "http"
+            http10 = { keep_alive = false, default_host = "healthcheck.local" }
+            "#,
+        )
+        .expect("could not parse the toml");
+        let listener = listener
+            .to_http(None)
+            .expect("could not build the listener");
+        assert_eq!(
+            listener.http10,
+            Some(Http10Options {
+                implicit_close: true,
+                keep_alive: false,
+                default_host: Some("healthcheck.local".to_owned()),
+            })
+        );
+
+        let defaults = ListenerBuilder::new_http(SocketAddress::new_v4(127, 0, 0, 1, 8080))
+            .to_http(None)
+            .expect("could not build the listener");
+        assert_eq!(defaults.http10, None);
+        let options = defaults.http10.unwrap_or_default();
+        assert!(options.implicit_close && options.keep_alive);
+    }
+
+    #[test]
+    fn listener_ipv6_options() {
+        let build = |address: &str| {
+            let mut listener: ListenerBuilder = toml::from_str(&format!(
+                r#"
+                address = "{address}"
+                protocol = "tcp"
+                ipv6_only = false
+                normalize_ipv4_mapped = true
+                "#
+            ))
+            .expect("could not parse the toml");
+            listener.to_tcp(None)
+        };
+
+        let listener = build("[::]:8080").expect("could not build the listener");
+        assert_eq!(listener.ipv6_only, Some(false));
+        assert!(listener.normalize_ipv4_mapped);
+
+        assert!(matches!(
+            build("0.0.0.0:8080"),
+            Err(ConfigError::Ipv6OnlyOnIpv4(_))
+        ));
+    }
+
+    #[test]
+    fn listener_websocket_idle_timeout() {
+        let build = |protocol: &str, websocket_idle_timeout: u32| {
+            let listener: ListenerBuilder = toml::from_str(&format!(
+                r#"
+                address = "127.0.0.1:8080"
+                protocol = "{protocol}"
+                websocket_idle_timeout = {websocket_idle_timeout}
+                "#
+            ))
+            .expect("could not parse the toml");
+            listener
+        };
+
+        let http = build("http", 3600)
+            .to_http(None)
+            .expect("could not build the listener");
+        assert_eq!(http.websocket_idle_timeout, Some(3600));
+        let https = build("https", 3600)
+            .to_tls(None)
+            .expect("could not build the listener");
+        assert_eq!(https.websocket_idle_timeout, Some(3600));
+
+        assert!(matches!(
+            build("http", 0).to_http(None),
+            Err(ConfigError::InvalidTimeouts { .. })
+        ));
+    }
+
+    #[test]
+    fn replication_roles() {
+        let build = |role: &str| {
+            build_config(&format!(
+                r#"
+                [replication]
+                {role}
+                certificate = "cert.pem"
+                key = "key.pem"
+                ca_certificate = "ca.pem"
+                "#
+            ))
+        };
+
+        let standby = build(
+            r#"primary_address = "10.0.0.1:9090"
+                primary_name = "primary.example.com""#,
+        )
+        .expect("could not build the config");
+        assert!(standby
+            .replication
+            .is_some_and(|replication| replication.primary_address.is_some()));
+
+        assert!(build(r#"listen_address = "0.0.0.0:9090""#).is_ok());
+        assert!(matches!(
+            build(r#"primary_address = "10.0.0.1:9090""#),
+            Err(ConfigError::InvalidReplication(_))
+        ));
+        assert!(matches!(build(""), Err(ConfigError::InvalidReplication(_))));
+    }
+
+    #[test]
+    fn readiness_section() {
+        let config = build_config(
+            r#"
+            [readiness]
+            critical_clusters = ["app"]
+            "#,
+        )
+        .expect("could not build the config");
+        assert_eq!(
+            config.readiness,
+            Some(ReadinessConfig {
+                critical_clusters: vec!["app".to_owned()],
+                timeout: DEFAULT_READINESS_TIMEOUT,
+            })
+        );
+    }
+
+    #[test]
+    fn command_broker_section() {
+        let config = build_config(
+            r#"
+            [command_broker]
+            user = "nobody"
+            "#,
+        )
+        .expect("could not build the config");
+        assert_eq!(
+            config.command_broker,
+            Some(CommandBrokerConfig {
+                user: Some("nobody".to_owned()),
+                group: None,
+            })
+        );
+    }
+
+    #[test]
+    fn grpc_section() {
+        let config = build_config(
+            r#"
+            [grpc]
+            address = "127.0.0.1:9091"
+            certificate = "server.pem"
+            key = "server.key"
+            ca_certificate = "ca.pem"
+            "#,
+        )
+        .expect("could not build the config");
+        assert_eq!(
+            config.grpc.map(|grpc| grpc.address),
+            Some("127.0.0.1:9091".parse().unwrap())
+        );
+
+        assert!(toml::from_str::<FileConfig>(
+            r#"
+            [grpc]
+            address = "127.0.0.1:9091"
+            "#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn acme_section() {
+        let build = |domains: &str| {
+            build_config(&format!(
+                r#"
+                [acme]
+                storage = "/var/lib/sozu/acme"
+                http_listener = "0.0.0.0:80"
+                {domains}
+                "#
+            ))
+        };
+
+        let config = build(
+            r#"
+            [[acme.domains]]
+            names = ["example.com", "www.example.com"]
+            https_listener = "0.0.0.0:443"
+            "#,
+        )
+        .expect("could not build the config");
+        let acme = config.acme.expect("the acme section is missing");
+        assert_eq!(acme.directory, DEFAULT_ACME_DIRECTORY);
+        assert_eq!(acme.renew_before, DEFAULT_ACME_RENEW_BEFORE);
+        assert_eq!(
+            acme.domain("www.example.com")
+                .map(|domain| domain.https_listener),
+            Some("0.0.0.0:443".parse().unwrap())
+        );
+        assert!(acme.domain("example.org").is_none());
+
+        assert!(matches!(
+            build(
+                r#"
+                [[acme.domains]]
+                names = ["*.example.com"]
+                https_listener = "0.0.0.0:443"
+                "#
+            ),
+            Err(ConfigError::InvalidAcme(_))
+        ));
+    }
+
+    #[test]
+    fn frontends_refer_to_named_listeners() {
+        let build = |listeners: &str, frontend: &str| {
+            build_config(&format!(
+                r#"
+                {listeners}
+
+                [clusters.app]
+                protocol = "http"
+                frontends = [{{ {frontend}, hostname = "example.com" }}]
+                backends = [{{ address = "127.0.0.1:1026" }}]
+                "#
+            ))
+        };
+        let public = r#"
+            [[listeners]]
+            protocol = "http"
+            address = "0.0.0.0:8080"
+            name = "public"
+            "#;
+
+        let config = build(public, r#"listener = "public""#).expect("could not build the config");
+        match config.clusters.get("app") {
+            Some(ClusterConfig::Http(cluster)) => assert_eq!(
+                cluster.frontends[0].address,
+                "0.0.0.0:8080".parse().unwrap()
+            ),
+            cluster => panic!("unexpected cluster: {cluster:?}"),
+        }
+
+        assert!(matches!(
+            build(public, r#"listener = "internal""#),
+            Err(ConfigError::UnknownListener(_))
+        ));
+        assert!(matches!(
+            build(public, r#"listener = "public", address = "0.0.0.0:8080""#),
+            Err(ConfigError::FrontendListener(_))
+        ));
+        assert!(matches!(
+            build(
+                &format!(
+                    r#"{public}
+                    [[listeners]]
+                    protocol = "tcp"
+                    address = "0.0.0.0:5432"
+                    name = "public"
+                    "#
+                ),
+                r#"address = "0.0.0.0:8080""#
+            ),
+            Err(ConfigError::ListenerNameAlreadyUsed(_))
+        ));
+        assert!(!is_valid_listener_name("127.0.0.1:80"));
+        assert!(!is_valid_listener_name("my listener"));
+    }
+    #[test]
+    fn check_reports_all_errors() {
+        let file_config: FileConfig = toml::from_str(
+            r#"
+            [[listeners]]
+            protocol = "http"
+            address = "0.0.0.0:8080"
+            name = "public"
+            request_deadline = 0
+
+            [[listeners]]
+            protocol = "tcp"
+            address = "0.0.0.0:5432"
+            name = "public"
+
+            [clusters.app]
+            protocol = "http"
+            template = "unknown"
+            frontends = []
+            backends = []
+
+            [clusters.other]
+            protocol = "http"
+            frontends = [{ address = "0.0.0.0:8080", hostname = "example.com" }]
+            backends = [{ address = "127.0.0.1:1026" }]
+            "#,
+        )
+        .expect("could not parse the toml");
+
+        let (config, errors) = ConfigBuilder::new(file_config.clone(), "").check();
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                ConfigError::InvalidTimeouts { .. },
+                ConfigError::ListenerNameAlreadyUsed(_),
+                ConfigError::UnknownTemplate { .. },
+            ]
+        ));
+        // the valid parts are still built
+        assert!(config.clusters.contains_key("other"));
+
+        // into_config only returns the first one
+        assert!(matches!(
+            ConfigBuilder::new(file_config, "").into_config(),
+            Err(ConfigError::InvalidTimeouts { .. })
+        ));
+    }
 }
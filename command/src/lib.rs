@@ -8,6 +8,8 @@ extern crate serde;
 pub mod logging;
 /// Custom buffer used for parsing within the Sōzu codebase.
 pub mod buffer;
+/// Builders of the requests adding clusters, frontends and backends
+pub mod builder;
 /// TLS certificates
 pub mod certificate;
 /// channels used for communication between main process and workers
@@ -67,8 +69,14 @@ pub mod request;
 pub mod response;
 /// sockets used to pass file descriptors
 pub mod scm_socket;
+/// Keys signing the values given to clients, and their rotation
+pub mod signing;
 /// A representation of Sōzu's state
 pub mod state;
+/// A SQL like language to query the state
+pub mod state_query;
+/// Compact transfer of the state to new workers
+pub mod state_transfer;
 /// A writer used for logging
 pub mod writer;
 
@@ -86,6 +94,27 @@ pub enum ObjectKind {
     TcpCluster,
     TcpListener,
     TcpFrontend,
+    ScheduledChange,
+}
+
+impl ObjectKind {
+    /// name of the kind in structured errors
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectKind::Backend => "backend",
+            ObjectKind::Certificate => "certificate",
+            ObjectKind::Cluster => "cluster",
+            ObjectKind::HttpFrontend => "http_frontend",
+            ObjectKind::HttpsFrontend => "https_frontend",
+            ObjectKind::HttpListener => "http_listener",
+            ObjectKind::HttpsListener => "https_listener",
+            ObjectKind::Listener => "listener",
+            ObjectKind::TcpCluster => "tcp_cluster",
+            ObjectKind::TcpListener => "tcp_listener",
+            ObjectKind::TcpFrontend => "tcp_frontend",
+            ObjectKind::ScheduledChange => "scheduled_change",
+        }
+    }
 }
 
 pub trait AsString {
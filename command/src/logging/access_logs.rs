@@ -1,4 +1,12 @@
-use std::{collections::BTreeMap, mem::ManuallyDrop, net::SocketAddr, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{Error as IoError, Write},
+    mem::ManuallyDrop,
+    net::SocketAddr,
+    str::FromStr,
+    time::Duration,
+};
 
 use rusty_ulid::Ulid;
 
@@ -6,6 +14,7 @@ use crate::{
     logging::{LogLevel, Rfc3339Time},
     proto::command::{
         protobuf_endpoint, HttpEndpoint, ProtobufAccessLog, ProtobufEndpoint, TcpEndpoint,
+        TlsProperties,
     },
 };
 
@@ -55,6 +64,7 @@ impl<T> DuplicateOwnership for &[T] {
 
 pub struct LogMessage<'a>(pub Option<&'a str>);
 pub struct LogDuration(pub Option<Duration>);
+pub struct LogTls<'a>(pub Option<&'a TlsRecord<'a>>);
 
 #[derive(Debug)]
 pub struct LogContext<'a> {
@@ -92,6 +102,17 @@ impl CachedTags {
     }
 }
 
+/// properties of the TLS handshake of an HTTPS or WSS session
+#[derive(Debug)]
+pub struct TlsRecord<'a> {
+    pub version: &'a str,
+    pub cipher: &'a str,
+    pub sni: Option<&'a str>,
+    pub alpn: Option<&'a str>,
+    pub resumed: bool,
+    pub client_certificate_subject: Option<&'a str>,
+}
+
 #[derive(Debug)]
 pub struct FullTags<'a> {
     pub concatenated: Option<&'a str>,
@@ -108,6 +129,7 @@ pub struct RequestRecord<'a> {
     pub protocol: &'a str,
     pub endpoint: EndpointRecord<'a>,
     pub tags: Option<&'a CachedTags>,
+    pub tls: Option<TlsRecord<'a>>,
     pub client_rtt: Option<Duration>,
     pub server_rtt: Option<Duration>,
     pub user_agent: Option<&'a str>,
@@ -153,6 +175,14 @@ impl RequestRecord<'_> {
                 }),
                 EndpointRecord::Tcp => protobuf_endpoint::Inner::Tcp(TcpEndpoint {}),
             };
+            let tls = self.tls.map(|tls| TlsProperties {
+                version: tls.version.duplicate(),
+                cipher: tls.cipher.duplicate(),
+                sni: tls.sni.duplicate(),
+                alpn: tls.alpn.duplicate(),
+                resumed: tls.resumed,
+                client_certificate_subject: tls.client_certificate_subject.duplicate(),
+            });
 
             ManuallyDrop::new(ProtobufAccessLog {
                 backend_address: self.backend_address.map(Into::into),
@@ -178,7 +208,453 @@ impl RequestRecord<'_> {
                 user_agent: self.user_agent.duplicate(),
                 tag: self.tag.duplicate(),
                 time: self.precise_time.into(),
+                tls,
             })
         }
     }
 }
+
+/// method, authority, path, status and reason of an HTTP endpoint
+type HttpEndpointFields<'a> = (
+    Option<&'a str>,
+    Option<&'a str>,
+    Option<&'a str>,
+    Option<u16>,
+    Option<&'a str>,
+);
+
+impl RequestRecord<'_> {
+    fn http_endpoint(&self) -> HttpEndpointFields {
+        match self.endpoint {
+            EndpointRecord::Http {
+                method,
+                authority,
+                path,
+                status,
+                reason,
+            } => (method, authority, path, status, reason),
+            EndpointRecord::Tcp => (None, None, None, None, None),
+        }
+    }
+
+    /// Writes the access log as a single line JSON object, durations are in microseconds
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> Result<(), IoError> {
+        let (method, authority, path, status, reason) = self.http_endpoint();
+        let log = JsonAccessLog {
+            time: self.now.to_string(),
+            pid: self.pid,
+            tag: self.tag,
+            level: self.level.as_str(true, false).trim_end(),
+            request_id: self.context.request_id.to_string(),
+            cluster_id: self.context.cluster_id,
+            backend_id: self.context.backend_id,
+            client: self.session_address,
+            backend: self.backend_address,
+            protocol: self.protocol,
+            method,
+            authority,
+            path,
+            status,
+            reason,
+            response_time: self.response_time.as_micros() as u64,
+            service_time: self.service_time.as_micros() as u64,
+            client_rtt: self.client_rtt.map(|t| t.as_micros() as u64),
+            server_rtt: self.server_rtt.map(|t| t.as_micros() as u64),
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            tags: self.tags.map(|tags| &tags.tags),
+            user_agent: self.user_agent,
+            tls: self.tls.as_ref().map(|tls| JsonTls {
+                version: tls.version,
+                cipher: tls.cipher,
+                sni: tls.sni,
+                alpn: tls.alpn,
+                resumed: tls.resumed,
+                client_certificate_subject: tls.client_certificate_subject,
+            }),
+            message: self.message,
+        };
+        serde_json::to_writer(&mut *writer, &log).map_err(IoError::from)?;
+        writer.write_all(b"\n")
+    }
+}
+
+#[derive(Serialize)]
+struct JsonTls<'a> {
+    version: &'a str,
+    cipher: &'a str,
+    sni: Option<&'a str>,
+    alpn: Option<&'a str>,
+    resumed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_certificate_subject: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonAccessLog<'a> {
+    time: String,
+    pid: i32,
+    tag: &'a str,
+    level: &'a str,
+    request_id: String,
+    cluster_id: Option<&'a str>,
+    backend_id: Option<&'a str>,
+    client: Option<SocketAddr>,
+    backend: Option<SocketAddr>,
+    protocol: &'a str,
+    method: Option<&'a str>,
+    authority: Option<&'a str>,
+    path: Option<&'a str>,
+    status: Option<u16>,
+    reason: Option<&'a str>,
+    response_time: u64,
+    service_time: u64,
+    client_rtt: Option<u64>,
+    server_rtt: Option<u64>,
+    bytes_in: usize,
+    bytes_out: usize,
+    tags: Option<&'a BTreeMap<String, String>>,
+    user_agent: Option<&'a str>,
+    tls: Option<JsonTls<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+}
+
+/// A value of an access log that can be placed in a template, as `{name}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogField {
+    Time,
+    Pid,
+    Tag,
+    RequestId,
+    ClusterId,
+    BackendId,
+    Client,
+    Backend,
+    Protocol,
+    Method,
+    Authority,
+    Path,
+    Status,
+    Reason,
+    ResponseTime,
+    ServiceTime,
+    ClientRtt,
+    ServerRtt,
+    BytesIn,
+    BytesOut,
+    Tags,
+    UserAgent,
+    TlsVersion,
+    Sni,
+    Message,
+}
+
+impl FromStr for AccessLogField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "time" => Self::Time,
+            "pid" => Self::Pid,
+            "tag" => Self::Tag,
+            "request_id" => Self::RequestId,
+            "cluster_id" => Self::ClusterId,
+            "backend_id" => Self::BackendId,
+            "client" => Self::Client,
+            "backend" => Self::Backend,
+            "protocol" => Self::Protocol,
+            "method" => Self::Method,
+            "authority" => Self::Authority,
+            "path" => Self::Path,
+            "status" => Self::Status,
+            "reason" => Self::Reason,
+            "response_time" => Self::ResponseTime,
+            "service_time" => Self::ServiceTime,
+            "client_rtt" => Self::ClientRtt,
+            "server_rtt" => Self::ServerRtt,
+            "bytes_in" => Self::BytesIn,
+            "bytes_out" => Self::BytesOut,
+            "tags" => Self::Tags,
+            "user_agent" => Self::UserAgent,
+            "tls_version" => Self::TlsVersion,
+            "sni" => Self::Sni,
+            "message" => Self::Message,
+            _ => return Err(format!("unknown field {{{s}}}")),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Field(AccessLogField),
+}
+
+/// A parsed access log format string, like `{client} {method} {path} {status}`.
+///
+/// `{{` and `}}` stand for literal braces, absent values are written as `-`
+/// and durations are in microseconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogTemplate(Vec<TemplatePart>);
+
+impl FromStr for AccessLogTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        return Err(format!("unclosed brace in {s:?}"));
+                    };
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TemplatePart::Field(rest[..end].parse()?));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("unmatched closing brace in {s:?}")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Self(parts))
+    }
+}
+
+struct OrDash<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for OrDash<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+impl AccessLogTemplate {
+    /// Writes the access log following the template, with a trailing newline
+    pub fn write<W: Write>(&self, log: &RequestRecord, writer: &mut W) -> Result<(), IoError> {
+        let (method, authority, path, status, reason) = log.http_endpoint();
+        for part in &self.0 {
+            let field = match part {
+                TemplatePart::Literal(literal) => {
+                    writer.write_all(literal.as_bytes())?;
+                    continue;
+                }
+                TemplatePart::Field(field) => field,
+            };
+            match field {
+                AccessLogField::Time => write!(writer, "{}", log.now),
+                AccessLogField::Pid => write!(writer, "{}", log.pid),
+                AccessLogField::Tag => write!(writer, "{}", log.tag),
+                AccessLogField::RequestId => write!(writer, "{}", log.context.request_id),
+                AccessLogField::ClusterId => write!(writer, "{}", OrDash(log.context.cluster_id)),
+                AccessLogField::BackendId => write!(writer, "{}", OrDash(log.context.backend_id)),
+                AccessLogField::Client => write!(writer, "{}", OrDash(log.session_address)),
+                AccessLogField::Backend => write!(writer, "{}", OrDash(log.backend_address)),
+                AccessLogField::Protocol => write!(writer, "{}", log.protocol),
+                AccessLogField::Method => write!(writer, "{}", OrDash(method)),
+                AccessLogField::Authority => write!(writer, "{}", OrDash(authority)),
+                AccessLogField::Path => write!(writer, "{}", OrDash(path)),
+                AccessLogField::Status => write!(writer, "{}", OrDash(status)),
+                AccessLogField::Reason => write!(writer, "{}", OrDash(reason)),
+                AccessLogField::ResponseTime => {
+                    write!(writer, "{}", log.response_time.as_micros())
+                }
+                AccessLogField::ServiceTime => write!(writer, "{}", log.service_time.as_micros()),
+                AccessLogField::ClientRtt => {
+                    write!(writer, "{}", OrDash(log.client_rtt.map(|t| t.as_micros())))
+                }
+                AccessLogField::ServerRtt => {
+                    write!(writer, "{}", OrDash(log.server_rtt.map(|t| t.as_micros())))
+                }
+                AccessLogField::BytesIn => write!(writer, "{}", log.bytes_in),
+                AccessLogField::BytesOut => write!(writer, "{}", log.bytes_out),
+                AccessLogField::Tags => write!(
+                    writer,
+                    "{}",
+                    OrDash(
+                        log.tags
+                            .map(|tags| tags.concatenated.as_str())
+                            .filter(|tags| !tags.is_empty())
+                    )
+                ),
+                AccessLogField::UserAgent => write!(writer, "{}", OrDash(log.user_agent)),
+                AccessLogField::TlsVersion => {
+                    write!(
+                        writer,
+                        "{}",
+                        OrDash(log.tls.as_ref().map(|tls| tls.version))
+                    )
+                }
+                AccessLogField::Sni => write!(
+                    writer,
+                    "{}",
+                    OrDash(log.tls.as_ref().and_then(|tls| tls.sni))
+                ),
+                AccessLogField::Message => write!(writer, "{}", OrDash(log.message)),
+            }?;
+        }
+        writer.write_all(b"\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_log_template_and_json() {
+        let tags = CachedTags::new(BTreeMap::from([("env".to_owned(), "prod".to_owned())]));
+        let log = RequestRecord {
+            message: None,
+            context: LogContext {
+                request_id: Ulid::from(0u128),
+                cluster_id: Some("app"),
+                backend_id: None,
+            },
+            session_address: Some("127.0.0.1:4242".parse().unwrap()),
+            backend_address: None,
+            protocol: "HTTP",
+            endpoint: EndpointRecord::Http {
+                method: Some("GET"),
+                authority: Some("app.example.com"),
+                path: Some("/"),
+                status: Some(200),
+                reason: Some("OK"),
+            },
+            tags: Some(&tags),
+            tls: None,
+            client_rtt: None,
+            server_rtt: None,
+            user_agent: None,
+            service_time: Duration::from_micros(150),
+            response_time: Duration::from_millis(2),
+            bytes_in: 10,
+            bytes_out: 20,
+            pid: 1,
+            tag: "WRK-00",
+            level: LogLevel::Info,
+            now: Rfc3339Time {
+                inner: ::time::OffsetDateTime::UNIX_EPOCH,
+            },
+            precise_time: 0,
+        };
+
+        let template: AccessLogTemplate =
+            "{{{cluster_id}}} {backend_id} {client} {method} {path} {status} {response_time} {bytes_out} {tags}"
+                .parse()
+                .expect("could not parse the template");
+        let mut line = Vec::new();
+        template.write(&log, &mut line).unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "{app} - 127.0.0.1:4242 GET / 200 2000 20 env=prod\n"
+        );
+
+        assert!("{unknown}".parse::<AccessLogTemplate>().is_err());
+        assert!("{status".parse::<AccessLogTemplate>().is_err());
+        assert!("status}".parse::<AccessLogTemplate>().is_err());
+
+        let mut line = Vec::new();
+        log.write_json(&mut line).unwrap();
+        assert_eq!(line.last(), Some(&b'\n'));
+        let json: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(json["cluster_id"], "app");
+        assert_eq!(json["backend_id"], serde_json::Value::Null);
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["response_time"], 2000);
+        assert_eq!(json["tags"]["env"], "prod");
+        assert_eq!(json["level"], "INFO-ACCESS");
+    }
+
+    #[test]
+    fn log_the_tls_properties() {
+        let log = RequestRecord {
+            message: None,
+            context: LogContext {
+                request_id: Ulid::from(0u128),
+                cluster_id: Some("app"),
+                backend_id: None,
+            },
+            session_address: Some("127.0.0.1:4242".parse().unwrap()),
+            backend_address: None,
+            protocol: "HTTPS",
+            endpoint: EndpointRecord::Http {
+                method: Some("GET"),
+                authority: Some("app.example.com"),
+                path: Some("/"),
+                status: Some(200),
+                reason: Some("OK"),
+            },
+            tags: None,
+            tls: Some(TlsRecord {
+                version: "TLSv1.2",
+                cipher: "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                sni: Some("app.example.com"),
+                alpn: None,
+                resumed: true,
+                client_certificate_subject: Some("CN=client"),
+            }),
+            client_rtt: None,
+            server_rtt: None,
+            user_agent: None,
+            service_time: Duration::from_micros(150),
+            response_time: Duration::from_millis(2),
+            bytes_in: 10,
+            bytes_out: 20,
+            pid: 1,
+            tag: "WRK-00",
+            level: LogLevel::Info,
+            now: Rfc3339Time {
+                inner: ::time::OffsetDateTime::UNIX_EPOCH,
+            },
+            precise_time: 0,
+        };
+
+        let template: AccessLogTemplate = "{tls_version} {sni} {status}"
+            .parse()
+            .expect("could not parse the template");
+        let mut line = Vec::new();
+        template.write(&log, &mut line).unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "TLSv1.2 app.example.com 200\n"
+        );
+
+        assert_eq!(
+            LogTls(log.tls.as_ref()).to_string(),
+            " [tls TLSv1.2 TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 sni=app.example.com alpn=- resumed client_cert=\"CN=client\"]"
+        );
+        assert_eq!(LogTls(None).to_string(), "");
+
+        let mut line = Vec::new();
+        log.write_json(&mut line).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(json["tls"]["version"], "TLSv1.2");
+        assert_eq!(
+            json["tls"]["cipher"],
+            "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"
+        );
+        assert_eq!(json["tls"]["alpn"], serde_json::Value::Null);
+        assert_eq!(json["tls"]["resumed"], true);
+        assert_eq!(json["tls"]["client_certificate_subject"], "CN=client");
+    }
+}
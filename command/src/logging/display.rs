@@ -2,8 +2,8 @@ use std::fmt;
 
 use crate::{
     logging::{
-        EndpointRecord, FullTags, LogContext, LogDuration, LogLevel, LogMessage, LoggerBackend,
-        Rfc3339Time,
+        EndpointRecord, FullTags, LogContext, LogDuration, LogLevel, LogMessage, LogTls,
+        LoggerBackend, Rfc3339Time,
     },
     AsStr,
 };
@@ -72,6 +72,27 @@ impl fmt::Display for LogMessage<'_> {
     }
 }
 
+impl fmt::Display for LogTls<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(tls) = self.0 else {
+            return Ok(());
+        };
+        write!(
+            f,
+            " [tls {} {} sni={} alpn={} {}",
+            tls.version,
+            tls.cipher,
+            tls.sni.unwrap_or("-"),
+            tls.alpn.unwrap_or("-"),
+            if tls.resumed { "resumed" } else { "full" },
+        )?;
+        if let Some(subject) = tls.client_certificate_subject {
+            write!(f, " client_cert=\"{subject}\"")?;
+        }
+        write!(f, "]")
+    }
+}
+
 impl fmt::Display for LogDuration {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.0 {
@@ -16,7 +16,7 @@ use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
 use crate::{
     config::Config,
-    logging::{LogDuration, LogMessage, RequestRecord},
+    logging::{AccessLogTemplate, LogDuration, LogMessage, LogTls, RequestRecord},
     proto::command::ProtobufAccessLogFormat,
     AsString,
 };
@@ -35,6 +35,9 @@ pub static COMPAT_LOGGER: CompatLogger = CompatLogger;
 pub enum AccessLogFormat {
     Ascii,
     Protobuf,
+    Json,
+    /// follows the `access_logs_template` format string
+    Template,
 }
 
 impl From<&ProtobufAccessLogFormat> for AccessLogFormat {
@@ -42,6 +45,8 @@ impl From<&ProtobufAccessLogFormat> for AccessLogFormat {
         match value {
             ProtobufAccessLogFormat::Ascii => Self::Ascii,
             ProtobufAccessLogFormat::Protobuf => Self::Protobuf,
+            ProtobufAccessLogFormat::Json => Self::Json,
+            ProtobufAccessLogFormat::Template => Self::Template,
         }
     }
 }
@@ -51,6 +56,8 @@ impl From<&Option<AccessLogFormat>> for ProtobufAccessLogFormat {
         match value {
             Some(AccessLogFormat::Ascii) | None => Self::Ascii,
             Some(AccessLogFormat::Protobuf) => Self::Protobuf,
+            Some(AccessLogFormat::Json) => Self::Json,
+            Some(AccessLogFormat::Template) => Self::Template,
         }
     }
 }
@@ -64,6 +71,8 @@ pub struct InnerLogger {
     access_backend: Option<LoggerBackend>,
     /// how to format the access logs
     access_format: AccessLogFormat,
+    /// used by the template format, which falls back to ascii without it
+    access_template: Option<AccessLogTemplate>,
     access_colored: bool,
     buffer: LoggerBuffer,
 }
@@ -102,6 +111,7 @@ impl Default for Logger {
                 colored: false,
                 access_backend: None,
                 access_format: AccessLogFormat::Ascii,
+                access_template: None,
                 access_colored: false,
                 buffer: LoggerBuffer(Vec::with_capacity(4096)),
             },
@@ -117,6 +127,7 @@ impl Logger {
         Self::default()
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn init(
         tag: String,
         spec: &str,
@@ -124,6 +135,7 @@ impl Logger {
         colored: bool,
         access_backend: Option<LoggerBackend>,
         access_format: Option<AccessLogFormat>,
+        access_template: Option<AccessLogTemplate>,
         access_colored: Option<bool>,
     ) {
         let (directives, _errors) = parse_logging_spec(spec);
@@ -144,6 +156,7 @@ impl Logger {
                 logger.backend = backend;
                 logger.access_backend = access_backend;
                 logger.access_format = access_format.unwrap_or(AccessLogFormat::Ascii);
+                logger.access_template = access_template;
                 logger.tag = tag;
                 logger.pid = unsafe { libc::getpid() };
                 logger.initialized = true;
@@ -164,6 +177,18 @@ impl Logger {
         self.directives = directives;
     }
 
+    /// replace the target of the logs, the access logs follow it if they have no target of their own
+    pub fn set_backend(&mut self, backend: LoggerBackend, colored: bool) {
+        self.colored = match backend {
+            LoggerBackend::Stdout(_) => colored,
+            _ => false,
+        };
+        if self.access_backend.is_none() && !matches!(backend, LoggerBackend::Stdout(_)) {
+            self.access_colored = false;
+        }
+        self.backend = backend;
+    }
+
     pub fn split(&mut self) -> (i32, &str, &mut InnerLogger) {
         (self.pid, &self.tag, &mut self.inner)
     }
@@ -213,6 +238,21 @@ fn log_arguments(
     }
 }
 
+/// write a preformatted access log in one call, to keep datagrams whole
+fn write_access_bytes(backend: &mut LoggerBackend, bytes: &[u8]) -> Result<(), IoError> {
+    match backend {
+        LoggerBackend::Stdout(stdout) => {
+            let _ = stdout.write(bytes);
+            return Ok(());
+        }
+        LoggerBackend::Tcp(socket) => socket.write(bytes),
+        LoggerBackend::File(file) => file.write(bytes),
+        LoggerBackend::Unix(socket) => socket.send(bytes),
+        LoggerBackend::Udp(socket, address) => socket.send_to(bytes, *address),
+    }
+    .map(|_| ())
+}
+
 impl InnerLogger {
     pub fn log(&mut self, args: Arguments) {
         if let Err(e) = log_arguments(args, &mut self.backend, &mut self.buffer) {
@@ -222,12 +262,13 @@ impl InnerLogger {
 
     /// write an access log to the proper logging target
     ///
-    /// Protobuf access logs are written with a prost length delimiter before, and 2 empty bytes after
+    /// Protobuf access logs are written with a prost length delimiter before, and 2 empty bytes after.
+    /// JSON and template access logs are written one per line.
     pub fn log_access(&mut self, log: RequestRecord) {
         let backend = self.access_backend.as_mut().unwrap_or(&mut self.backend);
 
-        let io_result = match self.access_format {
-            AccessLogFormat::Protobuf => {
+        let io_result = match (&self.access_format, &self.access_template) {
+            (AccessLogFormat::Protobuf, _) => {
                 let binary_log = log.into_binary_access_log();
                 let log_length = binary_log.encoded_len();
                 let total_length = log_length + encoded_len_varint(log_length as u64);
@@ -241,54 +282,57 @@ impl InnerLogger {
                     Err(IoError::new(IoErrorKind::InvalidData, e))
                 } else {
                     self.buffer.extend_from_slice(&[0, 0]); // add two empty bytes after each protobuf access log
-                    let bytes = &self.buffer;
-                    match backend {
-                        LoggerBackend::Stdout(stdout) => {
-                            let _ = stdout.write(bytes);
-                            return;
-                        }
-                        LoggerBackend::Tcp(socket) => socket.write(bytes),
-                        LoggerBackend::File(file) => file.write(bytes),
-                        LoggerBackend::Unix(socket) => socket.send(bytes),
-                        LoggerBackend::Udp(socket, address) => socket.send_to(bytes, *address),
-                    }
-                    .map(|_| ())
+                    write_access_bytes(backend, &self.buffer)
                 }
             }
-            AccessLogFormat::Ascii => crate::_prompt_log! {
-                logger: |args| log_arguments(args, backend, &mut self.buffer),
-                is_access: true,
-                condition: self.access_colored,
-                prompt: [
-                    log.now,
-                    log.precise_time,
-                    log.pid,
-                    log.level,
-                    log.tag,
-                ],
-                standard: {
-                    formats: ["{} {} {} {}/{}/{}/{} {} {} [{}] {} {}{}\n"],
-                    args: [
-                        log.context,
-                        log.session_address.as_string_or("-"),
-                        log.backend_address.as_string_or("-"),
-                        LogDuration(Some(log.response_time)),
-                        LogDuration(Some(log.service_time)),
-                        LogDuration(log.client_rtt),
-                        LogDuration(log.server_rtt),
-                        log.bytes_in,
-                        log.bytes_out,
-                        log.full_tags(),
-                        log.protocol,
-                        log.endpoint,
-                        LogMessage(log.message),
-                    ]
-                },
-                colored: {
-                    formats: ["\x1b[;1m{}\x1b[m {} {} {}/{}/{}/{} {} {} \x1b[2m[{}] \x1b[;1m{} {:#}\x1b[m{}\n"],
-                    args: @,
+            (AccessLogFormat::Json, _) => {
+                self.buffer.clear();
+                log.write_json(&mut self.buffer.0)
+                    .and_then(|_| write_access_bytes(backend, &self.buffer))
+            }
+            (AccessLogFormat::Template, Some(template)) => {
+                self.buffer.clear();
+                template
+                    .write(&log, &mut self.buffer.0)
+                    .and_then(|_| write_access_bytes(backend, &self.buffer))
+            }
+            (AccessLogFormat::Ascii, _) | (AccessLogFormat::Template, None) => {
+                crate::_prompt_log! {
+                    logger: |args| log_arguments(args, backend, &mut self.buffer),
+                    is_access: true,
+                    condition: self.access_colored,
+                    prompt: [
+                        log.now,
+                        log.precise_time,
+                        log.pid,
+                        log.level,
+                        log.tag,
+                    ],
+                    standard: {
+                        formats: ["{} {} {} {}/{}/{}/{} {} {} [{}] {} {}{}{}\n"],
+                        args: [
+                            log.context,
+                            log.session_address.as_string_or("-"),
+                            log.backend_address.as_string_or("-"),
+                            LogDuration(Some(log.response_time)),
+                            LogDuration(Some(log.service_time)),
+                            LogDuration(log.client_rtt),
+                            LogDuration(log.server_rtt),
+                            log.bytes_in,
+                            log.bytes_out,
+                            log.full_tags(),
+                            log.protocol,
+                            log.endpoint,
+                            LogTls(log.tls.as_ref()),
+                            LogMessage(log.message),
+                        ]
+                    },
+                    colored: {
+                        formats: ["\x1b[;1m{}\x1b[m {} {} {}/{}/{}/{} {} {} \x1b[2m[{}] \x1b[;1m{} {:#}\x1b[m{}{}\n"],
+                        args: @,
+                    }
                 }
-            },
+            }
         };
 
         if let Err(e) = io_result {
@@ -585,7 +629,22 @@ pub fn parse_logging_spec(spec: &str) -> (Vec<LogDirective>, Vec<LogSpecParseErr
 
 /// start the logger with all logs and access logs on stdout
 pub fn setup_default_logging(log_colored: bool, log_level: &str, tag: &str) {
-    setup_logging("stdout", log_colored, None, None, None, log_level, tag)
+    setup_logging(
+        "stdout",
+        log_colored,
+        None,
+        None,
+        None,
+        None,
+        log_level,
+        tag,
+    )
+}
+
+/// the tag of a process in the logs, with the generation of the main process that
+/// started it, to tell apart old and new processes after an upgrade: "WRK-01/g2"
+pub fn generation_tag(tag: &str, generation: u32) -> String {
+    format!("{tag}/g{generation}")
 }
 
 /// start the logger from config (takes RUST_LOG into account)
@@ -595,6 +654,7 @@ pub fn setup_logging_with_config(config: &Config, tag: &str) {
         config.log_colored,
         config.access_logs_target.as_deref(),
         config.access_logs_format.clone(),
+        config.access_logs_template.as_deref(),
         config.access_logs_colored,
         &config.log_level,
         tag,
@@ -605,17 +665,25 @@ pub fn setup_logging_with_config(config: &Config, tag: &str) {
 ///
 /// - determining logging backends
 /// - taking RUST_LOG into account
+#[allow(clippy::too_many_arguments)]
 pub fn setup_logging(
     log_target: &str,
     log_colored: bool,
     access_logs_target: Option<&str>,
     access_logs_format: Option<AccessLogFormat>,
+    access_logs_template: Option<&str>,
     access_logs_colored: Option<bool>,
     log_level: &str,
     tag: &str,
 ) {
     let backend = target_to_backend(log_target);
     let access_backend = access_logs_target.map(target_to_backend);
+    let access_template = access_logs_template.and_then(|template| {
+        template
+            .parse::<AccessLogTemplate>()
+            .map_err(|e| println!("invalid access log template, using ascii access logs: {e}"))
+            .ok()
+    });
 
     Logger::init(
         tag.to_string(),
@@ -624,66 +692,71 @@ pub fn setup_logging(
         log_colored,
         access_backend,
         access_logs_format,
+        access_template,
         access_logs_colored,
     );
 }
 
 pub fn target_to_backend(target: &str) -> LoggerBackend {
-    if target == "stdout" {
+    try_target_to_backend(target).unwrap_or_else(|e| {
+        println!("invalid log target configuration: {e}");
         LoggerBackend::Stdout(stdout())
+    })
+}
+
+/// open the logging backend of a target, like "stdout", "udp://127.0.0.1:9000"
+/// or "file:///var/log/sozu.log"
+pub fn try_target_to_backend(target: &str) -> Result<LoggerBackend, String> {
+    if target == "stdout" {
+        Ok(LoggerBackend::Stdout(stdout()))
     } else if let Some(addr) = target.strip_prefix("udp://") {
-        match addr.to_socket_addrs() {
-            Err(e) => {
-                println!("invalid log target configuration ({e:?}): {target}");
-                LoggerBackend::Stdout(stdout())
-            }
-            Ok(mut addrs) => {
-                let socket = UdpSocket::bind(("0.0.0.0", 0)).unwrap();
-                LoggerBackend::Udp(socket, addrs.next().unwrap())
-            }
-        }
+        let addr = resolve_target_address(addr, target)?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0))
+            .map_err(|e| format!("could not bind an UDP socket (error: {e:?})"))?;
+        Ok(LoggerBackend::Udp(socket, addr))
     } else if let Some(addr) = target.strip_prefix("tcp://") {
-        match addr.to_socket_addrs() {
-            Err(e) => {
-                println!("invalid log target configuration ({e:?}): {target}");
-                LoggerBackend::Stdout(stdout())
-            }
-            Ok(mut addrs) => LoggerBackend::Tcp(TcpStream::connect(addrs.next().unwrap()).unwrap()),
-        }
+        let addr = resolve_target_address(addr, target)?;
+        TcpStream::connect(addr)
+            .map(LoggerBackend::Tcp)
+            .map_err(|e| format!("could not connect to {addr} (error: {e:?})"))
     } else if let Some(addr) = target.strip_prefix("unix://") {
         let path = Path::new(addr);
         if !path.exists() {
-            println!("invalid log target configuration: {addr} is not a file");
-            LoggerBackend::Stdout(stdout())
-        } else {
-            let mut dir = env::temp_dir();
-            let s: String = thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(12)
-                .map(|c| c as char)
-                .collect();
-            dir.push(s);
-            let socket = UnixDatagram::bind(dir).unwrap();
-            socket.connect(path).unwrap();
-            LoggerBackend::Unix(socket)
+            return Err(format!("{addr} is not a file"));
         }
+        let mut dir = env::temp_dir();
+        let s: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(|c| c as char)
+            .collect();
+        dir.push(s);
+        let socket = UnixDatagram::bind(dir)
+            .map_err(|e| format!("could not bind an unix socket (error: {e:?})"))?;
+        socket
+            .connect(path)
+            .map_err(|e| format!("could not connect to {addr} (error: {e:?})"))?;
+        Ok(LoggerBackend::Unix(socket))
     } else if let Some(addr) = target.strip_prefix("file://") {
         let path = Path::new(addr);
-        match OpenOptions::new().create(true).append(true).open(path) {
-            Ok(file) => LoggerBackend::File(crate::writer::MultiLineWriter::new(file)),
-            Err(e) => {
-                println!(
-                    "invalid log target configuration: could not open file at {addr} (error: {e:?})"
-                );
-                LoggerBackend::Stdout(stdout())
-            }
-        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(|file| LoggerBackend::File(crate::writer::MultiLineWriter::new(file)))
+            .map_err(|e| format!("could not open file at {addr} (error: {e:?})"))
     } else {
-        println!("invalid log target configuration: {target}");
-        LoggerBackend::Stdout(stdout())
+        Err(target.to_owned())
     }
 }
 
+fn resolve_target_address(addr: &str, target: &str) -> Result<SocketAddr, String> {
+    addr.to_socket_addrs()
+        .map_err(|e| format!("{target} ({e:?})"))?
+        .next()
+        .ok_or_else(|| format!("{target} does not resolve to any address"))
+}
+
 #[macro_export]
 macro_rules! _prompt_log {
     {
@@ -1005,6 +1078,7 @@ macro_rules! setup_test_logger {
             None,
             None,
             None,
+            None,
         );
     };
 }
@@ -5,19 +5,26 @@ use std::{
 };
 
 use prettytable::{cell, row, Row, Table};
-use time::format_description;
+use time::{format_description, OffsetDateTime};
 use x509_parser::time::ASN1Time;
 
 use crate::{
     proto::{
         command::{
             filtered_metrics, protobuf_endpoint, request::RequestType,
-            response_content::ContentType, AggregatedMetrics, AvailableMetrics, CertificateAndKey,
-            CertificateSummary, CertificatesWithFingerprints, ClusterMetrics, CustomHttpAnswers,
-            Event, EventKind, FilteredMetrics, HttpEndpoint, HttpListenerConfig,
-            HttpsListenerConfig, ListOfCertificatesByAddress, ListedFrontends, ListenersList,
-            ProtobufEndpoint, QueryCertificatesFilters, RequestCounts, Response, ResponseContent,
-            ResponseStatus, RunState, SocketAddress, TlsVersion, WorkerInfos, WorkerMetrics,
+            response_content::ContentType, AggregatedMetrics, AvailableMetrics, BackendPinning,
+            BuildInfo, BuildInfos, CaptureBundle, CertificateAndKey, CertificateDetails,
+            CertificateSummary, CertificatesWithFingerprints, ClientAuthentication,
+            ClientAuthenticationMode, ClientRateLimit, Cluster, ClusterMetrics, CustomHttpAnswers,
+            DeviceClass, DrainingBackends, DuplicateHeader, DuplicateHeaderPolicy, Event,
+            EventHistory, EventKind, FaultInjection, FilterAction, FilteredMetrics, HealthChecks,
+            HealthOverride, Http10Options, HttpEndpoint, HttpListenerConfig, HttpsListenerConfig,
+            HttpsPolicy, ListOfCertificatesByAddress, ListedFrontends, ListenersList,
+            LoadBalancingAlgorithms, LoadMetric, PipelineStep, ProtobufEndpoint,
+            QueryCertificatesFilters, RequestCounts, RequestFilter, RequestHttpFrontend,
+            RequestPipeline, RequestRateLimit, Response, ResponseContent, ResponseError,
+            ResponseStatus, RunState, ScheduledChanges, SessionAudit, SessionAudits, SocketAddress,
+            StateChanges, StateQueryResult, TlsVersion, WorkerInfos, WorkerMetrics,
             WorkerResponses,
         },
         DisplayError,
@@ -65,7 +72,7 @@ pub fn concatenate_vector(vec: &[String]) -> String {
     vec.join(", ")
 }
 
-pub fn format_request_type(request_type: &RequestType) -> &str {
+pub fn format_request_type(request_type: &RequestType) -> &'static str {
     match request_type {
         RequestType::SaveState(_) => "SaveState",
         RequestType::LoadState(_) => "LoadState",
@@ -92,6 +99,8 @@ pub fn format_request_type(request_type: &RequestType) -> &str {
         RequestType::RemoveTcpFrontend(_) => "RemoveTcpFrontend",
         RequestType::AddBackend(_) => "AddBackend",
         RequestType::RemoveBackend(_) => "RemoveBackend",
+        RequestType::ReplaceBackends(_) => "ReplaceBackends",
+        RequestType::UpdateListenerAnswers(_) => "UpdateListenerAnswers",
         RequestType::AddHttpListener(_) => "AddHttpListener",
         RequestType::AddHttpsListener(_) => "AddHttpsListener",
         RequestType::AddTcpListener(_) => "AddTcpListener",
@@ -106,9 +115,35 @@ pub fn format_request_type(request_type: &RequestType) -> &str {
         RequestType::HardStop(_) => "HardStop",
         RequestType::ConfigureMetrics(_) => "ConfigureMetrics",
         RequestType::Logging(_) => "Logging",
+        RequestType::SetLogging(_) => "SetLogging",
         RequestType::ReturnListenSockets(_) => "ReturnListenSockets",
         RequestType::QueryCertificatesFromTheState(_) => "QueryCertificatesFromTheState",
         RequestType::QueryCertificatesFromWorkers(_) => "QueryCertificatesFromWorkers",
+        RequestType::AddScheduledChange(_) => "AddScheduledChange",
+        RequestType::RemoveScheduledChange(_) => "RemoveScheduledChange",
+        RequestType::ListScheduledChanges(_) => "ListScheduledChanges",
+        RequestType::QueryEvents(_) => "QueryEvents",
+        RequestType::SetRequestPipeline(_) => "SetRequestPipeline",
+        RequestType::SetBackendWeight(_) => "SetBackendWeight",
+        RequestType::DrainBackend(_) => "DrainBackend",
+        RequestType::SetLoadBalancing(_) => "SetLoadBalancing",
+        RequestType::SetFaultInjection(_) => "SetFaultInjection",
+        RequestType::SetSigningKeys(_) => "SetSigningKeys",
+        RequestType::RotateSigningKey(_) => "RotateSigningKey",
+        RequestType::AcmeOrder(_) => "AcmeOrder",
+        RequestType::SetBackendHealth(_) => "SetBackendHealth",
+        RequestType::SetAcceptShare(_) => "SetAcceptShare",
+        RequestType::QueryHealthChecks(_) => "QueryHealthChecks",
+        RequestType::SetBackendHealthOverride(_) => "SetBackendHealthOverride",
+        RequestType::SetStickyEntry(_) => "SetStickyEntry",
+        RequestType::QueryBuildInfo(_) => "QueryBuildInfo",
+        RequestType::GetChanges(_) => "GetChanges",
+        RequestType::QueryState(_) => "QueryState",
+        RequestType::ApplyState(_) => "ApplyState",
+        RequestType::StartCapture(_) => "StartCapture",
+        RequestType::CollectCapture(_) => "CollectCapture",
+        RequestType::AuditSessions(_) => "AuditSessions",
+        RequestType::Resync(_) => "Resync",
     }
 }
 
@@ -127,7 +162,12 @@ impl Response {
                     println!("Success: {}", self.message)
                 }
             }
-            ResponseStatus::Failure => println!("Failure: {}", self.message),
+            ResponseStatus::Failure => {
+                if let Some(error) = &self.error {
+                    return print_response_error(&self.message, error, json);
+                }
+                println!("Failure: {}", self.message);
+            }
             ResponseStatus::Processing => {
                 return Err(DisplayError::WrongResponseType(
                     "ResponseStatus::Processing".to_string(),
@@ -149,6 +189,26 @@ impl Response {
     }
 }
 
+/// the enums are written with their names, for the programs reading the JSON output
+fn print_response_error(
+    message: &str,
+    error: &ResponseError,
+    json: bool,
+) -> Result<(), DisplayError> {
+    if json {
+        return print_json_response(&serde_json::json!({
+            "message": message,
+            "code": error.code().as_str_name(),
+            "subsystem": error.subsystem().as_str_name(),
+            "entity_kind": error.entity_kind,
+            "entity_id": error.entity_id,
+            "hint": error.hint,
+        }));
+    }
+    println!("Failure: {message}\nError: {error}");
+    Ok(())
+}
+
 impl ResponseContent {
     fn display(&self, json: bool) -> Result<(), DisplayError> {
         let content_type = match &self.content_type {
@@ -184,6 +244,20 @@ impl ResponseContent {
             ContentType::Clusters(_) | ContentType::ClusterHashes(_) => Ok(()), // not displayed directly, see print_cluster_responses
             ContentType::CertificatesByAddress(certs) => print_certificates_by_address(certs),
             ContentType::Event(_event) => Ok(()), // not event displayed yet!
+            ContentType::ScheduledChanges(changes) => print_scheduled_changes(changes),
+            ContentType::EventHistory(history) => print_event_history(history),
+            ContentType::DrainingBackends(draining) => print_draining_backends(draining),
+            ContentType::StickyEntry(_) => Ok(()), // only exchanged between workers and main process
+            ContentType::BuildInfo(_) => Ok(()),   // gathered by the main process in BuildInfos
+            ContentType::BuildInfos(build_infos) => print_build_infos(build_infos),
+            ContentType::StateChanges(changes) => print_state_changes(changes),
+            ContentType::StateQueryResult(result) => print_state_query_result(result),
+            ContentType::CapturedRequests(_) => Ok(()), // gathered by the main process in CaptureBundle
+            ContentType::CaptureBundle(bundle) => print_capture_bundle(bundle),
+            ContentType::SessionAudit(_) => Ok(()), // gathered by the main process in SessionAudits
+            ContentType::SequenceGap(_) => Ok(()),  // handled by the main process
+            ContentType::SessionAudits(audits) => print_session_audits(audits),
+            ContentType::HealthChecks(health_checks) => print_health_checks(health_checks),
         }
     }
 }
@@ -215,7 +289,14 @@ impl WorkerResponses {
 pub fn print_status(worker_infos: &WorkerInfos) -> Result<(), DisplayError> {
     let mut table = Table::new();
     table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-    table.add_row(row!["worker id", "pid", "run state"]);
+    table.add_row(row![
+        "worker id",
+        "pid",
+        "run state",
+        "lag (ms)",
+        "queued requests",
+        "generation"
+    ]);
 
     let mut sorted_infos = worker_infos.vec.clone();
     sorted_infos.sort_by_key(|worker| worker.id);
@@ -226,12 +307,36 @@ pub fn print_status(worker_infos: &WorkerInfos) -> Result<(), DisplayError> {
             worker_info.pid,
             RunState::try_from(worker_info.run_state)
                 .map_err(DisplayError::DecodeError)?
-                .as_str_name()
+                .as_str_name(),
+            worker_info.lag(),
+            worker_info.queued_requests(),
+            worker_info.generation()
         );
         table.add_row(row);
     }
 
     table.printstd();
+
+    if let Some(generation) = worker_infos.main_generation {
+        println!("main process generation: {generation}");
+    }
+
+    if let Some(readiness) = &worker_infos.readiness {
+        if readiness.ready {
+            println!("listeners activated, the proxy is ready");
+        } else {
+            println!(
+                "listeners held back, waiting for {}",
+                readiness.waiting_for.as_deref().unwrap_or("activation")
+            );
+            if !readiness.clusters_without_backend.is_empty() {
+                println!(
+                    "critical clusters without a live backend: {}",
+                    readiness.clusters_without_backend.join(", ")
+                );
+            }
+        }
+    }
     Ok(())
 }
 
@@ -418,20 +523,21 @@ fn print_frontends(frontends: &ListedFrontends) -> Result<(), DisplayError> {
             "path",
             "method",
             "position",
-            "tags"
+            "tags",
+            "device",
+            "rate limit"
         ]);
         for http_frontend in frontends.http_frontends.iter() {
             table.add_row(row!(
-                http_frontend
-                    .cluster_id
-                    .clone()
-                    .unwrap_or("Deny".to_owned()),
+                format_frontend_route(http_frontend),
                 http_frontend.address.to_string(),
                 http_frontend.hostname.to_string(),
                 format!("{:?}", http_frontend.path),
                 format!("{:?}", http_frontend.method),
                 format!("{:?}", http_frontend.position),
-                format_tags_to_string(&http_frontend.tags)
+                format_tags_to_string(&http_frontend.tags),
+                format_device(http_frontend),
+                format_rate_limit(http_frontend)
             ));
         }
         table.printstd();
@@ -449,20 +555,23 @@ fn print_frontends(frontends: &ListedFrontends) -> Result<(), DisplayError> {
             "path",
             "method",
             "position",
-            "tags"
+            "tags",
+            "client TLS",
+            "device",
+            "rate limit"
         ]);
         for https_frontend in frontends.https_frontends.iter() {
             table.add_row(row!(
-                https_frontend
-                    .cluster_id
-                    .clone()
-                    .unwrap_or("Deny".to_owned()),
+                format_frontend_route(https_frontend),
                 https_frontend.address.to_string(),
                 https_frontend.hostname.to_string(),
                 format!("{:?}", https_frontend.path),
                 format!("{:?}", https_frontend.method),
                 format!("{:?}", https_frontend.position),
-                format_tags_to_string(&https_frontend.tags)
+                format_tags_to_string(&https_frontend.tags),
+                format_client_tls(https_frontend),
+                format_device(https_frontend),
+                format_rate_limit(https_frontend)
             ));
         }
         table.printstd();
@@ -473,11 +582,12 @@ fn print_frontends(frontends: &ListedFrontends) -> Result<(), DisplayError> {
         let mut table = Table::new();
         table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
         table.add_row(row!["TCP frontends  "]);
-        table.add_row(row!["Cluster ID", "address", "tags"]);
+        table.add_row(row!["Cluster ID", "address", "SNI", "tags"]);
         for tcp_frontend in frontends.tcp_frontends.iter() {
             table.add_row(row!(
                 tcp_frontend.cluster_id,
                 tcp_frontend.address,
+                tcp_frontend.sni.as_deref().unwrap_or("-"),
                 format_tags_to_string(&tcp_frontend.tags)
             ));
         }
@@ -506,6 +616,7 @@ pub fn print_listeners(listeners_list: &ListenersList) -> Result<(), DisplayErro
         table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
         table.add_row(row!["TCP frontends"]);
         table.add_row(row![
+            "name",
             "socket address",
             "public address",
             "expect proxy",
@@ -516,6 +627,7 @@ pub fn print_listeners(listeners_list: &ListenersList) -> Result<(), DisplayErro
         ]);
         for (_, tcp_listener) in listeners_list.tcp_listeners.iter() {
             table.add_row(row![
+                tcp_listener.name.as_deref().unwrap_or("-"),
                 format!("{:?}", tcp_listener.address),
                 format!("{:?}", tcp_listener.public_address),
                 tcp_listener.expect_proxy,
@@ -530,9 +642,36 @@ pub fn print_listeners(listeners_list: &ListenersList) -> Result<(), DisplayErro
     Ok(())
 }
 
+/// the algorithm of a cluster, with the metric it compares if any
+fn load_balancing_description(cluster: &Cluster) -> String {
+    let algorithm = LoadBalancingAlgorithms::try_from(cluster.load_balancing)
+        .map(|algorithm| algorithm.as_str_name().to_lowercase())
+        .unwrap_or_default();
+    if cluster.load_balancing == LoadBalancingAlgorithms::ConsistentHash as i32 {
+        let hash_key = cluster.hash_key.clone().unwrap_or_default();
+        return format!("{algorithm} ({hash_key})");
+    }
+    match cluster
+        .load_metric
+        .and_then(|n| LoadMetric::try_from(n).ok())
+    {
+        Some(metric) => format!("{algorithm} ({})", metric.as_str_name().to_lowercase()),
+        None => algorithm,
+    }
+}
+
 fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), DisplayError> {
     let mut cluster_table = create_cluster_table(
-        vec!["id", "sticky_session", "https_redirect"],
+        vec![
+            "id",
+            "load_balancing",
+            "sticky_session",
+            "https_redirect",
+            "pipeline",
+            "https_policy",
+            "rate_limit",
+            "fault_injection",
+        ],
         &worker_responses.map,
     );
 
@@ -542,10 +681,11 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
     let mut https_frontend_table =
         create_cluster_table(vec!["id", "hostname", "path"], &worker_responses.map);
 
-    let mut tcp_frontend_table = create_cluster_table(vec!["id", "address"], &worker_responses.map);
+    let mut tcp_frontend_table =
+        create_cluster_table(vec!["id", "address", "SNI"], &worker_responses.map);
 
     let mut backend_table = create_cluster_table(
-        vec!["backend id", "IP address", "Backup"],
+        vec!["backend id", "IP address", "Backup", "Draining"],
         &worker_responses.map,
     );
 
@@ -556,15 +696,34 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
     let mut https_frontends = BTreeMap::new();
     let mut tcp_frontends = BTreeMap::new();
     let mut backends = BTreeMap::new();
+    let mut draining_backends = Vec::new();
+    let mut backend_health = Vec::new();
 
     for (worker_id, response_content) in worker_responses.map.iter() {
         if let Some(ContentType::Clusters(clusters)) = &response_content.content_type {
             for cluster in clusters.vec.iter() {
                 if cluster.configuration.is_some() {
+                    // draining connections and health differ between workers, they are listed apart
+                    let mut cluster = cluster.clone();
+                    cluster.draining_backends.clear();
+                    cluster.backend_health.clear();
                     let entry = cluster_infos.entry(cluster).or_insert(Vec::new());
                     entry.push(worker_id.to_owned());
                 }
 
+                for draining in cluster.draining_backends.iter() {
+                    draining_backends.push((worker_id, draining));
+                }
+
+                let cluster_id = cluster
+                    .configuration
+                    .as_ref()
+                    .map(|conf| conf.cluster_id.as_str())
+                    .unwrap_or_default();
+                for health in cluster.backend_health.iter() {
+                    backend_health.push((worker_id, cluster_id, health));
+                }
+
                 for frontend in cluster.http_frontends.iter() {
                     let entry = http_frontends.entry(frontend).or_insert(Vec::new());
                     entry.push(worker_id.to_owned());
@@ -588,7 +747,7 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
         }
     }
 
-    if cluster_infos.is_empty() {
+    if cluster_infos.is_empty() && draining_backends.is_empty() {
         println!("no cluster found");
         return Ok(());
     }
@@ -596,22 +755,36 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
     println!("Cluster level configuration:\n");
 
     for (cluster_info, workers_the_cluster_is_present_on) in cluster_infos.iter() {
-        let mut row = Vec::new();
-        row.push(cell!(cluster_info
-            .configuration
-            .as_ref()
-            .map(|conf| conf.cluster_id.to_owned())
-            .unwrap_or_else(|| String::from("None"))));
-        row.push(cell!(cluster_info
-            .configuration
-            .as_ref()
-            .map(|conf| conf.sticky_session)
-            .unwrap_or_else(|| false)));
-        row.push(cell!(cluster_info
-            .configuration
-            .as_ref()
-            .map(|conf| conf.https_redirect)
-            .unwrap_or_else(|| false)));
+        let configuration = cluster_info.configuration.as_ref();
+        let mut row = vec![
+            cell!(configuration
+                .map(|conf| conf.cluster_id.to_owned())
+                .unwrap_or_else(|| String::from("None"))),
+            cell!(configuration
+                .map(load_balancing_description)
+                .unwrap_or_default()),
+            cell!(configuration
+                .map(|conf| conf.sticky_session)
+                .unwrap_or_else(|| false)),
+            cell!(configuration
+                .map(|conf| conf.https_redirect)
+                .unwrap_or_else(|| false)),
+            cell!(configuration
+                .map(|conf| conf.request_pipeline().to_string())
+                .unwrap_or_default()),
+            cell!(configuration
+                .and_then(|conf| conf.https_policy.as_ref())
+                .map(ToString::to_string)
+                .unwrap_or_default()),
+            cell!(configuration
+                .and_then(|conf| conf.rate_limit.as_ref())
+                .map(ToString::to_string)
+                .unwrap_or_default()),
+            cell!(configuration
+                .and_then(|conf| conf.fault_injection.as_ref())
+                .map(ToString::to_string)
+                .unwrap_or_default()),
+        ];
 
         for worker in workers_the_cluster_is_present_on {
             if worker_ids.contains(worker) {
@@ -677,7 +850,11 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
     println!("\nTCP frontends configuration:\n");
 
     for (key, values) in tcp_frontends.iter() {
-        let mut row = vec![cell!(key.cluster_id), cell!(format!("{}", key.address))];
+        let mut row = vec![
+            cell!(key.cluster_id),
+            cell!(format!("{}", key.address)),
+            cell!(key.sni.as_deref().unwrap_or("-")),
+        ];
 
         for val in values.iter() {
             if worker_ids.contains(val) {
@@ -702,6 +879,7 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
                 .backup
                 .map(|b| if b { "X" } else { "" })
                 .unwrap_or_else(|| "")),
+            cell!(if key.draining == Some(true) { "X" } else { "" }),
         ];
 
         for val in values {
@@ -717,6 +895,167 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
 
     backend_table.printstd();
 
+    if !backend_health.is_empty() {
+        println!("\nbackend health:\n");
+        let mut health_table = Table::new();
+        health_table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+        health_table.add_row(row![
+            "worker",
+            "cluster id",
+            "backend id",
+            "address",
+            "status",
+            "connections",
+            "connects",
+            "failed connects",
+            "consecutive failures",
+            "connect time (µs)"
+        ]);
+        backend_health.sort_by_key(|(worker_id, cluster_id, health)| {
+            (*worker_id, *cluster_id, &health.backend_id)
+        });
+        for (worker_id, cluster_id, health) in backend_health {
+            health_table.add_row(row![
+                worker_id,
+                cluster_id,
+                health.backend_id,
+                health.address,
+                health.status().as_str_name(),
+                health.connections,
+                health.connect_successes,
+                health.connect_failures,
+                health.consecutive_failures,
+                health
+                    .connection_time
+                    .map(|time| time.to_string())
+                    .unwrap_or_else(|| String::from("-")),
+            ]);
+        }
+        health_table.printstd();
+    }
+
+    if !draining_backends.is_empty() {
+        println!("\nremoved backends with open connections:\n");
+        let mut draining_table = Table::new();
+        draining_table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+        draining_table.add_row(row![
+            "worker",
+            "cluster id",
+            "backend id",
+            "address",
+            "connections",
+            "draining for (s)"
+        ]);
+        for (worker_id, draining) in draining_backends {
+            draining_table.add_row(row![
+                worker_id,
+                draining.cluster_id,
+                draining.backend_id,
+                draining.address,
+                draining.connections,
+                draining.draining_for,
+            ]);
+        }
+        draining_table.printstd();
+    }
+
+    Ok(())
+}
+
+fn print_draining_backends(draining: &DrainingBackends) -> Result<(), DisplayError> {
+    if draining.backends.is_empty() {
+        println!("no removed backend has open connections");
+        return Ok(());
+    }
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "cluster id",
+        "backend id",
+        "address",
+        "connections",
+        "draining for (s)"
+    ]);
+    for backend in &draining.backends {
+        table.add_row(row![
+            backend.cluster_id,
+            backend.backend_id,
+            backend.address,
+            backend.connections,
+            backend.draining_for,
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_health_checks(health_checks: &HealthChecks) -> Result<(), DisplayError> {
+    if health_checks.backends.is_empty() {
+        println!("no backend has active health checks nor a health override");
+        return Ok(());
+    }
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "cluster id",
+        "backend id",
+        "address",
+        "status",
+        "successes",
+        "failures",
+        "last check",
+        "last error"
+    ]);
+    for backend in &health_checks.backends {
+        table.add_row(row![
+            backend.cluster_id,
+            backend.backend_id,
+            backend.address,
+            match backend.health_override.map(HealthOverride::try_from) {
+                Some(Ok(HealthOverride::Up)) => "forced up",
+                Some(Ok(HealthOverride::Down)) => "forced down",
+                _ if backend.healthy => "healthy",
+                _ => "unhealthy",
+            },
+            backend.consecutive_successes,
+            backend.consecutive_failures,
+            match backend.last_check {
+                Some(timestamp) => format_timestamp(timestamp)?,
+                None => String::from("-"),
+            },
+            backend.last_error.as_string_or("-"),
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_build_infos(build_infos: &BuildInfos) -> Result<(), DisplayError> {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "process", "version", "commit", "protocol", "rustls", "features"
+    ]);
+
+    let build_row = |process: String, build: &BuildInfo| {
+        row![
+            process,
+            build.version,
+            build.commit(),
+            build.protocol_version,
+            build.rustls_version(),
+            build.features.join(", "),
+        ]
+    };
+    table.add_row(build_row("main".to_owned(), &build_infos.main));
+
+    let mut workers: Vec<(&String, &BuildInfo)> = build_infos.workers.iter().collect();
+    workers.sort_by_key(|(worker_id, _)| worker_id.parse::<u32>().unwrap_or(u32::MAX));
+    for (worker_id, build) in workers {
+        table.add_row(build_row(format!("worker {worker_id}"), build));
+    }
+
+    table.printstd();
     Ok(())
 }
 
@@ -821,11 +1160,33 @@ fn print_certificates_by_address(list: &ListOfCertificatesByAddress) -> Result<(
 
         for summary in certs.certificate_summaries.iter() {
             println!("\t\t{}", summary);
+
+            if let Some(details) = &summary.details {
+                print_certificate_details(details)?;
+            }
         }
     }
     Ok(())
 }
 
+fn print_certificate_details(details: &CertificateDetails) -> Result<(), DisplayError> {
+    let not_before =
+        ASN1Time::from_timestamp(details.not_before).map_err(|_| DisplayError::DateTime)?;
+    let not_after =
+        ASN1Time::from_timestamp(details.not_after).map_err(|_| DisplayError::DateTime)?;
+
+    println!("\t\t\tserved for: {}", concatenate_vector(&details.names));
+    println!(
+        "\t\t\tsubject alternative names: {}",
+        concatenate_vector(&details.subject_alternative_names)
+    );
+    println!("\t\t\tkey type: {}", details.key_type);
+    println!("\t\t\tchain length: {}", details.chain_length);
+    println!("\t\t\tvalid not before: {}", format_datetime(not_before)?);
+    println!("\t\t\tvalid not after: {}", format_datetime(not_after)?);
+    Ok(())
+}
+
 fn print_request_counts(request_counts: &RequestCounts) -> Result<(), DisplayError> {
     let mut table = Table::new();
     table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
@@ -838,6 +1199,160 @@ fn print_request_counts(request_counts: &RequestCounts) -> Result<(), DisplayErr
     Ok(())
 }
 
+fn print_event_history(history: &EventHistory) -> Result<(), DisplayError> {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row!["date", "origin", "event"]);
+
+    for record in &history.records {
+        table.add_row(row!(
+            format_timestamp(record.timestamp)?,
+            record.origin,
+            record.event
+        ));
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_state_changes(state_changes: &StateChanges) -> Result<(), DisplayError> {
+    println!("current epoch: {}", state_changes.epoch);
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+
+    if state_changes.is_snapshot {
+        println!(
+            "some changes since this epoch are no longer kept, these requests recreate the state:"
+        );
+        table.add_row(row!["request"]);
+        for request in &state_changes.snapshot {
+            table.add_row(row!(request.short_name()));
+        }
+    } else {
+        table.add_row(row!["epoch", "request"]);
+        for change in &state_changes.changes {
+            table.add_row(row!(change.epoch, change.request.short_name()));
+        }
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_state_query_result(result: &StateQueryResult) -> Result<(), DisplayError> {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.set_titles(Row::new(
+        result.columns.iter().map(|column| cell!(column)).collect(),
+    ));
+    for row in &result.rows {
+        table.add_row(Row::new(
+            result
+                .columns
+                .iter()
+                .map(|column| cell!(row.values.get(column).map_or("", String::as_str)))
+                .collect(),
+        ));
+    }
+    table.printstd();
+    println!("{} rows", result.rows.len());
+    Ok(())
+}
+
+fn print_capture_bundle(bundle: &CaptureBundle) -> Result<(), DisplayError> {
+    println!("capture of cluster {}", bundle.cluster_id);
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row!["worker", "requests", "truncated"]);
+
+    for (worker_id, captured) in &bundle.workers {
+        table.add_row(row!(worker_id, captured.requests.len(), captured.truncated));
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_session_audits(audits: &SessionAudits) -> Result<(), DisplayError> {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "worker",
+        "slab entries",
+        "sessions",
+        "connections",
+        "orphan entries",
+        "timeouts",
+        "orphan timeouts",
+        "reclaimed"
+    ]);
+
+    let mut workers: Vec<(&String, &SessionAudit)> = audits.workers.iter().collect();
+    workers.sort_by_key(|(worker_id, _)| worker_id.parse::<u32>().unwrap_or(u32::MAX));
+    for (worker_id, audit) in workers {
+        table.add_row(row![
+            worker_id,
+            audit.slab_entries,
+            audit.sessions,
+            audit.connections,
+            audit.orphan_entries,
+            audit.timer_entries,
+            audit.orphan_timers,
+            audit.reclaimed,
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_scheduled_changes(scheduled_changes: &ScheduledChanges) -> Result<(), DisplayError> {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "id",
+        "next application",
+        "every (s)",
+        "requests",
+        "last applied",
+        "last result"
+    ]);
+
+    for change in &scheduled_changes.changes {
+        let next = if change.every.is_none() && change.last_applied_at.is_some() {
+            String::from("done")
+        } else {
+            format_timestamp(change.at)?
+        };
+        let requests = change
+            .requests
+            .iter()
+            .filter_map(|request| request.request_type.as_ref())
+            .map(format_request_type)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let last_applied = match change.last_applied_at {
+            Some(timestamp) => format_timestamp(timestamp)?,
+            None => String::from("-"),
+        };
+        table.add_row(row!(
+            change.id,
+            next,
+            change.every.as_string_or("-"),
+            requests,
+            last_applied,
+            change.last_result.as_string_or("-"),
+        ));
+    }
+    table.printstd();
+    Ok(())
+}
+
+// ISO 8601, from a unix timestamp in seconds
+fn format_timestamp(timestamp: u64) -> Result<String, DisplayError> {
+    OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .map_err(|_| DisplayError::DateTime)?
+        .format(&format_description::well_known::Iso8601::DEFAULT)
+        .map_err(|_| DisplayError::DateTime)
+}
+
 fn format_tags_to_string(tags: &BTreeMap<String, String>) -> String {
     tags.iter()
         .map(|(k, v)| format!("{k}={v}"))
@@ -845,6 +1360,55 @@ fn format_tags_to_string(tags: &BTreeMap<String, String>) -> String {
         .join(", ")
 }
 
+/// TLS versions and cipher suites a frontend requires from the clients, "-" if any
+/// the cluster of a frontend, followed by the weighted clusters it splits its requests between
+fn format_frontend_route(frontend: &RequestHttpFrontend) -> String {
+    let mut route = frontend.cluster_id.clone().unwrap_or("Deny".to_owned());
+    for weighted in &frontend.split {
+        route.push_str(&format!("\n{}={}", weighted.cluster_id, weighted.weight));
+    }
+    route
+}
+
+fn format_health_override(state: i32) -> String {
+    HealthOverride::try_from(state).map_or("unknown".to_owned(), |state| state.to_string())
+}
+
+fn format_client_tls(frontend: &RequestHttpFrontend) -> String {
+    let mut conditions: Vec<String> = frontend
+        .client_tls_versions
+        .iter()
+        .filter_map(|version| TlsVersion::try_from(*version).ok())
+        .map(|version| version.as_str_name().to_owned())
+        .collect();
+    conditions.extend(frontend.client_cipher_suites.iter().cloned());
+    if conditions.is_empty() {
+        return String::from("-");
+    }
+    conditions.join("\n")
+}
+
+fn format_device(frontend: &RequestHttpFrontend) -> String {
+    let Some(device) = &frontend.device else {
+        return String::from("-");
+    };
+    let class = match DeviceClass::try_from(device.class) {
+        Ok(class) => class.as_str_name().to_owned(),
+        Err(_) => device.class.to_string(),
+    };
+    std::iter::once(class)
+        .chain(device.mobile_user_agents.iter().cloned())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_rate_limit(frontend: &RequestHttpFrontend) -> String {
+    match &frontend.rate_limit {
+        Some(rate_limit) => rate_limit.to_string(),
+        None => String::from("-"),
+    }
+}
+
 fn list_string_vec(vec: &[String]) -> String {
     let mut output = String::new();
     for item in vec.iter() {
@@ -922,17 +1486,42 @@ impl Display for HttpListenerConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut table = Table::new();
         table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+        table.add_row(row!["name", self.name.as_deref().unwrap_or("-")]);
         table.add_row(row!["socket address", format!("{:?}", self.address)]);
         table.add_row(row!["public address", format!("{:?}", self.public_address),]);
         for http_answer_row in CustomHttpAnswers::to_rows(&self.http_answers) {
             table.add_row(http_answer_row);
         }
         table.add_row(row!["expect proxy", self.expect_proxy]);
+        table.add_row(row!["detect proxy", self.detect_proxy]);
         table.add_row(row!["sticky name", self.sticky_name]);
         table.add_row(row!["front timeout", self.front_timeout]);
         table.add_row(row!["back timeout", self.back_timeout]);
         table.add_row(row!["connect timeout", self.connect_timeout]);
         table.add_row(row!["request timeout", self.request_timeout]);
+        table.add_row(row![
+            "websocket idle timeout",
+            self.websocket_idle_timeout
+                .map(|timeout| timeout.to_string())
+                .unwrap_or("-".to_owned())
+        ]);
+        table.add_row(row!["proxy status", format!("{:?}", self.proxy_status())]);
+        table.add_row(row![
+            "request rate limit",
+            RequestRateLimit::to_cell(&self.request_rate_limit)
+        ]);
+        table.add_row(row![
+            "backend pinning",
+            BackendPinning::to_cell(&self.backend_pinning)
+        ]);
+        table.add_row(row![
+            "duplicate headers",
+            DuplicateHeader::to_cell(&self.duplicate_headers)
+        ]);
+        table.add_row(row![
+            "HTTP/1.0",
+            self.http10.clone().unwrap_or_default().to_string()
+        ]);
         table.add_row(row!["activated", self.active]);
         write!(f, "{}", table)
     }
@@ -947,6 +1536,7 @@ impl Display for HttpsListenerConfig {
             tls_versions.push_str(&format!("{tls_version:?}\n"));
         }
 
+        table.add_row(row!["name", self.name.as_deref().unwrap_or("-")]);
         table.add_row(row!["socket address", format!("{:?}", self.address)]);
         table.add_row(row!["public address", format!("{:?}", self.public_address)]);
         for http_answer_row in CustomHttpAnswers::to_rows(&self.http_answers) {
@@ -962,11 +1552,46 @@ impl Display for HttpsListenerConfig {
         table.add_row(row!["groups list", list_string_vec(&self.groups_list),]);
         table.add_row(row!["key", format!("{:?}", self.key),]);
         table.add_row(row!["expect proxy", self.expect_proxy]);
+        table.add_row(row!["detect proxy", self.detect_proxy]);
+        table.add_row(row!["early data", self.early_data]);
+        table.add_row(row!["HTTP/2", self.http2]);
         table.add_row(row!["sticky name", self.sticky_name]);
         table.add_row(row!["front timeout", self.front_timeout]);
         table.add_row(row!["back timeout", self.back_timeout]);
         table.add_row(row!["connect timeout", self.connect_timeout]);
         table.add_row(row!["request timeout", self.request_timeout]);
+        table.add_row(row![
+            "websocket idle timeout",
+            self.websocket_idle_timeout
+                .map(|timeout| timeout.to_string())
+                .unwrap_or("-".to_owned())
+        ]);
+        table.add_row(row!["handshake timeout", self.handshake_timeout]);
+        table.add_row(row!["proxy status", format!("{:?}", self.proxy_status())]);
+        table.add_row(row![
+            "request rate limit",
+            RequestRateLimit::to_cell(&self.request_rate_limit)
+        ]);
+        table.add_row(row![
+            "backend pinning",
+            BackendPinning::to_cell(&self.backend_pinning)
+        ]);
+        table.add_row(row![
+            "duplicate headers",
+            DuplicateHeader::to_cell(&self.duplicate_headers)
+        ]);
+        table.add_row(row![
+            "client authentication",
+            ClientAuthentication::to_cell(&self.client_authentication)
+        ]);
+        table.add_row(row![
+            "unknown SNI cluster",
+            self.unknown_sni_cluster.as_deref().unwrap_or("-")
+        ]);
+        table.add_row(row![
+            "HTTP/1.0",
+            self.http10.clone().unwrap_or_default().to_string()
+        ]);
         table.add_row(row!["activated", self.active]);
         write!(f, "{}", table)
     }
@@ -1003,11 +1628,190 @@ impl CustomHttpAnswers {
             if let Some(a) = &answers.answer_507 {
                 rows.push(row!("507", a));
             }
+            if let Some(a) = &answers.answer_429 {
+                rows.push(row!("429", a));
+            }
         }
         rows
     }
 }
 
+impl RequestRateLimit {
+    fn to_cell(option: &Option<Self>) -> String {
+        match option {
+            Some(rate_limit) => rate_limit.to_string(),
+            None => "unlimited".to_owned(),
+        }
+    }
+}
+
+impl BackendPinning {
+    fn to_cell(option: &Option<Self>) -> String {
+        match option {
+            Some(backend_pinning) => backend_pinning.to_string(),
+            None => "disabled".to_owned(),
+        }
+    }
+}
+
+impl ClientAuthentication {
+    fn to_cell(option: &Option<Self>) -> String {
+        match option {
+            Some(client_authentication) => client_authentication.to_string(),
+            None => "disabled".to_owned(),
+        }
+    }
+}
+
+impl DuplicateHeader {
+    fn to_cell(duplicate_headers: &[Self]) -> String {
+        if duplicate_headers.is_empty() {
+            return "forwarded as is".to_owned();
+        }
+        duplicate_headers
+            .iter()
+            .map(|duplicate_header| {
+                let policy = DuplicateHeaderPolicy::try_from(duplicate_header.policy)
+                    .map(|policy| policy.as_str_name().to_lowercase())
+                    .unwrap_or_else(|_| "unknown".to_owned());
+                format!("{}: {policy}", duplicate_header.name)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Display for BackendPinning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} from {}", self.header, self.trusted.join(", "))
+    }
+}
+
+impl Display for ClientAuthentication {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mode = match ClientAuthenticationMode::try_from(self.mode) {
+            Ok(ClientAuthenticationMode::Required) => "certificate required",
+            Ok(ClientAuthenticationMode::Optional) => "certificate optional",
+            Err(_) => "unknown mode",
+        };
+        let authorities = self.ca_certificates.matches("BEGIN CERTIFICATE").count();
+        write!(f, "{mode}, {authorities} authorities")?;
+        if self.crl.is_some() {
+            write!(f, ", with revocation lists")?;
+        }
+        let headers = &self.headers;
+        write!(
+            f,
+            ", forwarded in {}, {}, {}, {}",
+            headers.subject, headers.issuer, headers.serial, headers.verify
+        )
+    }
+}
+
+impl Display for Http10Options {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let connection = match (self.implicit_close, self.keep_alive) {
+            (_, false) => "closed after each response",
+            (true, true) => "closed unless the client asks for keep-alive",
+            (false, true) => "kept alive",
+        };
+        write!(f, "connections {connection}")?;
+        if let Some(default_host) = &self.default_host {
+            write!(f, ", default host {default_host}")?;
+        }
+        Ok(())
+    }
+}
+
+/// the value of the Strict-Transport-Security header
+impl Display for HttpsPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "max-age={}", self.max_age)?;
+        if self.include_subdomains {
+            write!(f, "; includeSubDomains")?;
+        }
+        if self.preload {
+            write!(f, "; preload")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for RequestRateLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests per client IP in {}s",
+            self.requests, self.window
+        )?;
+        if !self.exempt.is_empty() {
+            write!(f, ", except {}", self.exempt.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for ClientRateLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests/s per client IP, burst of {}",
+            self.requests_per_second,
+            self.burst.unwrap_or(self.requests_per_second)
+        )
+    }
+}
+
+impl Display for FaultInjection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}ms delay on {}%, 503 on {}%",
+            self.delay, self.delay_percent, self.abort_percent
+        )
+    }
+}
+
+impl Display for PipelineStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let filter = match RequestFilter::try_from(self.filter) {
+            Ok(RequestFilter::HttpsRedirect) => "https_redirect",
+            Ok(RequestFilter::RateLimit) => "rate_limit",
+            Ok(RequestFilter::HeaderEdits) => "header_edits",
+            Ok(RequestFilter::FaultInjection) => "fault_injection",
+            Ok(RequestFilter::Mirroring) => "mirroring",
+            Ok(RequestFilter::BackendPinning) => "backend_pinning",
+            Err(_) => "unknown",
+        };
+        let action = match FilterAction::try_from(self.on_match) {
+            Ok(FilterAction::Stop) => "stop",
+            Ok(FilterAction::Continue) => "continue",
+            Err(_) => "unknown",
+        };
+        write!(f, "{filter}:{action}")
+    }
+}
+
+impl Display for RequestPipeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "-");
+        }
+        let steps: Vec<String> = self.steps.iter().map(ToString::to_string).collect();
+        write!(f, "{}", steps.join(" -> "))
+    }
+}
+
+impl Display for HealthOverride {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthOverride::Auto => write!(f, "auto"),
+            HealthOverride::Up => write!(f, "up"),
+            HealthOverride::Down => write!(f, "down"),
+        }
+    }
+}
+
 impl Display for Event {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let kind = match self.kind() {
@@ -1015,11 +1819,67 @@ impl Display for Event {
             EventKind::BackendUp => "backend up",
             EventKind::NoAvailableBackends => "no available backends",
             EventKind::RemovedBackendHasNoConnections => "removed backend has no connections",
+            EventKind::ClusterExpired => "cluster expired",
+            EventKind::FrontendExpired => "frontend expired",
+            EventKind::BackendExpired => "backend expired",
+            EventKind::AlertFired => "alert fired",
+            EventKind::AlertResolved => "alert resolved",
+            EventKind::BackendDraining => "backend draining",
+            EventKind::BackendEjected => "backend ejected",
+            EventKind::BackendReinstated => "backend reinstated",
+            EventKind::FdExhausted => "file descriptors exhausted",
+            EventKind::BackendHealthOverridden => "backend health overridden",
         };
+        if let Some(alert) = &self.alert {
+            return write!(
+                f,
+                "{} {}, cluster={}, value={}",
+                kind,
+                alert,
+                self.cluster_id(),
+                self.value(),
+            );
+        }
+        if self.kind() == EventKind::FdExhausted {
+            return write!(f, "{}, connections={}", kind, self.value());
+        }
         let address = match &self.address {
             Some(a) => a.to_string(),
             None => String::new(),
         };
+        if self.kind() == EventKind::BackendDraining {
+            return write!(
+                f,
+                "{}, backend={}, cluster={}, address={}, connections={}",
+                kind,
+                self.backend_id(),
+                self.cluster_id(),
+                address,
+                self.value(),
+            );
+        }
+        if self.kind() == EventKind::BackendHealthOverridden {
+            return write!(
+                f,
+                "{}, backend={}, cluster={}, address={}, state={}",
+                kind,
+                self.backend_id(),
+                self.cluster_id(),
+                address,
+                format_health_override(self.value() as i32),
+            );
+        }
+        if self.kind() == EventKind::BackendEjected {
+            return write!(
+                f,
+                "{}, backend={}, cluster={}, address={}, failures={}%",
+                kind,
+                self.backend_id(),
+                self.cluster_id(),
+                address,
+                self.value(),
+            );
+        }
         write!(
             f,
             "{}, backend={}, cluster={}, address={}",
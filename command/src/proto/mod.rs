@@ -6,6 +6,10 @@ pub mod command;
 /// Implementation of fmt::Display for the protobuf types, used in the CLI
 pub mod display;
 
+/// Version of the messages exchanged between the main process and the workers,
+/// to bump when a change breaks their compatibility
+pub const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(thiserror::Error, Debug)]
 pub enum DisplayError {
     #[error("Could not display content")]
@@ -34,6 +38,9 @@ impl From<command::request::RequestType> for command::Request {
     fn from(value: command::request::RequestType) -> Self {
         Self {
             request_type: Some(value),
+            dry_run: None,
+            expected_epoch: None,
+            expected_cluster_hash: None,
         }
     }
 }
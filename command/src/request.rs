@@ -13,9 +13,13 @@ use rusty_ulid::Ulid;
 use crate::{
     proto::{
         command::{
-            ip_address, request::RequestType, InitialState, IpAddress, LoadBalancingAlgorithms,
-            PathRuleKind, Request, RequestHttpFrontend, RulePosition, SocketAddress, Uint128,
-            WorkerRequest,
+            ip_address, request::RequestType, BackendPinning, ClientRateLimit, Cluster,
+            CustomHttpAnswers, DeviceClass, FilterAction, HashKey, HashKeyKind, HeaderEdit,
+            HeaderEditKind, HeaderPosition, HttpListenerConfig, HttpsListenerConfig, InitialState,
+            IpAddress, LoadBalancingAlgorithms, MirrorSink, PathRuleKind, PipelineStep, Request,
+            RequestFilter, RequestHttpFrontend, RequestMirror, RequestPipeline, RequestRateLimit,
+            RulePosition, SetLoadBalancing, SocketAddress, Timeouts, TlsVersion, Uint128,
+            WeightedCluster, WorkerRequest,
         },
         display::format_request_type,
     },
@@ -26,6 +30,10 @@ use crate::{
 pub enum RequestError {
     #[error("invalid value {value} for field '{name}'")]
     InvalidValue { name: String, value: i32 },
+    #[error("invalid traffic split: {0}")]
+    InvalidSplit(String),
+    #[error("invalid header edit: {0}")]
+    InvalidHeaderEdit(String),
     #[error("Could not read requests from file: {0}")]
     ReadFile(std::io::Error),
     #[error("Could not decode requests: {0}")]
@@ -61,10 +69,21 @@ impl Request {
                 proxy_destination.to_tcp_proxy = true
             }
 
+            // request filters, sticky sessions and injected faults only apply to HTTP and
+            // HTTPS traffic, the load balancing of the backends is changed at worker level
+            RequestType::SetRequestPipeline(_)
+            | RequestType::SetLoadBalancing(_)
+            | RequestType::SetFaultInjection(_) => {
+                proxy_destination.to_http_proxy = true;
+                proxy_destination.to_https_proxy = true;
+            }
+
             RequestType::AddCluster(_)
             | RequestType::AddBackend(_)
             | RequestType::RemoveCluster(_)
             | RequestType::RemoveBackend(_)
+            | RequestType::DrainBackend(_)
+            | RequestType::ReplaceBackends(_)
             | RequestType::SoftStop(_)
             | RequestType::HardStop(_)
             | RequestType::Status(_) => {
@@ -75,8 +94,19 @@ impl Request {
 
             // handled at worker level prior to this call
             RequestType::ConfigureMetrics(_)
+            | RequestType::SetBackendWeight(_)
+            | RequestType::SetStickyEntry(_)
+            | RequestType::SetSigningKeys(_)
+            | RequestType::SetBackendHealth(_)
+            | RequestType::SetAcceptShare(_)
             | RequestType::QueryMetrics(_)
+            | RequestType::QueryBuildInfo(_)
+            | RequestType::StartCapture(_)
+            | RequestType::CollectCapture(_)
+            | RequestType::AuditSessions(_)
+            | RequestType::Resync(_)
             | RequestType::Logging(_)
+            | RequestType::SetLogging(_)
             | RequestType::QueryClustersHashes(_)
             | RequestType::QueryClusterById(_)
             | RequestType::QueryClustersByDomain(_) => {}
@@ -89,6 +119,7 @@ impl Request {
             | RequestType::RemoveListener(_)
             | RequestType::ActivateListener(_)
             | RequestType::DeactivateListener(_)
+            | RequestType::UpdateListenerAnswers(_)
             | RequestType::ReturnListenSockets(_) => {}
 
             // These won't ever reach a worker anyway
@@ -103,7 +134,18 @@ impl Request {
             | RequestType::UpgradeMain(_)
             | RequestType::UpgradeWorker(_)
             | RequestType::SubscribeEvents(_)
-            | RequestType::ReloadConfiguration(_) => {}
+            | RequestType::ReloadConfiguration(_)
+            | RequestType::AddScheduledChange(_)
+            | RequestType::RemoveScheduledChange(_)
+            | RequestType::ListScheduledChanges(_)
+            | RequestType::QueryEvents(_)
+            | RequestType::GetChanges(_)
+            | RequestType::RotateSigningKey(_)
+            | RequestType::AcmeOrder(_)
+            | RequestType::QueryHealthChecks(_)
+            | RequestType::SetBackendHealthOverride(_)
+            | RequestType::QueryState(_)
+            | RequestType::ApplyState(_) => {}
         }
         proxy_destination
     }
@@ -116,6 +158,45 @@ impl Request {
         )
     }
 
+    /// True if the request changes the state, once accepted by [`ConfigState::dispatch`]
+    ///
+    /// [`ConfigState::dispatch`]: crate::state::ConfigState::dispatch
+    pub fn is_state_change(&self) -> bool {
+        matches!(
+            self.request_type,
+            Some(
+                RequestType::AddCluster(_)
+                    | RequestType::RemoveCluster(_)
+                    | RequestType::AddHttpListener(_)
+                    | RequestType::AddHttpsListener(_)
+                    | RequestType::AddTcpListener(_)
+                    | RequestType::RemoveListener(_)
+                    | RequestType::ActivateListener(_)
+                    | RequestType::DeactivateListener(_)
+                    | RequestType::UpdateListenerAnswers(_)
+                    | RequestType::AddHttpFrontend(_)
+                    | RequestType::RemoveHttpFrontend(_)
+                    | RequestType::AddHttpsFrontend(_)
+                    | RequestType::RemoveHttpsFrontend(_)
+                    | RequestType::AddTcpFrontend(_)
+                    | RequestType::RemoveTcpFrontend(_)
+                    | RequestType::AddCertificate(_)
+                    | RequestType::RemoveCertificate(_)
+                    | RequestType::ReplaceCertificate(_)
+                    | RequestType::AddBackend(_)
+                    | RequestType::RemoveBackend(_)
+                    | RequestType::DrainBackend(_)
+                    | RequestType::ReplaceBackends(_)
+                    | RequestType::SetRequestPipeline(_)
+                    | RequestType::SetBackendWeight(_)
+                    | RequestType::SetLoadBalancing(_)
+                    | RequestType::SetFaultInjection(_)
+                    | RequestType::AddScheduledChange(_)
+                    | RequestType::RemoveScheduledChange(_)
+            )
+        )
+    }
+
     pub fn short_name(&self) -> &str {
         match &self.request_type {
             Some(request_type) => format_request_type(request_type),
@@ -126,7 +207,11 @@ impl Request {
 
 impl WorkerRequest {
     pub fn new(id: String, content: Request) -> Self {
-        Self { id, content }
+        Self {
+            id,
+            content,
+            sequence: None,
+        }
     }
 }
 
@@ -150,6 +235,23 @@ pub fn read_initial_state<R: Read>(reader: &mut R) -> Result<InitialState, Reque
     InitialState::decode(&buffer[..]).map_err(RequestError::Decode)
 }
 
+impl CustomHttpAnswers {
+    /// replace the answers that are set in `other`, keep the others
+    pub fn merge(&mut self, other: CustomHttpAnswers) {
+        self.answer_301 = other.answer_301.or(self.answer_301.take());
+        self.answer_400 = other.answer_400.or(self.answer_400.take());
+        self.answer_401 = other.answer_401.or(self.answer_401.take());
+        self.answer_404 = other.answer_404.or(self.answer_404.take());
+        self.answer_408 = other.answer_408.or(self.answer_408.take());
+        self.answer_413 = other.answer_413.or(self.answer_413.take());
+        self.answer_502 = other.answer_502.or(self.answer_502.take());
+        self.answer_503 = other.answer_503.or(self.answer_503.take());
+        self.answer_504 = other.answer_504.or(self.answer_504.take());
+        self.answer_507 = other.answer_507.or(self.answer_507.take());
+        self.answer_429 = other.answer_429.or(self.answer_429.take());
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ProxyDestinations {
     pub to_http_proxy: bool,
@@ -160,6 +262,22 @@ pub struct ProxyDestinations {
 impl RequestHttpFrontend {
     /// convert a requested frontend to a usable one by parsing its address
     pub fn to_frontend(self) -> Result<HttpFrontend, RequestError> {
+        if let Some(mirror) = &self.mirror {
+            mirror.validate()?;
+        }
+        validate_split(self.cluster_id.as_deref(), &self.split)?;
+        for edit in &self.headers {
+            edit.validate()?;
+        }
+        if let Some(device) = &self.device {
+            DeviceClass::try_from(device.class).map_err(|_| RequestError::InvalidValue {
+                name: "device.class".to_string(),
+                value: device.class,
+            })?;
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.validate()?;
+        }
         Ok(HttpFrontend {
             address: self.address.into(),
             cluster_id: self.cluster_id,
@@ -173,10 +291,170 @@ impl RequestHttpFrontend {
                 }
             })?,
             tags: Some(self.tags),
+            expires_at: self.expires_at,
+            client_tls_versions: self
+                .client_tls_versions
+                .iter()
+                .map(|version| {
+                    TlsVersion::try_from(*version).map_err(|_| RequestError::InvalidValue {
+                        name: "client_tls_versions".to_string(),
+                        value: *version,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            client_cipher_suites: self.client_cipher_suites,
+            timeouts: self.timeouts,
+            mirror: self.mirror,
+            split: self.split,
+            headers: self.headers,
+            device: self.device,
+            rate_limit: self.rate_limit,
         })
     }
 }
 
+/// check the clusters a frontend splits its requests between, if any.
+/// `cluster_id` is the cluster of the frontend, that must be one of them
+pub fn validate_split(
+    cluster_id: Option<&str>,
+    split: &[WeightedCluster],
+) -> Result<(), RequestError> {
+    if split.is_empty() {
+        return Ok(());
+    }
+    let Some(cluster_id) = cluster_id else {
+        return Err(RequestError::InvalidSplit(
+            "a frontend denying its requests can not split them".to_owned(),
+        ));
+    };
+    if !split
+        .iter()
+        .any(|weighted| weighted.cluster_id == cluster_id)
+    {
+        return Err(RequestError::InvalidSplit(format!(
+            "the cluster of the frontend, {cluster_id}, must be one of the weighted clusters"
+        )));
+    }
+    for (index, weighted) in split.iter().enumerate() {
+        if weighted.cluster_id.is_empty() {
+            return Err(RequestError::InvalidSplit("empty cluster id".to_owned()));
+        }
+        if split[..index]
+            .iter()
+            .any(|other| other.cluster_id == weighted.cluster_id)
+        {
+            return Err(RequestError::InvalidSplit(format!(
+                "cluster {} is listed twice",
+                weighted.cluster_id
+            )));
+        }
+    }
+    if split.iter().all(|weighted| weighted.weight == 0) {
+        return Err(RequestError::InvalidSplit(
+            "at least one cluster needs a weight above 0".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// bytes of request body a mirror may copy, to bound the cost of mirroring
+pub const MAX_MIRROR_BODY_PREFIX: u32 = 65_536;
+
+impl RequestMirror {
+    pub fn validate(&self) -> Result<(), RequestError> {
+        let invalid = |name: &str, value: u32| RequestError::InvalidValue {
+            name: format!("mirror.{name}"),
+            value: value as i32,
+        };
+        MirrorSink::try_from(self.sink).map_err(|_| RequestError::InvalidValue {
+            name: "mirror.sink".to_string(),
+            value: self.sink,
+        })?;
+        if self.sample_one_in == 0 {
+            return Err(invalid("sample_one_in", self.sample_one_in));
+        }
+        if self.max_per_second == 0 {
+            return Err(invalid("max_per_second", self.max_per_second));
+        }
+        if self.max_body_prefix > MAX_MIRROR_BODY_PREFIX {
+            return Err(invalid("max_body_prefix", self.max_body_prefix));
+        }
+        Ok(())
+    }
+}
+
+impl ClientRateLimit {
+    pub fn validate(&self) -> Result<(), RequestError> {
+        if self.requests_per_second == 0 {
+            return Err(RequestError::InvalidValue {
+                name: "rate_limit.requests_per_second".to_string(),
+                value: 0,
+            });
+        }
+        if self.burst == Some(0) {
+            return Err(RequestError::InvalidValue {
+                name: "rate_limit.burst".to_string(),
+                value: 0,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl HeaderEdit {
+    pub fn new(
+        position: HeaderPosition,
+        kind: HeaderEditKind,
+        key: &str,
+        val: Option<&str>,
+    ) -> Self {
+        HeaderEdit {
+            position: position as i32,
+            kind: kind as i32,
+            key: key.to_owned(),
+            val: val.map(ToOwned::to_owned),
+        }
+    }
+
+    /// check the enums, that the name is an HTTP token, and that added or set headers
+    /// have a value fitting on one line
+    pub fn validate(&self) -> Result<(), RequestError> {
+        HeaderPosition::try_from(self.position).map_err(|_| RequestError::InvalidValue {
+            name: "headers.position".to_string(),
+            value: self.position,
+        })?;
+        let kind = HeaderEditKind::try_from(self.kind).map_err(|_| RequestError::InvalidValue {
+            name: "headers.kind".to_string(),
+            value: self.kind,
+        })?;
+        let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if self.key.is_empty() || !self.key.chars().all(is_token) {
+            return Err(RequestError::InvalidHeaderEdit(format!(
+                "invalid header name {:?}",
+                self.key
+            )));
+        }
+        match (kind, &self.val) {
+            (HeaderEditKind::Remove, None) => Ok(()),
+            (HeaderEditKind::Remove, Some(_)) => Err(RequestError::InvalidHeaderEdit(format!(
+                "removing {} does not take a value",
+                self.key
+            ))),
+            (_, None) => Err(RequestError::InvalidHeaderEdit(format!(
+                "no value for {}",
+                self.key
+            ))),
+            (_, Some(val)) if val.contains(['\r', '\n', '\0']) => {
+                Err(RequestError::InvalidHeaderEdit(format!(
+                    "the value of {} has a line break",
+                    self.key
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Display for RequestHttpFrontend {
     /// Used to create a unique summary of the frontend, used as a key in maps
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -194,9 +472,36 @@ impl Display for RequestHttpFrontend {
         };
 
         match &self.method {
-            Some(method) => write!(f, "{s};{method}"),
-            None => write!(f, "{s}"),
+            Some(method) => write!(f, "{s};{method}")?,
+            None => write!(f, "{s}")?,
+        }
+
+        // frontends of the same route can send different TLS clients to different clusters
+        if !self.client_tls_versions.is_empty() {
+            let versions: Vec<String> = self
+                .client_tls_versions
+                .iter()
+                .map(|version| match TlsVersion::try_from(*version) {
+                    Ok(version) => version.as_str_name().to_owned(),
+                    Err(_) => version.to_string(),
+                })
+                .collect();
+            write!(f, ";tls={}", versions.join(","))?;
+        }
+        if !self.client_cipher_suites.is_empty() {
+            write!(f, ";ciphers={}", self.client_cipher_suites.join(","))?;
         }
+        // as can mobile and desktop devices
+        if let Some(device) = &self.device {
+            match DeviceClass::try_from(device.class) {
+                Ok(class) => write!(f, ";device={}", class.as_str_name())?,
+                Err(_) => write!(f, ";device={}", device.class)?,
+            }
+            if !device.mobile_user_agents.is_empty() {
+                write!(f, ":{}", device.mobile_user_agents.join(","))?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -228,11 +533,315 @@ impl FromStr for LoadBalancingAlgorithms {
             "random" => Ok(LoadBalancingAlgorithms::Random),
             "power_of_two" => Ok(LoadBalancingAlgorithms::PowerOfTwo),
             "least_loaded" => Ok(LoadBalancingAlgorithms::LeastLoaded),
+            "consistent_hash" => Ok(LoadBalancingAlgorithms::ConsistentHash),
+            "peak_ewma" => Ok(LoadBalancingAlgorithms::PeakEwma),
             _ => Err(ParseErrorLoadBalancing {}),
         }
     }
 }
 
+impl SetLoadBalancing {
+    /// change the options of the cluster that are set in the request
+    pub fn apply(&self, cluster: &mut Cluster) {
+        if let Some(load_balancing) = self.load_balancing {
+            cluster.load_balancing = load_balancing;
+        }
+        if self.load_metric.is_some() {
+            cluster.load_metric = self.load_metric;
+        }
+        if let Some(sticky_session) = self.sticky_session {
+            cluster.sticky_session = sticky_session;
+        }
+        if let Some(sticky_table) = self.sticky_table {
+            cluster.sticky_table = sticky_table;
+        }
+        if self.hash_key.is_some() {
+            cluster.hash_key = self.hash_key.clone();
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ParseErrorHashKey {
+    #[error("unknown hash key '{0}', expected 'source_ip', 'header:<name>' or 'cookie:<name>'")]
+    UnknownKind(String),
+    #[error("missing the name of the header or cookie in hash key '{0}'")]
+    MissingName(String),
+}
+
+impl FromStr for HashKey {
+    type Err = ParseErrorHashKey;
+
+    /// parses `source_ip`, `header:<name>` or `cookie:<name>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, name) = match s.split_once(':') {
+            Some((kind, name)) => (kind, Some(name.trim())),
+            None => (s, None),
+        };
+
+        let kind = match kind.trim().to_lowercase().as_str() {
+            "source_ip" if name.is_none() => HashKeyKind::SourceIp,
+            "header" => HashKeyKind::Header,
+            "cookie" => HashKeyKind::Cookie,
+            _ => return Err(ParseErrorHashKey::UnknownKind(s.to_owned())),
+        };
+
+        let name = match (kind, name) {
+            (HashKeyKind::SourceIp, _) => None,
+            (_, Some(name)) if !name.is_empty() => Some(name.to_owned()),
+            _ => return Err(ParseErrorHashKey::MissingName(s.to_owned())),
+        };
+
+        Ok(HashKey {
+            kind: kind as i32,
+            name,
+        })
+    }
+}
+
+impl Display for HashKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (HashKeyKind::try_from(self.kind), &self.name) {
+            (Ok(HashKeyKind::Header), Some(name)) => write!(f, "header:{name}"),
+            (Ok(HashKeyKind::Cookie), Some(name)) => write!(f, "cookie:{name}"),
+            _ => write!(f, "source_ip"),
+        }
+    }
+}
+
+/// the filters in the order they run without an explicit pipeline
+const IMPLICIT_PIPELINE: [(RequestFilter, FilterAction); 6] = [
+    (RequestFilter::Mirroring, FilterAction::Continue),
+    (RequestFilter::HttpsRedirect, FilterAction::Stop),
+    (RequestFilter::RateLimit, FilterAction::Stop),
+    (RequestFilter::FaultInjection, FilterAction::Stop),
+    (RequestFilter::HeaderEdits, FilterAction::Continue),
+    (RequestFilter::BackendPinning, FilterAction::Continue),
+];
+
+impl Cluster {
+    /// The ordered filters applied to requests routed to this cluster.
+    ///
+    /// Without an explicit pipeline, the filters run in the order of
+    /// `IMPLICIT_PIPELINE`, the redirect only when the legacy `https_redirect`
+    /// flag is set. The filters an explicit pipeline leaves out run after it,
+    /// in that same order.
+    ///
+    /// An HTTPS policy always redirects: its redirect steps stop the request,
+    /// and one is added in front of the pipeline if it has none.
+    pub fn request_pipeline(&self) -> RequestPipeline {
+        let redirect = PipelineStep {
+            filter: RequestFilter::HttpsRedirect as i32,
+            on_match: FilterAction::Stop as i32,
+        };
+        let mut pipeline = self.pipeline.clone().unwrap_or_default();
+        for (filter, on_match) in IMPLICIT_PIPELINE {
+            let missing = !pipeline
+                .steps
+                .iter()
+                .any(|step| step.filter == filter as i32);
+            if missing && (filter != RequestFilter::HttpsRedirect || self.https_redirect) {
+                pipeline.steps.push(PipelineStep {
+                    filter: filter as i32,
+                    on_match: on_match as i32,
+                });
+            }
+        }
+
+        if self.https_policy.is_some() {
+            let mut redirects = false;
+            for step in pipeline
+                .steps
+                .iter_mut()
+                .filter(|step| step.filter == RequestFilter::HttpsRedirect as i32)
+            {
+                step.on_match = FilterAction::Stop as i32;
+                redirects = true;
+            }
+            if !redirects {
+                pipeline.steps.insert(0, redirect);
+            }
+        }
+        pipeline
+    }
+}
+
+impl Timeouts {
+    /// the timeouts set here, completed with the ones of `defaults`
+    pub fn or(&self, defaults: &Timeouts) -> Timeouts {
+        Timeouts {
+            body_read: self.body_read.or(defaults.body_read),
+            backend_connect: self.backend_connect.or(defaults.backend_connect),
+            backend_response: self.backend_response.or(defaults.backend_response),
+            request_deadline: self.request_deadline.or(defaults.request_deadline),
+        }
+    }
+
+    /// the timeouts and their names, in the order they apply to a request
+    pub fn named(&self) -> [(&'static str, Option<u32>); 4] {
+        [
+            ("body_read", self.body_read),
+            ("backend_connect", self.backend_connect),
+            ("backend_response", self.backend_response),
+            ("request_deadline", self.request_deadline),
+        ]
+    }
+}
+
+impl HttpListenerConfig {
+    /// the timeouts of the requests of the listener, before the overrides of
+    /// their cluster and frontend
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            body_read: Some(self.front_timeout),
+            backend_connect: Some(self.connect_timeout),
+            backend_response: Some(self.back_timeout),
+            request_deadline: self.request_deadline,
+        }
+    }
+}
+
+impl HttpsListenerConfig {
+    /// the timeouts of the requests of the listener, before the overrides of
+    /// their cluster and frontend
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            body_read: Some(self.front_timeout),
+            backend_connect: Some(self.connect_timeout),
+            backend_response: Some(self.back_timeout),
+            request_deadline: self.request_deadline,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ParseErrorPipelineStep {
+    #[error("unknown request filter '{0}'")]
+    UnknownFilter(String),
+    #[error("unknown filter action '{0}', expected 'stop' or 'continue'")]
+    UnknownAction(String),
+}
+
+impl FromStr for PipelineStep {
+    type Err = ParseErrorPipelineStep;
+
+    /// parses `filter[:action]`, the action defaulting to `stop`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (filter, action) = match s.split_once(':') {
+            Some((filter, action)) => (filter, Some(action)),
+            None => (s, None),
+        };
+
+        let filter = match filter.trim().to_lowercase().as_str() {
+            "https_redirect" => RequestFilter::HttpsRedirect,
+            "rate_limit" => RequestFilter::RateLimit,
+            "header_edits" => RequestFilter::HeaderEdits,
+            "fault_injection" => RequestFilter::FaultInjection,
+            "mirroring" => RequestFilter::Mirroring,
+            "backend_pinning" => RequestFilter::BackendPinning,
+            other => return Err(ParseErrorPipelineStep::UnknownFilter(other.to_owned())),
+        };
+
+        let on_match = match action.map(|a| a.trim().to_lowercase()).as_deref() {
+            None | Some("stop") => FilterAction::Stop,
+            Some("continue") => FilterAction::Continue,
+            Some(other) => return Err(ParseErrorPipelineStep::UnknownAction(other.to_owned())),
+        };
+
+        Ok(PipelineStep {
+            filter: filter as i32,
+            on_match: on_match as i32,
+        })
+    }
+}
+
+/// An IP network in CIDR notation, like `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ParseErrorIpNetwork {
+    #[error("invalid address in network '{0}'")]
+    InvalidAddress(String),
+    #[error("invalid prefix length in network '{0}'")]
+    InvalidPrefixLength(String),
+}
+
+impl FromStr for IpNetwork {
+    type Err = ParseErrorIpNetwork;
+
+    /// parses `address/prefix_length`, an address alone being a network of one address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = match s.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (s, None),
+        };
+
+        let address: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| ParseErrorIpNetwork::InvalidAddress(s.to_owned()))?;
+        let max_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            None => max_length,
+            Some(length) => length
+                .trim()
+                .parse()
+                .ok()
+                .filter(|length| *length <= max_length)
+                .ok_or_else(|| ParseErrorIpNetwork::InvalidPrefixLength(s.to_owned()))?,
+        };
+
+        Ok(IpNetwork {
+            address,
+            prefix_length,
+        })
+    }
+}
+
+impl IpNetwork {
+    /// true if the address is in the network. IPv4 addresses mapped in IPv6,
+    /// as seen by dual stack sockets, are compared as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl RequestRateLimit {
+    /// the networks of the clients that are not limited
+    pub fn exempt_networks(&self) -> Result<Vec<IpNetwork>, ParseErrorIpNetwork> {
+        self.exempt.iter().map(|network| network.parse()).collect()
+    }
+}
+
+impl BackendPinning {
+    /// the networks of the clients whose header is honored
+    pub fn trusted_networks(&self) -> Result<Vec<IpNetwork>, ParseErrorIpNetwork> {
+        self.trusted.iter().map(|network| network.parse()).collect()
+    }
+}
+
 impl SocketAddress {
     pub fn new_v4(a: u8, b: u8, c: u8, d: u8, port: u16) -> Self {
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), port).into()
@@ -241,35 +850,39 @@ impl SocketAddress {
 
 impl From<SocketAddr> for SocketAddress {
     fn from(socket_addr: SocketAddr) -> SocketAddress {
-        let ip_inner = match socket_addr {
-            SocketAddr::V4(ip_v4_addr) => ip_address::Inner::V4(u32::from(*ip_v4_addr.ip())),
-            SocketAddr::V6(ip_v6_addr) => {
-                ip_address::Inner::V6(Uint128::from(u128::from(*ip_v6_addr.ip())))
-            }
-        };
-
         SocketAddress {
             port: socket_addr.port() as u32,
-            ip: IpAddress {
-                inner: Some(ip_inner),
-            },
+            ip: socket_addr.ip().into(),
         }
     }
 }
 
 impl From<SocketAddress> for SocketAddr {
     fn from(socket_address: SocketAddress) -> Self {
-        let port = socket_address.port as u16;
+        SocketAddr::new(socket_address.ip.into(), socket_address.port as u16)
+    }
+}
 
-        let ip = match socket_address.ip.inner {
-            Some(inner) => match inner {
-                ip_address::Inner::V4(v4_value) => IpAddr::V4(Ipv4Addr::from(v4_value)),
-                ip_address::Inner::V6(v6_value) => IpAddr::V6(Ipv6Addr::from(u128::from(v6_value))),
-            },
-            None => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), // should never happen
+impl From<IpAddr> for IpAddress {
+    fn from(ip: IpAddr) -> IpAddress {
+        let inner = match ip {
+            IpAddr::V4(ip_v4_addr) => ip_address::Inner::V4(u32::from(ip_v4_addr)),
+            IpAddr::V6(ip_v6_addr) => ip_address::Inner::V6(Uint128::from(u128::from(ip_v6_addr))),
         };
 
-        SocketAddr::new(ip, port)
+        IpAddress { inner: Some(inner) }
+    }
+}
+
+impl From<IpAddress> for IpAddr {
+    fn from(ip_address: IpAddress) -> Self {
+        match ip_address.inner {
+            Some(ip_address::Inner::V4(v4_value)) => IpAddr::V4(Ipv4Addr::from(v4_value)),
+            Some(ip_address::Inner::V6(v6_value)) => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6_value)))
+            }
+            None => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), // should never happen
+        }
     }
 }
 
@@ -2,11 +2,14 @@ use std::{cmp::Ordering, collections::BTreeMap, fmt, net::SocketAddr};
 
 use crate::{
     proto::command::{
-        AddBackend, FilteredTimeSerie, LoadBalancingParams, PathRule, PathRuleKind,
-        RequestHttpFrontend, RequestTcpFrontend, Response, ResponseContent, ResponseStatus,
-        RulePosition, RunState, WorkerResponse,
+        AddBackend, ClientRateLimit, DeviceMatch, ErrorCode, ErrorSubsystem, FilteredTimeSerie,
+        HeaderEdit, ListenersList, LoadBalancingParams, PathRule, PathRuleKind,
+        RequestHttpFrontend, RequestMirror, RequestTcpFrontend, Response, ResponseContent,
+        ResponseError, ResponseStatus, RulePosition, RunState, Timeouts, TlsVersion,
+        WeightedCluster, WorkerResponse,
     },
     state::ClusterId,
+    ObjectKind,
 };
 
 impl Response {
@@ -19,10 +22,67 @@ impl Response {
             status: status as i32,
             message,
             content,
+            error: None,
+        }
+    }
+
+    /// a failure, with its structured description
+    pub fn failure(message: String, error: ResponseError) -> Response {
+        Response {
+            status: ResponseStatus::Failure as i32,
+            message,
+            content: None,
+            error: Some(error),
         }
     }
 }
 
+impl ResponseError {
+    pub fn new(code: ErrorCode, subsystem: ErrorSubsystem) -> Self {
+        Self {
+            code: code as i32,
+            subsystem: subsystem as i32,
+            entity_kind: None,
+            entity_id: None,
+            hint: None,
+        }
+    }
+
+    pub fn with_entity<T: ToString>(mut self, kind: &ObjectKind, id: T) -> Self {
+        self.entity_kind = Some(kind.name().to_owned());
+        self.entity_id = Some(id.to_string());
+        self
+    }
+
+    pub fn with_hint<T: Into<String>>(mut self, hint: T) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({})",
+            self.code().as_str_name(),
+            self.subsystem().as_str_name()
+        )?;
+        if let Some(id) = &self.entity_id {
+            write!(
+                f,
+                " on {} '{}'",
+                self.entity_kind.as_deref().unwrap_or("object"),
+                id
+            )?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, ", hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
 /// An HTTP or HTTPS frontend, as used *within* Sōzu
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct HttpFrontend {
@@ -39,6 +99,42 @@ pub struct HttpFrontend {
     #[serde(default)]
     pub position: RulePosition,
     pub tags: Option<BTreeMap<String, String>>,
+    /// unix timestamp (in seconds) after which the main process removes the frontend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// HTTPS only: TLS versions the client must have negotiated, any if empty
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_tls_versions: Vec<TlsVersion>,
+    /// HTTPS only: cipher suites the client must have negotiated, any if empty
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_cipher_suites: Vec<String>,
+    /// timeouts of the requests of the frontend, overriding the ones of its cluster
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeouts: Option<Timeouts>,
+    /// copy a sample of the raw requests of the frontend to a local sink
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<RequestMirror>,
+    /// clusters the requests are split between, in proportion to their weights
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub split: Vec<WeightedCluster>,
+    /// edits of the headers of the requests and responses of the frontend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<HeaderEdit>,
+    /// class of devices the requests must come from, any if None
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceMatch>,
+    /// token bucket of each client IP, on top of the one of the cluster
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<ClientRateLimit>,
 }
 
 impl From<HttpFrontend> for RequestHttpFrontend {
@@ -55,6 +151,19 @@ impl From<HttpFrontend> for RequestHttpFrontend {
             method: val.method,
             position: val.position.into(),
             tags,
+            expires_at: val.expires_at,
+            client_tls_versions: val
+                .client_tls_versions
+                .into_iter()
+                .map(|version| version as i32)
+                .collect(),
+            client_cipher_suites: val.client_cipher_suites,
+            timeouts: val.timeouts,
+            mirror: val.mirror,
+            split: val.split,
+            headers: val.headers,
+            device: val.device,
+            rate_limit: val.rate_limit,
         }
     }
 }
@@ -68,10 +177,33 @@ impl From<Backend> for AddBackend {
             sticky_id: val.sticky_id,
             load_balancing_parameters: val.load_balancing_parameters,
             backup: val.backup,
+            expires_at: val.expires_at,
+            draining: val.draining,
         }
     }
 }
 
+impl ListenersList {
+    /// the address of the listener with this name, whatever its protocol
+    pub fn address_by_name(&self, name: &str) -> Option<SocketAddr> {
+        let named = |listener_name: &Option<String>| listener_name.as_deref() == Some(name);
+        self.http_listeners
+            .values()
+            .find_map(|listener| named(&listener.name).then(|| listener.address.clone()))
+            .or_else(|| {
+                self.https_listeners
+                    .values()
+                    .find_map(|listener| named(&listener.name).then(|| listener.address.clone()))
+            })
+            .or_else(|| {
+                self.tcp_listeners
+                    .values()
+                    .find_map(|listener| named(&listener.name).then(|| listener.address.clone()))
+            })
+            .map(SocketAddr::from)
+    }
+}
+
 impl PathRule {
     pub fn prefix<S>(value: S) -> Self
     where
@@ -148,6 +280,14 @@ pub struct TcpFrontend {
     pub address: SocketAddr,
     /// custom tags to identify the frontend in the access logs
     pub tags: BTreeMap<String, String>,
+    /// unix timestamp (in seconds) after which the main process removes the frontend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// server name the TLS clients ask for, to route the connections by SNI
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
 }
 
 impl From<TcpFrontend> for RequestTcpFrontend {
@@ -156,6 +296,8 @@ impl From<TcpFrontend> for RequestTcpFrontend {
             cluster_id: val.cluster_id,
             address: val.address.into(),
             tags: val.tags,
+            expires_at: val.expires_at,
+            sni: val.sni,
         }
     }
 }
@@ -175,6 +317,14 @@ pub struct Backend {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub backup: Option<bool>,
+    /// unix timestamp (in seconds) after which the main process removes the backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// set while the backend is drained: no new session is routed to it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draining: Option<bool>,
 }
 
 impl Ord for Backend {
@@ -188,6 +338,8 @@ impl Ord for Backend {
                     .cmp(&o.load_balancing_parameters),
             )
             .then(self.backup.cmp(&o.backup))
+            .then(self.expires_at.cmp(&o.expires_at))
+            .then(self.draining.cmp(&o.draining))
             .then(socketaddr_cmp(&self.address, &o.address))
     }
 }
@@ -207,6 +359,8 @@ impl Backend {
             backend_id: self.backend_id,
             load_balancing_parameters: self.load_balancing_parameters,
             backup: self.backup,
+            expires_at: self.expires_at,
+            draining: self.draining,
         }
     }
 }
@@ -234,6 +388,7 @@ impl WorkerResponse {
             message: String::new(),
             status: ResponseStatus::Ok.into(),
             content: None,
+            error: None,
         }
     }
 
@@ -246,6 +401,7 @@ impl WorkerResponse {
             status: ResponseStatus::Ok.into(),
             message: String::new(),
             content: Some(content),
+            error: None,
         }
     }
 
@@ -259,9 +415,16 @@ impl WorkerResponse {
             message: error.to_string(),
             status: ResponseStatus::Failure.into(),
             content: None,
+            error: None,
         }
     }
 
+    /// attach the structured description of a failure
+    pub fn with_error(mut self, error: ResponseError) -> Self {
+        self.error = Some(error);
+        self
+    }
+
     pub fn processing<T>(id: T) -> Self
     where
         T: ToString,
@@ -271,6 +434,7 @@ impl WorkerResponse {
             message: String::new(),
             status: ResponseStatus::Processing.into(),
             content: None,
+            error: None,
         }
     }
 
@@ -283,6 +447,7 @@ impl WorkerResponse {
             message: String::new(),
             status: status.into(),
             content: None,
+            error: None,
         }
     }
 
@@ -0,0 +1,141 @@
+//! Keys used to sign the values Sōzu gives to clients, like sticky session cookies.
+//!
+//! The main process holds the keys and sends them to the workers. A rotation makes
+//! a new key the current one, and keeps the previous one so that the values signed
+//! before the rotation are still accepted until the next one.
+
+use std::fmt;
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::proto::command::{RotateSigningKey, SigningKey, SigningKeys};
+
+/// number of keys accepted by the workers: the current one and the previous one
+pub const ACCEPTED_SIGNING_KEYS: usize = 2;
+
+/// secrets shorter than this are refused
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SigningKeyError {
+    #[error("could not read the signing key file {path}: {error}")]
+    ReadFile { path: String, error: std::io::Error },
+    #[error(
+        "the secret of a signing key should hold at least {MIN_SECRET_LENGTH} bytes, it holds {0}"
+    )]
+    TooShort(usize),
+}
+
+impl SigningKey {
+    /// name the key after the hash of its secret
+    pub fn new(secret: Vec<u8>) -> Result<Self, SigningKeyError> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(SigningKeyError::TooShort(secret.len()));
+        }
+        let key_id = hex::encode(&Sha256::digest(&secret)[..4]);
+        Ok(Self { key_id, secret })
+    }
+
+    /// create a key with a random secret
+    pub fn generate() -> Self {
+        let mut secret = vec![0; MIN_SECRET_LENGTH];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let key_id = hex::encode(&Sha256::digest(&secret)[..4]);
+        Self { key_id, secret }
+    }
+
+    /// read the secret from a text file, without its surrounding whitespace,
+    /// so that several main processes can share it
+    pub fn from_file(path: &str) -> Result<Self, SigningKeyError> {
+        let content = std::fs::read_to_string(path).map_err(|error| SigningKeyError::ReadFile {
+            path: path.to_owned(),
+            error,
+        })?;
+        Self::new(content.trim().as_bytes().to_vec())
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("key_id", &self.key_id)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+impl fmt::Debug for RotateSigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotateSigningKey")
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl SigningKeys {
+    pub fn current(&self) -> Option<&SigningKey> {
+        self.keys.first()
+    }
+
+    /// make `key` the current key, keep the previous one and forget the older ones.
+    /// Returns false if `key` already is the current key
+    pub fn rotate(&mut self, key: SigningKey) -> bool {
+        if self
+            .current()
+            .is_some_and(|current| current.key_id == key.key_id)
+        {
+            return false;
+        }
+        self.keys.retain(|known| known.key_id != key.key_id);
+        self.keys.insert(0, key);
+        self.keys.truncate(ACCEPTED_SIGNING_KEYS);
+        true
+    }
+
+    /// the ids of the keys, current first
+    pub fn key_ids(&self) -> Vec<&str> {
+        self.keys.iter().map(|key| key.key_id.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_signing_keys() {
+        let first = SigningKey::generate();
+        let second = SigningKey::generate();
+        let third = SigningKey::new(vec![b'x'; 40]).unwrap();
+
+        let mut keys = SigningKeys::default();
+        assert!(keys.rotate(first.clone()));
+        assert!(!keys.rotate(first.clone()));
+        assert!(keys.rotate(second.clone()));
+        assert_eq!(
+            keys.key_ids(),
+            vec![second.key_id.as_str(), first.key_id.as_str()]
+        );
+
+        assert!(keys.rotate(third.clone()));
+        assert_eq!(keys.keys, vec![third.clone(), second]);
+        assert_eq!(
+            SigningKey::new(vec![b'x'; 40]).unwrap().key_id,
+            third.key_id
+        );
+    }
+
+    #[test]
+    fn refuse_short_secrets_and_hide_them() {
+        assert!(matches!(
+            SigningKey::new(b"too short".to_vec()),
+            Err(SigningKeyError::TooShort(9))
+        ));
+
+        let key = SigningKey::new(vec![b'y'; 32]).unwrap();
+        let debug = format!("{:?}", SigningKeys { keys: vec![key] });
+        assert!(debug.contains("<redacted>"));
+        assert!(!debug.contains("121, 121"));
+    }
+}
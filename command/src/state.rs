@@ -14,14 +14,18 @@ use prost::{DecodeError, Message};
 
 use crate::{
     certificate::{calculate_fingerprint, CertificateError, Fingerprint},
+    config::is_valid_listener_name,
     proto::{
         command::{
             request::RequestType, ActivateListener, AddBackend, AddCertificate, CertificateAndKey,
-            Cluster, ClusterInformation, DeactivateListener, FrontendFilters, HttpListenerConfig,
-            HttpsListenerConfig, InitialState, ListedFrontends, ListenerType, ListenersList,
-            PathRule, QueryCertificatesFilters, RemoveBackend, RemoveCertificate, RemoveListener,
-            ReplaceCertificate, Request, RequestCounts, RequestHttpFrontend, RequestTcpFrontend,
-            SocketAddress, TcpListenerConfig, WorkerRequest,
+            Cluster, ClusterInformation, DeactivateListener, DrainBackend, ErrorCode,
+            ErrorSubsystem, FrontendFilters, HttpListenerConfig, HttpsListenerConfig, InitialState,
+            ListedFrontends, ListenerType, ListenersList, LoadBalancingParams, PathRule,
+            QueryCertificatesFilters, RemoveBackend, RemoveCertificate, RemoveListener,
+            ReplaceBackends, ReplaceCertificate, Request, RequestCounts, RequestHttpFrontend,
+            RequestTcpFrontend, ResponseError, ScheduledChange, SetBackendWeight,
+            SetFaultInjection, SetLoadBalancing, SetRequestPipeline, SocketAddress,
+            TcpListenerConfig, UpdateListenerAnswers, WorkerRequest,
         },
         display::format_request_type,
     },
@@ -58,6 +62,43 @@ pub enum StateError {
     FrontendConversion { frontend: String, error: String },
     #[error("Could not write state to file: {0}")]
     FileError(std::io::Error),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+}
+
+impl StateError {
+    /// the structured description of the error, sent to clients along with its message
+    pub fn response_error(&self) -> ResponseError {
+        let error = |code| ResponseError::new(code, ErrorSubsystem::State);
+        match self {
+            StateError::EmptyRequest
+            | StateError::WrongRequest(_)
+            | StateError::FrontendConversion { .. } => error(ErrorCode::InvalidRequest),
+            StateError::NoChange => error(ErrorCode::NoChange),
+            StateError::UndispatchableRequest => error(ErrorCode::UnsupportedRequest),
+            StateError::NotFound { kind, id } => error(ErrorCode::NotFound)
+                .with_entity(kind, id)
+                .with_hint(match kind {
+                    ObjectKind::Cluster | ObjectKind::TcpCluster => {
+                        "add the cluster before its frontends and backends"
+                    }
+                    ObjectKind::Listener
+                    | ObjectKind::HttpListener
+                    | ObjectKind::HttpsListener
+                    | ObjectKind::TcpListener => "add the listener first",
+                    _ => "check the id or address with the list commands",
+                }),
+            StateError::Exists { kind, id } => error(ErrorCode::AlreadyExists)
+                .with_entity(kind, id)
+                .with_hint("remove it before adding it again"),
+            StateError::AddCertificate(_)
+            | StateError::RemoveCertificate(_)
+            | StateError::ReplaceCertificate(_) => error(ErrorCode::InvalidCertificate),
+            StateError::FileError(_) => error(ErrorCode::IoError),
+            StateError::Conflict(_) => error(ErrorCode::Conflict)
+                .with_hint("read the state again, then retry with the new epoch or hash"),
+        }
+    }
 }
 
 impl From<DecodeError> for StateError {
@@ -66,6 +107,30 @@ impl From<DecodeError> for StateError {
     }
 }
 
+/// requests that a scheduled change can contain
+fn is_schedulable(request_type: &RequestType) -> bool {
+    matches!(
+        request_type,
+        RequestType::AddCluster(_)
+            | RequestType::RemoveCluster(_)
+            | RequestType::AddBackend(_)
+            | RequestType::RemoveBackend(_)
+            | RequestType::ReplaceBackends(_)
+            | RequestType::AddHttpFrontend(_)
+            | RequestType::RemoveHttpFrontend(_)
+            | RequestType::AddHttpsFrontend(_)
+            | RequestType::RemoveHttpsFrontend(_)
+            | RequestType::AddTcpFrontend(_)
+            | RequestType::RemoveTcpFrontend(_)
+            | RequestType::AddCertificate(_)
+            | RequestType::ReplaceCertificate(_)
+            | RequestType::RemoveCertificate(_)
+            | RequestType::ActivateListener(_)
+            | RequestType::DeactivateListener(_)
+            | RequestType::UpdateListenerAnswers(_)
+    )
+}
+
 /// The `ConfigState` represents the state of Sōzu's business, which is to forward traffic
 /// from frontends to backends. Hence, it contains all details about:
 ///
@@ -93,6 +158,12 @@ pub struct ConfigState {
     pub certificates: HashMap<SocketAddr, HashMap<Fingerprint, CertificateAndKey>>,
     /// A census of requests that were received. Name of the request -> number of occurences
     pub request_counts: BTreeMap<String, i32>,
+    /// batches of requests applied by the main process at a given time, by id
+    #[serde(default)]
+    pub scheduled_changes: BTreeMap<String, ScheduledChange>,
+    /// incremented by every dispatched request, to tell whether the state changed. Not saved
+    #[serde(skip)]
+    generation: u64,
 }
 
 impl ConfigState {
@@ -107,6 +178,7 @@ impl ConfigState {
         };
 
         self.increment_request_count(request);
+        self.generation += 1;
 
         match request_type {
             RequestType::AddCluster(cluster) => self.add_cluster(cluster),
@@ -128,9 +200,19 @@ impl ConfigState {
             RequestType::RemoveTcpFrontend(front) => self.remove_tcp_frontend(front),
             RequestType::AddBackend(add_backend) => self.add_backend(add_backend),
             RequestType::RemoveBackend(backend) => self.remove_backend(backend),
+            RequestType::DrainBackend(drain) => self.drain_backend(drain),
+            RequestType::ReplaceBackends(replace) => self.replace_backends(replace),
+            RequestType::UpdateListenerAnswers(update) => self.update_listener_answers(update),
+            RequestType::AddScheduledChange(change) => self.add_scheduled_change(change),
+            RequestType::RemoveScheduledChange(id) => self.remove_scheduled_change(id),
+            RequestType::SetRequestPipeline(set) => self.set_request_pipeline(set),
+            RequestType::SetBackendWeight(set) => self.set_backend_weight(set),
+            RequestType::SetLoadBalancing(set) => self.set_load_balancing(set),
+            RequestType::SetFaultInjection(set) => self.set_fault_injection(set),
 
             // This is to avoid the error message
             RequestType::Logging(_)
+            | RequestType::SetLogging(_)
             | RequestType::CountRequests(_)
             | RequestType::Status(_)
             | RequestType::SoftStop(_)
@@ -141,12 +223,136 @@ impl ConfigState {
             | RequestType::QueryClustersHashes(_)
             | RequestType::ConfigureMetrics(_)
             | RequestType::ReturnListenSockets(_)
+            | RequestType::ListScheduledChanges(_)
+            | RequestType::QueryEvents(_)
+            | RequestType::SetStickyEntry(_)
+            | RequestType::SetSigningKeys(_)
+            | RequestType::SetBackendHealth(_)
+            | RequestType::SetAcceptShare(_)
+            | RequestType::QueryHealthChecks(_)
+            | RequestType::QueryBuildInfo(_)
+            | RequestType::GetChanges(_)
+            | RequestType::QueryState(_)
+            | RequestType::StartCapture(_)
+            | RequestType::CollectCapture(_)
+            | RequestType::AuditSessions(_)
+            | RequestType::Resync(_)
             | RequestType::HardStop(_) => Ok(()),
 
             _other_request => Err(StateError::UndispatchableRequest),
         }
     }
 
+    /// Validate a request against a copy of the state, leaving this one untouched.
+    ///
+    /// On top of the checks of [`Self::dispatch`], frontends and backends must
+    /// belong to a known cluster. Returns the requests that would bring the
+    /// current state to the one obtained by applying the request.
+    pub fn dry_run(&self, request: &Request) -> Result<Vec<Request>, StateError> {
+        let cluster_id = match &request.request_type {
+            Some(RequestType::AddHttpFrontend(front))
+            | Some(RequestType::AddHttpsFrontend(front)) => front.cluster_id.as_ref(),
+            Some(RequestType::AddTcpFrontend(front)) => Some(&front.cluster_id),
+            Some(RequestType::AddBackend(backend)) => Some(&backend.cluster_id),
+            Some(RequestType::ReplaceBackends(replace)) => Some(&replace.cluster_id),
+            Some(RequestType::SetBackendWeight(set)) => Some(&set.cluster_id),
+            Some(RequestType::DrainBackend(drain)) => Some(&drain.cluster_id),
+            Some(RequestType::SetLoadBalancing(set)) => Some(&set.cluster_id),
+            Some(RequestType::SetFaultInjection(set)) => Some(&set.cluster_id),
+            _ => None,
+        };
+        if let Some(cluster_id) = cluster_id {
+            if !self.clusters.contains_key(cluster_id) {
+                return Err(StateError::NotFound {
+                    kind: ObjectKind::Cluster,
+                    id: cluster_id.to_owned(),
+                });
+            }
+        }
+
+        let mut state = self.clone();
+        state.dispatch(request)?;
+        Ok(self.diff(&state))
+    }
+
+    /// Check the epoch and the cluster hash a request expects, `epoch` being the
+    /// one of the last change applied by the main process
+    pub fn check_expected_state(&self, request: &Request, epoch: u64) -> Result<(), StateError> {
+        if let Some(expected_epoch) = request.expected_epoch {
+            if expected_epoch != epoch {
+                return Err(StateError::Conflict(format!(
+                    "the state is at epoch {epoch}, the request expects epoch {expected_epoch}"
+                )));
+            }
+        }
+
+        if let Some(expected) = &request.expected_cluster_hash {
+            let hash = self.hash_state().get(&expected.cluster_id).copied();
+            if hash != expected.hash {
+                let describe = |hash: Option<u64>| match hash {
+                    Some(hash) => format!("hash {hash}"),
+                    None => "no hash".to_owned(),
+                };
+                return Err(StateError::Conflict(format!(
+                    "cluster {} has {}, the request expects {}",
+                    expected.cluster_id,
+                    describe(hash),
+                    describe(expected.hash)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Requests removing the frontends, backends and clusters whose expiration date,
+    /// a unix timestamp in seconds, is before `now`.
+    /// The frontends and backends of an expired cluster are removed with it.
+    pub fn expired_objects(&self, now: u64) -> Vec<Request> {
+        let is_expired = |expires_at: Option<u64>| expires_at.is_some_and(|date| date <= now);
+        let expired_clusters: BTreeSet<&ClusterId> = self
+            .clusters
+            .iter()
+            .filter(|(_, cluster)| is_expired(cluster.expires_at))
+            .map(|(cluster_id, _)| cluster_id)
+            .collect();
+        let in_expired_cluster = |cluster_id: Option<&ClusterId>| {
+            cluster_id.is_some_and(|id| expired_clusters.contains(id))
+        };
+
+        let mut requests: Vec<Request> = Vec::new();
+        for front in self.http_fronts.values() {
+            if is_expired(front.expires_at) || in_expired_cluster(front.cluster_id.as_ref()) {
+                requests.push(RequestType::RemoveHttpFrontend(front.clone().into()).into());
+            }
+        }
+        for front in self.https_fronts.values() {
+            if is_expired(front.expires_at) || in_expired_cluster(front.cluster_id.as_ref()) {
+                requests.push(RequestType::RemoveHttpsFrontend(front.clone().into()).into());
+            }
+        }
+        for front in self.tcp_fronts.values().flatten() {
+            if is_expired(front.expires_at) || in_expired_cluster(Some(&front.cluster_id)) {
+                requests.push(RequestType::RemoveTcpFrontend(front.clone().into()).into());
+            }
+        }
+        for backend in self.backends.values().flatten() {
+            if is_expired(backend.expires_at) || in_expired_cluster(Some(&backend.cluster_id)) {
+                requests.push(
+                    RequestType::RemoveBackend(RemoveBackend {
+                        cluster_id: backend.cluster_id.clone(),
+                        backend_id: backend.backend_id.clone(),
+                        address: backend.address.into(),
+                    })
+                    .into(),
+                );
+            }
+        }
+        for cluster_id in expired_clusters {
+            requests.push(RequestType::RemoveCluster(cluster_id.clone()).into());
+        }
+        requests
+    }
+
     /// Increments the count for this request type
     fn increment_request_count(&mut self, request: &Request) {
         if let Some(request_type) = &request.request_type {
@@ -158,6 +364,11 @@ impl ConfigState {
         }
     }
 
+    /// changes every time a request is dispatched, even if it fails
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     pub fn get_request_counts(&self) -> RequestCounts {
         RequestCounts {
             map: self.request_counts.clone(),
@@ -180,8 +391,150 @@ impl ConfigState {
         }
     }
 
+    fn set_request_pipeline(&mut self, set: &SetRequestPipeline) -> Result<(), StateError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(StateError::NotFound {
+                kind: ObjectKind::Cluster,
+                id: set.cluster_id.to_owned(),
+            })?;
+        cluster.pipeline = set.pipeline.clone();
+        Ok(())
+    }
+
+    fn set_load_balancing(&mut self, set: &SetLoadBalancing) -> Result<(), StateError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(StateError::NotFound {
+                kind: ObjectKind::Cluster,
+                id: set.cluster_id.to_owned(),
+            })?;
+        set.apply(cluster);
+        Ok(())
+    }
+
+    fn set_fault_injection(&mut self, set: &SetFaultInjection) -> Result<(), StateError> {
+        if let Some(faults) = &set.fault_injection {
+            if faults.delay_percent > 100 || faults.abort_percent > 100 {
+                return Err(StateError::WrongRequest(String::from(
+                    "the share of the requests delayed or aborted should be between 0 and 100",
+                )));
+            }
+        }
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(StateError::NotFound {
+                kind: ObjectKind::Cluster,
+                id: set.cluster_id.to_owned(),
+            })?;
+        cluster.fault_injection = set.fault_injection.clone();
+        Ok(())
+    }
+
+    fn add_scheduled_change(&mut self, change: &ScheduledChange) -> Result<(), StateError> {
+        if change.every == Some(0) {
+            return Err(StateError::WrongRequest(String::from(
+                "the interval of a scheduled change can not be zero",
+            )));
+        }
+        for request in &change.requests {
+            match &request.request_type {
+                Some(request_type) if is_schedulable(request_type) => {}
+                Some(request_type) => {
+                    return Err(StateError::WrongRequest(format!(
+                        "{} requests can not be scheduled",
+                        format_request_type(request_type)
+                    )))
+                }
+                None => return Err(StateError::EmptyRequest),
+            }
+        }
+
+        match self.scheduled_changes.entry(change.id.clone()) {
+            BTreeMapEntry::Vacant(entry) => entry.insert(change.clone()),
+            BTreeMapEntry::Occupied(_) => {
+                return Err(StateError::Exists {
+                    kind: ObjectKind::ScheduledChange,
+                    id: change.id.clone(),
+                })
+            }
+        };
+        Ok(())
+    }
+
+    fn remove_scheduled_change(&mut self, id: &str) -> Result<(), StateError> {
+        match self.scheduled_changes.remove(id) {
+            Some(_) => Ok(()),
+            None => Err(StateError::NotFound {
+                kind: ObjectKind::ScheduledChange,
+                id: id.to_owned(),
+            }),
+        }
+    }
+
+    /// Return the requests of the scheduled changes due at `now`, a unix timestamp
+    /// in seconds, by change id. Recurring changes are moved to their next date.
+    pub fn take_due_scheduled_changes(&mut self, now: u64) -> Vec<(String, Vec<Request>)> {
+        let mut due = Vec::new();
+        for change in self.scheduled_changes.values_mut() {
+            let pending = change.every.is_some() || change.last_applied_at.is_none();
+            if !pending || change.at > now {
+                continue;
+            }
+
+            due.push((change.id.clone(), change.requests.clone()));
+            change.last_applied_at = Some(now);
+            if let Some(every) = change.every.filter(|every| *every > 0) {
+                // skip the dates missed while the main process was not running
+                let missed = (now - change.at) / every + 1;
+                change.at += missed * every;
+            }
+        }
+        due
+    }
+
+    /// the address of the listener with this name, whatever its protocol
+    pub fn listener_address_by_name(&self, name: &str) -> Option<SocketAddr> {
+        let named = |listener_name: &Option<String>| listener_name.as_deref() == Some(name);
+        self.http_listeners
+            .iter()
+            .find_map(|(address, listener)| named(&listener.name).then_some(*address))
+            .or_else(|| {
+                self.https_listeners
+                    .iter()
+                    .find_map(|(address, listener)| named(&listener.name).then_some(*address))
+            })
+            .or_else(|| {
+                self.tcp_listeners
+                    .iter()
+                    .find_map(|(address, listener)| named(&listener.name).then_some(*address))
+            })
+    }
+
+    /// a listener name should be valid and not used by another listener
+    fn check_listener_name(&self, name: &Option<String>) -> Result<(), StateError> {
+        let Some(name) = name else {
+            return Ok(());
+        };
+        if !is_valid_listener_name(name) {
+            return Err(StateError::WrongRequest(format!(
+                "invalid listener name {name:?}, it should only contain alphanumeric characters, '-', '_' and '.'"
+            )));
+        }
+        if let Some(address) = self.listener_address_by_name(name) {
+            return Err(StateError::Conflict(format!(
+                "listener name {name:?} is already used by the listener {address}"
+            )));
+        }
+        Ok(())
+    }
+
     fn add_http_listener(&mut self, listener: &HttpListenerConfig) -> Result<(), StateError> {
         let address: SocketAddr = listener.address.clone().into();
+        self.check_listener_name(&listener.name)?;
         match self.http_listeners.entry(address) {
             BTreeMapEntry::Vacant(vacant_entry) => vacant_entry.insert(listener.clone()),
             BTreeMapEntry::Occupied(_) => {
@@ -196,6 +549,7 @@ impl ConfigState {
 
     fn add_https_listener(&mut self, listener: &HttpsListenerConfig) -> Result<(), StateError> {
         let address: SocketAddr = listener.address.clone().into();
+        self.check_listener_name(&listener.name)?;
         match self.https_listeners.entry(address) {
             BTreeMapEntry::Vacant(vacant_entry) => vacant_entry.insert(listener.clone()),
             BTreeMapEntry::Occupied(_) => {
@@ -210,6 +564,7 @@ impl ConfigState {
 
     fn add_tcp_listener(&mut self, listener: &TcpListenerConfig) -> Result<(), StateError> {
         let address: SocketAddr = listener.address.clone().into();
+        self.check_listener_name(&listener.name)?;
         match self.tcp_listeners.entry(address) {
             BTreeMapEntry::Vacant(vacant_entry) => vacant_entry.insert(listener.clone()),
             BTreeMapEntry::Occupied(_) => {
@@ -309,6 +664,40 @@ impl ConfigState {
         }
     }
 
+    fn update_listener_answers(
+        &mut self,
+        update: &UpdateListenerAnswers,
+    ) -> Result<(), StateError> {
+        let http_answers = match ListenerType::try_from(update.proxy)? {
+            ListenerType::Http => self
+                .http_listeners
+                .get_mut(&update.address.clone().into())
+                .map(|listener| &mut listener.http_answers)
+                .ok_or(StateError::NotFound {
+                    kind: ObjectKind::HttpListener,
+                    id: update.address.to_string(),
+                })?,
+            ListenerType::Https => self
+                .https_listeners
+                .get_mut(&update.address.clone().into())
+                .map(|listener| &mut listener.http_answers)
+                .ok_or(StateError::NotFound {
+                    kind: ObjectKind::HttpsListener,
+                    id: update.address.to_string(),
+                })?,
+            ListenerType::Tcp => {
+                return Err(StateError::WrongRequest(
+                    "TCP listeners have no HTTP answers".to_string(),
+                ))
+            }
+        };
+
+        http_answers
+            .get_or_insert_with(Default::default)
+            .merge(update.http_answers.clone());
+        Ok(())
+    }
+
     fn add_http_frontend(&mut self, front: &RequestHttpFrontend) -> Result<(), StateError> {
         let front_as_key = front.to_string();
 
@@ -467,8 +856,15 @@ impl ConfigState {
             cluster_id: front.cluster_id.clone(),
             address: front.address.clone().into(),
             tags: front.tags.clone(),
+            expires_at: front.expires_at,
+            sni: front.sni.as_deref().map(str::to_lowercase),
         };
-        if tcp_frontends.contains(&tcp_frontend) {
+        // the expiration date is not part of the identity of the frontend
+        if tcp_frontends.iter().any(|front| {
+            front.address == tcp_frontend.address
+                && front.sni == tcp_frontend.sni
+                && front.tags == tcp_frontend.tags
+        }) {
             return Err(StateError::Exists {
                 kind: ObjectKind::TcpFrontend,
                 id: format!("{:?}", tcp_frontend),
@@ -491,8 +887,10 @@ impl ConfigState {
                     id: format!("{:?}", front_to_remove),
                 })?;
 
+        let address: SocketAddr = front_to_remove.address.clone().into();
+        let sni = front_to_remove.sni.as_deref().map(str::to_lowercase);
         let len = tcp_frontends.len();
-        tcp_frontends.retain(|front| front.address != front_to_remove.address.clone().into());
+        tcp_frontends.retain(|front| front.address != address || front.sni != sni);
         if tcp_frontends.len() == len {
             return Err(StateError::NoChange);
         }
@@ -507,6 +905,8 @@ impl ConfigState {
             sticky_id: add_backend.sticky_id.clone(),
             load_balancing_parameters: add_backend.load_balancing_parameters.clone(),
             backup: add_backend.backup,
+            expires_at: add_backend.expires_at,
+            draining: self.keeps_draining(add_backend),
         };
         let backends = self.backends.entry(backend.cluster_id.clone()).or_default();
 
@@ -537,6 +937,113 @@ impl ConfigState {
         Ok(())
     }
 
+    /// remove every address of a backend
+    /// Updating a backend does not undrain it, only a `DrainBackend` does
+    fn keeps_draining(&self, add_backend: &AddBackend) -> Option<bool> {
+        let address: SocketAddr = add_backend.address.clone().into();
+        let was_draining = self
+            .backends
+            .get(&add_backend.cluster_id)
+            .into_iter()
+            .flatten()
+            .any(|backend| {
+                backend.backend_id == add_backend.backend_id
+                    && backend.address == address
+                    && backend.draining == Some(true)
+            });
+        (was_draining || add_backend.draining == Some(true)).then_some(true)
+    }
+
+    /// mark every address of a backend as draining, or not anymore
+    fn drain_backend(&mut self, drain: &DrainBackend) -> Result<(), StateError> {
+        let mut backends = self
+            .backends
+            .get_mut(&drain.cluster_id)
+            .into_iter()
+            .flatten()
+            .filter(|backend| backend.backend_id == drain.backend_id)
+            .peekable();
+        if backends.peek().is_none() {
+            return Err(StateError::NotFound {
+                kind: ObjectKind::Backend,
+                id: drain.backend_id.to_owned(),
+            });
+        }
+        let draining = (!drain.undrain.unwrap_or(false)).then_some(true);
+        for backend in backends {
+            backend.draining = draining;
+        }
+        Ok(())
+    }
+
+    /// update the weight of every address of a backend
+    fn set_backend_weight(&mut self, set: &SetBackendWeight) -> Result<(), StateError> {
+        if set.weight < 0 {
+            return Err(StateError::WrongRequest(format!(
+                "the weight of backend {} can not be negative",
+                set.backend_id
+            )));
+        }
+        let mut backends = self
+            .backends
+            .get_mut(&set.cluster_id)
+            .into_iter()
+            .flatten()
+            .filter(|backend| backend.backend_id == set.backend_id)
+            .peekable();
+        if backends.peek().is_none() {
+            return Err(StateError::NotFound {
+                kind: ObjectKind::Backend,
+                id: set.backend_id.to_owned(),
+            });
+        }
+        for backend in backends {
+            backend
+                .load_balancing_parameters
+                .get_or_insert_with(LoadBalancingParams::default)
+                .weight = set.weight;
+        }
+        Ok(())
+    }
+
+    /// swap the whole backend list of a cluster for a new one
+    fn replace_backends(&mut self, replace: &ReplaceBackends) -> Result<(), StateError> {
+        let mut new_backends = Vec::with_capacity(replace.backends.len());
+
+        for add_backend in &replace.backends {
+            if add_backend.cluster_id != replace.cluster_id {
+                return Err(StateError::WrongRequest(format!(
+                    "backend {} belongs to cluster {}, not to cluster {}",
+                    add_backend.backend_id, add_backend.cluster_id, replace.cluster_id
+                )));
+            }
+            let backend = Backend {
+                address: add_backend.address.clone().into(),
+                cluster_id: add_backend.cluster_id.clone(),
+                backend_id: add_backend.backend_id.clone(),
+                sticky_id: add_backend.sticky_id.clone(),
+                load_balancing_parameters: add_backend.load_balancing_parameters.clone(),
+                backup: add_backend.backup,
+                expires_at: add_backend.expires_at,
+                draining: self.keeps_draining(add_backend),
+            };
+            new_backends.retain(|b: &Backend| {
+                b.backend_id != backend.backend_id || b.address != backend.address
+            });
+            new_backends.push(backend);
+        }
+        new_backends.sort();
+
+        if new_backends.is_empty() {
+            self.backends.remove(&replace.cluster_id);
+        } else {
+            self.backends
+                .insert(replace.cluster_id.to_owned(), new_backends);
+        }
+
+        Ok(())
+    }
+
     /// creates all requests needed to bootstrap the state
     fn generate_requests(&self) -> Vec<Request> {
         let mut v: Vec<Request> = Vec::new();
@@ -1203,6 +1710,10 @@ impl ConfigState {
             https_frontends,
             tcp_frontends,
             backends,
+            // only workers know about the connections of removed backends,
+            // and about the health of the backends
+            draining_backends: Vec::new(),
+            backend_health: Vec::new(),
         })
     }
 
@@ -1210,6 +1721,14 @@ impl ConfigState {
         self.backends.values().fold(0, |acc, v| acc + v.len())
     }
 
+    pub fn count_listeners(&self) -> usize {
+        self.http_listeners.len() + self.https_listeners.len() + self.tcp_listeners.len()
+    }
+
+    pub fn count_certificates(&self) -> usize {
+        self.certificates.values().fold(0, |acc, v| acc + v.len())
+    }
+
     pub fn count_frontends(&self) -> usize {
         self.http_fronts.values().count()
             + self.https_fronts.values().count()
@@ -1491,7 +2010,9 @@ mod tests {
 
     use super::*;
     use crate::proto::command::{
-        CustomHttpAnswers, LoadBalancingParams, RequestHttpFrontend, RulePosition,
+        CustomHttpAnswers, ExpectedClusterHash, FaultInjection, FilterAction, HashKey, HashKeyKind,
+        HttpsPolicy, LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric, PipelineStep,
+        RequestFilter, RequestHttpFrontend, RequestPipeline, RulePosition,
     };
 
     #[test]
@@ -1789,6 +2310,62 @@ mod tests {
         assert_eq!(diff, expected_diff);
     }
 
+    #[test]
+    fn serialized_state_converges_to_itself() {
+        let mut state = ConfigState::new();
+        let requests: Vec<Request> = vec![
+            RequestType::AddCluster(Cluster {
+                cluster_id: String::from("cluster_1"),
+                sticky_session: true,
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddHttpFrontend(RequestHttpFrontend {
+                cluster_id: Some(String::from("cluster_1")),
+                hostname: String::from("lolcatho.st"),
+                path: PathRule::prefix(String::from("/api")),
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddBackend(AddBackend {
+                cluster_id: String::from("cluster_1"),
+                backend_id: String::from("cluster_1-0"),
+                address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
+                load_balancing_parameters: Some(LoadBalancingParams::default()),
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddCertificate(AddCertificate {
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8443),
+                certificate: CertificateAndKey {
+                    certificate: String::from(include_str!("../assets/certificate.pem")),
+                    key: String::from(include_str!("../assets/key.pem")),
+                    names: vec!["lolcatho.st".to_string()],
+                    ..Default::default()
+                },
+                expired_at: None,
+            })
+            .into(),
+        ];
+        for request in &requests {
+            state.dispatch(request).expect("Could not execute request");
+        }
+
+        let serialized = serde_json::to_string(&state).expect("Could not serialize the state");
+        let desired: ConfigState =
+            serde_json::from_str(&serialized).expect("Could not deserialize the state");
+
+        let mut rebuilt = ConfigState::new();
+        for request in desired.produce_initial_state().requests {
+            rebuilt
+                .dispatch(&request.content)
+                .expect("Could not execute request");
+        }
+        assert!(state.diff(&rebuilt).is_empty());
+        assert_eq!(ConfigState::new().diff(&rebuilt).len(), requests.len());
+    }
+
     #[test]
     fn cluster_ids_by_domain() {
         let mut config = ConfigState::new();
@@ -1888,6 +2465,8 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: Some("sticky".to_string()),
             backup: None,
+            expires_at: None,
+            draining: None,
         };
 
         state
@@ -1943,6 +2522,34 @@ mod tests {
         assert_eq!(state.backends.get("cluster_1").unwrap().len(), 9);
     }
 
+    #[test]
+    fn tcp_frontends_by_sni() {
+        let mut state: ConfigState = Default::default();
+        let front = |sni: &str| RequestTcpFrontend {
+            cluster_id: String::from("tls"),
+            address: SocketAddress::new_v4(0, 0, 0, 0, 443),
+            sni: Some(sni.to_owned()),
+            ..Default::default()
+        };
+        for sni in ["a.example.com", "b.example.com"] {
+            state
+                .dispatch(&RequestType::AddTcpFrontend(front(sni)).into())
+                .expect("Could not execute request");
+        }
+        assert_eq!(state.tcp_fronts.get("tls").unwrap().len(), 2);
+
+        let duplicate = state.dispatch(&RequestType::AddTcpFrontend(front("A.example.com")).into());
+        assert!(matches!(duplicate, Err(StateError::Exists { .. })));
+
+        let remove = RequestType::RemoveTcpFrontend(front("b.example.com")).into();
+        state.dispatch(&remove).expect("Could not execute request");
+        let remaining = state.tcp_fronts.get("tls").unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].sni.as_deref(), Some("a.example.com"));
+
+        assert!(matches!(state.dispatch(&remove), Err(StateError::NoChange)));
+    }
+
     #[test]
     fn remove_backends_randomly() {
         let mut state: ConfigState = Default::default();
@@ -1992,50 +2599,180 @@ mod tests {
     }
 
     #[test]
-    fn listener_diff() {
+    fn replace_backends() {
         let mut state: ConfigState = Default::default();
-        let custom_http_answers = Some(CustomHttpAnswers {
-            answer_404: Some("test".to_string()),
-            ..Default::default()
-        });
+
+        for i in 0..3 {
+            state
+                .dispatch(
+                    &RequestType::AddBackend(AddBackend {
+                        cluster_id: String::from("cluster_1"),
+                        backend_id: format!("cluster_1-{i}"),
+                        address: SocketAddress::new_v4(127, 0, 0, 1, 1026 + i),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .expect("Could not execute request");
+        }
+
+        let replacement: Vec<AddBackend> = (2..5)
+            .map(|i| AddBackend {
+                cluster_id: String::from("cluster_1"),
+                backend_id: format!("cluster_1-{i}"),
+                address: SocketAddress::new_v4(127, 0, 0, 1, 1026 + i),
+                ..Default::default()
+            })
+            .collect();
+
         state
             .dispatch(
-                &RequestType::AddTcpListener(TcpListenerConfig {
-                    address: SocketAddress::new_v4(0, 0, 0, 0, 1234),
-                    ..Default::default()
+                &RequestType::ReplaceBackends(ReplaceBackends {
+                    cluster_id: String::from("cluster_1"),
+                    backends: replacement.clone(),
                 })
                 .into(),
             )
             .expect("Could not execute request");
+
+        let backend_ids: Vec<&str> = state
+            .backends
+            .get("cluster_1")
+            .unwrap()
+            .iter()
+            .map(|backend| backend.backend_id.as_str())
+            .collect();
+        assert_eq!(
+            backend_ids,
+            vec!["cluster_1-2", "cluster_1-3", "cluster_1-4"]
+        );
+
+        let mut wrong_cluster = replacement;
+        wrong_cluster[0].cluster_id = String::from("cluster_2");
+        let wrong_cluster_result = state.dispatch(
+            &RequestType::ReplaceBackends(ReplaceBackends {
+                cluster_id: String::from("cluster_1"),
+                backends: wrong_cluster,
+            })
+            .into(),
+        );
+        assert!(matches!(
+            wrong_cluster_result,
+            Err(StateError::WrongRequest(_))
+        ));
+        assert_eq!(state.backends.get("cluster_1").unwrap().len(), 3);
+
         state
             .dispatch(
-                &RequestType::ActivateListener(ActivateListener {
-                    address: SocketAddress::new_v4(0, 0, 0, 0, 1234),
-                    proxy: ListenerType::Tcp.into(),
-                    from_scm: false,
+                &RequestType::ReplaceBackends(ReplaceBackends {
+                    cluster_id: String::from("cluster_1"),
+                    backends: vec![],
                 })
                 .into(),
             )
             .expect("Could not execute request");
+        assert!(state.backends.get("cluster_1").is_none());
+    }
+
+    #[test]
+    fn update_listener_answers() {
+        let mut state: ConfigState = Default::default();
+        let address = SocketAddress::new_v4(0, 0, 0, 0, 8080);
         state
             .dispatch(
                 &RequestType::AddHttpListener(HttpListenerConfig {
-                    address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                    address: address.clone(),
+                    sticky_name: "SOZUBALANCEID".to_string(),
+                    http_answers: Some(CustomHttpAnswers {
+                        answer_404: Some("old 404".to_string()),
+                        answer_503: Some("old 503".to_string()),
+                        ..Default::default()
+                    }),
                     ..Default::default()
                 })
                 .into(),
             )
             .expect("Could not execute request");
+
         state
             .dispatch(
-                &RequestType::AddHttpsListener(HttpsListenerConfig {
-                    address: SocketAddress::new_v4(0, 0, 0, 0, 8443),
-                    ..Default::default()
+                &RequestType::UpdateListenerAnswers(UpdateListenerAnswers {
+                    address: address.clone(),
+                    proxy: ListenerType::Http.into(),
+                    http_answers: CustomHttpAnswers {
+                        answer_503: Some("new 503".to_string()),
+                        ..Default::default()
+                    },
                 })
                 .into(),
             )
             .expect("Could not execute request");
-        state
+
+        let listener = state.http_listeners.get(&address.clone().into()).unwrap();
+        let answers = listener.http_answers.as_ref().unwrap();
+        assert_eq!(answers.answer_404.as_deref(), Some("old 404"));
+        assert_eq!(answers.answer_503.as_deref(), Some("new 503"));
+        assert_eq!(listener.sticky_name, "SOZUBALANCEID");
+
+        let on_missing_listener = state.dispatch(
+            &RequestType::UpdateListenerAnswers(UpdateListenerAnswers {
+                address,
+                proxy: ListenerType::Https.into(),
+                http_answers: CustomHttpAnswers::default(),
+            })
+            .into(),
+        );
+        assert!(matches!(
+            on_missing_listener,
+            Err(StateError::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn listener_diff() {
+        let mut state: ConfigState = Default::default();
+        let custom_http_answers = Some(CustomHttpAnswers {
+            answer_404: Some("test".to_string()),
+            ..Default::default()
+        });
+        state
+            .dispatch(
+                &RequestType::AddTcpListener(TcpListenerConfig {
+                    address: SocketAddress::new_v4(0, 0, 0, 0, 1234),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        state
+            .dispatch(
+                &RequestType::ActivateListener(ActivateListener {
+                    address: SocketAddress::new_v4(0, 0, 0, 0, 1234),
+                    proxy: ListenerType::Tcp.into(),
+                    from_scm: false,
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        state
+            .dispatch(
+                &RequestType::AddHttpListener(HttpListenerConfig {
+                    address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        state
+            .dispatch(
+                &RequestType::AddHttpsListener(HttpsListenerConfig {
+                    address: SocketAddress::new_v4(0, 0, 0, 0, 8443),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        state
             .dispatch(
                 &RequestType::ActivateListener(ActivateListener {
                     address: SocketAddress::new_v4(0, 0, 0, 0, 8443),
@@ -2204,4 +2941,681 @@ mod tests {
 
         assert!(!certificate_found_by_domain_name.is_empty());
     }
+
+    #[test]
+    fn dry_run() {
+        let mut state: ConfigState = Default::default();
+        let add_backend: Request = RequestType::AddBackend(AddBackend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
+            ..Default::default()
+        })
+        .into();
+
+        assert!(matches!(
+            state.dry_run(&add_backend),
+            Err(StateError::NotFound {
+                kind: ObjectKind::Cluster,
+                ..
+            })
+        ));
+
+        state
+            .dispatch(
+                &RequestType::AddCluster(Cluster {
+                    cluster_id: String::from("cluster_1"),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        let before = state.clone();
+
+        let changes = state.dry_run(&add_backend).expect("the dry run failed");
+        assert_eq!(changes, vec![add_backend.clone()]);
+        assert_eq!(state, before);
+
+        state
+            .dispatch(&add_backend)
+            .expect("Could not execute request");
+        assert!(matches!(
+            state.dry_run(&RequestType::RemoveCluster(String::from("cluster_2")).into()),
+            Err(StateError::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn check_expected_state() {
+        let mut state: ConfigState = Default::default();
+        let mut request: Request = RequestType::AddBackend(AddBackend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
+            ..Default::default()
+        })
+        .into();
+        assert!(state.check_expected_state(&request, 12).is_ok());
+
+        request.expected_epoch = Some(12);
+        assert!(state.check_expected_state(&request, 12).is_ok());
+        assert!(matches!(
+            state.check_expected_state(&request, 13),
+            Err(StateError::Conflict(_))
+        ));
+
+        request.expected_cluster_hash = Some(ExpectedClusterHash {
+            cluster_id: String::from("cluster_1"),
+            hash: None,
+        });
+        assert!(state.check_expected_state(&request, 12).is_ok());
+
+        state
+            .dispatch(
+                &RequestType::AddCluster(Cluster {
+                    cluster_id: String::from("cluster_1"),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        let error = state.check_expected_state(&request, 12).unwrap_err();
+        assert_eq!(error.response_error().code(), ErrorCode::Conflict);
+
+        let hash = state.hash_state().get("cluster_1").copied();
+        request.expected_cluster_hash = Some(ExpectedClusterHash {
+            cluster_id: String::from("cluster_1"),
+            hash,
+        });
+        assert!(state.check_expected_state(&request, 12).is_ok());
+    }
+
+    #[test]
+    fn expired_objects() {
+        let mut state: ConfigState = Default::default();
+        let requests: Vec<Request> = vec![
+            RequestType::AddCluster(Cluster {
+                cluster_id: String::from("preview"),
+                expires_at: Some(100),
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddHttpFrontend(RequestHttpFrontend {
+                cluster_id: Some(String::from("preview")),
+                hostname: String::from("preview.example.com"),
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddCluster(Cluster {
+                cluster_id: String::from("production"),
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddBackend(AddBackend {
+                cluster_id: String::from("production"),
+                backend_id: String::from("production-0"),
+                address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
+                ..Default::default()
+            })
+            .into(),
+            RequestType::AddBackend(AddBackend {
+                cluster_id: String::from("production"),
+                backend_id: String::from("production-canary"),
+                address: SocketAddress::new_v4(127, 0, 0, 1, 1027),
+                expires_at: Some(200),
+                ..Default::default()
+            })
+            .into(),
+        ];
+        for request in &requests {
+            state.dispatch(request).expect("Could not execute request");
+        }
+
+        assert!(state.expired_objects(99).is_empty());
+
+        let expired = state.expired_objects(150);
+        assert_eq!(expired.len(), 2);
+        assert!(matches!(
+            expired[0].request_type,
+            Some(RequestType::RemoveHttpFrontend(_))
+        ));
+        assert_eq!(
+            expired[1].request_type,
+            Some(RequestType::RemoveCluster(String::from("preview")))
+        );
+        for request in &expired {
+            state.dispatch(request).expect("Could not execute request");
+        }
+
+        assert_eq!(
+            state.expired_objects(200),
+            vec![RequestType::RemoveBackend(RemoveBackend {
+                cluster_id: String::from("production"),
+                backend_id: String::from("production-canary"),
+                address: SocketAddress::new_v4(127, 0, 0, 1, 1027),
+            })
+            .into()]
+        );
+    }
+
+    #[test]
+    fn scheduled_changes() {
+        let mut state: ConfigState = Default::default();
+        let add_cluster: Request = RequestType::AddCluster(Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        })
+        .into();
+
+        let wrong_change = state.dispatch(
+            &RequestType::AddScheduledChange(ScheduledChange {
+                id: String::from("save"),
+                at: 100,
+                requests: vec![RequestType::SaveState(String::from("state.json")).into()],
+                ..Default::default()
+            })
+            .into(),
+        );
+        assert!(matches!(wrong_change, Err(StateError::WrongRequest(_))));
+
+        for (id, every) in [("once", None), ("daily", Some(86400))] {
+            state
+                .dispatch(
+                    &RequestType::AddScheduledChange(ScheduledChange {
+                        id: id.to_owned(),
+                        at: 100,
+                        every,
+                        requests: vec![add_cluster.clone()],
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .expect("Could not execute request");
+        }
+
+        assert!(state.take_due_scheduled_changes(99).is_empty());
+
+        let due = state.take_due_scheduled_changes(200);
+        assert_eq!(
+            due,
+            vec![
+                (String::from("daily"), vec![add_cluster.clone()]),
+                (String::from("once"), vec![add_cluster.clone()]),
+            ]
+        );
+        assert_eq!(state.scheduled_changes["daily"].at, 86500);
+        assert_eq!(state.scheduled_changes["once"].last_applied_at, Some(200));
+
+        assert!(state.take_due_scheduled_changes(86499).is_empty());
+        assert_eq!(
+            state.take_due_scheduled_changes(86500),
+            vec![(String::from("daily"), vec![add_cluster])]
+        );
+
+        state
+            .dispatch(&RequestType::RemoveScheduledChange(String::from("daily")).into())
+            .expect("Could not execute request");
+        assert_eq!(state.scheduled_changes.len(), 1);
+    }
+
+    #[test]
+    fn request_pipeline() {
+        let mut state: ConfigState = Default::default();
+        let set_pipeline = |steps: Option<Vec<&str>>| -> Request {
+            RequestType::SetRequestPipeline(SetRequestPipeline {
+                cluster_id: String::from("cluster_1"),
+                pipeline: steps.map(|steps| RequestPipeline {
+                    steps: steps
+                        .iter()
+                        .map(|step| step.parse().expect("invalid step"))
+                        .collect(),
+                }),
+            })
+            .into()
+        };
+
+        assert!(matches!(
+            state.dispatch(&set_pipeline(Some(vec![]))),
+            Err(StateError::NotFound {
+                kind: ObjectKind::Cluster,
+                ..
+            })
+        ));
+
+        state
+            .dispatch(
+                &RequestType::AddCluster(Cluster {
+                    cluster_id: String::from("cluster_1"),
+                    https_redirect: true,
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        let implicit = state.clusters["cluster_1"].request_pipeline();
+        assert_eq!(
+            implicit.to_string(),
+            "mirroring:continue -> https_redirect:stop -> rate_limit:stop -> fault_injection:stop \
+             -> header_edits:continue -> backend_pinning:continue"
+        );
+
+        // the filters left out run after the explicit steps
+        state
+            .dispatch(&set_pipeline(Some(vec![
+                "rate_limit",
+                "https_redirect:continue",
+            ])))
+            .expect("Could not execute request");
+        let explicit = state.clusters["cluster_1"].request_pipeline();
+        assert_eq!(
+            explicit.to_string(),
+            "rate_limit:stop -> https_redirect:continue -> mirroring:continue \
+             -> fault_injection:stop -> header_edits:continue -> backend_pinning:continue"
+        );
+
+        // the pipeline travels with the cluster when the state is replayed
+        let mut replayed: ConfigState = Default::default();
+        for request in replayed.diff(&state) {
+            replayed
+                .dispatch(&request)
+                .expect("Could not execute request");
+        }
+        assert_eq!(replayed.clusters, state.clusters);
+
+        state
+            .dispatch(&set_pipeline(None))
+            .expect("Could not execute request");
+        assert_eq!(state.clusters["cluster_1"].request_pipeline(), implicit);
+
+        assert!("https_redirect:maybe".parse::<PipelineStep>().is_err());
+        assert!("compress".parse::<PipelineStep>().is_err());
+        assert_eq!(
+            "Backend_Pinning:STOP".parse::<PipelineStep>(),
+            Ok(PipelineStep {
+                filter: RequestFilter::BackendPinning as i32,
+                on_match: FilterAction::Stop as i32,
+            })
+        );
+    }
+
+    #[test]
+    fn https_policy_redirects() {
+        let mut cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            https_policy: Some(HttpsPolicy {
+                include_subdomains: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            cluster.request_pipeline().to_string(),
+            "https_redirect:stop -> mirroring:continue -> rate_limit:stop -> fault_injection:stop \
+             -> header_edits:continue -> backend_pinning:continue"
+        );
+        assert_eq!(
+            cluster.https_policy.as_ref().unwrap().to_string(),
+            "max-age=31536000; includeSubDomains"
+        );
+
+        // the policy cannot be weakened by the pipeline
+        cluster.pipeline = Some(RequestPipeline {
+            steps: vec!["https_redirect:continue".parse().unwrap()],
+        });
+        assert_eq!(
+            cluster.request_pipeline().steps[0].to_string(),
+            "https_redirect:stop"
+        );
+        cluster.pipeline = Some(RequestPipeline {
+            steps: vec!["rate_limit".parse().unwrap()],
+        });
+        assert_eq!(
+            cluster.request_pipeline().steps[0].to_string(),
+            "https_redirect:stop"
+        );
+
+        cluster.https_policy = None;
+        assert!(!cluster
+            .request_pipeline()
+            .steps
+            .iter()
+            .any(|step| step.filter == RequestFilter::HttpsRedirect as i32));
+    }
+
+    #[test]
+    fn set_backend_weight() {
+        let mut state: ConfigState = Default::default();
+        for port in [1026, 1027] {
+            state
+                .dispatch(
+                    &RequestType::AddBackend(AddBackend {
+                        cluster_id: String::from("cluster_1"),
+                        backend_id: String::from("cluster_1-0"),
+                        address: SocketAddress::new_v4(127, 0, 0, 1, port),
+                        load_balancing_parameters: Some(LoadBalancingParams {
+                            weight: 100,
+                            slow_start_seconds: None,
+                        }),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .expect("Could not execute request");
+        }
+
+        let set_weight = |backend_id: &str, weight: i32| -> Request {
+            RequestType::SetBackendWeight(SetBackendWeight {
+                cluster_id: String::from("cluster_1"),
+                backend_id: backend_id.to_owned(),
+                weight,
+            })
+            .into()
+        };
+
+        state
+            .dispatch(&set_weight("cluster_1-0", 10))
+            .expect("Could not execute request");
+        assert!(state.backends["cluster_1"].iter().all(|backend| backend
+            .load_balancing_parameters
+            == Some(LoadBalancingParams {
+                weight: 10,
+                slow_start_seconds: None,
+            })));
+
+        assert!(matches!(
+            state.dispatch(&set_weight("cluster_1-1", 10)),
+            Err(StateError::NotFound {
+                kind: ObjectKind::Backend,
+                ..
+            })
+        ));
+        assert!(matches!(
+            state.dispatch(&set_weight("cluster_1-0", -1)),
+            Err(StateError::WrongRequest(_))
+        ));
+    }
+
+    #[test]
+    fn drain_backend() {
+        let mut state: ConfigState = Default::default();
+        for (backend_id, port) in [
+            ("cluster_1-0", 1026),
+            ("cluster_1-0", 1027),
+            ("cluster_1-1", 1028),
+        ] {
+            state
+                .dispatch(
+                    &RequestType::AddBackend(AddBackend {
+                        cluster_id: String::from("cluster_1"),
+                        backend_id: String::from(backend_id),
+                        address: SocketAddress::new_v4(127, 0, 0, 1, port),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .expect("Could not execute request");
+        }
+
+        let drain = |backend_id: &str, undrain: bool| -> Request {
+            RequestType::DrainBackend(DrainBackend {
+                cluster_id: String::from("cluster_1"),
+                backend_id: String::from(backend_id),
+                wait: false,
+                undrain: Some(undrain),
+            })
+            .into()
+        };
+        let draining = |state: &ConfigState| -> Vec<(String, Option<bool>)> {
+            state.backends["cluster_1"]
+                .iter()
+                .map(|backend| (backend.backend_id.clone(), backend.draining))
+                .collect()
+        };
+
+        state
+            .dispatch(&drain("cluster_1-0", false))
+            .expect("Could not execute request");
+        // the drained backend stays in the state, at all its addresses
+        assert_eq!(
+            draining(&state),
+            vec![
+                (String::from("cluster_1-0"), Some(true)),
+                (String::from("cluster_1-0"), Some(true)),
+                (String::from("cluster_1-1"), None),
+            ]
+        );
+        assert!(state.generate_requests().iter().any(|request| matches!(
+            &request.request_type,
+            Some(RequestType::AddBackend(add)) if add.draining == Some(true)
+        )));
+
+        state
+            .dispatch(&drain("cluster_1-0", true))
+            .expect("Could not execute request");
+        assert!(draining(&state)
+            .iter()
+            .all(|(_, draining)| draining.is_none()));
+
+        assert!(matches!(
+            state.dispatch(&drain("cluster_1-2", false)),
+            Err(StateError::NotFound {
+                kind: ObjectKind::Backend,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn set_load_balancing() {
+        let mut state: ConfigState = Default::default();
+        state
+            .dispatch(
+                &RequestType::AddCluster(Cluster {
+                    cluster_id: String::from("cluster_1"),
+                    sticky_session: true,
+                    load_balancing: LoadBalancingAlgorithms::RoundRobin as i32,
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        state
+            .dispatch(
+                &RequestType::AddBackend(AddBackend {
+                    cluster_id: String::from("cluster_1"),
+                    backend_id: String::from("cluster_1-0"),
+                    address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        let before = state.clone();
+
+        let set = |cluster_id: &str| -> Request {
+            RequestType::SetLoadBalancing(SetLoadBalancing {
+                cluster_id: cluster_id.to_owned(),
+                load_balancing: Some(LoadBalancingAlgorithms::LeastLoaded as i32),
+                load_metric: Some(LoadMetric::Requests as i32),
+                sticky_table: Some(true),
+                ..Default::default()
+            })
+            .into()
+        };
+        state
+            .dispatch(&set("cluster_1"))
+            .expect("Could not execute request");
+
+        let cluster = &state.clusters["cluster_1"];
+        assert_eq!(
+            cluster.load_balancing,
+            LoadBalancingAlgorithms::LeastLoaded as i32
+        );
+        assert_eq!(cluster.load_metric, Some(LoadMetric::Requests as i32));
+        assert!(cluster.sticky_table);
+        // the options that are not set are kept, with the backends
+        assert!(cluster.sticky_session);
+        assert_eq!(state.backends, before.backends);
+
+        let hash_key = HashKey {
+            kind: HashKeyKind::Cookie as i32,
+            name: Some(String::from("session")),
+        };
+        state
+            .dispatch(
+                &RequestType::SetLoadBalancing(SetLoadBalancing {
+                    cluster_id: String::from("cluster_1"),
+                    load_balancing: Some(LoadBalancingAlgorithms::ConsistentHash as i32),
+                    hash_key: Some(hash_key.clone()),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        let cluster = &state.clusters["cluster_1"];
+        assert_eq!(cluster.hash_key, Some(hash_key));
+        assert!(cluster.sticky_table);
+        // workers resyncing to this state only update the cluster
+        assert!(matches!(
+            before.diff(&state).as_slice(),
+            [Request {
+                request_type: Some(RequestType::AddCluster(_)),
+                ..
+            }]
+        ));
+
+        assert!(matches!(
+            state.dispatch(&set("cluster_2")),
+            Err(StateError::NotFound {
+                kind: ObjectKind::Cluster,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn set_fault_injection() {
+        let mut state: ConfigState = Default::default();
+        state
+            .dispatch(
+                &RequestType::AddCluster(Cluster {
+                    cluster_id: String::from("cluster_1"),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        let before = state.clone();
+
+        let set = |fault_injection: Option<FaultInjection>| -> Request {
+            RequestType::SetFaultInjection(SetFaultInjection {
+                cluster_id: String::from("cluster_1"),
+                fault_injection,
+            })
+            .into()
+        };
+        let faults = FaultInjection {
+            delay: 200,
+            delay_percent: 10,
+            abort_percent: 5,
+        };
+        state
+            .dispatch(&set(Some(faults.clone())))
+            .expect("Could not execute request");
+        assert_eq!(
+            state.clusters["cluster_1"].fault_injection,
+            Some(faults.clone())
+        );
+        assert!(matches!(
+            before.diff(&state).as_slice(),
+            [Request {
+                request_type: Some(RequestType::AddCluster(_)),
+                ..
+            }]
+        ));
+
+        assert!(matches!(
+            state.dispatch(&set(Some(FaultInjection {
+                abort_percent: 101,
+                ..faults
+            }))),
+            Err(StateError::WrongRequest(_))
+        ));
+
+        state
+            .dispatch(&set(None))
+            .expect("Could not execute request");
+        assert_eq!(state.clusters["cluster_1"].fault_injection, None);
+    }
+
+    #[test]
+    fn structured_errors() {
+        let mut state: ConfigState = Default::default();
+        let add_front: Request = RequestType::AddHttpFrontend(RequestHttpFrontend {
+            cluster_id: Some(String::from("cluster_1")),
+            hostname: String::from("lolcatho.st"),
+            address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+            ..Default::default()
+        })
+        .into();
+        state
+            .dispatch(&add_front)
+            .expect("Could not execute request");
+
+        let error = state
+            .dispatch(&add_front)
+            .expect_err("the frontend should already exist")
+            .response_error();
+        assert_eq!(error.code(), ErrorCode::AlreadyExists);
+        assert_eq!(error.subsystem(), ErrorSubsystem::State);
+        assert_eq!(error.entity_kind.as_deref(), Some("http_frontend"));
+        assert!(error.entity_id.is_some());
+
+        let error = state
+            .dispatch(&RequestType::RemoveCluster(String::from("cluster_2")).into())
+            .expect_err("the cluster should not exist")
+            .response_error();
+        assert_eq!(error.code(), ErrorCode::NotFound);
+        assert_eq!(error.entity_id.as_deref(), Some("cluster_2"));
+    }
+
+    #[test]
+    fn listener_names() {
+        let mut state: ConfigState = Default::default();
+        state
+            .dispatch(
+                &RequestType::AddHttpListener(HttpListenerConfig {
+                    address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                    name: Some(String::from("public")),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+        assert_eq!(
+            state.listener_address_by_name("public"),
+            Some("0.0.0.0:8080".parse().unwrap())
+        );
+
+        let add_tcp_listener = |name: &str| -> Request {
+            RequestType::AddTcpListener(TcpListenerConfig {
+                address: SocketAddress::new_v4(0, 0, 0, 0, 5432),
+                name: Some(name.to_owned()),
+                ..Default::default()
+            })
+            .into()
+        };
+        assert!(matches!(
+            state.dispatch(&add_tcp_listener("public")),
+            Err(StateError::Conflict(_))
+        ));
+        assert!(matches!(
+            state.dispatch(&add_tcp_listener("0.0.0.0:5432")),
+            Err(StateError::WrongRequest(_))
+        ));
+        state
+            .dispatch(&add_tcp_listener("database"))
+            .expect("Could not execute request");
+    }
 }
@@ -0,0 +1,1182 @@
+//! A SQL like language to query the state of the main process
+//!
+//! ```text
+//! SELECT hostname, cluster FROM frontends WHERE tag.team = 'payments' ORDER BY hostname LIMIT 10
+//! ```
+//!
+//! The tables are `frontends`, `clusters`, `backends`, `listeners`, `certificates` and
+//! `timeouts`, their columns are listed by [`Table::columns`]. Every value is a string, and two
+//! values are compared as numbers if both are numbers. Conditions use `=`, `!=`, `<`,
+//! `<=`, `>`, `>=`, `LIKE` (`%` matches any characters, `_` exactly one) and
+//! `IS [NOT] NULL`, and are combined with `AND`, `OR`, `NOT` and parentheses.
+//! A column without value, like the cluster of a frontend that denies its requests,
+//! does not match any comparison.
+
+use std::{cmp::Ordering, collections::BTreeMap, fmt};
+
+use crate::{
+    proto::command::{
+        CertificateAndKey, Cluster, HttpListenerConfig, HttpsListenerConfig,
+        LoadBalancingAlgorithms, PathRuleKind, SocketAddress, StateQueryResult, StateQueryRow,
+        Timeouts,
+    },
+    response::HttpFrontend,
+    state::ConfigState,
+};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum StateQueryError {
+    #[error("invalid query: {0}")]
+    Syntax(String),
+    #[error("unknown table '{0}', the tables are frontends, clusters, backends, listeners, certificates and timeouts")]
+    UnknownTable(String),
+    #[error("unknown column '{column}' in table {table}, its columns are: {}", .table.columns().join(", "))]
+    UnknownColumn { table: Table, column: String },
+}
+
+/// column -> value
+type Row = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Frontends,
+    Clusters,
+    Backends,
+    Listeners,
+    Certificates,
+    /// the timeouts applied to the requests of each HTTP and HTTPS frontend
+    Timeouts,
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Table::Frontends => "frontends",
+            Table::Clusters => "clusters",
+            Table::Backends => "backends",
+            Table::Listeners => "listeners",
+            Table::Certificates => "certificates",
+            Table::Timeouts => "timeouts",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Table {
+    fn parse(name: &str) -> Result<Self, StateQueryError> {
+        match name.to_lowercase().as_str() {
+            "frontends" => Ok(Table::Frontends),
+            "clusters" => Ok(Table::Clusters),
+            "backends" => Ok(Table::Backends),
+            "listeners" => Ok(Table::Listeners),
+            "certificates" => Ok(Table::Certificates),
+            "timeouts" => Ok(Table::Timeouts),
+            _ => Err(StateQueryError::UnknownTable(name.to_owned())),
+        }
+    }
+
+    /// the columns selected by `*`. The tags of the frontends are in
+    /// the `tag.<key>` columns as well
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            Table::Frontends => &[
+                "protocol",
+                "address",
+                "hostname",
+                "path",
+                "path_kind",
+                "method",
+                "position",
+                "cluster",
+                "tags",
+                "expires_at",
+            ],
+            Table::Clusters => &[
+                "cluster",
+                "load_balancing",
+                "sticky_session",
+                "https_redirect",
+                "https_policy",
+                "transparent",
+                "sticky_table",
+                "frontends",
+                "backends",
+                "backend_srv_record",
+                "dscp",
+                "expires_at",
+            ],
+            Table::Backends => &[
+                "cluster",
+                "backend",
+                "address",
+                "weight",
+                "backup",
+                "sticky_id",
+                "expires_at",
+                "draining",
+            ],
+            Table::Listeners => &[
+                "protocol",
+                "address",
+                "public_address",
+                "active",
+                "expect_proxy",
+            ],
+            Table::Certificates => &["address", "fingerprint", "names"],
+            Table::Timeouts => &[
+                "protocol",
+                "address",
+                "hostname",
+                "path",
+                "method",
+                "cluster",
+                "header_read",
+                "header_read_from",
+                "body_read",
+                "body_read_from",
+                "backend_connect",
+                "backend_connect_from",
+                "backend_response",
+                "backend_response_from",
+                "request_deadline",
+                "request_deadline_from",
+            ],
+        }
+    }
+
+    fn has_column(&self, column: &str) -> bool {
+        self.columns().contains(&column)
+            || (*self == Table::Frontends && column.starts_with("tag."))
+    }
+
+    fn rows(&self, state: &ConfigState) -> Vec<Row> {
+        match self {
+            Table::Frontends => frontend_rows(state),
+            Table::Timeouts => timeout_rows(state),
+            Table::Clusters => state
+                .clusters
+                .values()
+                .map(|cluster| cluster_row(state, cluster))
+                .collect(),
+            Table::Backends => state
+                .backends
+                .values()
+                .flatten()
+                .map(|backend| {
+                    let mut row = Row::new();
+                    row.insert("cluster".to_owned(), backend.cluster_id.clone());
+                    row.insert("backend".to_owned(), backend.backend_id.clone());
+                    row.insert("address".to_owned(), backend.address.to_string());
+                    if let Some(parameters) = &backend.load_balancing_parameters {
+                        row.insert("weight".to_owned(), parameters.weight.to_string());
+                    }
+                    row.insert(
+                        "backup".to_owned(),
+                        backend.backup.unwrap_or(false).to_string(),
+                    );
+                    insert_opt(&mut row, "sticky_id", backend.sticky_id.as_ref());
+                    insert_opt(&mut row, "expires_at", backend.expires_at);
+                    row.insert(
+                        "draining".to_owned(),
+                        backend.draining.unwrap_or(false).to_string(),
+                    );
+                    row
+                })
+                .collect(),
+            Table::Listeners => {
+                let listener_row = |protocol: &str,
+                                    address: &SocketAddress,
+                                    public_address: Option<&SocketAddress>,
+                                    active: bool,
+                                    expect_proxy: bool| {
+                    let mut row = Row::new();
+                    row.insert("protocol".to_owned(), protocol.to_owned());
+                    row.insert("address".to_owned(), address.to_string());
+                    insert_opt(&mut row, "public_address", public_address);
+                    row.insert("active".to_owned(), active.to_string());
+                    row.insert("expect_proxy".to_owned(), expect_proxy.to_string());
+                    row
+                };
+                let http = state.http_listeners.values().map(|listener| {
+                    listener_row(
+                        "http",
+                        &listener.address,
+                        listener.public_address.as_ref(),
+                        listener.active,
+                        listener.expect_proxy,
+                    )
+                });
+                let https = state.https_listeners.values().map(|listener| {
+                    listener_row(
+                        "https",
+                        &listener.address,
+                        listener.public_address.as_ref(),
+                        listener.active,
+                        listener.expect_proxy,
+                    )
+                });
+                let tcp = state.tcp_listeners.values().map(|listener| {
+                    listener_row(
+                        "tcp",
+                        &listener.address,
+                        listener.public_address.as_ref(),
+                        listener.active,
+                        listener.expect_proxy,
+                    )
+                });
+                http.chain(https).chain(tcp).collect()
+            }
+            Table::Certificates => {
+                let mut rows: Vec<Row> = state
+                    .certificates
+                    .iter()
+                    .flat_map(|(address, certificates)| {
+                        certificates.iter().map(move |(fingerprint, certificate)| {
+                            certificate_row(
+                                address.to_string(),
+                                fingerprint.to_string(),
+                                certificate,
+                            )
+                        })
+                    })
+                    .collect();
+                // the certificates are not kept in order in the state
+                rows.sort_by(|a, b| {
+                    (&a["address"], &a["fingerprint"]).cmp(&(&b["address"], &b["fingerprint"]))
+                });
+                rows
+            }
+        }
+    }
+}
+
+fn insert_opt<T: ToString>(row: &mut Row, column: &str, value: Option<T>) {
+    if let Some(value) = value {
+        row.insert(column.to_owned(), value.to_string());
+    }
+}
+
+fn insert_tags(row: &mut Row, tags: &BTreeMap<String, String>) {
+    if tags.is_empty() {
+        return;
+    }
+    let joined = tags
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    row.insert("tags".to_owned(), joined);
+    for (key, value) in tags {
+        row.insert(format!("tag.{key}"), value.clone());
+    }
+}
+
+fn frontend_rows(state: &ConfigState) -> Vec<Row> {
+    let http_row = |protocol: &str, frontend: &HttpFrontend| {
+        let mut row = Row::new();
+        row.insert("protocol".to_owned(), protocol.to_owned());
+        row.insert("address".to_owned(), frontend.address.to_string());
+        row.insert("hostname".to_owned(), frontend.hostname.clone());
+        row.insert("path".to_owned(), frontend.path.value.clone());
+        if let Ok(kind) = PathRuleKind::try_from(frontend.path.kind) {
+            row.insert("path_kind".to_owned(), kind.as_str_name().to_lowercase());
+        }
+        insert_opt(&mut row, "method", frontend.method.as_ref());
+        row.insert(
+            "position".to_owned(),
+            frontend.position.as_str_name().to_lowercase(),
+        );
+        insert_opt(&mut row, "cluster", frontend.cluster_id.as_ref());
+        if let Some(tags) = &frontend.tags {
+            insert_tags(&mut row, tags);
+        }
+        insert_opt(&mut row, "expires_at", frontend.expires_at);
+        row
+    };
+
+    let http = state
+        .http_fronts
+        .values()
+        .map(|frontend| http_row("http", frontend));
+    let https = state
+        .https_fronts
+        .values()
+        .map(|frontend| http_row("https", frontend));
+    let mut tcp_clusters: Vec<_> = state.tcp_fronts.keys().collect();
+    tcp_clusters.sort();
+    let tcp = tcp_clusters
+        .into_iter()
+        .flat_map(|cluster_id| &state.tcp_fronts[cluster_id])
+        .map(|frontend| {
+            let mut row = Row::new();
+            row.insert("protocol".to_owned(), "tcp".to_owned());
+            row.insert("address".to_owned(), frontend.address.to_string());
+            row.insert("cluster".to_owned(), frontend.cluster_id.clone());
+            insert_tags(&mut row, &frontend.tags);
+            insert_opt(&mut row, "expires_at", frontend.expires_at);
+            row
+        });
+
+    http.chain(https).chain(tcp).collect()
+}
+
+/// Each timeout comes from the frontend, its cluster or its listener, in this
+/// order. The header read timeout is only set on the listener, since the
+/// frontend is only known once the headers are read. Without listener at the
+/// address of the frontend, the defaults of the listeners are shown.
+fn timeout_rows(state: &ConfigState) -> Vec<Row> {
+    let timeout_row = |protocol: &str,
+                       frontend: &HttpFrontend,
+                       header_read: Option<u32>,
+                       listener: Option<Timeouts>| {
+        let cluster = frontend
+            .cluster_id
+            .as_ref()
+            .and_then(|cluster_id| state.clusters.get(cluster_id))
+            .and_then(|cluster| cluster.timeouts.as_ref());
+        let listener_source = if listener.is_some() {
+            "listener"
+        } else {
+            "default"
+        };
+        let listener = listener.unwrap_or_else(|| HttpListenerConfig::default().timeouts());
+        let header_read =
+            header_read.unwrap_or_else(|| HttpListenerConfig::default().request_timeout);
+
+        let mut row = Row::new();
+        row.insert("protocol".to_owned(), protocol.to_owned());
+        row.insert("address".to_owned(), frontend.address.to_string());
+        row.insert("hostname".to_owned(), frontend.hostname.clone());
+        row.insert("path".to_owned(), frontend.path.value.clone());
+        insert_opt(&mut row, "method", frontend.method.as_ref());
+        insert_opt(&mut row, "cluster", frontend.cluster_id.as_ref());
+        row.insert("header_read".to_owned(), header_read.to_string());
+        row.insert("header_read_from".to_owned(), listener_source.to_owned());
+
+        let levels = [
+            ("frontend", frontend.timeouts.clone().unwrap_or_default()),
+            ("cluster", cluster.cloned().unwrap_or_default()),
+            (listener_source, listener),
+        ];
+        for (index, (name, _)) in Timeouts::default().named().into_iter().enumerate() {
+            let set = levels.iter().find_map(|(source, timeouts)| {
+                timeouts.named()[index].1.map(|value| (source, value))
+            });
+            if let Some((source, value)) = set {
+                row.insert(name.to_owned(), value.to_string());
+                row.insert(format!("{name}_from"), (*source).to_owned());
+            }
+        }
+        row
+    };
+
+    let http = state.http_fronts.values().map(|frontend| {
+        let listener = state.http_listeners.get(&frontend.address);
+        timeout_row(
+            "http",
+            frontend,
+            listener.map(|listener| listener.request_timeout),
+            listener.map(HttpListenerConfig::timeouts),
+        )
+    });
+    let https = state.https_fronts.values().map(|frontend| {
+        let listener = state.https_listeners.get(&frontend.address);
+        timeout_row(
+            "https",
+            frontend,
+            listener.map(|listener| listener.request_timeout),
+            listener.map(HttpsListenerConfig::timeouts),
+        )
+    });
+    http.chain(https).collect()
+}
+
+fn cluster_row(state: &ConfigState, cluster: &Cluster) -> Row {
+    let cluster_id = &cluster.cluster_id;
+    let frontends = state
+        .http_fronts
+        .values()
+        .chain(state.https_fronts.values())
+        .filter(|frontend| frontend.cluster_id.as_ref() == Some(cluster_id))
+        .count()
+        + state.tcp_fronts.get(cluster_id).map_or(0, Vec::len);
+    let backends = state.backends.get(cluster_id).map_or(0, Vec::len);
+
+    let mut row = Row::new();
+    row.insert("cluster".to_owned(), cluster_id.clone());
+    if let Ok(load_balancing) = LoadBalancingAlgorithms::try_from(cluster.load_balancing) {
+        row.insert(
+            "load_balancing".to_owned(),
+            load_balancing.as_str_name().to_lowercase(),
+        );
+    }
+    row.insert(
+        "sticky_session".to_owned(),
+        cluster.sticky_session.to_string(),
+    );
+    row.insert(
+        "https_redirect".to_owned(),
+        cluster.https_redirect.to_string(),
+    );
+    insert_opt(&mut row, "https_policy", cluster.https_policy.as_ref());
+    row.insert("transparent".to_owned(), cluster.transparent.to_string());
+    row.insert("sticky_table".to_owned(), cluster.sticky_table.to_string());
+    row.insert("frontends".to_owned(), frontends.to_string());
+    row.insert("backends".to_owned(), backends.to_string());
+    insert_opt(
+        &mut row,
+        "backend_srv_record",
+        cluster.backend_srv_record.as_ref(),
+    );
+    insert_opt(&mut row, "dscp", cluster.dscp);
+    insert_opt(&mut row, "expires_at", cluster.expires_at);
+    row
+}
+
+fn certificate_row(address: String, fingerprint: String, certificate: &CertificateAndKey) -> Row {
+    let mut row = Row::new();
+    row.insert("address".to_owned(), address);
+    row.insert("fingerprint".to_owned(), fingerprint);
+    if !certificate.names.is_empty() {
+        row.insert("names".to_owned(), certificate.names.join(", "));
+    }
+    row
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    String(String),
+    Number(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::String(string) => write!(f, "string '{string}'"),
+            Token::Number(number) => write!(f, "number {number}"),
+            Token::Symbol(symbol) => write!(f, "'{symbol}'"),
+        }
+    }
+}
+
+const SYMBOLS: &[&str] = &["!=", "<>", "<=", ">=", "<", ">", "=", ",", "(", ")", "*"];
+
+fn tokenize(query: &str) -> Result<Vec<Token>, StateQueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+
+    while let Some(&(index, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    // a doubled quote stands for the quote itself
+                    Some((_, quote)) if quote == c => match chars.peek() {
+                        Some(&(_, next)) if next == c => {
+                            string.push(c);
+                            chars.next();
+                        }
+                        _ => break,
+                    },
+                    Some((_, other)) => string.push(other),
+                    None => {
+                        return Err(StateQueryError::Syntax(format!(
+                            "unterminated string starting at position {index}"
+                        )))
+                    }
+                }
+            }
+            tokens.push(Token::String(string));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| query[index..].starts_with(**symbol))
+                .ok_or_else(|| {
+                    StateQueryError::Syntax(format!(
+                        "unexpected character '{c}' at position {index}"
+                    ))
+                })?;
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Like,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    Compare {
+        column: String,
+        operator: Operator,
+        value: String,
+    },
+    IsNull {
+        column: String,
+    },
+}
+
+impl Condition {
+    fn columns<'a>(&'a self, columns: &mut Vec<&'a str>) {
+        match self {
+            Condition::And(left, right) | Condition::Or(left, right) => {
+                left.columns(columns);
+                right.columns(columns);
+            }
+            Condition::Not(condition) => condition.columns(columns),
+            Condition::Compare { column, .. } | Condition::IsNull { column } => {
+                columns.push(column)
+            }
+        }
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        match self {
+            Condition::And(left, right) => left.matches(row) && right.matches(row),
+            Condition::Or(left, right) => left.matches(row) || right.matches(row),
+            Condition::Not(condition) => !condition.matches(row),
+            Condition::IsNull { column } => !row.contains_key(column),
+            Condition::Compare {
+                column,
+                operator,
+                value,
+            } => match row.get(column) {
+                None => false,
+                Some(row_value) => match operator {
+                    Operator::Like => like(row_value, value),
+                    Operator::Equal => compare(row_value, value) == Ordering::Equal,
+                    Operator::NotEqual => compare(row_value, value) != Ordering::Equal,
+                    Operator::Less => compare(row_value, value) == Ordering::Less,
+                    Operator::LessOrEqual => compare(row_value, value) != Ordering::Greater,
+                    Operator::Greater => compare(row_value, value) == Ordering::Greater,
+                    Operator::GreaterOrEqual => compare(row_value, value) != Ordering::Less,
+                },
+            },
+        }
+    }
+}
+
+/// compare as numbers if both values are numbers
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+/// SQL LIKE: `%` matches any characters, `_` exactly one
+fn like(value: &str, pattern: &str) -> bool {
+    let value: Vec<char> = value.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut v, mut p) = (0, 0);
+    // position after the last '%' of the pattern, and the value position it matched from
+    let mut last_wildcard: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('%') => {
+                p += 1;
+                last_wildcard = Some((p, v));
+            }
+            Some(c) if *c == '_' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match last_wildcard {
+                // let the '%' match one more character
+                Some((after_wildcard, matched_from)) => {
+                    p = after_wildcard;
+                    v = matched_from + 1;
+                    last_wildcard = Some((after_wildcard, v));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '%')
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Query {
+    /// None for `*`
+    columns: Option<Vec<String>>,
+    table: Table,
+    filter: Option<Condition>,
+    /// column, descending
+    order_by: Option<(String, bool)>,
+    limit: Option<usize>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn unexpected(token: Option<Token>, expected: &str) -> StateQueryError {
+        StateQueryError::Syntax(match token {
+            Some(token) => format!("expected {expected}, got {token}"),
+            None => format!("expected {expected} at the end of the query"),
+        })
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), StateQueryError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(Self::unexpected(self.peek().cloned(), keyword))
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(known)) if *known == symbol) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn word(&mut self, expected: &str) -> Result<String, StateQueryError> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            other => Err(Self::unexpected(other, expected)),
+        }
+    }
+
+    fn query(&mut self) -> Result<Query, StateQueryError> {
+        self.expect_keyword("SELECT")?;
+        let columns = if self.eat_symbol("*") {
+            None
+        } else {
+            let mut columns = vec![self.word("a column")?];
+            while self.eat_symbol(",") {
+                columns.push(self.word("a column")?);
+            }
+            Some(columns)
+        };
+
+        self.expect_keyword("FROM")?;
+        let table = Table::parse(&self.word("a table")?)?;
+
+        let filter = if self.eat_keyword("WHERE") {
+            Some(self.or()?)
+        } else {
+            None
+        };
+
+        let order_by = if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            let column = self.word("a column")?;
+            let descending = self.eat_keyword("DESC");
+            if !descending {
+                self.eat_keyword("ASC");
+            }
+            Some((column, descending))
+        } else {
+            None
+        };
+
+        let limit = if self.eat_keyword("LIMIT") {
+            match self.next() {
+                Some(Token::Number(number)) => Some(
+                    number
+                        .parse()
+                        .map_err(|_| StateQueryError::Syntax(format!("invalid limit {number}")))?,
+                ),
+                other => return Err(Self::unexpected(other, "a number")),
+            }
+        } else {
+            None
+        };
+
+        if let Some(token) = self.next() {
+            return Err(StateQueryError::Syntax(format!(
+                "unexpected {token} after the query"
+            )));
+        }
+
+        Ok(Query {
+            columns,
+            table,
+            filter,
+            order_by,
+            limit,
+        })
+    }
+
+    fn or(&mut self) -> Result<Condition, StateQueryError> {
+        let mut condition = self.and()?;
+        while self.eat_keyword("OR") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, StateQueryError> {
+        let mut condition = self.not()?;
+        while self.eat_keyword("AND") {
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, StateQueryError> {
+        if self.eat_keyword("NOT") {
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+        if self.eat_symbol("(") {
+            let condition = self.or()?;
+            if !self.eat_symbol(")") {
+                return Err(Self::unexpected(self.peek().cloned(), "')'"));
+            }
+            return Ok(condition);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Condition, StateQueryError> {
+        let column = self.word("a column")?;
+
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            let condition = Condition::IsNull { column };
+            return Ok(if negated {
+                Condition::Not(Box::new(condition))
+            } else {
+                condition
+            });
+        }
+
+        let (operator, negated) = match self.next() {
+            Some(Token::Symbol("=")) => (Operator::Equal, false),
+            Some(Token::Symbol("!=" | "<>")) => (Operator::NotEqual, false),
+            Some(Token::Symbol("<")) => (Operator::Less, false),
+            Some(Token::Symbol("<=")) => (Operator::LessOrEqual, false),
+            Some(Token::Symbol(">")) => (Operator::Greater, false),
+            Some(Token::Symbol(">=")) => (Operator::GreaterOrEqual, false),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("LIKE") => (Operator::Like, false),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("NOT") => {
+                self.expect_keyword("LIKE")?;
+                (Operator::Like, true)
+            }
+            other => return Err(Self::unexpected(other, "a comparison operator")),
+        };
+
+        let value = match self.next() {
+            Some(Token::String(value) | Token::Number(value)) => value,
+            // unquoted values, like true or false
+            Some(Token::Word(value)) => value,
+            other => return Err(Self::unexpected(other, "a value")),
+        };
+
+        let condition = Condition::Compare {
+            column,
+            operator,
+            value,
+        };
+        Ok(if negated {
+            Condition::Not(Box::new(condition))
+        } else {
+            condition
+        })
+    }
+}
+
+impl Query {
+    fn parse(query: &str) -> Result<Self, StateQueryError> {
+        let mut parser = Parser {
+            tokens: tokenize(query)?,
+            position: 0,
+        };
+        let query = parser.query()?;
+
+        let mut columns: Vec<&str> = Vec::new();
+        if let Some(selected) = &query.columns {
+            columns.extend(selected.iter().map(String::as_str));
+        }
+        if let Some(filter) = &query.filter {
+            filter.columns(&mut columns);
+        }
+        if let Some((column, _)) = &query.order_by {
+            columns.push(column);
+        }
+        if let Some(unknown) = columns
+            .into_iter()
+            .find(|column| !query.table.has_column(column))
+        {
+            return Err(StateQueryError::UnknownColumn {
+                table: query.table,
+                column: unknown.to_owned(),
+            });
+        }
+
+        Ok(query)
+    }
+}
+
+impl ConfigState {
+    /// Run a query like `SELECT hostname, cluster FROM frontends WHERE tag.team = 'payments'`
+    /// on the state, see [`crate::state_query`] for the language
+    pub fn query(&self, query: &str) -> Result<StateQueryResult, StateQueryError> {
+        let query = Query::parse(query)?;
+
+        let mut rows: Vec<Row> = query
+            .table
+            .rows(self)
+            .into_iter()
+            .filter(|row| {
+                query
+                    .filter
+                    .as_ref()
+                    .map_or(true, |condition| condition.matches(row))
+            })
+            .collect();
+
+        if let Some((column, descending)) = &query.order_by {
+            // the rows without value come first
+            rows.sort_by(|a, b| {
+                let ordering = match (a.get(column), b.get(column)) {
+                    (Some(a), Some(b)) => compare(a, b),
+                    (a, b) => a.is_some().cmp(&b.is_some()),
+                };
+                if *descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+        }
+        if let Some(limit) = query.limit {
+            rows.truncate(limit);
+        }
+
+        let columns: Vec<String> = match query.columns {
+            Some(columns) => columns,
+            None => query
+                .table
+                .columns()
+                .iter()
+                .map(|column| column.to_string())
+                .collect(),
+        };
+
+        let rows = rows
+            .into_iter()
+            .map(|mut row| StateQueryRow {
+                values: columns
+                    .iter()
+                    .filter_map(|column| row.remove(column).map(|value| (column.to_owned(), value)))
+                    .collect(),
+            })
+            .collect();
+
+        Ok(StateQueryResult { columns, rows })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::command::{
+        request::RequestType, AddBackend, LoadBalancingParams, PathRule, RequestHttpFrontend,
+    };
+
+    fn state() -> ConfigState {
+        let mut state = ConfigState::new();
+        let requests: Vec<RequestType> = vec![
+            RequestType::AddCluster(Cluster {
+                cluster_id: "payments".to_owned(),
+                ..Default::default()
+            }),
+            RequestType::AddCluster(Cluster {
+                cluster_id: "web".to_owned(),
+                sticky_session: true,
+                ..Default::default()
+            }),
+            RequestType::AddHttpFrontend(RequestHttpFrontend {
+                cluster_id: Some("payments".to_owned()),
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                hostname: "pay.example.com".to_owned(),
+                path: PathRule::prefix("/api".to_owned()),
+                tags: BTreeMap::from([("team".to_owned(), "payments".to_owned())]),
+                ..Default::default()
+            }),
+            RequestType::AddHttpFrontend(RequestHttpFrontend {
+                cluster_id: Some("web".to_owned()),
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                hostname: "www.example.com".to_owned(),
+                path: PathRule::prefix(String::new()),
+                ..Default::default()
+            }),
+            RequestType::AddHttpFrontend(RequestHttpFrontend {
+                cluster_id: None,
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                hostname: "admin.example.com".to_owned(),
+                path: PathRule::prefix(String::new()),
+                ..Default::default()
+            }),
+            RequestType::AddBackend(AddBackend {
+                cluster_id: "web".to_owned(),
+                backend_id: "web-0".to_owned(),
+                address: SocketAddress::new_v4(10, 0, 0, 1, 8000),
+                load_balancing_parameters: Some(LoadBalancingParams {
+                    weight: 100,
+                    slow_start_seconds: None,
+                }),
+                ..Default::default()
+            }),
+            RequestType::AddBackend(AddBackend {
+                cluster_id: "web".to_owned(),
+                backend_id: "web-1".to_owned(),
+                address: SocketAddress::new_v4(10, 0, 0, 2, 8000),
+                load_balancing_parameters: Some(LoadBalancingParams {
+                    weight: 20,
+                    slow_start_seconds: None,
+                }),
+                ..Default::default()
+            }),
+        ];
+        for request in requests {
+            state
+                .dispatch(&request.into())
+                .expect("could not build the state");
+        }
+        state
+    }
+
+    fn values(result: &StateQueryResult, column: &str) -> Vec<Option<String>> {
+        result
+            .rows
+            .iter()
+            .map(|row| row.values.get(column).cloned())
+            .collect()
+    }
+
+    #[test]
+    fn select_with_tags() {
+        let result = state()
+            .query("SELECT hostname, cluster FROM frontends WHERE tag.team='payments'")
+            .expect("could not run the query");
+
+        assert_eq!(result.columns, vec!["hostname", "cluster"]);
+        assert_eq!(
+            values(&result, "hostname"),
+            vec![Some("pay.example.com".to_owned())]
+        );
+        assert_eq!(
+            values(&result, "cluster"),
+            vec![Some("payments".to_owned())]
+        );
+    }
+
+    #[test]
+    fn conditions_order_and_limit() {
+        let state = state();
+
+        let result = state
+            .query("select hostname from frontends where cluster is null or hostname like 'www.%'")
+            .unwrap();
+        assert_eq!(
+            values(&result, "hostname"),
+            vec![
+                Some("admin.example.com".to_owned()),
+                Some("www.example.com".to_owned())
+            ]
+        );
+
+        // the weights are compared as numbers
+        let result = state
+            .query("SELECT backend, weight FROM backends WHERE weight > 30 OR NOT (cluster = 'web') ORDER BY weight DESC")
+            .unwrap();
+        assert_eq!(values(&result, "backend"), vec![Some("web-0".to_owned())]);
+
+        let result = state
+            .query("SELECT * FROM backends ORDER BY weight LIMIT 1")
+            .unwrap();
+        assert_eq!(result.columns, Table::Backends.columns());
+        assert_eq!(values(&result, "backend"), vec![Some("web-1".to_owned())]);
+
+        let result = state
+            .query("SELECT cluster, backends FROM clusters WHERE sticky_session = true")
+            .unwrap();
+        assert_eq!(values(&result, "backends"), vec![Some("2".to_owned())]);
+    }
+
+    #[test]
+    fn invalid_queries() {
+        let state = state();
+
+        assert_eq!(
+            state.query("SELECT * FROM routes"),
+            Err(StateQueryError::UnknownTable("routes".to_owned()))
+        );
+        assert!(matches!(
+            state.query("SELECT name FROM clusters"),
+            Err(StateQueryError::UnknownColumn { .. })
+        ));
+        assert!(matches!(
+            state.query("SELECT * FROM clusters WHERE cluster = 'web"),
+            Err(StateQueryError::Syntax(_))
+        ));
+        assert!(matches!(
+            state.query("SELECT * FROM clusters WHERE cluster 'web'"),
+            Err(StateQueryError::Syntax(_))
+        ));
+        assert!(matches!(
+            state.query("SELECT * FROM clusters LIMIT 1 2"),
+            Err(StateQueryError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn timeouts_of_the_frontends() {
+        let mut state = state();
+        let requests: Vec<RequestType> = vec![
+            RequestType::AddHttpListener(HttpListenerConfig {
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                back_timeout: 40,
+                request_deadline: Some(300),
+                ..Default::default()
+            }),
+            RequestType::AddCluster(Cluster {
+                cluster_id: "payments".to_owned(),
+                timeouts: Some(Timeouts {
+                    backend_response: Some(90),
+                    request_deadline: Some(120),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            RequestType::AddHttpFrontend(RequestHttpFrontend {
+                cluster_id: Some("payments".to_owned()),
+                address: SocketAddress::new_v4(0, 0, 0, 0, 8080),
+                hostname: "pay.example.com".to_owned(),
+                path: PathRule::prefix("/upload".to_owned()),
+                timeouts: Some(Timeouts {
+                    body_read: Some(600),
+                    request_deadline: Some(900),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        ];
+        for request in requests {
+            state.dispatch(&request.into()).unwrap();
+        }
+
+        let result = state
+            .query("SELECT * FROM timeouts WHERE hostname = 'pay.example.com' ORDER BY path")
+            .unwrap();
+        assert_eq!(result.columns, Table::Timeouts.columns());
+        let row = |path: &str| {
+            let row = result
+                .rows
+                .iter()
+                .find(|row| row.values["path"] == path)
+                .unwrap();
+            Table::Timeouts.columns()[6..]
+                .iter()
+                .map(|column| row.values[*column].as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            row("/api"),
+            vec![
+                "10", "listener", "60", "listener", "3", "listener", "90", "cluster", "120",
+                "cluster"
+            ]
+        );
+        assert_eq!(
+            row("/upload"),
+            vec![
+                "10", "listener", "600", "frontend", "3", "listener", "90", "cluster", "900",
+                "frontend"
+            ]
+        );
+
+        let result = state
+            .query("SELECT backend_response, request_deadline FROM timeouts WHERE hostname = 'www.example.com'")
+            .unwrap();
+        assert_eq!(
+            values(&result, "backend_response"),
+            vec![Some("40".to_owned())]
+        );
+        assert_eq!(
+            values(&result, "request_deadline"),
+            vec![Some("300".to_owned())]
+        );
+    }
+
+    #[test]
+    fn like_patterns() {
+        assert!(like("api.example.com", "%.example.com"));
+        assert!(like("api.example.com", "api%"));
+        assert!(like("api", "a_i"));
+        assert!(like("aXbXc", "a%b%c"));
+        assert!(!like("api.example.org", "%.example.com"));
+        assert!(!like("api", "a_"));
+    }
+}
@@ -0,0 +1,332 @@
+//! Compact transfer of the state from the main process to a new worker.
+//!
+//! The requests of the state are split in chunks, each chunk is a protobuf
+//! `InitialState`, compressed with deflate and followed by its checksum:
+//!
+//! ```text
+//! magic (10 bytes) | total requests (u64)
+//! chunk: requests (u32) | compressed length (u32) | crc32 (u32) | compressed bytes
+//! ...
+//! end:   0u32
+//! ```
+//!
+//! All integers are little endian. A state written as a single protobuf message,
+//! the format used before, is still read.
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use prost::{DecodeError, Message};
+
+use crate::proto::command::{InitialState, WorkerRequest};
+
+/// marks the chunked format, with its version in the last byte
+pub const STATE_TRANSFER_MAGIC: &[u8; 10] = b"SOZUSTATE\x01";
+
+/// number of requests in each chunk
+pub const STATE_CHUNK_SIZE: usize = 2000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum StateTransferError {
+    #[error("could not write the state: {0}")]
+    Write(io::Error),
+    #[error("could not read the state: {0}")]
+    Read(io::Error),
+    #[error(
+        "chunk {chunk} is corrupted, its checksum is {actual:#010x} instead of {expected:#010x}"
+    )]
+    Checksum {
+        chunk: usize,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("could not decompress chunk {chunk}: {error}")]
+    Decompress { chunk: usize, error: io::Error },
+    #[error("could not decode chunk {chunk}: {error}")]
+    Decode { chunk: usize, error: DecodeError },
+    #[error("could not decode the state: {0}")]
+    DecodeLegacy(DecodeError),
+    #[error("chunk {chunk} should hold {expected} requests, it holds {actual}")]
+    ChunkLength {
+        chunk: usize,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("the state should hold {expected} requests, {actual} were read")]
+    RequestCount { expected: usize, actual: usize },
+}
+
+/// What was written or read during a transfer
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TransferSummary {
+    pub requests: usize,
+    pub chunks: usize,
+    /// size of the requests encoded in protobuf
+    pub raw_bytes: usize,
+    /// size of the compressed chunks
+    pub compressed_bytes: usize,
+}
+
+/// write the requests in chunks, compressed and checksummed
+pub fn write_state<W: Write>(
+    requests: &[WorkerRequest],
+    writer: &mut W,
+) -> Result<TransferSummary, StateTransferError> {
+    let mut summary = TransferSummary {
+        requests: requests.len(),
+        ..Default::default()
+    };
+
+    writer
+        .write_all(STATE_TRANSFER_MAGIC)
+        .map_err(StateTransferError::Write)?;
+    writer
+        .write_all(&(requests.len() as u64).to_le_bytes())
+        .map_err(StateTransferError::Write)?;
+
+    for chunk in requests.chunks(STATE_CHUNK_SIZE) {
+        let encoded = InitialState {
+            requests: chunk.to_vec(),
+        }
+        .encode_to_vec();
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder
+            .write_all(&encoded)
+            .map_err(StateTransferError::Write)?;
+        let compressed = encoder.finish().map_err(StateTransferError::Write)?;
+
+        writer
+            .write_all(&(chunk.len() as u32).to_le_bytes())
+            .map_err(StateTransferError::Write)?;
+        writer
+            .write_all(&(compressed.len() as u32).to_le_bytes())
+            .map_err(StateTransferError::Write)?;
+        writer
+            .write_all(&crc32fast::hash(&compressed).to_le_bytes())
+            .map_err(StateTransferError::Write)?;
+        writer
+            .write_all(&compressed)
+            .map_err(StateTransferError::Write)?;
+
+        summary.chunks += 1;
+        summary.raw_bytes += encoded.len();
+        summary.compressed_bytes += compressed.len();
+    }
+
+    writer
+        .write_all(&0u32.to_le_bytes())
+        .map_err(StateTransferError::Write)?;
+    writer.flush().map_err(StateTransferError::Write)?;
+
+    Ok(summary)
+}
+
+/// read a state written by [`write_state`], or a single protobuf message.
+/// `progress` is called after each chunk with the requests read so far and the total
+pub fn read_state<R: Read, F: FnMut(usize, usize)>(
+    reader: &mut R,
+    mut progress: F,
+) -> Result<(InitialState, TransferSummary), StateTransferError> {
+    let mut magic = Vec::with_capacity(STATE_TRANSFER_MAGIC.len());
+    reader
+        .by_ref()
+        .take(STATE_TRANSFER_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .map_err(StateTransferError::Read)?;
+
+    if magic != STATE_TRANSFER_MAGIC {
+        let mut buffer = magic;
+        reader
+            .read_to_end(&mut buffer)
+            .map_err(StateTransferError::Read)?;
+        let state = InitialState::decode(&buffer[..]).map_err(StateTransferError::DecodeLegacy)?;
+        let summary = TransferSummary {
+            requests: state.requests.len(),
+            chunks: 1,
+            raw_bytes: buffer.len(),
+            compressed_bytes: buffer.len(),
+        };
+        progress(summary.requests, summary.requests);
+        return Ok((state, summary));
+    }
+
+    let total = read_u64(reader)? as usize;
+    let mut summary = TransferSummary::default();
+    // the count is not trusted before the chunks are verified
+    let mut requests = Vec::with_capacity(total.min(STATE_CHUNK_SIZE * 64));
+
+    loop {
+        let chunk_length = read_u32(reader)? as usize;
+        if chunk_length == 0 {
+            break;
+        }
+        let chunk = summary.chunks;
+        let compressed_length = read_u32(reader)? as usize;
+        let expected = read_u32(reader)?;
+
+        let mut compressed = Vec::new();
+        reader
+            .by_ref()
+            .take(compressed_length as u64)
+            .read_to_end(&mut compressed)
+            .map_err(StateTransferError::Read)?;
+        if compressed.len() != compressed_length {
+            return Err(StateTransferError::Read(
+                io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+
+        let actual = crc32fast::hash(&compressed);
+        if actual != expected {
+            return Err(StateTransferError::Checksum {
+                chunk,
+                expected,
+                actual,
+            });
+        }
+
+        let mut encoded = Vec::new();
+        DeflateDecoder::new(&compressed[..])
+            .read_to_end(&mut encoded)
+            .map_err(|error| StateTransferError::Decompress { chunk, error })?;
+        let state = InitialState::decode(&encoded[..])
+            .map_err(|error| StateTransferError::Decode { chunk, error })?;
+
+        if state.requests.len() != chunk_length {
+            return Err(StateTransferError::ChunkLength {
+                chunk,
+                expected: chunk_length,
+                actual: state.requests.len(),
+            });
+        }
+
+        requests.extend(state.requests);
+        summary.chunks += 1;
+        summary.raw_bytes += encoded.len();
+        summary.compressed_bytes += compressed_length;
+        progress(requests.len(), total);
+    }
+
+    if requests.len() != total {
+        return Err(StateTransferError::RequestCount {
+            expected: total,
+            actual: requests.len(),
+        });
+    }
+    summary.requests = total;
+
+    Ok((InitialState { requests }, summary))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, StateTransferError> {
+    let mut bytes = [0; 4];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(StateTransferError::Read)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, StateTransferError> {
+    let mut bytes = [0; 8];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(StateTransferError::Read)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::command::{request::RequestType, AddBackend, LoadBalancingParams};
+
+    fn requests(count: usize) -> Vec<WorkerRequest> {
+        (0..count)
+            .map(|i| {
+                WorkerRequest::new(
+                    format!("SAVE-{i}"),
+                    RequestType::AddBackend(AddBackend {
+                        cluster_id: format!("cluster-{}", i / 10),
+                        backend_id: format!("cluster-{}-{}", i / 10, i % 10),
+                        address: "127.0.0.1:8080"
+                            .parse::<std::net::SocketAddr>()
+                            .unwrap()
+                            .into(),
+                        load_balancing_parameters: Some(LoadBalancingParams::default()),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn write_and_read_the_state_in_chunks() {
+        let requests = requests(STATE_CHUNK_SIZE * 2 + 10);
+        let mut buffer = Vec::new();
+        let written = write_state(&requests, &mut buffer).unwrap();
+        assert_eq!(written.chunks, 3);
+        assert!(written.compressed_bytes < written.raw_bytes);
+
+        let mut steps = Vec::new();
+        let (state, read) = read_state(&mut &buffer[..], |loaded, total| {
+            steps.push((loaded, total))
+        })
+        .unwrap();
+
+        assert_eq!(state.requests, requests);
+        assert_eq!(read, written);
+        assert_eq!(
+            steps,
+            vec![
+                (STATE_CHUNK_SIZE, requests.len()),
+                (STATE_CHUNK_SIZE * 2, requests.len()),
+                (requests.len(), requests.len())
+            ]
+        );
+    }
+
+    #[test]
+    fn write_and_read_an_empty_state() {
+        let mut buffer = Vec::new();
+        write_state(&[], &mut buffer).unwrap();
+        let (state, summary) = read_state(&mut &buffer[..], |_, _| {}).unwrap();
+        assert!(state.requests.is_empty());
+        assert_eq!(summary.chunks, 0);
+    }
+
+    #[test]
+    fn detect_a_corrupted_chunk() {
+        let requests = requests(STATE_CHUNK_SIZE + 1);
+        let mut buffer = Vec::new();
+        write_state(&requests, &mut buffer).unwrap();
+
+        let last = buffer.len() - 5;
+        buffer[last] ^= 0xff;
+        match read_state(&mut &buffer[..], |_, _| {}) {
+            Err(StateTransferError::Checksum { chunk, .. }) => assert_eq!(chunk, 1),
+            other => panic!("expected a checksum error, got {other:?}"),
+        }
+
+        buffer.truncate(buffer.len() - 20);
+        assert!(matches!(
+            read_state(&mut &buffer[..], |_, _| {}),
+            Err(StateTransferError::Read(_))
+        ));
+    }
+
+    #[test]
+    fn read_a_state_in_a_single_message() {
+        let state = InitialState {
+            requests: requests(12),
+        };
+        let buffer = state.encode_to_vec();
+        let (read, summary) = read_state(&mut &buffer[..], |_, _| {}).unwrap();
+        assert_eq!(read, state);
+        assert_eq!(summary.requests, 12);
+
+        let (read, _) = read_state(&mut &[][..], |_, _| {}).unwrap();
+        assert!(read.requests.is_empty());
+    }
+}
@@ -159,7 +159,9 @@ impl Worker {
     }
 
     pub fn upgrade<S: Into<String>>(&mut self, name: S) -> Self {
-        self.send_proxy_request_type(RequestType::ReturnListenSockets(ReturnListenSockets {}));
+        self.send_proxy_request_type(RequestType::ReturnListenSockets(
+            ReturnListenSockets::default(),
+        ));
         self.read_to_last();
 
         self.scm_main_to_worker
@@ -201,6 +203,7 @@ impl Worker {
             .write_message(&WorkerRequest {
                 id: self.command_id.next(),
                 content: request,
+                sequence: None,
             })
             .expect("Could not write message on command channel");
     }
@@ -304,6 +307,8 @@ impl Worker {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id,
             backup: None,
+            expires_at: None,
+            draining: None,
         }
     }
 }
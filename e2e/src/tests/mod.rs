@@ -8,9 +8,7 @@ use std::{
 
 use sozu_command_lib::{
     config::ListenerBuilder,
-    proto::command::{
-        request::RequestType, ActivateListener, Cluster, ListenerType, Request, ServerConfig,
-    },
+    proto::command::{request::RequestType, ActivateListener, Cluster, ListenerType, ServerConfig},
     scm_socket::Listeners,
     state::ConfigState,
 };
@@ -57,32 +55,33 @@ pub fn setup_test<S: Into<String>>(
 ) -> (Worker, Vec<SocketAddr>) {
     let mut worker = Worker::start_new_worker(name, config, &listeners, state);
 
-    worker.send_proxy_request(Request {
-        request_type: Some(RequestType::AddHttpListener(
+    worker.send_proxy_request(
+        RequestType::AddHttpListener(
             ListenerBuilder::new_http(front_address.into())
                 .to_http(None)
                 .unwrap(),
-        )),
-    });
-    worker.send_proxy_request(Request {
-        request_type: Some(RequestType::ActivateListener(ActivateListener {
+        )
+        .into(),
+    );
+    worker.send_proxy_request(
+        RequestType::ActivateListener(ActivateListener {
             address: front_address.into(),
             proxy: ListenerType::Http.into(),
             from_scm: false,
-        })),
-    });
-    worker.send_proxy_request(Request {
-        request_type: Some(RequestType::AddCluster(Cluster {
+        })
+        .into(),
+    );
+    worker.send_proxy_request(
+        RequestType::AddCluster(Cluster {
             sticky_session: should_stick,
             ..Worker::default_cluster("cluster_0")
-        })),
-    });
-    worker.send_proxy_request(Request {
-        request_type: Some(RequestType::AddHttpFrontend(Worker::default_http_frontend(
-            "cluster_0",
-            front_address,
-        ))),
-    });
+        })
+        .into(),
+    );
+    worker.send_proxy_request(
+        RequestType::AddHttpFrontend(Worker::default_http_frontend("cluster_0", front_address))
+            .into(),
+    );
 
     let mut backends = Vec::new();
     for i in 0..nb_backends {
@@ -443,6 +443,101 @@ pub fn try_tls_endpoint() -> State {
     }
 }
 
+/// a ClientHello asking for a server name, made of ASCII bytes to be sent by the mock client
+fn client_hello(server_name: &str) -> String {
+    let name = server_name.as_bytes();
+    let mut extensions = vec![0x00, 0x00];
+    extensions.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+    extensions.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+    extensions.push(0x00);
+    extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(name);
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend_from_slice(&[0x2a; 32]);
+    hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+
+    let mut record = vec![
+        0x16,
+        0x03,
+        0x01,
+        0x00,
+        hello.len() as u8 + 4,
+        0x01,
+        0x00,
+        0x00,
+    ];
+    record.push(hello.len() as u8);
+    record.extend_from_slice(&hello);
+    String::from_utf8(record).expect("the ClientHello should be made of ASCII bytes")
+}
+
+pub fn try_unknown_sni_passthrough() -> State {
+    let front_port = provide_port();
+    let front_address = SocketAddress::new_v4(127, 0, 0, 1, front_port);
+    let back_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("SNI-PASSTHROUGH", config, &listeners, state);
+
+    worker.send_proxy_request_type(RequestType::AddHttpsListener(
+        ListenerBuilder::new_https(front_address.clone())
+            .with_unknown_sni_cluster(Some("legacy".to_owned()))
+            .to_tls(None)
+            .unwrap(),
+    ));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: front_address.clone(),
+        proxy: ListenerType::Https.into(),
+        from_scm: false,
+    }));
+
+    let certificate_and_key = CertificateAndKey {
+        certificate: String::from(include_str!("../../../lib/assets/local-certificate.pem")),
+        key: String::from(include_str!("../../../lib/assets/local-key.pem")),
+        certificate_chain: vec![],
+        versions: vec![],
+        names: vec![],
+    };
+    worker.send_proxy_request_type(RequestType::AddCertificate(AddCertificate {
+        address: front_address.clone(),
+        certificate: certificate_and_key,
+        expired_at: None,
+    }));
+
+    worker.send_proxy_request_type(RequestType::AddCluster(Worker::default_cluster("legacy")));
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "legacy",
+        "legacy-0",
+        back_address,
+        None,
+    )));
+    worker.read_to_last();
+
+    let mut backend = SyncBackend::new("LEGACY", back_address, "pong");
+    backend.connect();
+
+    let hello = client_hello("legacy.example.com");
+    let mut client = Client::new("client", front_address.into(), hello.clone());
+    client.connect();
+    client.send();
+
+    let relayed = backend.accept(0) && backend.receive(0).as_deref() == Some(hello.as_str());
+    backend.send(0);
+    let answered = client.receive().as_deref() == Some("pong");
+
+    worker.soft_stop();
+    let success = worker.wait_for_server_stop();
+
+    if success && relayed && answered {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
 pub fn test_upgrade() -> State {
     let front_address = create_local_address();
 
@@ -1738,6 +1833,18 @@ fn test_tls_endpoint() {
     );
 }
 
+#[test]
+fn test_unknown_sni_passthrough() {
+    assert_eq!(
+        repeat_until_error_or(
+            10,
+            "TLS passthrough: Sōzu should relay the connections of unknown server names",
+            try_unknown_sni_passthrough
+        ),
+        State::Success
+    );
+}
+
 #[test]
 fn test_http_behaviors() {
     assert_eq!(
@@ -73,6 +73,7 @@ fn main() -> anyhow::Result<()> {
         .write_message(&WorkerRequest {
             id: String::from("add-the-cluster"),
             content: RequestType::AddCluster(cluster).into(),
+            sequence: None,
         })
         .expect("Could not send AddHttpFrontend request");
 
@@ -80,6 +81,7 @@ fn main() -> anyhow::Result<()> {
         .write_message(&WorkerRequest {
             id: String::from("add-the-frontend"),
             content: RequestType::AddHttpFrontend(http_front).into(),
+            sequence: None,
         })
         .expect("Could not send AddHttpFrontend request");
 
@@ -87,6 +89,7 @@ fn main() -> anyhow::Result<()> {
         .write_message(&WorkerRequest {
             id: String::from("add-the-backend"),
             content: RequestType::AddBackend(http_backend).into(),
+            sequence: None,
         })
         .expect("Could not send AddBackend request");
 
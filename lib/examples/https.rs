@@ -25,6 +25,7 @@ fn main() -> anyhow::Result<()> {
     sozu_lib::metrics::setup(
         &SocketAddress::new_v4(127, 0, 0, 1, 8125).into(),
         "main",
+        0,
         false,
         None,
     );
@@ -61,16 +62,20 @@ fn main() -> anyhow::Result<()> {
         address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        expires_at: None,
+        draining: None,
     };
 
     command.write_message(&WorkerRequest {
         id: String::from("ID_ABCD"),
         content: RequestType::AddHttpFrontend(http_front).into(),
+        sequence: None,
     });
 
     command.write_message(&WorkerRequest {
         id: String::from("ID_EFGH"),
         content: RequestType::AddBackend(http_backend).into(),
+        sequence: None,
     });
 
     info!("MAIN\tHTTP -> {:?}", command.read_message());
@@ -110,6 +115,7 @@ fn main() -> anyhow::Result<()> {
             expired_at: None,
         })
         .into(),
+        sequence: None,
     });
 
     let tls_front = RequestHttpFrontend {
@@ -123,6 +129,7 @@ fn main() -> anyhow::Result<()> {
     command2.write_message(&WorkerRequest {
         id: String::from("ID_IJKL2"),
         content: RequestType::AddHttpsFrontend(tls_front).into(),
+        sequence: None,
     });
     let tls_backend = AddBackend {
         cluster_id: String::from("cluster_1"),
@@ -131,11 +138,14 @@ fn main() -> anyhow::Result<()> {
         address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        expires_at: None,
+        draining: None,
     };
 
     command2.write_message(&WorkerRequest {
         id: String::from("ID_MNOP"),
         content: RequestType::AddBackend(tls_backend).into(),
+        sequence: None,
     });
 
     let cert2 = include_str!("../assets/cert_test.pem");
@@ -157,6 +167,7 @@ fn main() -> anyhow::Result<()> {
             expired_at: None,
         })
         .into(),
+        sequence: None,
     });
 
     let tls_front2 = RequestHttpFrontend {
@@ -170,6 +181,7 @@ fn main() -> anyhow::Result<()> {
     command2.write_message(&WorkerRequest {
         id: String::from("ID_QRST2"),
         content: RequestType::AddHttpsFrontend(tls_front2).into(),
+        sequence: None,
     });
 
     let tls_backend2 = AddBackend {
@@ -179,11 +191,14 @@ fn main() -> anyhow::Result<()> {
         address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        expires_at: None,
+        draining: None,
     };
 
     command2.write_message(&WorkerRequest {
         id: String::from("ID_UVWX"),
         content: RequestType::AddBackend(tls_backend2).into(),
+        sequence: None,
     });
 
     info!("MAIN\tTLS -> {:?}", command2.read_message());
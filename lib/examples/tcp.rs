@@ -46,16 +46,20 @@ fn main() -> anyhow::Result<()> {
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         sticky_id: None,
         backup: None,
+        expires_at: None,
+        draining: None,
     };
 
     command.write_message(&WorkerRequest {
         id: String::from("ID_ABCD"),
         content: RequestType::AddTcpFrontend(tcp_front).into(),
+        sequence: None,
     });
 
     command.write_message(&WorkerRequest {
         id: String::from("ID_EFGH"),
         content: RequestType::AddBackend(tcp_backend).into(),
+        sequence: None,
     });
 
     info!("TCP -> {:?}", command.read_message());
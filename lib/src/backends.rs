@@ -1,16 +1,33 @@
-use std::{cell::RefCell, collections::HashMap, net::SocketAddr, rc::Rc, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
+};
 
 use mio::net::TcpStream;
+use rand::{thread_rng, Rng};
 
 use sozu_command::{
-    proto::command::{Event, EventKind, LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric},
+    config::DEFAULT_BACKEND_WEIGHT,
+    proto::command::{
+        BackendHealth, BackendHealthStatus, DrainingBackend, Event, EventKind, HashKey,
+        HashKeyKind, LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric, OutlierDetection,
+        StickyEntry,
+    },
     state::ClusterId,
 };
 
 use crate::{
-    load_balancing::{LeastLoaded, LoadBalancingAlgorithm, PowerOfTwo, Random, RoundRobin},
+    load_balancing::{
+        hash_value, ConsistentHash, LeastLoaded, LoadBalancingAlgorithm, PowerOfTwo, Random,
+        RoundRobin,
+    },
     retry::{self, RetryPolicy},
-    server::{self, push_event},
+    server::{self, push_event, push_sticky_entry},
+    signing::StickySigner,
+    socket::{connect_from, is_fd_exhaustion, set_dscp},
     PeakEWMA,
 };
 
@@ -20,6 +37,13 @@ pub enum BackendError {
     NoBackendForCluster(String),
     #[error("Failed to connect to socket with MIO: {0}")]
     MioConnection(std::io::Error),
+    #[error("no file descriptor left to connect to the backend: {0}")]
+    FdExhausted(std::io::Error),
+    #[error("No backend {backend_id} in cluster {cluster_id}")]
+    NoBackendWithId {
+        cluster_id: String,
+        backend_id: String,
+    },
     #[error("This backend is not in a normal status: status={0:?}")]
     Status(BackendStatus),
     #[error(
@@ -33,6 +57,16 @@ pub enum BackendError {
     },
 }
 
+/// maximum number of clients remembered by the sticky table of a cluster.
+/// New clients are load balanced without being recorded once it is full
+pub const MAX_STICKY_ENTRIES: usize = 10_000;
+
+/// the outcomes of the requests of a backend are counted in buckets of this length
+const OUTCOME_BUCKET_LENGTH: Duration = Duration::from_secs(1);
+
+/// share of its traffic a backend gets at the start of its slow start
+const SLOW_START_INITIAL_FACTOR: f64 = 0.1;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum BackendStatus {
     Normal,
@@ -40,6 +74,40 @@ pub enum BackendStatus {
     Closed,
 }
 
+/// why the load balancing left a backend out of the choice for a request
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SkipReason {
+    /// the backend was removed or drained, and waits for its connections to close
+    Draining,
+    /// outlier detection ejected the backend
+    Ejected,
+    /// the backend fails the active health checks of the main process
+    FailingHealthChecks,
+    /// the retry policy waits before connecting again, after connection failures
+    RetryBackoff,
+    /// backups only get the traffic when no other backend is available
+    Backup,
+    /// the backend warms up, and was skipped to ramp up its traffic
+    SlowStart,
+    /// the weighing load balancing never picks a backend of weight 0
+    WeightZero,
+}
+
+impl SkipReason {
+    /// the counter incremented for the backend, each time it is left out for this reason
+    pub fn metric_key(&self) -> &'static str {
+        match self {
+            SkipReason::Draining => "backend.selection.skipped.draining",
+            SkipReason::Ejected => "backend.selection.skipped.ejected",
+            SkipReason::FailingHealthChecks => "backend.selection.skipped.health_check",
+            SkipReason::RetryBackoff => "backend.selection.skipped.retry_backoff",
+            SkipReason::Backup => "backend.selection.skipped.backup",
+            SkipReason::SlowStart => "backend.selection.skipped.slow_start",
+            SkipReason::WeightZero => "backend.selection.skipped.weight_zero",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Backend {
     pub sticky_id: Option<String>,
@@ -50,9 +118,24 @@ pub struct Backend {
     pub active_connections: usize,
     pub active_requests: usize,
     pub failures: usize,
+    /// successful connections since the backend was added
+    pub connect_successes: u64,
+    /// failed connection attempts since the backend was added
+    pub connect_failures: u64,
     pub load_balancing_parameters: Option<LoadBalancingParams>,
     pub backup: bool,
     pub connection_time: PeakEWMA,
+    pub response_time: PeakEWMA,
+    /// outcomes of the recent requests, None if the cluster has no outlier detection
+    pub outcomes: Option<OutcomeWindow>,
+    /// set while outlier detection keeps the backend out of load balancing
+    pub ejected_until: Option<Instant>,
+    /// set while the backend fails the active health checks of the main process
+    pub failing_health_checks: bool,
+    /// set while the traffic sent to the backend ramps up, after it was added or came back up
+    pub warming_since: Option<Instant>,
+    /// set while the backend is drained: no new session is routed to it
+    pub draining: bool,
 }
 
 impl Backend {
@@ -73,9 +156,17 @@ impl Backend {
             active_connections: 0,
             active_requests: 0,
             failures: 0,
+            connect_successes: 0,
+            connect_failures: 0,
             load_balancing_parameters,
             backup: backup.unwrap_or(false),
             connection_time: PeakEWMA::new(),
+            response_time: PeakEWMA::new(),
+            outcomes: None,
+            ejected_until: None,
+            failing_health_checks: false,
+            warming_since: None,
+            draining: false,
         }
     }
 
@@ -88,13 +179,34 @@ impl Backend {
     }
 
     pub fn can_open(&self) -> bool {
-        if let Some(action) = self.retry_policy.can_try() {
-            self.status == BackendStatus::Normal && action == retry::RetryAction::OKAY
-        } else {
-            false
+        self.unavailability().is_none()
+    }
+
+    /// why no new connection can be opened to the backend, if none can
+    pub fn unavailability(&self) -> Option<SkipReason> {
+        if self.status != BackendStatus::Normal || self.draining {
+            return Some(SkipReason::Draining);
+        }
+        if self.ejected_until.is_some() {
+            return Some(SkipReason::Ejected);
+        }
+        if self.failing_health_checks {
+            return Some(SkipReason::FailingHealthChecks);
+        }
+        match self.retry_policy.can_try() {
+            Some(retry::RetryAction::OKAY) => None,
+            _ => Some(SkipReason::RetryBackoff),
         }
     }
 
+    /// load balancing weight of the backend, as configured
+    pub fn weight(&self) -> i32 {
+        self.load_balancing_parameters
+            .as_ref()
+            .map(|p| p.weight)
+            .unwrap_or(DEFAULT_BACKEND_WEIGHT)
+    }
+
     pub fn inc_connections(&mut self) -> Option<usize> {
         if self.status == BackendStatus::Normal {
             self.active_connections += 1;
@@ -132,21 +244,118 @@ impl Backend {
         self.connection_time.observe(dur.as_nanos() as f64);
     }
 
+    /// the health of the backend, as seen by this worker
+    pub fn health(&self) -> BackendHealth {
+        let status = if self.status != BackendStatus::Normal {
+            BackendHealthStatus::Closing
+        } else if self.draining {
+            BackendHealthStatus::Draining
+        } else if self.failing_health_checks {
+            BackendHealthStatus::Unhealthy
+        } else if self.ejected_until.is_some() {
+            BackendHealthStatus::Ejected
+        } else if self.retry_policy.is_down() {
+            BackendHealthStatus::Down
+        } else {
+            BackendHealthStatus::Healthy
+        };
+
+        BackendHealth {
+            backend_id: self.backend_id.clone(),
+            address: self.address.into(),
+            status: status as i32,
+            connections: self.active_connections as u64,
+            connect_successes: self.connect_successes,
+            connect_failures: self.connect_failures,
+            consecutive_failures: self.failures as u64,
+            // the moving average starts from an arbitrary value
+            connection_time: (self.connect_successes > 0)
+                .then_some((self.connection_time.rtt / 1_000f64) as u64),
+        }
+    }
+
     pub fn peak_ewma_connection(&mut self) -> f64 {
         self.connection_time.get(self.active_connections)
     }
 
-    pub fn try_connect(&mut self) -> Result<mio::net::TcpStream, BackendError> {
+    pub fn set_response_time(&mut self, dur: Duration) {
+        self.response_time.observe(dur.as_nanos() as f64);
+    }
+
+    /// latency estimate of the backend, scaled by its outstanding requests
+    pub fn peak_ewma_response(&mut self) -> f64 {
+        self.response_time.get(self.active_requests)
+    }
+
+    /// ramp the traffic sent to the backend up from a fraction, if it has a slow start
+    pub fn start_slow_start(&mut self) {
+        if self.slow_start().is_some() {
+            self.warming_since = Some(Instant::now());
+        }
+    }
+
+    fn slow_start(&self) -> Option<Duration> {
+        self.load_balancing_parameters
+            .as_ref()
+            .and_then(|params| params.slow_start_seconds)
+            .filter(|seconds| *seconds > 0)
+            .map(|seconds| Duration::from_secs(seconds as u64))
+    }
+
+    /// share of its traffic the backend gets while it warms up, 1.0 once it is warm
+    pub fn slow_start_factor(&mut self, now: Instant) -> f64 {
+        let (Some(since), Some(slow_start)) = (self.warming_since, self.slow_start()) else {
+            self.warming_since = None;
+            return 1.0;
+        };
+        let elapsed = now.saturating_duration_since(since);
+        if elapsed >= slow_start {
+            self.warming_since = None;
+            return 1.0;
+        }
+        SLOW_START_INITIAL_FACTOR
+            + (1.0 - SLOW_START_INITIAL_FACTOR) * elapsed.as_secs_f64() / slow_start.as_secs_f64()
+    }
+
+    /// load balancing weight of the backend, reduced while it warms up
+    pub fn effective_weight(&mut self, now: Instant) -> i32 {
+        (self.weight() as f64 * self.slow_start_factor(now)).ceil() as i32
+    }
+
+    /// count the outcome of a request for outlier detection, if the cluster uses it
+    pub fn record_outcome(&mut self, outcome: RequestOutcome) {
+        if let Some(outcomes) = self.outcomes.as_mut() {
+            outcomes.record(outcome, Instant::now());
+        }
+    }
+
+    /// Connect to the backend, from the `source_address` IP if there is one.
+    /// A transparent connection can use a non local source address.
+    pub fn try_connect(
+        &mut self,
+        source_address: Option<IpAddr>,
+        transparent: bool,
+    ) -> Result<mio::net::TcpStream, BackendError> {
         if self.status != BackendStatus::Normal {
             return Err(BackendError::Status(self.status.to_owned()));
         }
 
-        match mio::net::TcpStream::connect(self.address) {
+        let connection = match source_address {
+            Some(source) => connect_from(self.address, source, transparent),
+            None => mio::net::TcpStream::connect(self.address),
+        };
+
+        match connection {
             Ok(tcp_stream) => {
                 //self.retry_policy.succeed();
                 self.inc_connections();
                 Ok(tcp_stream)
             }
+            // running out of file descriptors is not the fault of the backend
+            Err(io_error) if is_fd_exhaustion(&io_error) => {
+                incr!("backend.connections.fd_exhausted");
+                Err(BackendError::FdExhausted(io_error))
+            }
             Err(io_error) => {
                 self.retry_policy.fail();
                 self.failures += 1;
@@ -160,6 +369,117 @@ impl Backend {
     }
 }
 
+/// How a request sent to a backend ended, as classified by outlier detection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestOutcome {
+    /// the backend answered with a status below 500
+    Success,
+    /// the backend answered with a 5xx status
+    ServerError,
+    /// the backend did not answer before the backend timeout
+    Timeout,
+}
+
+/// request counts of a backend over a period
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutcomeCounts {
+    pub requests: u64,
+    pub server_errors: u64,
+    pub timeouts: u64,
+}
+
+impl OutcomeCounts {
+    fn add(&mut self, other: &OutcomeCounts) {
+        self.requests += other.requests;
+        self.server_errors += other.server_errors;
+        self.timeouts += other.timeouts;
+    }
+
+    /// percentage of the requests answered with a 5xx
+    pub fn error_ratio(&self) -> f64 {
+        ratio(self.server_errors, self.requests)
+    }
+
+    /// percentage of the requests that timed out
+    pub fn timeout_ratio(&self) -> f64 {
+        ratio(self.timeouts, self.requests)
+    }
+
+    /// percentage of the requests answered with a 5xx or that timed out
+    pub fn failure_ratio(&self) -> f64 {
+        ratio(self.server_errors + self.timeouts, self.requests)
+    }
+}
+
+fn ratio(count: u64, requests: u64) -> f64 {
+    if requests == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / requests as f64
+    }
+}
+
+/// Outcomes of the requests of a backend in a sliding window, counted in buckets
+/// of one second so that the memory used does not depend on the traffic
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutcomeWindow {
+    length: Duration,
+    buckets: VecDeque<(Instant, OutcomeCounts)>,
+}
+
+impl OutcomeWindow {
+    pub fn new(length: Duration) -> Self {
+        OutcomeWindow {
+            length,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, outcome: RequestOutcome, now: Instant) {
+        self.expire(now);
+        let current_bucket = self
+            .buckets
+            .back()
+            .is_some_and(|(start, _)| now.duration_since(*start) < OUTCOME_BUCKET_LENGTH);
+        if !current_bucket {
+            self.buckets.push_back((now, OutcomeCounts::default()));
+        }
+        let Some((_, counts)) = self.buckets.back_mut() else {
+            return;
+        };
+        counts.requests += 1;
+        match outcome {
+            RequestOutcome::Success => {}
+            RequestOutcome::ServerError => counts.server_errors += 1,
+            RequestOutcome::Timeout => counts.timeouts += 1,
+        }
+    }
+
+    /// counts of the requests in the window ending now
+    pub fn counts(&mut self, now: Instant) -> OutcomeCounts {
+        self.expire(now);
+        let mut counts = OutcomeCounts::default();
+        for (_, bucket) in &self.buckets {
+            counts.add(bucket);
+        }
+        counts
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self
+            .buckets
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) >= self.length)
+        {
+            self.buckets.pop_front();
+        }
+    }
+}
+
 // when a backend has been removed from configuration and the last connection to
 // it has stopped, it will be dropped, so we can notify that the backend server
 // can be safely stopped
@@ -170,15 +490,34 @@ impl std::ops::Drop for Backend {
             backend_id: Some(self.backend_id.clone()),
             address: Some(self.address.into()),
             cluster_id: None,
+            alert: None,
+            value: None,
         });
     }
 }
 
+/// a backend removed from the configuration or drained while sessions were still using it
+#[derive(Debug)]
+struct RemovedBackend {
+    cluster_id: ClusterId,
+    backend: Weak<RefCell<Backend>>,
+    removed_at: Instant,
+    /// open connections in the last report
+    reported_connections: usize,
+    /// the backend was drained and stays in the configuration, so it is not
+    /// dropped when its last connection closes
+    drained: bool,
+}
+
 #[derive(Debug)]
 pub struct BackendMap {
     pub backends: HashMap<ClusterId, BackendList>,
     pub max_failures: usize,
     pub available: bool,
+    /// removed or drained backends that still have open connections
+    removed: Vec<RemovedBackend>,
+    /// signs the sticky session cookies and checks the ones sent by the clients
+    pub sticky_signer: StickySigner,
 }
 
 impl Default for BackendMap {
@@ -193,6 +532,8 @@ impl BackendMap {
             backends: HashMap::new(),
             max_failures: 3,
             available: true,
+            removed: Vec::new(),
+            sticky_signer: StickySigner::default(),
         }
     }
 
@@ -210,16 +551,32 @@ impl BackendMap {
     }
 
     pub fn add_backend(&mut self, cluster_id: &str, backend: Backend) {
-        self.backends
-            .entry(cluster_id.to_string())
-            .or_default()
-            .add_backend(backend);
+        let address = backend.address;
+        let list = self.backends.entry(cluster_id.to_string()).or_default();
+        list.add_backend(backend);
+        let draining = list
+            .backends
+            .iter()
+            .filter(|backend| {
+                let backend = backend.borrow();
+                backend.address == address && backend.draining
+            })
+            .cloned()
+            .collect();
+        self.track_removed(cluster_id, draining, true);
     }
 
     // TODO: return <Result, BackendError>, log the error downstream
     pub fn remove_backend(&mut self, cluster_id: &str, backend_address: &SocketAddr) {
         if let Some(backends) = self.backends.get_mut(cluster_id) {
+            let removed = backends
+                .backends
+                .iter()
+                .filter(|backend| backend.borrow().address == *backend_address)
+                .cloned()
+                .collect();
             backends.remove_backend(backend_address);
+            self.track_removed(cluster_id, removed, false);
         } else {
             error!(
                 "Backend was already removed: cluster id {}, address {:?}",
@@ -228,6 +585,239 @@ impl BackendMap {
         }
     }
 
+    /// Take a backend out of the load balancing of its cluster, at all its addresses,
+    /// or put it back. The open connections of a drained backend are left to finish,
+    /// and reported as draining
+    pub fn drain_backend(
+        &mut self,
+        cluster_id: &str,
+        backend_id: &str,
+        draining: bool,
+    ) -> Result<(), BackendError> {
+        let backends: Vec<_> = self
+            .backends
+            .get(cluster_id)
+            .into_iter()
+            .flat_map(|list| list.backends.iter())
+            .filter(|backend| backend.borrow().backend_id == backend_id)
+            .cloned()
+            .collect();
+        if backends.is_empty() {
+            return Err(BackendError::NoBackendWithId {
+                cluster_id: cluster_id.to_owned(),
+                backend_id: backend_id.to_owned(),
+            });
+        }
+        for backend in &backends {
+            backend.borrow_mut().draining = draining;
+        }
+        if draining {
+            self.track_removed(cluster_id, backends, true);
+        }
+        Ok(())
+    }
+
+    /// swap the backend list of a cluster, keeping the connection and retry
+    /// state of backends that are still present
+    pub fn replace_backends(&mut self, cluster_id: &str, backends: Vec<Backend>) {
+        let list = self.get_or_create_backend_list_for_cluster(cluster_id);
+        let removed = list
+            .backends
+            .iter()
+            .filter(|old_backend| {
+                let old_backend = old_backend.borrow();
+                !backends.iter().any(|new_backend| {
+                    new_backend.address == old_backend.address
+                        && new_backend.backend_id == old_backend.backend_id
+                })
+            })
+            .cloned()
+            .collect();
+        list.replace_backends(backends);
+        self.track_removed(cluster_id, removed, false);
+    }
+
+    /// The backends of a removed cluster stay in the map, in case the cluster
+    /// comes back, but their remaining connections are reported as draining
+    pub fn track_removed_cluster(&mut self, cluster_id: &str) {
+        let backends = self
+            .backends
+            .get(cluster_id)
+            .map(|list| list.backends.clone())
+            .unwrap_or_default();
+        self.track_removed(cluster_id, backends, false);
+    }
+
+    fn track_removed(
+        &mut self,
+        cluster_id: &str,
+        backends: Vec<Rc<RefCell<Backend>>>,
+        drained: bool,
+    ) {
+        for backend in backends {
+            let connections = backend.borrow().active_connections;
+            let already_tracked = self
+                .removed
+                .iter()
+                .any(|removed| removed.backend.ptr_eq(&Rc::downgrade(&backend)));
+            if connections > 0 && !already_tracked {
+                self.removed.push(RemovedBackend {
+                    cluster_id: cluster_id.to_owned(),
+                    backend: Rc::downgrade(&backend),
+                    removed_at: Instant::now(),
+                    reported_connections: connections,
+                    drained,
+                });
+            }
+        }
+    }
+
+    /// forget the removed backends that were dropped or have no connection left,
+    /// and the drained ones that were undrained
+    fn prune_removed(&mut self) {
+        self.removed.retain(|removed| {
+            removed.backend.upgrade().is_some_and(|backend| {
+                let backend = backend.borrow();
+                backend.active_connections > 0 && (!removed.drained || backend.draining)
+            })
+        });
+    }
+
+    /// removed or drained backends that still have open connections, of one cluster or of all
+    pub fn draining_backends(&mut self, cluster_id: Option<&str>) -> Vec<DrainingBackend> {
+        self.prune_removed();
+        self.removed
+            .iter()
+            .filter(|removed| cluster_id.map_or(true, |id| removed.cluster_id == id))
+            .filter_map(|removed| {
+                let backend = removed.backend.upgrade()?;
+                let backend = backend.borrow();
+                Some(DrainingBackend {
+                    cluster_id: removed.cluster_id.clone(),
+                    backend_id: backend.backend_id.clone(),
+                    address: backend.address.into(),
+                    connections: backend.active_connections as u64,
+                    draining_for: removed.removed_at.elapsed().as_secs(),
+                })
+            })
+            .collect()
+    }
+
+    /// health of the backends of a cluster, as seen by this worker
+    pub fn backend_health(&self, cluster_id: &str) -> Vec<BackendHealth> {
+        self.backends
+            .get(cluster_id)
+            .map(|list| {
+                list.backends
+                    .iter()
+                    .map(|backend| backend.borrow().health())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Events for the removed or drained backends whose connection count changed
+    /// since the last report. A removed backend tells it has no connection left
+    /// when it is dropped, a drained one when its last connection is closed
+    pub fn draining_events(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        for removed in self.removed.iter_mut() {
+            let Some(backend) = removed.backend.upgrade() else {
+                continue;
+            };
+            let backend = backend.borrow();
+            if backend.active_connections == removed.reported_connections {
+                continue;
+            }
+            removed.reported_connections = backend.active_connections;
+            if backend.active_connections == 0 {
+                if removed.drained && backend.draining {
+                    events.push(Event {
+                        kind: EventKind::RemovedBackendHasNoConnections as i32,
+                        cluster_id: Some(removed.cluster_id.clone()),
+                        backend_id: Some(backend.backend_id.clone()),
+                        address: Some(backend.address.into()),
+                        alert: None,
+                        value: None,
+                    });
+                }
+                continue;
+            }
+            events.push(Event {
+                kind: EventKind::BackendDraining as i32,
+                cluster_id: Some(removed.cluster_id.clone()),
+                backend_id: Some(backend.backend_id.clone()),
+                address: Some(backend.address.into()),
+                alert: None,
+                value: Some(backend.active_connections as u64),
+            });
+        }
+        self.prune_removed();
+        events
+    }
+
+    /// Change the weight of a backend in place, keeping its connections and retry state
+    pub fn set_backend_weight(
+        &mut self,
+        cluster_id: &str,
+        backend_id: &str,
+        weight: i32,
+    ) -> Result<(), BackendError> {
+        let mut found = false;
+        for backend in self
+            .backends
+            .get(cluster_id)
+            .into_iter()
+            .flat_map(|list| list.backends.iter())
+        {
+            let mut backend = backend.borrow_mut();
+            if backend.backend_id == backend_id {
+                backend
+                    .load_balancing_parameters
+                    .get_or_insert_with(LoadBalancingParams::default)
+                    .weight = weight;
+                found = true;
+            }
+        }
+        if !found {
+            return Err(BackendError::NoBackendWithId {
+                cluster_id: cluster_id.to_owned(),
+                backend_id: backend_id.to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Take a backend out of load balancing while it fails its active health checks,
+    /// or put it back
+    pub fn set_backend_health(
+        &mut self,
+        cluster_id: &str,
+        backend_id: &str,
+        address: &SocketAddr,
+        healthy: bool,
+    ) -> Result<(), BackendError> {
+        let backend = self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|list| {
+                list.backends.iter().find(|backend| {
+                    let backend = backend.borrow();
+                    backend.backend_id == backend_id && backend.address == *address
+                })
+            })
+            .ok_or_else(|| BackendError::NoBackendWithId {
+                cluster_id: cluster_id.to_owned(),
+                backend_id: backend_id.to_owned(),
+            })?;
+        let mut backend = backend.borrow_mut();
+        if healthy && backend.failing_health_checks {
+            backend.start_slow_start();
+        }
+        backend.failing_health_checks = !healthy;
+        Ok(())
+    }
+
     // TODO: return <Result, BackendError>, log the error downstream
     pub fn close_backend_connection(&mut self, cluster_id: &str, addr: &SocketAddr) {
         if let Some(cluster_backends) = self.backends.get_mut(cluster_id) {
@@ -244,9 +834,24 @@ impl BackendMap {
             .unwrap_or(false)
     }
 
+    /// Select a backend of the cluster and connect to it.
+    /// The client address is used as source address by transparent clusters.
     pub fn backend_from_cluster_id(
         &mut self,
         cluster_id: &str,
+        client_address: Option<SocketAddr>,
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), BackendError> {
+        self.backend_from_hash_value(cluster_id, client_address, None)
+    }
+
+    /// Select a backend of the cluster and connect to it, like `backend_from_cluster_id`.
+    /// Clusters with a consistent hash choose it from the hash of the value, or of the
+    /// client IP if the request does not have one
+    pub fn backend_from_hash_value(
+        &mut self,
+        cluster_id: &str,
+        client_address: Option<SocketAddr>,
+        hash_value: Option<&[u8]>,
     ) -> Result<(Rc<RefCell<Backend>>, TcpStream), BackendError> {
         let cluster_backends = self
             .backends
@@ -258,23 +863,41 @@ impl BackendMap {
             return Err(BackendError::NoBackendForCluster(cluster_id.to_owned()));
         }
 
-        let next_backend = match cluster_backends.next_available_backend() {
-            Some(nb) => nb,
-            None => {
-                if self.available {
-                    self.available = false;
-
-                    push_event(Event {
-                        kind: EventKind::NoAvailableBackends as i32,
-                        cluster_id: Some(cluster_id.to_owned()),
-                        backend_id: None,
-                        address: None,
-                    });
+        let sticky_key = cluster_backends.sticky_key(client_address);
+        let sticky_backend = sticky_key
+            .as_deref()
+            .and_then(|key| cluster_backends.find_in_sticky_table(key));
+
+        if let Some(backend) = &sticky_backend {
+            incr!(
+                "backend.selection.sticky_table",
+                Some(cluster_id),
+                Some(&backend.borrow().backend_id)
+            );
+        }
+
+        let hash = cluster_backends.request_hash(client_address, hash_value);
+        let next_backend =
+            match sticky_backend.or_else(|| cluster_backends.select_backend(cluster_id, hash)) {
+                Some(nb) => nb,
+                None => {
+                    if self.available {
+                        self.available = false;
+
+                        push_event(Event {
+                            kind: EventKind::NoAvailableBackends as i32,
+                            cluster_id: Some(cluster_id.to_owned()),
+                            backend_id: None,
+                            address: None,
+                            alert: None,
+                            value: None,
+                        });
+                    }
+                    return Err(BackendError::NoBackendForCluster(cluster_id.to_owned()));
                 }
-                return Err(BackendError::NoBackendForCluster(cluster_id.to_owned()));
-            }
-        };
+            };
 
+        let (source_address, transparent) = cluster_backends.connection_source(client_address);
         let mut borrowed_backend = next_backend.borrow_mut();
 
         debug!(
@@ -287,40 +910,122 @@ impl BackendMap {
             )
         );
 
-        let tcp_stream = borrowed_backend.try_connect().map_err(|backend_error| {
-            BackendError::ConnectionFailures {
-                cluster_id: cluster_id.to_owned(),
-                backend_address: borrowed_backend.address,
-                failures: borrowed_backend.failures,
-                error: backend_error.to_string(),
-            }
-        })?;
+        let tcp_stream = borrowed_backend
+            .try_connect(source_address, transparent)
+            .map_err(|backend_error| match backend_error {
+                BackendError::FdExhausted(_) => backend_error,
+                _ => BackendError::ConnectionFailures {
+                    cluster_id: cluster_id.to_owned(),
+                    backend_address: borrowed_backend.address,
+                    failures: borrowed_backend.failures,
+                    error: backend_error.to_string(),
+                },
+            })?;
+        mark_connection(&tcp_stream, cluster_backends.dscp);
         self.available = true;
 
+        if let Some(key) = sticky_key {
+            cluster_backends.record_sticky_entry(cluster_id, key, &borrowed_backend.backend_id);
+        }
+
         Ok((next_backend.clone(), tcp_stream))
     }
 
+    /// connect to a backend chosen by its id, for the requests pinned to it. The
+    /// load balancing, the health checks and the outlier ejections are bypassed,
+    /// so that a backend can be debugged even if it is taken out of the rotation
+    pub fn backend_from_id(
+        &mut self,
+        cluster_id: &str,
+        backend_id: &str,
+        client_address: Option<SocketAddr>,
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), BackendError> {
+        let no_backend = || BackendError::NoBackendWithId {
+            cluster_id: cluster_id.to_owned(),
+            backend_id: backend_id.to_owned(),
+        };
+        let cluster_backends = self.backends.get_mut(cluster_id).ok_or_else(no_backend)?;
+        let backend = cluster_backends
+            .backends
+            .iter()
+            .find(|backend| backend.borrow().backend_id == backend_id)
+            .cloned()
+            .ok_or_else(no_backend)?;
+
+        let (source_address, transparent) = cluster_backends.connection_source(client_address);
+        let mut borrowed_backend = backend.borrow_mut();
+        debug!(
+            "Connecting {} -> {:?} pinned",
+            cluster_id, borrowed_backend.address
+        );
+
+        let tcp_stream = borrowed_backend
+            .try_connect(source_address, transparent)
+            .map_err(|backend_error| match backend_error {
+                BackendError::FdExhausted(_) | BackendError::Status(_) => backend_error,
+                _ => BackendError::ConnectionFailures {
+                    cluster_id: cluster_id.to_owned(),
+                    backend_address: borrowed_backend.address,
+                    failures: borrowed_backend.failures,
+                    error: backend_error.to_string(),
+                },
+            })?;
+        mark_connection(&tcp_stream, cluster_backends.dscp);
+        incr!(
+            "backend.selection.pinned",
+            Some(cluster_id),
+            Some(backend_id)
+        );
+
+        Ok((backend.clone(), tcp_stream))
+    }
+
     pub fn backend_from_sticky_session(
         &mut self,
         cluster_id: &str,
         sticky_session: &str,
+        client_address: Option<SocketAddr>,
+        hash_value: Option<&[u8]>,
     ) -> Result<(Rc<RefCell<Backend>>, TcpStream), BackendError> {
+        let Some(sticky_session) = self.sticky_signer.verify(cluster_id, sticky_session) else {
+            debug!(
+                "invalid signature on sticky_session {} for cluster {}",
+                sticky_session, cluster_id
+            );
+            incr!("sticky_session.invalid_signature");
+            return self.backend_from_hash_value(cluster_id, client_address, hash_value);
+        };
+
         let sticky_conn = self
             .backends
             .get_mut(cluster_id)
-            .and_then(|cluster_backends| cluster_backends.find_sticky(sticky_session))
-            .map(|backend| {
+            .and_then(|cluster_backends| {
+                let source = cluster_backends.connection_source(client_address);
+                let dscp = cluster_backends.dscp;
+                cluster_backends
+                    .find_sticky(sticky_session)
+                    .map(|backend| (backend, source, dscp))
+            })
+            .map(|(backend, (source_address, transparent), dscp)| {
                 let mut borrowed = backend.borrow_mut();
-                let conn = borrowed.try_connect();
-
-                conn.map(|tcp_stream| (backend.clone(), tcp_stream))
-                    .map_err(|e| {
-                        error!(
-                            "could not connect {} to {:?} using session {} ({} failures)",
-                            cluster_id, borrowed.address, sticky_session, borrowed.failures
-                        );
-                        e
-                    })
+                let conn = borrowed.try_connect(source_address, transparent);
+
+                conn.map(|tcp_stream| {
+                    mark_connection(&tcp_stream, dscp);
+                    incr!(
+                        "backend.selection.sticky_session",
+                        Some(cluster_id),
+                        Some(&borrowed.backend_id)
+                    );
+                    (backend.clone(), tcp_stream)
+                })
+                .map_err(|e| {
+                    error!(
+                        "could not connect {} to {:?} using session {} ({} failures)",
+                        cluster_id, borrowed.address, sticky_session, borrowed.failures
+                    );
+                    e
+                })
             });
 
         match sticky_conn {
@@ -330,7 +1035,7 @@ impl BackendMap {
                     "Couldn't find a backend corresponding to sticky_session {} for cluster {}",
                     sticky_session, cluster_id
                 );
-                self.backend_from_cluster_id(cluster_id)
+                self.backend_from_hash_value(cluster_id, client_address, hash_value)
             }
         }
     }
@@ -340,11 +1045,87 @@ impl BackendMap {
         cluster_id: &str,
         lb_algo: LoadBalancingAlgorithms,
         metric: Option<LoadMetric>,
+        hash_key: Option<HashKey>,
     ) {
         // The cluster can be created before the backends were registered because of the async config messages.
         // So when we set the load balancing policy, we have to create the backend list if if it doesn't exist yet.
         let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
-        cluster_backends.set_load_balancing_policy(lb_algo, metric);
+        cluster_backends.set_load_balancing_policy(lb_algo, metric, hash_key);
+    }
+
+    /// the header or cookie of the requests the cluster hashes to choose their backend
+    pub fn request_hash_key(&self, cluster_id: &str) -> Option<&HashKey> {
+        self.backends
+            .get(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.hash_key.as_ref())
+            .filter(|hash_key| hash_key.kind != HashKeyKind::SourceIp as i32)
+    }
+
+    pub fn set_source_address_for_cluster(
+        &mut self,
+        cluster_id: &str,
+        source_address: Option<IpAddr>,
+        transparent: bool,
+    ) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        cluster_backends.source_address = source_address;
+        cluster_backends.transparent = transparent;
+    }
+
+    pub fn set_dscp_for_cluster(&mut self, cluster_id: &str, dscp: Option<u8>, on_clients: bool) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        cluster_backends.dscp = dscp;
+        cluster_backends.dscp_on_clients = on_clients;
+    }
+
+    pub fn set_outlier_detection_for_cluster(
+        &mut self,
+        cluster_id: &str,
+        outlier_detection: Option<OutlierDetection>,
+    ) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        cluster_backends.set_outlier_detection(outlier_detection);
+    }
+
+    /// eject and reinstate the backends of the clusters with outlier detection,
+    /// returns the events describing the changes
+    pub fn detect_outliers(&mut self) -> Vec<Event> {
+        let now = Instant::now();
+        let mut events = Vec::new();
+        for (cluster_id, cluster_backends) in self.backends.iter_mut() {
+            events.extend(cluster_backends.detect_outliers(cluster_id, now));
+        }
+        events
+    }
+
+    /// DSCP value the sessions of the cluster should set on their client socket
+    pub fn client_dscp(&self, cluster_id: &str) -> Option<u8> {
+        self.backends
+            .get(cluster_id)
+            .filter(|cluster_backends| cluster_backends.dscp_on_clients)
+            .and_then(|cluster_backends| cluster_backends.dscp)
+    }
+
+    pub fn set_sticky_table_for_cluster(&mut self, cluster_id: &str, sticky_table: bool) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        match (sticky_table, &cluster_backends.sticky_table) {
+            (true, None) => cluster_backends.sticky_table = Some(HashMap::new()),
+            (false, _) => cluster_backends.sticky_table = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Record a choice made by another worker, without sharing it again
+    pub fn set_sticky_entry(&mut self, cluster_id: &str, key: &str, backend_id: &str) {
+        if let Some(table) = self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.sticky_table.as_mut())
+        {
+            if table.len() < MAX_STICKY_ENTRIES || table.contains_key(key) {
+                table.insert(key.to_owned(), backend_id.to_owned());
+            }
+        }
     }
 
     pub fn get_or_create_backend_list_for_cluster(&mut self, cluster_id: &str) -> &mut BackendList {
@@ -357,6 +1138,20 @@ pub struct BackendList {
     pub backends: Vec<Rc<RefCell<Backend>>>,
     pub next_id: u32,
     pub load_balancing: Box<dyn LoadBalancingAlgorithm>,
+    /// local address used to connect to the backends
+    pub source_address: Option<IpAddr>,
+    /// connect to the backends from the address of the client
+    pub transparent: bool,
+    /// backend id by client IP, shared with the other workers. None if disabled
+    pub sticky_table: Option<HashMap<String, String>>,
+    /// DSCP value of the packets sent to the backends
+    pub dscp: Option<u8>,
+    /// also mark the packets sent to the clients with the DSCP value
+    pub dscp_on_clients: bool,
+    /// eject the backends with more errors or timeouts than the others. None if disabled
+    pub outlier_detection: Option<OutlierDetection>,
+    /// what the requests are hashed on, for the consistent hash. None with other algorithms
+    pub hash_key: Option<HashKey>,
 }
 
 impl Default for BackendList {
@@ -371,6 +1166,13 @@ impl BackendList {
             backends: Vec::new(),
             next_id: 0,
             load_balancing: Box::new(Random),
+            source_address: None,
+            transparent: false,
+            sticky_table: None,
+            dscp: None,
+            dscp_on_clients: false,
+            outlier_detection: None,
+            hash_key: None,
         }
     }
 
@@ -379,14 +1181,15 @@ impl BackendList {
     ) -> BackendList {
         let mut list = BackendList::new();
         for backend in backend_vec {
-            let backend = Backend::new(
+            let mut new_backend = Backend::new(
                 &backend.backend_id,
                 backend.address,
                 backend.sticky_id.clone(),
                 backend.load_balancing_parameters.clone(),
                 backend.backup,
             );
-            list.add_backend(backend);
+            new_backend.draining = backend.draining.unwrap_or(false);
+            list.add_backend(new_backend);
         }
 
         list
@@ -397,6 +1200,9 @@ impl BackendList {
             b.borrow().address == backend.address && b.borrow().backend_id == backend.backend_id
         }) {
             None => {
+                let mut backend = backend;
+                backend.outcomes = self.outcome_window();
+                backend.start_slow_start();
                 let backend = Rc::new(RefCell::new(backend));
                 self.backends.push(backend);
                 self.next_id += 1;
@@ -409,6 +1215,8 @@ impl BackendList {
                 b.load_balancing_parameters
                     .clone_from(&backend.load_balancing_parameters);
                 b.backup = backend.backup;
+                // only a drain request undrains a backend
+                b.draining |= backend.draining;
             }
         }
     }
@@ -416,6 +1224,24 @@ impl BackendList {
     pub fn remove_backend(&mut self, backend_address: &SocketAddr) {
         self.backends
             .retain(|backend| &backend.borrow().address != backend_address);
+        self.prune_sticky_table();
+    }
+
+    /// removes the backends absent from the new list, adds the new ones
+    /// and updates the configuration of the others
+    pub fn replace_backends(&mut self, backends: Vec<Backend>) {
+        self.backends.retain(|old_backend| {
+            let old_backend = old_backend.borrow();
+            backends.iter().any(|new_backend| {
+                new_backend.address == old_backend.address
+                    && new_backend.backend_id == old_backend.backend_id
+            })
+        });
+
+        for backend in backends {
+            self.add_backend(backend);
+        }
+        self.prune_sticky_table();
     }
 
     pub fn has_backend(&self, backend_address: &SocketAddr) -> bool {
@@ -433,11 +1259,75 @@ impl BackendList {
             .find(|backend| backend.borrow().address == *backend_address)
     }
 
-    pub fn find_sticky(&mut self, sticky_session: &str) -> Option<&mut Rc<RefCell<Backend>>> {
-        self.backends
-            .iter_mut()
-            .find(|b| b.borrow().sticky_id.as_deref() == Some(sticky_session))
-            .and_then(|b| if b.borrow().can_open() { Some(b) } else { None })
+    /// source address of a new connection to a backend, and wether it is transparent
+    pub fn connection_source(&self, client_address: Option<SocketAddr>) -> (Option<IpAddr>, bool) {
+        match (self.transparent, client_address) {
+            (true, Some(client_address)) => (Some(client_address.ip()), true),
+            _ => (self.source_address, false),
+        }
+    }
+
+    /// key of the client in the sticky table, if the cluster has one
+    pub fn sticky_key(&self, client_address: Option<SocketAddr>) -> Option<String> {
+        match (&self.sticky_table, client_address) {
+            (Some(_), Some(client_address)) => Some(client_address.ip().to_string()),
+            _ => None,
+        }
+    }
+
+    /// the backend recorded for this client, if it can still be used
+    pub fn find_in_sticky_table(&self, key: &str) -> Option<Rc<RefCell<Backend>>> {
+        let backend_id = self.sticky_table.as_ref()?.get(key)?;
+        self.backends
+            .iter()
+            .find(|backend| {
+                let backend = backend.borrow();
+                &backend.backend_id == backend_id && backend.can_open()
+            })
+            .cloned()
+    }
+
+    /// remember the backend chosen for a client, and share it with the other
+    /// workers through the main process when it changed
+    pub fn record_sticky_entry(&mut self, cluster_id: &str, key: String, backend_id: &str) {
+        let Some(table) = self.sticky_table.as_mut() else {
+            return;
+        };
+        if table.get(&key).map(String::as_str) == Some(backend_id) {
+            return;
+        }
+        if table.len() >= MAX_STICKY_ENTRIES && !table.contains_key(&key) {
+            debug!(
+                "sticky table of cluster {} is full, not recording client {}",
+                cluster_id, key
+            );
+            return;
+        }
+        table.insert(key.clone(), backend_id.to_owned());
+        push_sticky_entry(StickyEntry {
+            cluster_id: cluster_id.to_owned(),
+            key,
+            backend_id: backend_id.to_owned(),
+        });
+    }
+
+    /// forget the clients of the backends that are not in the list anymore
+    fn prune_sticky_table(&mut self) {
+        let backends = &self.backends;
+        if let Some(table) = self.sticky_table.as_mut() {
+            table.retain(|_, backend_id| {
+                backends
+                    .iter()
+                    .any(|backend| &backend.borrow().backend_id == backend_id)
+            });
+        }
+    }
+
+    pub fn find_sticky(&mut self, sticky_session: &str) -> Option<&mut Rc<RefCell<Backend>>> {
+        self.backends
+            .iter_mut()
+            .find(|b| b.borrow().sticky_id.as_deref() == Some(sticky_session))
+            .and_then(|b| if b.borrow().can_open() { Some(b) } else { None })
     }
 
     pub fn available_backends(&mut self, backup: bool) -> Vec<Rc<RefCell<Backend>>> {
@@ -452,24 +1342,255 @@ impl BackendList {
     }
 
     pub fn next_available_backend(&mut self) -> Option<Rc<RefCell<Backend>>> {
-        let mut backends = self.available_backends(false);
+        self.choose_backend(None, &mut Vec::new())
+    }
 
-        if backends.is_empty() {
-            backends = self.available_backends(true);
+    pub fn next_backend_for_hash(&mut self, hash: u64) -> Option<Rc<RefCell<Backend>>> {
+        self.choose_backend(Some(hash), &mut Vec::new())
+    }
+
+    /// Choose a backend with the load balancing, from the hash of the request if the
+    /// cluster has one, and count the backends left out. Why each of them was left out
+    /// is only counted when no backend could be chosen, to keep this path cheap
+    pub fn select_backend(
+        &mut self,
+        cluster_id: &str,
+        hash: Option<u64>,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let mut skipped = Vec::new();
+        let selected = self.choose_backend(hash, &mut skipped);
+        match &selected {
+            Some(backend) => {
+                incr!(
+                    "backend.selection.load_balanced",
+                    Some(cluster_id),
+                    Some(&backend.borrow().backend_id)
+                );
+                if !skipped.is_empty() {
+                    count!(
+                        "backend.selection.skipped",
+                        skipped.len() as i64,
+                        Some(cluster_id),
+                        None
+                    );
+                }
+            }
+            None => {
+                for (backend, reason) in skipped {
+                    incr!(
+                        reason.metric_key(),
+                        Some(cluster_id),
+                        Some(&backend.borrow().backend_id)
+                    );
+                }
+            }
         }
+        selected
+    }
 
+    /// Choose among the available backends, or the available backups if none is.
+    /// The backends left out are pushed to `skipped`, with the reason
+    fn choose_backend(
+        &mut self,
+        hash: Option<u64>,
+        skipped: &mut Vec<(Rc<RefCell<Backend>>, SkipReason)>,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let mut backends = Vec::new();
+        let mut backups = Vec::new();
+        for backend in &self.backends {
+            let borrowed = backend.borrow();
+            match borrowed.unavailability() {
+                Some(reason) => skipped.push((backend.clone(), reason)),
+                None if borrowed.backup => backups.push(backend.clone()),
+                None => backends.push(backend.clone()),
+            }
+        }
+        if backends.is_empty() {
+            backends = backups;
+        } else {
+            skipped.extend(
+                backups
+                    .into_iter()
+                    .map(|backup| (backup, SkipReason::Backup)),
+            );
+        }
         if backends.is_empty() {
             return None;
         }
 
-        self.load_balancing.next_available_backend(&mut backends)
+        let weighs_backends = self.load_balancing.weighs_backends();
+        let selected = match hash {
+            Some(hash) => self
+                .load_balancing
+                .next_backend_for_hash(&mut backends, hash),
+            None => {
+                if !weighs_backends {
+                    backends = skip_warming_backends(backends, Instant::now(), skipped);
+                }
+                self.load_balancing.next_available_backend(&mut backends)
+            }
+        };
+
+        if weighs_backends {
+            skipped.extend(
+                backends
+                    .into_iter()
+                    .filter(|backend| {
+                        backend.borrow().weight() == 0
+                            && !selected
+                                .as_ref()
+                                .is_some_and(|selected| Rc::ptr_eq(selected, backend))
+                    })
+                    .map(|backend| (backend, SkipReason::WeightZero)),
+            );
+        }
+        selected
+    }
+
+    /// hash of the value of the request, or of the client IP, if the cluster
+    /// chooses its backends with a consistent hash
+    pub fn request_hash(
+        &self,
+        client_address: Option<SocketAddr>,
+        value: Option<&[u8]>,
+    ) -> Option<u64> {
+        self.hash_key.as_ref()?;
+        match (value, client_address) {
+            (Some(value), _) => Some(hash_value(value)),
+            (None, Some(client_address)) => {
+                Some(hash_value(client_address.ip().to_string().as_bytes()))
+            }
+            (None, None) => None,
+        }
+    }
+
+    /// Start or stop counting the outcomes of the requests of the backends. Disabling
+    /// outlier detection reinstates the ejected backends
+    pub fn set_outlier_detection(&mut self, outlier_detection: Option<OutlierDetection>) {
+        let window_changed = self.outlier_detection.as_ref().map(|config| config.window)
+            != outlier_detection.as_ref().map(|config| config.window);
+        self.outlier_detection = outlier_detection;
+        let outcome_window = self.outcome_window();
+        for backend in &self.backends {
+            let mut backend = backend.borrow_mut();
+            if window_changed {
+                backend.outcomes.clone_from(&outcome_window);
+            }
+            if self.outlier_detection.is_none() {
+                backend.ejected_until = None;
+            }
+        }
+    }
+
+    fn outcome_window(&self) -> Option<OutcomeWindow> {
+        self.outlier_detection
+            .as_ref()
+            .map(|config| OutcomeWindow::new(Duration::from_secs(config.window.into())))
+    }
+
+    /// Reinstate the backends whose ejection is over, then eject the backends whose
+    /// ratio of 5xx responses or of timeouts exceeds the ratio of the cluster by the
+    /// configured threshold, worst first, while the maximum ejection percentage allows it
+    pub fn detect_outliers(&mut self, cluster_id: &str, now: Instant) -> Vec<Event> {
+        let Some(config) = self.outlier_detection.clone() else {
+            return Vec::new();
+        };
+
+        let mut events = Vec::new();
+        let mut ejected = 0;
+        for backend in &self.backends {
+            let mut backend = backend.borrow_mut();
+            match backend.ejected_until {
+                Some(until) if until <= now => {
+                    backend.ejected_until = None;
+                    backend.start_slow_start();
+                    info!(
+                        "outlier detection reinstated backend {} of cluster {}",
+                        backend.backend_id, cluster_id
+                    );
+                    events.push(outlier_event(
+                        EventKind::BackendReinstated,
+                        cluster_id,
+                        &backend,
+                        None,
+                    ));
+                }
+                Some(_) => ejected += 1,
+                None => {}
+            }
+        }
+
+        let mut cluster_counts = OutcomeCounts::default();
+        let mut candidates = Vec::new();
+        for backend in &self.backends {
+            let mut borrowed = backend.borrow_mut();
+            if borrowed.ejected_until.is_some() {
+                continue;
+            }
+            let Some(counts) = borrowed
+                .outcomes
+                .as_mut()
+                .map(|outcomes| outcomes.counts(now))
+            else {
+                continue;
+            };
+            cluster_counts.add(&counts);
+            if counts.requests >= u64::from(config.min_requests) {
+                candidates.push((backend.clone(), counts));
+            }
+        }
+
+        let max_ejected = self.backends.len() * config.max_ejection_percent.min(100) as usize / 100;
+        let error_limit = cluster_counts.error_ratio() + f64::from(config.error_threshold);
+        let timeout_limit = cluster_counts.timeout_ratio() + f64::from(config.timeout_threshold);
+        candidates.sort_by(|(_, a), (_, b)| b.failure_ratio().total_cmp(&a.failure_ratio()));
+
+        for (backend, counts) in candidates {
+            if ejected >= max_ejected {
+                break;
+            }
+            if counts.error_ratio() <= error_limit && counts.timeout_ratio() <= timeout_limit {
+                continue;
+            }
+            let mut backend = backend.borrow_mut();
+            backend.ejected_until = Some(now + Duration::from_secs(config.ejection_time.into()));
+            if let Some(outcomes) = backend.outcomes.as_mut() {
+                outcomes.clear();
+            }
+            ejected += 1;
+
+            warn!(
+                "outlier detection ejected backend {} of cluster {} for {}s: {:.1}% errors, {:.1}% timeouts (cluster: {:.1}% errors, {:.1}% timeouts)",
+                backend.backend_id,
+                cluster_id,
+                config.ejection_time,
+                counts.error_ratio(),
+                counts.timeout_ratio(),
+                cluster_counts.error_ratio(),
+                cluster_counts.timeout_ratio(),
+            );
+            incr!(
+                "outlier_detection.ejections",
+                Some(cluster_id),
+                Some(&backend.backend_id)
+            );
+            events.push(outlier_event(
+                EventKind::BackendEjected,
+                cluster_id,
+                &backend,
+                Some(counts.failure_ratio().round() as u64),
+            ));
+        }
+        events
     }
 
     pub fn set_load_balancing_policy(
         &mut self,
         load_balancing_policy: LoadBalancingAlgorithms,
         metric: Option<LoadMetric>,
+        hash_key: Option<HashKey>,
     ) {
+        self.hash_key = None;
         match load_balancing_policy {
             LoadBalancingAlgorithms::RoundRobin => {
                 self.load_balancing = Box::new(RoundRobin::new())
@@ -485,6 +1606,66 @@ impl BackendList {
                     metric: metric.unwrap_or(LoadMetric::Connections),
                 })
             }
+            // the peak EWMA is a shorthand, the metric of the cluster does not apply
+            LoadBalancingAlgorithms::PeakEwma => {
+                self.load_balancing = Box::new(PowerOfTwo {
+                    metric: LoadMetric::ResponseTime,
+                })
+            }
+            LoadBalancingAlgorithms::ConsistentHash => {
+                self.load_balancing = Box::new(ConsistentHash::new());
+                self.hash_key = Some(hash_key.unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// Skip each backend that warms up with the probability of the traffic it should not
+/// get yet, for the algorithms that do not weigh the backends. The skipped backends
+/// are pushed to `skipped`. All the backends are kept if they are all skipped
+fn skip_warming_backends(
+    backends: Vec<Rc<RefCell<Backend>>>,
+    now: Instant,
+    skipped: &mut Vec<(Rc<RefCell<Backend>>, SkipReason)>,
+) -> Vec<Rc<RefCell<Backend>>> {
+    let mut rng = thread_rng();
+    let (kept, warming): (Vec<_>, Vec<_>) = backends.iter().cloned().partition(|backend| {
+        let factor = backend.borrow_mut().slow_start_factor(now);
+        factor >= 1.0 || rng.gen_bool(factor)
+    });
+    if kept.is_empty() {
+        return backends;
+    }
+    skipped.extend(
+        warming
+            .into_iter()
+            .map(|backend| (backend, SkipReason::SlowStart)),
+    );
+    kept
+}
+
+fn outlier_event(
+    kind: EventKind,
+    cluster_id: &str,
+    backend: &Backend,
+    failure_ratio: Option<u64>,
+) -> Event {
+    Event {
+        kind: kind as i32,
+        cluster_id: Some(cluster_id.to_owned()),
+        backend_id: Some(backend.backend_id.clone()),
+        address: Some(backend.address.into()),
+        alert: None,
+        value: failure_ratio,
+    }
+}
+
+/// Set the DSCP value of the cluster on a new backend connection. A failure is only
+/// logged: the connection is still usable, without the priority
+fn mark_connection(tcp_stream: &TcpStream, dscp: Option<u8>) {
+    if let Some(dscp) = dscp {
+        if let Err(e) = set_dscp(tcp_stream, dscp) {
+            error!("could not set DSCP {} on backend connection: {}", dscp, e);
         }
     }
 }
@@ -531,10 +1712,119 @@ mod backends_test {
             ),
         );
 
-        assert!(backend_map.backend_from_cluster_id(cluster_id).is_ok());
+        assert!(backend_map
+            .backend_from_cluster_id(cluster_id, None)
+            .is_ok());
         sender.send(()).unwrap();
     }
 
+    #[test]
+    fn it_should_connect_from_the_source_address_of_the_cluster() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let source_address: IpAddr = "127.0.0.2".parse().unwrap();
+
+        backend_map.set_source_address_for_cluster(cluster_id, Some(source_address), false);
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new(
+                &format!("{cluster_id}-1"),
+                listener.local_addr().unwrap(),
+                None,
+                None,
+                None,
+            ),
+        );
+
+        let (_backend, stream) = backend_map
+            .backend_from_cluster_id(cluster_id, None)
+            .unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), source_address);
+
+        let (_client, peer_address) = listener.accept().unwrap();
+        assert_eq!(peer_address.ip(), source_address);
+    }
+
+    #[test]
+    fn it_should_connect_from_the_client_address_in_transparent_mode() {
+        let mut backends_list = BackendList::new();
+        let client_address: SocketAddr = "192.0.2.1:54321".parse().unwrap();
+        let source_address: IpAddr = "127.0.0.2".parse().unwrap();
+
+        backends_list.source_address = Some(source_address);
+        assert_eq!(
+            (Some(source_address), false),
+            backends_list.connection_source(Some(client_address))
+        );
+
+        backends_list.transparent = true;
+        assert_eq!(
+            (Some(client_address.ip()), true),
+            backends_list.connection_source(Some(client_address))
+        );
+        assert_eq!(
+            (Some(source_address), false),
+            backends_list.connection_source(None)
+        );
+    }
+
+    #[test]
+    fn it_should_keep_clients_on_the_backend_of_the_sticky_table() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        let client_address: SocketAddr = "192.0.2.1:54321".parse().unwrap();
+
+        let listener_1 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener_2 = TcpListener::bind("127.0.0.1:0").unwrap();
+        backend_map.set_sticky_table_for_cluster(cluster_id, true);
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new(
+                "backend-1",
+                listener_1.local_addr().unwrap(),
+                None,
+                None,
+                None,
+            ),
+        );
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new(
+                "backend-2",
+                listener_2.local_addr().unwrap(),
+                None,
+                None,
+                None,
+            ),
+        );
+
+        // recorded by another worker
+        backend_map.set_sticky_entry(cluster_id, "192.0.2.1", "backend-2");
+        for _ in 0..5 {
+            let (backend, _stream) = backend_map
+                .backend_from_cluster_id(cluster_id, Some(client_address))
+                .unwrap();
+            assert_eq!(backend.borrow().backend_id, "backend-2");
+        }
+
+        // the clients of a removed backend are load balanced again
+        backend_map.remove_backend(cluster_id, &listener_2.local_addr().unwrap());
+        let (backend, _stream) = backend_map
+            .backend_from_cluster_id(cluster_id, Some(client_address))
+            .unwrap();
+        assert_eq!(backend.borrow().backend_id, "backend-1");
+        assert_eq!(
+            backend_map.backends[cluster_id]
+                .sticky_table
+                .as_ref()
+                .and_then(|table| table.get("192.0.2.1"))
+                .map(String::as_str),
+            Some("backend-1")
+        );
+    }
+
     #[test]
     fn it_should_not_retrieve_a_backend_from_cluster_id_when_backend_has_not_been_recorded() {
         let mut backend_map = BackendMap::new();
@@ -545,15 +1835,241 @@ mod backends_test {
         );
 
         assert!(backend_map
-            .backend_from_cluster_id(cluster_not_recorded)
+            .backend_from_cluster_id(cluster_not_recorded, None)
             .is_err());
     }
 
+    #[test]
+    fn it_should_report_the_health_of_the_backends() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        for i in 1..=3 {
+            backend_map.add_backend(
+                cluster_id,
+                Backend::new(
+                    &format!("{cluster_id}-{i}"),
+                    format!("127.0.0.1:900{i}").parse().unwrap(),
+                    None,
+                    None,
+                    None,
+                ),
+            );
+        }
+        {
+            let list = &backend_map.backends[cluster_id];
+            let mut connected = list.backends[0].borrow_mut();
+            connected.connect_successes = 2;
+            connected.connect_failures = 1;
+            connected.active_connections = 1;
+            connected.set_connection_time(Duration::from_millis(3));
+
+            list.backends[1].borrow_mut().ejected_until = Some(Instant::now());
+            list.backends[2].borrow_mut().set_closing();
+        }
+
+        let health = backend_map.backend_health(cluster_id);
+        assert_eq!(health.len(), 3);
+        assert_eq!(health[0].status(), BackendHealthStatus::Healthy);
+        assert_eq!(health[0].connections, 1);
+        assert_eq!(health[0].connect_successes, 2);
+        assert_eq!(health[0].connect_failures, 1);
+        assert!(health[0].connection_time.is_some());
+        assert_eq!(health[1].status(), BackendHealthStatus::Ejected);
+        assert_eq!(health[1].connection_time, None);
+        assert_eq!(health[2].status(), BackendHealthStatus::Closing);
+
+        assert!(backend_map.backend_health("unknown").is_empty());
+    }
+
+    #[test]
+    fn it_should_skip_the_backends_failing_health_checks() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        let address: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new("mycluster-1", address, None, None, None),
+        );
+
+        backend_map
+            .set_backend_health(cluster_id, "mycluster-1", &address, false)
+            .expect("the backend should exist");
+        assert_eq!(
+            backend_map.backend_health(cluster_id)[0].status(),
+            BackendHealthStatus::Unhealthy
+        );
+        assert!(backend_map
+            .get_or_create_backend_list_for_cluster(cluster_id)
+            .next_available_backend()
+            .is_none());
+
+        backend_map
+            .set_backend_health(cluster_id, "mycluster-1", &address, true)
+            .expect("the backend should exist");
+        assert!(backend_map
+            .get_or_create_backend_list_for_cluster(cluster_id)
+            .next_available_backend()
+            .is_some());
+
+        assert!(backend_map
+            .set_backend_health(cluster_id, "mycluster-2", &address, false)
+            .is_err());
+    }
+
+    #[test]
+    fn it_should_tell_why_backends_were_left_out_of_the_load_balancing() {
+        let mut backend_list = BackendList::new();
+        let params = |weight| {
+            Some(LoadBalancingParams {
+                weight,
+                slow_start_seconds: None,
+            })
+        };
+        let backends = [
+            Backend::new("up", "127.0.0.1:9001".parse().unwrap(), None, None, None),
+            Backend::new(
+                "backup",
+                "127.0.0.1:9002".parse().unwrap(),
+                None,
+                None,
+                Some(true),
+            ),
+            Backend::new(
+                "unhealthy",
+                "127.0.0.1:9003".parse().unwrap(),
+                None,
+                None,
+                None,
+            ),
+            Backend::new(
+                "ejected",
+                "127.0.0.1:9004".parse().unwrap(),
+                None,
+                None,
+                None,
+            ),
+            Backend::new(
+                "zero",
+                "127.0.0.1:9005".parse().unwrap(),
+                None,
+                params(0),
+                None,
+            ),
+        ];
+        for backend in backends {
+            backend_list.add_backend(backend);
+        }
+        backend_list.backends[2].borrow_mut().failing_health_checks = true;
+        backend_list.backends[3].borrow_mut().ejected_until = Some(Instant::now());
+
+        let mut skipped = Vec::new();
+        let selected = backend_list
+            .choose_backend(None, &mut skipped)
+            .expect("a backend should be available");
+        assert_eq!(selected.borrow().backend_id, "up");
+        let reasons: Vec<(String, SkipReason)> = skipped
+            .iter()
+            .map(|(backend, reason)| (backend.borrow().backend_id.clone(), *reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                ("unhealthy".to_owned(), SkipReason::FailingHealthChecks),
+                ("ejected".to_owned(), SkipReason::Ejected),
+                ("backup".to_owned(), SkipReason::Backup),
+                ("zero".to_owned(), SkipReason::WeightZero),
+            ]
+        );
+
+        backend_list.backends[0].borrow_mut().set_closing();
+        let mut skipped = Vec::new();
+        backend_list.choose_backend(None, &mut skipped);
+        assert_eq!(skipped[0].1, SkipReason::Draining);
+    }
+
+    #[test]
+    fn it_should_ramp_up_the_traffic_of_a_backend_with_slow_start() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        let address: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new(
+                "mycluster-1",
+                address,
+                None,
+                Some(LoadBalancingParams {
+                    weight: 100,
+                    slow_start_seconds: Some(10),
+                }),
+                None,
+            ),
+        );
+        let backend = backend_map.backends[cluster_id].backends[0].clone();
+
+        let start = backend
+            .borrow()
+            .warming_since
+            .expect("a new backend should warm up");
+        assert_eq!(backend.borrow_mut().effective_weight(start), 10);
+        let halfway = backend
+            .borrow_mut()
+            .slow_start_factor(start + Duration::from_secs(5));
+        assert!((halfway - 0.55).abs() < 1e-9);
+        assert_eq!(
+            backend
+                .borrow_mut()
+                .slow_start_factor(start + Duration::from_secs(10)),
+            1.0
+        );
+        assert!(backend.borrow().warming_since.is_none());
+
+        // a backend back from failing health checks warms up again
+        for healthy in [false, true] {
+            backend_map
+                .set_backend_health(cluster_id, "mycluster-1", &address, healthy)
+                .expect("the backend should exist");
+        }
+        assert!(backend.borrow().warming_since.is_some());
+    }
+
+    #[test]
+    fn it_should_connect_to_a_pinned_backend_failing_health_checks() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        let address: SocketAddr = "127.0.0.1:1237".parse().unwrap();
+        let (sender, receiver) = channel();
+        run_mock_tcp_server("127.0.0.1:1237", receiver);
+
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new("mycluster-1", address, None, None, None),
+        );
+        backend_map
+            .set_backend_health(cluster_id, "mycluster-1", &address, false)
+            .expect("the backend should exist");
+
+        let (backend, _) = backend_map
+            .backend_from_id(cluster_id, "mycluster-1", None)
+            .expect("the pinned backend should be reached");
+        assert_eq!(backend.borrow().backend_id, "mycluster-1");
+
+        assert!(matches!(
+            backend_map.backend_from_id(cluster_id, "mycluster-2", None),
+            Err(BackendError::NoBackendWithId { .. })
+        ));
+        assert!(matches!(
+            backend_map.backend_from_id("other", "mycluster-1", None),
+            Err(BackendError::NoBackendWithId { .. })
+        ));
+        sender.send(()).unwrap();
+    }
+
     #[test]
     fn it_should_not_retrieve_a_backend_from_cluster_id_when_backend_list_is_empty() {
         let mut backend_map = BackendMap::new();
 
-        assert!(backend_map.backend_from_cluster_id("dumb").is_err());
+        assert!(backend_map.backend_from_cluster_id("dumb", None).is_err());
     }
 
     #[test]
@@ -599,7 +2115,7 @@ mod backends_test {
         );
 
         assert!(backend_map
-            .backend_from_sticky_session(cluster_id, sticky_session)
+            .backend_from_sticky_session(cluster_id, sticky_session, None, None)
             .is_ok());
         sender.send(()).unwrap();
     }
@@ -612,7 +2128,7 @@ mod backends_test {
         let sticky_session = "test";
 
         assert!(backend_map
-            .backend_from_sticky_session(cluster_id, sticky_session)
+            .backend_from_sticky_session(cluster_id, sticky_session, None, None)
             .is_err());
     }
 
@@ -623,7 +2139,140 @@ mod backends_test {
         let sticky_session = "test";
 
         assert!(backend_map
-            .backend_from_sticky_session(mycluster_not_recorded, sticky_session)
+            .backend_from_sticky_session(mycluster_not_recorded, sticky_session, None, None)
+            .is_err());
+    }
+
+    #[test]
+    fn it_should_set_the_weight_of_a_backend_in_place() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new(
+                "myback",
+                "127.0.0.1:80".parse().unwrap(),
+                None,
+                Some(LoadBalancingParams {
+                    weight: 100,
+                    slow_start_seconds: None,
+                }),
+                None,
+            ),
+        );
+        let backend = backend_map.backends[cluster_id].backends[0].clone();
+        backend.borrow_mut().failures = 2;
+
+        assert!(backend_map
+            .set_backend_weight(cluster_id, "myback", 10)
+            .is_ok());
+        assert_eq!(
+            backend.borrow().load_balancing_parameters,
+            Some(LoadBalancingParams {
+                weight: 10,
+                slow_start_seconds: None,
+            })
+        );
+        assert_eq!(backend.borrow().failures, 2);
+
+        assert!(backend_map
+            .set_backend_weight(cluster_id, "otherback", 10)
+            .is_err());
+    }
+
+    #[test]
+    fn it_should_report_the_connections_of_removed_backends() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        let address = "127.0.0.1:80".parse().unwrap();
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new("myback", address, None, None, None),
+        );
+
+        // a session keeps the backend while its connection is open
+        let session_backend = backend_map.backends[cluster_id].backends[0].clone();
+        session_backend.borrow_mut().inc_connections();
+        session_backend.borrow_mut().inc_connections();
+
+        backend_map.remove_backend(cluster_id, &address);
+        let draining = backend_map.draining_backends(Some(cluster_id));
+        assert_eq!(draining.len(), 1);
+        assert_eq!(draining[0].connections, 2);
+        assert!(backend_map.draining_events().is_empty());
+
+        session_backend.borrow_mut().dec_connections();
+        let events = backend_map.draining_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].value, Some(1));
+
+        drop(session_backend);
+        assert!(backend_map.draining_backends(None).is_empty());
+    }
+
+    #[test]
+    fn it_should_drain_every_address_of_a_backend() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+        for address in ["127.0.0.1:80", "127.0.0.1:81"] {
+            backend_map.add_backend(
+                cluster_id,
+                Backend::new("myback", address.parse().unwrap(), None, None, None),
+            );
+        }
+        backend_map.add_backend(
+            cluster_id,
+            Backend::new(
+                "otherback",
+                "127.0.0.1:82".parse().unwrap(),
+                None,
+                None,
+                None,
+            ),
+        );
+        let session_backend = backend_map.backends[cluster_id].backends[1].clone();
+        session_backend.borrow_mut().inc_connections();
+
+        backend_map
+            .drain_backend(cluster_id, "myback", true)
+            .expect("the backend should exist");
+        // the drained backend stays in the map, out of load balancing
+        let unavailability = |backend_map: &BackendMap| -> Vec<Option<SkipReason>> {
+            backend_map.backends[cluster_id]
+                .backends
+                .iter()
+                .map(|backend| backend.borrow().unavailability())
+                .collect()
+        };
+        assert_eq!(
+            unavailability(&backend_map),
+            vec![Some(SkipReason::Draining), Some(SkipReason::Draining), None]
+        );
+
+        let draining = backend_map.draining_backends(Some(cluster_id));
+        assert_eq!(draining.len(), 1);
+        assert_eq!(
+            draining[0].address,
+            "127.0.0.1:81".parse::<SocketAddr>().unwrap().into()
+        );
+
+        // the drained backend is not dropped, it tells its last connection closed
+        session_backend.borrow_mut().dec_connections();
+        let events = backend_map.draining_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].kind,
+            EventKind::RemovedBackendHasNoConnections as i32
+        );
+        assert!(backend_map.draining_backends(None).is_empty());
+
+        backend_map
+            .drain_backend(cluster_id, "myback", false)
+            .expect("the backend should exist");
+        assert_eq!(unavailability(&backend_map), vec![None, None, None]);
+
+        assert!(backend_map
+            .drain_backend(cluster_id, "unknown", true)
             .is_err());
     }
 
@@ -665,4 +2314,98 @@ mod backends_test {
 
         assert_eq!(1, backends_list.backends.len());
     }
+
+    #[test]
+    fn it_should_keep_existing_backends_when_replacing_the_list() {
+        let mut backends_list = BackendList::new();
+        for i in 0..3 {
+            backends_list.add_backend(Backend::new(
+                &format!("myback-{i}"),
+                format!("127.0.0.1:{}", 8000 + i).parse().unwrap(),
+                None,
+                None,
+                None,
+            ));
+        }
+        backends_list.backends[2].borrow_mut().active_connections = 5;
+
+        backends_list.replace_backends(vec![
+            Backend::new(
+                "myback-2",
+                "127.0.0.1:8002".parse().unwrap(),
+                None,
+                None,
+                None,
+            ),
+            Backend::new(
+                "myback-3",
+                "127.0.0.1:8003".parse().unwrap(),
+                None,
+                None,
+                None,
+            ),
+        ]);
+
+        assert_eq!(2, backends_list.backends.len());
+        assert_eq!(5, backends_list.backends[0].borrow().active_connections);
+        assert!(!backends_list.has_backend(&"127.0.0.1:8000".parse().unwrap()));
+        assert!(backends_list.has_backend(&"127.0.0.1:8003".parse().unwrap()));
+    }
+
+    #[test]
+    fn it_should_eject_the_backends_failing_more_than_the_cluster() {
+        let mut backends_list = BackendList::new();
+        backends_list.set_outlier_detection(Some(OutlierDetection {
+            max_ejection_percent: 25,
+            ..Default::default()
+        }));
+        for i in 0..4 {
+            backends_list.add_backend(Backend::new(
+                &format!("myback-{i}"),
+                format!("127.0.0.1:{}", 8000 + i).parse().unwrap(),
+                None,
+                None,
+                None,
+            ));
+        }
+
+        let record = |index: usize, requests: usize, outcome: RequestOutcome| {
+            let mut backend = backends_list.backends[index].borrow_mut();
+            for _ in 0..requests {
+                backend.record_outcome(outcome);
+            }
+        };
+        // the first two backends fail all their requests, the third one too
+        // but it did not receive enough requests to be evaluated
+        record(0, 20, RequestOutcome::ServerError);
+        record(1, 30, RequestOutcome::Timeout);
+        record(2, 10, RequestOutcome::ServerError);
+        record(3, 100, RequestOutcome::Success);
+
+        let now = Instant::now();
+        let events = backends_list.detect_outliers("mycluster", now);
+        assert_eq!(1, events.len());
+        assert_eq!(EventKind::BackendEjected, events[0].kind());
+        assert_eq!(Some(100), events[0].value);
+
+        // only one backend out of four can be ejected
+        let ejected: Vec<bool> = backends_list
+            .backends
+            .iter()
+            .map(|backend| backend.borrow().ejected_until.is_some())
+            .collect();
+        assert_eq!(vec![true, false, false, false], ejected);
+        assert_eq!(3, backends_list.available_backends(false).len());
+
+        let events = backends_list.detect_outliers(
+            "mycluster",
+            now + Duration::from_secs(OutlierDetection::default().ejection_time.into()),
+        );
+        assert_eq!(1, events.len());
+        assert_eq!(EventKind::BackendReinstated, events[0].kind());
+        assert!(backends_list
+            .backends
+            .iter()
+            .all(|backend| backend.borrow().can_open()));
+    }
 }
@@ -0,0 +1,167 @@
+//! Debug capture of the metadata of the requests of a cluster
+//!
+//! While a capture runs, the worker records the timings, status, chosen backend
+//! and an allowlist of headers of each request of the cluster, never the bodies.
+//! The records are kept until the main process collects them.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use sozu_command::proto::command::{CapturedRequest, CapturedRequests, StartCapture};
+
+/// requests recorded by each worker if the capture does not set a maximum
+const DEFAULT_MAX_REQUESTS: usize = 10_000;
+
+thread_local! {
+  pub static CAPTURES: RefCell<Captures> = RefCell::new(Captures::default());
+}
+
+#[derive(Debug)]
+struct Capture {
+    deadline: Instant,
+    /// lowercased header names
+    headers: Vec<String>,
+    max_requests: usize,
+    captured: CapturedRequests,
+}
+
+#[derive(Debug, Default)]
+pub struct Captures {
+    /// cluster id -> capture
+    captures: HashMap<String, Capture>,
+}
+
+impl Captures {
+    /// start recording the requests of a cluster, dropping its previous capture
+    pub fn start(&mut self, start: &StartCapture, now: Instant) {
+        let capture = Capture {
+            deadline: now + Duration::from_secs(start.duration as u64),
+            headers: start
+                .headers
+                .iter()
+                .map(|header| header.to_ascii_lowercase())
+                .collect(),
+            max_requests: start
+                .max_requests
+                .map(|max| max as usize)
+                .unwrap_or(DEFAULT_MAX_REQUESTS),
+            captured: CapturedRequests::default(),
+        };
+        self.captures.insert(start.cluster_id.clone(), capture);
+    }
+
+    /// stop the capture of a cluster and return what it recorded
+    pub fn collect(&mut self, cluster_id: &str) -> CapturedRequests {
+        self.captures
+            .remove(cluster_id)
+            .map(|capture| capture.captured)
+            .unwrap_or_default()
+    }
+
+    /// lowercased names of the headers recorded by the running captures,
+    /// empty if no capture runs
+    pub fn captured_headers(&self) -> Vec<String> {
+        if self.captures.is_empty() {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let mut headers = Vec::new();
+        for capture in self.captures.values() {
+            if capture.deadline > now {
+                headers.extend(capture.headers.iter().cloned());
+            }
+        }
+        headers
+    }
+
+    /// record a request if a capture of its cluster is running.
+    /// `request` is only built in that case, and its headers are
+    /// filtered to the allowlist of the capture
+    pub fn record<F>(&mut self, cluster_id: &str, now: Instant, request: F)
+    where
+        F: FnOnce() -> CapturedRequest,
+    {
+        let capture = match self.captures.get_mut(cluster_id) {
+            Some(capture) if capture.deadline > now => capture,
+            _ => return,
+        };
+        if capture.captured.requests.len() >= capture.max_requests {
+            capture.captured.truncated = true;
+            return;
+        }
+
+        let mut request = request();
+        request
+            .request_headers
+            .retain(|name, _| capture.headers.contains(name));
+        request
+            .response_headers
+            .retain(|name, _| capture.headers.contains(name));
+        capture.captured.requests.push(request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(request_id: &str) -> CapturedRequest {
+        CapturedRequest {
+            request_id: request_id.to_owned(),
+            request_headers: [
+                ("user-agent".to_owned(), "curl".to_owned()),
+                ("authorization".to_owned(), "secret".to_owned()),
+            ]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn capture_until_deadline() {
+        let mut captures = Captures::default();
+        let start = Instant::now();
+        assert!(captures.captured_headers().is_empty());
+
+        captures.start(
+            &StartCapture {
+                cluster_id: "cluster_1".to_owned(),
+                duration: 10,
+                headers: vec!["User-Agent".to_owned()],
+                max_requests: Some(2),
+            },
+            start,
+        );
+        assert_eq!(captures.captured_headers(), vec!["user-agent".to_owned()]);
+
+        captures.record("cluster_1", start, || request("a"));
+        captures.record("cluster_2", start, || request("other cluster"));
+        captures.record("cluster_1", start + Duration::from_secs(1), || request("b"));
+        captures.record("cluster_1", start + Duration::from_secs(2), || request("c"));
+        captures.record("cluster_1", start + Duration::from_secs(11), || {
+            panic!("the capture is over")
+        });
+
+        let captured = captures.collect("cluster_1");
+        assert!(captured.truncated);
+        assert_eq!(
+            captured
+                .requests
+                .iter()
+                .map(|request| request.request_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            captured.requests[0].request_headers,
+            [("user-agent".to_owned(), "curl".to_owned())].into()
+        );
+
+        // collecting stops the capture
+        assert_eq!(captures.collect("cluster_1"), CapturedRequests::default());
+        assert!(captures.captured_headers().is_empty());
+    }
+}
@@ -0,0 +1,356 @@
+//! Run a Sōzu worker inside another program
+//!
+//! The `sozu` binary forks and re-executes itself to start its workers.
+//! Programs that want to ship a single binary with a proxy inside can use
+//! [`EmbeddedWorker`] instead: it runs the worker event loop in a thread of
+//! the current process, and exposes the same channel the main process uses,
+//! to send requests and receive events.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use sozu_command_lib::{
+//!     config::ListenerBuilder,
+//!     proto::command::{
+//!         request::RequestType, ActivateListener, InitialState, ListenerType, ServerConfig,
+//!         SocketAddress,
+//!     },
+//!     scm_socket::Listeners,
+//! };
+//! use sozu_lib::embedded::EmbeddedWorker;
+//!
+//! let mut worker = EmbeddedWorker::start(
+//!     "EMBEDDED",
+//!     ServerConfig::default(),
+//!     InitialState::default(),
+//!     &Listeners::default(),
+//! )
+//! .expect("could not start the worker");
+//!
+//! let address = SocketAddress::new_v4(127, 0, 0, 1, 8080);
+//! let listener = ListenerBuilder::new_http(address.clone())
+//!     .to_http(None)
+//!     .expect("could not build the listener");
+//!
+//! worker
+//!     .execute(RequestType::AddHttpListener(listener).into())
+//!     .expect("could not add the listener");
+//! worker
+//!     .execute(
+//!         RequestType::ActivateListener(ActivateListener {
+//!             address,
+//!             proxy: ListenerType::Http.into(),
+//!             from_scm: false,
+//!         })
+//!         .into(),
+//!     )
+//!     .expect("could not activate the listener");
+//!
+//! for event in worker
+//!     .poll_events(Duration::from_millis(100))
+//!     .expect("could not read events") {
+//!     println!("event: {event:?}");
+//! }
+//!
+//! worker.stop(false).expect("could not stop the worker");
+//! ```
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    os::unix::io::IntoRawFd,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use mio::net::UnixStream;
+
+use sozu_command::{
+    channel::{Channel, ChannelError},
+    logging::{generation_tag, setup_logging, AccessLogFormat},
+    proto::command::{
+        request::RequestType, response_content::ContentType, Event, HardStop, InitialState,
+        Request, ResponseStatus, ServerConfig, SoftStop, WorkerRequest, WorkerResponse,
+    },
+    scm_socket::{Listeners, ScmSocket, ScmSocketError},
+};
+
+use crate::{
+    metrics::{self, MetricError},
+    server::{Server, ServerError},
+};
+
+/// id of the worker responses that carry an event
+const EVENT_ID: &str = "EVENT";
+
+#[derive(thiserror::Error, Debug)]
+pub enum EmbeddedError {
+    #[error("could not create the command channel: {0}")]
+    CreateChannel(ChannelError),
+    #[error("could not create the unix socket pair: {0}")]
+    CreateUnixStream(std::io::Error),
+    #[error("could not create scm socket: {0}")]
+    CreateScmSocket(ScmSocketError),
+    #[error("could not send listeners to the worker: {0}")]
+    SendListeners(ScmSocketError),
+    #[error("could not spawn the worker thread: {0}")]
+    SpawnThread(std::io::Error),
+    #[error("could not write the request on the channel: {0}")]
+    WriteRequest(ChannelError),
+    #[error("could not read the response from the channel: {0}")]
+    ReadResponse(ChannelError),
+    #[error("the worker rejected request {id}: {message}")]
+    RequestFailure { id: String, message: String },
+    #[error("the worker could not be created: {0}")]
+    NewServer(ServerError),
+    #[error("the worker thread panicked")]
+    ThreadPanicked,
+}
+
+/// Set up logging for a worker, using the log settings of its configuration.
+///
+/// Do not try to log anything before this, or the logger will panic.
+pub fn setup_worker_logging(config: &ServerConfig, worker_id: &str) {
+    let access_log_format = AccessLogFormat::from(&config.access_log_format());
+
+    setup_logging(
+        &config.log_target,
+        config.log_colored,
+        config.access_logs_target.as_deref(),
+        Some(access_log_format),
+        config.access_logs_template.as_deref(),
+        Some(config.log_colored),
+        &config.log_level,
+        &generation_tag(worker_id, config.generation),
+    );
+}
+
+/// Set up the metrics drain of the current thread, if the configuration has
+/// a metrics section
+pub fn setup_worker_metrics(config: &ServerConfig, worker_id: &str) -> Result<(), MetricError> {
+    if let Some(metrics) = config.metrics.as_ref() {
+        let address = metrics.address.parse::<SocketAddr>().map_err(|error| {
+            MetricError::WrongUdpAddress {
+                address: metrics.address.to_owned(),
+                error: error.to_string(),
+            }
+        })?;
+        metrics::setup(
+            &address,
+            worker_id,
+            config.generation,
+            metrics.tagged_metrics,
+            metrics.prefix.clone(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Handle to a Sōzu worker running in a thread of the current process
+pub struct EmbeddedWorker {
+    pub name: String,
+    channel: Channel<WorkerRequest, WorkerResponse>,
+    /// to pass listen sockets back and forth with the worker
+    pub scm: ScmSocket,
+    events: VecDeque<Event>,
+    request_counter: usize,
+    thread: JoinHandle<Result<(), EmbeddedError>>,
+}
+
+impl EmbeddedWorker {
+    /// Start a worker in a new thread.
+    ///
+    /// The worker applies the initial state, then takes the listen sockets
+    /// from `listeners` when activating listeners with `from_scm: true`.
+    /// Logging and metrics are set up from `config`, within the thread.
+    pub fn start<S: Into<String>>(
+        name: S,
+        config: ServerConfig,
+        initial_state: InitialState,
+        listeners: &Listeners,
+    ) -> Result<Self, EmbeddedError> {
+        let name = name.into();
+
+        let (scm_to_worker, scm_to_embedder) =
+            UnixStream::pair().map_err(EmbeddedError::CreateUnixStream)?;
+        let scm =
+            ScmSocket::new(scm_to_worker.into_raw_fd()).map_err(EmbeddedError::CreateScmSocket)?;
+        let worker_scm = ScmSocket::new(scm_to_embedder.into_raw_fd())
+            .map_err(EmbeddedError::CreateScmSocket)?;
+
+        // the worker blocks on receiving listeners when it is created
+        scm.send_listeners(listeners)
+            .map_err(EmbeddedError::SendListeners)?;
+
+        let (channel, worker_channel) =
+            Channel::generate(config.command_buffer_size, config.max_command_buffer_size)
+                .map_err(EmbeddedError::CreateChannel)?;
+
+        let worker_id = name.clone();
+        let thread = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                setup_worker_logging(&config, &worker_id);
+                if let Err(e) = setup_worker_metrics(&config, &worker_id) {
+                    error!("could not setup metrics on worker {}: {}", worker_id, e);
+                }
+
+                let mut server = Server::try_new_from_config(
+                    worker_channel,
+                    worker_scm,
+                    config,
+                    initial_state,
+                    false,
+                )
+                .map_err(EmbeddedError::NewServer)?;
+
+                info!("starting event loop of embedded worker {}", worker_id);
+                server.run();
+                info!("ending event loop of embedded worker {}", worker_id);
+                Ok(())
+            })
+            .map_err(EmbeddedError::SpawnThread)?;
+
+        Ok(Self {
+            name,
+            channel,
+            scm,
+            events: VecDeque::new(),
+            request_counter: 0,
+            thread,
+        })
+    }
+
+    /// Send a request to the worker without waiting for its response.
+    /// Returns the id of the request.
+    pub fn send(&mut self, request: Request) -> Result<String, EmbeddedError> {
+        let id = format!("{}-{}", self.name, self.request_counter);
+        self.request_counter += 1;
+
+        self.channel
+            .write_message(&WorkerRequest {
+                id: id.clone(),
+                content: request,
+                sequence: None,
+            })
+            .map_err(EmbeddedError::WriteRequest)?;
+
+        Ok(id)
+    }
+
+    /// Send a request to the worker and wait for its final response.
+    ///
+    /// Events received in the meantime are kept, see [`Self::poll_events`].
+    pub fn execute(&mut self, request: Request) -> Result<WorkerResponse, EmbeddedError> {
+        let id = self.send(request)?;
+
+        loop {
+            let response = self.read_response()?;
+
+            if response.id != id || response.status == ResponseStatus::Processing as i32 {
+                continue;
+            }
+            if response.status == ResponseStatus::Failure as i32 {
+                return Err(EmbeddedError::RequestFailure {
+                    id,
+                    message: response.message,
+                });
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Return the events received so far.
+    ///
+    /// Reads pending messages from the worker until none arrives within `timeout`.
+    pub fn poll_events(&mut self, timeout: Duration) -> Result<Vec<Event>, EmbeddedError> {
+        loop {
+            match self.channel.read_message_blocking_timeout(Some(timeout)) {
+                Ok(response) => self.keep_event(response),
+                Err(ChannelError::TimeoutReached(_)) => break,
+                Err(e) => return Err(EmbeddedError::ReadResponse(e)),
+            }
+        }
+
+        Ok(self.events.drain(..).collect())
+    }
+
+    /// Stop the worker, and wait for its thread to end.
+    ///
+    /// A soft stop waits for the sessions to close, a hard stop closes them right away.
+    pub fn stop(mut self, hard: bool) -> Result<(), EmbeddedError> {
+        let request = if hard {
+            RequestType::HardStop(HardStop {})
+        } else {
+            RequestType::SoftStop(SoftStop {})
+        };
+        self.execute(request.into())?;
+
+        self.thread
+            .join()
+            .map_err(|_| EmbeddedError::ThreadPanicked)?
+    }
+
+    /// read one message from the worker, keeping it aside if it is an event
+    fn read_response(&mut self) -> Result<WorkerResponse, EmbeddedError> {
+        let response = self
+            .channel
+            .read_message()
+            .map_err(EmbeddedError::ReadResponse)?;
+        self.keep_event(response.clone());
+        Ok(response)
+    }
+
+    fn keep_event(&mut self, response: WorkerResponse) {
+        if response.id != EVENT_ID {
+            return;
+        }
+        if let Some(ContentType::Event(event)) = response.content.and_then(|c| c.content_type) {
+            self.events.push_back(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sozu_command::proto::command::Cluster;
+
+    #[test]
+    fn start_execute_and_stop() {
+        let mut worker = EmbeddedWorker::start(
+            "EMBEDDED",
+            ServerConfig {
+                log_level: "error".to_string(),
+                ..Default::default()
+            },
+            InitialState::default(),
+            &Listeners::default(),
+        )
+        .expect("could not start the embedded worker");
+
+        let response = worker
+            .execute(
+                RequestType::AddCluster(Cluster {
+                    cluster_id: "cluster_1".to_string(),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("could not add a cluster");
+        assert_eq!(response.status, ResponseStatus::Ok as i32);
+
+        let response = worker
+            .execute(RequestType::RemoveCluster("cluster_1".to_string()).into())
+            .expect("could not remove the cluster");
+        assert_eq!(response.status, ResponseStatus::Ok as i32);
+
+        assert!(worker
+            .poll_events(Duration::from_millis(10))
+            .expect("could not poll events")
+            .is_empty());
+
+        worker.stop(true).expect("could not stop the worker");
+    }
+}
@@ -2,7 +2,7 @@ use std::{
     cell::RefCell,
     collections::{hash_map::Entry, BTreeMap, HashMap},
     io::ErrorKind,
-    net::{Shutdown, SocketAddr},
+    net::{IpAddr, Shutdown, SocketAddr},
     os::unix::io::AsRawFd,
     rc::{Rc, Weak},
     str::from_utf8_unchecked,
@@ -19,15 +19,19 @@ use rusty_ulid::Ulid;
 use sozu_command::{
     logging::CachedTags,
     proto::command::{
-        request::RequestType, Cluster, HttpListenerConfig, ListenerType, RemoveListener,
-        RequestHttpFrontend, WorkerRequest, WorkerResponse,
+        request::RequestType, ClientCertificateHeaders, Cluster, CustomHttpAnswers,
+        DuplicateHeader, Http10Options, HttpListenerConfig, ListenerType, ProxyStatusHeader,
+        RemoveListener, RequestHttpFrontend, SetFaultInjection, SetLoadBalancing,
+        SetRequestPipeline, Timeouts, UpdateListenerAnswers, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
+    request::IpNetwork,
     response::HttpFrontend,
     state::ClusterId,
 };
 
 use crate::{
+    backend_pinning_trusted,
     backends::BackendMap,
     pool::Pool,
     protocol::{
@@ -39,9 +43,10 @@ use crate::{
         proxy_protocol::expect::ExpectProxyProtocol,
         Http, Pipe, SessionState,
     },
-    router::{Route, Router},
+    rate_limit::{RequestRateLimiter, CLIENT_RATE_LIMITS},
+    router::{ClientTls, FrontendOptions, RequestHead, RequestHeaders, Route, Router},
     server::{ListenToken, SessionManager},
-    socket::server_bind,
+    socket::{is_fd_exhaustion, server_bind},
     timer::TimeoutContainer,
     AcceptError, FrontendFromRequestError, L7ListenerHandler, L7Proxy, ListenerError,
     ListenerHandler, Protocol, ProxyConfiguration, ProxyError, ProxySession, SessionIsToBeClosed,
@@ -95,6 +100,7 @@ impl HttpSession {
         configured_frontend_timeout: Duration,
         configured_request_timeout: Duration,
         expect_proxy: bool,
+        detect_proxy: bool,
         listener: Rc<RefCell<HttpListener>>,
         pool: Weak<RefCell<Pool>>,
         proxy: Rc<RefCell<HttpProxy>>,
@@ -107,19 +113,20 @@ impl HttpSession {
         let request_id = Ulid::generate();
         let container_frontend_timeout = TimeoutContainer::new(configured_request_timeout, token);
 
-        let state = if expect_proxy {
+        let state = if expect_proxy || detect_proxy {
             trace!("starting in expect proxy state");
             gauge_add!("protocol.proxy.expect", 1);
 
-            HttpStateMachine::Expect(ExpectProxyProtocol::new(
-                container_frontend_timeout,
-                sock,
-                token,
-                request_id,
-            ))
+            let mut expect =
+                ExpectProxyProtocol::new(container_frontend_timeout, sock, token, request_id);
+            expect.detect = detect_proxy;
+            HttpStateMachine::Expect(expect)
         } else {
             gauge_add!("protocol.http", 1);
-            let session_address = sock.peer_addr().ok();
+            let session_address = sock
+                .peer_addr()
+                .ok()
+                .map(|address| listener.borrow().client_address(address));
 
             HttpStateMachine::Http(Http::new(
                 answers.clone(),
@@ -181,12 +188,30 @@ impl HttpSession {
         expect: ExpectProxyProtocol<TcpStream>,
     ) -> Option<HttpStateMachine> {
         debug!("switching to HTTP");
-        match expect
-            .addresses
-            .as_ref()
-            .map(|add| (add.destination(), add.source()))
-        {
-            Some((Some(public_address), Some(session_address))) => {
+        let addresses = if expect.header_absent {
+            let listener = self.listener.borrow();
+            let session_address = expect
+                .frontend
+                .peer_addr()
+                .ok()
+                .map(|address| listener.client_address(address));
+            Some((listener.public_address(), session_address))
+        } else {
+            match expect
+                .addresses
+                .as_ref()
+                .map(|add| (add.destination(), add.source()))
+            {
+                Some((Some(public_address), Some(session_address))) => Some((
+                    public_address,
+                    Some(self.listener.borrow().client_address(session_address)),
+                )),
+                _ => None,
+            }
+        };
+
+        match addresses {
+            Some((public_address, session_address)) => {
                 let mut http = Http::new(
                     self.answers.clone(),
                     self.configured_backend_timeout,
@@ -200,7 +225,7 @@ impl HttpSession {
                     Protocol::HTTP,
                     public_address,
                     expect.request_id,
-                    Some(session_address),
+                    session_address,
                     self.sticky_name.clone(),
                 )
                 .ok()?;
@@ -229,6 +254,7 @@ impl HttpSession {
         };
 
         let ws_context = http.websocket_context();
+        let websocket = http.context.websocket;
         let mut container_frontend_timeout = http.container_frontend_timeout;
         let mut container_backend_timeout = http.container_backend_timeout;
         container_frontend_timeout.reset();
@@ -261,6 +287,10 @@ impl HttpSession {
         pipe.frontend_readiness.event = http.frontend_readiness.event;
         pipe.backend_readiness.event = http.backend_readiness.event;
         pipe.set_back_token(back_token);
+        if websocket {
+            let idle_timeout = self.listener.borrow().config.websocket_idle_timeout;
+            pipe.enable_websocket(idle_timeout.map(|timeout| Duration::from_secs(timeout as u64)));
+        }
 
         gauge_add!("protocol.http", -1);
         gauge_add!("protocol.ws", 1);
@@ -305,6 +335,9 @@ impl ProxySession for HttpSession {
         }
 
         self.state.cancel_timeouts();
+        if let HttpStateMachine::WebSocket(pipe) = &mut self.state {
+            pipe.close_websocket();
+        }
 
         let front_socket = self.state.front_socket();
         if let Err(e) = front_socket.shutdown(Shutdown::Both) {
@@ -400,6 +433,9 @@ pub struct HttpListener {
     config: HttpListenerConfig,
     fronts: Router,
     listener: Option<MioTcpListener>,
+    rate_limiter: Option<RequestRateLimiter>,
+    /// networks of the clients allowed to pin their requests to a backend
+    backend_pinning_trusted: Vec<IpNetwork>,
     tags: BTreeMap<String, CachedTags>,
     token: Token,
 }
@@ -419,9 +455,28 @@ impl ListenerHandler for HttpListener {
             None => self.tags.remove(&key),
         };
     }
+
+    fn normalizes_ipv4_mapped(&self) -> bool {
+        self.config.normalize_ipv4_mapped
+    }
 }
 
 impl L7ListenerHandler for HttpListener {
+    fn check_request_rate(&mut self, client: IpAddr) -> Result<(), Duration> {
+        match self.rate_limiter.as_mut() {
+            Some(rate_limiter) => rate_limiter.check(client, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    fn get_backend_pinning_header(&self, client: IpAddr) -> Option<String> {
+        let backend_pinning = self.config.backend_pinning.as_ref()?;
+        self.backend_pinning_trusted
+            .iter()
+            .any(|network| network.contains(client))
+            .then(|| backend_pinning.header.to_owned())
+    }
+
     fn get_sticky_name(&self) -> &str {
         &self.config.sticky_name
     }
@@ -430,13 +485,37 @@ impl L7ListenerHandler for HttpListener {
         self.config.connect_timeout
     }
 
+    fn get_timeouts(&self) -> Timeouts {
+        self.config.timeouts()
+    }
+
+    fn get_proxy_status(&self) -> Option<ProxyStatusHeader> {
+        self.config
+            .proxy_status
+            .and_then(|header| ProxyStatusHeader::try_from(header).ok())
+    }
+
+    fn get_http10_options(&self) -> Http10Options {
+        self.config.http10.clone().unwrap_or_default()
+    }
+
+    fn get_duplicate_headers(&self) -> Vec<DuplicateHeader> {
+        self.config.duplicate_headers.clone()
+    }
+
+    fn get_client_certificate_headers(&self) -> Option<ClientCertificateHeaders> {
+        None
+    }
+
     // redundant, already called once in extract_route
     fn frontend_from_request(
         &self,
         host: &str,
         uri: &str,
         method: &Method,
-    ) -> Result<Route, FrontendFromRequestError> {
+        tls: Option<&ClientTls>,
+        headers: &dyn RequestHeaders,
+    ) -> Result<(Route, FrontendOptions), FrontendFromRequestError> {
         let start = Instant::now();
         let (remaining_input, (hostname, _)) = match hostname_and_port(host.as_bytes()) {
             Ok(tuple) => tuple,
@@ -465,10 +544,18 @@ impl L7ListenerHandler for HttpListener {
         */
         let host = unsafe { from_utf8_unchecked(hostname) };
 
-        let route = self.fronts.lookup(host, uri, method).map_err(|e| {
-            incr!("http.failed_backend_matching");
-            FrontendFromRequestError::NoClusterFound(e)
-        })?;
+        let rule = self
+            .fronts
+            .lookup_rule(
+                &RequestHead::new(host, uri, method)
+                    .with_tls(tls)
+                    .with_headers(headers),
+            )
+            .map_err(|e| {
+                incr!("http.failed_backend_matching");
+                FrontendFromRequestError::NoClusterFound(e)
+            })?;
+        let route = rule.route.clone();
 
         let now = Instant::now();
 
@@ -476,7 +563,7 @@ impl L7ListenerHandler for HttpListener {
             time!("frontend_matching_time", cluster, (now - start).as_millis());
         }
 
-        Ok(route)
+        Ok((route, rule.options()))
     }
 }
 
@@ -538,6 +625,23 @@ impl HttpProxy {
         Ok(())
     }
 
+    pub fn update_listener_answers(
+        &mut self,
+        update: UpdateListenerAnswers,
+    ) -> Result<(), ProxyError> {
+        let address = update.address.into();
+        let listener = self
+            .listeners
+            .values()
+            .find(|listener| listener.borrow().address == address)
+            .ok_or(ProxyError::NoListenerFound(address))?;
+
+        listener
+            .borrow_mut()
+            .update_answers(update.http_answers)
+            .map_err(ProxyError::UpdateAnswers)
+    }
+
     pub fn activate_listener(
         &self,
         addr: &SocketAddr,
@@ -572,6 +676,20 @@ impl HttpProxy {
             .collect()
     }
 
+    /// file descriptors of the listeners, which stay registered in this worker
+    pub fn listener_fds(&self) -> Vec<(SocketAddr, i32)> {
+        self.listeners
+            .values()
+            .filter_map(|listener| {
+                let owned = listener.borrow();
+                owned
+                    .listener
+                    .as_ref()
+                    .map(|listener| (owned.address, listener.as_raw_fd()))
+            })
+            .collect()
+    }
+
     pub fn give_back_listener(
         &mut self,
         address: SocketAddr,
@@ -609,8 +727,36 @@ impl HttpProxy {
         Ok(())
     }
 
+    pub fn set_request_pipeline(&mut self, set: SetRequestPipeline) -> Result<(), ProxyError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(ProxyError::NoClusterFound(set.cluster_id.clone()))?;
+        cluster.pipeline = set.pipeline;
+        Ok(())
+    }
+
+    pub fn set_load_balancing(&mut self, set: SetLoadBalancing) -> Result<(), ProxyError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(ProxyError::NoClusterFound(set.cluster_id.clone()))?;
+        set.apply(cluster);
+        Ok(())
+    }
+
+    pub fn set_fault_injection(&mut self, set: SetFaultInjection) -> Result<(), ProxyError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(ProxyError::NoClusterFound(set.cluster_id.clone()))?;
+        cluster.fault_injection = set.fault_injection;
+        Ok(())
+    }
+
     pub fn remove_cluster(&mut self, cluster_id: &str) -> Result<(), ProxyError> {
         self.clusters.remove(cluster_id);
+        CLIENT_RATE_LIMITS.with(|limits| limits.borrow_mut().remove_cluster(cluster_id));
 
         for listener in self.listeners.values() {
             listener
@@ -648,6 +794,8 @@ impl HttpProxy {
     }
 
     pub fn remove_http_frontend(&mut self, front: RequestHttpFrontend) -> Result<(), ProxyError> {
+        // the buckets of the frontend are keyed by its summary
+        let summary = front.to_string();
         let front = front.clone().to_frontend().map_err(|request_error| {
             ProxyError::WrongInputFrontend {
                 front,
@@ -668,6 +816,8 @@ impl HttpProxy {
             .remove_http_front(front)
             .map_err(ProxyError::RemoveFrontend)?;
 
+        CLIENT_RATE_LIMITS.with(|limits| limits.borrow_mut().remove_frontend(&summary));
+
         listener.set_tags(hostname, None);
         Ok(())
     }
@@ -721,6 +871,11 @@ impl HttpProxy {
 
 impl HttpListener {
     pub fn new(config: HttpListenerConfig, token: Token) -> Result<HttpListener, ListenerError> {
+        let rate_limiter = config
+            .request_rate_limit
+            .as_ref()
+            .map(RequestRateLimiter::new);
+        let backend_pinning_trusted = backend_pinning_trusted(config.backend_pinning.as_ref());
         Ok(HttpListener {
             active: false,
             address: config.address.clone().into(),
@@ -731,11 +886,35 @@ impl HttpListener {
             config,
             fronts: Router::new(),
             listener: None,
+            rate_limiter,
+            backend_pinning_trusted,
             tags: BTreeMap::new(),
             token,
         })
     }
 
+    /// the address the clients connect to, as seen by the backends
+    fn public_address(&self) -> SocketAddr {
+        match self.config.public_address.clone() {
+            Some(pub_addr) => pub_addr.into(),
+            None => self.config.address.clone().into(),
+        }
+    }
+
+    /// replace the answers that are set, applying them to the ongoing sessions too
+    pub fn update_answers(&mut self, http_answers: CustomHttpAnswers) -> Result<(), ListenerError> {
+        let mut new_answers = self.config.http_answers.clone().unwrap_or_default();
+        new_answers.merge(http_answers);
+        let new_answers = Some(new_answers);
+
+        self.answers
+            .borrow_mut()
+            .update_listener_answers(&new_answers)
+            .map_err(|(status, error)| ListenerError::TemplateParse(status, error))?;
+        self.config.http_answers = new_answers;
+        Ok(())
+    }
+
     pub fn activate(
         &mut self,
         registry: &Registry,
@@ -748,12 +927,12 @@ impl HttpListener {
 
         let mut listener = match tcp_listener {
             Some(tcp_listener) => tcp_listener,
-            None => {
-                server_bind(address).map_err(|server_bind_error| ListenerError::Activation {
+            None => server_bind(address, self.config.ipv6_only).map_err(|server_bind_error| {
+                ListenerError::Activation {
                     address,
                     error: server_bind_error.to_string(),
-                })?
-            }
+                }
+            })?,
         };
 
         registry
@@ -783,6 +962,7 @@ impl HttpListener {
             sock.accept()
                 .map_err(|e| match e.kind() {
                     ErrorKind::WouldBlock => AcceptError::WouldBlock,
+                    _ if is_fd_exhaustion(&e) => AcceptError::TooManyFiles,
                     _ => {
                         error!("accept() IO error: {:?}", e);
                         AcceptError::IoError
@@ -809,6 +989,18 @@ impl ProxyConfiguration for HttpProxy {
                 debug!("{} remove cluster {:?}", request_id, cluster_id);
                 self.remove_cluster(&cluster_id)
             }
+            Some(RequestType::SetRequestPipeline(set)) => {
+                debug!("{} set request pipeline {:?}", request_id, set);
+                self.set_request_pipeline(set)
+            }
+            Some(RequestType::SetLoadBalancing(set)) => {
+                debug!("{} set load balancing {:?}", request_id, set);
+                self.set_load_balancing(set)
+            }
+            Some(RequestType::SetFaultInjection(set)) => {
+                debug!("{} set fault injection {:?}", request_id, set);
+                self.set_fault_injection(set)
+            }
             Some(RequestType::AddHttpFrontend(front)) => {
                 debug!("{} add front {:?}", request_id, front);
                 self.add_http_frontend(front)
@@ -821,6 +1013,13 @@ impl ProxyConfiguration for HttpProxy {
                 debug!("removing HTTP listener at address {:?}", remove.address);
                 self.remove_listener(remove)
             }
+            Some(RequestType::UpdateListenerAnswers(update)) => {
+                debug!(
+                    "updating answers of HTTP listener at address {:?}",
+                    update.address
+                );
+                self.update_listener_answers(update)
+            }
             Some(RequestType::SoftStop(_)) => {
                 debug!("{} processing soft shutdown", request_id);
                 match self.soft_stop() {
@@ -861,7 +1060,8 @@ impl ProxyConfiguration for HttpProxy {
             }
             Err(proxy_error) => {
                 debug!("{} unsuccessful: {}", request_id, proxy_error);
-                WorkerResponse::error(request_id, proxy_error)
+                WorkerResponse::error(request_id, &proxy_error)
+                    .with_error(proxy_error.response_error())
             }
         }
     }
@@ -910,10 +1110,7 @@ impl ProxyConfiguration for HttpProxy {
             return Err(AcceptError::RegisterError);
         }
 
-        let public_address: SocketAddr = match owned.config.public_address.clone() {
-            Some(pub_addr) => pub_addr.into(),
-            None => owned.config.address.clone().into(),
-        };
+        let public_address = owned.public_address();
 
         let session = HttpSession::new(
             owned.answers.clone(),
@@ -922,6 +1119,7 @@ impl ProxyConfiguration for HttpProxy {
             Duration::from_secs(owned.config.front_timeout as u64),
             Duration::from_secs(owned.config.request_timeout as u64),
             owned.config.expect_proxy,
+            owned.config.detect_proxy,
             listener.clone(),
             Rc::downgrade(&self.pool),
             proxy,
@@ -1112,6 +1310,7 @@ mod tests {
             .write_message(&WorkerRequest {
                 id: String::from("ID_ABCD"),
                 content: RequestType::AddHttpFrontend(front).into(),
+                sequence: None,
             })
             .unwrap();
         let backend = Backend {
@@ -1121,11 +1320,14 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            expires_at: None,
+            draining: None,
         };
         command
             .write_message(&WorkerRequest {
                 id: String::from("ID_EFGH"),
                 content: RequestType::AddBackend(backend.to_add_backend()).into(),
+                sequence: None,
             })
             .unwrap();
 
@@ -1195,6 +1397,7 @@ mod tests {
             .write_message(&WorkerRequest {
                 id: String::from("ID_ABCD"),
                 content: RequestType::AddHttpFrontend(front).into(),
+                sequence: None,
             })
             .unwrap();
         let backend = Backend {
@@ -1204,11 +1407,14 @@ mod tests {
             cluster_id: String::from("cluster_1"),
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
+            expires_at: None,
+            draining: None,
         };
         command
             .write_message(&WorkerRequest {
                 id: String::from("ID_EFGH"),
                 content: RequestType::AddBackend(backend.to_add_backend()).into(),
+                sequence: None,
             })
             .unwrap();
 
@@ -1326,6 +1532,15 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some(cluster_id1),
                 tags: None,
+                expires_at: None,
+                client_tls_versions: vec![],
+                client_cipher_suites: vec![],
+                timeouts: None,
+                mirror: None,
+                split: vec![],
+                headers: vec![],
+                device: None,
+                rate_limit: None,
             })
             .expect("Could not add http frontend");
         fronts
@@ -1337,6 +1552,15 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some(cluster_id2),
                 tags: None,
+                expires_at: None,
+                client_tls_versions: vec![],
+                client_cipher_suites: vec![],
+                timeouts: None,
+                mirror: None,
+                split: vec![],
+                headers: vec![],
+                device: None,
+                rate_limit: None,
             })
             .expect("Could not add http frontend");
         fronts
@@ -1348,6 +1572,18 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some(cluster_id3),
                 tags: None,
+                expires_at: None,
+                client_tls_versions: vec![],
+                client_cipher_suites: vec![],
+                timeouts: Some(Timeouts {
+                    request_deadline: Some(5),
+                    ..Default::default()
+                }),
+                mirror: None,
+                split: vec![],
+                headers: vec![],
+                device: None,
+                rate_limit: None,
             })
             .expect("Could not add http frontend");
         fronts
@@ -1359,6 +1595,15 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some("cluster_1".to_owned()),
                 tags: None,
+                expires_at: None,
+                client_tls_versions: vec![],
+                client_cipher_suites: vec![],
+                timeouts: None,
+                mirror: None,
+                split: vec![],
+                headers: vec![],
+                device: None,
+                rate_limit: None,
             })
             .expect("Could not add http frontend");
 
@@ -1378,29 +1623,54 @@ mod tests {
             config: default_config,
             token: Token(0),
             active: true,
+            rate_limiter: None,
+            backend_pinning_trusted: Vec::new(),
             tags: BTreeMap::new(),
         };
 
-        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get);
-        let frontend2 = listener.frontend_from_request("lolcatho.st", "/test", &Method::Get);
-        let frontend3 = listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get);
-        let frontend4 = listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get);
-        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get);
+        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get, None, &());
+        let frontend2 =
+            listener.frontend_from_request("lolcatho.st", "/test", &Method::Get, None, &());
+        let frontend3 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get, None, &());
+        let frontend4 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get, None, &());
+        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get, None, &());
         assert_eq!(
             frontend1.expect("should find frontend"),
-            Route::ClusterId("cluster_1".to_string())
+            (
+                Route::ClusterId("cluster_1".to_string()),
+                FrontendOptions::default()
+            )
         );
         assert_eq!(
             frontend2.expect("should find frontend"),
-            Route::ClusterId("cluster_1".to_string())
+            (
+                Route::ClusterId("cluster_1".to_string()),
+                FrontendOptions::default()
+            )
         );
         assert_eq!(
             frontend3.expect("should find frontend"),
-            Route::ClusterId("cluster_2".to_string())
+            (
+                Route::ClusterId("cluster_2".to_string()),
+                FrontendOptions::default()
+            )
         );
         assert_eq!(
             frontend4.expect("should find frontend"),
-            Route::ClusterId("cluster_3".to_string())
+            (
+                Route::ClusterId("cluster_3".to_string()),
+                FrontendOptions {
+                    timeouts: Some(Timeouts {
+                        request_deadline: Some(5),
+                        ..Default::default()
+                    }),
+                    mirror: None,
+                    headers: vec![],
+                    rate_limit: None,
+                }
+            )
         );
         assert!(frontend5.is_err());
     }
@@ -2,7 +2,7 @@ use std::{
     cell::RefCell,
     collections::{hash_map::Entry, BTreeMap, HashMap},
     io::ErrorKind,
-    net::{Shutdown, SocketAddr as StdSocketAddr},
+    net::{IpAddr, Shutdown, SocketAddr as StdSocketAddr},
     os::unix::io::AsRawFd,
     rc::{Rc, Weak},
     str::{from_utf8, from_utf8_unchecked},
@@ -29,8 +29,9 @@ use rustls::{
         },
         CryptoProvider,
     },
-    CipherSuite, ProtocolVersion, ServerConfig as RustlsServerConfig, ServerConnection,
-    SupportedCipherSuite,
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    CipherSuite, ProtocolVersion, RootCertStore, ServerConfig as RustlsServerConfig,
+    ServerConnection, SupportedCipherSuite,
 };
 use rusty_ulid::Ulid;
 
@@ -39,16 +40,21 @@ use sozu_command::{
     config::DEFAULT_CIPHER_SUITES,
     proto::command::{
         request::RequestType, response_content::ContentType, AddCertificate, CertificateSummary,
-        CertificatesByAddress, Cluster, HttpsListenerConfig, ListOfCertificatesByAddress,
-        ListenerType, RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
-        ResponseContent, TlsVersion, WorkerRequest, WorkerResponse,
+        CertificatesByAddress, ClientAuthentication, ClientAuthenticationMode,
+        ClientCertificateHeaders, Cluster, CustomHttpAnswers, DuplicateHeader, Http10Options,
+        HttpsListenerConfig, ListOfCertificatesByAddress, ListenerType, ProxyStatusHeader,
+        RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
+        ResponseContent, SetFaultInjection, SetLoadBalancing, SetRequestPipeline, Timeouts,
+        TlsVersion, UpdateListenerAnswers, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
+    request::IpNetwork,
     response::HttpFrontend,
     state::ClusterId,
 };
 
 use crate::{
+    backend_pinning_trusted,
     backends::BackendMap,
     pool::Pool,
     protocol::{
@@ -58,13 +64,16 @@ use crate::{
             parser::{hostname_and_port, Method},
             ResponseStream,
         },
+        pipe::WebSocketContext,
         proxy_protocol::expect::ExpectProxyProtocol,
         rustls::TlsHandshake,
         Http, Pipe, SessionState,
     },
-    router::{Route, Router},
+    rate_limit::{RequestRateLimiter, CLIENT_RATE_LIMITS},
+    router::{ClientTls, FrontendOptions, RequestHead, RequestHeaders, Route, Router},
     server::{ListenToken, SessionManager},
-    socket::{server_bind, FrontRustls},
+    sni::{parse_server_name, ServerName, MAX_RECORD_SIZE},
+    socket::{is_fd_exhaustion, server_bind, FrontRustls},
     timer::TimeoutContainer,
     tls::MutexCertificateResolver,
     util::UnwrapLog,
@@ -73,8 +82,11 @@ use crate::{
     SessionMetrics, SessionResult, StateMachineBuilder, StateResult,
 };
 
-// const SERVER_PROTOS: &[&str] = &["http/1.1", "h2"];
 const SERVER_PROTOS: &[&str] = &["http/1.1"];
+/// ALPN protocols of the listeners with HTTP/2 enabled, in order of preference
+const SERVER_PROTOS_HTTP2: &[&str] = &["h2", "http/1.1"];
+/// maximum amount of TLS 1.3 early data accepted on a connection, when enabled
+const MAX_EARLY_DATA_SIZE: u32 = 16384;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TlsCluster {
@@ -90,12 +102,15 @@ StateMachineBuilder! {
     /// - TLS handshake
     /// - HTTP or HTTP2
     /// - WebSocket (passthrough), only from HTTP
+    /// - TLS passthrough to a TCP cluster, instead of the handshake, for the
+    ///   server names without certificate
     enum HttpsStateMachine impl SessionState {
         Expect(ExpectProxyProtocol<MioTcpStream>, ServerConnection),
         Handshake(TlsHandshake),
         Http(Http<FrontRustls, HttpsListener>),
         WebSocket(Pipe<FrontRustls, HttpsListener>),
-        Http2(Http2<FrontRustls>) -> todo!("H2"),
+        Http2(Http2<FrontRustls, HttpsListener>),
+        Passthrough(Pipe<MioTcpStream, HttpsListener>),
     }
 }
 
@@ -109,6 +124,8 @@ pub struct HttpsSession {
     configured_backend_timeout: Duration,
     configured_connect_timeout: Duration,
     configured_frontend_timeout: Duration,
+    configured_handshake_timeout: Duration,
+    configured_request_timeout: Duration,
     frontend_token: Token,
     has_been_closed: bool,
     last_event: Instant,
@@ -120,6 +137,8 @@ pub struct HttpsSession {
     public_address: StdSocketAddr,
     state: HttpsStateMachine,
     sticky_name: String,
+    /// TCP cluster of the unknown server names, until the ClientHello is read
+    unknown_sni_cluster: Option<ClusterId>,
 }
 
 impl HttpsSession {
@@ -130,7 +149,9 @@ impl HttpsSession {
         configured_connect_timeout: Duration,
         configured_frontend_timeout: Duration,
         configured_request_timeout: Duration,
+        configured_handshake_timeout: Duration,
         expect_proxy: bool,
+        detect_proxy: bool,
         listener: Rc<RefCell<HttpsListener>>,
         pool: Weak<RefCell<Pool>>,
         proxy: Rc<RefCell<HttpsProxy>>,
@@ -141,25 +162,31 @@ impl HttpsSession {
         token: Token,
         wait_time: Duration,
     ) -> HttpsSession {
-        let peer_address = if expect_proxy {
+        let peer_address = if expect_proxy || detect_proxy {
             // Will be defined later once the expect proxy header has been received and parsed
             None
         } else {
-            sock.peer_addr().ok()
+            sock.peer_addr()
+                .ok()
+                .map(|address| listener.borrow().client_address(address))
         };
 
         let request_id = Ulid::generate();
-        let container_frontend_timeout = TimeoutContainer::new(configured_request_timeout, token);
+        let unknown_sni_cluster = listener.borrow().config.unknown_sni_cluster.clone();
 
-        let state = if expect_proxy {
+        let state = if expect_proxy || detect_proxy {
             trace!("starting in expect proxy state");
             gauge_add!("protocol.proxy.expect", 1);
-            HttpsStateMachine::Expect(
-                ExpectProxyProtocol::new(container_frontend_timeout, sock, token, request_id),
-                rustls_details,
-            )
+            let container_frontend_timeout =
+                TimeoutContainer::new(configured_request_timeout, token);
+            let mut expect =
+                ExpectProxyProtocol::new(container_frontend_timeout, sock, token, request_id);
+            expect.detect = detect_proxy;
+            HttpsStateMachine::Expect(expect, rustls_details)
         } else {
             gauge_add!("protocol.tls.handshake", 1);
+            let container_frontend_timeout =
+                TimeoutContainer::new(configured_handshake_timeout, token);
             HttpsStateMachine::Handshake(TlsHandshake::new(
                 container_frontend_timeout,
                 rustls_details,
@@ -176,6 +203,8 @@ impl HttpsSession {
             configured_backend_timeout,
             configured_connect_timeout,
             configured_frontend_timeout,
+            configured_handshake_timeout,
+            configured_request_timeout,
             frontend_token: token,
             has_been_closed: false,
             last_event: Instant::now(),
@@ -187,6 +216,7 @@ impl HttpsSession {
             public_address,
             state,
             sticky_name,
+            unknown_sni_cluster,
         }
     }
 
@@ -198,6 +228,10 @@ impl HttpsSession {
             HttpsStateMachine::Http(http) => self.upgrade_http(http),
             HttpsStateMachine::Http2(_) => self.upgrade_http2(),
             HttpsStateMachine::WebSocket(wss) => self.upgrade_websocket(wss),
+            HttpsStateMachine::Passthrough(pipe) => {
+                error!("Upgrade called on a TLS passthrough, this should not happen");
+                Some(HttpsStateMachine::Passthrough(pipe))
+            }
             HttpsStateMachine::FailedUpgrade(_) => unreachable!(),
         };
 
@@ -216,38 +250,57 @@ impl HttpsSession {
         mut expect: ExpectProxyProtocol<MioTcpStream>,
         ssl: ServerConnection,
     ) -> Option<HttpsStateMachine> {
-        if let Some(ref addresses) = expect.addresses {
-            if let (Some(public_address), Some(session_address)) =
-                (addresses.destination(), addresses.source())
+        let addresses = if expect.header_absent {
+            // the public address is the one of the listener, set when accepting
+            let peer_address = expect
+                .frontend
+                .peer_addr()
+                .ok()
+                .map(|address| self.listener.borrow().client_address(address));
+            Some((self.public_address, peer_address))
+        } else {
+            match expect
+                .addresses
+                .as_ref()
+                .map(|addresses| (addresses.destination(), addresses.source()))
             {
-                self.public_address = public_address;
-                self.peer_address = Some(session_address);
-
-                let ExpectProxyProtocol {
-                    container_frontend_timeout,
-                    frontend,
-                    frontend_readiness: readiness,
-                    request_id,
-                    ..
-                } = expect;
-
-                let mut handshake = TlsHandshake::new(
-                    container_frontend_timeout,
-                    ssl,
-                    frontend,
-                    self.frontend_token,
-                    request_id,
-                    self.peer_address,
-                );
-                handshake.frontend_readiness.event = readiness.event;
-                // Can we remove this? If not why?
-                // Add e2e test for proto-proxy upgrades
-                handshake.frontend_readiness.event.insert(Ready::READABLE);
-
-                gauge_add!("protocol.proxy.expect", -1);
-                gauge_add!("protocol.tls.handshake", 1);
-                return Some(HttpsStateMachine::Handshake(handshake));
+                Some((Some(public_address), Some(session_address))) => Some((
+                    public_address,
+                    Some(self.listener.borrow().client_address(session_address)),
+                )),
+                _ => None,
             }
+        };
+
+        if let Some((public_address, peer_address)) = addresses {
+            self.public_address = public_address;
+            self.peer_address = peer_address;
+
+            let ExpectProxyProtocol {
+                mut container_frontend_timeout,
+                frontend,
+                frontend_readiness: readiness,
+                request_id,
+                ..
+            } = expect;
+
+            container_frontend_timeout.set_duration(self.configured_handshake_timeout);
+            let mut handshake = TlsHandshake::new(
+                container_frontend_timeout,
+                ssl,
+                frontend,
+                self.frontend_token,
+                request_id,
+                self.peer_address,
+            );
+            handshake.frontend_readiness.event = readiness.event;
+            // Can we remove this? If not why?
+            // Add e2e test for proto-proxy upgrades
+            handshake.frontend_readiness.event.insert(Ready::READABLE);
+
+            gauge_add!("protocol.proxy.expect", -1);
+            gauge_add!("protocol.tls.handshake", 1);
+            return Some(HttpsStateMachine::Handshake(handshake));
         }
 
         // currently, only happens in expect proxy protocol with AF_UNSPEC address
@@ -258,7 +311,7 @@ impl HttpsSession {
         None
     }
 
-    fn upgrade_handshake(&mut self, handshake: TlsHandshake) -> Option<HttpsStateMachine> {
+    fn upgrade_handshake(&mut self, mut handshake: TlsHandshake) -> Option<HttpsStateMachine> {
         // Add 1st routing phase
         // - get SNI
         // - get ALPN
@@ -290,6 +343,12 @@ impl HttpsSession {
         if let Some(cipher) = handshake.session.negotiated_cipher_suite() {
             incr!(rustls_ciphersuite_str(cipher));
         };
+        handshake.record_handshake_metrics();
+
+        // the request timeout starts once the handshake is done
+        handshake
+            .container_frontend_timeout
+            .set_duration(self.configured_request_timeout);
 
         let front_stream = FrontRustls {
             stream: handshake.stream,
@@ -324,15 +383,22 @@ impl HttpsSession {
             }
             AlpnProtocols::H2 => {
                 let mut http = Http2::new(
+                    self.answers.clone(),
+                    self.configured_backend_timeout,
+                    self.configured_connect_timeout,
+                    self.configured_frontend_timeout,
+                    handshake.container_frontend_timeout,
                     front_stream,
                     self.frontend_token,
+                    self.listener.clone(),
                     self.pool.clone(),
-                    Some(self.public_address),
-                    None,
+                    self.proxy.clone(),
+                    self.public_address,
+                    self.peer_address,
                     self.sticky_name.clone(),
                 );
 
-                http.frontend.readiness.event = handshake.frontend_readiness.event;
+                http.frontend_readiness.event = handshake.frontend_readiness.event;
 
                 gauge_add!("protocol.http2", 1);
                 Some(HttpsStateMachine::Http2(http))
@@ -355,6 +421,7 @@ impl HttpsSession {
         };
 
         let ws_context = http.websocket_context();
+        let websocket = http.context.websocket;
         let mut container_frontend_timeout = http.container_frontend_timeout;
         let mut container_backend_timeout = http.container_backend_timeout;
         container_frontend_timeout.reset();
@@ -387,6 +454,10 @@ impl HttpsSession {
         pipe.frontend_readiness.event = http.frontend_readiness.event;
         pipe.backend_readiness.event = http.backend_readiness.event;
         pipe.set_back_token(back_token);
+        if websocket {
+            let idle_timeout = self.listener.borrow().config.websocket_idle_timeout;
+            pipe.enable_websocket(idle_timeout.map(|timeout| Duration::from_secs(timeout as u64)));
+        }
 
         gauge_add!("protocol.https", -1);
         gauge_add!("protocol.wss", 1);
@@ -396,7 +467,8 @@ impl HttpsSession {
     }
 
     fn upgrade_http2(&self) -> Option<HttpsStateMachine> {
-        todo!()
+        error!("Upgrade called on HTTP/2, this should not happen");
+        None
     }
 
     fn upgrade_websocket(
@@ -407,6 +479,169 @@ impl HttpsSession {
         error!("Upgrade called on WSS, this should not happen");
         Some(HttpsStateMachine::WebSocket(wss))
     }
+
+    /// Peek at the ClientHello before the handshake, without consuming it. If the listener
+    /// has no certificate for its server name, the connection is relayed to the unknown SNI
+    /// cluster. Returns whether to close the session if the handshake must not go on yet
+    fn route_unknown_sni(
+        &mut self,
+        session: Rc<RefCell<dyn ProxySession>>,
+    ) -> Option<SessionIsToBeClosed> {
+        let HttpsStateMachine::Handshake(handshake) = &self.state else {
+            return None;
+        };
+
+        let mut buffer = vec![0; MAX_RECORD_SIZE];
+        let server_name = match handshake.stream.peek(&mut buffer) {
+            Ok(0) => return Some(true),
+            Ok(size) => parse_server_name(&buffer[..size]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => ServerName::Incomplete,
+            Err(e) => {
+                error!("error reading the ClientHello: {:?}", e);
+                return Some(true);
+            }
+        };
+
+        let server_name = match server_name {
+            ServerName::Incomplete => return Some(handshake.frontend_readiness.event.is_hup()),
+            ServerName::Found(server_name) => server_name,
+            // the handshake goes on with the default certificate, or fails
+            ServerName::Missing | ServerName::NotTls => {
+                self.unknown_sni_cluster = None;
+                return None;
+            }
+        };
+
+        let cluster_id = self.unknown_sni_cluster.take()?;
+        let has_certificate = match self.listener.borrow().resolver.0.lock() {
+            Ok(resolver) => resolver
+                .domain_lookup(server_name.as_bytes(), true)
+                .is_some(),
+            Err(_) => true,
+        };
+        if has_certificate {
+            return None;
+        }
+
+        Some(self.upgrade_passthrough(session, cluster_id, &server_name))
+    }
+
+    /// Replace the handshake with a relay of the raw TLS stream to a backend of the
+    /// TCP cluster, the backend terminating TLS
+    fn upgrade_passthrough(
+        &mut self,
+        session: Rc<RefCell<dyn ProxySession>>,
+        cluster_id: ClusterId,
+        server_name: &str,
+    ) -> SessionIsToBeClosed {
+        let HttpsStateMachine::Handshake(mut handshake) = self.state.take() else {
+            return true;
+        };
+
+        let proxy = self.proxy.borrow();
+        let (backend, mut back_socket) = match proxy
+            .backends
+            .borrow_mut()
+            .backend_from_cluster_id(&cluster_id, self.peer_address)
+        {
+            Ok(backend_and_socket) => backend_and_socket,
+            Err(e) => {
+                incr!("https.sni_passthrough.no_backend", Some(&cluster_id), None);
+                error!(
+                    "could not relay the TLS connection for {} to cluster {}: {}",
+                    server_name, cluster_id, e
+                );
+                return true;
+            }
+        };
+        let backend_id = backend.borrow().backend_id.clone();
+
+        let Some((front_buffer, back_buffer)) = self.pool.upgrade().and_then(|pool| {
+            let mut pool = pool.borrow_mut();
+            Some((pool.checkout()?, pool.checkout()?))
+        }) else {
+            error!("could not get buffers from the pool to relay a TLS connection");
+            backend.borrow_mut().dec_connections();
+            return true;
+        };
+
+        if let Err(e) = back_socket.set_nodelay(true) {
+            error!(
+                "error setting nodelay on back socket({:?}): {:?}",
+                back_socket, e
+            );
+        }
+
+        let back_token = Token(proxy.sessions.borrow_mut().slab.insert(session));
+        if let Err(e) = proxy.registry.register(
+            &mut back_socket,
+            back_token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!("error registering back socket({:?}): {:?}", back_socket, e);
+        }
+
+        handshake
+            .container_frontend_timeout
+            .set_duration(self.configured_frontend_timeout);
+        let container_backend_timeout =
+            TimeoutContainer::new(self.configured_connect_timeout, back_token);
+        backend.borrow_mut().active_requests += 1;
+
+        let mut pipe = Pipe::new(
+            back_buffer,
+            Some(backend_id),
+            Some(back_socket),
+            Some(backend),
+            Some(container_backend_timeout),
+            Some(handshake.container_frontend_timeout),
+            Some(cluster_id.clone()),
+            front_buffer,
+            self.frontend_token,
+            handshake.stream,
+            self.listener.clone(),
+            Protocol::TCP,
+            handshake.request_id,
+            self.peer_address,
+            WebSocketContext::Tcp,
+        );
+        pipe.frontend_readiness.event = handshake.frontend_readiness.event;
+        pipe.set_back_token(back_token);
+
+        incr!("https.sni_passthrough", Some(&cluster_id), None);
+        debug!(
+            "relaying the TLS connection for {} to cluster {}",
+            server_name, cluster_id
+        );
+        gauge_add!("protocol.tls.handshake", -1);
+        gauge_add!("protocol.tls.passthrough", 1);
+        self.state = HttpsStateMachine::Passthrough(pipe);
+        false
+    }
+
+    /// the back socket of a TLS passthrough is not closed by the pipe
+    fn close_passthrough_backend(&mut self) {
+        let HttpsStateMachine::Passthrough(pipe) = &mut self.state else {
+            return;
+        };
+        let proxy = self.proxy.borrow();
+        for token in pipe.back_token() {
+            proxy.remove_session(token);
+        }
+        if let Some(socket) = pipe.back_socket_mut() {
+            if let Err(e) = proxy.registry.deregister(socket) {
+                error!("error deregistering back socket({:?}): {:?}", socket, e);
+            }
+            if let Err(e) = socket.shutdown(Shutdown::Both) {
+                if e.kind() != ErrorKind::NotConnected {
+                    error!("error shutting down back socket({:?}): {:?}", socket, e);
+                }
+            }
+        }
+        if let Some(backend) = &pipe.backend {
+            backend.borrow_mut().dec_connections();
+        }
+    }
 }
 
 impl ProxySession for HttpsSession {
@@ -428,6 +663,7 @@ impl ProxySession for HttpsSession {
                 gauge_add!("websocket.active_requests", -1);
             }
             StateMarker::Http2 => gauge_add!("protocol.http2", -1),
+            StateMarker::Passthrough => gauge_add!("protocol.tls.passthrough", -1),
         }
 
         if self.state.failed() {
@@ -437,11 +673,16 @@ impl ProxySession for HttpsSession {
                 StateMarker::Http => incr!("https.upgrade.http.failed"),
                 StateMarker::WebSocket => incr!("https.upgrade.wss.failed"),
                 StateMarker::Http2 => incr!("https.upgrade.http2.failed"),
+                StateMarker::Passthrough => incr!("https.upgrade.passthrough.failed"),
             }
             return;
         }
 
         self.state.cancel_timeouts();
+        self.close_passthrough_backend();
+        if let HttpsStateMachine::WebSocket(pipe) = &mut self.state {
+            pipe.close_websocket();
+        }
 
         let front_socket = self.state.front_socket();
         if let Err(e) = front_socket.shutdown(Shutdown::Both) {
@@ -493,6 +734,13 @@ impl ProxySession for HttpsSession {
     fn ready(&mut self, session: Rc<RefCell<dyn ProxySession>>) -> SessionIsToBeClosed {
         self.metrics.service_start();
 
+        if self.unknown_sni_cluster.is_some() {
+            if let Some(to_be_closed) = self.route_unknown_sni(session.clone()) {
+                self.metrics.service_stop();
+                return to_be_closed;
+            }
+        }
+
         let session_result =
             self.state
                 .ready(session.clone(), self.proxy.clone(), &mut self.metrics);
@@ -538,6 +786,9 @@ pub struct HttpsListener {
     config: HttpsListenerConfig,
     fronts: Router,
     listener: Option<MioTcpListener>,
+    rate_limiter: Option<RequestRateLimiter>,
+    /// networks of the clients allowed to pin their requests to a backend
+    backend_pinning_trusted: Vec<IpNetwork>,
     resolver: Arc<MutexCertificateResolver>,
     rustls_details: Arc<RustlsServerConfig>,
     tags: BTreeMap<String, CachedTags>,
@@ -559,9 +810,28 @@ impl ListenerHandler for HttpsListener {
             None => self.tags.remove(&key),
         };
     }
+
+    fn normalizes_ipv4_mapped(&self) -> bool {
+        self.config.normalize_ipv4_mapped
+    }
 }
 
 impl L7ListenerHandler for HttpsListener {
+    fn check_request_rate(&mut self, client: IpAddr) -> Result<(), Duration> {
+        match self.rate_limiter.as_mut() {
+            Some(rate_limiter) => rate_limiter.check(client, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    fn get_backend_pinning_header(&self, client: IpAddr) -> Option<String> {
+        let backend_pinning = self.config.backend_pinning.as_ref()?;
+        self.backend_pinning_trusted
+            .iter()
+            .any(|network| network.contains(client))
+            .then(|| backend_pinning.header.to_owned())
+    }
+
     fn get_sticky_name(&self) -> &str {
         &self.config.sticky_name
     }
@@ -570,12 +840,39 @@ impl L7ListenerHandler for HttpsListener {
         self.config.connect_timeout
     }
 
+    fn get_timeouts(&self) -> Timeouts {
+        self.config.timeouts()
+    }
+
+    fn get_proxy_status(&self) -> Option<ProxyStatusHeader> {
+        self.config
+            .proxy_status
+            .and_then(|header| ProxyStatusHeader::try_from(header).ok())
+    }
+
+    fn get_http10_options(&self) -> Http10Options {
+        self.config.http10.clone().unwrap_or_default()
+    }
+
+    fn get_duplicate_headers(&self) -> Vec<DuplicateHeader> {
+        self.config.duplicate_headers.clone()
+    }
+
+    fn get_client_certificate_headers(&self) -> Option<ClientCertificateHeaders> {
+        self.config
+            .client_authentication
+            .as_ref()
+            .map(|client_authentication| client_authentication.headers.clone())
+    }
+
     fn frontend_from_request(
         &self,
         host: &str,
         uri: &str,
         method: &Method,
-    ) -> Result<Route, FrontendFromRequestError> {
+        tls: Option<&ClientTls>,
+        headers: &dyn RequestHeaders,
+    ) -> Result<(Route, FrontendOptions), FrontendFromRequestError> {
         let start = Instant::now();
         let (remaining_input, (hostname, _)) = match hostname_and_port(host.as_bytes()) {
             Ok(tuple) => tuple,
@@ -599,10 +896,18 @@ impl L7ListenerHandler for HttpsListener {
         // chars in there
         let host = unsafe { from_utf8_unchecked(hostname) };
 
-        let route = self.fronts.lookup(host, uri, method).map_err(|e| {
-            incr!("http.failed_backend_matching");
-            FrontendFromRequestError::NoClusterFound(e)
-        })?;
+        let rule = self
+            .fronts
+            .lookup_rule(
+                &RequestHead::new(host, uri, method)
+                    .with_tls(tls)
+                    .with_headers(headers),
+            )
+            .map_err(|e| {
+                incr!("http.failed_backend_matching");
+                FrontendFromRequestError::NoClusterFound(e)
+            })?;
+        let route = rule.route.clone();
 
         let now = Instant::now();
 
@@ -610,7 +915,7 @@ impl L7ListenerHandler for HttpsListener {
             time!("frontend_matching_time", cluster, (now - start).as_millis());
         }
 
-        Ok(route)
+        Ok((route, rule.options()))
     }
 }
 
@@ -634,12 +939,31 @@ impl HttpsListener {
                 HttpAnswers::new(&config.http_answers)
                     .map_err(|(status, error)| ListenerError::TemplateParse(status, error))?,
             )),
+            rate_limiter: config
+                .request_rate_limit
+                .as_ref()
+                .map(RequestRateLimiter::new),
+            backend_pinning_trusted: backend_pinning_trusted(config.backend_pinning.as_ref()),
             config,
             token,
             tags: BTreeMap::new(),
         })
     }
 
+    /// replace the answers that are set, applying them to the ongoing sessions too
+    pub fn update_answers(&mut self, http_answers: CustomHttpAnswers) -> Result<(), ListenerError> {
+        let mut new_answers = self.config.http_answers.clone().unwrap_or_default();
+        new_answers.merge(http_answers);
+        let new_answers = Some(new_answers);
+
+        self.answers
+            .borrow_mut()
+            .update_listener_answers(&new_answers)
+            .map_err(|(status, error)| ListenerError::TemplateParse(status, error))?;
+        self.config.http_answers = new_answers;
+        Ok(())
+    }
+
     pub fn activate(
         &mut self,
         registry: &Registry,
@@ -652,12 +976,12 @@ impl HttpsListener {
 
         let mut listener = match tcp_listener {
             Some(tcp_listener) => tcp_listener,
-            None => {
-                server_bind(address).map_err(|server_bind_error| ListenerError::Activation {
+            None => server_bind(address, self.config.ipv6_only).map_err(|server_bind_error| {
+                ListenerError::Activation {
                     address,
                     error: server_bind_error.to_string(),
-                })?
-            }
+                }
+            })?,
         };
 
         registry
@@ -720,19 +1044,35 @@ impl HttpsListener {
             })
             .collect::<Vec<_>>();
 
-        let provider = CryptoProvider {
+        let provider = Arc::new(CryptoProvider {
             cipher_suites: ciphers,
             ..ring::default_provider()
-        };
+        });
 
-        let mut server_config = RustlsServerConfig::builder_with_provider(provider.into())
+        let builder = RustlsServerConfig::builder_with_provider(provider.clone())
             .with_protocol_versions(&versions[..])
-            .map_err(|err| ListenerError::BuildRustls(err.to_string()))?
-            .with_no_client_auth()
-            .with_cert_resolver(resolver);
+            .map_err(|err| ListenerError::BuildRustls(err.to_string()))?;
+        let builder = match &config.client_authentication {
+            Some(client_authentication) => builder.with_client_cert_verifier(
+                Self::create_client_certificate_verifier(client_authentication, provider)?,
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(resolver);
         server_config.send_tls13_tickets = config.send_tls13_tickets as usize;
+        if config.early_data {
+            // early data is only accepted on resumed TLS 1.3 sessions,
+            // the answers can be sent before the client's Finished message
+            server_config.max_early_data_size = MAX_EARLY_DATA_SIZE;
+            server_config.send_half_rtt_data = true;
+        }
 
-        let mut protocols = SERVER_PROTOS
+        let server_protos = if config.http2 {
+            SERVER_PROTOS_HTTP2
+        } else {
+            SERVER_PROTOS
+        };
+        let mut protocols = server_protos
             .iter()
             .map(|proto| proto.as_bytes().to_vec())
             .collect::<Vec<_>>();
@@ -741,6 +1081,42 @@ impl HttpsListener {
         Ok(server_config)
     }
 
+    /// verify the client certificates with the authorities and revocation lists of the listener
+    fn create_client_certificate_verifier(
+        client_authentication: &ClientAuthentication,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<dyn ClientCertVerifier>, ListenerError> {
+        let mut roots = RootCertStore::empty();
+        for certificate in
+            rustls_pemfile::certs(&mut client_authentication.ca_certificates.as_bytes())
+        {
+            let certificate = certificate.map_err(|error| {
+                ListenerError::BuildRustls(format!("invalid client authority: {error}"))
+            })?;
+            roots.add(certificate).map_err(|error| {
+                ListenerError::BuildRustls(format!("invalid client authority: {error}"))
+            })?;
+        }
+
+        let mut crls = Vec::new();
+        if let Some(crl) = &client_authentication.crl {
+            for crl in rustls_pemfile::crls(&mut crl.as_bytes()) {
+                crls.push(crl.map_err(|error| {
+                    ListenerError::BuildRustls(format!("invalid revocation list: {error}"))
+                })?);
+            }
+        }
+
+        let mut verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).with_crls(crls);
+        if client_authentication.mode == ClientAuthenticationMode::Optional as i32 {
+            verifier = verifier.allow_unauthenticated();
+        }
+        verifier
+            .build()
+            .map_err(|error| ListenerError::BuildRustls(error.to_string()))
+    }
+
     pub fn add_https_front(&mut self, tls_front: HttpFrontend) -> Result<(), ListenerError> {
         self.fronts
             .add_http_front(&tls_front)
@@ -759,6 +1135,7 @@ impl HttpsListener {
             sock.accept()
                 .map_err(|e| match e.kind() {
                     ErrorKind::WouldBlock => AcceptError::WouldBlock,
+                    _ if is_fd_exhaustion(&e) => AcceptError::TooManyFiles,
                     _ => {
                         error!("accept() IO error: {:?}", e);
                         AcceptError::IoError
@@ -814,6 +1191,24 @@ impl HttpsProxy {
         }
     }
 
+    pub fn update_listener_answers(
+        &mut self,
+        update: UpdateListenerAnswers,
+    ) -> Result<Option<ResponseContent>, ProxyError> {
+        let address = update.address.into();
+        let listener = self
+            .listeners
+            .values()
+            .find(|listener| listener.borrow().address == address)
+            .ok_or(ProxyError::NoListenerFound(address))?;
+
+        listener
+            .borrow_mut()
+            .update_answers(update.http_answers)
+            .map_err(ProxyError::UpdateAnswers)?;
+        Ok(None)
+    }
+
     pub fn remove_listener(
         &mut self,
         remove: RemoveListener,
@@ -890,6 +1285,7 @@ impl HttpsProxy {
                     .map(|(k, fingerprint)| CertificateSummary {
                         domain: String::from_utf8(k).unwrap(),
                         fingerprint: fingerprint.to_string(),
+                        details: None,
                     })
                     .collect();
 
@@ -914,24 +1310,38 @@ impl HttpsProxy {
         &mut self,
         domain: String,
     ) -> Result<Option<ResponseContent>, ProxyError> {
+        // only the listeners that serve this domain, with the certificate
+        // their SNI resolution would pick
         let certificates = self
             .listeners
             .values()
-            .map(|listener| {
+            .filter_map(|listener| {
                 let owned = listener.borrow();
                 let resolver = unwrap_msg!(owned.resolver.0.lock());
-                let mut certificate_summaries = vec![];
 
-                if let Some((k, fingerprint)) = resolver.domain_lookup(domain.as_bytes(), true) {
-                    certificate_summaries.push(CertificateSummary {
-                        domain: String::from_utf8(k.to_vec()).unwrap(),
-                        fingerprint: fingerprint.to_string(),
+                let (k, fingerprint) = resolver.domain_lookup(domain.as_bytes(), true)?;
+
+                let details = resolver
+                    .get_certificate(fingerprint)
+                    .and_then(|certificate| match certificate.details() {
+                        Ok(details) => Some(details),
+                        Err(e) => {
+                            error!(
+                                "could not get details of certificate {}: {}",
+                                fingerprint, e
+                            );
+                            None
+                        }
                     });
-                }
-                CertificatesByAddress {
+
+                Some(CertificatesByAddress {
                     address: owned.address.into(),
-                    certificate_summaries,
-                }
+                    certificate_summaries: vec![CertificateSummary {
+                        domain: String::from_utf8(k.to_vec()).unwrap(),
+                        fingerprint: fingerprint.to_string(),
+                        details,
+                    }],
+                })
             })
             .collect();
 
@@ -979,6 +1389,20 @@ impl HttpsProxy {
             .collect()
     }
 
+    /// file descriptors of the listeners, which stay registered in this worker
+    pub fn listener_fds(&self) -> Vec<(StdSocketAddr, i32)> {
+        self.listeners
+            .values()
+            .filter_map(|listener| {
+                let owned = listener.borrow();
+                owned
+                    .listener
+                    .as_ref()
+                    .map(|listener| (owned.address, listener.as_raw_fd()))
+            })
+            .collect()
+    }
+
     pub fn give_back_listener(
         &mut self,
         address: StdSocketAddr,
@@ -1019,11 +1443,48 @@ impl HttpsProxy {
         Ok(None)
     }
 
+    pub fn set_request_pipeline(
+        &mut self,
+        set: SetRequestPipeline,
+    ) -> Result<Option<ResponseContent>, ProxyError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(ProxyError::NoClusterFound(set.cluster_id.clone()))?;
+        cluster.pipeline = set.pipeline;
+        Ok(None)
+    }
+
+    pub fn set_load_balancing(
+        &mut self,
+        set: SetLoadBalancing,
+    ) -> Result<Option<ResponseContent>, ProxyError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(ProxyError::NoClusterFound(set.cluster_id.clone()))?;
+        set.apply(cluster);
+        Ok(None)
+    }
+
+    pub fn set_fault_injection(
+        &mut self,
+        set: SetFaultInjection,
+    ) -> Result<Option<ResponseContent>, ProxyError> {
+        let cluster = self
+            .clusters
+            .get_mut(&set.cluster_id)
+            .ok_or(ProxyError::NoClusterFound(set.cluster_id.clone()))?;
+        cluster.fault_injection = set.fault_injection;
+        Ok(None)
+    }
+
     pub fn remove_cluster(
         &mut self,
         cluster_id: &str,
     ) -> Result<Option<ResponseContent>, ProxyError> {
         self.clusters.remove(cluster_id);
+        CLIENT_RATE_LIMITS.with(|limits| limits.borrow_mut().remove_cluster(cluster_id));
         for listener in self.listeners.values() {
             listener
                 .borrow()
@@ -1064,6 +1525,8 @@ impl HttpsProxy {
         &mut self,
         front: RequestHttpFrontend,
     ) -> Result<Option<ResponseContent>, ProxyError> {
+        // the buckets of the frontend are keyed by its summary
+        let summary = front.to_string();
         let front = front.clone().to_frontend().map_err(|request_error| {
             ProxyError::WrongInputFrontend {
                 front,
@@ -1082,6 +1545,8 @@ impl HttpsProxy {
         listener
             .remove_https_front(front)
             .map_err(ProxyError::RemoveFrontend)?;
+
+        CLIENT_RATE_LIMITS.with(|limits| limits.borrow_mut().remove_frontend(&summary));
         Ok(None)
     }
 
@@ -1232,7 +1697,9 @@ impl ProxyConfiguration for HttpsProxy {
             Duration::from_secs(owned.config.connect_timeout as u64),
             Duration::from_secs(owned.config.front_timeout as u64),
             Duration::from_secs(owned.config.request_timeout as u64),
+            Duration::from_secs(owned.config.handshake_timeout as u64),
             owned.config.expect_proxy,
+            owned.config.detect_proxy,
             listener.clone(),
             Rc::downgrade(&self.pool),
             proxy,
@@ -1265,6 +1732,18 @@ impl ProxyConfiguration for HttpsProxy {
                 debug!("{} remove cluster {:?}", request_id, cluster_id);
                 self.remove_cluster(&cluster_id)
             }
+            RequestType::SetRequestPipeline(set) => {
+                debug!("{} set request pipeline {:?}", request_id, set);
+                self.set_request_pipeline(set)
+            }
+            RequestType::SetLoadBalancing(set) => {
+                debug!("{} set load balancing {:?}", request_id, set);
+                self.set_load_balancing(set)
+            }
+            RequestType::SetFaultInjection(set) => {
+                debug!("{} set fault injection {:?}", request_id, set);
+                self.set_fault_injection(set)
+            }
             RequestType::AddHttpsFrontend(front) => {
                 debug!("{} add https front {:?}", request_id, front);
                 self.add_https_frontend(front)
@@ -1295,6 +1774,13 @@ impl ProxyConfiguration for HttpsProxy {
                 debug!("removing HTTPS listener at address {:?}", remove.address);
                 self.remove_listener(remove)
             }
+            RequestType::UpdateListenerAnswers(update) => {
+                debug!(
+                    "updating answers of HTTPS listener at address {:?}",
+                    update.address
+                );
+                self.update_listener_answers(update)
+            }
             RequestType::SoftStop(_) => {
                 debug!("{} processing soft shutdown", request_id);
                 match self.soft_stop() {
@@ -1347,7 +1833,8 @@ impl ProxyConfiguration for HttpsProxy {
             }
             Err(proxy_error) => {
                 debug!("{} unsuccessful: {}", request_id, proxy_error);
-                WorkerResponse::error(request_id, proxy_error)
+                WorkerResponse::error(request_id, &proxy_error)
+                    .with_error(proxy_error.response_error())
             }
         }
     }
@@ -1396,7 +1883,7 @@ impl L7Proxy for HttpsProxy {
 }
 
 /// Used for metrics keeping
-fn rustls_version_str(version: ProtocolVersion) -> &'static str {
+pub(crate) fn rustls_version_str(version: ProtocolVersion) -> &'static str {
     match version {
         ProtocolVersion::SSLv2 => "tls.version.SSLv2",
         ProtocolVersion::SSLv3 => "tls.version.SSLv3",
@@ -1413,7 +1900,7 @@ fn rustls_version_str(version: ProtocolVersion) -> &'static str {
 }
 
 /// Used for metrics keeping
-fn rustls_ciphersuite_str(cipher: SupportedCipherSuite) -> &'static str {
+pub(crate) fn rustls_ciphersuite_str(cipher: SupportedCipherSuite) -> &'static str {
     match cipher.suite() {
         CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256 => {
             "tls.cipher.TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256"
@@ -1515,7 +2002,7 @@ mod tests {
         proto::command::{CustomHttpAnswers, SocketAddress},
     };
 
-    use crate::router::{trie::TrieNode, MethodRule, PathRule, Route, Router};
+    use crate::router::{trie::TrieNode, MethodRule, PathRule, Route, Router, TlsRule};
 
     /*
     #[test]
@@ -1548,24 +2035,28 @@ mod tests {
             "lolcatho.st".as_bytes(),
             &PathRule::Prefix(uri1),
             &MethodRule::new(None),
+            &TlsRule::default(),
             &Route::ClusterId(cluster_id1.clone())
         ));
         assert!(fronts.add_tree_rule(
             "lolcatho.st".as_bytes(),
             &PathRule::Prefix(uri2),
             &MethodRule::new(None),
+            &TlsRule::default(),
             &Route::ClusterId(cluster_id2)
         ));
         assert!(fronts.add_tree_rule(
             "lolcatho.st".as_bytes(),
             &PathRule::Prefix(uri3),
             &MethodRule::new(None),
+            &TlsRule::default(),
             &Route::ClusterId(cluster_id3)
         ));
         assert!(fronts.add_tree_rule(
             "other.domain".as_bytes(),
             &PathRule::Prefix("test".to_string()),
             &MethodRule::new(None),
+            &TlsRule::default(),
             &Route::ClusterId(cluster_id1)
         ));
 
@@ -1600,35 +2091,52 @@ mod tests {
             config: default_config,
             token: Token(0),
             active: true,
+            rate_limiter: None,
+            backend_pinning_trusted: Vec::new(),
             tags: BTreeMap::new(),
         };
 
         println!("TEST {}", line!());
-        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get);
+        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get, None, &());
         assert_eq!(
             frontend1.expect("should find a frontend"),
-            Route::ClusterId("cluster_1".to_string())
+            (
+                Route::ClusterId("cluster_1".to_string()),
+                FrontendOptions::default()
+            )
         );
         println!("TEST {}", line!());
-        let frontend2 = listener.frontend_from_request("lolcatho.st", "/test", &Method::Get);
+        let frontend2 =
+            listener.frontend_from_request("lolcatho.st", "/test", &Method::Get, None, &());
         assert_eq!(
             frontend2.expect("should find a frontend"),
-            Route::ClusterId("cluster_1".to_string())
+            (
+                Route::ClusterId("cluster_1".to_string()),
+                FrontendOptions::default()
+            )
         );
         println!("TEST {}", line!());
-        let frontend3 = listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get);
+        let frontend3 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get, None, &());
         assert_eq!(
             frontend3.expect("should find a frontend"),
-            Route::ClusterId("cluster_2".to_string())
+            (
+                Route::ClusterId("cluster_2".to_string()),
+                FrontendOptions::default()
+            )
         );
         println!("TEST {}", line!());
-        let frontend4 = listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get);
+        let frontend4 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get, None, &());
         assert_eq!(
             frontend4.expect("should find a frontend"),
-            Route::ClusterId("cluster_3".to_string())
+            (
+                Route::ClusterId("cluster_3".to_string()),
+                FrontendOptions::default()
+            )
         );
         println!("TEST {}", line!());
-        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get);
+        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get, None, &());
         assert!(frontend5.is_err());
         // assert!(false);
     }
@@ -1699,4 +2207,55 @@ mod tests {
             Some(&("hello.sub.test.example.com".as_bytes().to_vec(), 2u8))
         );
     }
+
+    #[test]
+    fn close_the_handshakes_that_outlast_their_timeout() {
+        use std::{
+            io::Read,
+            net::TcpStream,
+            time::{Duration, Instant},
+        };
+
+        use sozu_command::{
+            proto::command::{request::RequestType, ActivateListener, ListenerType},
+            state::ConfigState,
+        };
+
+        use crate::testing::{free_address, TestProxy};
+
+        let front = free_address();
+        let listener = ListenerBuilder::new_https(front.into())
+            .with_handshake_timeout(Some(1))
+            .with_request_timeout(Some(10))
+            .to_tls(None)
+            .unwrap();
+        let mut state = ConfigState::new();
+        for request in [
+            RequestType::AddHttpsListener(listener),
+            RequestType::ActivateListener(ActivateListener {
+                address: front.into(),
+                proxy: ListenerType::Https.into(),
+                from_scm: false,
+            }),
+        ] {
+            state.dispatch(&request.into()).unwrap();
+        }
+        let mut proxy = TestProxy::start("HANDSHAKE_TIMEOUT", &state).unwrap();
+
+        // the client never sends its ClientHello
+        let mut client = TcpStream::connect(front).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let start = Instant::now();
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        let closed = start.elapsed();
+        assert!(closed < Duration::from_secs(5), "{closed:?}");
+
+        assert_eq!(
+            proxy.proxy_count("tls.handshake.failed.timeout").unwrap(),
+            1
+        );
+        proxy.stop().unwrap();
+    }
 }
@@ -23,6 +23,10 @@
 //!
 //! ## How to use this library directly
 //!
+//! The simplest way to run a worker inside your own program is the
+//! [`embedded::EmbeddedWorker`], which starts a complete worker in a thread and
+//! exposes methods to send it requests and receive its events.
+//!
 //! This documentation here explains how to write a binary that will start a single Sōzu
 //! worker and give it orders. The method has two steps:
 //!
@@ -173,6 +177,7 @@
 //!     .write_message(&WorkerRequest {
 //!         id: String::from("add-the-cluster"),
 //!         content: RequestType::AddCluster(cluster).into(),
+//!         sequence: None,
 //!     })
 //!     .expect("Could not send AddHttpFrontend request");
 //!
@@ -180,6 +185,7 @@
 //!     .write_message(&WorkerRequest {
 //!         id: String::from("add-the-frontend"),
 //!         content: RequestType::AddHttpFrontend(http_front).into(),
+//!         sequence: None,
 //!     })
 //!     .expect("Could not send AddHttpFrontend request");
 //!
@@ -187,6 +193,7 @@
 //!     .write_message(&WorkerRequest {
 //!         id: String::from("add-the-backend"),
 //!         content: RequestType::AddBackend(http_backend).into(),
+//!         sequence: None,
 //!     })
 //!     .expect("Could not send AddBackend request");
 //!
@@ -277,6 +284,7 @@
 //!         .write_message(&WorkerRequest {
 //!             id: String::from("add-the-cluster"),
 //!             content: RequestType::AddCluster(cluster).into(),
+//!             sequence: None,
 //!         })
 //!         .expect("Could not send AddHttpFrontend request");
 //!
@@ -284,6 +292,7 @@
 //!         .write_message(&WorkerRequest {
 //!             id: String::from("add-the-frontend"),
 //!             content: RequestType::AddHttpFrontend(http_front).into(),
+//!             sequence: None,
 //!         })
 //!         .expect("Could not send AddHttpFrontend request");
 //!
@@ -291,6 +300,7 @@
 //!         .write_message(&WorkerRequest {
 //!             id: String::from("add-the-backend"),
 //!             content: RequestType::AddBackend(http_backend).into(),
+//!             sequence: None,
 //!         })
 //!         .expect("Could not send AddBackend request");
 //!
@@ -316,13 +326,21 @@ pub mod util;
 pub mod metrics;
 
 pub mod backends;
+pub mod capture;
+pub mod embedded;
 pub mod features;
 pub mod http;
 pub mod load_balancing;
+pub mod mirror;
 pub mod pool;
 pub mod protocol;
+pub mod rate_limit;
 pub mod retry;
 pub mod router;
+pub mod signing;
+pub mod sink;
+pub mod slow_log;
+pub mod sni;
 pub mod socket;
 pub mod timer;
 pub mod tls;
@@ -335,12 +353,13 @@ pub mod server;
 pub mod tcp;
 
 pub mod https;
+pub mod testing;
 
 use std::{
     cell::RefCell,
     collections::{BTreeMap, HashMap},
     fmt::{self, Display, Formatter},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     rc::Rc,
     str,
     time::{Duration, Instant},
@@ -356,13 +375,21 @@ use tls::CertificateResolverError;
 
 use sozu_command::{
     logging::{CachedTags, LogContext},
-    proto::command::{Cluster, ListenerType, RequestHttpFrontend, WorkerRequest, WorkerResponse},
+    proto::command::{
+        BackendPinning, ClientCertificateHeaders, Cluster, DuplicateHeader, ErrorCode,
+        ErrorSubsystem, Http10Options, ListenerType, ProxyStatusHeader, RequestHttpFrontend,
+        ResponseError, Timeouts, WorkerRequest, WorkerResponse,
+    },
     ready::Ready,
+    request::IpNetwork,
     state::ClusterId,
     AsStr, ObjectKind,
 };
 
-use crate::{backends::BackendMap, router::Route};
+use crate::{
+    backends::BackendMap,
+    router::{ClientTls, FrontendOptions, RequestHeaders, Route},
+};
 
 /// Anything that can be registered in mio (subscribe to kernel events)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -531,6 +558,18 @@ pub trait ListenerHandler {
     }
 
     fn set_tags(&mut self, key: String, tags: Option<BTreeMap<String, String>>);
+
+    /// true if the IPv4 clients of a dual-stack listener are seen with their IPv4 address
+    fn normalizes_ipv4_mapped(&self) -> bool;
+
+    /// the address of a client, as used in the headers, logs and rate limits
+    fn client_address(&self, address: SocketAddr) -> SocketAddr {
+        if self.normalizes_ipv4_mapped() {
+            socket::unmap_ipv4(address)
+        } else {
+            address
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -548,13 +587,55 @@ pub trait L7ListenerHandler {
 
     fn get_connect_timeout(&self) -> u32;
 
-    /// retrieve a frontend by parsing a request's hostname, uri and method
+    /// timeouts of the requests, before the overrides of their cluster and frontend
+    fn get_timeouts(&self) -> Timeouts;
+
+    /// header describing what the proxy did with a request, added to the responses
+    fn get_proxy_status(&self) -> Option<ProxyStatusHeader>;
+
+    /// how the requests of HTTP/1.0 clients are treated
+    fn get_http10_options(&self) -> Http10Options;
+
+    /// retrieve a frontend by parsing a request's hostname, uri and method,
+    /// the TLS parameters negotiated by HTTPS clients and the request headers.
+    /// The options set on the frontend, like its timeouts, are returned with its route
     fn frontend_from_request(
         &self,
         host: &str,
         uri: &str,
         method: &Method,
-    ) -> Result<Route, FrontendFromRequestError>;
+        tls: Option<&ClientTls>,
+        headers: &dyn RequestHeaders,
+    ) -> Result<(Route, FrontendOptions), FrontendFromRequestError>;
+
+    /// count a request of the client against the rate limit of the listener,
+    /// returns how long the client should wait if it is over the limit
+    fn check_request_rate(&mut self, client: IpAddr) -> Result<(), Duration>;
+
+    /// name of the header pinning the requests of the client to a backend,
+    /// if the listener trusts the client with it
+    fn get_backend_pinning_header(&self, client: IpAddr) -> Option<String>;
+
+    /// how the requests repeating a header are handled
+    fn get_duplicate_headers(&self) -> Vec<DuplicateHeader>;
+
+    /// headers forwarding the details of the client certificates, if the listener verifies them
+    fn get_client_certificate_headers(&self) -> Option<ClientCertificateHeaders>;
+}
+
+/// the trusted networks of the backend pinning of a listener. They were validated
+/// with the configuration, the invalid ones are skipped
+pub(crate) fn backend_pinning_trusted(backend_pinning: Option<&BackendPinning>) -> Vec<IpNetwork> {
+    backend_pinning
+        .map(|backend_pinning| {
+            backend_pinning
+                .trusted_networks()
+                .unwrap_or_else(|parse_error| {
+                    error!("ignoring the backend pinning networks: {}", parse_error);
+                    Vec::new()
+                })
+        })
+        .unwrap_or_default()
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -602,6 +683,12 @@ pub enum RetrieveClusterError {
     NoPath,
     #[error("unauthorized route")]
     UnauthorizedRoute,
+    #[error("request headers of {size} bytes exceed the limit of {max} bytes")]
+    HeaderSizeExceeded { size: usize, max: usize },
+    #[error("the client is over the rate limit of its {0}")]
+    RateLimited(&'static str),
+    #[error("the request was aborted by the faults injected in its cluster")]
+    FaultInjected,
     #[error("{0}")]
     RetrieveFrontend(FrontendFromRequestError),
 }
@@ -610,6 +697,8 @@ pub enum RetrieveClusterError {
 #[derive(Debug, PartialEq, Eq)]
 pub enum AcceptError {
     IoError,
+    /// the process or the system ran out of file descriptors
+    TooManyFiles,
     TooManySessions,
     WouldBlock,
     RegisterError,
@@ -653,12 +742,16 @@ pub enum ProxyError {
     },
     #[error("found no listener with address {0:?}")]
     NoListenerFound(SocketAddr),
+    #[error("found no cluster with id {0}")]
+    NoClusterFound(String),
     #[error("a listener is already present for this token")]
     ListenerAlreadyPresent,
     #[error("could not add listener: {0}")]
     AddListener(ListenerError),
     #[error("could not add cluster: {0}")]
     AddCluster(ListenerError),
+    #[error("could not update the answers of the listener: {0}")]
+    UpdateAnswers(ListenerError),
     #[error("failed to activate listener with address {address:?}: {listener_error}")]
     ListenerActivation {
         address: SocketAddr,
@@ -693,6 +786,44 @@ pub enum ProxyError {
     UnactivatedListener,
 }
 
+impl ProxyError {
+    /// the structured description of the error, sent to the main process along with its message
+    pub fn response_error(&self) -> ResponseError {
+        let error = |code| ResponseError::new(code, ErrorSubsystem::Worker);
+        match self {
+            ProxyError::SoftStop { .. }
+            | ProxyError::HardStop { .. }
+            | ProxyError::Lock(_)
+            | ProxyError::RegisterListener(_) => error(ErrorCode::InternalError),
+            ProxyError::NoListenerFound(address) => error(ErrorCode::NotFound)
+                .with_entity(&ObjectKind::Listener, address)
+                .with_hint("add the listener first"),
+            ProxyError::NoClusterFound(cluster_id) => error(ErrorCode::NotFound)
+                .with_entity(&ObjectKind::Cluster, cluster_id)
+                .with_hint("add the cluster before its frontends and backends"),
+            ProxyError::ListenerAlreadyPresent => error(ErrorCode::AlreadyExists),
+            ProxyError::AddListener(_)
+            | ProxyError::AddCluster(_)
+            | ProxyError::UpdateAnswers(_)
+            | ProxyError::WrongInputFrontend { .. }
+            | ProxyError::AddFrontend(_)
+            | ProxyError::RemoveFrontend(_) => error(ErrorCode::InvalidRequest),
+            ProxyError::ListenerActivation { address, .. }
+            | ProxyError::BindToSocket(address, _) => {
+                error(ErrorCode::AddressUnavailable).with_entity(&ObjectKind::Listener, address)
+            }
+            ProxyError::AddCertificate(_)
+            | ProxyError::RemoveCertificate(_)
+            | ProxyError::ReplaceCertificate(_)
+            | ProxyError::WrongCertificateFingerprint(_) => error(ErrorCode::InvalidCertificate),
+            ProxyError::UnsupportedMessage => error(ErrorCode::UnsupportedRequest),
+            ProxyError::UnactivatedListener => {
+                error(ErrorCode::InvalidRequest).with_hint("activate the listener first")
+            }
+        }
+    }
+}
+
 use self::server::ListenToken;
 pub trait ProxyConfiguration {
     fn notify(&mut self, message: WorkerRequest) -> WorkerResponse;
@@ -1148,112 +1279,3 @@ impl PeakEWMA {
         (active_requests + 1) as f64 * self.rtt
     }
 }
-
-pub mod testing {
-    pub use std::{cell::RefCell, os::fd::IntoRawFd, rc::Rc};
-
-    pub use anyhow::Context;
-    pub use mio::{net::UnixStream, Poll, Registry, Token};
-    pub use slab::Slab;
-    pub use sozu_command::{
-        proto::command::{
-            HttpListenerConfig, HttpsListenerConfig, ServerConfig, TcpListenerConfig,
-        },
-        scm_socket::{Listeners, ScmSocket},
-    };
-
-    pub use crate::{
-        backends::BackendMap,
-        http::HttpProxy,
-        https::HttpsProxy,
-        pool::Pool,
-        server::Server,
-        server::{ListenSession, ProxyChannel, SessionManager},
-        tcp::TcpProxy,
-        Protocol, ProxySession,
-    };
-
-    /// Everything needed to create a Server
-    pub struct ServerParts {
-        pub event_loop: Poll,
-        pub registry: Registry,
-        pub sessions: Rc<RefCell<SessionManager>>,
-        pub pool: Rc<RefCell<Pool>>,
-        pub backends: Rc<RefCell<BackendMap>>,
-        pub client_scm_socket: ScmSocket,
-        pub server_scm_socket: ScmSocket,
-        pub server_config: ServerConfig,
-    }
-
-    /// Setup a standalone server, for testing purposes
-    pub fn prebuild_server(
-        max_buffers: usize,
-        buffer_size: usize,
-        send_scm: bool,
-    ) -> anyhow::Result<ServerParts> {
-        let event_loop = Poll::new().with_context(|| "Failed at creating event loop")?;
-        let backends = Rc::new(RefCell::new(BackendMap::new()));
-        let server_config = ServerConfig {
-            max_connections: max_buffers as u64,
-            ..Default::default()
-        };
-
-        let pool = Rc::new(RefCell::new(Pool::with_capacity(
-            1,
-            max_buffers,
-            buffer_size,
-        )));
-
-        let mut sessions: Slab<Rc<RefCell<dyn ProxySession>>> = Slab::with_capacity(max_buffers);
-        {
-            let entry = sessions.vacant_entry();
-            info!("taking token {:?} for channel", entry.key());
-            entry.insert(Rc::new(RefCell::new(ListenSession {
-                protocol: Protocol::Channel,
-            })));
-        }
-        {
-            let entry = sessions.vacant_entry();
-            info!("taking token {:?} for timer", entry.key());
-            entry.insert(Rc::new(RefCell::new(ListenSession {
-                protocol: Protocol::Timer,
-            })));
-        }
-        {
-            let entry = sessions.vacant_entry();
-            info!("taking token {:?} for metrics", entry.key());
-            entry.insert(Rc::new(RefCell::new(ListenSession {
-                protocol: Protocol::Metrics,
-            })));
-        }
-        let sessions = SessionManager::new(sessions, max_buffers);
-
-        let registry = event_loop
-            .registry()
-            .try_clone()
-            .with_context(|| "Failed at creating a registry")?;
-
-        let (scm_server, scm_client) =
-            UnixStream::pair().with_context(|| "Failed at creating scm unix stream")?;
-        let client_scm_socket = ScmSocket::new(scm_client.into_raw_fd())
-            .with_context(|| "Failed at creating the scm client socket")?;
-        let server_scm_socket = ScmSocket::new(scm_server.into_raw_fd())
-            .with_context(|| "Failed at creating the scm server socket")?;
-        if send_scm {
-            client_scm_socket
-                .send_listeners(&Listeners::default())
-                .with_context(|| "Failed at sending empty listeners")?;
-        }
-
-        Ok(ServerParts {
-            event_loop,
-            registry,
-            sessions,
-            pool,
-            backends,
-            client_scm_socket,
-            server_scm_socket,
-            server_config,
-        })
-    }
-}
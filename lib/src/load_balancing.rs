@@ -1,4 +1,4 @@
-use std::{cell::RefCell, fmt::Debug, rc::Rc};
+use std::{cell::RefCell, fmt::Debug, rc::Rc, time::Instant};
 
 use rand::{
     distributions::{Distribution, WeightedIndex},
@@ -6,13 +6,34 @@ use rand::{
     thread_rng, Rng,
 };
 
+use sha2::{Digest, Sha256};
+
 use crate::{backends::Backend, sozu_command::proto::command::LoadMetric};
 
+/// points of a backend of weight 100 on the ring of the consistent hash
+const VIRTUAL_NODES: i32 = 160;
+
 pub trait LoadBalancingAlgorithm: Debug {
     fn next_available_backend(
         &mut self,
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>>;
+
+    /// choose the backend of a request from the hash of one of its values.
+    /// The algorithms that do not hash requests ignore it
+    fn next_backend_for_hash(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+        _hash: u64,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        self.next_available_backend(backends)
+    }
+
+    /// the algorithm accounts for the weights of the backends. The backends that warm
+    /// up are skipped at random for the others, that ignore the weights
+    fn weighs_backends(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
@@ -55,15 +76,10 @@ impl LoadBalancingAlgorithm for Random {
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>> {
         let mut rng = thread_rng();
+        let now = Instant::now();
         let weights: Vec<i32> = backends
             .iter()
-            .map(|b| {
-                b.borrow()
-                    .load_balancing_parameters
-                    .as_ref()
-                    .map(|p| p.weight)
-                    .unwrap_or(100)
-            })
+            .map(|b| b.borrow_mut().effective_weight(now))
             .collect();
 
         if let Ok(dist) = WeightedIndex::new(weights) {
@@ -75,6 +91,10 @@ impl LoadBalancingAlgorithm for Random {
                 .map(|backend| (*backend).clone())
         }
     }
+
+    fn weighs_backends(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Debug)]
@@ -94,10 +114,13 @@ impl LoadBalancingAlgorithm for LeastLoaded {
             LoadMetric::Requests => backends
                 .iter_mut()
                 .min_by_key(|backend| backend.borrow().active_requests),
-            LoadMetric::ConnectionTime => {
+            LoadMetric::ConnectionTime | LoadMetric::ResponseTime => {
                 let mut b = None;
                 for backend in backends.iter_mut() {
-                    let cost2 = backend.borrow_mut().peak_ewma_connection();
+                    let cost2 = match self.metric {
+                        LoadMetric::ResponseTime => backend.borrow_mut().peak_ewma_response(),
+                        _ => backend.borrow_mut().peak_ewma_connection(),
+                    };
 
                     match b.take() {
                         None => b = Some((cost2, backend)),
@@ -136,6 +159,7 @@ impl LoadBalancingAlgorithm for PowerOfTwo {
                 LoadMetric::Connections => backend.borrow().active_connections as f64,
                 LoadMetric::Requests => backend.borrow().active_requests as f64,
                 LoadMetric::ConnectionTime => backend.borrow_mut().peak_ewma_connection(),
+                LoadMetric::ResponseTime => backend.borrow_mut().peak_ewma_response(),
             };
 
             if first.is_none() {
@@ -172,13 +196,95 @@ impl LoadBalancingAlgorithm for PowerOfTwo {
     }
 }
 
+/// Places each backend on a ring at the hashes of its virtual nodes, as many as its
+/// weight allows, and sends a request to the first backend after the hash of its key.
+/// Adding or removing a backend only moves the keys of its part of the ring.
+///
+/// The hashes do not depend on the process, so that all workers agree on the backends
+#[derive(Debug, Default)]
+pub struct ConsistentHash {
+    /// ids and weights of the backends the ring was built for
+    members: Vec<(String, i32)>,
+    /// sorted points of the ring, with the index of their backend in `members`
+    ring: Vec<(u64, usize)>,
+}
+
+impl ConsistentHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn rebuild(&mut self, members: Vec<(String, i32)>) {
+        self.ring.clear();
+        for (index, (backend_id, weight)) in members.iter().enumerate() {
+            let nodes = VIRTUAL_NODES * weight.max(&0) / 100;
+            for node in 0..nodes {
+                self.ring
+                    .push((hash_value(format!("{backend_id}-{node}").as_bytes()), index));
+            }
+        }
+        self.ring.sort_unstable();
+        self.members = members;
+    }
+}
+
+impl LoadBalancingAlgorithm for ConsistentHash {
+    /// requests without a key are spread randomly on the ring
+    fn next_available_backend(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        self.next_backend_for_hash(backends, thread_rng().gen())
+    }
+
+    fn next_backend_for_hash(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+        hash: u64,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let members: Vec<(String, i32)> = backends
+            .iter()
+            .map(|b| {
+                let b = b.borrow();
+                (b.backend_id.clone(), b.weight())
+            })
+            .collect();
+        if members != self.members {
+            self.rebuild(members);
+        }
+
+        if self.ring.is_empty() {
+            return Random.next_available_backend(backends);
+        }
+
+        let point = self.ring.partition_point(|(point, _)| *point < hash) % self.ring.len();
+        backends.get(self.ring[point].1).cloned()
+    }
+
+    /// the ring keeps the configured weights, a slow start would move the keys
+    fn weighs_backends(&self) -> bool {
+        true
+    }
+}
+
+/// hash of a value of a request, or of a virtual node, on the ring of the consistent hash
+pub fn hash_value(value: &[u8]) -> u64 {
+    let digest = Sha256::digest(value);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::retry::{ExponentialBackoffPolicy, RetryPolicyWrapper};
     use crate::sozu_command::proto::command::LoadMetric;
     use crate::{backends::BackendStatus, PeakEWMA};
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
 
     fn create_backend(id: String, connections: Option<usize>) -> Backend {
         Backend {
@@ -190,9 +296,17 @@ mod test {
             active_connections: connections.unwrap_or(0),
             active_requests: 0,
             failures: 0,
+            connect_successes: 0,
+            connect_failures: 0,
             load_balancing_parameters: None,
             backup: false,
             connection_time: PeakEWMA::new(),
+            response_time: PeakEWMA::new(),
+            outcomes: None,
+            ejected_until: None,
+            failing_health_checks: false,
+            warming_since: None,
+            draining: false,
         }
     }
 
@@ -231,6 +345,61 @@ mod test {
         assert!(backend.is_none());
     }
 
+    #[test]
+    fn it_should_avoid_slow_or_busy_backends_with_response_time() {
+        let slow = Rc::new(RefCell::new(create_backend("slow".to_string(), None)));
+        let fast = Rc::new(RefCell::new(create_backend("fast".to_string(), None)));
+        slow.borrow_mut()
+            .set_response_time(Duration::from_millis(500));
+        fast.borrow_mut()
+            .set_response_time(Duration::from_millis(5));
+        let mut backends = vec![slow.clone(), fast.clone()];
+
+        let mut least_loaded = LeastLoaded {
+            metric: LoadMetric::ResponseTime,
+        };
+        let backend = least_loaded.next_available_backend(&mut backends).unwrap();
+        assert_eq!(backend.borrow().backend_id, "fast");
+
+        // the estimate is scaled by the requests waiting on the backend
+        fast.borrow_mut().active_requests = 1_000;
+        let backend = least_loaded.next_available_backend(&mut backends).unwrap();
+        assert_eq!(backend.borrow().backend_id, "slow");
+    }
+
+    #[test]
+    fn it_should_prefer_the_faster_and_less_busy_backends_with_power_of_two() {
+        let slow = Rc::new(RefCell::new(create_backend("slow".to_string(), None)));
+        let fast = Rc::new(RefCell::new(create_backend("fast".to_string(), None)));
+        let faster = Rc::new(RefCell::new(create_backend("faster".to_string(), None)));
+        slow.borrow_mut()
+            .set_response_time(Duration::from_millis(500));
+        fast.borrow_mut()
+            .set_response_time(Duration::from_millis(5));
+        faster
+            .borrow_mut()
+            .set_response_time(Duration::from_millis(1));
+        let mut backends = vec![slow.clone(), fast.clone(), faster.clone()];
+
+        // the PEAK_EWMA load balancing
+        let mut power_of_two = PowerOfTwo {
+            metric: LoadMetric::ResponseTime,
+        };
+        for _ in 0..20 {
+            let backend = power_of_two.next_available_backend(&mut backends).unwrap();
+            assert_ne!(backend.borrow().backend_id, "slow");
+        }
+
+        // the estimate is scaled by the requests waiting on the backend
+        fast.borrow_mut().active_requests = 1_000;
+        for _ in 0..20 {
+            let backend = power_of_two.next_available_backend(&mut backends).unwrap();
+            assert_ne!(backend.borrow().backend_id, "fast");
+        }
+
+        assert!(power_of_two.next_available_backend(&mut vec![]).is_none());
+    }
+
     #[test]
     fn it_should_find_backend_with_roundrobin_when_some_backends_were_removed() {
         let mut backends = vec![
@@ -248,4 +417,43 @@ mod test {
         let backend2 = roundrobin.next_available_backend(&mut backends);
         assert_eq!(backend2.as_ref(), backends.first());
     }
+
+    #[test]
+    fn it_should_only_move_the_keys_of_a_removed_backend_with_consistent_hash() {
+        let mut backends: Vec<_> = ["toto", "voto", "yoto", "zoto"]
+            .iter()
+            .map(|id| Rc::new(RefCell::new(create_backend(id.to_string(), None))))
+            .collect();
+        let mut consistent_hash = ConsistentHash::new();
+
+        let choose = |consistent_hash: &mut ConsistentHash, backends: &mut Vec<_>, key: u32| {
+            consistent_hash
+                .next_backend_for_hash(backends, hash_value(&key.to_be_bytes()))
+                .map(|backend| backend.borrow().backend_id.clone())
+                .unwrap()
+        };
+        let before: Vec<String> = (0..1000)
+            .map(|key| choose(&mut consistent_hash, &mut backends, key))
+            .collect();
+        for key in 0..1000 {
+            assert_eq!(
+                choose(&mut consistent_hash, &mut backends, key),
+                before[key as usize]
+            );
+        }
+        for id in ["toto", "voto", "yoto", "zoto"] {
+            let count = before.iter().filter(|chosen| *chosen == id).count();
+            assert!(count > 100, "{id} only got {count} keys");
+        }
+
+        backends.remove(1);
+        for key in 0..1000 {
+            let chosen = choose(&mut consistent_hash, &mut backends, key);
+            if before[key as usize] != "voto" {
+                assert_eq!(chosen, before[key as usize]);
+            } else {
+                assert_ne!(chosen, "voto");
+            }
+        }
+    }
 }
@@ -29,7 +29,7 @@ impl AggregatedMetric {
             MetricValue::Gauge(value) => Ok(AggregatedMetric::Gauge(value)),
             MetricValue::GaugeAdd(value) => Ok(AggregatedMetric::Gauge(value as usize)),
             MetricValue::Count(value) => Ok(AggregatedMetric::Count(value)),
-            MetricValue::Time(value) => {
+            MetricValue::Time(value) | MetricValue::Size(value) => {
                 let mut histogram = ::hdrhistogram::Histogram::new(3).map_err(|error| {
                     MetricError::HistogramCreation {
                         time_metric: metric.clone(),
@@ -60,7 +60,8 @@ impl AggregatedMetric {
             (&mut AggregatedMetric::Count(ref mut v1), MetricValue::Count(v2)) => {
                 *v1 += v2;
             }
-            (&mut AggregatedMetric::Time(ref mut v1), MetricValue::Time(v2)) => {
+            (&mut AggregatedMetric::Time(ref mut v1), MetricValue::Time(v2))
+            | (&mut AggregatedMetric::Time(ref mut v1), MetricValue::Size(v2)) => {
                 if let Err(e) = (*v1).record(v2 as u64) {
                     error!("could not record time metric: {:?}", e.to_string());
                 }
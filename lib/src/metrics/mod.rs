@@ -49,6 +49,8 @@ pub enum MetricValue {
     GaugeAdd(i64),
     Count(i64),
     Time(usize),
+    /// a size in bytes, aggregated in percentiles like a time
+    Size(usize),
 }
 
 impl MetricValue {
@@ -56,6 +58,10 @@ impl MetricValue {
         matches!(self, &MetricValue::Time(_))
     }
 
+    fn is_size(&self) -> bool {
+        matches!(self, &MetricValue::Size(_))
+    }
+
     fn update(&mut self, key: &'static str, m: MetricValue) -> bool {
         match (self, m) {
             (&mut MetricValue::Gauge(ref mut v1), MetricValue::Gauge(v2)) => {
@@ -129,6 +135,7 @@ impl StoredMetricValue {
 pub fn setup<O: Into<String>>(
     metrics_host: &SocketAddr,
     origin: O,
+    generation: u32,
     use_tagged_metrics: bool,
     prefix: Option<String>,
 ) -> Result<(), MetricError> {
@@ -145,6 +152,7 @@ pub fn setup<O: Into<String>>(
         }
         (*metrics.borrow_mut()).set_up_remote(metrics_socket, *metrics_host);
         (*metrics.borrow_mut()).set_up_origin(origin.into());
+        (*metrics.borrow_mut()).set_up_generation(generation);
         (*metrics.borrow_mut()).set_up_tagged_metrics(use_tagged_metrics);
     });
     Ok(())
@@ -192,6 +200,12 @@ impl Aggregator {
         }
     }
 
+    pub fn set_up_generation(&mut self, generation: u32) {
+        if let Some(n) = self.network.as_mut() {
+            n.generation = generation;
+        }
+    }
+
     pub fn set_up_tagged_metrics(&mut self, tagged: bool) {
         if let Some(n) = self.network.as_mut() {
             n.use_tagged_metrics = tagged;
@@ -314,7 +328,17 @@ macro_rules! count (
     $crate::metrics::METRICS.with(|metrics| {
       (*metrics.borrow_mut()).count_add($key, v);
     });
-  })
+  });
+  ($key:expr, $value:expr, $cluster_id:expr, $backend_id:expr) => {
+    {
+        use $crate::metrics::Subscriber;
+        let v = $value;
+
+        $crate::metrics::METRICS.with(|metrics| {
+          (*metrics.borrow_mut()).receive_metric($key, $cluster_id, $backend_id, $crate::metrics::MetricValue::Count(v));
+        });
+    }
+  }
 );
 
 /// adds 1 to a counter
@@ -344,7 +368,17 @@ macro_rules! gauge (
     $crate::metrics::METRICS.with(|metrics| {
       (*metrics.borrow_mut()).set_gauge($key, v);
     });
-  })
+  });
+  ($key:expr, $value:expr, $cluster_id:expr, $backend_id:expr) => {
+    {
+        use $crate::metrics::Subscriber;
+        let v = $value;
+
+        $crate::metrics::METRICS.with(|metrics| {
+          (*metrics.borrow_mut()).receive_metric($key, $cluster_id, $backend_id, $crate::metrics::MetricValue::Gauge(v));
+        });
+    }
+  }
 );
 
 #[macro_export]
@@ -387,6 +421,39 @@ macro_rules! time (
 
       m.receive_metric($key, Some(cluster), None, MetricValue::Time(v as usize));
     });
+  });
+  ($key:expr, $cluster_id:expr, $backend_id:expr, $value: expr) => ({
+    use $crate::metrics::{MetricValue,Subscriber};
+    let v = $value;
+    $crate::metrics::METRICS.with(|metrics| {
+      let m = &mut *metrics.borrow_mut();
+
+      m.receive_metric($key, $cluster_id, $backend_id, MetricValue::Time(v as usize));
+    });
+  })
+);
+
+/// records a size in bytes, aggregated in percentiles
+#[macro_export]
+macro_rules! size (
+  ($key:expr, $value: expr) => ({
+    use $crate::metrics::{MetricValue,Subscriber};
+    let v = $value;
+    $crate::metrics::METRICS.with(|metrics| {
+      let m = &mut *metrics.borrow_mut();
+
+      m.receive_metric($key, None, None, MetricValue::Size(v as usize));
+    });
+  });
+  ($key:expr, $cluster_id:expr, $value: expr) => ({
+    use $crate::metrics::{MetricValue,Subscriber};
+    let v = $value;
+    $crate::metrics::METRICS.with(|metrics| {
+      let m = &mut *metrics.borrow_mut();
+      let cluster: &str = $cluster_id;
+
+      m.receive_metric($key, Some(cluster), None, MetricValue::Size(v as usize));
+    });
   })
 );
 
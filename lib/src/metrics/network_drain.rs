@@ -20,8 +20,10 @@ pub struct MetricLine {
     label: &'static str,
     cluster_id: Option<String>,
     backend_id: Option<String>,
-    /// in milliseconds
-    duration: usize,
+    /// in milliseconds for a time, in bytes for a size
+    value: usize,
+    /// statsd type of the metric: `ms` for a time, `h` for a size
+    kind: &'static str,
 }
 
 /// gathers metrics and send them on a UDP socket
@@ -39,6 +41,8 @@ pub struct NetworkDrain {
     backend_metrics: HashMap<(String, String, String), StoredMetricValue>,
     pub use_tagged_metrics: bool,
     pub origin: String,
+    /// generation of the main process that started this process, sent as a tag
+    pub generation: u32,
     created: Instant,
 }
 
@@ -56,6 +60,7 @@ impl NetworkDrain {
             backend_metrics: HashMap::new(),
             use_tagged_metrics: false,
             origin: String::from("x"),
+            generation: 0,
             created: Instant::now(),
         }
     }
@@ -92,8 +97,8 @@ impl NetworkDrain {
                     MetricValue::Gauge(value) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.{},origin={},version={}:{}|g\n",
-                                self.prefix, key, self.origin, VERSION, value
+                                "{}.{},origin={},version={},generation={}:{}|g\n",
+                                self.prefix, key, self.origin, VERSION, self.generation, value
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
@@ -109,8 +114,8 @@ impl NetworkDrain {
 
                         let res = if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.{},origin={},version={}:{}|c\n",
-                                self.prefix, key, self.origin, VERSION, value
+                                "{}.{},origin={},version={},generation={}:{}|c\n",
+                                self.prefix, key, self.origin, VERSION, self.generation, value
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
@@ -176,8 +181,8 @@ impl NetworkDrain {
                     MetricValue::Gauge(value) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.cluster.{},origin={},version={},cluster_id={}:{}|g\n",
-                                self.prefix, key.1, self.origin, VERSION, key.0, value
+                                "{}.cluster.{},origin={},version={},generation={},cluster_id={}:{}|g\n",
+                                self.prefix, key.1, self.origin, VERSION, self.generation, key.0, value
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
@@ -193,8 +198,8 @@ impl NetworkDrain {
 
                         let res = if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.cluster.{},origin={},version={},cluster_id={}:{}|c\n",
-                                self.prefix, key.1, self.origin, VERSION, key.0, value
+                                "{}.cluster.{},origin={},version={},generation={},cluster_id={}:{}|c\n",
+                                self.prefix, key.1, self.origin, VERSION, self.generation, key.0, value
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
@@ -260,8 +265,8 @@ impl NetworkDrain {
                     MetricValue::Gauge(value) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.backend.{},origin={},version={},cluster_id={},backend_id={}:{}|g\n",
-                                self.prefix, key.2, self.origin, VERSION, key.0, key.1, value
+                                "{}.backend.{},origin={},version={},generation={},cluster_id={},backend_id={}:{}|g\n",
+                                self.prefix, key.2, self.origin, VERSION, self.generation, key.0, key.1, value
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
@@ -277,8 +282,8 @@ impl NetworkDrain {
 
                         let res = if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.backend.{},origin={},version={},cluster_id={},backend_id={}:{}|c\n",
-                                self.prefix, key.2, self.origin, VERSION, key.0, key.1, value
+                                "{}.backend.{},origin={},version={},generation={},cluster_id={},backend_id={}:{}|c\n",
+                                self.prefix, key.2, self.origin, VERSION, self.generation, key.0, key.1, value
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
@@ -338,49 +343,63 @@ impl NetworkDrain {
                     (Some(cluster_id), Some(backend_id)) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.backend.{},origin={},version={},cluster_id={},backend_id={}:{}|ms\n",
-                                self.prefix, metric.label, self.origin, VERSION, cluster_id, backend_id, metric.duration
+                                "{}.backend.{},origin={},version={},generation={},cluster_id={},backend_id={}:{}|{}\n",
+                                self.prefix, metric.label, self.origin, VERSION, self.generation, cluster_id, backend_id, metric.value, metric.kind
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
-                                "{}.{}.cluster.{}.backend.{}.{}:{}|ms\n",
+                                "{}.{}.cluster.{}.backend.{}.{}:{}|{}\n",
                                 self.prefix,
                                 self.origin,
                                 cluster_id,
                                 backend_id,
                                 metric.label,
-                                metric.duration
+                                metric.value,
+                                metric.kind
                             ))
                         }
                     }
                     (Some(cluster_id), None) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.cluster.{},origin={},version={},cluster_id={}:{}|ms\n",
+                                "{}.cluster.{},origin={},version={},generation={},cluster_id={}:{}|{}\n",
                                 self.prefix,
                                 metric.label,
                                 self.origin,
                                 VERSION,
+                                self.generation,
                                 cluster_id,
-                                metric.duration
+                                metric.value,
+                                metric.kind
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
-                                "{}.{}.cluster.{}.{}:{}|ms\n",
-                                self.prefix, self.origin, cluster_id, metric.label, metric.duration
+                                "{}.{}.cluster.{}.{}:{}|{}\n",
+                                self.prefix,
+                                self.origin,
+                                cluster_id,
+                                metric.label,
+                                metric.value,
+                                metric.kind
                             ))
                         }
                     }
                     (None, None) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.{},origin={},version={}:{}|ms\n",
-                                self.prefix, metric.label, self.origin, VERSION, metric.duration
+                                "{}.{},origin={},version={},generation={}:{}|{}\n",
+                                self.prefix,
+                                metric.label,
+                                self.origin,
+                                VERSION,
+                                self.generation,
+                                metric.value,
+                                metric.kind
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
-                                "{}.{}.{}:{}|ms\n",
-                                self.prefix, self.origin, metric.label, metric.duration
+                                "{}.{}.{}:{}|{}\n",
+                                self.prefix, self.origin, metric.label, metric.value, metric.kind
                             ))
                         }
                     }
@@ -423,6 +442,13 @@ impl NetworkDrain {
     }
 }
 
+/// a cluster or backend id usable in a statsd line. The backend ids generated
+/// from the configuration contain the address of the backend, and the ':' of
+/// its port or of an IPv6 address would end the metric name
+fn statsd_id(id: &str) -> String {
+    id.replace([':', '|', '@', ',', '=', '[', ']', ' '], "_")
+}
+
 impl Subscriber for NetworkDrain {
     fn receive_metric(
         &mut self,
@@ -431,22 +457,26 @@ impl Subscriber for NetworkDrain {
         backend_id: Option<&str>,
         metric: MetricValue,
     ) {
-        if metric.is_time() {
-            if let MetricValue::Time(millis) = metric {
-                self.queue.push_back(MetricLine {
-                    label: key,
-                    cluster_id: cluster_id.map(|s| s.to_string()),
-                    backend_id: backend_id.map(|s| s.to_string()),
-                    duration: millis,
-                });
-            }
+        if metric.is_time() || metric.is_size() {
+            let (value, kind) = match metric {
+                MetricValue::Size(bytes) => (bytes, "h"),
+                MetricValue::Time(millis) => (millis, "ms"),
+                _ => return,
+            };
+            self.queue.push_back(MetricLine {
+                label: key,
+                cluster_id: cluster_id.map(statsd_id),
+                backend_id: backend_id.map(statsd_id),
+                value,
+                kind,
+            });
             return;
         }
 
         match (cluster_id, backend_id) {
             (None, _) => {}
             (Some(cid), None) => {
-                let k = (String::from(cid), String::from(key));
+                let k = (statsd_id(cid), String::from(key));
                 if let Entry::Vacant(e) = self.cluster_metrics.entry(k.to_owned()) {
                     e.insert(StoredMetricValue::new(self.created, metric));
                 } else if let Some(stored_metric) = self.cluster_metrics.get_mut(&k) {
@@ -455,7 +485,7 @@ impl Subscriber for NetworkDrain {
                 return;
             }
             (Some(cid), Some(bid)) => {
-                let k = (String::from(cid), String::from(bid), String::from(key));
+                let k = (statsd_id(cid), statsd_id(bid), String::from(key));
                 if let Entry::Vacant(e) = self.backend_metrics.entry(k.to_owned()) {
                     e.insert(StoredMetricValue::new(self.created, metric));
                 } else if let Some(stored_metric) = self.backend_metrics.get_mut(&k) {
@@ -501,3 +531,52 @@ impl Subscriber for NetworkDrain {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statsd_ids_of_ipv6_backends() {
+        assert_eq!(statsd_id("app"), "app");
+        assert_eq!(
+            statsd_id("app-0-[2001:db8::1]:8080"),
+            "app-0-_2001_db8__1__8080"
+        );
+        assert_eq!(statsd_id("app-0-192.0.2.1:8080"), "app-0-192.0.2.1_8080");
+    }
+
+    #[test]
+    fn tag_the_metrics_with_the_generation() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut drain =
+            NetworkDrain::new(String::from("sozu"), socket, receiver.local_addr().unwrap());
+        drain.use_tagged_metrics = true;
+        drain.origin = String::from("WRK-01");
+        drain.generation = 2;
+
+        // the writer only sends full packets, of about 500 bytes
+        for _ in 0..10 {
+            drain.receive_metric("response_time", Some("app"), None, MetricValue::Time(12));
+        }
+        drain.send_metrics();
+        drain.remote.flush().unwrap();
+
+        let mut buffer = [0; 1024];
+        let size = receiver.recv(&mut buffer).unwrap();
+        let packet = str::from_utf8(&buffer[..size]).unwrap();
+        assert!(packet.lines().count() > 1, "{packet}");
+        for line in packet.lines() {
+            assert_eq!(
+                line,
+                format!(
+                    "sozu.cluster.response_time,origin=WRK-01,version={VERSION},generation=2,cluster_id=app:12|ms"
+                )
+            );
+        }
+    }
+}
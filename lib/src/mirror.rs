@@ -0,0 +1,129 @@
+//! Mirror of a sample of the raw requests of a frontend to a local sink
+//!
+//! A mirrored request is copied as a record made of a line
+//! `SOZU-MIRROR <request id> <unix time in ms> <cluster id> <client address> <length>`
+//! followed by `length` bytes: the request line, the headers and at most
+//! `max_body_prefix` bytes of the body, as far as they were received with the headers.
+//! Mirroring never blocks the worker, see [`crate::sink`] for how the records are written.
+
+use std::{cell::RefCell, net::SocketAddr, time::Instant};
+
+use rand::Rng;
+
+use sozu_command::proto::command::RequestMirror;
+
+use crate::sink::Sinks;
+
+thread_local! {
+  pub static MIRRORS: RefCell<Mirrors> = RefCell::new(Mirrors::default());
+}
+
+/// true for one request in `sample_one_in`, chosen at random
+pub fn sampled(mirror: &RequestMirror) -> bool {
+    mirror.sample_one_in <= 1 || rand::thread_rng().gen_range(0..mirror.sample_one_in) == 0
+}
+
+/// the part of a raw request that is mirrored: everything up to the end of
+/// the headers and at most `max_body_prefix` bytes after it.
+/// None if the headers are not complete
+pub fn mirrored_bytes(raw: &[u8], max_body_prefix: u32) -> Option<&[u8]> {
+    let header_end = memchr::memmem::find(raw, b"\r\n\r\n")? + 4;
+    let end = raw.len().min(header_end + max_body_prefix as usize);
+    Some(&raw[..end])
+}
+
+/// the record of a mirrored request
+pub fn record(
+    request_id: &str,
+    unix_time_ms: u128,
+    cluster_id: &str,
+    client: Option<SocketAddr>,
+    raw: &[u8],
+) -> Vec<u8> {
+    let client = client
+        .map(|address| address.to_string())
+        .unwrap_or_else(|| "-".to_owned());
+    let mut record = format!(
+        "SOZU-MIRROR {request_id} {unix_time_ms} {cluster_id} {client} {}\n",
+        raw.len()
+    )
+    .into_bytes();
+    record.extend_from_slice(raw);
+    record
+}
+
+#[derive(Debug, Default)]
+pub struct Mirrors {
+    /// shared by the frontends writing to the same path
+    sinks: Sinks,
+}
+
+impl Mirrors {
+    /// write the record of a sampled request to the sink of the mirror, unless the
+    /// sink already got `max_per_second` records in the current second.
+    /// `record` is only built if it is written. Returns false if it was dropped
+    pub fn write<F>(&mut self, mirror: &RequestMirror, now: Instant, record: F) -> bool
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        self.sinks.write(
+            mirror.sink(),
+            &mirror.path,
+            mirror.max_per_second,
+            now,
+            record,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command::proto::command::MirrorSink;
+    use std::time::Duration;
+
+    #[test]
+    fn cap_the_records_of_a_sink_per_second() {
+        let path = std::env::temp_dir().join(format!("sozu-mirror-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mirror = RequestMirror {
+            sink: MirrorSink::File as i32,
+            path: path.to_string_lossy().into_owned(),
+            sample_one_in: 1,
+            max_per_second: 2,
+            max_body_prefix: 4,
+        };
+        assert!(sampled(&mirror));
+
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\n\r\nbody and more";
+        let mirrored = mirrored_bytes(raw, mirror.max_body_prefix).unwrap();
+        assert_eq!(mirrored, b"POST / HTTP/1.1\r\nHost: a\r\n\r\nbody");
+        assert_eq!(mirrored_bytes(b"GET / HTTP/1.1\r\nHost", 4), None);
+
+        let mut mirrors = Mirrors::default();
+        let start = Instant::now();
+        let client = "127.0.0.1:1234".parse().ok();
+        let make = |id: &'static str| move || record(id, 1000, "cluster_1", client, mirrored);
+        assert!(mirrors.write(&mirror, start, make("a")));
+        assert!(mirrors.write(&mirror, start, make("b")));
+        assert!(
+            !mirrors.write(&mirror, start + Duration::from_millis(500), || {
+                panic!("over the rate of the sink")
+            })
+        );
+        assert!(mirrors.write(&mirror, start + Duration::from_secs(1), make("c")));
+
+        let content = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let expected: Vec<u8> = ["a", "b", "c"]
+            .iter()
+            .flat_map(|id| {
+                let mut record =
+                    format!("SOZU-MIRROR {id} 1000 cluster_1 127.0.0.1:1234 32\n").into_bytes();
+                record.extend_from_slice(mirrored);
+                record
+            })
+            .collect();
+        assert_eq!(content, expected);
+    }
+}
@@ -0,0 +1,111 @@
+//! Conversion of the HTTP/1.1 responses of the backends to HTTP/2
+
+use kawa::{AsBuffer, Block, BlockConverter, Chunk, Flags, Kawa, Pair, StatusLine, Store};
+
+use super::hpack;
+
+/// headers specific to an HTTP/1.1 connection, that HTTP/2 forbids
+const CONNECTION_HEADERS: &[&[u8]] = &[
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+    b"te",
+    b"trailer",
+];
+
+/// Encodes the status line and the headers of a response in a header block,
+/// and passes its body through. The trailers are dropped.
+#[derive(Debug, Default)]
+pub struct H2BlockConverter {
+    /// header block of the response, complete once `end_header` is set
+    pub header_block: Vec<u8>,
+    pub end_header: bool,
+    /// the status of an informational response, which is not forwarded
+    pub informational: bool,
+}
+
+impl H2BlockConverter {
+    /// prepare the converter for the next response of the stream
+    pub fn reset(&mut self) {
+        self.header_block.clear();
+        self.end_header = false;
+        self.informational = false;
+    }
+}
+
+impl<T: AsBuffer> BlockConverter<T> for H2BlockConverter {
+    fn call(&mut self, block: Block, kawa: &mut Kawa<T>) {
+        match block {
+            Block::StatusLine => {
+                if let StatusLine::Response { code, status, .. } = kawa.detached.status_line.pop() {
+                    self.informational = (100..200).contains(&code);
+                    hpack::encode_status(
+                        status.data(kawa.storage.buffer()),
+                        &mut self.header_block,
+                    );
+                }
+            }
+            Block::Cookies => kawa.detached.jar.clear(),
+            Block::Header(Pair {
+                key: Store::Empty, ..
+            }) => {
+                // elided header
+            }
+            Block::Header(Pair { key, val }) => {
+                // headers after the body are trailers
+                if self.end_header {
+                    return;
+                }
+                let buffer = kawa.storage.buffer();
+                let name = key.data(buffer).to_ascii_lowercase();
+                if CONNECTION_HEADERS.contains(&name.as_slice()) {
+                    return;
+                }
+                hpack::encode_header(&name, val.data(buffer), &mut self.header_block);
+            }
+            Block::ChunkHeader(_) => {}
+            Block::Chunk(Chunk { data }) => kawa.push_out(data),
+            Block::Flags(Flags { end_header, .. }) => {
+                self.end_header |= end_header;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::h2::hpack::Decoder;
+
+    #[test]
+    fn convert_a_chunked_response() {
+        let mut storage = b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\n".to_vec();
+        let length = storage.len();
+        storage.resize(4096, 0);
+        let mut buffer = kawa::Buffer::new(kawa::SliceBuffer(&mut storage));
+        buffer.fill(length);
+        let mut response = Kawa::new(kawa::Kind::Response, buffer);
+        kawa::h1::parse(&mut response, &mut kawa::h1::NoCallbacks);
+        assert!(response.is_terminated());
+
+        let mut converter = H2BlockConverter::default();
+        response.prepare(&mut converter);
+        assert!(converter.end_header);
+        assert!(!converter.informational);
+        assert_eq!(
+            Decoder::new().decode(&converter.header_block),
+            Ok(vec![
+                (b":status".to_vec(), b"200".to_vec()),
+                (b"content-type".to_vec(), b"text/plain".to_vec()),
+            ])
+        );
+        let body: Vec<u8> = response
+            .as_io_slice()
+            .iter()
+            .flat_map(|slice| slice.to_vec())
+            .collect();
+        assert_eq!(body, b"hello");
+    }
+}
@@ -0,0 +1,259 @@
+//! HPACK header compression (RFC 7541), on top of the `hpack` crate
+//!
+//! The crate decodes the header blocks and maintains the dynamic table the
+//! client indexes its headers in. It unwraps the dynamic table size updates
+//! and accepts them anywhere and of any size, so the structure of a block is
+//! checked before it is decoded.
+//!
+//! The encoder of the crate indexes every header in a table it can not shrink
+//! to the size the client sets. The headers are sent as literals without
+//! indexing instead, so the client's table is never used and the responses do
+//! not depend on the previous ones.
+
+use hpack::encoder::encode_integer_into;
+
+use super::parser::H2Error;
+
+/// size of the dynamic table before the client changes it, the only size accepted
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// indexes of the `:status` pseudo headers in the static table
+const STATUS_INDEXES: &[(&[u8], usize)] = &[
+    (b"200", 8),
+    (b"204", 9),
+    (b"206", 10),
+    (b"304", 11),
+    (b"400", 12),
+    (b"404", 13),
+    (b"500", 14),
+];
+
+/// a decoded header name and value
+pub type Header = (Vec<u8>, Vec<u8>);
+pub type HeaderList = Vec<Header>;
+
+pub struct Decoder {
+    decoder: hpack::Decoder<'static>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        let mut decoder = hpack::Decoder::new();
+        decoder.set_max_table_size(DEFAULT_TABLE_SIZE);
+        Decoder { decoder }
+    }
+
+    /// decode a complete header block. Any error is a COMPRESSION_ERROR,
+    /// the connection can not be used anymore since the table may be out of sync
+    pub fn decode(&mut self, block: &[u8]) -> Result<HeaderList, H2Error> {
+        check_block(block)?;
+        self.decoder
+            .decode(block)
+            .map_err(|_| H2Error::CompressionError)
+    }
+}
+
+/// Walk the representations of a header block without decoding them. The
+/// integers and string lengths must fit in the block, and the dynamic table size
+/// updates must come first and stay within the default size
+fn check_block(mut block: &[u8]) -> Result<(), H2Error> {
+    let mut first = true;
+    while let Some(&byte) = block.first() {
+        block = if byte & 0x80 != 0 {
+            // indexed header field
+            integer(block, 7)?.1
+        } else if byte & 0x40 != 0 {
+            // literal header field with incremental indexing
+            literal(block, 6)?
+        } else if byte & 0x20 != 0 {
+            // dynamic table size update, only allowed at the start of a block
+            let (size, rest) = integer(block, 5)?;
+            if !first || size > DEFAULT_TABLE_SIZE {
+                return Err(H2Error::CompressionError);
+            }
+            block = rest;
+            continue;
+        } else {
+            // literal header field without indexing or never indexed
+            literal(block, 4)?
+        };
+        first = false;
+    }
+    Ok(())
+}
+
+/// skip a literal header field, its name being indexed or a string
+fn literal(block: &[u8], prefix: u8) -> Result<&[u8], H2Error> {
+    let (index, rest) = integer(block, prefix)?;
+    let rest = if index == 0 { string(rest)? } else { rest };
+    string(rest)
+}
+
+/// skip a string, Huffman encoded or not
+fn string(block: &[u8]) -> Result<&[u8], H2Error> {
+    let (length, rest) = integer(block, 7)?;
+    rest.get(length..).ok_or(H2Error::CompressionError)
+}
+
+/// decode an integer with an N-bit prefix (RFC 7541, section 5.1)
+fn integer(block: &[u8], prefix: u8) -> Result<(usize, &[u8]), H2Error> {
+    let mask = (1u16 << prefix) as usize - 1;
+    let (first, mut rest) = block.split_first().ok_or(H2Error::CompressionError)?;
+    let mut value = *first as usize & mask;
+    if value < mask {
+        return Ok((value, rest));
+    }
+
+    let mut shift = 0;
+    loop {
+        let (byte, remaining) = rest.split_first().ok_or(H2Error::CompressionError)?;
+        rest = remaining;
+        // larger values are not needed for lengths and indexes, and could overflow
+        if shift > 21 {
+            return Err(H2Error::CompressionError);
+        }
+        value += ((*byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if *byte & 0x80 == 0 {
+            return Ok((value, rest));
+        }
+    }
+}
+
+/// encode an integer with an N-bit prefix, `flags` filling the bits before it
+fn encode_integer(value: usize, prefix: u8, flags: u8, out: &mut Vec<u8>) {
+    // writing in a vector does not fail
+    let _ = encode_integer_into(value, prefix, flags, out);
+}
+
+fn encode_string(string: &[u8], out: &mut Vec<u8>) {
+    encode_integer(string.len(), 7, 0, out);
+    out.extend_from_slice(string);
+}
+
+/// encode the `:status` pseudo header, indexed when it is in the static table
+pub fn encode_status(status: &[u8], out: &mut Vec<u8>) {
+    match STATUS_INDEXES.iter().find(|(value, _)| *value == status) {
+        Some((_, index)) => encode_integer(*index, 7, 0x80, out),
+        None => {
+            encode_integer(8, 4, 0, out);
+            encode_string(status, out);
+        }
+    }
+}
+
+/// encode a header as a literal without indexing, its name must be lowercase
+pub fn encode_header(name: &[u8], value: &[u8], out: &mut Vec<u8>) {
+    out.push(0);
+    encode_string(name, out);
+    encode_string(value, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        (name.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn decode_the_examples_of_the_rfc() {
+        // RFC 7541, C.4: requests with Huffman coding, sharing the dynamic table
+        let mut decoder = Decoder::new();
+        let first = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(
+            decoder.decode(&first),
+            Ok(vec![
+                header(":method", "GET"),
+                header(":scheme", "http"),
+                header(":path", "/"),
+                header(":authority", "www.example.com"),
+            ])
+        );
+        let second = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&second),
+            Ok(vec![
+                header(":method", "GET"),
+                header(":scheme", "http"),
+                header(":path", "/"),
+                header(":authority", "www.example.com"),
+                header("cache-control", "no-cache"),
+            ])
+        );
+        // both literals were indexed, the most recent first
+        assert_eq!(
+            decoder.decode(&[0xbe, 0xbf]),
+            Ok(vec![
+                header("cache-control", "no-cache"),
+                header(":authority", "www.example.com"),
+            ])
+        );
+    }
+
+    #[test]
+    fn reject_malformed_blocks() {
+        let mut decoder = Decoder::new();
+        // index 0
+        assert_eq!(decoder.decode(&[0x80]), Err(H2Error::CompressionError));
+        // index out of the tables
+        assert_eq!(
+            decoder.decode(&[0xbf, 0x10]),
+            Err(H2Error::CompressionError)
+        );
+        // truncated string
+        assert_eq!(
+            decoder.decode(&[0x00, 0x05, b'a']),
+            Err(H2Error::CompressionError)
+        );
+        // table size larger than the setting, and update after a header
+        assert_eq!(
+            decoder.decode(&[0x3f, 0xe2, 0x1f]),
+            Err(H2Error::CompressionError)
+        );
+        assert_eq!(
+            decoder.decode(&[0x82, 0x20]),
+            Err(H2Error::CompressionError)
+        );
+        // truncated table size update
+        assert_eq!(decoder.decode(&[0x3f]), Err(H2Error::CompressionError));
+        // endless integer
+        assert_eq!(
+            decoder.decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            Err(H2Error::CompressionError)
+        );
+    }
+
+    #[test]
+    fn encode_and_decode_responses() {
+        let mut block = Vec::new();
+        encode_status(b"404", &mut block);
+        encode_status(b"418", &mut block);
+        encode_header(b"content-type", b"text/plain", &mut block);
+        let long_value = "a".repeat(300);
+        encode_header(b"x-long", long_value.as_bytes(), &mut block);
+        assert_eq!(block[0], 0x8d);
+
+        assert_eq!(
+            Decoder::new().decode(&block),
+            Ok(vec![
+                header(":status", "404"),
+                header(":status", "418"),
+                header("content-type", "text/plain"),
+                header("x-long", &long_value),
+            ])
+        );
+    }
+}
@@ -1,460 +1,966 @@
-//FIXME: we disallow warnings for the HTTP/2 module temporarily
-#![allow(warnings)]
-use std::{cell::RefCell, net::SocketAddr, rc::Weak};
+//! HTTP/2 on the HTTPS listeners
+//!
+//! The connection state parses the frames of the client, decodes its header
+//! blocks and sends back the responses in frames. Each stream is proxied by its
+//! own HTTP/1.1 state (see the [`stream`] module), so the backends are reached
+//! over HTTP/1.1 with the routing, load balancing, retries and access logs of
+//! the HTTP/1.1 sessions.
+//!
+//! Not supported: server push, the CONNECT method and upgrades (websockets stay
+//! on HTTP/1.1 connections). The trailers of requests and responses are dropped.
+
+mod converter;
+mod hpack;
+mod parser;
+mod serializer;
+mod stream;
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    net::SocketAddr,
+    rc::{Rc, Weak},
+    time::Duration,
+};
 
-use mio::{net::TcpStream, *};
+use mio::{net::TcpStream, Token};
 use rusty_ulid::Ulid;
+use sozu_command::{config::MAX_LOOP_ITERATIONS, ready::Ready};
 
 use crate::{
-    pool::{Checkout, Pool},
+    pool::Pool,
+    protocol::{http::answers::HttpAnswers, Http, SessionState},
     socket::{SocketHandler, SocketResult},
-    sozu_command::buffer::fixed::Buffer,
-    sozu_command::ready::Ready,
-    {Protocol, Readiness, SessionMetrics, StateResult},
+    timer::TimeoutContainer,
+    L7ListenerHandler, L7Proxy, ListenerHandler, Protocol, ProxySession, Readiness,
+    SessionIsToBeClosed, SessionMetrics, SessionResult, StateResult,
 };
 
-mod parser;
-mod serializer;
-mod state;
-mod stream;
-
-type BackendToken = Token;
+use self::{
+    parser::{
+        Frame, FrameHeader, H2Error, DEFAULT_INITIAL_WINDOW_SIZE, DEFAULT_MAX_FRAME_SIZE,
+        FRAME_HEADER_SIZE, MAX_WINDOW_SIZE, PREFACE, SETTINGS_ENABLE_PUSH,
+        SETTINGS_INITIAL_WINDOW_SIZE, SETTINGS_MAX_CONCURRENT_STREAMS, SETTINGS_MAX_FRAME_SIZE,
+    },
+    stream::{ResponseCallbacks, Stream, StreamSocket},
+};
 
-#[derive(PartialEq)]
-pub enum SessionStatus {
-    Normal,
-    DefaultAnswer,
+/// streams a client can open at once
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+/// largest header block accepted in HEADERS and CONTINUATION frames
+const MAX_HEADER_BLOCK_SIZE: usize = 65_536;
+/// largest frame size a client can set
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+/// no frame is parsed nor response read while more is waiting to be written
+const WRITE_HIGH_WATER_MARK: usize = 65_536;
+/// a complete frame of the default maximum size
+const READ_BUFFER_SIZE: usize = FRAME_HEADER_SIZE + DEFAULT_MAX_FRAME_SIZE as usize;
+
+/// a header block received in a HEADERS frame and the CONTINUATION frames following it
+struct HeaderBlock {
+    stream_id: u32,
+    end_stream: bool,
+    fragment: Vec<u8>,
 }
 
-pub struct Http2<Front: SocketHandler> {
-    pub frontend: Connection<Front>,
-    backend: Option<TcpStream>,
+pub struct Http2<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> {
+    answers: Rc<RefCell<HttpAnswers>>,
+    configured_backend_timeout: Duration,
+    configured_connect_timeout: Duration,
+    configured_frontend_timeout: Duration,
+    pub container_frontend_timeout: TimeoutContainer,
+    pub frontend_readiness: Readiness,
+    pub frontend_socket: Front,
     frontend_token: Token,
-    backend_token: Option<Token>,
-    back_buf: Option<Checkout>,
-    pub cluster_id: Option<String>,
-    pub request_id: Ulid,
-    pub back_readiness: Readiness,
-    pub log_ctx: String,
-    public_address: Option<SocketAddr>,
-    pub state: Option<state::State>,
+    listener: Rc<RefCell<L>>,
     pool: Weak<RefCell<Pool>>,
+    proxy: Rc<RefCell<dyn L7Proxy>>,
+    public_address: SocketAddr,
+    session_address: Option<SocketAddr>,
+    sticky_name: String,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    preface_received: bool,
+    decoder: hpack::Decoder,
+    header_block: Option<HeaderBlock>,
+    /// highest stream identifier opened by the client
+    last_stream_id: u32,
+    /// DATA received on the connection and not given back in a WINDOW_UPDATE yet
+    connection_credit: u32,
+    /// bytes the client accepts on the connection
+    send_window: i64,
+    /// initial window of the streams, set by the client
+    initial_window_size: u32,
+    /// largest frame the client accepts
+    max_frame_size: u32,
+    goaway_sent: bool,
+    streams: BTreeMap<u32, Stream<L>>,
 }
 
-impl<Front: SocketHandler> Http2<Front> {
+impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http2<Front, L> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        frontend: Front,
+        answers: Rc<RefCell<HttpAnswers>>,
+        configured_backend_timeout: Duration,
+        configured_connect_timeout: Duration,
+        configured_frontend_timeout: Duration,
+        mut container_frontend_timeout: TimeoutContainer,
+        frontend_socket: Front,
         frontend_token: Token,
+        listener: Rc<RefCell<L>>,
         pool: Weak<RefCell<Pool>>,
-        public_address: Option<SocketAddr>,
-        client_address: Option<SocketAddr>,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+        public_address: SocketAddr,
+        session_address: Option<SocketAddr>,
         sticky_name: String,
-    ) -> Http2<Front> {
-        let request_id = Ulid::generate();
-        let log_ctx = format!("{}\tunknown\t", &request_id);
-        let (read, write) = {
-            let p0 = pool.upgrade().unwrap();
-            let mut p = p0.borrow_mut();
-            let res = (p.checkout().unwrap(), p.checkout().unwrap());
-            res
-        };
-        let session = Http2 {
-            frontend: Connection::new(frontend, read, write),
-            frontend_token,
-            backend: None,
-            backend_token: None,
-            back_buf: None,
-            cluster_id: None,
-            state: Some(state::State::new()),
-            request_id,
-            back_readiness: Readiness {
+    ) -> Http2<Front, L> {
+        // the requests have their own timeouts, the connection only closes when idle
+        container_frontend_timeout.set_duration(configured_frontend_timeout);
+
+        let mut write_buffer = Vec::new();
+        serializer::settings(
+            &mut write_buffer,
+            &[(SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS)],
+        );
+
+        Http2 {
+            answers,
+            configured_backend_timeout,
+            configured_connect_timeout,
+            configured_frontend_timeout,
+            container_frontend_timeout,
+            frontend_readiness: Readiness {
                 interest: Ready::READABLE | Ready::WRITABLE | Ready::HUP | Ready::ERROR,
                 event: Ready::EMPTY,
             },
-            log_ctx,
-            public_address,
+            frontend_socket,
+            frontend_token,
+            listener,
             pool,
-        };
-
-        trace!("created http2");
-        session
+            proxy,
+            public_address,
+            session_address,
+            sticky_name,
+            read_buffer: Vec::with_capacity(READ_BUFFER_SIZE),
+            write_buffer,
+            preface_received: false,
+            decoder: hpack::Decoder::new(),
+            header_block: None,
+            last_stream_id: 0,
+            connection_credit: 0,
+            send_window: DEFAULT_INITIAL_WINDOW_SIZE as i64,
+            initial_window_size: DEFAULT_INITIAL_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            goaway_sent: false,
+            streams: BTreeMap::new(),
+        }
     }
 
     pub fn front_socket(&self) -> &TcpStream {
-        self.frontend.socket.socket_ref()
+        self.frontend_socket.socket_ref()
     }
 
-    pub fn front_socket_mut(&mut self) -> &mut TcpStream {
-        self.frontend.socket.socket_mut()
-    }
+    /// read from the client, returns true if data was read and an error if the socket closed
+    fn read(&mut self) -> Result<bool, ()> {
+        let start = self.read_buffer.len();
+        if !self.frontend_readiness.event.is_readable() || start == READ_BUFFER_SIZE {
+            return Ok(false);
+        }
 
-    pub fn back_socket(&self) -> Option<&TcpStream> {
-        self.backend.as_ref()
-    }
+        self.read_buffer.resize(READ_BUFFER_SIZE, 0);
+        let (size, socket_state) = self
+            .frontend_socket
+            .socket_read(&mut self.read_buffer[start..]);
+        self.read_buffer.truncate(start + size);
 
-    pub fn back_socket_mut(&mut self) -> Option<&mut TcpStream> {
-        self.backend.as_mut()
+        if size > 0 {
+            self.container_frontend_timeout.reset();
+        }
+        match socket_state {
+            SocketResult::Continue => {}
+            SocketResult::WouldBlock => self.frontend_readiness.event.remove(Ready::READABLE),
+            SocketResult::Closed | SocketResult::Error => return Err(()),
+        }
+        Ok(size > 0)
     }
 
-    pub fn set_back_socket(&mut self, socket: TcpStream) {
-        self.backend = Some(socket);
+    /// write the pending frames, returns true if data was written and an error if the socket closed
+    fn flush(&mut self) -> Result<bool, ()> {
+        let mut written = 0;
+        while !self.write_buffer.is_empty() || self.frontend_socket.socket_wants_write() {
+            let (size, socket_state) = self.frontend_socket.socket_write(&self.write_buffer);
+            self.write_buffer.drain(..size);
+            written += size;
+            match socket_state {
+                SocketResult::Continue if size > 0 => {}
+                SocketResult::Continue => break,
+                SocketResult::WouldBlock => {
+                    self.frontend_readiness.event.remove(Ready::WRITABLE);
+                    break;
+                }
+                SocketResult::Closed | SocketResult::Error => return Err(()),
+            }
+        }
+        Ok(written > 0)
     }
 
-    pub fn back_token(&self) -> Option<Token> {
-        self.backend_token
-    }
+    /// parse and handle the complete frames read, the errors are connection errors
+    fn parse_frames(&mut self, session: &Rc<RefCell<dyn ProxySession>>) -> Result<bool, H2Error> {
+        let buffer = std::mem::take(&mut self.read_buffer);
+        let mut input = &buffer[..];
+
+        let result = loop {
+            if self.write_buffer.len() > WRITE_HIGH_WATER_MARK {
+                break Ok(());
+            }
+
+            if !self.preface_received {
+                if input.len() < PREFACE.len() {
+                    if !PREFACE.starts_with(input) {
+                        break Err(H2Error::ProtocolError);
+                    }
+                    break Ok(());
+                }
+                match parser::preface(input) {
+                    Ok((rest, _)) => {
+                        input = rest;
+                        self.preface_received = true;
+                        continue;
+                    }
+                    Err(_) => break Err(H2Error::ProtocolError),
+                }
+            }
 
-    pub fn log_context(&self) -> String {
-        if let Some(ref cluster_id) = self.cluster_id {
-            format!("{}\t{}\t", self.request_id, cluster_id)
-        } else {
-            format!("{}\tunknown\t", self.request_id)
+            let (rest, header) = match parser::frame_header(input) {
+                Ok(frame_header) => frame_header,
+                Err(_) => break Ok(()),
+            };
+            // the default maximum frame size is the one advertised
+            if header.payload_len > DEFAULT_MAX_FRAME_SIZE {
+                break Err(H2Error::FrameSizeError);
+            }
+            let payload_len = header.payload_len as usize;
+            if rest.len() < payload_len {
+                break Ok(());
+            }
+            let (payload, rest) = rest.split_at(payload_len);
+            input = rest;
+
+            if let Err(error) = self.handle_frame(&header, payload, session) {
+                break Err(error);
+            }
+        };
+
+        let consumed = buffer.len() - input.len();
+        self.read_buffer = buffer;
+        self.read_buffer.drain(..consumed);
+
+        if self.connection_credit > 0 {
+            serializer::window_update(&mut self.write_buffer, 0, self.connection_credit);
+            self.connection_credit = 0;
         }
+
+        result.map(|_| consumed > 0)
     }
 
-    pub fn set_back_token(&mut self, token: Token) {
-        self.backend_token = Some(token);
+    fn handle_frame(
+        &mut self,
+        header: &FrameHeader,
+        payload: &[u8],
+        session: &Rc<RefCell<dyn ProxySession>>,
+    ) -> Result<(), H2Error> {
+        let frame = parser::frame(header, payload)?;
+        trace!("H2 received {:?}", frame);
+
+        // a header block is sent in contiguous frames (RFC 9113, section 6.10)
+        if let Some(block) = &self.header_block {
+            match frame {
+                Frame::Continuation { stream_id, .. } if stream_id == block.stream_id => {}
+                _ => return Err(H2Error::ProtocolError),
+            }
+        }
+
+        match frame {
+            Frame::Data {
+                stream_id,
+                payload,
+                end_stream,
+            } => self.handle_data(stream_id, payload, header.payload_len, end_stream)?,
+            Frame::Headers {
+                stream_id,
+                fragment,
+                end_stream,
+                end_headers,
+            } => {
+                if stream_id > self.last_stream_id {
+                    if stream_id % 2 == 0 {
+                        return Err(H2Error::ProtocolError);
+                    }
+                    self.last_stream_id = stream_id;
+                } else if !self.streams.contains_key(&stream_id) {
+                    return Err(H2Error::StreamClosed);
+                }
+                self.header_block = Some(HeaderBlock {
+                    stream_id,
+                    end_stream,
+                    fragment: fragment.to_vec(),
+                });
+                if end_headers {
+                    self.complete_headers(session)?;
+                }
+            }
+            Frame::Continuation {
+                fragment,
+                end_headers,
+                ..
+            } => {
+                let block = self.header_block.as_mut().ok_or(H2Error::ProtocolError)?;
+                block.fragment.extend_from_slice(fragment);
+                if block.fragment.len() > MAX_HEADER_BLOCK_SIZE {
+                    return Err(H2Error::EnhanceYourCalm);
+                }
+                if end_headers {
+                    self.complete_headers(session)?;
+                }
+            }
+            Frame::Priority | Frame::Unknown => {}
+            Frame::RstStream { stream_id, .. } => {
+                if stream_id > self.last_stream_id {
+                    return Err(H2Error::ProtocolError);
+                }
+                self.reset_stream(stream_id, None);
+            }
+            Frame::Settings { ack: true, .. } => {}
+            Frame::Settings {
+                ack: false,
+                settings,
+            } => {
+                self.apply_settings(&settings)?;
+                serializer::settings_ack(&mut self.write_buffer);
+            }
+            Frame::Ping { ack: true, .. } => {}
+            Frame::Ping {
+                ack: false,
+                payload,
+            } => serializer::ping_ack(&mut self.write_buffer, &payload),
+            Frame::GoAway { error_code, .. } => {
+                debug!("H2 client sent GOAWAY with error code {}", error_code);
+                // the current streams are completed before closing
+                self.goaway(H2Error::NoError);
+            }
+            Frame::WindowUpdate {
+                stream_id: 0,
+                increment,
+            } => {
+                if increment == 0 {
+                    return Err(H2Error::ProtocolError);
+                }
+                self.send_window += increment as i64;
+                if self.send_window > MAX_WINDOW_SIZE as i64 {
+                    return Err(H2Error::FlowControlError);
+                }
+            }
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                let error = match self.streams.get_mut(&stream_id) {
+                    Some(_) if increment == 0 => Some(H2Error::ProtocolError),
+                    Some(stream) => {
+                        stream.send_window += increment as i64;
+                        (stream.send_window > MAX_WINDOW_SIZE as i64)
+                            .then_some(H2Error::FlowControlError)
+                    }
+                    None => None,
+                };
+                if let Some(error) = error {
+                    self.reset_stream(stream_id, Some(error));
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn front_hup(&mut self) -> StateResult {
-        StateResult::CloseSession
+    fn apply_settings(&mut self, settings: &[(u16, u32)]) -> Result<(), H2Error> {
+        for (identifier, value) in settings {
+            match *identifier {
+                SETTINGS_ENABLE_PUSH if *value > 1 => return Err(H2Error::ProtocolError),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if *value > MAX_WINDOW_SIZE {
+                        return Err(H2Error::FlowControlError);
+                    }
+                    // the change applies to the windows of the open streams
+                    let delta = *value as i64 - self.initial_window_size as i64;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE as i64 {
+                            return Err(H2Error::FlowControlError);
+                        }
+                    }
+                    self.initial_window_size = *value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE).contains(value) {
+                        return Err(H2Error::ProtocolError);
+                    }
+                    self.max_frame_size = *value;
+                }
+                // the responses are encoded without the dynamic table, and the other
+                // settings do not apply to a server
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
-    pub fn back_hup(&mut self) -> StateResult {
-        error!("todo[{}:{}]: back_hup", file!(), line!());
-        StateResult::CloseSession
-        /*
-        if self.back_buf.output_data_size() == 0 || self.back_buf.next_output_data().len() == 0 {
-          if self.back_readiness.event.is_readable() {
-            self.back_readiness().interest.insert(Ready::READABLE);
-            error!("Http2::back_hup: backend connection closed but the kernel still holds some data. readiness: {:?} -> {:?}", self.frontend.readiness, self.back_readiness);
-            SessionResult::Continue
-          } else {
-            SessionResult::CloseSession
-          }
-        } else {
-          self.frontend.readiness().interest.insert(Ready::WRITABLE);
-          if self.back_readiness.event.is_readable() {
-            self.back_readiness.interest.insert(Ready::READABLE);
-          }
-          SessionResult::Continue
-        }
-        */
+    fn handle_data(
+        &mut self,
+        stream_id: u32,
+        data: &[u8],
+        flow_controlled: u32,
+        end_stream: bool,
+    ) -> Result<(), H2Error> {
+        if stream_id > self.last_stream_id {
+            return Err(H2Error::ProtocolError);
+        }
+        // the connection window is given back right away, the streams are flow controlled
+        self.connection_credit += flow_controlled;
+
+        let error = match self.streams.get_mut(&stream_id) {
+            // DATA sent before the client received the reset of the stream
+            None => return Ok(()),
+            Some(stream) if stream.reset => return Ok(()),
+            Some(stream) if stream.end_stream_received => Some(H2Error::StreamClosed),
+            Some(stream) => {
+                stream.unacknowledged += flow_controlled;
+                if stream.unacknowledged > DEFAULT_INITIAL_WINDOW_SIZE {
+                    Some(H2Error::FlowControlError)
+                } else {
+                    stream.push_data(data, end_stream).err()
+                }
+            }
+        };
+        if let Some(error) = error {
+            self.reset_stream(stream_id, Some(error));
+        }
+        Ok(())
     }
 
-    // Read content from the session
-    pub fn readable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
-        trace!("http2 readable");
-        error!("todo[{}:{}]: readable", file!(), line!());
+    /// decode a complete header block, for a new stream or the trailers of a request
+    fn complete_headers(&mut self, session: &Rc<RefCell<dyn ProxySession>>) -> Result<(), H2Error> {
+        let HeaderBlock {
+            stream_id,
+            end_stream,
+            fragment,
+        } = match self.header_block.take() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        // the block is decoded even if the stream is refused, to keep the table in sync
+        let headers = self.decoder.decode(&fragment)?;
 
-        /* do not handle buffer pooling for now
-        if self.front_buf.is_none() {
-          if let Some(p) = self.pool.upgrade() {
-            if let Some(buf) = p.borrow_mut().checkout() {
-              self.front_buf = Some(buf);
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.reset {
+                return Ok(());
+            }
+            let result = if stream.end_stream_received || !end_stream {
+                Err(H2Error::ProtocolError)
             } else {
-              error!("cannot get front buffer from pool, closing");
-              return SessionResult::CloseSession;
+                stream.end_with_trailers()
+            };
+            if let Err(error) = result {
+                self.reset_stream(stream_id, Some(error));
             }
-          }
+            return Ok(());
         }
-        */
 
-        if self.frontend.read_buffer.available_space() == 0 {
-            if self.backend_token == None {
-                //let answer_413 = "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
-                //self.set_answer(DefaultAnswerStatus::Answer413, Rc::new(Vec::from(answer_413.as_bytes())));
-                self.frontend.readiness.interest.remove(Ready::READABLE);
-                self.frontend.readiness.interest.insert(Ready::WRITABLE);
-            } else {
-                self.frontend.readiness.interest.remove(Ready::READABLE);
-                self.back_readiness.interest.insert(Ready::WRITABLE);
-            }
-            return StateResult::Continue;
+        if self.goaway_sent {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS as usize {
+            serializer::rst_stream(&mut self.write_buffer, stream_id, H2Error::RefusedStream);
+            return Ok(());
         }
+        let (head, body, head_request) = match stream::request_head(&headers, end_stream) {
+            Ok(request) => request,
+            Err(error) => {
+                incr!("http2.requests.malformed");
+                serializer::rst_stream(&mut self.write_buffer, stream_id, error);
+                return Ok(());
+            }
+        };
 
-        let res = self.frontend.read(metrics);
+        match self.open_stream(session, stream_id, head, body, head_request, end_stream) {
+            Some(stream) => {
+                self.streams.insert(stream_id, stream);
+            }
+            None => {
+                serializer::rst_stream(&mut self.write_buffer, stream_id, H2Error::RefusedStream)
+            }
+        }
+        Ok(())
+    }
 
-        match res {
-            SocketResult::Error => {
-                let front_readiness = self.frontend.readiness.clone();
-                let back_readiness = self.back_readiness.clone();
+    fn open_stream(
+        &mut self,
+        session: &Rc<RefCell<dyn ProxySession>>,
+        id: u32,
+        head: Vec<u8>,
+        body: stream::RequestBody,
+        head_request: bool,
+        end_stream: bool,
+    ) -> Option<Stream<L>> {
+        let output = self.pool.upgrade()?.borrow_mut().checkout()?;
+        let mut socket = StreamSocket::new(&self.frontend_socket, output);
+        socket.input = head;
+
+        let token = self.proxy.borrow().add_session(session.clone());
+        let mut http = match Http::new(
+            self.answers.clone(),
+            self.configured_backend_timeout,
+            self.configured_connect_timeout,
+            self.configured_frontend_timeout,
+            TimeoutContainer::new(self.configured_frontend_timeout, token),
+            socket,
+            token,
+            self.listener.clone(),
+            self.pool.clone(),
+            Protocol::HTTPS,
+            self.public_address,
+            Ulid::generate(),
+            self.session_address,
+            self.sticky_name.clone(),
+        ) {
+            Ok(http) => http,
+            Err(error) => {
                 error!(
-                    "front socket error, closing the connection. Readiness: {:?} -> {:?}",
-                    front_readiness, back_readiness
+                    "could not create the state of HTTP/2 stream {}: {:?}",
+                    id, error
                 );
-                return StateResult::CloseSession;
+                self.proxy.borrow().remove_session(token);
+                return None;
             }
-            SocketResult::Closed => {
-                return StateResult::CloseSession;
-            }
-            SocketResult::WouldBlock => {}
-            SocketResult::Continue => {}
         };
+        http.frontend_readiness.event = Ready::READABLE | Ready::WRITABLE;
+
+        incr!("http2.streams");
+        Some(Stream {
+            id,
+            http,
+            token,
+            metrics: SessionMetrics::new(None),
+            converter: Default::default(),
+            callbacks: ResponseCallbacks { head: head_request },
+            body,
+            received: 0,
+            end_stream_received: end_stream,
+            unacknowledged: 0,
+            send_window: self.initial_window_size as i64,
+            headers_sent: false,
+            end_stream_sent: false,
+            http_closed: false,
+            reset: false,
+            dirty: true,
+        })
+    }
 
-        self.readable_parse(metrics)
+    /// stop a stream, sending RST_STREAM if the reset comes from the proxy
+    fn reset_stream(&mut self, stream_id: u32, error: Option<H2Error>) {
+        if let Some(error) = error {
+            serializer::rst_stream(&mut self.write_buffer, stream_id, error);
+        }
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.reset = true;
+            stream.dirty = true;
+            // the state logs the request as interrupted by the client
+            stream.http.frontend_readiness.event.insert(Ready::HUP);
+        }
     }
 
-    pub fn readable_parse(&mut self, metrics: &mut SessionMetrics) -> StateResult {
-        let mut state = self.state.take().unwrap();
-        let (sz, cont) = { state.parse_and_handle(self.frontend.read_buffer.data()) };
-        self.frontend.read_buffer.consume(sz);
-        self.frontend.readiness.interest = state.interest;
-        self.state = Some(state);
-
-        match cont {
-            state::FrameResult::Close => StateResult::CloseSession,
-            state::FrameResult::Continue => StateResult::Continue,
-            state::FrameResult::ConnectBackend(id) => StateResult::ConnectBackend,
-        }
-
-        /*let is_initial = unwrap_msg!(self.state.as_ref()).request == Some(RequestState::Initial);
-        // if there's no host, continue parsing until we find it
-        let has_host = unwrap_msg!(self.state.as_ref()).has_host();
-        if !has_host {
-          self.state = Some(parse_request_until_stop(unwrap_msg!(self.state.take()), &self.request_id,
-            &mut self.front_buf.as_mut().unwrap(), &self.sticky_name));
-          if unwrap_msg!(self.state.as_ref()).is_front_error() {
-            self.log_request_error(metrics, "front parsing error, closing the connection");
-            incr!("http.front_parse_errors");
-
-            // increment active requests here because it will be decremented right away
-            // when closing the connection. It's slightly easier than decrementing it
-            // at every place we return SessionResult::CloseSession
-            gauge_add!("http.active_requests", 1);
-
-            return SessionResult::CloseSession;
-          }
-        }
-        */
+    /// stop accepting streams, the open ones are completed
+    fn goaway(&mut self, error: H2Error) {
+        if !self.goaway_sent {
+            serializer::goaway(&mut self.write_buffer, self.last_stream_id, error);
+            self.goaway_sent = true;
+        }
     }
 
-    // Forward content to session
-    pub fn writable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
-        trace!("http2 writable");
-        error!("todo[{}:{}]: writable", file!(), line!());
-
-        let mut state = self.state.take().unwrap();
-        //FIXME: do that in a loop until no more frames or WouldBlock
-        match state.gen(self.frontend.write_buffer.space()) {
-            Ok(sz) => {
-                self.frontend.write_buffer.fill(sz);
-                //FIXME: use real condition here to indicate there was nothing to write
-                if sz == 0 {}
-            }
-            Err(e) => {
-                self.state = Some(state);
-                error!("error serializing to front write buffer: {:?}", e);
-                return StateResult::CloseSession;
+    fn connection_error(&mut self, error: H2Error) -> SessionResult {
+        error!("H2 connection error: {:?}", error);
+        incr!("http2.connection_errors");
+        self.goaway_sent = false;
+        self.goaway(error);
+        let _ = self.flush();
+        SessionResult::Close
+    }
+
+    /// run the states of the streams that received data or events
+    fn run_streams(
+        &mut self,
+        session: &Rc<RefCell<dyn ProxySession>>,
+        proxy: &Rc<RefCell<dyn L7Proxy>>,
+    ) -> bool {
+        let mut progress = false;
+        let in_early_data = self.frontend_socket.socket_in_early_data();
+
+        for stream in self.streams.values_mut() {
+            if stream.http_closed {
+                continue;
+            }
+            let socket = &mut stream.http.frontend_socket;
+            if socket.in_early_data && !in_early_data {
+                // the held requests can be proxied now
+                socket.in_early_data = false;
+                stream.dirty = true;
+                stream.http.frontend_readiness.event.insert(Ready::READABLE);
+            }
+            if !stream.dirty {
+                continue;
+            }
+            stream.dirty = false;
+            progress = true;
+
+            stream.metrics.service_start();
+            let session_result =
+                stream
+                    .http
+                    .ready(session.clone(), proxy.clone(), &mut stream.metrics);
+            stream.metrics.service_stop();
+
+            match session_result {
+                SessionResult::Continue => {
+                    // give back the window of the request body read by the state
+                    if !stream.end_stream_received
+                        && stream.unacknowledged > 0
+                        && stream.http.frontend_socket.input.is_empty()
+                    {
+                        serializer::window_update(
+                            &mut self.write_buffer,
+                            stream.id,
+                            stream.unacknowledged,
+                        );
+                        stream.unacknowledged = 0;
+                    }
+                }
+                SessionResult::Close | SessionResult::Upgrade => close_stream_state(stream, proxy),
             }
         }
+        progress
+    }
 
-        self.frontend.readiness.interest = state.interest;
-
-        let res = self.frontend.write(metrics);
-        match res {
-            SocketResult::Error | SocketResult::Closed => {
-                error!(
-                    "{}\t[{:?}] error writing to front socket, closing",
-                    self.log_ctx, self.frontend_token
-                );
-                incr!("http2.errors");
-                metrics.service_stop();
-                self.frontend.readiness.reset();
-                self.back_readiness.reset();
-                self.state = Some(state);
-                return StateResult::CloseSession;
+    /// send the responses written by the states, and remove the finished streams
+    fn send_responses(&mut self) -> bool {
+        let mut progress = false;
+        for stream in self.streams.values_mut() {
+            progress |= send_response(
+                stream,
+                &mut self.write_buffer,
+                &mut self.send_window,
+                self.max_frame_size as usize,
+            );
+            // the state logged the request and waits for the next one, there is none
+            if stream.end_stream_sent && !stream.http_closed {
+                close_stream_state(stream, &self.proxy);
             }
-            SocketResult::WouldBlock => {}
-            SocketResult::Continue => {}
         }
 
-        self.state = Some(state);
-        StateResult::Continue
+        let finished: Vec<u32> = self
+            .streams
+            .values()
+            .filter(|stream| stream.http_closed && (stream.end_stream_sent || stream.reset))
+            .map(|stream| stream.id)
+            .collect();
+        for id in finished {
+            if let Some(stream) = self.streams.remove(&id) {
+                // the response is complete, the rest of the request is not needed
+                if !stream.end_stream_received && !stream.reset {
+                    serializer::rst_stream(&mut self.write_buffer, id, H2Error::NoError);
+                }
+            }
+        }
+        progress
     }
+}
 
-    // Forward content to cluster
-    pub fn back_writable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
-        trace!("http2 back_writable");
-        error!("todo[{}:{}]: back_writable", file!(), line!());
-        StateResult::CloseSession
-        /*
-        if self.front_buf.output_data_size() == 0 || self.front_buf.next_output_data().len() == 0 {
-          self.frontend.readiness.interest.insert(Ready::READABLE);
-          self.back_readiness.interest.remove(Ready::WRITABLE);
-          return SessionResult::Continue;
-        }
-
-        let tokens = self.tokens().clone();
-        let output_size = self.front_buf.output_data_size();
-
-        let mut sz = 0usize;
-        let mut socket_res = SocketResult::Continue;
-
-        if let Some(ref mut backend) = self.backend {
-          while socket_res == SocketResult::Continue && self.front_buf.output_data_size() > 0 {
-            // no more data in buffer, stop here
-            if self.front_buf.next_output_data().len() == 0 {
-              self.frontend.readiness.interest.insert(Ready::READABLE);
-              self.back_readiness.interest.remove(Ready::WRITABLE);
-              return SessionResult::Continue;
-            }
-
-            let (current_sz, current_res) = backend.socket_write(self.front_buf.next_output_data());
-            socket_res = current_res;
-            self.front_buf.consume_output_data(current_sz);
-            self.front_buf_position += current_sz;
-            sz += current_sz;
-          }
-        }
-
-        metrics.backend_bout += sz;
-
-        if let Some((front,back)) = tokens {
-          debug!("{}\tBACK [{}->{}]: wrote {} bytes of {}", self.log_ctx, front.0, back.0, sz, output_size);
-        }
-        match socket_res {
-          SocketResult::Error | SocketResult::Closed => {
-            error!("{}\tback socket write error, closing connection", self.log_ctx);
-            metrics.service_stop();
-            incr!("http2.errors");
-            self.frontend.readiness.reset();
-            self.back_readiness.reset();
-            return SessionResult::CloseSession;
-          },
-          SocketResult::WouldBlock => {
-            self.back_readiness.event.remove(Ready::WRITABLE);
-
-          },
-          SocketResult::Continue => {}
-        }
-        SessionResult::Continue
-        */
-    }
+/// close the HTTP/1.1 state of a stream and release its token
+fn close_stream_state<L: ListenerHandler + L7ListenerHandler>(
+    stream: &mut Stream<L>,
+    proxy: &Rc<RefCell<dyn L7Proxy>>,
+) {
+    stream.http.cancel_timeouts();
+    stream.http.close(proxy.clone(), &mut stream.metrics);
+    proxy.borrow().remove_session(stream.token);
+    stream.http_closed = true;
+}
 
-    // Read content from cluster
-    pub fn back_readable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
-        trace!("http2 back_readable");
-        error!("todo[{}:{}]: back_readable", file!(), line!());
-        StateResult::CloseSession
-        /*
-        if self.back_buf.buffer.available_space() == 0 {
-          self.back_readiness.interest.remove(Ready::READABLE);
-          return SessionResult::Continue;
-        }
-
-        let tokens     = self.tokens().clone();
-
-        if let Some(ref mut backend) = self.backend {
-          let (sz, r) = backend.socket_read(&mut self.back_buf.buffer.space());
-          self.back_buf.buffer.fill(sz);
-          self.back_buf.sliced_input(sz);
-          self.back_buf.consume_parsed_data(sz);
-          self.back_buf.slice_output(sz);
-
-          if let Some((front,back)) = tokens {
-            debug!("{}\tBACK  [{}<-{}]: read {} bytes", self.log_ctx, front.0, back.0, sz);
-          }
-
-          if r != SocketResult::Continue || sz == 0 {
-            self.back_readiness.event.remove(Ready::READABLE);
-          }
-          if sz > 0 {
-            self.frontend.readiness.interest.insert(Ready::WRITABLE);
-            metrics.backend_bin += sz;
-          }
-
-          match r {
-            SocketResult::Error => {
-              error!("{}\tback socket read error, closing connection", self.log_ctx);
-              metrics.service_stop();
-              incr!("http2.errors");
-              self.frontend.readiness.reset();
-              self.back_readiness.reset();
-              return SessionResult::CloseSession;
-            },
-            SocketResult::Closed => {
-              metrics.service_stop();
-              self.frontend.readiness.reset();
-              self.back_readiness.reset();
-              return SessionResult::CloseSession;
-            },
-            SocketResult::WouldBlock => {
-              self.back_readiness.event.remove(Ready::READABLE);
-            },
-            SocketResult::Continue => {}
-          }
+/// translate the response written by the state of a stream in HEADERS and DATA
+/// frames, as far as the flow control windows allow
+fn send_response<L: ListenerHandler + L7ListenerHandler>(
+    stream: &mut Stream<L>,
+    out: &mut Vec<u8>,
+    connection_window: &mut i64,
+    max_frame_size: usize,
+) -> bool {
+    if stream.reset || stream.end_stream_sent {
+        return false;
+    }
+    let id = stream.id;
+    let written = out.len();
+    let mut consumed = false;
+    let response = &mut stream.http.frontend_socket.output;
+
+    while !stream.headers_sent {
+        kawa::h1::parse(response, &mut stream.callbacks);
+        if response.is_error() {
+            error!(
+                "H2 stream {}: invalid response {:?}",
+                id, response.parsing_phase
+            );
+            break;
+        }
+        response.prepare(&mut stream.converter);
+        if !stream.converter.end_header {
+            break;
+        }
+        if stream.converter.informational {
+            serializer::headers(
+                out,
+                id,
+                &stream.converter.header_block,
+                false,
+                max_frame_size,
+            );
+            // wait for the final response
+            stream.converter.reset();
+            response.clear();
+            response.consume(0);
+            continue;
         }
+        let end_stream = response.is_terminated() && response.is_completed();
+        serializer::headers(
+            out,
+            id,
+            &stream.converter.header_block,
+            end_stream,
+            max_frame_size,
+        );
+        stream.headers_sent = true;
+        stream.end_stream_sent = end_stream;
+    }
+
+    if stream.headers_sent && !stream.end_stream_sent {
+        kawa::h1::parse(response, &mut stream.callbacks);
+        response.prepare(&mut stream.converter);
+
+        // without length, the body of the response ends with the backend connection
+        let close_delimited =
+            response.body_size == kawa::BodySize::Empty && response.is_main_phase();
+        let finished = response.is_terminated() || (stream.http_closed && close_delimited);
+
+        while out.len() < WRITE_HIGH_WATER_MARK {
+            let available: usize = response.as_io_slice().iter().map(|slice| slice.len()).sum();
+            if available == 0 {
+                if finished {
+                    serializer::frame_header(
+                        out,
+                        0,
+                        parser::FrameType::Data,
+                        parser::FLAG_END_STREAM,
+                        id,
+                    );
+                    stream.end_stream_sent = true;
+                }
+                break;
+            }
 
-        SessionResult::Continue
-        */
+            let window = (*connection_window)
+                .min(stream.send_window)
+                .min(max_frame_size as i64);
+            if window <= 0 {
+                break;
+            }
+            let size = available.min(window as usize);
+            let position = serializer::data_header_placeholder(out);
+            let mut remaining = size;
+            for slice in response.as_io_slice() {
+                let length = remaining.min(slice.len());
+                out.extend_from_slice(&slice[..length]);
+                remaining -= length;
+                if remaining == 0 {
+                    break;
+                }
+            }
+            response.consume(size);
+            consumed = true;
+            *connection_window -= size as i64;
+            stream.send_window -= size as i64;
+
+            let end_stream = finished && size == available;
+            serializer::fill_data_header(out, position, id, end_stream);
+            if end_stream {
+                stream.end_stream_sent = true;
+                break;
+            }
+        }
     }
-}
 
-pub struct Connection<Socket: SocketHandler> {
-    pub socket: Socket,
-    pub readiness: Readiness,
-    pub read_buffer: Checkout,
-    pub write_buffer: Checkout,
-}
+    if consumed && !stream.http_closed {
+        // the state can write the rest of the response
+        stream.dirty = true;
+        stream.http.frontend_readiness.event.insert(Ready::WRITABLE);
+    }
 
-impl<Socket: SocketHandler> Connection<Socket> {
-    pub fn new(socket: Socket, read_buffer: Checkout, write_buffer: Checkout) -> Self {
-        Connection {
-            socket,
-            readiness: Readiness {
-                interest: Ready::READABLE | Ready::WRITABLE | Ready::HUP | Ready::ERROR,
-                event: Ready::EMPTY,
-            },
-            //FIXME: capacity can be configured
-            read_buffer,
-            //FIXME: capacity can be configured
-            write_buffer,
+    let response = &stream.http.frontend_socket.output;
+    let complete = response.is_terminated()
+        || (response.body_size == kawa::BodySize::Empty && response.is_main_phase());
+    if !stream.end_stream_sent && (response.is_error() || (stream.http_closed && !complete)) {
+        // the response will not be completed
+        serializer::rst_stream(out, id, H2Error::InternalError);
+        stream.reset = true;
+        if !stream.http_closed {
+            stream.dirty = true;
+            stream.http.frontend_readiness.event.insert(Ready::HUP);
         }
     }
 
-    pub fn read(&mut self, metrics: &mut SessionMetrics) -> SocketResult {
-        let (sz, res) = self.socket.socket_read(self.read_buffer.space());
+    consumed || out.len() > written
+}
 
-        if sz > 0 {
-            count!("bytes_in", sz as i64);
-            metrics.bin += sz;
+impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
+    for Http2<Front, L>
+{
+    fn ready(
+        &mut self,
+        session: Rc<RefCell<dyn ProxySession>>,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+        _metrics: &mut SessionMetrics,
+    ) -> SessionResult {
+        let mut counter = 0;
+
+        while counter < MAX_LOOP_ITERATIONS {
+            counter += 1;
+
+            if self.frontend_readiness.event.is_hup() || self.frontend_readiness.event.is_error() {
+                return SessionResult::Close;
+            }
 
-            self.read_buffer.fill(sz);
+            let mut progress = match self.read() {
+                Ok(progress) => progress,
+                Err(()) => return SessionResult::Close,
+            };
+            match self.parse_frames(&session) {
+                Ok(parsed) => progress |= parsed,
+                Err(error) => return self.connection_error(error),
+            }
+            progress |= self.run_streams(&session, &proxy);
+            progress |= self.send_responses();
+            match self.flush() {
+                Ok(written) => progress |= written,
+                Err(()) => return SessionResult::Close,
+            }
 
-            if self.read_buffer.available_space() == 0 {
-                self.readiness.interest.remove(Ready::READABLE);
+            if self.goaway_sent && self.streams.is_empty() && self.write_buffer.is_empty() {
+                return SessionResult::Close;
+            }
+            if !progress {
+                return SessionResult::Continue;
             }
-        } else {
-            self.readiness.event.remove(Ready::READABLE);
         }
 
-        if res == SocketResult::WouldBlock {
-            self.readiness.event.remove(Ready::READABLE);
+        error!(
+            "H2 session went through {} iterations, there's a probable infinite loop bug, closing the connection",
+            MAX_LOOP_ITERATIONS
+        );
+        incr!("http2.infinite_loop.error");
+        self.print_state("HTTPS");
+        SessionResult::Close
+    }
+
+    fn update_readiness(&mut self, token: Token, events: Ready) {
+        if self.frontend_token == token {
+            self.frontend_readiness.event |= events;
+            return;
         }
+        if let Some(stream) = self
+            .streams
+            .values_mut()
+            .find(|stream| stream.http.backend_token == Some(token))
+        {
+            stream.http.update_readiness(token, events);
+            stream.dirty = true;
+        }
+    }
 
-        res
+    fn close(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>, _metrics: &mut SessionMetrics) {
+        for stream in self.streams.values_mut() {
+            if !stream.http_closed {
+                close_stream_state(stream, &proxy);
+            }
+        }
+        self.streams.clear();
     }
 
-    pub fn write(&mut self, metrics: &mut SessionMetrics) -> SocketResult {
-        let mut sz = 0usize;
-        let mut res = SocketResult::Continue;
-        while res == SocketResult::Continue && self.write_buffer.available_data() > 0 {
-            let (current_sz, current_res) = self.socket.socket_write(self.write_buffer.data());
-            res = current_res;
-            self.write_buffer.consume(current_sz);
-            sz += current_sz;
+    fn timeout(&mut self, token: Token, _metrics: &mut SessionMetrics) -> StateResult {
+        if self.frontend_token == token {
+            self.container_frontend_timeout.triggered();
+            if self.streams.is_empty() {
+                self.goaway(H2Error::NoError);
+                let _ = self.flush();
+                return StateResult::CloseSession;
+            }
+            self.container_frontend_timeout.reset();
+            return StateResult::Continue;
         }
 
-        if sz > 0 {
-            count!("bytes_out", sz as i64);
-            metrics.bout += sz;
+        let proxy = self.proxy.clone();
+        let stream = match self.streams.values_mut().find(|stream| {
+            !stream.http_closed
+                && (stream.token == token || stream.http.backend_token == Some(token))
+        }) {
+            Some(stream) => stream,
+            None => {
+                error!("H2 got timeout for an invalid token {:?}", token);
+                return StateResult::Continue;
+            }
+        };
+
+        stream.metrics.service_start();
+        let state_result = stream.http.timeout(token, &mut stream.metrics);
+        stream.metrics.service_stop();
+        if state_result == StateResult::CloseSession {
+            close_stream_state(stream, &proxy);
+        }
+
+        self.send_responses();
+        match self.flush() {
+            Ok(_) => StateResult::Continue,
+            Err(()) => StateResult::CloseSession,
+        }
+    }
+
+    fn cancel_timeouts(&mut self) {
+        self.container_frontend_timeout.cancel();
+        for stream in self.streams.values_mut() {
+            stream.http.cancel_timeouts();
         }
+    }
 
-        if res == SocketResult::WouldBlock {
-            self.readiness.event.remove(Ready::WRITABLE);
+    fn print_state(&self, context: &str) {
+        error!(
+            "{} Session(H2)\n\tFrontend:\n\t\ttoken: {:?}\treadiness: {:?}\n\tStreams: {:?}",
+            context,
+            self.frontend_token,
+            self.frontend_readiness,
+            self.streams.keys().collect::<Vec<_>>(),
+        );
+        for stream in self.streams.values() {
+            stream.http.print_state(context);
         }
+    }
 
-        res
+    fn shutting_down(&mut self) -> SessionIsToBeClosed {
+        self.goaway(H2Error::NoError);
+        let _ = self.flush();
+        self.streams.is_empty()
     }
 }
@@ -1,16 +1,31 @@
-use std::convert::From;
+//! Parsing of the HTTP/2 frames sent by the clients (RFC 9113, section 4 and 6)
 
 use nom::{
     bytes::streaming::{tag, take},
-    combinator::{complete, map, map_opt},
-    error::{ErrorKind, ParseError},
-    multi::many0,
     number::streaming::{be_u16, be_u24, be_u32, be_u8},
-    sequence::tuple,
-    Err, HexDisplay, IResult, Offset,
+    IResult,
 };
 
-#[derive(Clone, Debug, PartialEq)]
+/// the connection preface every client starts with
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const FRAME_HEADER_SIZE: usize = 9;
+/// the largest frame payload that can be sent before the peer raises its limit
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+pub const DEFAULT_INITIAL_WINDOW_SIZE: u32 = 65_535;
+pub const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct FrameHeader {
     pub payload_len: u32,
     pub frame_type: FrameType,
@@ -18,7 +33,7 @@ pub struct FrameHeader {
     pub stream_id: u32,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FrameType {
     Data,
     Headers,
@@ -30,185 +45,136 @@ pub enum FrameType {
     GoAway,
     WindowUpdate,
     Continuation,
+    /// frames of unknown types must be ignored
+    Unknown(u8),
 }
 
-/*
-const NO_ERROR: u32 = 0x0;
-const PROTOCOL_ERROR: u32 = 0x1;
-const INTERNAL_ERROR: u32 = 0x2;
-const FLOW_CONTROL_ERROR: u32 = 0x3;
-const SETTINGS_TIMEOUT: u32 = 0x4;
-const STREAM_CLOSED: u32 = 0x5;
-const FRAME_SIZE_ERROR: u32 = 0x6;
-const REFUSED_STREAM: u32 = 0x7;
-const CANCEL: u32 = 0x8;
-const COMPRESSION_ERROR: u32 = 0x9;
-const CONNECT_ERROR: u32 = 0xa;
-const ENHANCE_YOUR_CALM: u32 = 0xb;
-const INADEQUATE_SECURITY: u32 = 0xc;
-const HTTP_1_1_REQUIRED: u32 = 0xd;
-*/
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Error<'a> {
-    pub input: &'a [u8],
-    pub error: InnerError,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum InnerError {
-    Nom(ErrorKind),
-    NoError,
-    ProtocolError,
-    InternalError,
-    FlowControlError,
-    SettingsTimeout,
-    StreamClosed,
-    FrameSizeError,
-    RefusedStream,
-    Cancel,
-    CompressionError,
-    ConnectError,
-    EnhanceYourCalm,
-    InadequateSecurity,
-    HTTP11Required,
-}
-
-impl<'a> Error<'a> {
-    pub fn new(input: &'a [u8], error: InnerError) -> Error<'a> {
-        Error { input, error }
-    }
-}
-
-impl<'a> ParseError<&'a [u8]> for Error<'a> {
-    fn from_error_kind(input: &'a [u8], kind: ErrorKind) -> Self {
-        Error {
-            input,
-            error: InnerError::Nom(kind),
-        }
-    }
-
-    fn append(input: &'a [u8], kind: ErrorKind, other: Self) -> Self {
-        Error {
-            input,
-            error: InnerError::Nom(kind),
+impl From<u8> for FrameType {
+    fn from(frame_type: u8) -> Self {
+        match frame_type {
+            0 => FrameType::Data,
+            1 => FrameType::Headers,
+            2 => FrameType::Priority,
+            3 => FrameType::RstStream,
+            4 => FrameType::Settings,
+            5 => FrameType::PushPromise,
+            6 => FrameType::Ping,
+            7 => FrameType::GoAway,
+            8 => FrameType::WindowUpdate,
+            9 => FrameType::Continuation,
+            other => FrameType::Unknown(other),
         }
     }
 }
 
-impl<'a> From<(&'a [u8], ErrorKind)> for Error<'a> {
-    fn from((input, kind): (&'a [u8], ErrorKind)) -> Self {
-        Error {
-            input,
-            error: InnerError::Nom(kind),
+impl From<FrameType> for u8 {
+    fn from(frame_type: FrameType) -> Self {
+        match frame_type {
+            FrameType::Data => 0,
+            FrameType::Headers => 1,
+            FrameType::Priority => 2,
+            FrameType::RstStream => 3,
+            FrameType::Settings => 4,
+            FrameType::PushPromise => 5,
+            FrameType::Ping => 6,
+            FrameType::GoAway => 7,
+            FrameType::WindowUpdate => 8,
+            FrameType::Continuation => 9,
+            FrameType::Unknown(other) => other,
         }
     }
 }
 
-pub fn preface(i: &[u8]) -> IResult<&[u8], &[u8]> {
-    tag(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")(i)
+/// error codes of the RST_STREAM and GOAWAY frames, some are never sent by a server
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum H2Error {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    SettingsTimeout = 0x4,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    ConnectError = 0xa,
+    EnhanceYourCalm = 0xb,
+    InadequateSecurity = 0xc,
+    Http11Required = 0xd,
 }
 
-// https://httpwg.org/specs/rfc7540.html#rfc.section.4.1
-/*named!(pub frame_header<FrameHeader>,
-  do_parse!(
-    payload_len: dbg_dmp!(be_u24) >>
-    frame_type: map_opt!(be_u8, convert_frame_type) >>
-    flags: dbg_dmp!(be_u8) >>
-    stream_id: dbg_dmp!(verify!(be_u32, |id| {
-      match frame_type {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Data {
+        stream_id: u32,
+        /// the data without the padding
+        payload: &'a [u8],
+        end_stream: bool,
+    },
+    Headers {
+        stream_id: u32,
+        fragment: &'a [u8],
+        end_stream: bool,
+        end_headers: bool,
+    },
+    Priority,
+    RstStream {
+        stream_id: u32,
+        error_code: u32,
+    },
+    Settings {
+        ack: bool,
+        settings: Vec<(u16, u32)>,
+    },
+    Ping {
+        ack: bool,
+        payload: [u8; 8],
+    },
+    GoAway {
+        last_stream_id: u32,
+        error_code: u32,
+    },
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
+    Continuation {
+        stream_id: u32,
+        fragment: &'a [u8],
+        end_headers: bool,
+    },
+    /// frame of an unknown type, to ignore
+    Unknown,
+}
 
-      }
-    }) >>
-    (FrameHeader { payload_len, frame_type, flags, stream_id })
-  )
-);
-  */
+pub fn preface(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    tag(PREFACE)(input)
+}
 
-pub fn frame_header(input: &[u8]) -> IResult<&[u8], FrameHeader, Error> {
-    let (i1, payload_len) = be_u24(input)?;
-    let (i2, frame_type) = map_opt(be_u8, convert_frame_type)(i1)?;
-    let (i3, flags) = be_u8(i2)?;
-    let (i4, stream_id) = be_u32(i3)?;
+pub fn frame_header(input: &[u8]) -> IResult<&[u8], FrameHeader> {
+    let (input, payload_len) = be_u24(input)?;
+    let (input, frame_type) = be_u8(input)?;
+    let (input, flags) = be_u8(input)?;
+    let (input, stream_id) = be_u32(input)?;
 
     Ok((
-        i4,
+        input,
         FrameHeader {
             payload_len,
-            frame_type,
+            frame_type: FrameType::from(frame_type),
             flags,
-            stream_id,
+            // the reserved bit is ignored
+            stream_id: stream_id & MAX_WINDOW_SIZE,
         },
     ))
 }
 
-fn convert_frame_type(t: u8) -> Option<FrameType> {
-    info!("got frame type: {}", t);
-    match t {
-        0 => Some(FrameType::Data),
-        1 => Some(FrameType::Headers),
-        2 => Some(FrameType::Priority),
-        3 => Some(FrameType::RstStream),
-        4 => Some(FrameType::Settings),
-        5 => Some(FrameType::PushPromise),
-        6 => Some(FrameType::Ping),
-        7 => Some(FrameType::GoAway),
-        8 => Some(FrameType::WindowUpdate),
-        9 => Some(FrameType::Continuation),
-        _ => None,
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum Frame<'a> {
-    Data(Data<'a>),
-    Headers(Headers<'a>),
-    Priority,
-    RstStream(RstStream),
-    Settings(Settings),
-    PushPromise,
-    Ping(Ping),
-    GoAway,
-    WindowUpdate(WindowUpdate),
-    Continuation,
-}
-
-impl<'a> Frame<'a> {
-    pub fn is_stream_specific(&self) -> bool {
-        match self {
-            Frame::Data(_)
-            | Frame::Headers(_)
-            | Frame::Priority
-            | Frame::RstStream(_)
-            | Frame::PushPromise
-            | Frame::Continuation => true,
-            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway => false,
-            Frame::WindowUpdate(w) => w.stream_id != 0,
-        }
-    }
-
-    pub fn stream_id(&self) -> u32 {
-        match self {
-            Frame::Data(d) => d.stream_id,
-            Frame::Headers(h) => h.stream_id,
-            Frame::Priority => unimplemented!(),
-            Frame::RstStream(r) => r.stream_id,
-            Frame::PushPromise => unimplemented!(),
-            Frame::Continuation => unimplemented!(),
-            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway => 0,
-            Frame::WindowUpdate(w) => w.stream_id,
-        }
-    }
-}
-
-pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, header) = frame_header(input)?;
-
-    info!("got frame header: {:?}", header);
-
-    if header.payload_len > max_frame_size {
-        return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
-    }
+/// parse the complete payload of a frame. The errors are connection errors
+pub fn frame<'a>(header: &FrameHeader, payload: &'a [u8]) -> Result<Frame<'a>, H2Error> {
+    let stream_id = header.stream_id;
+    let flags = header.flags;
 
     let valid_stream_id = match header.frame_type {
         FrameType::Data
@@ -216,262 +182,226 @@ pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Fram
         | FrameType::Priority
         | FrameType::RstStream
         | FrameType::PushPromise
-        | FrameType::Continuation => header.stream_id != 0,
-        FrameType::Settings | FrameType::Ping | FrameType::GoAway => header.stream_id == 0,
-        FrameType::WindowUpdate => true,
+        | FrameType::Continuation => stream_id != 0,
+        FrameType::Settings | FrameType::Ping | FrameType::GoAway => stream_id == 0,
+        FrameType::WindowUpdate | FrameType::Unknown(_) => true,
     };
-
     if !valid_stream_id {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
+        return Err(H2Error::ProtocolError);
     }
 
-    let f = match header.frame_type {
-        FrameType::Data => data_frame(i, &header)?,
-        FrameType::Headers => headers_frame(i, &header)?,
-        FrameType::Priority => {
-            if header.payload_len != 5 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
+    let frame = match header.frame_type {
+        FrameType::Data => Frame::Data {
+            stream_id,
+            payload: unpad(flags, payload)?,
+            end_stream: flags & FLAG_END_STREAM != 0,
+        },
+        FrameType::Headers => {
+            let mut fragment = unpad(flags, payload)?;
+            if flags & FLAG_PRIORITY != 0 {
+                // the stream dependency and the weight are ignored
+                fragment = fragment.get(5..).ok_or(H2Error::FrameSizeError)?;
             }
-            unimplemented!();
-        }
-        FrameType::RstStream => {
-            if header.payload_len != 4 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
+            Frame::Headers {
+                stream_id,
+                fragment,
+                end_stream: flags & FLAG_END_STREAM != 0,
+                end_headers: flags & FLAG_END_HEADERS != 0,
             }
-            rst_stream_frame(i, &header)?
-        }
-        FrameType::PushPromise => {
-            unimplemented!();
         }
-        FrameType::Continuation => {
-            unimplemented!();
+        FrameType::Priority => {
+            if payload.len() != 5 {
+                return Err(H2Error::FrameSizeError);
+            }
+            Frame::Priority
         }
+        FrameType::RstStream => Frame::RstStream {
+            stream_id,
+            error_code: fixed_u32(payload)?,
+        },
         FrameType::Settings => {
-            if header.payload_len % 6 != 0 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
+            let ack = flags & FLAG_ACK != 0;
+            if payload.len() % 6 != 0 || (ack && !payload.is_empty()) {
+                return Err(H2Error::FrameSizeError);
             }
-            settings_frame(i, &header)?
-        }
-        FrameType::Ping => {
-            if header.payload_len != 8 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
+            Frame::Settings {
+                ack,
+                settings: settings(payload)?,
             }
-            ping_frame(i, &header)?
         }
+        // clients can not push streams
+        FrameType::PushPromise => return Err(H2Error::ProtocolError),
+        FrameType::Ping => Frame::Ping {
+            ack: flags & FLAG_ACK != 0,
+            payload: payload.try_into().map_err(|_| H2Error::FrameSizeError)?,
+        },
         FrameType::GoAway => {
-            unimplemented!();
-        }
-        FrameType::WindowUpdate => {
-            if header.payload_len != 4 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
+            let (_, (last_stream_id, error_code)) =
+                go_away(payload).map_err(|_| H2Error::FrameSizeError)?;
+            Frame::GoAway {
+                last_stream_id: last_stream_id & MAX_WINDOW_SIZE,
+                error_code,
             }
-            window_update_frame(i, &header)?
         }
+        FrameType::WindowUpdate => Frame::WindowUpdate {
+            stream_id,
+            increment: fixed_u32(payload)? & MAX_WINDOW_SIZE,
+        },
+        FrameType::Continuation => Frame::Continuation {
+            stream_id,
+            fragment: payload,
+            end_headers: flags & FLAG_END_HEADERS != 0,
+        },
+        FrameType::Unknown(_) => Frame::Unknown,
     };
 
-    Ok(f)
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Data<'a> {
-    pub stream_id: u32,
-    pub payload: &'a [u8],
-    pub end_stream: bool,
+    Ok(frame)
 }
 
-pub fn data_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (remaining, i) = take(header.payload_len)(input)?;
-
-    let (i1, pad_length) = if header.flags & 0x8 != 0 {
-        let (i, pad_length) = be_u8(i)?;
-        (i, Some(pad_length))
-    } else {
-        (i, None)
-    };
-
-    if pad_length.is_some() && i1.len() <= pad_length.unwrap() as usize {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
+/// remove the padding of a DATA or HEADERS frame
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], H2Error> {
+    if flags & FLAG_PADDED == 0 {
+        return Ok(payload);
     }
-
-    let (_, payload) = take(i1.len() - pad_length.unwrap_or(0) as usize)(i1)?;
-
-    Ok((
-        remaining,
-        Frame::Data(Data {
-            stream_id: header.stream_id,
-            payload,
-            end_stream: header.flags & 0x1 != 0,
-        }),
-    ))
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Headers<'a> {
-    pub stream_id: u32,
-    pub stream_dependency: Option<StreamDependency>,
-    pub weight: Option<u8>,
-    pub header_block_fragment: &'a [u8],
-    pub end_stream: bool,
-    pub end_headers: bool,
-    pub priority: bool,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct StreamDependency {
-    pub exclusive: bool,
-    pub stream_id: u32,
-}
-
-pub fn headers_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (remaining, i) = take(header.payload_len)(input)?;
-
-    let (i1, pad_length) = if header.flags & 0x8 != 0 {
-        let (i, pad_length) = be_u8(i)?;
-        (i, Some(pad_length))
-    } else {
-        (i, None)
-    };
-
-    let (i2, stream_dependency) = if header.flags & 0x20 != 0 {
-        let (i, stream) = map(be_u32, |i| StreamDependency {
-            exclusive: i & 0x8000 != 0,
-            stream_id: i & 0x7FFF,
-        })(i1)?;
-        (i, Some(stream))
-    } else {
-        (i1, None)
-    };
-
-    let (i3, weight) = if header.flags & 0x20 != 0 {
-        let (i, weight) = be_u8(i2)?;
-        (i, Some(weight))
-    } else {
-        (i2, None)
-    };
-
-    if pad_length.is_some() && i3.len() <= pad_length.unwrap() as usize {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
+    let (input, pad_length) = be_u8::<_, ()>(payload).map_err(|_| H2Error::FrameSizeError)?;
+    let pad_length = pad_length as usize;
+    if pad_length > input.len() {
+        return Err(H2Error::ProtocolError);
     }
-
-    let (_, header_block_fragment) = take(i3.len() - pad_length.unwrap_or(0) as usize)(i3)?;
-
-    Ok((
-        remaining,
-        Frame::Headers(Headers {
-            stream_id: header.stream_id,
-            stream_dependency,
-            weight,
-            header_block_fragment,
-            end_stream: header.flags & 0x1 != 0,
-            end_headers: header.flags & 0x4 != 0,
-            priority: header.flags & 0x20 != 0,
-        }),
-    ))
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct RstStream {
-    pub stream_id: u32,
-    pub error_code: u32,
-}
-
-pub fn rst_stream_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, error_code) = be_u32(input)?;
-    Ok((
-        i,
-        Frame::RstStream(RstStream {
-            stream_id: header.stream_id,
-            error_code,
-        }),
-    ))
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Settings {
-    pub settings: Vec<Setting>,
+    Ok(&input[..input.len() - pad_length])
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct Setting {
-    pub identifier: u16,
-    pub value: u32,
-}
-
-pub fn settings_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, data) = take(header.payload_len)(input)?;
-
-    let (_, settings) = many0(map(
-        complete(tuple((be_u16, be_u32))),
-        |(identifier, value)| Setting { identifier, value },
-    ))(data)?;
-
-    Ok((i, Frame::Settings(Settings { settings })))
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Ping {
-    pub payload: [u8; 8],
+fn fixed_u32(payload: &[u8]) -> Result<u32, H2Error> {
+    if payload.len() != 4 {
+        return Err(H2Error::FrameSizeError);
+    }
+    be_u32::<_, ()>(payload)
+        .map(|(_, value)| value)
+        .map_err(|_| H2Error::FrameSizeError)
 }
 
-pub fn ping_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, data) = take(8usize)(input)?;
-
-    let mut p = Ping { payload: [0; 8] };
-
-    for i in 0..8 {
-        p.payload[i] = data[i];
+fn settings(mut payload: &[u8]) -> Result<Vec<(u16, u32)>, H2Error> {
+    let mut settings = Vec::with_capacity(payload.len() / 6);
+    while !payload.is_empty() {
+        let (input, identifier) = be_u16::<_, ()>(payload).map_err(|_| H2Error::FrameSizeError)?;
+        let (input, value) = be_u32::<_, ()>(input).map_err(|_| H2Error::FrameSizeError)?;
+        settings.push((identifier, value));
+        payload = input;
     }
-
-    Ok((i, Frame::Ping(p)))
+    Ok(settings)
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct WindowUpdate {
-    pub stream_id: u32,
-    pub increment: u32,
+fn go_away(payload: &[u8]) -> IResult<&[u8], (u32, u32)> {
+    let (input, last_stream_id) = be_u32(payload)?;
+    let (input, error_code) = be_u32(input)?;
+    // the debug data is ignored
+    let (input, _) = take(input.len())(input)?;
+    Ok((input, (last_stream_id, error_code)))
 }
 
-pub fn window_update_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, increment) = be_u32(input)?;
-    let increment = increment & 0x7FFF;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    //FIXME: if stream id is 0, trat it as connection error?
-    if increment == 0 {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
+    fn header(frame_type: FrameType, flags: u8, stream_id: u32, payload: &[u8]) -> FrameHeader {
+        FrameHeader {
+            payload_len: payload.len() as u32,
+            frame_type,
+            flags,
+            stream_id,
+        }
     }
 
-    Ok((
-        i,
-        Frame::WindowUpdate(WindowUpdate {
-            stream_id: header.stream_id,
-            increment,
-        }),
-    ))
+    #[test]
+    fn parse_frames() {
+        let input = [0, 0, 4, 8, 0, 0x80, 0, 0, 1, 0, 0, 0, 10];
+        let (payload, parsed) = frame_header(&input).unwrap();
+        assert_eq!(
+            parsed,
+            FrameHeader {
+                payload_len: 4,
+                frame_type: FrameType::WindowUpdate,
+                flags: 0,
+                stream_id: 1,
+            }
+        );
+        assert_eq!(
+            frame(&parsed, payload),
+            Ok(Frame::WindowUpdate {
+                stream_id: 1,
+                increment: 10
+            })
+        );
+
+        // padded DATA frame
+        let payload = [3, b'a', b'b', 0, 0, 0];
+        assert_eq!(
+            frame(
+                &header(FrameType::Data, FLAG_PADDED | FLAG_END_STREAM, 3, &payload),
+                &payload
+            ),
+            Ok(Frame::Data {
+                stream_id: 3,
+                payload: b"ab",
+                end_stream: true
+            })
+        );
+        let payload = [6, b'a', b'b', 0, 0, 0];
+        assert_eq!(
+            frame(&header(FrameType::Data, FLAG_PADDED, 3, &payload), &payload),
+            Err(H2Error::ProtocolError)
+        );
+
+        // HEADERS frame with a priority
+        let payload = [0x80, 0, 0, 1, 16, 0x82, 0x84];
+        assert_eq!(
+            frame(
+                &header(
+                    FrameType::Headers,
+                    FLAG_PRIORITY | FLAG_END_HEADERS,
+                    1,
+                    &payload
+                ),
+                &payload
+            ),
+            Ok(Frame::Headers {
+                stream_id: 1,
+                fragment: &[0x82, 0x84],
+                end_stream: false,
+                end_headers: true
+            })
+        );
+
+        let payload = [0, 4, 0, 0, 0xff, 0xff, 0, 3, 0, 0, 0, 100];
+        assert_eq!(
+            frame(&header(FrameType::Settings, 0, 0, &payload), &payload),
+            Ok(Frame::Settings {
+                ack: false,
+                settings: vec![(4, 65535), (3, 100)]
+            })
+        );
+        assert_eq!(
+            frame(
+                &header(FrameType::Settings, 0, 0, &payload[..5]),
+                &payload[..5]
+            ),
+            Err(H2Error::FrameSizeError)
+        );
+        assert_eq!(
+            frame(&header(FrameType::Settings, 0, 1, &payload), &payload),
+            Err(H2Error::ProtocolError)
+        );
+        assert_eq!(
+            frame(&header(FrameType::Ping, 0, 0, &[1; 7]), &[1; 7]),
+            Err(H2Error::FrameSizeError)
+        );
+        assert_eq!(
+            frame(&header(FrameType::PushPromise, 0, 1, &[]), &[]),
+            Err(H2Error::ProtocolError)
+        );
+        assert_eq!(
+            frame(&header(FrameType::Unknown(42), 0, 1, &[1, 2]), &[1, 2]),
+            Ok(Frame::Unknown)
+        );
+    }
 }
-
-#[macro_export]
-macro_rules! map_err(
-  (__impl $i:expr, $submac:ident!( $($args:tt)* ), $g:expr) => (
-    ($submac!($i, $($args)*)).map_err(|e| {
-      $g(e)
-    })
-  );
-);
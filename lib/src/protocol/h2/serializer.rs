@@ -1,37 +1,141 @@
-use cookie_factory::{
-    bytes::{be_u24, be_u32, be_u8},
-    gen,
-    sequence::tuple,
-    GenError,
+//! Serialization of the HTTP/2 frames sent to the clients
+
+use super::parser::{
+    FrameType, H2Error, FLAG_ACK, FLAG_END_HEADERS, FLAG_END_STREAM, FRAME_HEADER_SIZE,
 };
 
-use crate::protocol::h2::parser::{FrameHeader, FrameType};
-
-pub fn gen_frame_header<'a, 'b>(
-    x: (&'a mut [u8], usize),
-    frame: &'b FrameHeader,
-) -> Result<(&'a mut [u8], usize), GenError> {
-    let serializer = tuple((
-        be_u24(frame.payload_len),
-        be_u8(serialize_frame_type(&frame.frame_type)),
-        be_u8(frame.flags),
-        be_u32(frame.stream_id),
-    ));
-
-    gen(serializer, x.0).map(|(buf, sz)| (buf, sz as usize))
-}
-
-pub fn serialize_frame_type(f: &FrameType) -> u8 {
-    match *f {
-        FrameType::Data => 0,
-        FrameType::Headers => 1,
-        FrameType::Priority => 2,
-        FrameType::RstStream => 3,
-        FrameType::Settings => 4,
-        FrameType::PushPromise => 5,
-        FrameType::Ping => 6,
-        FrameType::GoAway => 7,
-        FrameType::WindowUpdate => 8,
-        FrameType::Continuation => 9,
+pub fn frame_header(
+    out: &mut Vec<u8>,
+    payload_len: usize,
+    frame_type: FrameType,
+    flags: u8,
+    stream_id: u32,
+) {
+    out.extend_from_slice(&(payload_len as u32).to_be_bytes()[1..]);
+    out.push(frame_type.into());
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+}
+
+pub fn settings(out: &mut Vec<u8>, settings: &[(u16, u32)]) {
+    frame_header(out, settings.len() * 6, FrameType::Settings, 0, 0);
+    for (identifier, value) in settings {
+        out.extend_from_slice(&identifier.to_be_bytes());
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+pub fn settings_ack(out: &mut Vec<u8>) {
+    frame_header(out, 0, FrameType::Settings, FLAG_ACK, 0);
+}
+
+pub fn ping_ack(out: &mut Vec<u8>, payload: &[u8; 8]) {
+    frame_header(out, 8, FrameType::Ping, FLAG_ACK, 0);
+    out.extend_from_slice(payload);
+}
+
+pub fn window_update(out: &mut Vec<u8>, stream_id: u32, increment: u32) {
+    frame_header(out, 4, FrameType::WindowUpdate, 0, stream_id);
+    out.extend_from_slice(&increment.to_be_bytes());
+}
+
+pub fn rst_stream(out: &mut Vec<u8>, stream_id: u32, error: H2Error) {
+    frame_header(out, 4, FrameType::RstStream, 0, stream_id);
+    out.extend_from_slice(&(error as u32).to_be_bytes());
+}
+
+pub fn goaway(out: &mut Vec<u8>, last_stream_id: u32, error: H2Error) {
+    frame_header(out, 8, FrameType::GoAway, 0, 0);
+    out.extend_from_slice(&last_stream_id.to_be_bytes());
+    out.extend_from_slice(&(error as u32).to_be_bytes());
+}
+
+/// a header block split in a HEADERS frame and as many CONTINUATION frames as needed
+pub fn headers(
+    out: &mut Vec<u8>,
+    stream_id: u32,
+    block: &[u8],
+    end_stream: bool,
+    max_frame_size: usize,
+) {
+    let mut chunks = block.chunks(max_frame_size).peekable();
+    let mut frame_type = FrameType::Headers;
+    let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+    // an empty block is still sent in a HEADERS frame
+    let first: &[u8] = chunks.next().unwrap_or_default();
+    let mut chunk = Some(first);
+    while let Some(fragment) = chunk {
+        let next = chunks.next();
+        if next.is_none() {
+            flags |= FLAG_END_HEADERS;
+        }
+        frame_header(out, fragment.len(), frame_type, flags, stream_id);
+        out.extend_from_slice(fragment);
+        frame_type = FrameType::Continuation;
+        flags = 0;
+        chunk = next;
+    }
+}
+
+/// reserve the header of a DATA frame, to write once the size of the payload is known
+pub fn data_header_placeholder(out: &mut Vec<u8>) -> usize {
+    let position = out.len();
+    out.extend_from_slice(&[0; FRAME_HEADER_SIZE]);
+    position
+}
+
+pub fn fill_data_header(out: &mut [u8], position: usize, stream_id: u32, end_stream: bool) {
+    let payload_len = out.len() - position - FRAME_HEADER_SIZE;
+    let mut header = Vec::with_capacity(FRAME_HEADER_SIZE);
+    frame_header(
+        &mut header,
+        payload_len,
+        FrameType::Data,
+        if end_stream { FLAG_END_STREAM } else { 0 },
+        stream_id,
+    );
+    out[position..position + FRAME_HEADER_SIZE].copy_from_slice(&header);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::h2::parser::{frame, frame_header as parse_header, Frame};
+
+    #[test]
+    fn serialize_frames() {
+        let mut out = Vec::new();
+        window_update(&mut out, 3, 1000);
+        let (payload, header) = parse_header(&out).unwrap();
+        assert_eq!(
+            frame(&header, payload),
+            Ok(Frame::WindowUpdate {
+                stream_id: 3,
+                increment: 1000
+            })
+        );
+
+        let mut out = Vec::new();
+        headers(&mut out, 1, &[1, 2, 3, 4, 5], true, 2);
+        let (rest, first) = parse_header(&out).unwrap();
+        assert_eq!(first.frame_type, FrameType::Headers);
+        assert_eq!(first.flags, FLAG_END_STREAM);
+        assert_eq!(first.payload_len, 2);
+        let (rest, second) = parse_header(&rest[2..]).unwrap();
+        assert_eq!(second.frame_type, FrameType::Continuation);
+        assert_eq!(second.flags, 0);
+        let (rest, third) = parse_header(&rest[2..]).unwrap();
+        assert_eq!(third.flags, FLAG_END_HEADERS);
+        assert_eq!(rest, &[5]);
+
+        let mut out = Vec::new();
+        headers(&mut out, 1, &[], false, 16384);
+        assert_eq!(out, [0, 0, 0, 1, FLAG_END_HEADERS, 0, 0, 0, 1]);
+
+        let mut out = vec![0xff];
+        let position = data_header_placeholder(&mut out);
+        out.extend_from_slice(b"hello");
+        fill_data_header(&mut out, position, 5, true);
+        assert_eq!(&out[1..], b"\0\0\x05\0\x01\0\0\0\x05hello");
     }
 }
@@ -1,102 +1,462 @@
+//! The streams of an HTTP/2 connection
+//!
+//! Each stream is proxied by an HTTP/1.1 session state reading its request from,
+//! and writing its response to, a [`StreamSocket`] in memory: the request
+//! headers are translated to HTTP/1.1 when the stream opens, its DATA frames are
+//! appended as they arrive, and the response written by the state is parsed and
+//! sent back in HEADERS and DATA frames.
+
 use std::{
-    collections::{HashMap, VecDeque},
-    str::from_utf8,
+    io::IoSlice,
+    mem::ManuallyDrop,
+    os::unix::io::{AsRawFd, FromRawFd},
 };
 
-use hpack::Decoder;
+use mio::{net::TcpStream, Token};
+use rustls::{ProtocolVersion, SupportedCipherSuite};
 
-use super::{
-    parser,
-    state::{FrameResult, OutputFrame},
+use crate::{
+    pool::Checkout,
+    protocol::{
+        h2::{converter::H2BlockConverter, hpack::HeaderList, parser::H2Error},
+        http::{parser::compare_no_case, Http},
+    },
+    router::ClientTls,
+    socket::{ClientCertificate, SocketHandler, SocketResult, TlsProperties, TransportProtocol},
+    L7ListenerHandler, ListenerHandler, SessionMetrics,
 };
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum St {
-    Init,
-    ClientPrefaceReceived,
-    ServerPrefaceSent,
+/// headers specific to an HTTP/1.1 connection, a request carrying one is malformed
+const CONNECTION_HEADERS: &[&[u8]] = &[
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+];
+
+/// owned copy of the TLS properties of the connection, for the access logs of the streams
+struct StreamTls {
+    version: ProtocolVersion,
+    cipher_suite: Option<SupportedCipherSuite>,
+    sni: Option<String>,
+    alpn: Option<String>,
+    resumed: bool,
+    client_certificate_subject: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct Stream {
-    pub id: u32,
-    pub state: StreamState,
-    pub output: VecDeque<OutputFrame>,
-    pub inbound_headers: HashMap<Vec<u8>, Vec<u8>>,
+/// The frontend of the HTTP/1.1 state of a stream
+pub struct StreamSocket {
+    /// the request, translated to HTTP/1.1, not read yet
+    pub input: Vec<u8>,
+    /// the response written by the state, parsed to be sent in frames
+    pub output: kawa::Kawa<Checkout>,
+    /// true while the request may have been received in TLS 1.3 early data
+    pub in_early_data: bool,
+    /// the frontend socket, for its addresses and round trip time. It is owned by
+    /// the connection, so it is never closed from here
+    socket: ManuallyDrop<TcpStream>,
+    protocol: TransportProtocol,
+    client_tls: Option<ClientTls>,
+    tls: Option<StreamTls>,
+    client_certificate: Option<ClientCertificate>,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum StreamState {
-    Idle,
-    ReservedLocal,
-    ReservedRemote,
-    Open,
-    HalfClosedLocal,
-    HalfClosedRemote,
-    Closed,
+impl StreamSocket {
+    pub fn new<Front: SocketHandler>(frontend: &Front, output: Checkout) -> StreamSocket {
+        // SAFETY: the streams are dropped with the connection holding the socket,
+        // and the ManuallyDrop prevents closing its file descriptor twice
+        let socket =
+            ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(frontend.socket_ref().as_raw_fd()) });
+        let tls = frontend.socket_tls_properties().map(|tls| StreamTls {
+            version: tls.version,
+            cipher_suite: tls.cipher_suite,
+            sni: tls.sni.map(ToOwned::to_owned),
+            alpn: tls.alpn.map(ToOwned::to_owned),
+            resumed: tls.resumed,
+            client_certificate_subject: tls.client_certificate_subject,
+        });
+        StreamSocket {
+            input: Vec::new(),
+            output: kawa::Kawa::new(kawa::Kind::Response, kawa::Buffer::new(output)),
+            in_early_data: frontend.socket_in_early_data(),
+            socket,
+            protocol: frontend.protocol(),
+            client_tls: frontend.socket_client_tls(),
+            tls,
+            client_certificate: frontend.socket_client_certificate(),
+        }
+    }
 }
 
-impl Stream {
-    pub fn new(id: u32) -> Stream {
-        info!("new stream with id {}", id);
+impl SocketHandler for StreamSocket {
+    fn socket_read(&mut self, buf: &mut [u8]) -> (usize, SocketResult) {
+        let size = buf.len().min(self.input.len());
+        buf[..size].copy_from_slice(&self.input[..size]);
+        self.input.drain(..size);
+        if self.input.is_empty() {
+            (size, SocketResult::WouldBlock)
+        } else {
+            (size, SocketResult::Continue)
+        }
+    }
+
+    fn socket_write(&mut self, buf: &[u8]) -> (usize, SocketResult) {
+        self.socket_write_vectored(&[IoSlice::new(buf)])
+    }
 
-        Stream {
-            id,
-            state: StreamState::Idle,
-            output: VecDeque::new(),
-            inbound_headers: HashMap::new(),
+    fn socket_write_vectored(&mut self, bufs: &[IoSlice]) -> (usize, SocketResult) {
+        let storage = &mut self.output.storage;
+        let mut size = 0;
+        for buf in bufs {
+            let space = storage.space();
+            let written = space.len().min(buf.len());
+            space[..written].copy_from_slice(&buf[..written]);
+            storage.fill(written);
+            size += written;
+            if written < buf.len() {
+                return (size, SocketResult::WouldBlock);
+            }
         }
+        (size, SocketResult::Continue)
+    }
+
+    fn socket_in_early_data(&self) -> bool {
+        self.in_early_data
+    }
+
+    fn socket_client_tls(&self) -> Option<ClientTls> {
+        self.client_tls.clone()
+    }
+
+    fn socket_client_certificate(&self) -> Option<ClientCertificate> {
+        self.client_certificate.clone()
+    }
+
+    fn socket_tls_properties(&self) -> Option<TlsProperties> {
+        self.tls.as_ref().map(|tls| TlsProperties {
+            version: tls.version,
+            cipher_suite: tls.cipher_suite,
+            sni: tls.sni.as_deref(),
+            alpn: tls.alpn.as_deref(),
+            resumed: tls.resumed,
+            client_certificate_subject: tls.client_certificate_subject.clone(),
+        })
+    }
+
+    fn socket_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    fn socket_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    fn protocol(&self) -> TransportProtocol {
+        self.protocol
     }
 
-    pub fn handle(&mut self, frame: &parser::Frame) -> FrameResult {
-        match self.state {
-            StreamState::Idle => match frame {
-                parser::Frame::Headers(h) => {
-                    let mut decoder = Decoder::new();
-                    match decoder.decode(h.header_block_fragment) {
-                        Err(e) => {
-                            error!("error decoding headers: {:?}", e);
-                            FrameResult::Close
-                        }
-                        Ok(mut h) => {
-                            let mut has_authority = false;
-                            let mut has_path = false;
-
-                            self.inbound_headers.extend(h.drain(..).map(|(k, v)| {
-                                if &k == b"authority" {
-                                    has_authority = true;
-                                }
-
-                                if &k == b"path" {
-                                    has_path = true;
-                                }
-
-                                info!("{} -> {}", from_utf8(&k).unwrap(), from_utf8(&v).unwrap());
-                                (k, v)
-                            }));
-
-                            self.state = StreamState::Open;
-                            info!("stream[{}] state is now {:?}", self.id, self.state);
-                            info!("headers: {:?}", self.inbound_headers);
-
-                            if self.inbound_headers.contains_key(&b":authority"[..])
-                                && self.inbound_headers.contains_key(&b":path"[..])
-                            {
-                                info!("will send connect_to_backend");
-                                FrameResult::ConnectBackend(self.id)
-                            } else {
-                                FrameResult::Continue
-                            }
-                        }
-                    }
+    fn read_error(&self) {}
+
+    fn write_error(&self) {}
+}
+
+/// How the body of a request is delimited once translated to HTTP/1.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestBody {
+    /// the HEADERS frame ended the stream
+    None,
+    Length(usize),
+    /// the length is unknown, the DATA frames are sent as chunks
+    Chunked,
+}
+
+/// translate the decoded headers of a request to an HTTP/1.1 request head.
+/// Malformed requests are stream errors (RFC 9113, section 8.1.1)
+pub fn request_head(
+    headers: &HeaderList,
+    end_stream: bool,
+) -> Result<(Vec<u8>, RequestBody, bool), H2Error> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut authority = None;
+    let mut path = None;
+    let mut content_length = None;
+    let mut cookies: Vec<&[u8]> = Vec::new();
+    let mut regular = Vec::new();
+
+    for (name, value) in headers {
+        if value
+            .iter()
+            .any(|byte| matches!(byte, b'\0' | b'\r' | b'\n'))
+        {
+            return Err(H2Error::ProtocolError);
+        }
+        if let Some(pseudo) = name.strip_prefix(b":") {
+            let field = match pseudo {
+                b"method" => &mut method,
+                b"scheme" => &mut scheme,
+                b"authority" => &mut authority,
+                b"path" => &mut path,
+                _ => return Err(H2Error::ProtocolError),
+            };
+            // pseudo headers come once, before the regular ones
+            if field.is_some() || !regular.is_empty() || !cookies.is_empty() {
+                return Err(H2Error::ProtocolError);
+            }
+            *field = Some(value.as_slice());
+            continue;
+        }
+
+        if name.is_empty() || !name.iter().all(|byte| is_lowercase_token(*byte)) {
+            return Err(H2Error::ProtocolError);
+        }
+        if CONNECTION_HEADERS.contains(&name.as_slice())
+            || (name == b"te" && !compare_no_case(value, b"trailers"))
+        {
+            return Err(H2Error::ProtocolError);
+        }
+        match name.as_slice() {
+            b"cookie" => cookies.push(value),
+            b"content-length" => {
+                let length = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .ok_or(H2Error::ProtocolError)?;
+                if content_length.is_some_and(|previous| previous != length) {
+                    return Err(H2Error::ProtocolError);
                 }
-                frame => {
-                    panic!("unknown frame for now: {:?}", frame);
+                content_length = Some(length);
+            }
+            // the backends are not asked to confirm the body, the client sends it anyway
+            b"te" | b"expect" | b"host" if name != b"host" || authority.is_some() => {}
+            b"host" => authority = Some(value.as_slice()),
+            _ => regular.push((name.as_slice(), value.as_slice())),
+        }
+    }
+
+    let method = method.ok_or(H2Error::ProtocolError)?;
+    // CONNECT tunnels are not supported
+    if method == b"CONNECT" || scheme.is_none() {
+        return Err(H2Error::ProtocolError);
+    }
+    let path = path
+        .filter(|path| !path.is_empty() && !path.contains(&b' '))
+        .ok_or(H2Error::ProtocolError)?;
+    let authority = authority
+        .filter(|authority| !authority.is_empty() && !authority.contains(&b' '))
+        .ok_or(H2Error::ProtocolError)?;
+    if method.is_empty() || !method.iter().all(|byte| is_token(*byte)) {
+        return Err(H2Error::ProtocolError);
+    }
+
+    let body = match (end_stream, content_length) {
+        (true, Some(length)) if length > 0 => return Err(H2Error::ProtocolError),
+        (true, _) => RequestBody::None,
+        (false, Some(length)) => RequestBody::Length(length),
+        (false, None) => RequestBody::Chunked,
+    };
+
+    let mut head = Vec::with_capacity(256);
+    head.extend_from_slice(method);
+    head.push(b' ');
+    head.extend_from_slice(path);
+    head.extend_from_slice(b" HTTP/1.1\r\nHost: ");
+    head.extend_from_slice(authority);
+    head.extend_from_slice(b"\r\n");
+    for (name, value) in regular {
+        head.extend_from_slice(name);
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value);
+        head.extend_from_slice(b"\r\n");
+    }
+    if !cookies.is_empty() {
+        head.extend_from_slice(b"Cookie: ");
+        head.extend_from_slice(&cookies.join(&b"; "[..]));
+        head.extend_from_slice(b"\r\n");
+    }
+    match body {
+        RequestBody::Length(length) => {
+            head.extend_from_slice(format!("Content-Length: {length}\r\n").as_bytes())
+        }
+        RequestBody::Chunked => head.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+        RequestBody::None => {}
+    }
+    head.extend_from_slice(b"\r\n");
+
+    Ok((head, body, method == b"HEAD"))
+}
+
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+fn is_lowercase_token(byte: u8) -> bool {
+    is_token(byte) && !byte.is_ascii_uppercase()
+}
+
+/// Terminates the responses to HEAD requests after their headers, since their
+/// Content-Length describes a body that is not sent
+pub struct ResponseCallbacks {
+    pub head: bool,
+}
+
+impl kawa::h1::ParserCallbacks<Checkout> for ResponseCallbacks {
+    fn on_headers(&mut self, kawa: &mut kawa::Kawa<Checkout>) {
+        if let kawa::StatusLine::Response { code, .. } = kawa.detached.status_line {
+            if self.head && code >= 200 {
+                kawa.parsing_phase = kawa::ParsingPhase::Terminated;
+            }
+        }
+    }
+}
+
+pub struct Stream<L: ListenerHandler + L7ListenerHandler> {
+    pub id: u32,
+    /// proxies the request translated to HTTP/1.1
+    pub http: Http<StreamSocket, L>,
+    /// token of the frontend timeout of the stream
+    pub token: Token,
+    pub metrics: SessionMetrics,
+    pub converter: H2BlockConverter,
+    pub callbacks: ResponseCallbacks,
+    pub body: RequestBody,
+    /// DATA payload received
+    pub received: usize,
+    pub end_stream_received: bool,
+    /// flow controlled bytes received and not given back to the client in a WINDOW_UPDATE
+    pub unacknowledged: u32,
+    /// bytes the client accepts on this stream
+    pub send_window: i64,
+    pub headers_sent: bool,
+    pub end_stream_sent: bool,
+    /// the state stopped, the rest of its response is sent before the stream is removed
+    pub http_closed: bool,
+    /// the stream was reset by either side, no frame is sent on it anymore
+    pub reset: bool,
+    /// something changed for the state since it last ran
+    pub dirty: bool,
+}
+
+impl<L: ListenerHandler + L7ListenerHandler> Stream<L> {
+    /// append the payload of a DATA frame to the request
+    pub fn push_data(&mut self, data: &[u8], end_stream: bool) -> Result<(), H2Error> {
+        self.received += data.len();
+        let input = &mut self.http.frontend_socket.input;
+        match self.body {
+            RequestBody::None => return Err(H2Error::StreamClosed),
+            RequestBody::Length(length) => {
+                if self.received > length || (end_stream && self.received != length) {
+                    return Err(H2Error::ProtocolError);
+                }
+                input.extend_from_slice(data);
+            }
+            RequestBody::Chunked => {
+                if !data.is_empty() {
+                    input.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+                    input.extend_from_slice(data);
+                    input.extend_from_slice(b"\r\n");
+                }
+                if end_stream {
+                    input.extend_from_slice(b"0\r\n\r\n");
                 }
-            },
-            s => {
-                unimplemented!("stream[{}] state {:?} not implemented", self.id, self.state);
             }
         }
+        if end_stream {
+            self.end_stream_received = true;
+        }
+        self.dirty = true;
+        self.http
+            .frontend_readiness
+            .event
+            .insert(crate::Ready::READABLE);
+        Ok(())
+    }
+
+    /// the request ends without DATA, with trailers that are not forwarded
+    pub fn end_with_trailers(&mut self) -> Result<(), H2Error> {
+        self.push_data(&[], true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(list: &[(&str, &str)]) -> HeaderList {
+        list.iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn translate_requests() {
+        let request = headers(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/upload?a=1"),
+            ("cookie", "a=1"),
+            ("content-type", "text/plain"),
+            ("cookie", "b=2"),
+            ("te", "trailers"),
+        ]);
+        let (head, body, head_request) = request_head(&request, false).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&head).unwrap(),
+            "POST /upload?a=1 HTTP/1.1\r\nHost: example.com\r\ncontent-type: text/plain\r\nCookie: a=1; b=2\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+        assert_eq!(body, RequestBody::Chunked);
+        assert!(!head_request);
+
+        let request = headers(&[
+            (":method", "HEAD"),
+            (":scheme", "https"),
+            (":path", "/"),
+            ("host", "example.com"),
+        ]);
+        let (head, body, head_request) = request_head(&request, true).unwrap();
+        assert_eq!(head, b"HEAD / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(body, RequestBody::None);
+        assert!(head_request);
+    }
+
+    #[test]
+    fn reject_malformed_requests() {
+        let valid = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/"),
+        ];
+        assert!(request_head(&headers(&valid), true).is_ok());
+
+        for malformed in [
+            &[(":method", "GET"), (":path", "/"), (":authority", "a")][..],
+            &[(":method", "CONNECT"), (":authority", "a:443")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/")],
+            &[(":status", "200")],
+        ] {
+            assert_eq!(
+                request_head(&headers(malformed), true),
+                Err(H2Error::ProtocolError)
+            );
+        }
+        for extra in [
+            ("Content-Type", "text/plain"),
+            ("connection", "keep-alive"),
+            ("te", "gzip"),
+            ("x-header", "a\r\nb: c"),
+            ("content-length", "12"),
+            (":path", "/again"),
+        ] {
+            let mut request = headers(&valid);
+            request.push((extra.0.as_bytes().to_vec(), extra.1.as_bytes().to_vec()));
+            assert_eq!(
+                request_head(&request, true),
+                Err(H2Error::ProtocolError),
+                "{extra:?}"
+            );
+        }
     }
 }
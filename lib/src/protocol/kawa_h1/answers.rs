@@ -307,6 +307,8 @@ pub struct ListenerAnswers {
     pub answer_408: Template,
     /// PayloadTooLarge
     pub answer_413: Template,
+    /// TooManyRequests
+    pub answer_429: Template,
     /// BadGateway
     pub answer_502: Template,
     /// ServiceUnavailable
@@ -463,6 +465,28 @@ Sozu-Id: %REQUEST_ID\r
     )
 }
 
+fn default_429() -> String {
+    String::from(
+        "\
+HTTP/1.1 429 Too Many Requests\r
+Cache-Control: no-cache\r
+Connection: close\r
+Retry-After: %RETRY_AFTER\r
+Sozu-Id: %REQUEST_ID\r
+\r
+<style>pre{background:#EEE;padding:10px;border:1px solid #AAA;border-radius: 5px;}</style>
+<h1>429 Too Many Requests</h1>
+<pre>
+{
+    \"route\": \"%ROUTE\",
+    \"request_id\": \"%REQUEST_ID\",
+}
+</pre>
+<p>Too many requests were sent from this address, retry in %RETRY_AFTER seconds.</p>
+<footer>This is an automatic answer by Sozu.</footer>",
+    )
+}
+
 fn default_502() -> String {
     String::from(
         "\
@@ -628,6 +652,12 @@ impl HttpAnswers {
             valid_in_header: true,
             typ: ReplacementType::Variable(0),
         };
+        let retry_after = TemplateVariable {
+            name: "RETRY_AFTER",
+            valid_in_body: true,
+            valid_in_header: true,
+            typ: ReplacementType::Variable(0),
+        };
 
         let location = TemplateVariable {
             name: "REDIRECT_LOCATION",
@@ -679,6 +709,11 @@ impl HttpAnswers {
                 answer,
                 &[length, route, request_id, capacity, message, phase],
             ),
+            429 => Template::new(
+                429,
+                answer,
+                &[length, route, request_id, retry_after]
+            ),
             502 => Template::new(
                 502,
                 answer,
@@ -743,6 +778,12 @@ impl HttpAnswers {
                         .and_then(|c| c.answer_413.clone())
                         .unwrap_or(default_413()),
                 )?,
+                answer_429: Self::template(
+                    429,
+                    conf.as_ref()
+                        .and_then(|c| c.answer_429.clone())
+                        .unwrap_or(default_429()),
+                )?,
                 answer_502: Self::template(
                     502,
                     conf.as_ref()
@@ -772,6 +813,16 @@ impl HttpAnswers {
         })
     }
 
+    /// replace the answers of the listener, keeping the custom answers of clusters.
+    /// Nothing is changed if one of the templates is invalid.
+    pub fn update_listener_answers(
+        &mut self,
+        conf: &Option<CustomHttpAnswers>,
+    ) -> Result<(), (u16, TemplateError)> {
+        self.listener_answers = Self::new(conf)?.listener_answers;
+        Ok(())
+    }
+
     pub fn add_custom_answer(
         &mut self,
         cluster_id: &str,
@@ -841,6 +892,15 @@ impl HttpAnswers {
                 variables_once = vec![message.into()];
                 &self.listener_answers.answer_413
             }
+            DefaultAnswer::Answer429 { retry_after } => {
+                variables = vec![
+                    route.into(),
+                    request_id.into(),
+                    retry_after.to_string().into(),
+                ];
+                variables_once = vec![];
+                &self.listener_answers.answer_429
+            }
             DefaultAnswer::Answer502 {
                 message,
                 phase,
@@ -1,17 +1,28 @@
 use std::{
+    collections::BTreeMap,
+    fmt::Write,
     net::{IpAddr, SocketAddr},
     str::{from_utf8, from_utf8_unchecked},
+    time::{Duration, Instant},
 };
 
 use rusty_ulid::Ulid;
 
 use crate::{
+    capture::CAPTURES,
     pool::Checkout,
     protocol::http::{parser::compare_no_case, GenericHttpStream, Method},
+    socket::ClientCertificate,
     Protocol,
 };
 
-use sozu_command_lib::logging::LogContext;
+use sozu_command_lib::{
+    logging::LogContext,
+    proto::command::{
+        ClientCertificateHeaders, DuplicateHeader, DuplicateHeaderPolicy, HeaderEdit,
+        HeaderEditKind, HeaderPosition, Http10Options, ProxyStatusHeader, SlowLog,
+    },
+};
 
 /// This is the container used to store and use information about the session from within a Kawa parser callback
 #[derive(Debug)]
@@ -23,6 +34,8 @@ pub struct HttpContext {
     pub keep_alive_frontend: bool,
     /// the value of the sticky session cookie in the request
     pub sticky_session_found: Option<String>,
+    /// set if the request line has the HTTP/1.0 version
+    pub http10: bool,
     // ---------- Status Line
     /// the value of the method in the request line
     pub method: Option<Method>,
@@ -36,6 +49,28 @@ pub struct HttpContext {
     pub reason: Option<String>,
     // ---------- Additional optional data
     pub user_agent: Option<String>,
+    /// time spent editing the headers of the request, counted in the filter time budget
+    pub header_edit_time: Duration,
+    /// number of failed connection attempts to the backends before forwarding the request
+    pub retries: u8,
+    /// the header edits of the frontend changed the request or the response
+    pub rewritten: bool,
+    /// bytes of the request body forwarded to the backend, without the chunk headers
+    pub request_body_size: usize,
+    /// bytes of the response body forwarded to the client, without the chunk headers
+    pub response_body_size: usize,
+    /// headers of the request recorded for the running debug captures, lowercased name -> value
+    pub captured_request_headers: BTreeMap<String, String>,
+    /// headers of the response recorded for the running debug captures, lowercased name -> value
+    pub captured_response_headers: BTreeMap<String, String>,
+    /// id of the backend named in the backend pinning header of the request
+    pub pinned_backend: Option<String>,
+    /// set once the request is routed to a cluster. A retry routes it again to connect
+    /// to another backend, and skips what is done once per request
+    pub routed: bool,
+    /// set if the response switches the protocol to WebSocket: a 101 status with an
+    /// "Upgrade" header with a "websocket" value
+    pub websocket: bool,
 
     // ========== Read only
     /// signals wether Kawa should write a "Connection" header with a "close" value (request and response)
@@ -55,12 +90,52 @@ pub struct HttpContext {
     /// the sticky session that should be used
     /// used to create a "Set-Cookie" header in the response in case it differs from sticky_session_found
     pub sticky_session: Option<String>,
+    /// signals that the request was received in TLS 1.3 early data, before the end of the handshake
+    /// Kawa should write an "Early-Data" header in the request if its method is idempotent
+    pub early_data: bool,
+    /// header describing what the proxy did with the request, that Kawa should write in the response
+    pub proxy_status: Option<ProxyStatusHeader>,
+    /// address of the backend the request was sent to
+    pub backend_address: Option<SocketAddr>,
+    /// how the listener treats the requests of HTTP/1.0 clients
+    pub http10_options: Http10Options,
+    /// the value of the "Strict-Transport-Security" header Kawa should write in the response,
+    /// set when the cluster of an HTTPS request has an HTTPS policy
+    pub strict_transport_security: Option<String>,
+    /// maximum size of the response body, set by the cluster of the request
+    pub max_response_body_size: Option<usize>,
+    /// how long the response body may be held to coalesce its writes, set by the cluster
+    pub response_flush_delay: Option<Duration>,
+    /// how long the request is held before it is written to the backend, drawn from
+    /// the faults injected in the cluster
+    pub fault_delay: Option<Duration>,
+    /// when the request was fully written to the backend. The response time of the
+    /// backend goes from there to the first byte of its response
+    pub request_sent: Option<Instant>,
+    /// where the request is logged if it is slow, set by the cluster
+    pub slow_log: Option<SlowLog>,
+    /// edits of the headers Kawa should apply to the response, set by the frontend
+    pub header_edits: Vec<HeaderEdit>,
+    /// the header Kawa should read from the request, and remove, to pin it to a backend.
+    /// Only set for the clients the listener trusts with it
+    pub backend_pinning_header: Option<String>,
+    /// policies of the listener for the headers repeated in a request
+    pub duplicate_headers: Vec<DuplicateHeader>,
+    /// headers Kawa should replace with the details of the client certificate.
+    /// Only set on the listeners verifying the client certificates
+    pub client_certificate_headers: Option<ClientCertificateHeaders>,
+    /// certificate the client authenticated with
+    pub client_certificate: Option<ClientCertificate>,
 }
 
 impl kawa::h1::ParserCallbacks<Checkout> for HttpContext {
     fn on_headers(&mut self, stream: &mut GenericHttpStream) {
         match stream.kind {
-            kawa::Kind::Request => self.on_request_headers(stream),
+            kawa::Kind::Request => {
+                let start = Instant::now();
+                self.on_request_headers(stream);
+                self.header_edit_time = start.elapsed();
+            }
             kawa::Kind::Response => self.on_response_headers(stream),
         }
     }
@@ -77,17 +152,37 @@ impl HttpContext {
     ///   - front keep-alive
     ///   - sticky cookie
     ///   - user-agent
+    ///   - pinned backend
+    ///
+    /// Repeated headers are normalized first, the request is rejected if the listener says so
     fn on_request_headers(&mut self, request: &mut GenericHttpStream) {
+        if let Err(reason) = normalize_duplicate_headers(request, &self.duplicate_headers) {
+            request.parsing_phase.error(reason.into());
+            return;
+        }
+
         let buf = &mut request.storage.mut_buffer();
 
         // Captures the request line
         if let kawa::StatusLine::Request {
+            version,
             method,
             authority,
             path,
             ..
-        } = &request.detached.status_line
+        } = &mut request.detached.status_line
         {
+            self.http10 = matches!(version, kawa::Version::V10);
+            if self.http10 {
+                incr!("http.http10.requests");
+                // HTTP/1.0 does not require the Host header
+                if let (kawa::Store::Empty, Some(default_host)) =
+                    (&authority, &self.http10_options.default_host)
+                {
+                    incr!("http.http10.default_host");
+                    *authority = kawa::Store::from_string(default_host.to_owned());
+                }
+            }
             self.method = method.data_opt(buf).map(Method::new);
             self.authority = authority
                 .data_opt(buf)
@@ -135,10 +230,21 @@ impl HttpContext {
         let mut has_x_port = false;
         let mut has_x_proto = false;
         let mut has_connection = false;
+        let mut asks_keep_alive = false;
+        let mut has_early_data = false;
+        let captured_headers = CAPTURES.with(|captures| captures.borrow().captured_headers());
         for block in &mut request.blocks {
             match block {
                 kawa::Block::Header(header) if !header.is_elided() => {
                     let key = header.key.data(buf);
+                    if !captured_headers.is_empty() {
+                        capture_header(
+                            &captured_headers,
+                            &mut self.captured_request_headers,
+                            key,
+                            header.val.data(buf),
+                        );
+                    }
                     if compare_no_case(key, b"connection") {
                         has_connection = true;
                         if self.closing {
@@ -146,6 +252,7 @@ impl HttpContext {
                         } else {
                             let val = header.val.data(buf);
                             self.keep_alive_frontend &= !compare_no_case(val, b"close");
+                            asks_keep_alive = compare_no_case(val, b"keep-alive");
                         }
                     } else if compare_no_case(key, b"X-Forwarded-Proto") {
                         has_x_proto = true;
@@ -176,24 +283,57 @@ impl HttpContext {
                         x_for = Some(header);
                     } else if compare_no_case(key, b"Forwarded") {
                         forwarded = Some(header);
+                    } else if compare_no_case(key, b"Early-Data") {
+                        has_early_data = true;
                     } else if compare_no_case(key, b"User-Agent") {
                         self.user_agent = header
                             .val
                             .data_opt(buf)
                             .and_then(|data| from_utf8(data).ok())
                             .map(ToOwned::to_owned);
+                    } else if self
+                        .backend_pinning_header
+                        .as_ref()
+                        .is_some_and(|name| compare_no_case(key, name.as_bytes()))
+                    {
+                        self.pinned_backend = header
+                            .val
+                            .data_opt(buf)
+                            .and_then(|data| from_utf8(data).ok())
+                            .map(str::trim)
+                            .filter(|backend_id| !backend_id.is_empty())
+                            .map(ToOwned::to_owned);
+                        header.elide();
+                    } else if self
+                        .client_certificate_headers
+                        .as_ref()
+                        .is_some_and(|headers| is_client_certificate_header(headers, key))
+                    {
+                        // only Sōzu tells the backends about the client certificate
+                        header.elide();
                     }
                 }
                 _ => {}
             }
         }
 
+        // HTTP/1.0 clients expect the connection to close after the response,
+        // unless they ask for keep-alive
+        if self.http10 {
+            if !self.http10_options.keep_alive
+                || (self.http10_options.implicit_close && !asks_keep_alive)
+            {
+                self.keep_alive_frontend = false;
+            } else if asks_keep_alive {
+                incr!("http.http10.keep_alive");
+            }
+        }
+
         // If session_address is set:
         // - append its ip address to the list of "X-Forwarded-For" if it was found, creates it if not
         // - append "proto=[PROTO];for=[PEER];by=[PUBLIC]" to the list of "Forwarded" if it was found, creates it if not
         if let Some(peer_addr) = self.session_address {
             let peer_ip = peer_addr.ip();
-            let peer_port = peer_addr.port();
             let has_x_for = x_for.is_some();
             let has_forwarded = forwarded.is_some();
 
@@ -204,27 +344,8 @@ impl HttpContext {
             }
             if let Some(header) = &mut forwarded {
                 let value = unsafe { from_utf8_unchecked(header.val.data(buf)) };
-                let new_value = match (peer_ip, public_ip) {
-                    (IpAddr::V4(_), IpAddr::V4(_)) => {
-                        format!("{value}, proto={proto};for={peer_ip}:{peer_port};by={public_ip}")
-                    }
-                    (IpAddr::V4(_), IpAddr::V6(_)) => {
-                        format!(
-                            "{value}, proto={proto};for={peer_ip}:{peer_port};by=\"{public_ip}\""
-                        )
-                    }
-                    (IpAddr::V6(_), IpAddr::V4(_)) => {
-                        format!(
-                            "{value}, proto={proto};for=\"{peer_ip}:{peer_port}\";by={public_ip}"
-                        )
-                    }
-                    (IpAddr::V6(_), IpAddr::V6(_)) => {
-                        format!(
-                        "{value}, proto={proto};for=\"{peer_ip}:{peer_port}\";by=\"{public_ip}\""
-                    )
-                    }
-                };
-                header.val = kawa::Store::from_string(new_value);
+                let element = forwarded_element(proto, peer_addr, public_ip);
+                header.val = kawa::Store::from_string(format!("{value}, {element}"));
             }
 
             if !has_x_for {
@@ -234,23 +355,9 @@ impl HttpContext {
                 }));
             }
             if !has_forwarded {
-                let value = match (peer_ip, public_ip) {
-                    (IpAddr::V4(_), IpAddr::V4(_)) => {
-                        format!("proto={proto};for={peer_ip}:{peer_port};by={public_ip}")
-                    }
-                    (IpAddr::V4(_), IpAddr::V6(_)) => {
-                        format!("proto={proto};for={peer_ip}:{peer_port};by=\"{public_ip}\"")
-                    }
-                    (IpAddr::V6(_), IpAddr::V4(_)) => {
-                        format!("proto={proto};for=\"{peer_ip}:{peer_port}\";by={public_ip}")
-                    }
-                    (IpAddr::V6(_), IpAddr::V6(_)) => {
-                        format!("proto={proto};for=\"{peer_ip}:{peer_port}\";by=\"{public_ip}\"")
-                    }
-                };
                 request.push_block(kawa::Block::Header(kawa::Pair {
                     key: kawa::Store::Static(b"Forwarded"),
-                    val: kawa::Store::from_string(value),
+                    val: kawa::Store::from_string(forwarded_element(proto, peer_addr, public_ip)),
                 }));
             }
         }
@@ -275,6 +382,39 @@ impl HttpContext {
             }));
         }
 
+        // Create an "Early-Data" header for requests received in TLS 1.3 early data (RFC 8470)
+        // other requests are held until the handshake completes, so they are not marked
+        if self.early_data && !has_early_data && self.is_idempotent() {
+            request.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(b"Early-Data"),
+                val: kawa::Store::Static(b"1"),
+            }));
+        }
+
+        // Create the headers describing the client certificate on the listeners verifying them
+        if let Some(headers) = &self.client_certificate_headers {
+            let verify = match &self.client_certificate {
+                Some(certificate) => {
+                    for (name, value) in [
+                        (&headers.subject, &certificate.subject),
+                        (&headers.issuer, &certificate.issuer),
+                        (&headers.serial, &certificate.serial),
+                    ] {
+                        request.push_block(kawa::Block::Header(kawa::Pair {
+                            key: kawa::Store::from_string(name.to_owned()),
+                            val: kawa::Store::from_string(value.to_owned()),
+                        }));
+                    }
+                    "SUCCESS"
+                }
+                None => "NONE",
+            };
+            request.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::from_string(headers.verify.to_owned()),
+                val: kawa::Store::Static(verify.as_bytes()),
+            }));
+        }
+
         // Create a custom "Sozu-Id" header
         request.push_block(kawa::Block::Header(kawa::Pair {
             key: kawa::Store::Static(b"Sozu-Id"),
@@ -308,23 +448,64 @@ impl HttpContext {
         // If found:
         // - set Connection to "close" if closing is set
         // - set keep_alive_backend to false if Connection is "close"
+        // - elide Strict-Transport-Security if the HTTPS policy of the cluster replaces it
+        let mut has_connection = false;
+        let captured_headers = CAPTURES.with(|captures| captures.borrow().captured_headers());
         for block in &mut response.blocks {
             match block {
                 kawa::Block::Header(header) if !header.is_elided() => {
                     let key = header.key.data(buf);
+                    if !captured_headers.is_empty() {
+                        capture_header(
+                            &captured_headers,
+                            &mut self.captured_response_headers,
+                            key,
+                            header.val.data(buf),
+                        );
+                    }
+                    if self.strict_transport_security.is_some()
+                        && compare_no_case(key, b"strict-transport-security")
+                    {
+                        header.elide();
+                        continue;
+                    }
                     if compare_no_case(key, b"connection") {
+                        has_connection = true;
                         if self.closing {
                             header.val = kawa::Store::Static(b"close");
                         } else {
                             let val = header.val.data(buf);
                             self.keep_alive_backend &= !compare_no_case(val, b"close");
                         }
+                    } else if self.status == Some(101) && compare_no_case(key, b"upgrade") {
+                        self.websocket = header
+                            .val
+                            .data(buf)
+                            .split(|c| *c == b',')
+                            .filter_map(|protocol| from_utf8(protocol).ok())
+                            .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"));
                     }
                 }
                 _ => {}
             }
         }
 
+        // An HTTP/1.0 client closes the connection after the response unless it is told
+        // it is kept alive
+        if self.http10 && self.keep_alive_frontend && !self.closing && !has_connection {
+            response.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(b"Connection"),
+                val: kawa::Store::Static(b"keep-alive"),
+            }));
+        }
+
+        if let Some(strict_transport_security) = &self.strict_transport_security {
+            response.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(b"Strict-Transport-Security"),
+                val: kawa::Store::from_string(strict_transport_security.to_owned()),
+            }));
+        }
+
         // If the sticky_session is set and differs from the one found in the request
         // create a "Set-Cookie" header to update the sticky_name value
         if let Some(sticky_session) = &self.sticky_session {
@@ -344,18 +525,91 @@ impl HttpContext {
             key: kawa::Store::Static(b"Sozu-Id"),
             val: kawa::Store::from_string(self.id.to_string()),
         }));
+
+        self.rewritten |= edit_headers(
+            response,
+            &self.header_edits,
+            HeaderPosition::Response,
+            &self.id.to_string(),
+        );
+
+        // Create a "Proxy-Status" or "X-Sozu-Status" header if the listener asks for it,
+        // after the ones of the upstream proxies
+        if let Some(header) = self.proxy_status_header(None, true) {
+            response.push_block(kawa::Block::Header(header));
+        }
+    }
+
+    /// Header describing what the proxy did with the request (RFC 9209), if the listener asks for one.
+    ///
+    /// `error` is the RFC 9209 error type of an answer generated by the proxy,
+    /// `next_hop` tells if the backend the request was sent to is relevant.
+    pub fn proxy_status_header(&self, error: Option<&str>, next_hop: bool) -> Option<kawa::Pair> {
+        let name: &'static [u8] = match self.proxy_status? {
+            ProxyStatusHeader::ProxyStatus => b"Proxy-Status",
+            ProxyStatusHeader::XSozuStatus => b"X-Sozu-Status",
+        };
+
+        let mut value = String::from("sozu");
+        if let Some(error) = error {
+            let _ = write!(value, "; error={error}");
+        }
+        if let Some(address) = self.backend_address.filter(|_| next_hop) {
+            let _ = write!(value, "; next-hop=\"{address}\"");
+        }
+        if let (None, Some(status)) = (error, self.status) {
+            let _ = write!(value, "; received-status={status}");
+        }
+        if self.retries > 0 {
+            let _ = write!(value, "; retries={}", self.retries);
+        }
+        if self.rewritten {
+            value.push_str("; rewritten");
+        }
+
+        Some(kawa::Pair {
+            key: kawa::Store::Static(name),
+            val: kawa::Store::from_string(value),
+        })
     }
 
     pub fn reset(&mut self) {
         self.keep_alive_backend = true;
         self.keep_alive_frontend = true;
         self.sticky_session_found = None;
+        self.http10 = false;
         self.method = None;
         self.authority = None;
         self.path = None;
         self.status = None;
         self.reason = None;
         self.user_agent = None;
+        self.header_edit_time = Duration::ZERO;
+        self.retries = 0;
+        self.rewritten = false;
+        self.request_body_size = 0;
+        self.response_body_size = 0;
+        self.captured_request_headers.clear();
+        self.captured_response_headers.clear();
+        self.pinned_backend = None;
+        self.routed = false;
+        self.websocket = false;
+        self.early_data = false;
+        self.strict_transport_security = None;
+        self.max_response_body_size = None;
+        self.response_flush_delay = None;
+        self.fault_delay = None;
+        self.request_sent = None;
+        self.slow_log = None;
+        self.header_edits.clear();
+    }
+
+    /// true if the method of the request is known and idempotent
+    pub fn is_idempotent(&self) -> bool {
+        self.method
+            .as_ref()
+            .map(Method::is_idempotent)
+            .unwrap_or(false)
     }
 
     pub fn log_context(&self) -> LogContext {
@@ -366,3 +620,313 @@ impl HttpContext {
         }
     }
 }
+
+/// record a header if its name is in the allowlist of a running debug capture.
+/// Repeated headers are joined with a comma
+fn capture_header(
+    captured_headers: &[String],
+    captured: &mut BTreeMap<String, String>,
+    key: &[u8],
+    val: &[u8],
+) {
+    let name = String::from_utf8_lossy(key).to_ascii_lowercase();
+    if !captured_headers.contains(&name) {
+        return;
+    }
+    let val = String::from_utf8_lossy(val);
+    captured
+        .entry(name)
+        .and_modify(|value| {
+            value.push_str(", ");
+            value.push_str(&val);
+        })
+        .or_insert_with(|| val.into_owned());
+}
+
+/// apply the edits of a frontend for this position to the headers of a stream,
+/// in order. Added headers go after the existing ones. Returns true if a header was changed
+pub fn edit_headers<T: kawa::AsBuffer>(
+    stream: &mut kawa::Kawa<T>,
+    edits: &[HeaderEdit],
+    position: HeaderPosition,
+    request_id: &str,
+) -> bool {
+    let mut edited = false;
+    for edit in edits.iter().filter(|edit| edit.position == position as i32) {
+        let kind = match HeaderEditKind::try_from(edit.kind) {
+            Ok(kind) => kind,
+            Err(_) => continue,
+        };
+        if matches!(kind, HeaderEditKind::Set | HeaderEditKind::Remove) {
+            let buf = stream.storage.buffer();
+            for block in &mut stream.blocks {
+                if let kawa::Block::Header(header) = block {
+                    if !header.is_elided()
+                        && compare_no_case(header.key.data(buf), edit.key.as_bytes())
+                    {
+                        header.elide();
+                        edited = true;
+                    }
+                }
+            }
+        }
+        if let (HeaderEditKind::Add | HeaderEditKind::Set, Some(val)) = (kind, &edit.val) {
+            let end_header = stream
+                .blocks
+                .iter()
+                .position(|block| matches!(block, kawa::Block::Flags(flags) if flags.end_header))
+                .unwrap_or(stream.blocks.len());
+            stream.blocks.insert(
+                end_header,
+                kawa::Block::Header(kawa::Pair {
+                    key: kawa::Store::from_string(edit.key.to_owned()),
+                    val: kawa::Store::from_string(val.replace("%REQUEST_ID", request_id)),
+                }),
+            );
+            edited = true;
+        }
+    }
+    edited
+}
+
+fn is_client_certificate_header(headers: &ClientCertificateHeaders, key: &[u8]) -> bool {
+    [
+        &headers.subject,
+        &headers.issuer,
+        &headers.serial,
+        &headers.verify,
+    ]
+    .iter()
+    .any(|name| compare_no_case(key, name.as_bytes()))
+}
+
+/// name of a header, even if Kawa elided it. Kawa elides every Host header, swapping the
+/// value of the first one into the authority, and the duplicates of the Content-Length
+/// header, by clearing their key: their name is read back from the buffer, before the value
+fn header_name<'a>(buf: &'a [u8], header: &'a kawa::Pair) -> Option<&'a [u8]> {
+    if !header.is_elided() {
+        return Some(header.key.data(buf));
+    }
+    let kawa::Store::Slice(val) = &header.val else {
+        return matches!(header.val, kawa::Store::Empty).then_some(b"host");
+    };
+    let mut end = val.start as usize;
+    while end > 0 && matches!(buf[end - 1], b' ' | b'\t') {
+        end -= 1;
+    }
+    if end == 0 || buf[end - 1] != b':' {
+        return None;
+    }
+    end -= 1;
+    let start = buf[..end]
+        .iter()
+        .rposition(|c| *c == b'\n')
+        .map_or(0, |newline| newline + 1);
+    Some(&buf[start..end])
+}
+
+/// apply the policies of the listener to the headers a request repeats.
+/// Kawa already keeps the first Host header and merges the identical Content-Length
+/// headers (it rejects the different ones), only their rejection is left to do here
+pub fn normalize_duplicate_headers<T: kawa::AsBuffer>(
+    request: &mut kawa::Kawa<T>,
+    duplicate_headers: &[DuplicateHeader],
+) -> Result<(), &'static str> {
+    for duplicate_header in duplicate_headers {
+        let policy = match DuplicateHeaderPolicy::try_from(duplicate_header.policy) {
+            Ok(policy) => policy,
+            Err(_) => continue,
+        };
+        let name = duplicate_header.name.as_bytes();
+        let buf = request.storage.buffer();
+        let count = request
+            .blocks
+            .iter()
+            .filter(|block| match block {
+                kawa::Block::Header(header) => {
+                    header_name(buf, header).is_some_and(|key| compare_no_case(key, name))
+                }
+                _ => false,
+            })
+            .count();
+        if count < 2 {
+            continue;
+        }
+
+        if policy == DuplicateHeaderPolicy::Reject {
+            incr!("http.duplicate_headers.rejected");
+            return Err(if compare_no_case(name, b"host") {
+                "Repeated Host header"
+            } else if compare_no_case(name, b"content-length") {
+                "Repeated Content-Length header"
+            } else {
+                "Repeated header rejected by the listener"
+            });
+        }
+        if compare_no_case(name, b"host") {
+            incr!("http.duplicate_headers.kept_first");
+            continue;
+        }
+        if compare_no_case(name, b"content-length") {
+            incr!("http.duplicate_headers.merged");
+            continue;
+        }
+
+        let mut headers = request.blocks.iter_mut().filter_map(|block| match block {
+            kawa::Block::Header(header)
+                if !header.is_elided() && compare_no_case(header.key.data(buf), name) =>
+            {
+                Some(header)
+            }
+            _ => None,
+        });
+        let Some(first) = headers.next() else {
+            continue;
+        };
+        match policy {
+            DuplicateHeaderPolicy::First => {
+                incr!("http.duplicate_headers.kept_first");
+                headers.for_each(|header| header.elide());
+            }
+            _ => {
+                incr!("http.duplicate_headers.merged");
+                let mut merged = first.val.data(buf).to_vec();
+                for header in headers {
+                    merged.extend_from_slice(b", ");
+                    merged.extend_from_slice(header.val.data(buf));
+                    header.elide();
+                }
+                first.val = kawa::Store::from_vec(merged);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// "proto=[PROTO];for=[PEER];by=[PUBLIC]" element of a Forwarded header (RFC 7239).
+/// IPv6 nodes are bracketed and quoted, like `for="[2001:db8::1]:4711"`
+fn forwarded_element(proto: &str, peer_addr: SocketAddr, public_ip: IpAddr) -> String {
+    let peer_ip = peer_addr.ip();
+    let peer_port = peer_addr.port();
+    match (peer_ip, public_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => {
+            format!("proto={proto};for={peer_ip}:{peer_port};by={public_ip}")
+        }
+        (IpAddr::V4(_), IpAddr::V6(_)) => {
+            format!("proto={proto};for={peer_ip}:{peer_port};by=\"[{public_ip}]\"")
+        }
+        (IpAddr::V6(_), IpAddr::V4(_)) => {
+            format!("proto={proto};for=\"[{peer_ip}]:{peer_port}\";by={public_ip}")
+        }
+        (IpAddr::V6(_), IpAddr::V6(_)) => {
+            format!("proto={proto};for=\"[{peer_ip}]:{peer_port}\";by=\"[{public_ip}]\"")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    #[test]
+    fn forwarded_element_brackets_ipv6_nodes() {
+        assert_eq!(
+            forwarded_element(
+                "https",
+                "192.0.2.1:4711".parse().unwrap(),
+                "192.0.2.2".parse().unwrap()
+            ),
+            "proto=https;for=192.0.2.1:4711;by=192.0.2.2"
+        );
+        assert_eq!(
+            forwarded_element(
+                "http",
+                "[2001:db8::1]:4711".parse().unwrap(),
+                "192.0.2.2".parse().unwrap()
+            ),
+            "proto=http;for=\"[2001:db8::1]:4711\";by=192.0.2.2"
+        );
+        assert_eq!(
+            forwarded_element(
+                "http",
+                "192.0.2.1:4711".parse().unwrap(),
+                "::1".parse().unwrap()
+            ),
+            "proto=http;for=192.0.2.1:4711;by=\"[::1]\""
+        );
+        assert_eq!(
+            forwarded_element(
+                "http",
+                "[2001:db8::1]:4711".parse().unwrap(),
+                "::1".parse().unwrap()
+            ),
+            "proto=http;for=\"[2001:db8::1]:4711\";by=\"[::1]\""
+        );
+    }
+
+    fn normalize(request: &[u8], policies: &[(&str, DuplicateHeaderPolicy)]) -> Option<String> {
+        let duplicate_headers: Vec<DuplicateHeader> = policies
+            .iter()
+            .map(|(name, policy)| DuplicateHeader {
+                name: name.to_string(),
+                policy: *policy as i32,
+            })
+            .collect();
+        let mut storage = [0u8; 512];
+        let mut stream = kawa::Kawa::new(
+            kawa::Kind::Request,
+            kawa::Buffer::new(kawa::SliceBuffer(&mut storage)),
+        );
+        stream.storage.write_all(request).unwrap();
+        kawa::h1::parse(&mut stream, &mut kawa::h1::NoCallbacks);
+        normalize_duplicate_headers(&mut stream, &duplicate_headers).ok()?;
+
+        let buf = stream.storage.buffer();
+        let mut headers = String::new();
+        for block in &stream.blocks {
+            if let kawa::Block::Header(header) = block {
+                if !header.is_elided() {
+                    let key = from_utf8(header.key.data(buf)).unwrap();
+                    let val = from_utf8(header.val.data(buf)).unwrap();
+                    let _ = write!(headers, "{key}: {val}|");
+                }
+            }
+        }
+        Some(headers)
+    }
+
+    #[test]
+    fn normalize_the_repeated_headers() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Id: 1\r\nAccept: a\r\nx-id:  2\r\nAccept: b\r\n\r\n";
+        assert_eq!(
+            normalize(request, &[]).unwrap(),
+            "X-Id: 1|Accept: a|x-id: 2|Accept: b|"
+        );
+        assert_eq!(
+            normalize(
+                request,
+                &[
+                    ("x-id", DuplicateHeaderPolicy::First),
+                    ("accept", DuplicateHeaderPolicy::Merge)
+                ]
+            )
+            .unwrap(),
+            "X-Id: 1|Accept: a, b|"
+        );
+        assert!(normalize(request, &[("x-id", DuplicateHeaderPolicy::Reject)]).is_none());
+        assert!(normalize(request, &[("host", DuplicateHeaderPolicy::Reject)]).is_some());
+
+        // Kawa elides the Host headers and the duplicated Content-Length headers
+        let request = b"POST / HTTP/1.1\r\nHost: a.com\r\nContent-Length: 0\r\nHost:b.com\r\ncontent-length:\t0\r\n\r\n";
+        assert_eq!(normalize(request, &[]).unwrap(), "Content-Length: 0|");
+        assert!(normalize(request, &[("host", DuplicateHeaderPolicy::Reject)]).is_none());
+        assert!(normalize(
+            request,
+            &[("content-length", DuplicateHeaderPolicy::Reject)]
+        )
+        .is_none());
+        assert!(normalize(request, &[("host", DuplicateHeaderPolicy::First)]).is_some());
+    }
+}
@@ -5,38 +5,50 @@ pub mod parser;
 
 use std::{
     cell::RefCell,
+    collections::BTreeMap,
     io::ErrorKind,
     net::{Shutdown, SocketAddr},
     rc::{Rc, Weak},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use mio::{net::TcpStream, Interest, Token};
+use rand::Rng;
 use rusty_ulid::Ulid;
 use sozu_command::{
     config::MAX_LOOP_ITERATIONS,
     logging::EndpointRecord,
-    proto::command::{Event, EventKind, ListenerType},
+    proto::command::{
+        CapturedRequest, ClientRateLimit, Event, EventKind, FaultInjection, FilterAction, HashKey,
+        HashKeyKind, HeaderEdit, HeaderPosition, ListenerType, RequestFilter, RequestMirror,
+        RequestPipeline, Timeouts,
+    },
 };
 // use time::{Duration, Instant};
 
 use crate::{
-    backends::{Backend, BackendError},
+    backends::{Backend, BackendError, RequestOutcome},
+    capture::CAPTURES,
+    mirror::{mirrored_bytes, record, sampled, MIRRORS},
     pool::{Checkout, Pool},
     protocol::{
         http::{
             answers::DefaultAnswerStream,
             diagnostics::{diagnostic_400_502, diagnostic_413_507},
-            editor::HttpContext,
-            parser::Method,
+            editor::{edit_headers, HttpContext},
+            parser::{compare_no_case, Method},
         },
         pipe::WebSocketContext,
         SessionState,
     },
+    rate_limit::CLIENT_RATE_LIMITS,
     retry::RetryPolicy,
-    router::Route,
+    router::{FrontendOptions, RequestHeaders},
     server::{push_event, CONN_RETRIES},
-    socket::{stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol},
+    slow_log::{is_slow, SlowRequest, SLOW_LOGS},
+    socket::{
+        set_dscp, stats::socket_rtt, SocketHandler, SocketResult, TlsProperties, TransportProtocol,
+    },
     sozu_command::{logging::LogContext, ready::Ready},
     timer::TimeoutContainer,
     AcceptError, BackendConnectAction, BackendConnectionError, BackendConnectionStatus,
@@ -73,6 +85,22 @@ impl kawa::AsBuffer for Checkout {
     }
 }
 
+/// the headers of a request, for the frontends matching on them
+impl RequestHeaders for GenericHttpStream {
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        let buf = self.storage.buffer();
+        self.blocks.iter().find_map(|block| match block {
+            kawa::Block::Header(header)
+                if !header.is_elided()
+                    && compare_no_case(header.key.data(buf), name.as_bytes()) =>
+            {
+                Some(header.val.data(buf))
+            }
+            _ => None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DefaultAnswer {
     Answer301 {
@@ -93,6 +121,9 @@ pub enum DefaultAnswer {
         phase: kawa::ParsingPhaseMarker,
         capacity: usize,
     },
+    Answer429 {
+        retry_after: u64,
+    },
     Answer502 {
         message: String,
         phase: kawa::ParsingPhaseMarker,
@@ -111,6 +142,26 @@ pub enum DefaultAnswer {
     },
 }
 
+impl DefaultAnswer {
+    /// error type of the answer in a Proxy-Status header (RFC 9209)
+    fn proxy_status_error(&self) -> Option<&'static str> {
+        match self {
+            DefaultAnswer::Answer301 { .. } => None,
+            DefaultAnswer::Answer400 { .. }
+            | DefaultAnswer::Answer408 { .. }
+            | DefaultAnswer::Answer413 { .. } => Some("http_request_error"),
+            DefaultAnswer::Answer401 { .. } | DefaultAnswer::Answer429 { .. } => {
+                Some("http_request_denied")
+            }
+            DefaultAnswer::Answer404 { .. } => Some("destination_not_found"),
+            DefaultAnswer::Answer502 { .. } => Some("http_protocol_error"),
+            DefaultAnswer::Answer503 { .. } => Some("destination_unavailable"),
+            DefaultAnswer::Answer504 { .. } => Some("http_response_timeout"),
+            DefaultAnswer::Answer507 { .. } => Some("http_response_header_section_size"),
+        }
+    }
+}
+
 impl From<&DefaultAnswer> for u16 {
     fn from(answer: &DefaultAnswer) -> u16 {
         match answer {
@@ -120,6 +171,7 @@ impl From<&DefaultAnswer> for u16 {
             DefaultAnswer::Answer404 { .. } => 404,
             DefaultAnswer::Answer408 { .. } => 408,
             DefaultAnswer::Answer413 { .. } => 413,
+            DefaultAnswer::Answer429 { .. } => 429,
             DefaultAnswer::Answer502 { .. } => 502,
             DefaultAnswer::Answer503 { .. } => 503,
             DefaultAnswer::Answer504 { .. } => 504,
@@ -136,6 +188,38 @@ pub enum TimeoutStatus {
     WaitingForResponse,
 }
 
+/// the timer can trigger up to half a tick early, a timeout this close to the
+/// deadline of its request is attributed to the deadline
+const DEADLINE_TOLERANCE: Duration = Duration::from_millis(100);
+
+/// Timeouts of the request being handled, the ones of the listener until the
+/// request is routed, then the ones of its frontend, cluster and listener
+#[derive(Debug, Clone, Copy)]
+struct RequestTimeouts {
+    body_read: Duration,
+    backend_connect: Duration,
+    backend_response: Duration,
+    /// when the request should be answered, and the deadline it was given
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl RequestTimeouts {
+    /// a timeout shortened to trigger at the deadline of the request at the latest
+    fn within_deadline(&self, duration: Duration) -> Duration {
+        match self.deadline {
+            Some((at, _)) => duration.min(at.saturating_duration_since(Instant::now())),
+            None => duration,
+        }
+    }
+
+    /// the deadline of the request, if it has passed
+    fn exceeded_deadline(&self) -> Option<Duration> {
+        self.deadline
+            .filter(|(at, _)| Instant::now() + DEADLINE_TOLERANCE >= *at)
+            .map(|(_, deadline)| deadline)
+    }
+}
+
 pub enum ResponseStream {
     BackendAnswer(GenericHttpStream),
     DefaultAnswer(u16, DefaultAnswerStream),
@@ -152,9 +236,18 @@ pub struct Http<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> {
     pub backend_token: Option<Token>,
     pub container_backend_timeout: TimeoutContainer,
     pub container_frontend_timeout: TimeoutContainer,
+    /// triggers the write of a response body held back by the flush delay of its cluster
+    container_flush_timeout: TimeoutContainer,
+    /// when the response body started to be held back
+    flush_held_since: Option<Instant>,
+    /// triggers the write of a request held back by the faults injected in its cluster
+    container_fault_timeout: TimeoutContainer,
+    /// when the request started to be held back
+    fault_held_since: Option<Instant>,
     configured_backend_timeout: Duration,
     configured_connect_timeout: Duration,
     configured_frontend_timeout: Duration,
+    request_timeouts: RequestTimeouts,
     /// attempts to connect to the backends during the session
     connection_attempts: u8,
     pub frontend_readiness: Readiness,
@@ -205,6 +298,15 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
             None => return Err(AcceptError::BufferCapacityReached),
         };
+        let proxy_status = listener.borrow().get_proxy_status();
+        let http10_options = listener.borrow().get_http10_options();
+        let duplicate_headers = listener.borrow().get_duplicate_headers();
+        let client_certificate_headers = listener.borrow().get_client_certificate_headers();
+        let client_certificate = client_certificate_headers
+            .as_ref()
+            .and_then(|_| frontend_socket.socket_client_certificate());
+        let backend_pinning_header = session_address
+            .and_then(|address| listener.borrow().get_backend_pinning_header(address.ip()));
         Ok(Http {
             answers,
             backend_connection_status: BackendConnectionStatus::NotConnected,
@@ -216,9 +318,19 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             configured_backend_timeout,
             configured_connect_timeout,
             configured_frontend_timeout,
+            request_timeouts: RequestTimeouts {
+                body_read: configured_frontend_timeout,
+                backend_connect: configured_connect_timeout,
+                backend_response: configured_backend_timeout,
+                deadline: None,
+            },
             connection_attempts: 0,
             container_backend_timeout: TimeoutContainer::new_empty(configured_connect_timeout),
             container_frontend_timeout,
+            container_flush_timeout: TimeoutContainer::new_empty(Duration::ZERO),
+            container_fault_timeout: TimeoutContainer::new_empty(Duration::ZERO),
+            flush_held_since: None,
+            fault_held_since: None,
             frontend_readiness: Readiness {
                 interest: Ready::READABLE | Ready::HUP | Ready::ERROR,
                 event: Ready::EMPTY,
@@ -249,6 +361,8 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 sticky_name,
                 sticky_session: None,
                 sticky_session_found: None,
+                early_data: false,
+                http10: false,
 
                 method: None,
                 authority: None,
@@ -256,6 +370,30 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 status: None,
                 reason: None,
                 user_agent: None,
+                header_edit_time: Duration::ZERO,
+                retries: 0,
+                rewritten: false,
+                request_body_size: 0,
+                response_body_size: 0,
+                captured_request_headers: BTreeMap::new(),
+                captured_response_headers: BTreeMap::new(),
+                pinned_backend: None,
+                routed: false,
+                websocket: false,
+                proxy_status,
+                backend_address: None,
+                http10_options,
+                strict_transport_security: None,
+                max_response_body_size: None,
+                response_flush_delay: None,
+                fault_delay: None,
+                request_sent: None,
+                slow_log: None,
+                header_edits: Vec::new(),
+                backend_pinning_header,
+                duplicate_headers,
+                client_certificate_headers,
+                client_certificate,
             },
         })
     }
@@ -283,7 +421,17 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         // reset the front timeout and cancel the back timeout while we are
         // waiting for a new request
+        self.request_timeouts = RequestTimeouts {
+            body_read: self.configured_frontend_timeout,
+            backend_connect: self.configured_connect_timeout,
+            backend_response: self.configured_backend_timeout,
+            deadline: None,
+        };
         self.container_backend_timeout.cancel();
+        self.container_flush_timeout.cancel();
+        self.flush_held_since = None;
+        self.container_fault_timeout.cancel();
+        self.fault_held_since = None;
         self.container_frontend_timeout
             .set_duration(self.configured_frontend_timeout);
         self.frontend_readiness.interest = Ready::READABLE | Ready::HUP | Ready::ERROR;
@@ -318,7 +466,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
     pub fn readable(&mut self, metrics: &mut SessionMetrics) -> StateResult {
         trace!("{} ============== readable", log_context!(self));
-        if !self.container_frontend_timeout.reset() {
+        if !self.reset_frontend_timeout() {
             error!(
                 "could not reset front timeout {:?}",
                 self.configured_frontend_timeout
@@ -403,8 +551,16 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         trace!("{} ============== readable_parse", log_context!(self));
         let was_initial = self.request_stream.is_initial();
-        let was_not_proxying = !self.request_stream.is_main_phase();
+        // a non idempotent request received in TLS 1.3 early data is not
+        // forwarded before the end of the handshake, since it could be replayed
+        let was_held = self.request_stream.is_main_phase()
+            && self.context.early_data
+            && !self.context.is_idempotent();
+        let was_not_proxying = !self.request_stream.is_main_phase() || was_held;
 
+        if !self.request_stream.is_main_phase() {
+            self.context.early_data = self.frontend_socket.socket_in_early_data();
+        }
         kawa::h1::parse(&mut self.request_stream, &mut self.context);
         // kawa::debug_kawa(&self.request_stream);
 
@@ -456,6 +612,38 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         }
 
         if self.request_stream.is_main_phase() {
+            if self.context.early_data && !self.context.is_idempotent() {
+                if self.frontend_socket.socket_in_early_data() {
+                    // a replayed request could have side effects, wait for
+                    // the client to finish the handshake
+                    if !was_held {
+                        incr!("https.early_data.held");
+                    }
+                    self.frontend_readiness.interest.insert(Ready::READABLE);
+                    return StateResult::Continue;
+                }
+                self.context.early_data = false;
+            } else if self.context.early_data && was_not_proxying {
+                incr!("https.early_data.requests");
+            }
+
+            if was_not_proxying {
+                if let Some(client) = self.get_session_address() {
+                    let rate = self.listener.borrow_mut().check_request_rate(client.ip());
+                    if let Err(retry_after) = rate {
+                        debug!(
+                            "{} {} is over the request rate limit",
+                            log_context!(self),
+                            client.ip()
+                        );
+                        self.set_answer(DefaultAnswer::Answer429 {
+                            retry_after: retry_after.as_secs(),
+                        });
+                        return StateResult::Continue;
+                    }
+                }
+            }
+
             self.backend_readiness.interest.insert(Ready::WRITABLE);
             if was_not_proxying {
                 // Sozu tries to connect only once all the headers were gathered and edited
@@ -478,6 +666,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             _ => return self.writable_default_answer(metrics),
         };
 
+        self.context.response_body_size += pending_body_size(response_stream);
         response_stream.prepare(&mut kawa::h1::BlockConverter);
 
         let bufs = response_stream.as_io_slice();
@@ -495,6 +684,16 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             count!("bytes_out", size as i64);
             metrics.bout += size;
             self.backend_readiness.interest.insert(Ready::READABLE);
+            if let Some(held_since) = self.flush_held_since.take() {
+                self.container_flush_timeout.cancel();
+                if let Some(cluster_id) = self.context.cluster_id.as_deref() {
+                    time!(
+                        "http.response_flush_delay",
+                        cluster_id,
+                        held_since.elapsed().as_millis()
+                    );
+                }
+            }
         }
 
         match socket_state {
@@ -653,6 +852,17 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             return SessionResult::Continue;
         }
 
+        // the request is written once the injected delay is over
+        if let Some(delay) = self.context.fault_delay {
+            if self.fault_held_since.is_none() {
+                self.fault_held_since = Some(Instant::now());
+                self.container_fault_timeout.set_duration(delay);
+                self.container_fault_timeout.set(self.frontend_token);
+            }
+            self.backend_readiness.interest.remove(Ready::WRITABLE);
+            return SessionResult::Continue;
+        }
+
         let backend_socket = if let Some(backend_socket) = &mut self.backend_socket {
             backend_socket
         } else {
@@ -660,6 +870,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             return SessionResult::Close;
         };
 
+        self.context.request_body_size += pending_body_size(&self.request_stream);
         self.request_stream.prepare(&mut kawa::h1::BlockConverter);
 
         let bufs = self.request_stream.as_io_slice();
@@ -704,10 +915,14 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         if self.request_stream.is_terminated() && self.request_stream.is_completed() {
             self.backend_readiness.interest.remove(Ready::WRITABLE);
+            // a response started before the end of the request does not measure the backend
+            if self.context.request_sent.is_none() && metrics.backend_bin == 0 {
+                self.context.request_sent = Some(Instant::now());
+            }
 
             // cancel the front timeout while we are waiting for the server to answer
             self.container_frontend_timeout.cancel();
-            self.container_backend_timeout.reset();
+            self.reset_backend_response_timeout();
         }
         SessionResult::Continue
     }
@@ -715,7 +930,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     // Read content from cluster
     pub fn backend_readable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
         trace!("{} ============== backend_readable", log_context!(self));
-        if !self.container_backend_timeout.reset() {
+        if !self.reset_backend_response_timeout() {
             error!(
                 "{} Could not reset back timeout {:?}",
                 log_context!(self),
@@ -770,6 +985,17 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             response_stream.storage.fill(size);
             count!("back_bytes_in", size as i64);
             metrics.backend_bin += size;
+            if let (Some(sent), Some(backend)) = (self.context.request_sent.take(), &self.backend) {
+                let mut backend = backend.borrow_mut();
+                backend.set_response_time(sent.elapsed());
+                // the estimate compared by the RESPONSE_TIME load metric, in microseconds
+                gauge!(
+                    "backend_response_time_ewma",
+                    (backend.response_time.rtt / 1_000f64) as usize,
+                    self.context.cluster_id.as_deref(),
+                    metrics.backend_id.as_deref()
+                );
+            }
             // if self.kawa_response.storage.is_full() {
             //     self.backend_readiness.interest.remove(Ready::READABLE);
             // }
@@ -847,12 +1073,66 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         }
 
+        if let Some(max) = self.context.max_response_body_size {
+            let announced = match response_stream.body_size {
+                kawa::BodySize::Length(length) => length,
+                _ => 0,
+            };
+            // the body forwarded so far, and the one waiting in the buffer
+            let received = self.context.response_body_size + pending_body_size(response_stream);
+            let size = announced.max(received);
+            if size > max {
+                incr!(
+                    "http.response_body_too_large",
+                    self.context.cluster_id.as_deref(),
+                    self.context.backend_id.as_deref()
+                );
+                let cluster_id = self.context.cluster_id.as_deref().unwrap_or("-");
+                let message = format!(
+                    "The response body is at least {size} bytes, cluster {cluster_id} accepts at most {max} bytes."
+                );
+                if response_stream.consumed {
+                    self.log_request_error(
+                        metrics,
+                        &format!("{message} Cutting the response, closing session."),
+                    );
+                    return SessionResult::Close;
+                }
+                let phase = response_stream.parsing_phase.marker();
+                self.set_answer(DefaultAnswer::Answer502 {
+                    phase,
+                    details: String::new(),
+                    message,
+                });
+                return SessionResult::Continue;
+            }
+        }
+
         if response_stream.is_main_phase() {
-            self.frontend_readiness.interest.insert(Ready::WRITABLE);
+            match self.context.response_flush_delay {
+                // once the head is written, the body waits for the delay, a full buffer
+                // or the end of the response
+                Some(delay)
+                    if response_stream.consumed
+                        && !response_stream.is_terminated()
+                        && !response_stream.storage.is_full() =>
+                {
+                    if self.flush_held_since.is_none() {
+                        self.flush_held_since = Some(Instant::now());
+                        self.container_flush_timeout.set_duration(delay);
+                        self.container_flush_timeout.set(self.frontend_token);
+                    }
+                }
+                _ => self.frontend_readiness.interest.insert(Ready::WRITABLE),
+            }
         }
         if response_stream.is_terminated() {
             metrics.backend_stop();
             self.backend_stop = Some(Instant::now());
+            self.record_outcome(match self.context.status {
+                Some(status) if status >= 500 => RequestOutcome::ServerError,
+                _ => RequestOutcome::Success,
+            });
             self.backend_readiness.interest.remove(Ready::READABLE);
         }
         SessionResult::Continue
@@ -870,6 +1150,13 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         }
     }
 
+    /// count how the request ended on its backend, for outlier detection
+    fn record_outcome(&self, outcome: RequestOutcome) {
+        if let Some(backend) = &self.backend {
+            backend.borrow_mut().record_outcome(outcome);
+        }
+    }
+
     pub fn get_session_address(&self) -> Option<SocketAddr> {
         self.context
             .session_address
@@ -918,15 +1205,35 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     pub fn log_request(&self, metrics: &SessionMetrics, error: bool, message: Option<&str>) {
         let listener = self.listener.borrow();
         let tags = self.context.authority.as_ref().and_then(|host| {
-            let hostname = match host.split_once(':') {
-                None => host,
-                Some((hostname, _)) => hostname,
+            let hostname = match parser::hostname_and_port(host.as_bytes()) {
+                // only ascii chars were accepted by the parser
+                Ok((_, (hostname, _))) => unsafe { std::str::from_utf8_unchecked(hostname) },
+                Err(_) => host,
             };
             listener.get_tags(hostname)
         });
 
         let context = self.context.log_context();
+        let tls = self.frontend_socket.socket_tls_properties();
         metrics.register_end_of_session(&context);
+        if let Some(cluster_id) = context.cluster_id {
+            if let Some(tls) = &tls {
+                tls.count_for_cluster(cluster_id);
+            }
+            save_http_body_metrics(
+                cluster_id,
+                self.context.request_body_size,
+                self.context.response_body_size,
+            );
+            CAPTURES.with(|captures| {
+                captures
+                    .borrow_mut()
+                    .record(cluster_id, Instant::now(), || {
+                        self.captured_request(metrics)
+                    })
+            });
+            self.log_slow_request(cluster_id, metrics);
+        }
 
         log_access! {
             error,
@@ -937,6 +1244,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             protocol: self.protocol_string(),
             endpoint: self.log_endpoint(),
             tags,
+            tls: tls.as_ref().map(TlsProperties::record),
             client_rtt: socket_rtt(self.front_socket()),
             server_rtt: self.backend_socket.as_ref().and_then(socket_rtt),
             service_time: metrics.service_time(),
@@ -947,6 +1255,89 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         };
     }
 
+    /// write the request to the slow log of its cluster if it took too long
+    fn log_slow_request(&self, cluster_id: &str, metrics: &SessionMetrics) {
+        let Some(slow_log) = &self.context.slow_log else {
+            return;
+        };
+        let response_time = metrics.response_time();
+        if !is_slow(slow_log, response_time) {
+            return;
+        }
+        let backend_id = self.context.backend_id.as_deref();
+        incr!("http.slow_requests", Some(cluster_id), backend_id);
+
+        let written = SLOW_LOGS.with(|slow_logs| {
+            slow_logs.borrow_mut().write(
+                slow_log.sink(),
+                &slow_log.path,
+                slow_log.max_per_second,
+                Instant::now(),
+                || {
+                    SlowRequest {
+                        request_id: &self.context.id.to_string(),
+                        unix_time_ms: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|elapsed| elapsed.as_millis())
+                            .unwrap_or_default(),
+                        cluster_id,
+                        backend_id,
+                        client: self.get_session_address(),
+                        method: self.context.method.as_ref().map(ToString::to_string),
+                        authority: self.context.authority.as_deref(),
+                        path: self.context.path.as_deref(),
+                        status: self.context.status,
+                        response_time,
+                        service_time: metrics.service_time(),
+                        wait_time: metrics.wait_time,
+                        backend_connect: metrics.backend_connection_time(),
+                        backend_response: metrics.backend_response_time(),
+                        retries: self.context.retries,
+                        bytes_in: metrics.bin,
+                        bytes_out: metrics.bout,
+                    }
+                    .record()
+                },
+            )
+        });
+        if !written {
+            incr!("http.slow_log.dropped", Some(cluster_id), backend_id);
+        }
+    }
+
+    /// metadata of the request recorded by the debug captures
+    fn captured_request(&self, metrics: &SessionMetrics) -> CapturedRequest {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        CapturedRequest {
+            timestamp,
+            request_id: self.context.id.to_string(),
+            backend_id: self.context.backend_id.clone(),
+            client_address: self
+                .get_session_address()
+                .map(|address| address.to_string()),
+            backend_address: self
+                .get_backend_address()
+                .map(|address| address.to_string()),
+            method: self.context.method.as_ref().map(ToString::to_string),
+            authority: self.context.authority.clone(),
+            path: self.context.path.clone(),
+            status: self.context.status.map(u32::from),
+            response_time: metrics.response_time().as_micros() as u64,
+            service_time: metrics.service_time().as_micros() as u64,
+            backend_response_time: metrics
+                .backend_response_time()
+                .map(|time| time.as_micros() as u64),
+            bytes_in: metrics.bin as u64,
+            bytes_out: metrics.bout as u64,
+            request_headers: self.context.captured_request_headers.clone(),
+            response_headers: self.context.captured_response_headers.clone(),
+        }
+    }
+
     pub fn log_request_success(&self, metrics: &SessionMetrics) {
         save_http_status_metric(self.context.status, self.context.log_context());
         self.log_request(metrics, false, None);
@@ -997,6 +1388,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     self.context.cluster_id.as_deref(),
                     self.context.backend_id.as_deref()
                 ),
+                DefaultAnswer::Answer429 { .. } => incr!("http.429.errors"),
                 DefaultAnswer::Answer502 { .. } => incr!(
                     "http.502.errors",
                     self.context.cluster_id.as_deref(),
@@ -1020,6 +1412,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             };
         }
 
+        let proxy_status_error = answer.proxy_status_error();
         let mut kawa = self.answers.borrow().get(
             answer,
             self.context.id.to_string(),
@@ -1027,6 +1420,20 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             self.context.backend_id.as_deref(),
             self.get_route(),
         );
+        self.context.retries = self.connection_attempts;
+        // the backend is only relevant for the errors it caused
+        let next_hop = matches!(status, 502 | 503 | 504 | 507);
+        if let Some(header) = self
+            .context
+            .proxy_status_header(proxy_status_error, next_hop)
+        {
+            let end_header = kawa
+                .blocks
+                .iter()
+                .position(|block| matches!(block, kawa::Block::Flags(flags) if flags.end_header))
+                .unwrap_or(kawa.blocks.len());
+            kawa.blocks.insert(end_header, kawa::Block::Header(header));
+        }
         kawa.prepare(&mut kawa::h1::BlockConverter);
         self.context.status = Some(status);
         self.context.reason = None;
@@ -1097,6 +1504,55 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         }
     }
 
+    /// re-arm the front timeout, shortened to the deadline of the request if any
+    fn reset_frontend_timeout(&mut self) -> bool {
+        if self.request_timeouts.deadline.is_some() {
+            let duration = self
+                .request_timeouts
+                .within_deadline(self.container_frontend_timeout.duration());
+            self.container_frontend_timeout.set_duration(duration);
+        }
+        self.container_frontend_timeout.reset()
+    }
+
+    /// re-arm the back timeout while the response is awaited or received,
+    /// shortened to the deadline of the request if any
+    fn reset_backend_response_timeout(&mut self) -> bool {
+        let duration = self
+            .request_timeouts
+            .within_deadline(self.request_timeouts.backend_response);
+        if duration != self.container_backend_timeout.duration() {
+            self.container_backend_timeout.set_duration(duration);
+        }
+        self.container_backend_timeout.reset()
+    }
+
+    /// resolve the timeouts of a routed request: each one is taken from its
+    /// frontend, its cluster or its listener, in this order
+    fn set_request_timeouts(&mut self, frontend: Option<Timeouts>, cluster: Option<Timeouts>) {
+        let timeouts = frontend
+            .unwrap_or_default()
+            .or(&cluster.unwrap_or_default())
+            .or(&self.listener.borrow().get_timeouts());
+        let seconds = |timeout: Option<u32>, default: Duration| {
+            timeout.map_or(default, |seconds| Duration::from_secs(seconds as u64))
+        };
+
+        self.request_timeouts = RequestTimeouts {
+            body_read: seconds(timeouts.body_read, self.configured_frontend_timeout),
+            backend_connect: seconds(timeouts.backend_connect, self.configured_connect_timeout),
+            backend_response: seconds(timeouts.backend_response, self.configured_backend_timeout),
+            deadline: timeouts.request_deadline.map(|seconds| {
+                let deadline = Duration::from_secs(seconds as u64);
+                (Instant::now() + deadline, deadline)
+            }),
+        };
+        let body_read = self
+            .request_timeouts
+            .within_deadline(self.request_timeouts.body_read);
+        self.container_frontend_timeout.set_duration(body_read);
+    }
+
     pub fn front_socket(&self) -> &TcpStream {
         self.frontend_socket.socket_ref()
     }
@@ -1137,6 +1593,9 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         }
 
+        // the request is sent again to the next backend
+        self.context.request_sent = None;
+
         if let Some(token) = self.backend_token.take() {
             proxy.remove_session(token);
 
@@ -1249,12 +1708,16 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         };
 
-        let route_result = self
-            .listener
-            .borrow()
-            .frontend_from_request(host, uri, method);
+        let client_tls = self.frontend_socket.socket_client_tls();
+        let route_result = self.listener.borrow().frontend_from_request(
+            host,
+            uri,
+            method,
+            client_tls.as_ref(),
+            &self.request_stream,
+        );
 
-        let route = match route_result {
+        let (route, frontend_options) = match route_result {
             Ok(route) => route,
             Err(frontend_error) => {
                 self.set_answer(DefaultAnswer::Answer404 {});
@@ -1262,32 +1725,341 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         };
 
-        let cluster_id = match route {
-            Route::ClusterId(cluster_id) => cluster_id,
-            Route::Deny => {
+        let cluster_id = match route.cluster_id() {
+            Some(cluster_id) => cluster_id,
+            None => {
                 self.set_answer(DefaultAnswer::Answer401 {});
                 return Err(RetrieveClusterError::UnauthorizedRoute);
             }
         };
 
-        let frontend_should_redirect_https = matches!(proxy.borrow().kind(), ListenerType::Http)
-            && proxy
-                .borrow()
-                .clusters()
-                .get(&cluster_id)
-                .map(|cluster| cluster.https_redirect)
-                .unwrap_or(false);
+        let (
+            pipeline,
+            max_header_size,
+            filter_time_budget,
+            https_policy,
+            cluster_timeouts,
+            max_response_body_size,
+            response_flush_delay,
+            slow_log,
+            rate_limit,
+            fault_injection,
+        ) = proxy
+            .borrow()
+            .clusters()
+            .get(&cluster_id)
+            .map(|cluster| {
+                (
+                    cluster.request_pipeline(),
+                    cluster.max_request_header_size,
+                    cluster.filter_time_budget,
+                    cluster.https_policy.clone(),
+                    cluster.timeouts.clone(),
+                    cluster.max_response_body_size,
+                    cluster.response_flush_delay,
+                    cluster.slow_log.clone(),
+                    cluster.rate_limit.clone(),
+                    cluster.fault_injection.clone(),
+                )
+            })
+            .unwrap_or_default();
+
+        let pipeline_start = Instant::now();
+        // a retry routes the request again, it already went through the pipeline
+        if !self.context.routed {
+            let listener_type = proxy.borrow().kind();
+            self.run_request_pipeline(
+                &cluster_id,
+                &pipeline,
+                listener_type,
+                &frontend_options,
+                rate_limit.as_ref(),
+                fault_injection.as_ref(),
+            )?;
+        }
+
+        // HTTP requests of a cluster with an HTTPS policy were redirected by its pipeline
+        self.context.strict_transport_security = https_policy
+            .filter(|_| self.context.protocol == Protocol::HTTPS)
+            .map(|https_policy| https_policy.to_string());
+
+        self.set_request_timeouts(frontend_options.timeouts, cluster_timeouts);
+        self.context.max_response_body_size = max_response_body_size.map(|max| max as usize);
+        self.context.response_flush_delay = response_flush_delay
+            .filter(|delay| *delay > 0)
+            .map(|delay| Duration::from_millis(delay as u64));
+        self.context.slow_log = slow_log;
+
+        self.context.header_edits = frontend_options.headers;
+
+        if let Some(budget) = filter_time_budget {
+            let spent = self.context.header_edit_time + pipeline_start.elapsed();
+            if spent > Duration::from_micros(budget) {
+                incr!("http.budget.filter_time_exceeded", Some(&cluster_id), None);
+                warn!(
+                    "{} spent {}µs in header edits and filters, over the budget of {}µs of cluster {}",
+                    log_context!(self),
+                    spent.as_micros(),
+                    budget,
+                    cluster_id
+                );
+            }
+        }
 
-        if frontend_should_redirect_https {
-            self.set_answer(DefaultAnswer::Answer301 {
-                location: format!("https://{host}{uri}"),
-            });
-            return Err(RetrieveClusterError::UnauthorizedRoute);
+        if let Some(max) = max_header_size {
+            let size = outbound_header_size(&self.request_stream);
+            if size > max as usize {
+                incr!("http.budget.header_size_exceeded", Some(&cluster_id), None);
+                self.set_answer(DefaultAnswer::Answer413 {
+                    message: format!(
+                        "The request headers are {size} bytes once forwarded, cluster {cluster_id} accepts at most {max} bytes."
+                    ),
+                    phase: self.request_stream.parsing_phase.marker(),
+                    capacity: max as usize,
+                });
+                return Err(RetrieveClusterError::HeaderSizeExceeded {
+                    size,
+                    max: max as usize,
+                });
+            }
         }
 
+        self.context.routed = true;
         Ok(cluster_id)
     }
 
+    /// run the filters of the pipeline of the cluster, in order. A step that
+    /// stops on a match skips the next ones, and sends the answer of its filter
+    fn run_request_pipeline(
+        &mut self,
+        cluster_id: &str,
+        pipeline: &RequestPipeline,
+        listener_type: ListenerType,
+        frontend_options: &FrontendOptions,
+        cluster_rate_limit: Option<&ClientRateLimit>,
+        fault_injection: Option<&FaultInjection>,
+    ) -> Result<(), RetrieveClusterError> {
+        // the pin of the request only applies if its filter runs
+        let mut pinned_backend = self.context.pinned_backend.take();
+
+        for step in &pipeline.steps {
+            let stop = step.on_match != FilterAction::Continue as i32;
+            let matched = match RequestFilter::try_from(step.filter) {
+                Ok(RequestFilter::HttpsRedirect) => {
+                    self.redirect_to_https(cluster_id, listener_type, stop)?
+                }
+                Ok(RequestFilter::RateLimit) => self.check_client_rate_limits(
+                    cluster_id,
+                    frontend_options,
+                    cluster_rate_limit,
+                    stop,
+                )?,
+                Ok(RequestFilter::FaultInjection) => match fault_injection {
+                    Some(fault_injection) => {
+                        self.inject_faults(cluster_id, fault_injection, stop)?
+                    }
+                    None => false,
+                },
+                Ok(RequestFilter::Mirroring) => {
+                    match frontend_options.mirror.as_ref().filter(|m| sampled(m)) {
+                        Some(mirror) => {
+                            self.mirror_request(mirror, cluster_id);
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                Ok(RequestFilter::HeaderEdits) => {
+                    self.edit_request_headers(&frontend_options.headers)
+                }
+                Ok(RequestFilter::BackendPinning) => {
+                    self.context.pinned_backend = pinned_backend.take();
+                    self.context.pinned_backend.is_some()
+                }
+                Err(_) => false,
+            };
+            if !matched {
+                continue;
+            }
+            if stop {
+                debug!(
+                    "{} filter {} matched, skipping the rest of the pipeline of cluster {}",
+                    log_context!(self),
+                    step,
+                    cluster_id
+                );
+                break;
+            }
+            debug!(
+                "{} filter {} matched, continuing the pipeline of cluster {}",
+                log_context!(self),
+                step,
+                cluster_id
+            );
+        }
+        Ok(())
+    }
+
+    /// answer requests received on an HTTP listener with a 301 to the same URL over HTTPS
+    fn redirect_to_https(
+        &mut self,
+        cluster_id: &str,
+        listener_type: ListenerType,
+        stop: bool,
+    ) -> Result<bool, RetrieveClusterError> {
+        if listener_type != ListenerType::Http {
+            return Ok(false);
+        }
+        if !stop {
+            debug!(
+                "{} request to cluster {} not redirected to HTTPS",
+                log_context!(self),
+                cluster_id
+            );
+            return Ok(true);
+        }
+        let location = format!(
+            "https://{}{}",
+            self.context.authority.as_deref().unwrap_or_default(),
+            self.context.path.as_deref().unwrap_or_default()
+        );
+        self.set_answer(DefaultAnswer::Answer301 { location });
+        Err(RetrieveClusterError::UnauthorizedRoute)
+    }
+
+    /// apply the request header edits of the frontend, returns false if it has none
+    fn edit_request_headers(&mut self, edits: &[HeaderEdit]) -> bool {
+        if !edits
+            .iter()
+            .any(|edit| edit.position == HeaderPosition::Request as i32)
+        {
+            return false;
+        }
+        self.context.rewritten |= edit_headers(
+            &mut self.request_stream,
+            edits,
+            HeaderPosition::Request,
+            &self.context.id.to_string(),
+        );
+        true
+    }
+
+    /// take a token from the buckets of the client in the frontend and in the cluster.
+    /// If one of them is empty, the client is answered with a 429 when `stop` is set
+    fn check_client_rate_limits(
+        &mut self,
+        cluster_id: &str,
+        frontend_options: &FrontendOptions,
+        cluster_rate_limit: Option<&ClientRateLimit>,
+        stop: bool,
+    ) -> Result<bool, RetrieveClusterError> {
+        let Some(client) = self.get_session_address().map(|address| address.ip()) else {
+            return Ok(false);
+        };
+        let now = Instant::now();
+
+        let checked = CLIENT_RATE_LIMITS.with(|limits| {
+            limits.borrow_mut().check(
+                frontend_options
+                    .rate_limit
+                    .as_ref()
+                    .map(|(frontend, rate_limit)| (frontend.as_str(), rate_limit)),
+                cluster_rate_limit.map(|rate_limit| (cluster_id, rate_limit)),
+                client,
+                now,
+            )
+        });
+
+        let Err((scope, retry_after)) = checked else {
+            return Ok(false);
+        };
+        if !stop {
+            debug!(
+                "{} {} is over the rate limit of the {} of cluster {}, not rejected",
+                log_context!(self),
+                client,
+                scope,
+                cluster_id
+            );
+            return Ok(true);
+        }
+        match scope {
+            "frontend" => incr!("http.rate_limit.frontend_rejected", Some(cluster_id), None),
+            _ => incr!("http.rate_limit.cluster_rejected", Some(cluster_id), None),
+        }
+        debug!(
+            "{} {} is over the rate limit of the {} of cluster {}",
+            log_context!(self),
+            client,
+            scope,
+            cluster_id
+        );
+        self.set_answer(DefaultAnswer::Answer429 {
+            retry_after: retry_after.as_secs(),
+        });
+        Err(RetrieveClusterError::RateLimited(scope))
+    }
+
+    /// answer the request with a 503 when `stop` is set, or hold it before it is written
+    /// to the backend, if it is drawn among the requests the faults of its cluster are
+    /// injected in. Returns whether it was drawn to be aborted
+    fn inject_faults(
+        &mut self,
+        cluster_id: &str,
+        fault_injection: &FaultInjection,
+        stop: bool,
+    ) -> Result<bool, RetrieveClusterError> {
+        let mut rng = rand::thread_rng();
+        let aborted = rng.gen_range(0..100) < fault_injection.abort_percent;
+        if aborted && stop {
+            incr!("http.fault_injection.aborted", Some(cluster_id), None);
+            self.set_answer(DefaultAnswer::Answer503 {
+                message: format!("A fault injected in cluster {cluster_id} aborted the request."),
+            });
+            return Err(RetrieveClusterError::FaultInjected);
+        }
+        if aborted {
+            debug!(
+                "{} request drawn to be aborted by the faults of cluster {}, not aborted",
+                log_context!(self),
+                cluster_id
+            );
+        }
+        if fault_injection.delay > 0 && rng.gen_range(0..100) < fault_injection.delay_percent {
+            incr!("http.fault_injection.delayed", Some(cluster_id), None);
+            self.context.fault_delay = Some(Duration::from_millis(fault_injection.delay as u64));
+        }
+        Ok(aborted)
+    }
+
+    /// copy the raw request to the sink of the mirror of its frontend
+    fn mirror_request(&self, mirror: &RequestMirror, cluster_id: &str) {
+        let Some(raw) = mirrored_bytes(self.request_stream.storage.used(), mirror.max_body_prefix)
+        else {
+            return;
+        };
+        let written = MIRRORS.with(|mirrors| {
+            mirrors.borrow_mut().write(mirror, Instant::now(), || {
+                let unix_time_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis())
+                    .unwrap_or_default();
+                record(
+                    &self.context.id.to_string(),
+                    unix_time_ms,
+                    cluster_id,
+                    self.get_session_address(),
+                    raw,
+                )
+            })
+        });
+        if written {
+            incr!("http.mirror.written", Some(cluster_id), None);
+        } else {
+            incr!("http.mirror.dropped", Some(cluster_id), None);
+        }
+    }
+
     pub fn backend_from_request(
         &mut self,
         cluster_id: &str,
@@ -1295,6 +2067,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         proxy: Rc<RefCell<dyn L7Proxy>>,
         metrics: &mut SessionMetrics,
     ) -> Result<TcpStream, BackendConnectionError> {
+        if let Some(backend_id) = self.context.pinned_backend.clone() {
+            return self.pinned_backend_from_request(cluster_id, &backend_id, proxy, metrics);
+        }
+        let backends = proxy.borrow().backends();
         let (backend, conn) = self
             .get_backend_for_sticky_session(
                 frontend_should_stick,
@@ -1315,23 +2091,57 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             // update sticky name in case it changed I guess?
             self.context.sticky_name = self.listener.borrow().get_sticky_name().to_string();
 
-            self.context.sticky_session = Some(
-                backend
-                    .borrow()
-                    .sticky_id
-                    .clone()
-                    .unwrap_or_else(|| backend.borrow().backend_id.clone()),
-            );
+            let backend = backend.borrow();
+            let sticky_id = backend.sticky_id.as_ref().unwrap_or(&backend.backend_id);
+            self.context.sticky_session =
+                Some(backends.borrow().sticky_signer.sign(cluster_id, sticky_id));
         }
 
         metrics.backend_id = Some(backend.borrow().backend_id.clone());
         metrics.backend_start();
+        self.context.backend_address = Some(backend.borrow().address);
         self.set_backend_id(backend.borrow().backend_id.clone());
 
         self.backend = Some(backend);
         Ok(conn)
     }
 
+    /// connect to the backend a trusted client pinned its request to, without
+    /// load balancing or sticky session
+    fn pinned_backend_from_request(
+        &mut self,
+        cluster_id: &str,
+        backend_id: &str,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+        metrics: &mut SessionMetrics,
+    ) -> Result<TcpStream, BackendConnectionError> {
+        let (backend, conn) = proxy
+            .borrow()
+            .backends()
+            .borrow_mut()
+            .backend_from_id(cluster_id, backend_id, self.get_session_address())
+            .map_err(|backend_error| {
+                self.set_answer(DefaultAnswer::Answer503 {
+                    message: backend_error.to_string(),
+                });
+                BackendConnectionError::Backend(backend_error)
+            })?;
+        incr!("http.backend_pinning", Some(cluster_id), Some(backend_id));
+        info!(
+            "{} request pinned to backend {}",
+            log_context!(self),
+            backend_id
+        );
+
+        metrics.backend_id = Some(backend_id.to_owned());
+        metrics.backend_start();
+        self.context.backend_address = Some(backend.borrow().address);
+        self.set_backend_id(backend_id.to_owned());
+
+        self.backend = Some(backend);
+        Ok(conn)
+    }
+
     fn get_backend_for_sticky_session(
         &self,
         frontend_should_stick: bool,
@@ -1339,17 +2149,42 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         cluster_id: &str,
         proxy: Rc<RefCell<dyn L7Proxy>>,
     ) -> Result<(Rc<RefCell<Backend>>, TcpStream), BackendError> {
+        let backends = proxy.borrow().backends();
+        let hash_value = backends
+            .borrow()
+            .request_hash_key(cluster_id)
+            .and_then(|hash_key| self.request_hash_value(hash_key));
         match (frontend_should_stick, sticky_session) {
-            (true, Some(sticky_session)) => proxy
-                .borrow()
-                .backends()
-                .borrow_mut()
-                .backend_from_sticky_session(cluster_id, sticky_session),
-            _ => proxy
-                .borrow()
-                .backends()
-                .borrow_mut()
-                .backend_from_cluster_id(cluster_id),
+            (true, Some(sticky_session)) => backends.borrow_mut().backend_from_sticky_session(
+                cluster_id,
+                sticky_session,
+                self.get_session_address(),
+                hash_value.as_deref(),
+            ),
+            _ => backends.borrow_mut().backend_from_hash_value(
+                cluster_id,
+                self.get_session_address(),
+                hash_value.as_deref(),
+            ),
+        }
+    }
+
+    /// the value of the header or cookie a cluster with a consistent hash
+    /// chooses the backend from, if the request has it
+    fn request_hash_value(&self, hash_key: &HashKey) -> Option<Vec<u8>> {
+        let name = hash_key.name.as_deref()?;
+        match HashKeyKind::try_from(hash_key.kind) {
+            Ok(HashKeyKind::Header) => self.request_stream.get(name).map(<[u8]>::to_vec),
+            Ok(HashKeyKind::Cookie) => {
+                let buf = self.request_stream.storage.buffer();
+                self.request_stream
+                    .detached
+                    .jar
+                    .iter()
+                    .find(|cookie| cookie.key.data(buf) == name.as_bytes())
+                    .map(|cookie| cookie.val.data(buf).to_vec())
+            }
+            _ => None,
         }
     }
 
@@ -1384,11 +2219,18 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 .as_ref()
                 .map(|backend| {
                     let backend = backend.borrow();
-                    proxy
-                        .borrow()
-                        .backends()
-                        .borrow()
-                        .has_backend(&cluster_id, &backend)
+                    // a request pinned to another backend needs a new connection
+                    let pinned_elsewhere = self
+                        .context
+                        .pinned_backend
+                        .as_ref()
+                        .is_some_and(|backend_id| *backend_id != backend.backend_id);
+                    !pinned_elsewhere
+                        && proxy
+                            .borrow()
+                            .backends()
+                            .borrow()
+                            .has_backend(&cluster_id, &backend)
                 })
                 .unwrap_or(false);
 
@@ -1426,6 +2268,17 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 e
             );
         }
+        let client_dscp = proxy.borrow().backends().borrow().client_dscp(&cluster_id);
+        if let Some(dscp) = client_dscp {
+            if let Err(e) = set_dscp(self.front_socket(), dscp) {
+                error!(
+                    "{} Error setting DSCP {} on front socket: {:?}",
+                    log_context!(self),
+                    dscp,
+                    e
+                );
+            }
+        }
 
         self.backend_readiness.interest = Ready::WRITABLE | Ready::HUP | Ready::ERROR;
         self.backend_connection_status = BackendConnectionStatus::Connecting(Instant::now());
@@ -1447,7 +2300,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 }
 
                 self.set_backend_socket(socket, self.backend.clone());
-                self.set_backend_timeout(self.configured_connect_timeout);
+                self.set_backend_timeout(
+                    self.request_timeouts
+                        .within_deadline(self.request_timeouts.backend_connect),
+                );
 
                 Ok(BackendConnectAction::Replace)
             }
@@ -1469,7 +2325,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
                 self.set_backend_socket(socket, self.backend.clone());
                 self.set_backend_token(backend_token);
-                self.set_backend_timeout(self.configured_connect_timeout);
+                self.set_backend_timeout(
+                    self.request_timeouts
+                        .within_deadline(self.request_timeouts.backend_connect),
+                );
 
                 Ok(BackendConnectAction::New)
             }
@@ -1495,7 +2354,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
             // the back timeout was of connect_timeout duration before,
             // now that we're connected, move to backend_timeout duration
-            self.set_backend_timeout(self.configured_backend_timeout);
+            self.set_backend_timeout(
+                self.request_timeouts
+                    .within_deadline(self.request_timeouts.backend_response),
+            );
             // if we are not waiting for the backend response, its timeout is concelled
             // it should be set when the request has been entirely transmitted
             if !self.backend_readiness.interest.is_readable() {
@@ -1522,7 +2384,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                         backend_id: Some(backend.backend_id.to_owned()),
                         address: Some(backend.address.into()),
                         cluster_id: None,
+                        alert: None,
+                        value: None,
                     });
+                    backend.start_slow_start();
                 }
 
                 if let BackendConnectionStatus::Connecting(start) = last {
@@ -1531,6 +2396,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
                 //successful connection, reset failure counter
                 backend.failures = 0;
+                backend.connect_successes += 1;
                 backend.active_requests += 1;
                 backend.retry_policy.succeed();
             }
@@ -1541,6 +2407,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         if let Some(backend) = &self.backend {
             let mut backend = backend.borrow_mut();
             backend.failures += 1;
+            backend.connect_failures += 1;
 
             let already_unavailable = backend.retry_policy.is_down();
             backend.retry_policy.fail();
@@ -1569,6 +2436,8 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     backend_id: Some(backend.backend_id.to_owned()),
                     address: Some(backend.address.into()),
                     cluster_id: None,
+                    alert: None,
+                    value: None,
                 });
             }
         }
@@ -1613,7 +2482,9 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     self.log_request_success(metrics);
                     StateResult::CloseSession
                 } else {
-                    // writable() will be called again and finish the session properly
+                    // writable() will be called again and finish the session properly,
+                    // even if the body was held back by a flush delay
+                    self.frontend_readiness.interest.insert(Ready::WRITABLE);
                     StateResult::CloseBackend
                 }
             }
@@ -1696,6 +2567,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 }
             } else {
                 metrics.backend_connected();
+                self.context.retries = self.connection_attempts;
                 self.connection_attempts = 0;
                 self.set_backend_connected(BackendConnectionStatus::Connected, metrics);
                 // we might get an early response from the backend, so we want to look
@@ -1910,6 +2782,76 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
 
     fn timeout(&mut self, token: Token, metrics: &mut SessionMetrics) -> StateResult {
         //info!("got timeout for token: {:?}", token);
+        if let Some(deadline) = self.request_timeouts.exceeded_deadline() {
+            if self.frontend_token == token {
+                self.container_frontend_timeout.triggered();
+            } else if self.backend_token == Some(token) {
+                self.container_backend_timeout.triggered();
+            } else {
+                error!("{} Got timeout for an invalid token", log_context!(self));
+                return StateResult::CloseSession;
+            }
+            incr!(
+                "http.request_deadline_exceeded",
+                self.context.cluster_id.as_deref(),
+                self.context.backend_id.as_deref()
+            );
+            return match self.timeout_status() {
+                TimeoutStatus::Request | TimeoutStatus::WaitingForResponse => {
+                    if self.backend_token.is_some() {
+                        self.record_outcome(RequestOutcome::Timeout);
+                    }
+                    self.set_answer(DefaultAnswer::Answer504 {
+                        duration: format!("{deadline:?} (request deadline)"),
+                    });
+                    self.writable(metrics)
+                }
+                // the response started, it cannot be replaced by an answer
+                TimeoutStatus::Response => {
+                    error!(
+                        "{} request deadline of {:?} exceeded while receiving the response (cluster {:?})",
+                        log_context!(self),
+                        deadline,
+                        self.context.cluster_id
+                    );
+                    StateResult::CloseSession
+                }
+                TimeoutStatus::WaitingForNewRequest => StateResult::CloseSession,
+            };
+        }
+
+        // the flush timeout shares the token of the frontend, it is told apart by its date
+        if self.frontend_token == token
+            && self.flush_held_since.is_some_and(|held_since| {
+                Instant::now() + DEADLINE_TOLERANCE
+                    >= held_since + self.container_flush_timeout.duration()
+            })
+        {
+            self.container_flush_timeout.triggered();
+            self.frontend_readiness.interest.insert(Ready::WRITABLE);
+            return self.writable(metrics);
+        }
+
+        // so does the timeout of a request held back by an injected delay
+        if self.frontend_token == token
+            && self.fault_held_since.is_some_and(|held_since| {
+                Instant::now() + DEADLINE_TOLERANCE
+                    >= held_since + self.container_fault_timeout.duration()
+            })
+        {
+            self.container_fault_timeout.triggered();
+            self.fault_held_since = None;
+            self.context.fault_delay = None;
+            self.backend_readiness.interest.insert(Ready::WRITABLE);
+            if self.backend_connection_status != BackendConnectionStatus::Connected {
+                return StateResult::Continue;
+            }
+            return match self.backend_writable(metrics) {
+                SessionResult::Close => StateResult::CloseSession,
+                _ => StateResult::Continue,
+            };
+        }
+
         if self.frontend_token == token {
             self.container_frontend_timeout.triggered();
             return match self.timeout_status() {
@@ -1951,12 +2893,14 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
                     self.writable(metrics)
                 }
                 TimeoutStatus::WaitingForResponse => {
+                    self.record_outcome(RequestOutcome::Timeout);
                     self.set_answer(DefaultAnswer::Answer504 {
                         duration: self.container_backend_timeout.to_string(),
                     });
                     self.writable(metrics)
                 }
                 TimeoutStatus::Response => {
+                    self.record_outcome(RequestOutcome::Timeout);
                     error!(
                         "backend {:?} timeout while receiving response (cluster {:?})",
                         self.context.backend_id, self.context.cluster_id
@@ -1975,6 +2919,8 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
     fn cancel_timeouts(&mut self) {
         self.container_backend_timeout.cancel();
         self.container_frontend_timeout.cancel();
+        self.container_flush_timeout.cancel();
+        self.container_fault_timeout.cancel();
     }
 
     fn print_state(&self, context: &str) {
@@ -2057,3 +3003,830 @@ fn save_http_status_metric(status: Option<u16>, context: LogContext) {
         }
     }
 }
+
+/// Save the sizes of the bodies of a request and its response, forwarded for a cluster
+fn save_http_body_metrics(cluster_id: &str, request_body_size: usize, response_body_size: usize) {
+    size!("http.request_body_size", cluster_id, request_body_size);
+    size!("http.response_body_size", cluster_id, response_body_size);
+    count!(
+        "http.request_body_bytes",
+        request_body_size as i64,
+        Some(cluster_id),
+        None
+    );
+    count!(
+        "http.response_body_bytes",
+        response_body_size as i64,
+        Some(cluster_id),
+        None
+    );
+}
+
+/// Size in bytes of the request line and headers of a parsed request, as they will be
+/// written to the backend: it accounts for the headers added or elided by the proxy.
+fn outbound_header_size<T: kawa::AsBuffer>(stream: &kawa::Kawa<T>) -> usize {
+    let buf = stream.storage.buffer();
+    let len = |store: &kawa::Store| store.data_opt(buf).map_or(0, <[u8]>::len);
+
+    let mut size = 0;
+    for block in &stream.blocks {
+        match block {
+            kawa::Block::StatusLine => {
+                if let kawa::StatusLine::Request {
+                    method,
+                    uri,
+                    authority,
+                    ..
+                } = &stream.detached.status_line
+                {
+                    // "{method} {uri} HTTP/1.x\r\nHost: {authority}\r\n"
+                    size += len(method) + len(uri) + len(authority) + 20;
+                }
+            }
+            kawa::Block::Header(pair) if !pair.is_elided() => {
+                // "{key}: {val}\r\n"
+                size += len(&pair.key) + len(&pair.val) + 4;
+            }
+            kawa::Block::Cookies => {
+                let mut cookies = stream
+                    .detached
+                    .jar
+                    .iter()
+                    .filter(|cookie| !cookie.is_elided())
+                    .peekable();
+                if cookies.peek().is_none() {
+                    continue;
+                }
+                // "Cookie: {key}={val}; {key}={val}\r\n"
+                size += 10;
+                for (index, cookie) in cookies.enumerate() {
+                    if index > 0 {
+                        size += 2;
+                    }
+                    size += len(&cookie.key) + len(&cookie.val) + 1;
+                }
+            }
+            kawa::Block::Flags(flags) if flags.end_header => {
+                return size + 2;
+            }
+            _ => {}
+        }
+    }
+    size
+}
+
+/// Size in bytes of the body parsed in the stream and not written yet, without the
+/// chunk headers of a chunked body
+fn pending_body_size<T: kawa::AsBuffer>(stream: &kawa::Kawa<T>) -> usize {
+    let buf = stream.storage.buffer();
+    stream
+        .blocks
+        .iter()
+        .map(|block| match block {
+            kawa::Block::Chunk(chunk) => chunk.data.data_opt(buf).map_or(0, <[u8]>::len),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// elides the User-Agent header and adds a Sozu-Id header, like the editor of the proxy
+    struct Edit;
+
+    impl<T: kawa::AsBuffer> kawa::h1::ParserCallbacks<T> for Edit {
+        fn on_headers(&mut self, stream: &mut kawa::Kawa<T>) {
+            let buf = stream.storage.buffer();
+            for block in &mut stream.blocks {
+                if let kawa::Block::Header(header) = block {
+                    if header.key.data_opt(buf) == Some(b"User-Agent") {
+                        header.elide();
+                    }
+                }
+            }
+            stream.push_block(kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(b"Sozu-Id"),
+                val: kawa::Store::Static(b"0123456789"),
+            }));
+        }
+    }
+
+    fn parse<C>(request: &[u8], callbacks: &mut C) -> usize
+    where
+        C: for<'a> kawa::h1::ParserCallbacks<kawa::SliceBuffer<'a>>,
+    {
+        let mut storage = [0u8; 256];
+        let mut stream = kawa::Kawa::new(
+            kawa::Kind::Request,
+            kawa::Buffer::new(kawa::SliceBuffer(&mut storage)),
+        );
+        stream.storage.write_all(request).unwrap();
+        kawa::h1::parse(&mut stream, callbacks);
+        outbound_header_size(&stream)
+    }
+
+    #[test]
+    fn outbound_header_size_matches_the_written_headers() {
+        let request = b"GET /path?q=1 HTTP/1.1\r\nHost: example.com\r\nUser-Agent: test\r\nCookie: a=1; b=22\r\n\r\n";
+        assert_eq!(parse(request, &mut kawa::h1::NoCallbacks), request.len());
+        assert_eq!(
+            parse(request, &mut Edit),
+            request.len() - "User-Agent: test\r\n".len() + "Sozu-Id: 0123456789\r\n".len()
+        );
+    }
+
+    #[test]
+    fn pending_body_size_skips_the_chunk_headers() {
+        let mut storage = [0u8; 256];
+        let mut stream = kawa::Kawa::new(
+            kawa::Kind::Request,
+            kawa::Buffer::new(kawa::SliceBuffer(&mut storage)),
+        );
+        stream
+            .storage
+            .write_all(b"POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n6\r\nefghij\r\n0\r\n\r\n")
+            .unwrap();
+        kawa::h1::parse(&mut stream, &mut kawa::h1::NoCallbacks);
+        assert_eq!(pending_body_size(&stream), 10);
+
+        stream.prepare(&mut kawa::h1::BlockConverter);
+        assert_eq!(pending_body_size(&stream), 0);
+    }
+
+    #[test]
+    fn replace_responses_larger_than_the_cluster_limit() {
+        use crate::testing::{
+            free_address, http_ok_response, http_request, http_state, send_request, status_code,
+            MockBackend, TestProxy,
+        };
+        use sozu_command::proto::command::Cluster;
+
+        let small = MockBackend::start(http_ok_response("small")).unwrap();
+        let large = MockBackend::start(http_ok_response(&"large".repeat(10))).unwrap();
+        let chunked = MockBackend::start(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n20\r\n0123456789abcdef0123456789abcdef\r\n0\r\n\r\n",
+        )
+        .unwrap();
+
+        for (backend, status, too_large) in [(&small, 200, 0), (&large, 502, 1), (&chunked, 502, 1)]
+        {
+            let front = free_address();
+            let cluster = Cluster {
+                cluster_id: String::from("cluster_1"),
+                max_response_body_size: Some(16),
+                ..Default::default()
+            };
+            let state = http_state(front, cluster, "example.com", &[backend.address]);
+            let mut proxy = TestProxy::start("LIMIT", &state).unwrap();
+
+            let response =
+                send_request(front, &http_request("GET", "example.com", "/", "")).unwrap();
+            assert_eq!(status_code(&response), Some(status), "{response}");
+            assert_eq!(
+                proxy
+                    .backend_count("cluster_1", "cluster_1-0", "http.response_body_too_large")
+                    .unwrap(),
+                too_large
+            );
+            proxy.stop().unwrap();
+        }
+    }
+
+    #[test]
+    fn mirror_the_requests_of_a_frontend_to_a_file() {
+        use crate::testing::{
+            free_address, http_ok_response, http_request, http_state, send_request, status_code,
+            MockBackend, TestProxy,
+        };
+        use sozu_command::proto::command::{
+            request::RequestType, Cluster, MirrorSink, PathRule, RequestHttpFrontend, RulePosition,
+        };
+
+        let path = std::env::temp_dir().join(format!("sozu-mirror-front-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let backend = MockBackend::start(http_ok_response("ok")).unwrap();
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        };
+        let mut state = http_state(front, cluster, "example.com", &[backend.address]);
+        state
+            .dispatch(
+                &RequestType::AddHttpFrontend(RequestHttpFrontend {
+                    cluster_id: Some(String::from("cluster_1")),
+                    address: front.into(),
+                    hostname: String::from("mirrored.com"),
+                    path: PathRule::prefix(String::from("/")),
+                    position: RulePosition::Tree.into(),
+                    mirror: Some(RequestMirror {
+                        sink: MirrorSink::File as i32,
+                        path: path.to_string_lossy().into_owned(),
+                        sample_one_in: 1,
+                        max_per_second: 2,
+                        max_body_prefix: 3,
+                    }),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .unwrap();
+        let mut proxy = TestProxy::start("MIRROR", &state).unwrap();
+
+        let request = http_request("POST", "mirrored.com", "/upload", "payload");
+        for _ in 0..3 {
+            assert_eq!(
+                status_code(&send_request(front, &request).unwrap()),
+                Some(200)
+            );
+        }
+        let other = http_request("GET", "example.com", "/", "");
+        assert_eq!(
+            status_code(&send_request(front, &other).unwrap()),
+            Some(200)
+        );
+
+        assert_eq!(
+            proxy
+                .cluster_count("cluster_1", "http.mirror.written")
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            proxy
+                .cluster_count("cluster_1", "http.mirror.dropped")
+                .unwrap(),
+            1
+        );
+        proxy.stop().unwrap();
+
+        let content = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mirrored = &request[..request.find("\r\n\r\n").unwrap() + 7];
+        let records: Vec<&str> = content.split("SOZU-MIRROR ").skip(1).collect();
+        assert_eq!(records.len(), 2, "{content}");
+        for record in records {
+            let (header, raw) = record.split_once('\n').unwrap();
+            let fields: Vec<&str> = header.split(' ').collect();
+            assert_eq!(fields[2], "cluster_1");
+            assert!(fields[3].starts_with("127.0.0.1:"));
+            assert_eq!(fields[4], mirrored.len().to_string());
+            assert_eq!(raw, mirrored);
+        }
+    }
+
+    #[test]
+    fn mirror_a_retried_request_once() {
+        use crate::testing::{
+            free_address, http_ok_response, http_request, http_state, send_request, status_code,
+            MockBackend, TestProxy,
+        };
+        use sozu_command::proto::command::{
+            request::RequestType, Cluster, MirrorSink, PathRule, RequestHttpFrontend, RulePosition,
+        };
+
+        let path = std::env::temp_dir().join(format!("sozu-mirror-retry-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let backend = MockBackend::start(http_ok_response("ok")).unwrap();
+        // nothing listens there, the requests sent to it are retried on the other backend
+        let closed = free_address();
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        };
+        let mut state = http_state(front, cluster, "example.com", &[closed, backend.address]);
+        state
+            .dispatch(
+                &RequestType::AddHttpFrontend(RequestHttpFrontend {
+                    cluster_id: Some(String::from("cluster_1")),
+                    address: front.into(),
+                    hostname: String::from("mirrored.com"),
+                    path: PathRule::prefix(String::from("/")),
+                    position: RulePosition::Tree.into(),
+                    mirror: Some(RequestMirror {
+                        sink: MirrorSink::File as i32,
+                        path: path.to_string_lossy().into_owned(),
+                        sample_one_in: 1,
+                        max_per_second: 100,
+                        max_body_prefix: 0,
+                    }),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .unwrap();
+        let mut proxy = TestProxy::start("MIRROR_RETRY", &state).unwrap();
+
+        let request = http_request("GET", "mirrored.com", "/", "");
+        for _ in 0..2 {
+            assert_eq!(
+                status_code(&send_request(front, &request).unwrap()),
+                Some(200)
+            );
+        }
+        assert_eq!(
+            proxy
+                .cluster_count("cluster_1", "http.mirror.written")
+                .unwrap(),
+            2
+        );
+        proxy.stop().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn edit_the_headers_of_a_frontend() {
+        use crate::testing::{
+            free_address, http_request, http_state, send_request, status_code, MockBackend,
+            TestProxy,
+        };
+        use sozu_command::proto::command::{
+            request::RequestType, Cluster, HeaderEdit, HeaderEditKind, PathRule,
+            RequestHttpFrontend, RulePosition,
+        };
+
+        let backend = MockBackend::start(
+            "HTTP/1.1 200 OK\r\nServer: backend/1.0\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .unwrap();
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        };
+        let mut state = http_state(front, cluster, "example.com", &[backend.address]);
+        state
+            .dispatch(
+                &RequestType::AddHttpFrontend(RequestHttpFrontend {
+                    cluster_id: Some(String::from("cluster_1")),
+                    address: front.into(),
+                    hostname: String::from("edited.com"),
+                    path: PathRule::prefix(String::from("/")),
+                    position: RulePosition::Tree.into(),
+                    headers: vec![
+                        HeaderEdit::new(
+                            HeaderPosition::Request,
+                            HeaderEditKind::Remove,
+                            "X-Debug",
+                            None,
+                        ),
+                        HeaderEdit::new(
+                            HeaderPosition::Request,
+                            HeaderEditKind::Set,
+                            "X-Request-Id",
+                            Some("%REQUEST_ID"),
+                        ),
+                        HeaderEdit::new(
+                            HeaderPosition::Response,
+                            HeaderEditKind::Remove,
+                            "Server",
+                            None,
+                        ),
+                        HeaderEdit::new(
+                            HeaderPosition::Response,
+                            HeaderEditKind::Add,
+                            "X-Frame-Options",
+                            Some("DENY"),
+                        ),
+                    ],
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .unwrap();
+        let proxy = TestProxy::start("HEADERS", &state).unwrap();
+
+        let request = http_request("GET", "edited.com", "/", "").replacen(
+            "\r\n",
+            "\r\nX-Debug: 1\r\nX-Request-Id: forged\r\n",
+            1,
+        );
+        let response = send_request(front, &request).unwrap();
+        assert_eq!(status_code(&response), Some(200), "{response}");
+        assert!(!response.contains("Server:"), "{response}");
+        assert!(response.contains("X-Frame-Options: DENY\r\n"), "{response}");
+
+        // the other frontends of the cluster are not edited
+        let response = send_request(front, &http_request("GET", "example.com", "/", "")).unwrap();
+        assert!(response.contains("Server: backend/1.0\r\n"), "{response}");
+        proxy.stop().unwrap();
+
+        let requests = backend.requests();
+        let sozu_id = requests[0]
+            .lines()
+            .find_map(|line| line.strip_prefix("Sozu-Id: "))
+            .unwrap();
+        assert!(!requests[0].contains("X-Debug"), "{}", requests[0]);
+        assert!(!requests[0].contains("forged"), "{}", requests[0]);
+        assert!(
+            requests[0].contains(&format!("X-Request-Id: {sozu_id}\r\n")),
+            "{}",
+            requests[0]
+        );
+    }
+
+    #[test]
+    fn hold_response_bodies_for_the_flush_delay() {
+        use std::{
+            io::Read,
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        use crate::testing::{free_address, http_request, http_state, TestProxy};
+        use sozu_command::proto::command::{Cluster, QueryMetricsOptions};
+
+        // the head and a first chunk, a second chunk 200ms later, the end 1.5s later
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_address = backend.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n");
+            thread::sleep(Duration::from_millis(200));
+            let _ = stream.write_all(b"6\r\nsecond\r\n");
+            thread::sleep(Duration::from_millis(1500));
+            let _ = stream.write_all(b"0\r\n\r\n");
+        });
+
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            response_flush_delay: Some(600),
+            ..Default::default()
+        };
+        let state = http_state(front, cluster, "example.com", &[backend_address]);
+        let mut proxy = TestProxy::start("FLUSH", &state).unwrap();
+
+        let mut client = TcpStream::connect(front).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let start = Instant::now();
+        client
+            .write_all(http_request("GET", "example.com", "/", "").as_bytes())
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buffer = [0; 4096];
+        while !String::from_utf8_lossy(&response).contains("second") {
+            let size = client.read(&mut buffer).unwrap();
+            assert!(size > 0, "{}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buffer[..size]);
+        }
+        // written after the delay, instead of right away, and before the end
+        let held = start.elapsed();
+        assert!(held >= Duration::from_millis(450), "{held:?}");
+        assert!(held < Duration::from_millis(1500), "{held:?}");
+        assert!(!String::from_utf8_lossy(&response).ends_with("0\r\n\r\n"));
+
+        // the end of the response is written as soon as it is read
+        while !String::from_utf8_lossy(&response).ends_with("0\r\n\r\n") {
+            let size = client.read(&mut buffer).unwrap();
+            assert!(size > 0, "{}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buffer[..size]);
+        }
+        assert!(start.elapsed() < Duration::from_millis(2500));
+
+        let metrics = proxy
+            .query_metrics(QueryMetricsOptions {
+                cluster_ids: vec![String::from("cluster_1")],
+                metric_names: vec![String::from("http.response_flush_delay")],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(metrics
+            .clusters
+            .get("cluster_1")
+            .is_some_and(|cluster| cluster.cluster.contains_key("http.response_flush_delay")));
+        proxy.stop().unwrap();
+    }
+
+    #[test]
+    fn inject_faults_in_the_requests_of_a_cluster() {
+        use crate::testing::{
+            free_address, http_ok_response, http_request, http_state, send_request, status_code,
+            MockBackend, TestProxy,
+        };
+        use sozu_command::proto::command::{
+            request::RequestType, Cluster, ResponseStatus, SetFaultInjection,
+        };
+
+        let backend = MockBackend::start(http_ok_response("ok")).unwrap();
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        };
+        let state = http_state(front, cluster, "example.com", &[backend.address]);
+        let mut proxy = TestProxy::start("FAULTS", &state).unwrap();
+        let request = http_request("GET", "example.com", "/", "");
+
+        let set = |proxy: &mut TestProxy, fault_injection: Option<FaultInjection>| {
+            let response = proxy
+                .send(RequestType::SetFaultInjection(SetFaultInjection {
+                    cluster_id: String::from("cluster_1"),
+                    fault_injection,
+                }))
+                .unwrap();
+            assert_eq!(response.status, ResponseStatus::Ok as i32, "{response:?}");
+        };
+
+        // every request is held for the delay before it reaches the backend
+        set(
+            &mut proxy,
+            Some(FaultInjection {
+                delay: 500,
+                delay_percent: 100,
+                abort_percent: 0,
+            }),
+        );
+        let start = Instant::now();
+        let response = send_request(front, &request).unwrap();
+        assert_eq!(status_code(&response), Some(200), "{response}");
+        let held = start.elapsed();
+        assert!(held >= Duration::from_millis(450), "{held:?}");
+
+        // every request is answered with a 503, without reaching the backend
+        set(
+            &mut proxy,
+            Some(FaultInjection {
+                delay: 0,
+                delay_percent: 0,
+                abort_percent: 100,
+            }),
+        );
+        let response = send_request(front, &request).unwrap();
+        assert_eq!(status_code(&response), Some(503), "{response}");
+        assert_eq!(backend.requests_received(), 1);
+
+        set(&mut proxy, None);
+        let start = Instant::now();
+        let response = send_request(front, &request).unwrap();
+        assert_eq!(status_code(&response), Some(200), "{response}");
+        assert!(start.elapsed() < Duration::from_millis(450));
+        assert_eq!(backend.requests_received(), 2);
+
+        assert_eq!(
+            proxy
+                .cluster_count("cluster_1", "http.fault_injection.delayed")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            proxy
+                .cluster_count("cluster_1", "http.fault_injection.aborted")
+                .unwrap(),
+            1
+        );
+        proxy.stop().unwrap();
+    }
+
+    #[test]
+    fn measure_the_response_time_from_the_end_of_the_request() {
+        use std::{io::Read, net::TcpStream, thread};
+
+        use crate::testing::{free_address, http_ok_response, http_state, MockBackend, TestProxy};
+        use sozu_command::proto::command::Cluster;
+
+        let backend = MockBackend::start(http_ok_response("ok")).unwrap();
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        };
+        let state = http_state(front, cluster, "example.com", &[backend.address]);
+        let mut proxy = TestProxy::start("RESPONSE_TIME", &state).unwrap();
+
+        // the client takes its time to send the body, the backend answers at once
+        let mut client = TcpStream::connect(front).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(600));
+        client.write_all(b"body").unwrap();
+        let mut response = Vec::new();
+        let mut buffer = [0; 1024];
+        while !String::from_utf8_lossy(&response).ends_with("ok") {
+            let size = client.read(&mut buffer).unwrap();
+            assert!(size > 0, "{}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buffer[..size]);
+        }
+
+        // the estimate starts at 50ms, the upload would have raised it past 600ms
+        let estimate = proxy
+            .backend_count("cluster_1", "cluster_1-0", "backend_response_time_ewma")
+            .unwrap();
+        assert!(0 < estimate && estimate < 50_000, "{estimate}µs");
+        proxy.stop().unwrap();
+    }
+
+    #[test]
+    fn run_the_filters_in_the_order_of_the_pipeline() {
+        use crate::testing::{
+            free_address, http_ok_response, http_request, http_state, send_request, status_code,
+            MockBackend, TestProxy,
+        };
+        use sozu_command::proto::command::{
+            request::RequestType, Cluster, ResponseStatus, SetRequestPipeline,
+        };
+
+        let backend = MockBackend::start(http_ok_response("ok")).unwrap();
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            fault_injection: Some(FaultInjection {
+                delay: 0,
+                delay_percent: 0,
+                abort_percent: 100,
+            }),
+            ..Default::default()
+        };
+        let state = http_state(front, cluster, "example.com", &[backend.address]);
+        let mut proxy = TestProxy::start("PIPELINE", &state).unwrap();
+        let request = http_request("GET", "example.com", "/path", "");
+
+        let set = |proxy: &mut TestProxy, steps: &[&str]| {
+            let response = proxy
+                .send(RequestType::SetRequestPipeline(SetRequestPipeline {
+                    cluster_id: String::from("cluster_1"),
+                    pipeline: Some(RequestPipeline {
+                        steps: steps.iter().map(|step| step.parse().unwrap()).collect(),
+                    }),
+                }))
+                .unwrap();
+            assert_eq!(response.status, ResponseStatus::Ok as i32, "{response:?}");
+        };
+
+        // the fault aborts the request before it is redirected
+        set(&mut proxy, &["fault_injection", "https_redirect"]);
+        let response = send_request(front, &request).unwrap();
+        assert_eq!(status_code(&response), Some(503), "{response}");
+
+        // the redirect answers first, the fault is not drawn
+        set(&mut proxy, &["https_redirect", "fault_injection"]);
+        let response = send_request(front, &request).unwrap();
+        assert_eq!(status_code(&response), Some(301), "{response}");
+        assert!(response.contains("https://example.com/path"), "{response}");
+
+        // steps that continue only log the answers of their filters
+        set(
+            &mut proxy,
+            &["https_redirect:continue", "fault_injection:continue"],
+        );
+        let response = send_request(front, &request).unwrap();
+        assert_eq!(status_code(&response), Some(200), "{response}");
+
+        assert_eq!(backend.requests_received(), 1);
+        assert_eq!(
+            proxy
+                .cluster_count("cluster_1", "http.fault_injection.aborted")
+                .unwrap(),
+            1
+        );
+        proxy.stop().unwrap();
+    }
+
+    #[test]
+    fn describe_what_the_proxy_did_in_the_proxy_status_header() {
+        use crate::testing::{
+            free_address, http_ok_response, http_request, http_state, send_request, status_code,
+            MockBackend, TestProxy,
+        };
+        use sozu_command::proto::command::{
+            request::RequestType, AddBackend, ClientRateLimit, Cluster, HeaderEdit, HeaderEditKind,
+            PathRule, ProxyStatusHeader, RequestHttpFrontend, RulePosition,
+        };
+
+        let backend = MockBackend::start(http_ok_response("ok")).unwrap();
+        let front = free_address();
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        };
+        let mut state = http_state(front, cluster, "example.com", &[backend.address]);
+        state.http_listeners.get_mut(&front).unwrap().proxy_status =
+            Some(ProxyStatusHeader::ProxyStatus as i32);
+
+        let faulty = Cluster {
+            cluster_id: String::from("cluster_2"),
+            fault_injection: Some(FaultInjection {
+                delay: 0,
+                delay_percent: 0,
+                abort_percent: 100,
+            }),
+            ..Default::default()
+        };
+        let retried = Cluster {
+            cluster_id: String::from("cluster_3"),
+            ..Default::default()
+        };
+        // nothing listens on the first backend, its requests are retried on the second one
+        let backends = [
+            (free_address(), "cluster_3-0"),
+            (backend.address, "cluster_3-1"),
+        ];
+        let mut requests = vec![
+            RequestType::AddCluster(faulty),
+            RequestType::AddCluster(retried),
+        ];
+        for (address, backend_id) in backends {
+            requests.push(RequestType::AddBackend(AddBackend {
+                cluster_id: String::from("cluster_3"),
+                backend_id: String::from(backend_id),
+                address: address.into(),
+                ..Default::default()
+            }));
+        }
+        for request in requests {
+            state.dispatch(&request.into()).unwrap();
+        }
+        let frontends = [
+            ("retried.com", "cluster_3", Vec::new(), None),
+            ("limited.com", "cluster_1", Vec::new(), Some(1)),
+            (
+                "edited.com",
+                "cluster_1",
+                vec![HeaderEdit::new(
+                    HeaderPosition::Response,
+                    HeaderEditKind::Add,
+                    "X-Edited",
+                    Some("yes"),
+                )],
+                None,
+            ),
+            ("faulty.com", "cluster_2", Vec::new(), None),
+        ];
+        for (hostname, cluster_id, headers, requests_per_second) in frontends {
+            state
+                .dispatch(
+                    &RequestType::AddHttpFrontend(RequestHttpFrontend {
+                        cluster_id: Some(String::from(cluster_id)),
+                        address: front.into(),
+                        hostname: String::from(hostname),
+                        path: PathRule::prefix(String::from("/")),
+                        position: RulePosition::Tree.into(),
+                        headers,
+                        rate_limit: requests_per_second.map(|requests_per_second| {
+                            ClientRateLimit {
+                                requests_per_second,
+                                burst: None,
+                            }
+                        }),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+        let proxy = TestProxy::start("PROXY_STATUS", &state).unwrap();
+
+        let proxy_status = |hostname: &str, status: u16| {
+            let response = send_request(front, &http_request("GET", hostname, "/", "")).unwrap();
+            assert_eq!(status_code(&response), Some(status), "{response}");
+            response
+                .lines()
+                .find_map(|line| line.strip_prefix("Proxy-Status: "))
+                .map(str::to_owned)
+                .unwrap_or_else(|| panic!("no Proxy-Status in {response}"))
+        };
+        let forwarded = format!(
+            "sozu; next-hop=\"{}\"; received-status=200",
+            backend.address
+        );
+
+        assert_eq!(proxy_status("example.com", 200), forwarded);
+        // the round robin tries the closed backend first
+        assert_eq!(
+            proxy_status("retried.com", 200),
+            format!("{forwarded}; retries=1")
+        );
+
+        // the load is shed by the rate limit of the frontend and by the faults of the cluster
+        assert_eq!(proxy_status("limited.com", 200), forwarded);
+        assert_eq!(
+            proxy_status("limited.com", 429),
+            "sozu; error=http_request_denied"
+        );
+        assert_eq!(
+            proxy_status("faulty.com", 503),
+            "sozu; error=destination_unavailable"
+        );
+
+        // the headers of the response are edited on the way back
+        assert_eq!(
+            proxy_status("edited.com", 200),
+            format!("{forwarded}; rewritten")
+        );
+        proxy.stop().unwrap();
+    }
+}
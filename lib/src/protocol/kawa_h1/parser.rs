@@ -6,11 +6,15 @@ use std::{
 };
 
 use nom::{
-    bytes::{self, complete::take_while},
-    character::{complete::digit1, is_alphanumeric},
-    combinator::opt,
+    branch::alt,
+    bytes::{
+        self,
+        complete::{take_while, take_while1},
+    },
+    character::{complete::digit1, is_alphanumeric, is_hex_digit},
+    combinator::{opt, recognize},
     error::{Error, ErrorKind},
-    sequence::preceded,
+    sequence::{delimited, preceded},
     Err, IResult,
 };
 
@@ -63,6 +67,21 @@ impl Method {
             Method::Custom(String::from(unsafe { from_utf8_unchecked(s) }))
         }
     }
+
+    /// Idempotent methods, as defined in RFC 9110 section 9.2.2. Requests with
+    /// these methods can be sent in TLS 1.3 early data, since a replay has no
+    /// more effect than the original request.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Method::Get
+                | Method::Head
+                | Method::Options
+                | Method::Trace
+                | Method::Put
+                | Method::Delete
+        )
+    }
 }
 
 impl AsRef<str> for Method {
@@ -121,10 +140,23 @@ fn is_hostname_char(i: u8) -> bool {
   b"-.".contains(&i)
 }
 
+fn is_ipv6_char(i: u8) -> bool {
+    is_hex_digit(i) || b":.".contains(&i)
+}
+
+/// an IPv6 literal between brackets, like `[2001:db8::1]` (RFC 3986), brackets included
+fn ipv6_literal(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    recognize(delimited(
+        bytes::complete::tag("["),
+        take_while1(is_ipv6_char),
+        bytes::complete::tag("]"),
+    ))(i)
+}
+
 // FIXME: convert port to u16 here
 #[allow(clippy::type_complexity)]
 pub fn hostname_and_port(i: &[u8]) -> IResult<&[u8], (&[u8], Option<&[u8]>)> {
-    let (i, host) = take_while(is_hostname_char)(i)?;
+    let (i, host) = alt((ipv6_literal, take_while(is_hostname_char)))(i)?;
     let (i, port) = opt(preceded(bytes::complete::tag(":"), digit1))(i)?;
 
     if !i.is_empty() {
@@ -181,3 +213,32 @@ fn test_view_out_of_bound() {
         )
     );
 }
+
+#[test]
+fn test_hostname_and_port() {
+    assert_eq!(
+        hostname_and_port(b"example.com:8080"),
+        Ok((&b""[..], (&b"example.com"[..], Some(&b"8080"[..]))))
+    );
+    assert_eq!(
+        hostname_and_port(b"[2001:db8::1]:8080"),
+        Ok((&b""[..], (&b"[2001:db8::1]"[..], Some(&b"8080"[..]))))
+    );
+    assert_eq!(
+        hostname_and_port(b"[::ffff:192.0.2.1]"),
+        Ok((&b""[..], (&b"[::ffff:192.0.2.1]"[..], None)))
+    );
+    assert!(hostname_and_port(b"2001:db8::1").is_err());
+    assert!(hostname_and_port(b"[2001:db8::1").is_err());
+    assert!(hostname_and_port(b"[example.com]").is_err());
+}
+
+#[test]
+fn test_idempotent_methods() {
+    for method in ["GET", "head", "OPTIONS", "TRACE", "PUT", "DELETE"] {
+        assert!(Method::new(method.as_bytes()).is_idempotent(), "{method}");
+    }
+    for method in ["POST", "CONNECT", "PATCH"] {
+        assert!(!Method::new(method.as_bytes()).is_idempotent(), "{method}");
+    }
+}
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, net::SocketAddr, rc::Rc};
+use std::{cell::RefCell, cmp::min, net::SocketAddr, rc::Rc, time::Duration};
 
 use mio::{net::TcpStream, Token};
 use rusty_ulid::Ulid;
@@ -11,7 +11,7 @@ use crate::{
     backends::Backend,
     pool::Checkout,
     protocol::{http::parser::Method, SessionState},
-    socket::{stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol},
+    socket::{stats::socket_rtt, SocketHandler, SocketResult, TlsProperties, TransportProtocol},
     sozu_command::ready::Ready,
     timer::TimeoutContainer,
     L7Proxy, ListenerHandler, Protocol, Readiness, SessionMetrics, SessionResult, StateResult,
@@ -47,6 +47,109 @@ enum ConnectionStatus {
     Closed,
 }
 
+/// opcode of the WebSocket Close frames (RFC 6455 section 5.5.1)
+const WEBSOCKET_OPCODE_CLOSE: u8 = 0x8;
+/// status code of the Close frames sent when sozu closes a WebSocket connection
+/// itself, on a timeout or when the other peer went away (RFC 6455 section 7.4.1)
+const WEBSOCKET_GOING_AWAY: u16 = 1001;
+
+/// Follows the WebSocket frames sent in one direction of an upgraded connection,
+/// to know where a frame starts without buffering them
+#[derive(Debug, Default)]
+struct FrameTracker {
+    /// the header of the current frame, up to 14 bytes
+    header: [u8; 14],
+    /// bytes of the header received so far, 0 between two frames
+    header_length: usize,
+    /// bytes of the payload of the current frame not received yet
+    remaining: u64,
+    /// a Close frame was sent in this direction
+    close_seen: bool,
+}
+
+impl FrameTracker {
+    fn observe(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let skipped = min(self.remaining, data.len() as u64) as usize;
+                self.remaining -= skipped as u64;
+                data = &data[skipped..];
+                continue;
+            }
+
+            self.header[self.header_length] = data[0];
+            self.header_length += 1;
+            data = &data[1..];
+
+            if let Some(payload_length) = self.payload_length() {
+                if self.header[0] & 0x0f == WEBSOCKET_OPCODE_CLOSE {
+                    self.close_seen = true;
+                }
+                self.remaining = payload_length;
+                self.header_length = 0;
+            }
+        }
+    }
+
+    /// length of the payload of the current frame, once its header is complete
+    fn payload_length(&self) -> Option<u64> {
+        if self.header_length < 2 {
+            return None;
+        }
+        let extended_length = match self.header[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask_length = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+        if self.header_length < 2 + extended_length + mask_length {
+            return None;
+        }
+
+        if extended_length == 0 {
+            return Some((self.header[1] & 0x7f) as u64);
+        }
+        Some(
+            self.header[2..2 + extended_length]
+                .iter()
+                .fold(0, |length, byte| length << 8 | *byte as u64),
+        )
+    }
+
+    /// a Close frame can be inserted in the stream without breaking a frame
+    fn can_close(&self) -> bool {
+        !self.close_seen && self.header_length == 0 && self.remaining == 0
+    }
+}
+
+/// Framing of a connection upgraded to WebSocket
+#[derive(Debug, Default)]
+struct WebSocketFraming {
+    /// frames sent by the client
+    from_frontend: FrameTracker,
+    /// frames sent by the backend
+    from_backend: FrameTracker,
+}
+
+/// a Close frame with the "going away" status code. The frames sent to a server
+/// are masked with the given key (RFC 6455 section 5.3)
+fn websocket_close_frame(mask: Option<[u8; 4]>) -> Vec<u8> {
+    let payload = WEBSOCKET_GOING_AWAY.to_be_bytes();
+    let mut frame = vec![0x80 | WEBSOCKET_OPCODE_CLOSE];
+    match mask {
+        Some(mask) => {
+            frame.push(0x80 | payload.len() as u8);
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => {
+            frame.push(payload.len() as u8);
+            frame.extend_from_slice(&payload);
+        }
+    }
+    frame
+}
+
 /// matches sozu_command_lib::logging::access_logs::EndpointRecords
 pub enum WebSocketContext {
     Http {
@@ -79,7 +182,11 @@ pub struct Pipe<Front: SocketHandler, L: ListenerHandler> {
     protocol: Protocol,
     request_id: Ulid,
     session_address: Option<SocketAddr>,
+    /// key of the tags of the listener to log, its address if unset
+    tags_key: Option<String>,
     websocket_context: WebSocketContext,
+    /// set if the connection was upgraded to the WebSocket protocol
+    websocket: Option<WebSocketFraming>,
 }
 
 impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
@@ -140,7 +247,9 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             protocol,
             request_id,
             session_address,
+            tags_key: None,
             websocket_context,
+            websocket: None,
         };
 
         trace!("created pipe");
@@ -189,10 +298,77 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         }
     }
 
+    /// Follow the WebSocket frames of the connection, to close it with Close frames
+    /// instead of a TCP teardown. The idle timeout replaces the front and back timeouts
+    pub fn enable_websocket(&mut self, idle_timeout: Option<Duration>) {
+        let mut framing = WebSocketFraming::default();
+        // the data received along the upgrade is forwarded as the first frames
+        framing.from_frontend.observe(self.frontend_buffer.data());
+        framing.from_backend.observe(self.backend_buffer.data());
+        self.websocket = Some(framing);
+
+        if let Some(idle_timeout) = idle_timeout {
+            if let Some(timeout) = self.container_frontend_timeout.as_mut() {
+                timeout.set_duration(idle_timeout);
+            }
+            if let Some(timeout) = self.container_backend_timeout.as_mut() {
+                timeout.set_duration(idle_timeout);
+            }
+        }
+
+        gauge_add!("websocket.active", 1);
+        gauge_add!(
+            "websocket.active",
+            1,
+            self.cluster_id.as_deref(),
+            self.backend_id.as_deref()
+        );
+    }
+
+    /// Send a Close frame to the peers of a WebSocket connection that sozu closes,
+    /// so that they see a closing handshake instead of a reset connection.
+    /// Nothing is sent to a peer that already got a Close frame, can not be written
+    /// to, or is in the middle of a frame
+    pub fn close_websocket(&mut self) {
+        let Some(framing) = self.websocket.as_ref() else {
+            return;
+        };
+
+        if matches!(
+            self.frontend_status,
+            ConnectionStatus::Normal | ConnectionStatus::WriteOpen
+        ) && framing.from_backend.can_close()
+            && self.backend_buffer.available_data() == 0
+        {
+            let frame = websocket_close_frame(None);
+            if self.frontend.socket_write(&frame).0 == frame.len() {
+                incr!("websocket.close_frames");
+            }
+        }
+
+        if matches!(
+            self.backend_status,
+            ConnectionStatus::Normal | ConnectionStatus::WriteOpen
+        ) && framing.from_frontend.can_close()
+            && self.frontend_buffer.available_data() == 0
+        {
+            if let Some(backend) = self.backend_socket.as_mut() {
+                let frame = websocket_close_frame(Some(rand::random()));
+                if backend.socket_write(&frame).0 == frame.len() {
+                    incr!("websocket.close_frames");
+                }
+            }
+        }
+    }
+
     pub fn set_cluster_id(&mut self, cluster_id: Option<String>) {
         self.cluster_id = cluster_id;
     }
 
+    pub fn set_tags_key(&mut self, tags_key: Option<String>) {
+        self.tags_key = tags_key;
+    }
+
     pub fn set_backend_id(&mut self, backend_id: Option<String>) {
         self.backend_id = backend_id;
     }
@@ -233,6 +409,8 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         let listener = self.listener.borrow();
         let context = self.log_context();
         let endpoint = self.log_endpoint();
+        let tls = self.frontend.socket_tls_properties();
+        let address = listener.get_addr().to_string();
         metrics.register_end_of_session(&context);
         log_access!(
             error,
@@ -242,7 +420,8 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             backend_address: self.get_backend_address(),
             protocol: self.protocol_string(),
             endpoint,
-            tags: listener.get_tags(&listener.get_addr().to_string()),
+            tags: listener.get_tags(self.tags_key.as_deref().unwrap_or(&address)),
+            tls: tls.as_ref().map(TlsProperties::record),
             client_rtt: socket_rtt(self.front_socket()),
             server_rtt: self.backend_socket.as_ref().and_then(socket_rtt),
             service_time: metrics.service_time(),
@@ -362,6 +541,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         if sz > 0 {
             //FIXME: replace with copy()
             self.frontend_buffer.fill(sz);
+            if let Some(framing) = self.websocket.as_mut() {
+                let data = self.frontend_buffer.data();
+                framing.from_frontend.observe(&data[data.len() - sz..]);
+            }
 
             count!("bytes_in", sz as i64);
             metrics.bin += sz;
@@ -579,6 +762,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         if let Some(ref mut backend) = self.backend_socket {
             let (size, remaining) = backend.socket_read(self.backend_buffer.space());
             self.backend_buffer.fill(size);
+            if let Some(framing) = self.websocket.as_mut() {
+                let data = self.backend_buffer.data();
+                framing.from_backend.observe(&data[data.len() - size..]);
+            }
 
             debug!("{} Read {} bytes", log_context!(self), size);
 
@@ -793,6 +980,16 @@ impl<Front: SocketHandler, L: ListenerHandler> SessionState for Pipe<Front, L> {
     }
 
     fn close(&mut self, _proxy: Rc<RefCell<dyn L7Proxy>>, _metrics: &mut SessionMetrics) {
+        if self.websocket.is_some() {
+            gauge_add!("websocket.active", -1);
+            gauge_add!(
+                "websocket.active",
+                -1,
+                self.cluster_id.as_deref(),
+                self.backend_id.as_deref()
+            );
+        }
+
         if let Some(backend) = self.backend.as_mut() {
             let mut backend = backend.borrow_mut();
             backend.active_requests = backend.active_requests.saturating_sub(1);
@@ -816,3 +1013,53 @@ impl<Front: SocketHandler, L: ListenerHandler> SessionState for Pipe<Front, L> {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_tracker_follows_split_frames() {
+        let mut tracker = FrameTracker::default();
+        assert!(tracker.can_close());
+
+        // masked text frame with a 5 bytes payload, received in two parts
+        let frame = [0x81, 0x85, 1, 2, 3, 4, b'h', b'e', b'l', b'l', b'o'];
+        tracker.observe(&frame[..3]);
+        assert!(!tracker.can_close());
+        tracker.observe(&frame[3..8]);
+        assert_eq!(tracker.remaining, 3);
+        tracker.observe(&frame[8..]);
+        assert!(tracker.can_close());
+
+        // binary frame with a 16 bits extended length
+        let mut frame = vec![0x82, 126, 0x01, 0x00];
+        frame.extend_from_slice(&[0; 256]);
+        tracker.observe(&frame[..100]);
+        assert_eq!(tracker.remaining, 160);
+        tracker.observe(&frame[100..]);
+        assert!(tracker.can_close());
+    }
+
+    #[test]
+    fn frame_tracker_sees_close_frames() {
+        let mut tracker = FrameTracker::default();
+        tracker.observe(&[0x89, 0x00, 0x88, 0x02, 0x03, 0xe8]);
+        assert!(tracker.close_seen);
+        assert!(!tracker.can_close());
+    }
+
+    #[test]
+    fn close_frames() {
+        assert_eq!(websocket_close_frame(None), vec![0x88, 0x02, 0x03, 0xe9]);
+        assert_eq!(
+            websocket_close_frame(Some([0xff, 0x00, 0xff, 0x00])),
+            vec![0x88, 0x82, 0xff, 0x00, 0xff, 0x00, 0xfc, 0xe9]
+        );
+
+        let mut tracker = FrameTracker::default();
+        tracker.observe(&websocket_close_frame(Some(rand::random())));
+        assert!(tracker.close_seen);
+        assert_eq!((tracker.header_length, tracker.remaining), (0, 0));
+    }
+}
@@ -15,10 +15,13 @@ use crate::{
     sozu_command::ready::Ready,
     tcp::TcpListener,
     timer::TimeoutContainer,
-    Protocol, Readiness, SessionMetrics, StateResult,
+    ListenerHandler, Protocol, Readiness, SessionMetrics, StateResult,
 };
 
-use super::{header::ProxyAddr, parser::parse_v2_header};
+use super::{
+    header::ProxyAddr,
+    parser::{parse_v2_header, PROTOCOL_SIGNATURE_V2},
+};
 
 #[derive(Clone, Copy)]
 pub enum HeaderLen {
@@ -38,6 +41,11 @@ pub struct ExpectProxyProtocol<Front: SocketHandler> {
     header_len: HeaderLen,
     index: usize,
     pub request_id: Ulid,
+    /// accept the connections without a header too, told apart by their first bytes
+    pub detect: bool,
+    /// set when detection found no header: the connection is upgraded with its
+    /// first bytes left unread, and without addresses
+    pub header_absent: bool,
 }
 
 impl<Front: SocketHandler> ExpectProxyProtocol<Front> {
@@ -63,10 +71,50 @@ impl<Front: SocketHandler> ExpectProxyProtocol<Front> {
             header_len: HeaderLen::V4,
             index: 0,
             request_id,
+            detect: false,
+            header_absent: false,
+        }
+    }
+
+    /// Peek at the first bytes of the connection, without consuming them.
+    /// Returns whether they start with the signature of a v2 header,
+    /// or None until enough bytes are received to tell
+    fn peek_signature(&mut self) -> Option<bool> {
+        let mut buffer = [0; PROTOCOL_SIGNATURE_V2.len()];
+        match self.frontend.socket_ref().peek(&mut buffer) {
+            Ok(size) if size > 0 && buffer[..size] != PROTOCOL_SIGNATURE_V2[..size] => Some(false),
+            Ok(size) if size == PROTOCOL_SIGNATURE_V2.len() => Some(true),
+            // a prefix of the signature, wait for the next bytes
+            Ok(size) if size > 0 => {
+                self.frontend_readiness.event.remove(Ready::READABLE);
+                None
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                self.frontend_readiness.event.remove(Ready::READABLE);
+                None
+            }
+            // the next protocol reads the end of the stream or the error, and closes
+            Ok(_) | Err(_) => Some(false),
         }
     }
 
     pub fn readable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+        if self.detect && self.index == 0 {
+            match self.peek_signature() {
+                Some(true) => {}
+                Some(false) => {
+                    trace!(
+                        "FRONT proxy protocol [{:?}]: no header, upgrading",
+                        self.frontend_token
+                    );
+                    incr!("proxy_protocol.absent");
+                    self.header_absent = true;
+                    return SessionResult::Upgrade;
+                }
+                None => return SessionResult::Continue,
+            }
+        }
+
         let total_len = match self.header_len {
             HeaderLen::V4 => 28,
             HeaderLen::V6 => 52,
@@ -168,7 +216,11 @@ impl<Front: SocketHandler> ExpectProxyProtocol<Front> {
         backend_token: Option<Token>,
         listener: Rc<RefCell<TcpListener>>,
     ) -> Pipe<Front, TcpListener> {
-        let addr = self.front_socket().peer_addr().ok();
+        let addr = self
+            .front_socket()
+            .peer_addr()
+            .ok()
+            .map(|address| listener.borrow().client_address(address));
 
         let mut pipe = Pipe::new(
             back_buf,
@@ -305,7 +357,7 @@ mod expect_test {
     use mio::net::TcpListener;
     use rusty_ulid::Ulid;
     use std::{
-        io::Write,
+        io::{Read, Write},
         net::TcpStream as StdTcpStream,
         net::{IpAddr, Ipv4Addr, SocketAddr},
         sync::{Arc, Barrier},
@@ -366,6 +418,75 @@ mod expect_test {
         };
     }
 
+    // Accept a connection on which `sent` is written, and read it in detection mode
+    fn detect_on_connection(sent: &[u8]) -> (ExpectProxyProtocol<TcpStream>, SessionResult) {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().expect("parse address error"))
+            .expect("could not bind");
+        let mut client =
+            StdTcpStream::connect(listener.local_addr().unwrap()).expect("could not connect");
+        client.write_all(sent).unwrap();
+
+        let stream = loop {
+            if let Ok((stream, _addr)) = listener.accept() {
+                break stream;
+            }
+        };
+        let container_frontend_timeout = TimeoutContainer::new(Duration::from_secs(10), Token(0));
+        let mut expect_pp = ExpectProxyProtocol::new(
+            container_frontend_timeout,
+            stream,
+            Token(0),
+            Ulid::generate(),
+        );
+        expect_pp.detect = true;
+
+        let mut session_metrics = SessionMetrics::new(None);
+        let mut res = SessionResult::Continue;
+        while res == SessionResult::Continue {
+            res = expect_pp.readable(&mut session_metrics);
+        }
+        (expect_pp, res)
+    }
+
+    fn read_rest(expect_pp: &mut ExpectProxyProtocol<TcpStream>) -> Vec<u8> {
+        let mut buffer = [0; 64];
+        loop {
+            if let Ok(size) = expect_pp.frontend.read(&mut buffer) {
+                return buffer[..size].to_vec();
+            }
+        }
+    }
+
+    #[test]
+    fn detection_should_leave_a_connection_without_header_unread() {
+        let request = b"GET / HTTP/1.1\r\nHost: lolcatho.st\r\n\r\n";
+        let (mut expect_pp, res) = detect_on_connection(request);
+
+        assert_eq!(res, SessionResult::Upgrade);
+        assert!(expect_pp.header_absent);
+        assert!(expect_pp.addresses.is_none());
+        assert_eq!(read_rest(&mut expect_pp), request.to_vec());
+    }
+
+    #[test]
+    fn detection_should_read_the_header_of_a_connection() {
+        let src_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(125, 25, 10, 1)), 8080);
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 4, 5, 8)), 4200);
+        let mut sent = HeaderV2::new(Command::Proxy, src_addr, dst_addr).into_bytes();
+        sent.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let (mut expect_pp, res) = detect_on_connection(&sent);
+
+        assert_eq!(res, SessionResult::Upgrade);
+        assert!(!expect_pp.header_absent);
+        let addresses = expect_pp.addresses.as_ref().expect("no addresses");
+        assert_eq!(addresses.source(), Some(src_addr));
+        assert_eq!(addresses.destination(), Some(dst_addr));
+        assert_eq!(
+            read_rest(&mut expect_pp),
+            b"GET / HTTP/1.1\r\n\r\n".to_vec()
+        );
+    }
+
     // Connect to the next middleware and send a proxy protocol header
     fn start_upfront_middleware(
         next_middleware_addr: SocketAddr,
@@ -177,6 +177,14 @@ impl HeaderV2 {
     }
 }
 
+/// the IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) of an IPv4 address
+fn to_ipv6(address: SocketAddr) -> SocketAddrV6 {
+    match address {
+        SocketAddr::V4(v4) => SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0),
+        SocketAddr::V6(v6) => v6,
+    }
+}
+
 pub enum ProxyAddr {
     Ipv4Addr {
         src_addr: SocketAddrV4,
@@ -204,7 +212,12 @@ impl ProxyAddr {
                 src_addr: addr_ipv6_src,
                 dst_addr: addr_ipv6_dst,
             },
-            _ => ProxyAddr::AfUnspec,
+            // a client and a listener of different families, like an IPv4 client seen
+            // with its unmapped address on a dual-stack listener: both are sent as IPv6
+            (src, dst) => ProxyAddr::Ipv6Addr {
+                src_addr: to_ipv6(src),
+                dst_addr: to_ipv6(dst),
+            },
         }
     }
 
@@ -381,4 +394,27 @@ mod test_v2 {
 
         assert_eq!(&expected[..], &header.into_bytes()[..]);
     }
+
+    #[test]
+    fn test_deserialize_tcp_mixed_families_proxy_protocol_header() {
+        let src_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(125, 25, 10, 1)), 8080);
+        let dst_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 4200);
+
+        let header = HeaderV2::new(Command::Proxy, src_addr, dst_addr);
+        let expected = [
+            0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54,
+            0x0A, // MAGIC header
+            0x21, // Version 2 and command PROXY
+            0x21, // family AF_UNIX with IPv6
+            0x00, 0x24, // address sizes = 36
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x7D, 0x19,
+            0x0A, 0x01, // IPv4-mapped source address
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x01, // destination address
+            0x1F, 0x90, // source port
+            0x10, 0x68,
+        ];
+
+        assert_eq!(&expected[..], &header.into_bytes()[..]);
+    }
 }
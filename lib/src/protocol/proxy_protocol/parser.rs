@@ -9,7 +9,7 @@ use nom::{
 
 use crate::protocol::proxy_protocol::header::{Command, HeaderV2, ProxyAddr};
 
-const PROTOCOL_SIGNATURE_V2: [u8; 12] = [
+pub(crate) const PROTOCOL_SIGNATURE_V2: [u8; 12] = [
     0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
 ];
 
@@ -13,7 +13,7 @@ use crate::{
     socket::{SocketHandler, SocketResult},
     sozu_command::ready::Ready,
     tcp::TcpListener,
-    Protocol, Readiness, SessionMetrics, SessionResult,
+    ListenerHandler, Protocol, Readiness, SessionMetrics, SessionResult,
 };
 
 pub struct RelayProxyProtocol<Front: SocketHandler> {
@@ -178,7 +178,11 @@ impl<Front: SocketHandler> RelayProxyProtocol<Front> {
         listener: Rc<RefCell<TcpListener>>,
     ) -> Pipe<Front, TcpListener> {
         let backend_socket = self.backend.take().unwrap();
-        let addr = self.front_socket().peer_addr().ok();
+        let addr = self
+            .front_socket()
+            .peer_addr()
+            .ok()
+            .map(|address| listener.borrow().client_address(address));
 
         let mut pipe = Pipe::new(
             back_buf,
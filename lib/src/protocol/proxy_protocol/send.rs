@@ -13,10 +13,10 @@ use crate::{
         pipe::{Pipe, WebSocketContext},
         proxy_protocol::header::{Command, HeaderV2, ProxyProtocolHeader},
     },
-    socket::SocketHandler,
+    socket::{unmap_ipv4, SocketHandler},
     sozu_command::ready::Ready,
     tcp::TcpListener,
-    BackendConnectionStatus, Protocol, Readiness, SessionMetrics, SessionResult,
+    BackendConnectionStatus, ListenerHandler, Protocol, Readiness, SessionMetrics, SessionResult,
 };
 
 pub struct SendProxyProtocol<Front: SocketHandler> {
@@ -28,6 +28,8 @@ pub struct SendProxyProtocol<Front: SocketHandler> {
     pub frontend_token: Token,
     pub frontend: Front,
     pub header: Option<Vec<u8>>,
+    /// write the IPv4 address of IPv4-mapped client and listener addresses in the header
+    pub normalize_ipv4_mapped: bool,
     pub request_id: Ulid,
 }
 
@@ -45,6 +47,7 @@ impl<Front: SocketHandler> SendProxyProtocol<Front> {
     ) -> Self {
         SendProxyProtocol {
             header: None,
+            normalize_ipv4_mapped: false,
             frontend,
             request_id,
             backend,
@@ -69,8 +72,12 @@ impl<Front: SocketHandler> SendProxyProtocol<Front> {
 
         // Generate the proxy protocol header if not already exist.
         if self.header.is_none() {
-            if let Ok(local_addr) = self.front_socket().local_addr() {
-                if let Ok(frontend_addr) = self.front_socket().peer_addr() {
+            if let Ok(mut local_addr) = self.front_socket().local_addr() {
+                if let Ok(mut frontend_addr) = self.front_socket().peer_addr() {
+                    if self.normalize_ipv4_mapped {
+                        frontend_addr = unmap_ipv4(frontend_addr);
+                        local_addr = unmap_ipv4(local_addr);
+                    }
                     self.header = Some(
                         ProxyProtocolHeader::V2(HeaderV2::new(
                             Command::Proxy,
@@ -159,7 +166,11 @@ impl<Front: SocketHandler> SendProxyProtocol<Front> {
         listener: Rc<RefCell<TcpListener>>,
     ) -> Pipe<Front, TcpListener> {
         let backend_socket = self.backend.take().unwrap();
-        let addr = self.front_socket().peer_addr().ok();
+        let addr = self
+            .front_socket()
+            .peer_addr()
+            .ok()
+            .map(|address| listener.borrow().client_address(address));
 
         let mut pipe = Pipe::new(
             back_buf,
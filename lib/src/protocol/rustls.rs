@@ -1,7 +1,7 @@
-use std::{cell::RefCell, io::ErrorKind, net::SocketAddr, rc::Rc};
+use std::{cell::RefCell, io::ErrorKind, net::SocketAddr, rc::Rc, time::Instant};
 
 use mio::{net::TcpStream, Token};
-use rustls::ServerConnection;
+use rustls::{Error as RustlsError, HandshakeKind, ServerConnection};
 use rusty_ulid::Ulid;
 use sozu_command::{config::MAX_LOOP_ITERATIONS, logging::LogContext};
 
@@ -46,6 +46,8 @@ pub struct TlsHandshake {
     pub peer_address: Option<SocketAddr>,
     pub request_id: Ulid,
     pub session: ServerConnection,
+    /// when the handshake started, to measure its duration
+    pub started_at: Instant,
     pub stream: TcpStream,
 }
 
@@ -72,6 +74,7 @@ impl TlsHandshake {
             peer_address,
             request_id,
             session,
+            started_at: Instant::now(),
             stream,
         }
     }
@@ -88,6 +91,7 @@ impl TlsHandshake {
                 match self.session.read_tls(&mut self.stream) {
                     Ok(0) => {
                         error!("{} Connection closed during handshake", log_context!(self));
+                        incr!("tls.handshake.failed.connection_closed");
                         return SessionResult::Close;
                     }
                     Ok(_) => {}
@@ -113,6 +117,7 @@ impl TlsHandshake {
                         log_context!(self),
                         e
                     );
+                    incr!(handshake_failure_metric(&e));
                     return SessionResult::Close;
                 }
             }
@@ -131,6 +136,14 @@ impl TlsHandshake {
         }
 
         if self.session.is_handshaking() {
+            if self.early_data_accepted() && !self.session.wants_write() {
+                // the client's early data can be read by the HTTP state machine,
+                // which will hold non idempotent requests until the end of the handshake
+                self.frontend_readiness.interest.insert(Ready::READABLE);
+                self.frontend_readiness.event.insert(Ready::READABLE);
+                self.frontend_readiness.interest.insert(Ready::WRITABLE);
+                return SessionResult::Upgrade;
+            }
             SessionResult::Continue
         } else {
             // handshake might be finished, but we still have something to send
@@ -178,6 +191,7 @@ impl TlsHandshake {
                         log_context!(self),
                         e
                     );
+                    incr!(handshake_failure_metric(&e));
                     return SessionResult::Close;
                 }
             }
@@ -196,6 +210,11 @@ impl TlsHandshake {
         }
 
         if self.session.is_handshaking() {
+            if self.early_data_accepted() && !self.session.wants_write() {
+                self.frontend_readiness.interest.insert(Ready::READABLE);
+                self.frontend_readiness.event.insert(Ready::READABLE);
+                return SessionResult::Upgrade;
+            }
             SessionResult::Continue
         } else if self.session.wants_read() {
             self.frontend_readiness.interest.insert(Ready::READABLE);
@@ -207,6 +226,21 @@ impl TlsHandshake {
         }
     }
 
+    /// Record the duration and kind of a successful handshake
+    pub fn record_handshake_metrics(&self) {
+        time!("tls.handshake_time", self.started_at.elapsed().as_millis());
+        match self.session.handshake_kind() {
+            Some(HandshakeKind::Resumed) => incr!("tls.handshake.resumed"),
+            Some(_) => incr!("tls.handshake.full"),
+            None => {}
+        }
+    }
+
+    /// true if the client sent TLS 1.3 early data that we accepted
+    fn early_data_accepted(&mut self) -> bool {
+        self.session.early_data().is_some()
+    }
+
     pub fn log_context(&self) -> LogContext {
         LogContext {
             request_id: self.request_id,
@@ -220,6 +254,21 @@ impl TlsHandshake {
     }
 }
 
+/// Metric key counting a handshake failure, according to its cause
+fn handshake_failure_metric(error: &RustlsError) -> &'static str {
+    match error {
+        RustlsError::AlertReceived(_) => "tls.handshake.failed.client_alert",
+        RustlsError::PeerIncompatible(_) => "tls.handshake.failed.protocol_mismatch",
+        // rustls returns this error when the certificate resolver finds nothing
+        RustlsError::General(_) => "tls.handshake.failed.no_certificate",
+        // only the client certificates are verified, on the listeners asking for them
+        RustlsError::NoCertificatesPresented | RustlsError::InvalidCertificate(_) => {
+            "tls.handshake.failed.client_certificate"
+        }
+        _ => "tls.handshake.failed.other",
+    }
+}
+
 impl SessionState for TlsHandshake {
     fn ready(
         &mut self,
@@ -289,6 +338,7 @@ impl SessionState for TlsHandshake {
         // relevant timeout is still stored in the Session as front_timeout.
         if self.frontend_token == token {
             self.container_frontend_timeout.triggered();
+            incr!("tls.handshake.failed.timeout");
             return StateResult::CloseSession;
         }
 
@@ -0,0 +1,567 @@
+//! Request rate limiting of the clients of an HTTP listener, cluster or frontend
+//!
+//! On a listener, each client IP gets a sliding window: the requests of the current
+//! window are added to the requests of the previous one, weighted by the part of the
+//! previous window that is still in the sliding window.
+//!
+//! On a cluster or a frontend, each client IP gets a token bucket, holding up to
+//! `burst` requests and refilled at `requests_per_second`. The limits are counted
+//! per worker.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use sozu_command::{
+    proto::command::{ClientRateLimit, RequestRateLimit},
+    request::IpNetwork,
+};
+
+thread_local! {
+    pub static CLIENT_RATE_LIMITS: RefCell<ClientRateLimiters> =
+        RefCell::new(ClientRateLimiters::default());
+}
+
+/// past this number of clients, new clients are not limited until
+/// the stale ones are forgotten, to bound the memory usage
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// the token buckets are pruned at most this often, whatever their refill time,
+/// so that fast refilling buckets do not scan all their clients on every request
+const MIN_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ClientWindow {
+    /// start of the current window
+    start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+#[derive(Debug)]
+pub struct RequestRateLimiter {
+    requests: u32,
+    window: Duration,
+    exempt: Vec<IpNetwork>,
+    clients: HashMap<IpAddr, ClientWindow>,
+    last_prune: Instant,
+}
+
+impl RequestRateLimiter {
+    pub fn new(config: &RequestRateLimit) -> Self {
+        let exempt = config
+            .exempt
+            .iter()
+            .filter_map(|network| match network.parse() {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    error!("ignoring the rate limit exemption: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        RequestRateLimiter {
+            requests: config.requests,
+            window: Duration::from_secs(config.window.max(1) as u64),
+            exempt,
+            clients: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// count a request of the client, or return how long it should wait
+    /// before sending another one if it went over the limit.
+    /// Rejected requests are not counted
+    pub fn check(&mut self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let client = canonical(client);
+        if self.exempt.iter().any(|network| network.contains(client)) {
+            return Ok(());
+        }
+
+        if now.saturating_duration_since(self.last_prune) >= self.window {
+            self.prune(now);
+        }
+
+        if !self.clients.contains_key(&client) && self.clients.len() >= MAX_TRACKED_CLIENTS {
+            warn!(
+                "more than {} clients are rate limited, not limiting {}",
+                MAX_TRACKED_CLIENTS, client
+            );
+            return Ok(());
+        }
+
+        let window = self.window;
+        let client_window = self.clients.entry(client).or_insert(ClientWindow {
+            start: now,
+            current: 0,
+            previous: 0,
+        });
+
+        let mut elapsed = now.saturating_duration_since(client_window.start);
+        if elapsed >= window {
+            // the previous window only counts if it directly precedes the current one
+            client_window.previous = if elapsed < window * 2 {
+                client_window.current
+            } else {
+                0
+            };
+            client_window.current = 0;
+            let windows = (elapsed.as_nanos() / window.as_nanos()) as u32;
+            client_window.start += window * windows;
+            elapsed = now.saturating_duration_since(client_window.start);
+        }
+
+        let remaining = window - elapsed;
+        let estimate = client_window.previous as f64 * remaining.as_secs_f64()
+            / window.as_secs_f64()
+            + client_window.current as f64;
+
+        if estimate + 1.0 > self.requests as f64 {
+            return Err(remaining.max(Duration::from_secs(1)));
+        }
+
+        client_window.current += 1;
+        Ok(())
+    }
+
+    /// forget the clients that did not send requests in the last two windows
+    fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.clients.retain(|_, client_window| {
+            now.saturating_duration_since(client_window.start) < window * 2
+        });
+        self.last_prune = now;
+    }
+}
+
+/// the clients of IPv4 addresses are the same whatever the listener family
+fn canonical(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(client),
+        client => client,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// token buckets of the clients of a cluster or a frontend
+#[derive(Debug)]
+struct TokenBuckets {
+    requests_per_second: f64,
+    burst: f64,
+    clients: HashMap<IpAddr, Bucket>,
+    last_prune: Instant,
+}
+
+impl TokenBuckets {
+    fn new(config: &ClientRateLimit, now: Instant) -> Self {
+        let requests_per_second = config.requests_per_second.max(1);
+        TokenBuckets {
+            requests_per_second: requests_per_second as f64,
+            burst: config.burst.unwrap_or(requests_per_second).max(1) as f64,
+            clients: HashMap::new(),
+            last_prune: now,
+        }
+    }
+
+    fn has_config(&self, config: &ClientRateLimit) -> bool {
+        let requests_per_second = config.requests_per_second.max(1);
+        self.requests_per_second == requests_per_second as f64
+            && self.burst == config.burst.unwrap_or(requests_per_second).max(1) as f64
+    }
+
+    /// time it takes to fill an empty bucket
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.requests_per_second)
+    }
+
+    /// refill the bucket of the client and tell if it holds a token, or how long
+    /// the client should wait for one. The token is taken by `take`
+    fn available(&mut self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let prune_interval = self.refill_time().max(MIN_PRUNE_INTERVAL);
+        if now.saturating_duration_since(self.last_prune) >= prune_interval {
+            self.prune(now);
+        }
+
+        if !self.clients.contains_key(&client) && self.clients.len() >= MAX_TRACKED_CLIENTS {
+            warn!(
+                "more than {} clients are rate limited, not limiting {}",
+                MAX_TRACKED_CLIENTS, client
+            );
+            return Ok(());
+        }
+
+        let (rate, burst) = (self.requests_per_second, self.burst);
+        let bucket = self.clients.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let missing = (1.0 - bucket.tokens) / rate;
+            return Err(Duration::from_secs(missing.ceil() as u64).max(Duration::from_secs(1)));
+        }
+        Ok(())
+    }
+
+    /// take the token `available` found in the bucket of the client
+    fn take(&mut self, client: IpAddr) {
+        if let Some(bucket) = self.clients.get_mut(&client) {
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    /// forget the clients whose bucket is full again
+    fn prune(&mut self, now: Instant) {
+        let (rate, burst) = (self.requests_per_second, self.burst);
+        self.clients.retain(|_, bucket| {
+            bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate
+                < burst
+        });
+        self.last_prune = now;
+    }
+}
+
+/// token buckets of the clients of each cluster and frontend with a rate limit,
+/// shared by all the sessions of the worker
+#[derive(Debug, Default)]
+pub struct ClientRateLimiters {
+    clusters: HashMap<String, TokenBuckets>,
+    /// by the summary of the frontend, see the Display of RequestHttpFrontend
+    frontends: HashMap<String, TokenBuckets>,
+}
+
+impl ClientRateLimiters {
+    /// take a token from the buckets of the client in the frontend and in the cluster,
+    /// only if both hold one. Otherwise no token is taken, and the scope that rejected
+    /// the client is returned with how long it should wait before sending another request
+    pub fn check(
+        &mut self,
+        frontend: Option<(&str, &ClientRateLimit)>,
+        cluster: Option<(&str, &ClientRateLimit)>,
+        client: IpAddr,
+        now: Instant,
+    ) -> Result<(), (&'static str, Duration)> {
+        let client = canonical(client);
+        if let Some((frontend, config)) = frontend {
+            scope_buckets(&mut self.frontends, frontend, config, now)
+                .available(client, now)
+                .map_err(|retry_after| ("frontend", retry_after))?;
+        }
+        if let Some((cluster_id, config)) = cluster {
+            scope_buckets(&mut self.clusters, cluster_id, config, now)
+                .available(client, now)
+                .map_err(|retry_after| ("cluster", retry_after))?;
+        }
+
+        if let Some(buckets) = frontend.and_then(|(frontend, _)| self.frontends.get_mut(frontend)) {
+            buckets.take(client);
+        }
+        if let Some(buckets) = cluster.and_then(|(cluster_id, _)| self.clusters.get_mut(cluster_id))
+        {
+            buckets.take(client);
+        }
+        Ok(())
+    }
+
+    /// take a token from the bucket of the client in the cluster,
+    /// or return how long it should wait before sending another request
+    pub fn check_cluster(
+        &mut self,
+        cluster_id: &str,
+        config: &ClientRateLimit,
+        client: IpAddr,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        self.check(None, Some((cluster_id, config)), client, now)
+            .map_err(|(_, retry_after)| retry_after)
+    }
+
+    /// take a token from the bucket of the client in the frontend,
+    /// or return how long it should wait before sending another request
+    pub fn check_frontend(
+        &mut self,
+        frontend: &str,
+        config: &ClientRateLimit,
+        client: IpAddr,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        self.check(Some((frontend, config)), None, client, now)
+            .map_err(|(_, retry_after)| retry_after)
+    }
+
+    /// forget the buckets of a removed cluster
+    pub fn remove_cluster(&mut self, cluster_id: &str) {
+        self.clusters.remove(cluster_id);
+    }
+
+    /// forget the buckets of a removed frontend
+    pub fn remove_frontend(&mut self, frontend: &str) {
+        self.frontends.remove(frontend);
+    }
+}
+
+/// the buckets of a scope, reset when its limit changes
+fn scope_buckets<'a>(
+    scopes: &'a mut HashMap<String, TokenBuckets>,
+    scope: &str,
+    config: &ClientRateLimit,
+    now: Instant,
+) -> &'a mut TokenBuckets {
+    if !scopes
+        .get(scope)
+        .is_some_and(|buckets| buckets.has_config(config))
+    {
+        scopes.insert(scope.to_owned(), TokenBuckets::new(config, now));
+    }
+    scopes
+        .get_mut(scope)
+        .expect("the buckets of the scope were just inserted")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests: u32, window: u32, exempt: &[&str]) -> RequestRateLimiter {
+        RequestRateLimiter::new(&RequestRateLimit {
+            requests,
+            window,
+            exempt: exempt.iter().map(|network| network.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn sliding_window() {
+        let mut limiter = limiter(2, 10, &[]);
+        let client: IpAddr = "192.168.1.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(client, start), Ok(()));
+        assert_eq!(limiter.check(client, start), Ok(()));
+        assert_eq!(
+            limiter.check(client, start + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        // another client has its own window
+        assert_eq!(limiter.check("192.168.1.2".parse().unwrap(), start), Ok(()));
+
+        // half of the previous window still counts for one request
+        assert_eq!(
+            limiter.check(client, start + Duration::from_secs(15)),
+            Ok(())
+        );
+        assert!(limiter
+            .check(client, start + Duration::from_secs(15))
+            .is_err());
+        // the previous window is forgotten after two windows
+        assert_eq!(
+            limiter.check(client, start + Duration::from_secs(40)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn exempt_networks() {
+        let mut limiter = limiter(1, 60, &["10.0.0.0/8", "not a network"]);
+        let start = Instant::now();
+
+        assert_eq!(limiter.exempt.len(), 1);
+        for _ in 0..10 {
+            assert_eq!(limiter.check("10.1.2.3".parse().unwrap(), start), Ok(()));
+            assert_eq!(
+                limiter.check("::ffff:10.1.2.3".parse().unwrap(), start),
+                Ok(())
+            );
+        }
+        assert_eq!(limiter.check("11.1.2.3".parse().unwrap(), start), Ok(()));
+        assert!(limiter
+            .check("::ffff:11.1.2.3".parse().unwrap(), start)
+            .is_err());
+    }
+
+    #[test]
+    fn prune_stale_clients() {
+        let mut limiter = limiter(1, 1, &[]);
+        let start = limiter.last_prune;
+
+        limiter
+            .check("192.168.1.1".parse().unwrap(), start)
+            .unwrap();
+        assert_eq!(limiter.clients.len(), 1);
+        limiter
+            .check(
+                "192.168.1.2".parse().unwrap(),
+                start + Duration::from_secs(3),
+            )
+            .unwrap();
+        assert_eq!(limiter.clients.len(), 1);
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut limiters = ClientRateLimiters::default();
+        let config = ClientRateLimit {
+            requests_per_second: 2,
+            burst: Some(3),
+        };
+        let client: IpAddr = "192.168.1.1".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(
+                limiters.check_cluster("app", &config, client, start),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            limiters.check_cluster("app", &config, client, start),
+            Err(Duration::from_secs(1))
+        );
+        // the frontends and the other clusters have their own buckets
+        assert_eq!(
+            limiters.check_frontend("app", &config, client, start),
+            Ok(())
+        );
+        assert_eq!(
+            limiters.check_cluster("api", &config, client, start),
+            Ok(())
+        );
+        assert_eq!(
+            limiters.check_cluster("app", &config, "::ffff:192.168.1.1".parse().unwrap(), start),
+            Err(Duration::from_secs(1))
+        );
+
+        // one token comes back every 500ms
+        let later = start + Duration::from_millis(500);
+        assert_eq!(
+            limiters.check_cluster("app", &config, client, later),
+            Ok(())
+        );
+        assert!(limiters
+            .check_cluster("app", &config, client, later)
+            .is_err());
+
+        // a new limit resets the buckets
+        let config = ClientRateLimit {
+            requests_per_second: 1,
+            burst: None,
+        };
+        assert_eq!(
+            limiters.check_cluster("app", &config, client, later),
+            Ok(())
+        );
+        assert!(limiters
+            .check_cluster("app", &config, client, later)
+            .is_err());
+    }
+
+    #[test]
+    fn no_token_taken_from_a_scope_if_the_other_rejects() {
+        let mut limiters = ClientRateLimiters::default();
+        let frontend = ClientRateLimit {
+            requests_per_second: 1,
+            burst: Some(2),
+        };
+        let cluster = ClientRateLimit {
+            requests_per_second: 1,
+            burst: Some(1),
+        };
+        let client: IpAddr = "192.168.1.1".parse().unwrap();
+        let start = Instant::now();
+
+        let check = |limiters: &mut ClientRateLimiters| {
+            limiters.check(
+                Some(("front", &frontend)),
+                Some(("app", &cluster)),
+                client,
+                start,
+            )
+        };
+        assert_eq!(check(&mut limiters), Ok(()));
+        assert_eq!(
+            check(&mut limiters),
+            Err(("cluster", Duration::from_secs(1)))
+        );
+        // the request the cluster rejected left its token in the frontend bucket
+        assert_eq!(
+            limiters.check_frontend("front", &frontend, client, start),
+            Ok(())
+        );
+        assert!(limiters
+            .check_frontend("front", &frontend, client, start)
+            .is_err());
+    }
+
+    #[test]
+    fn prune_full_buckets() {
+        let mut limiters = ClientRateLimiters::default();
+        let config = ClientRateLimit {
+            requests_per_second: 10,
+            burst: Some(10),
+        };
+        let start = Instant::now();
+
+        limiters
+            .check_cluster("app", &config, "192.168.1.1".parse().unwrap(), start)
+            .unwrap();
+        limiters
+            .check_cluster(
+                "app",
+                &config,
+                "192.168.1.2".parse().unwrap(),
+                start + Duration::from_secs(2),
+            )
+            .unwrap();
+        assert_eq!(limiters.clusters["app"].clients.len(), 1);
+
+        limiters.remove_cluster("app");
+        assert!(limiters.clusters.is_empty());
+    }
+
+    #[test]
+    fn prune_fast_refilling_buckets_at_most_once_per_second() {
+        let mut limiters = ClientRateLimiters::default();
+        // an empty bucket refills in 100ms
+        let config = ClientRateLimit {
+            requests_per_second: 100,
+            burst: Some(10),
+        };
+        let start = Instant::now();
+
+        limiters
+            .check_cluster("app", &config, "192.168.1.1".parse().unwrap(), start)
+            .unwrap();
+        // the first bucket is full again, but it is too early to prune it
+        limiters
+            .check_cluster(
+                "app",
+                &config,
+                "192.168.1.2".parse().unwrap(),
+                start + Duration::from_millis(500),
+            )
+            .unwrap();
+        assert_eq!(limiters.clusters["app"].clients.len(), 2);
+
+        limiters
+            .check_cluster(
+                "app",
+                &config,
+                "192.168.1.3".parse().unwrap(),
+                start + Duration::from_secs(1),
+            )
+            .unwrap();
+        assert_eq!(limiters.clusters["app"].clients.len(), 1);
+    }
+}
@@ -0,0 +1,344 @@
+//! Conditions a request must meet to be sent to the route of a frontend
+//!
+//! Every condition of a frontend implements [`Matcher`], so that it can be tested on
+//! its own and combined with [`AllOf`], [`AnyOf`] and [`Not`]. Types defined outside
+//! of this crate can implement [`Matcher`] too, and be added to the [`Matchers`] of a
+//! [`FrontendRule`](super::FrontendRule) without changing the router.
+
+use std::{fmt::Debug, sync::Arc};
+
+use regex::bytes::RegexSet;
+use sozu_command::{config::DEFAULT_MOBILE_USER_AGENTS, proto::command::DeviceClass};
+
+use crate::{
+    protocol::http::parser::Method,
+    router::{
+        ClientTls, DomainRule, MethodRule, MethodRuleResult, PathRule, PathRuleResult, TlsRule,
+        TlsRuleResult,
+    },
+};
+
+/// Access to the headers of a request
+pub trait RequestHeaders {
+    /// value of the first header with this name, compared case insensitively
+    fn get(&self, name: &str) -> Option<&[u8]>;
+}
+
+/// a request without headers
+impl RequestHeaders for () {
+    fn get(&self, _name: &str) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<[u8]>> RequestHeaders for Vec<(K, V)> {
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        self.iter()
+            .find(|(key, _)| key.as_ref().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_ref())
+    }
+}
+
+/// What the router knows about a request when looking up its route
+#[derive(Clone, Copy)]
+pub struct RequestHead<'a> {
+    /// hostname, without the port
+    pub hostname: &'a str,
+    pub path: &'a str,
+    pub method: &'a Method,
+    /// parameters negotiated by HTTPS clients, None for plain HTTP
+    pub tls: Option<&'a ClientTls>,
+    pub headers: &'a dyn RequestHeaders,
+}
+
+impl<'a> RequestHead<'a> {
+    /// a plain HTTP request without headers
+    pub fn new(hostname: &'a str, path: &'a str, method: &'a Method) -> Self {
+        RequestHead {
+            hostname,
+            path,
+            method,
+            tls: None,
+            headers: &(),
+        }
+    }
+
+    pub fn with_tls(mut self, tls: Option<&'a ClientTls>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_headers(mut self, headers: &'a dyn RequestHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+}
+
+/// A condition on a request
+pub trait Matcher: Debug + Send + Sync {
+    fn matches_request(&self, request: &RequestHead) -> bool;
+
+    /// matchers with the same id are the same condition. Removing a rule removes
+    /// the rule with the same conditions
+    fn id(&self) -> String {
+        format!("{self:?}")
+    }
+}
+
+impl Matcher for DomainRule {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        self.matches(request.hostname.as_bytes())
+    }
+}
+
+impl Matcher for PathRule {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        self.matches(request.path.as_bytes()) != PathRuleResult::None
+    }
+}
+
+impl Matcher for MethodRule {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        self.matches(request.method) != MethodRuleResult::None
+    }
+}
+
+impl Matcher for TlsRule {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        self.matches(request.tls) != TlsRuleResult::None
+    }
+}
+
+/// Matches requests with a header, and optionally a value for this header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderRule {
+    pub name: String,
+    /// matches any value if None
+    pub value: Option<String>,
+}
+
+impl HeaderRule {
+    pub fn new(name: &str, value: Option<&str>) -> Self {
+        HeaderRule {
+            name: name.to_ascii_lowercase(),
+            value: value.map(ToOwned::to_owned),
+        }
+    }
+}
+
+impl Matcher for HeaderRule {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        match (request.headers.get(&self.name), &self.value) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(value), Some(expected)) => value == expected.as_bytes(),
+        }
+    }
+}
+
+/// Matches the requests of a class of devices. The Sec-CH-UA-Mobile client hint
+/// tells if a device is mobile, the User-Agent is checked for the clients not sending it
+#[derive(Clone, Debug)]
+pub struct DeviceRule {
+    pub class: DeviceClass,
+    /// patterns of the User-Agent of the mobile devices
+    pub mobile_user_agents: RegexSet,
+}
+
+impl DeviceRule {
+    /// uses the default patterns if `mobile_user_agents` is empty
+    pub fn new(class: DeviceClass, mobile_user_agents: &[String]) -> Result<Self, regex::Error> {
+        let mobile_user_agents = if mobile_user_agents.is_empty() {
+            RegexSet::new(DEFAULT_MOBILE_USER_AGENTS)?
+        } else {
+            RegexSet::new(mobile_user_agents)?
+        };
+        Ok(DeviceRule {
+            class,
+            mobile_user_agents,
+        })
+    }
+
+    pub fn is_mobile(&self, headers: &dyn RequestHeaders) -> bool {
+        let hint = headers
+            .get("sec-ch-ua-mobile")
+            .and_then(|hint| std::str::from_utf8(hint).ok());
+        match hint.map(str::trim) {
+            Some("?1") => true,
+            Some("?0") => false,
+            _ => headers
+                .get("user-agent")
+                .is_some_and(|user_agent| self.mobile_user_agents.is_match(user_agent)),
+        }
+    }
+}
+
+impl Matcher for DeviceRule {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        let is_mobile = self.is_mobile(request.headers);
+        match self.class {
+            DeviceClass::Mobile => is_mobile,
+            DeviceClass::Desktop => !is_mobile,
+        }
+    }
+}
+
+/// Matches requests that all the matchers match
+#[derive(Debug)]
+pub struct AllOf(pub Vec<Box<dyn Matcher>>);
+
+impl Matcher for AllOf {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        self.0
+            .iter()
+            .all(|matcher| matcher.matches_request(request))
+    }
+}
+
+/// Matches requests that at least one of the matchers matches
+#[derive(Debug)]
+pub struct AnyOf(pub Vec<Box<dyn Matcher>>);
+
+impl Matcher for AnyOf {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        self.0
+            .iter()
+            .any(|matcher| matcher.matches_request(request))
+    }
+}
+
+/// Matches requests that the matcher does not match
+#[derive(Debug)]
+pub struct Not(pub Box<dyn Matcher>);
+
+impl Matcher for Not {
+    fn matches_request(&self, request: &RequestHead) -> bool {
+        !self.0.matches_request(request)
+    }
+}
+
+/// Conditions of a frontend besides its hostname, path, method and TLS parameters.
+/// All of them must match
+#[derive(Clone, Debug, Default)]
+pub struct Matchers(Vec<Arc<dyn Matcher>>);
+
+impl Matchers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<M: Matcher + 'static>(mut self, matcher: M) -> Self {
+        self.push(Arc::new(matcher));
+        self
+    }
+
+    pub fn push(&mut self, matcher: Arc<dyn Matcher>) {
+        self.0.push(matcher);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, request: &RequestHead) -> bool {
+        self.0
+            .iter()
+            .all(|matcher| matcher.matches_request(request))
+    }
+}
+
+impl PartialEq for Matchers {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(other.0.iter())
+                .all(|(left, right)| left.id() == right.id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combine_matchers() {
+        let headers = vec![("X-Canary", "1"), ("User-Agent", "curl/8.0")];
+        let request =
+            RequestHead::new("www.example.com", "/api/users", &Method::Get).with_headers(&headers);
+
+        assert!(HeaderRule::new("x-canary", None).matches_request(&request));
+        assert!(HeaderRule::new("X-CANARY", Some("1")).matches_request(&request));
+        assert!(!HeaderRule::new("x-canary", Some("2")).matches_request(&request));
+        assert!(
+            !HeaderRule::new("x-canary", None).matches_request(&RequestHead::new(
+                "www.example.com",
+                "/",
+                &Method::Get
+            ))
+        );
+
+        let canary_api = AllOf(vec![
+            Box::new(PathRule::Prefix("/api".to_owned())),
+            Box::new(AnyOf(vec![
+                Box::new(HeaderRule::new("x-canary", Some("1"))),
+                Box::new(MethodRule::new(Some("POST".to_owned()))),
+            ])),
+        ]);
+        assert!(canary_api.matches_request(&request));
+        assert!(!Not(Box::new(canary_api)).matches_request(&request));
+        assert!(!AllOf(vec![
+            Box::new("*.example.com".parse::<DomainRule>().unwrap()),
+            Box::new(PathRule::Prefix("/static".to_owned())),
+        ])
+        .matches_request(&request));
+    }
+
+    #[test]
+    fn match_devices() {
+        let mobile = DeviceRule::new(DeviceClass::Mobile, &[]).unwrap();
+        let desktop = DeviceRule::new(DeviceClass::Desktop, &[]).unwrap();
+        let is_mobile = |rule: &DeviceRule, headers: Vec<(&str, &str)>| {
+            rule.matches_request(
+                &RequestHead::new("www.example.com", "/", &Method::Get).with_headers(&headers),
+            )
+        };
+
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148";
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        assert!(is_mobile(&mobile, vec![("User-Agent", iphone)]));
+        assert!(!is_mobile(&mobile, vec![("User-Agent", firefox)]));
+        assert!(is_mobile(&desktop, vec![("User-Agent", firefox)]));
+        assert!(is_mobile(&desktop, vec![]));
+
+        // the client hint wins over the User-Agent
+        assert!(is_mobile(
+            &mobile,
+            vec![("User-Agent", firefox), ("Sec-CH-UA-Mobile", "?1")]
+        ));
+        assert!(is_mobile(
+            &desktop,
+            vec![("User-Agent", iphone), ("sec-ch-ua-mobile", "?0")]
+        ));
+
+        let android = DeviceRule::new(DeviceClass::Mobile, &["Android".to_owned()]).unwrap();
+        assert!(!is_mobile(&android, vec![("User-Agent", iphone)]));
+        assert!(DeviceRule::new(DeviceClass::Mobile, &["(".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn compare_matchers() {
+        let canary = Matchers::new().with(HeaderRule::new("X-Canary", Some("1")));
+
+        assert_eq!(
+            canary,
+            Matchers::new().with(HeaderRule::new("x-canary", Some("1")))
+        );
+        assert_ne!(
+            canary,
+            Matchers::new().with(HeaderRule::new("x-canary", Some("2")))
+        );
+        assert_ne!(canary, Matchers::new());
+    }
+}
@@ -1,16 +1,75 @@
+//! Routing of HTTP requests to the clusters of their frontends
+//!
+//! The [`Router`] of a listener holds the frontends as [`FrontendRule`]s. Their hostnames
+//! are looked up in a [`TrieNode`], or, for the rules placed before or after the tree,
+//! matched with a [`DomainRule`]. The other conditions of a frontend are [`Matcher`]s:
+//! the path, method and TLS rules, and any number of additional [`Matchers`], like
+//! [`HeaderRule`]s or types implementing [`Matcher`] outside of this crate.
+//!
+//! ```
+//! use sozu_command_lib::proto::command::RulePosition;
+//! use sozu_lib::{
+//!     protocol::http::parser::Method,
+//!     router::{FrontendRule, HeaderRule, PathRule, RequestHead, Route, Router},
+//! };
+//!
+//! let mut router = Router::new();
+//! router
+//!     .add_rule(
+//!         RulePosition::Tree,
+//!         "www.example.com",
+//!         FrontendRule::new(
+//!             PathRule::Prefix("/".to_owned()),
+//!             Route::ClusterId("stable".to_owned()),
+//!         ),
+//!     )
+//!     .unwrap();
+//! router
+//!     .add_rule(
+//!         RulePosition::Tree,
+//!         "www.example.com",
+//!         FrontendRule::new(
+//!             PathRule::Prefix("/".to_owned()),
+//!             Route::ClusterId("canary".to_owned()),
+//!         )
+//!         .with_matcher(HeaderRule::new("X-Canary", Some("1"))),
+//!     )
+//!     .unwrap();
+//!
+//! let headers = vec![("x-canary", "1")];
+//! let request = RequestHead::new("www.example.com", "/", &Method::Get);
+//! assert_eq!(
+//!     router.lookup_request(&request),
+//!     Ok(Route::ClusterId("stable".to_owned()))
+//! );
+//! assert_eq!(
+//!     router.lookup_request(&request.with_headers(&headers)),
+//!     Ok(Route::ClusterId("canary".to_owned()))
+//! );
+//! ```
+
+pub mod matcher;
 pub mod pattern_trie;
 pub mod trie;
 
 use std::{str::from_utf8, time::Instant};
 
+use rand::Rng;
 use regex::bytes::Regex;
 
 use sozu_command::{
-    proto::command::{PathRule as CommandPathRule, PathRuleKind, RulePosition},
+    config::ACME_CLUSTER_ID,
+    proto::command::{
+        ClientRateLimit, DeviceClass, HeaderEdit, PathRule as CommandPathRule, PathRuleKind,
+        RequestHttpFrontend, RequestMirror, RulePosition, Timeouts, TlsVersion, WeightedCluster,
+    },
     response::HttpFrontend,
     state::ClusterId,
 };
 
+pub use crate::router::matcher::{
+    AllOf, AnyOf, DeviceRule, HeaderRule, Matcher, Matchers, Not, RequestHead, RequestHeaders,
+};
 use crate::{protocol::http::parser::Method, router::pattern_trie::TrieNode};
 
 #[derive(thiserror::Error, Debug, PartialEq)]
@@ -19,6 +78,8 @@ pub enum RouterError {
     InvalidPathRule(String),
     #[error("parsing hostname {hostname} failed")]
     InvalidDomain { hostname: String },
+    #[error("Could not parse the User-Agent patterns {0:?}")]
+    InvalidDeviceRule(Vec<String>),
     #[error("Could not add route {0}")]
     AddRoute(String),
     #[error("Could not remove route {0}")]
@@ -31,10 +92,154 @@ pub enum RouterError {
     },
 }
 
+/// What a frontend changes in the handling of the requests it routes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrontendOptions {
+    /// overrides the timeouts of the cluster and listener
+    pub timeouts: Option<Timeouts>,
+    /// where a sample of the raw requests is copied
+    pub mirror: Option<RequestMirror>,
+    /// edits of the request and response headers
+    pub headers: Vec<HeaderEdit>,
+    /// token bucket of each client IP, with the summary of the frontend
+    /// that identifies its buckets
+    pub rate_limit: Option<(String, ClientRateLimit)>,
+}
+
+/// The conditions of a frontend besides its hostname, and the route of the requests
+/// that meet them
+#[derive(Clone, Debug)]
+pub struct FrontendRule {
+    pub path: PathRule,
+    pub method: MethodRule,
+    pub tls: TlsRule,
+    pub matchers: Matchers,
+    pub route: Route,
+    /// overrides the timeouts of the cluster and listener for the requests of the rule
+    pub timeouts: Option<Timeouts>,
+    pub mirror: Option<RequestMirror>,
+    pub headers: Vec<HeaderEdit>,
+    pub rate_limit: Option<(String, ClientRateLimit)>,
+}
+
+impl FrontendRule {
+    /// a rule matching any method and TLS parameters
+    pub fn new(path: PathRule, route: Route) -> Self {
+        FrontendRule {
+            path,
+            method: MethodRule::new(None),
+            tls: TlsRule::default(),
+            matchers: Matchers::new(),
+            route,
+            timeouts: None,
+            mirror: None,
+            headers: Vec::new(),
+            rate_limit: None,
+        }
+    }
+
+    pub fn with_method(mut self, method: MethodRule) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TlsRule) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Option<Timeouts>) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_mirror(mut self, mirror: Option<RequestMirror>) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    pub fn with_headers(mut self, headers: Vec<HeaderEdit>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Option<(String, ClientRateLimit)>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn with_matcher<M: Matcher + 'static>(mut self, matcher: M) -> Self {
+        self.matchers = self.matchers.with(matcher);
+        self
+    }
+
+    pub fn options(&self) -> FrontendOptions {
+        FrontendOptions {
+            timeouts: self.timeouts.clone(),
+            mirror: self.mirror.clone(),
+            headers: self.headers.clone(),
+            rate_limit: self.rate_limit.clone(),
+        }
+    }
+
+    /// true if both rules have the same conditions, whatever their routes
+    pub fn same_conditions(&self, other: &FrontendRule) -> bool {
+        self.path == other.path
+            && self.method == other.method
+            && self.tls == other.tls
+            && self.matchers == other.matchers
+    }
+
+    /// true if the request meets all the conditions of the rule
+    pub fn matches(&self, request: &RequestHead) -> bool {
+        self.path.matches_request(request)
+            && self.method.matches_request(request)
+            && self.tls.matches_request(request)
+            && self.matchers.matches(request)
+    }
+
+    fn from_http_front(front: &HttpFrontend) -> Result<Self, RouterError> {
+        let path = PathRule::from_config(front.path.clone())
+            .ok_or(RouterError::InvalidPathRule(front.path.to_string()))?;
+
+        let route = match &front.cluster_id {
+            Some(_) if !front.split.is_empty() => Route::Split(front.split.clone()),
+            Some(cluster_id) => Route::ClusterId(cluster_id.clone()),
+            None => Route::Deny,
+        };
+
+        let mut rule = FrontendRule::new(path, route)
+            .with_method(MethodRule::new(front.method.clone()))
+            .with_tls(TlsRule::new(
+                &front.client_tls_versions,
+                &front.client_cipher_suites,
+            ))
+            .with_timeouts(front.timeouts.clone())
+            .with_mirror(front.mirror.clone())
+            .with_headers(front.headers.clone())
+            .with_rate_limit(front.rate_limit.clone().map(|rate_limit| {
+                (
+                    RequestHttpFrontend::from(front.clone()).to_string(),
+                    rate_limit,
+                )
+            }));
+
+        if let Some(device) = &front.device {
+            let class = DeviceClass::try_from(device.class)
+                .map_err(|_| RouterError::InvalidDeviceRule(device.mobile_user_agents.clone()))?;
+            rule =
+                rule.with_matcher(DeviceRule::new(class, &device.mobile_user_agents).map_err(
+                    |_| RouterError::InvalidDeviceRule(device.mobile_user_agents.clone()),
+                )?);
+        }
+        Ok(rule)
+    }
+}
+
 pub struct Router {
-    pre: Vec<(DomainRule, PathRule, MethodRule, Route)>,
-    pub tree: TrieNode<Vec<(PathRule, MethodRule, Route)>>,
-    post: Vec<(DomainRule, PathRule, MethodRule, Route)>,
+    pre: Vec<(DomainRule, FrontendRule)>,
+    pub tree: TrieNode<Vec<FrontendRule>>,
+    post: Vec<(DomainRule, FrontendRule)>,
 }
 
 impl Default for Router {
@@ -52,50 +257,81 @@ impl Router {
         }
     }
 
+    /// `tls` holds the parameters negotiated by HTTPS clients, None for plain HTTP
     pub fn lookup(
         &self,
         hostname: &str,
         path: &str,
         method: &Method,
+        tls: Option<&ClientTls>,
     ) -> Result<Route, RouterError> {
-        let hostname_b = hostname.as_bytes();
-        let path_b = path.as_bytes();
-        for (domain_rule, path_rule, method_rule, cluster_id) in &self.pre {
-            if domain_rule.matches(hostname_b)
-                && path_rule.matches(path_b) != PathRuleResult::None
-                && method_rule.matches(method) != MethodRuleResult::None
-            {
-                return Ok(cluster_id.clone());
+        self.lookup_request(&RequestHead::new(hostname, path, method).with_tls(tls))
+    }
+
+    /// route of a request: the first matching rule placed before the tree,
+    /// then the best matching rule of the hostname in the tree, then the first
+    /// matching rule placed after the tree
+    pub fn lookup_request(&self, request: &RequestHead) -> Result<Route, RouterError> {
+        self.lookup_rule(request).map(|rule| rule.route.clone())
+    }
+
+    /// the rule routing a request, found like in [`Router::lookup_request`]
+    pub fn lookup_rule(&self, request: &RequestHead) -> Result<&FrontendRule, RouterError> {
+        let hostname_b = request.hostname.as_bytes();
+        let path_b = request.path.as_bytes();
+        for (domain_rule, rule) in &self.pre {
+            if domain_rule.matches(hostname_b) && rule.matches(request) {
+                return Ok(rule);
             }
         }
 
-        if let Some((_, path_rules)) = self.tree.lookup(hostname_b, true) {
+        if let Some((_, rules)) = self.tree.lookup(hostname_b, true) {
             let mut prefix_length = 0;
             let mut route = None;
+            // among the rules of the same length, the ones with TLS conditions
+            // or additional matchers win
+            let mut route_is_specific = false;
+
+            for rule in rules {
+                let tls_match = match rule.tls.matches(request.tls) {
+                    TlsRuleResult::None => continue,
+                    TlsRuleResult::All => false,
+                    TlsRuleResult::Equals => true,
+                };
+                if !rule.matchers.matches(request) {
+                    continue;
+                }
+                let specific = tls_match || !rule.matchers.is_empty();
 
-            for (rule, method_rule, cluster_id) in path_rules {
-                match rule.matches(path_b) {
+                match rule.path.matches(path_b) {
                     PathRuleResult::Regex | PathRuleResult::Equals => {
-                        match method_rule.matches(method) {
-                            MethodRuleResult::Equals => return Ok(cluster_id.clone()),
+                        match rule.method.matches(request.method) {
+                            MethodRuleResult::Equals => return Ok(rule),
                             MethodRuleResult::All => {
-                                prefix_length = path_b.len();
-                                route = Some(cluster_id);
+                                if specific || !route_is_specific {
+                                    prefix_length = path_b.len();
+                                    route = Some(rule);
+                                    route_is_specific = specific;
+                                }
                             }
                             MethodRuleResult::None => {}
                         }
                     }
                     PathRuleResult::Prefix(size) => {
-                        if size >= prefix_length {
-                            match method_rule.matches(method) {
+                        if size > prefix_length
+                            || (size == prefix_length && (specific || !route_is_specific))
+                        {
+                            match rule.method.matches(request.method) {
                                 // FIXME: the rule order will be important here
                                 MethodRuleResult::Equals => {
                                     prefix_length = size;
-                                    route = Some(cluster_id);
+                                    route = Some(rule);
+                                    route_is_specific = specific;
                                 }
                                 MethodRuleResult::All => {
                                     prefix_length = size;
-                                    route = Some(cluster_id);
+                                    route = Some(rule);
+                                    route_is_specific = specific;
                                 }
                                 MethodRuleResult::None => {}
                             }
@@ -105,98 +341,90 @@ impl Router {
                 }
             }
 
-            if let Some(cluster_id) = route {
-                return Ok(cluster_id.clone());
+            if let Some(rule) = route {
+                return Ok(rule);
             }
         }
 
-        for (domain_rule, path_rule, method_rule, cluster_id) in self.post.iter() {
-            if domain_rule.matches(hostname_b)
-                && path_rule.matches(path_b) != PathRuleResult::None
-                && method_rule.matches(method) != MethodRuleResult::None
-            {
-                return Ok(cluster_id.clone());
+        for (domain_rule, rule) in self.post.iter() {
+            if domain_rule.matches(hostname_b) && rule.matches(request) {
+                return Ok(rule);
             }
         }
 
         Err(RouterError::RouteNotFound {
-            host: hostname.to_owned(),
-            path: path.to_owned(),
-            method: method.to_owned(),
+            host: request.hostname.to_owned(),
+            path: request.path.to_owned(),
+            method: request.method.to_owned(),
         })
     }
 
     pub fn add_http_front(&mut self, front: &HttpFrontend) -> Result<(), RouterError> {
-        let path_rule = PathRule::from_config(front.path.clone())
-            .ok_or(RouterError::InvalidPathRule(front.path.to_string()))?;
-
-        let method_rule = MethodRule::new(front.method.clone());
-
-        let route = match &front.cluster_id {
-            Some(cluster_id) => Route::ClusterId(cluster_id.clone()),
-            None => Route::Deny,
-        };
-
-        let success = match front.position {
-            RulePosition::Pre => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
+        let rule = FrontendRule::from_http_front(front)?;
+        self.add_rule(front.position, &front.hostname, rule)
+            .map_err(|error| match error {
+                RouterError::AddRoute(_) => RouterError::AddRoute(format!("{:?}", front)),
+                error => error,
+            })
+    }
 
-                self.add_pre_rule(&domain, &path_rule, &method_rule, &route)
-            }
-            RulePosition::Post => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
+    pub fn remove_http_front(&mut self, front: &HttpFrontend) -> Result<(), RouterError> {
+        let rule = FrontendRule::from_http_front(front)?;
+        self.remove_rule(front.position, &front.hostname, &rule)
+            .map_err(|error| match error {
+                RouterError::RemoveRoute(_) => RouterError::RemoveRoute(format!("{:?}", front)),
+                error => error,
+            })
+    }
 
-                self.add_post_rule(&domain, &path_rule, &method_rule, &route)
-            }
-            RulePosition::Tree => {
-                self.add_tree_rule(front.hostname.as_bytes(), &path_rule, &method_rule, &route)
+    /// add a rule for a hostname, fails if the hostname already has a rule with the
+    /// same conditions at this position.
+    ///
+    /// The hostname of the rules placed in the tree can contain a wildcard or regex
+    /// labels, the other rules are matched with a [`DomainRule`]
+    pub fn add_rule(
+        &mut self,
+        position: RulePosition,
+        hostname: &str,
+        rule: FrontendRule,
+    ) -> Result<(), RouterError> {
+        let success = match position {
+            RulePosition::Tree => self.insert_tree_rule(hostname.as_bytes(), rule),
+            RulePosition::Pre | RulePosition::Post => {
+                let domain = parse_domain_rule(hostname)?;
+                let rules = match position {
+                    RulePosition::Pre => &mut self.pre,
+                    _ => &mut self.post,
+                };
+                insert_domain_rule(rules, domain, rule)
             }
         };
         if !success {
-            return Err(RouterError::AddRoute(format!("{:?}", front)));
+            return Err(RouterError::AddRoute(hostname.to_owned()));
         }
         Ok(())
     }
 
-    pub fn remove_http_front(&mut self, front: &HttpFrontend) -> Result<(), RouterError> {
-        let path_rule = PathRule::from_config(front.path.clone())
-            .ok_or(RouterError::InvalidPathRule(front.path.to_string()))?;
-
-        let method_rule = MethodRule::new(front.method.clone());
-
-        let remove_success = match front.position {
-            RulePosition::Pre => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
-
-                self.remove_pre_rule(&domain, &path_rule, &method_rule)
-            }
-            RulePosition::Post => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
-
-                self.remove_post_rule(&domain, &path_rule, &method_rule)
-            }
-            RulePosition::Tree => {
-                self.remove_tree_rule(front.hostname.as_bytes(), &path_rule, &method_rule)
+    /// remove the rule of a hostname with the same conditions as `rule`
+    pub fn remove_rule(
+        &mut self,
+        position: RulePosition,
+        hostname: &str,
+        rule: &FrontendRule,
+    ) -> Result<(), RouterError> {
+        let success = match position {
+            RulePosition::Tree => self.delete_tree_rule(hostname.as_bytes(), rule),
+            RulePosition::Pre | RulePosition::Post => {
+                let domain = parse_domain_rule(hostname)?;
+                let rules = match position {
+                    RulePosition::Pre => &mut self.pre,
+                    _ => &mut self.post,
+                };
+                delete_domain_rule(rules, &domain, rule)
             }
         };
-        if !remove_success {
-            return Err(RouterError::RemoveRoute(format!("{:?}", front)));
+        if !success {
+            return Err(RouterError::RemoveRoute(hostname.to_owned()));
         }
         Ok(())
     }
@@ -206,8 +434,82 @@ impl Router {
         hostname: &[u8],
         path: &PathRule,
         method: &MethodRule,
+        tls: &TlsRule,
         cluster: &Route,
     ) -> bool {
+        self.insert_tree_rule(hostname, rule_from_parts(path, method, tls, cluster))
+    }
+
+    pub fn remove_tree_rule(
+        &mut self,
+        hostname: &[u8],
+        path: &PathRule,
+        method: &MethodRule,
+        tls: &TlsRule,
+        // _cluster: &Route,
+    ) -> bool {
+        self.delete_tree_rule(hostname, &rule_from_parts(path, method, tls, &Route::Deny))
+    }
+
+    pub fn add_pre_rule(
+        &mut self,
+        domain: &DomainRule,
+        path: &PathRule,
+        method: &MethodRule,
+        tls: &TlsRule,
+        cluster_id: &Route,
+    ) -> bool {
+        insert_domain_rule(
+            &mut self.pre,
+            domain.to_owned(),
+            rule_from_parts(path, method, tls, cluster_id),
+        )
+    }
+
+    pub fn add_post_rule(
+        &mut self,
+        domain: &DomainRule,
+        path: &PathRule,
+        method: &MethodRule,
+        tls: &TlsRule,
+        cluster_id: &Route,
+    ) -> bool {
+        insert_domain_rule(
+            &mut self.post,
+            domain.to_owned(),
+            rule_from_parts(path, method, tls, cluster_id),
+        )
+    }
+
+    pub fn remove_pre_rule(
+        &mut self,
+        domain: &DomainRule,
+        path: &PathRule,
+        method: &MethodRule,
+        tls: &TlsRule,
+    ) -> bool {
+        delete_domain_rule(
+            &mut self.pre,
+            domain,
+            &rule_from_parts(path, method, tls, &Route::Deny),
+        )
+    }
+
+    pub fn remove_post_rule(
+        &mut self,
+        domain: &DomainRule,
+        path: &PathRule,
+        method: &MethodRule,
+        tls: &TlsRule,
+    ) -> bool {
+        delete_domain_rule(
+            &mut self.post,
+            domain,
+            &rule_from_parts(path, method, tls, &Route::Deny),
+        )
+    }
+
+    fn insert_tree_rule(&mut self, hostname: &[u8], rule: FrontendRule) -> bool {
         let hostname = match from_utf8(hostname) {
             Err(_) => return false,
             Ok(h) => h,
@@ -217,21 +519,18 @@ impl Router {
             Ok(hostname) => {
                 //FIXME: necessary ti build on stable rust (1.35), can be removed once 1.36 is there
                 let mut empty = true;
-                if let Some((_, ref mut paths)) =
+                if let Some((_, ref mut rules)) =
                     self.tree.domain_lookup_mut(hostname.as_bytes(), false)
                 {
                     empty = false;
-                    if !paths.iter().any(|(p, m, _)| p == path && m == method) {
-                        paths.push((path.to_owned(), method.to_owned(), cluster.to_owned()));
+                    if !rules.iter().any(|r| r.same_conditions(&rule)) {
+                        rules.push(rule);
                         return true;
                     }
                 }
 
                 if empty {
-                    self.tree.domain_insert(
-                        hostname.into_bytes(),
-                        vec![(path.to_owned(), method.to_owned(), cluster.to_owned())],
-                    );
+                    self.tree.domain_insert(hostname.into_bytes(), vec![rule]);
                     return true;
                 }
 
@@ -241,13 +540,7 @@ impl Router {
         }
     }
 
-    pub fn remove_tree_rule(
-        &mut self,
-        hostname: &[u8],
-        path: &PathRule,
-        method: &MethodRule,
-        // _cluster: &Route,
-    ) -> bool {
+    fn delete_tree_rule(&mut self, hostname: &[u8], rule: &FrontendRule) -> bool {
         let hostname = match from_utf8(hostname) {
             Err(_) => return false,
             Ok(h) => h,
@@ -256,15 +549,15 @@ impl Router {
         match ::idna::domain_to_ascii(hostname) {
             Ok(hostname) => {
                 let should_delete = {
-                    let paths_opt = self.tree.domain_lookup_mut(hostname.as_bytes(), false);
+                    let rules_opt = self.tree.domain_lookup_mut(hostname.as_bytes(), false);
 
-                    if let Some((_, paths)) = paths_opt {
-                        paths.retain(|(p, m, _)| p != path || m != method);
+                    if let Some((_, rules)) = rules_opt {
+                        rules.retain(|r| !r.same_conditions(rule));
                     }
 
-                    paths_opt
+                    rules_opt
                         .as_ref()
-                        .map(|(_, paths)| paths.is_empty())
+                        .map(|(_, rules)| rules.is_empty())
                         .unwrap_or(false)
                 };
 
@@ -277,90 +570,61 @@ impl Router {
             Err(_) => false,
         }
     }
+}
 
-    pub fn add_pre_rule(
-        &mut self,
-        domain: &DomainRule,
-        path: &PathRule,
-        method: &MethodRule,
-        cluster_id: &Route,
-    ) -> bool {
-        if !self
-            .pre
-            .iter()
-            .any(|(d, p, m, _)| d == domain && p == path && m == method)
-        {
-            self.pre.push((
-                domain.to_owned(),
-                path.to_owned(),
-                method.to_owned(),
-                cluster_id.to_owned(),
-            ));
-            true
-        } else {
-            false
-        }
-    }
+fn rule_from_parts(
+    path: &PathRule,
+    method: &MethodRule,
+    tls: &TlsRule,
+    route: &Route,
+) -> FrontendRule {
+    FrontendRule::new(path.to_owned(), route.to_owned())
+        .with_method(method.to_owned())
+        .with_tls(tls.to_owned())
+}
 
-    pub fn add_post_rule(
-        &mut self,
-        domain: &DomainRule,
-        path: &PathRule,
-        method: &MethodRule,
-        cluster_id: &Route,
-    ) -> bool {
-        if !self
-            .post
-            .iter()
-            .any(|(d, p, m, _)| d == domain && p == path && m == method)
-        {
-            self.post.push((
-                domain.to_owned(),
-                path.to_owned(),
-                method.to_owned(),
-                cluster_id.to_owned(),
-            ));
-            true
-        } else {
-            false
-        }
-    }
+fn parse_domain_rule(hostname: &str) -> Result<DomainRule, RouterError> {
+    hostname
+        .parse::<DomainRule>()
+        .map_err(|_| RouterError::InvalidDomain {
+            hostname: hostname.to_owned(),
+        })
+}
 
-    pub fn remove_pre_rule(
-        &mut self,
-        domain: &DomainRule,
-        path: &PathRule,
-        method: &MethodRule,
-    ) -> bool {
-        match self
-            .pre
-            .iter()
-            .position(|(d, p, m, _)| d == domain && p == path && m == method)
-        {
-            None => false,
-            Some(index) => {
-                self.pre.remove(index);
-                true
-            }
-        }
+fn insert_domain_rule(
+    rules: &mut Vec<(DomainRule, FrontendRule)>,
+    domain: DomainRule,
+    rule: FrontendRule,
+) -> bool {
+    if rules
+        .iter()
+        .any(|(d, r)| d == &domain && r.same_conditions(&rule))
+    {
+        return false;
+    }
+    // the challenges of the certificates ordered by the main process are answered
+    // before any other rule of the domain, even when added last
+    if rule.route == Route::ClusterId(ACME_CLUSTER_ID.to_owned()) {
+        rules.insert(0, (domain, rule));
+    } else {
+        rules.push((domain, rule));
     }
+    true
+}
 
-    pub fn remove_post_rule(
-        &mut self,
-        domain: &DomainRule,
-        path: &PathRule,
-        method: &MethodRule,
-    ) -> bool {
-        match self
-            .post
-            .iter()
-            .position(|(d, p, m, _)| d == domain && p == path && m == method)
-        {
-            None => false,
-            Some(index) => {
-                self.post.remove(index);
-                true
-            }
+fn delete_domain_rule(
+    rules: &mut Vec<(DomainRule, FrontendRule)>,
+    domain: &DomainRule,
+    rule: &FrontendRule,
+) -> bool {
+    match rules
+        .iter()
+        .position(|(d, r)| d == domain && r.same_conditions(rule))
+    {
+        None => false,
+        Some(index) => {
+            rules.remove(index);
+            true
         }
     }
 }
@@ -591,6 +855,60 @@ impl MethodRule {
     }
 }
 
+/// Conditions on the TLS parameters negotiated by an HTTPS client,
+/// to send legacy clients to a dedicated cluster
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsRule {
+    /// matches any version if empty
+    pub versions: Vec<TlsVersion>,
+    /// IANA names of the cipher suites, matches any cipher suite if empty
+    pub cipher_suites: Vec<String>,
+}
+
+/// TLS parameters negotiated by an HTTPS client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientTls {
+    pub version: TlsVersion,
+    pub cipher_suite: &'static str,
+}
+
+#[derive(PartialEq, Eq)]
+pub enum TlsRuleResult {
+    All,
+    Equals,
+    None,
+}
+
+impl TlsRule {
+    pub fn new(versions: &[TlsVersion], cipher_suites: &[String]) -> Self {
+        TlsRule {
+            versions: versions.to_owned(),
+            cipher_suites: cipher_suites.to_owned(),
+        }
+    }
+
+    pub fn matches(&self, tls: Option<&ClientTls>) -> TlsRuleResult {
+        if self.versions.is_empty() && self.cipher_suites.is_empty() {
+            return TlsRuleResult::All;
+        }
+        let Some(tls) = tls else {
+            return TlsRuleResult::None;
+        };
+
+        if (self.versions.is_empty() || self.versions.contains(&tls.version))
+            && (self.cipher_suites.is_empty()
+                || self
+                    .cipher_suites
+                    .iter()
+                    .any(|cipher_suite| cipher_suite == tls.cipher_suite))
+        {
+            TlsRuleResult::Equals
+        } else {
+            TlsRuleResult::None
+        }
+    }
+}
+
 /// The cluster to which the traffic will be redirected
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Route {
@@ -598,10 +916,43 @@ pub enum Route {
     Deny,
     /// the cluster to which the frontend belongs
     ClusterId(ClusterId),
+    /// clusters sharing the requests in proportion to their weights, for canary deployments
+    Split(Vec<WeightedCluster>),
+}
+
+impl Route {
+    /// the cluster a request is sent to, chosen at random for a split.
+    /// None if the request is denied
+    pub fn cluster_id(&self) -> Option<ClusterId> {
+        match self {
+            Route::Deny => None,
+            Route::ClusterId(cluster_id) => Some(cluster_id.to_owned()),
+            Route::Split(split) => {
+                let total = split.iter().map(|weighted| weighted.weight as u64).sum();
+                if total == 0 {
+                    return None;
+                }
+                pick_weighted(split, rand::thread_rng().gen_range(0..total)).map(str::to_owned)
+            }
+        }
+    }
+}
+
+/// the cluster owning the `roll`th unit of weight, `roll` being below the sum of the weights
+fn pick_weighted(split: &[WeightedCluster], mut roll: u64) -> Option<&str> {
+    for weighted in split {
+        if roll < weighted.weight as u64 {
+            return Some(&weighted.cluster_id);
+        }
+        roll -= weighted.weight as u64;
+    }
+    None
 }
 
 #[cfg(test)]
 mod tests {
+    use sozu_command::config::ACME_CHALLENGE_PATH;
+
     use super::*;
 
     #[test]
@@ -716,26 +1067,28 @@ mod tests {
             b"*.sozu.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("base".to_string())
         ));
         println!("{:#?}", router.tree);
         assert_eq!(
-            router.lookup("www.sozu.io", "/api", &Method::Get),
+            router.lookup("www.sozu.io", "/api", &Method::Get, None),
             Ok(Route::ClusterId("base".to_string()))
         );
         assert!(router.add_tree_rule(
             b"*.sozu.io",
             &PathRule::Prefix("/api".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("api".to_string())
         ));
         println!("{:#?}", router.tree);
         assert_eq!(
-            router.lookup("www.sozu.io", "/ap", &Method::Get),
+            router.lookup("www.sozu.io", "/ap", &Method::Get, None),
             Ok(Route::ClusterId("base".to_string()))
         );
         assert_eq!(
-            router.lookup("www.sozu.io", "/api", &Method::Get),
+            router.lookup("www.sozu.io", "/api", &Method::Get, None),
             Ok(Route::ClusterId("api".to_string()))
         );
     }
@@ -755,26 +1108,28 @@ mod tests {
             b"*.sozu.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("base".to_string())
         ));
         println!("{:#?}", router.tree);
         assert_eq!(
-            router.lookup("www.sozu.io", "/api", &Method::Get),
+            router.lookup("www.sozu.io", "/api", &Method::Get, None),
             Ok(Route::ClusterId("base".to_string()))
         );
         assert!(router.add_tree_rule(
             b"api.sozu.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("api".to_string())
         ));
         println!("{:#?}", router.tree);
         assert_eq!(
-            router.lookup("www.sozu.io", "/api", &Method::Get),
+            router.lookup("www.sozu.io", "/api", &Method::Get, None),
             Ok(Route::ClusterId("base".to_string()))
         );
         assert_eq!(
-            router.lookup("api.sozu.io", "/api", &Method::Get),
+            router.lookup("api.sozu.io", "/api", &Method::Get, None),
             Ok(Route::ClusterId("api".to_string()))
         );
     }
@@ -787,6 +1142,7 @@ mod tests {
             b"www./.*/.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("base".to_string())
         ));
         println!("{:#?}", router.tree);
@@ -794,26 +1150,30 @@ mod tests {
             b"www.doc./.*/.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("doc".to_string())
         ));
         println!("{:#?}", router.tree);
         assert_eq!(
-            router.lookup("www.sozu.io", "/", &Method::Get),
+            router.lookup("www.sozu.io", "/", &Method::Get, None),
             Ok(Route::ClusterId("base".to_string()))
         );
         assert_eq!(
-            router.lookup("www.doc.sozu.io", "/", &Method::Get),
+            router.lookup("www.doc.sozu.io", "/", &Method::Get, None),
             Ok(Route::ClusterId("doc".to_string()))
         );
         assert!(router.remove_tree_rule(
             b"www./.*/.io",
             &PathRule::Prefix("".to_string()),
-            &MethodRule::new(Some("GET".to_string()))
+            &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default()
         ));
         println!("{:#?}", router.tree);
-        assert!(router.lookup("www.sozu.io", "/", &Method::Get).is_err());
+        assert!(router
+            .lookup("www.sozu.io", "/", &Method::Get, None)
+            .is_err());
         assert_eq!(
-            router.lookup("www.doc.sozu.io", "/", &Method::Get),
+            router.lookup("www.doc.sozu.io", "/", &Method::Get, None),
             Ok(Route::ClusterId("doc".to_string()))
         );
     }
@@ -826,53 +1186,240 @@ mod tests {
             &"*".parse::<DomainRule>().unwrap(),
             &PathRule::Prefix("/.well-known/acme-challenge".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("acme".to_string())
         ));
         assert!(router.add_tree_rule(
             "www.example.com".as_bytes(),
             &PathRule::Prefix("/".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("example".to_string())
         ));
         assert!(router.add_tree_rule(
             "*.test.example.com".as_bytes(),
             &PathRule::Regex(Regex::new("/hello[A-Z]+/").unwrap()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("examplewildcard".to_string())
         ));
         assert!(router.add_tree_rule(
             "/test[0-9]/.example.com".as_bytes(),
             &PathRule::Prefix("/".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &TlsRule::default(),
             &Route::ClusterId("exampleregex".to_string())
         ));
 
         assert_eq!(
-            router.lookup("www.example.com", "/helloA", &Method::new(&b"GET"[..])),
+            router.lookup(
+                "www.example.com",
+                "/helloA",
+                &Method::new(&b"GET"[..]),
+                None
+            ),
             Ok(Route::ClusterId("example".to_string()))
         );
         assert_eq!(
             router.lookup(
                 "www.example.com",
                 "/.well-known/acme-challenge",
-                &Method::new(&b"GET"[..])
+                &Method::new(&b"GET"[..]),
+                None
             ),
             Ok(Route::ClusterId("acme".to_string()))
         );
         assert!(router
-            .lookup("www.test.example.com", "/", &Method::new(&b"GET"[..]))
+            .lookup("www.test.example.com", "/", &Method::new(&b"GET"[..]), None)
             .is_err());
         assert_eq!(
             router.lookup(
                 "www.test.example.com",
                 "/helloAB/",
-                &Method::new(&b"GET"[..])
+                &Method::new(&b"GET"[..]),
+                None
             ),
             Ok(Route::ClusterId("examplewildcard".to_string()))
         );
         assert_eq!(
-            router.lookup("test1.example.com", "/helloAB/", &Method::new(&b"GET"[..])),
+            router.lookup(
+                "test1.example.com",
+                "/helloAB/",
+                &Method::new(&b"GET"[..]),
+                None
+            ),
             Ok(Route::ClusterId("exampleregex".to_string()))
         );
     }
+
+    #[test]
+    fn match_router_on_client_tls() {
+        let mut router = Router::new();
+
+        assert!(router.add_tree_rule(
+            b"www.example.com",
+            &PathRule::Prefix("/".to_string()),
+            &MethodRule::new(None),
+            &TlsRule::default(),
+            &Route::ClusterId("modern".to_string())
+        ));
+        assert!(router.add_tree_rule(
+            b"www.example.com",
+            &PathRule::Prefix("/".to_string()),
+            &MethodRule::new(None),
+            &TlsRule::new(&[TlsVersion::TlsV12], &[]),
+            &Route::ClusterId("legacy".to_string())
+        ));
+        assert!(router.add_tree_rule(
+            b"www.example.com",
+            &PathRule::Prefix("/".to_string()),
+            &MethodRule::new(None),
+            &TlsRule::new(&[], &["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()]),
+            &Route::Deny
+        ));
+
+        let tls13 = ClientTls {
+            version: TlsVersion::TlsV13,
+            cipher_suite: "TLS13_AES_128_GCM_SHA256",
+        };
+        let tls12 = ClientTls {
+            version: TlsVersion::TlsV12,
+            cipher_suite: "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        };
+        let tls12_weak = ClientTls {
+            version: TlsVersion::TlsV12,
+            cipher_suite: "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        };
+
+        assert_eq!(
+            router.lookup("www.example.com", "/", &Method::Get, None),
+            Ok(Route::ClusterId("modern".to_string()))
+        );
+        assert_eq!(
+            router.lookup("www.example.com", "/", &Method::Get, Some(&tls13)),
+            Ok(Route::ClusterId("modern".to_string()))
+        );
+        assert_eq!(
+            router.lookup("www.example.com", "/", &Method::Get, Some(&tls12)),
+            Ok(Route::ClusterId("legacy".to_string()))
+        );
+        assert_eq!(
+            router.lookup("www.example.com", "/", &Method::Get, Some(&tls12_weak)),
+            Ok(Route::Deny)
+        );
+    }
+
+    #[test]
+    fn acme_challenges_before_other_pre_rules() {
+        let mut router = Router::new();
+        let domain = "example.com".parse::<DomainRule>().unwrap();
+
+        assert!(router.add_pre_rule(
+            &domain,
+            &PathRule::Prefix("/".to_string()),
+            &MethodRule::new(None),
+            &TlsRule::default(),
+            &Route::ClusterId("app".to_string())
+        ));
+        assert!(router.add_pre_rule(
+            &domain,
+            &PathRule::Prefix(ACME_CHALLENGE_PATH.to_string()),
+            &MethodRule::new(None),
+            &TlsRule::default(),
+            &Route::ClusterId(ACME_CLUSTER_ID.to_string())
+        ));
+
+        assert_eq!(
+            router.lookup(
+                "example.com",
+                "/.well-known/acme-challenge/token",
+                &Method::Get,
+                None
+            ),
+            Ok(Route::ClusterId(ACME_CLUSTER_ID.to_string()))
+        );
+        assert_eq!(
+            router.lookup("example.com", "/", &Method::Get, None),
+            Ok(Route::ClusterId("app".to_string()))
+        );
+    }
+
+    #[test]
+    fn ipv6_literal_hostnames() {
+        let mut router = Router::new();
+
+        assert!(router.add_tree_rule(
+            b"[2001:db8::1]",
+            &PathRule::Prefix("/".to_string()),
+            &MethodRule::new(None),
+            &TlsRule::default(),
+            &Route::ClusterId("tree".to_string())
+        ));
+        assert!(router.add_pre_rule(
+            &"[::1]".parse::<DomainRule>().unwrap(),
+            &PathRule::Prefix("/".to_string()),
+            &MethodRule::new(None),
+            &TlsRule::default(),
+            &Route::ClusterId("pre".to_string())
+        ));
+
+        assert_eq!(
+            router.lookup("[2001:db8::1]", "/", &Method::Get, None),
+            Ok(Route::ClusterId("tree".to_string()))
+        );
+        assert_eq!(
+            router.lookup("[::1]", "/", &Method::Get, None),
+            Ok(Route::ClusterId("pre".to_string()))
+        );
+        assert!(router.lookup("[::2]", "/", &Method::Get, None).is_err());
+    }
+
+    #[test]
+    fn split_between_weighted_clusters() {
+        let split = vec![
+            WeightedCluster {
+                cluster_id: "v1".to_string(),
+                weight: 95,
+            },
+            WeightedCluster {
+                cluster_id: "drained".to_string(),
+                weight: 0,
+            },
+            WeightedCluster {
+                cluster_id: "v2".to_string(),
+                weight: 5,
+            },
+        ];
+        assert_eq!(pick_weighted(&split, 0), Some("v1"));
+        assert_eq!(pick_weighted(&split, 94), Some("v1"));
+        assert_eq!(pick_weighted(&split, 95), Some("v2"));
+        assert_eq!(pick_weighted(&split, 99), Some("v2"));
+        assert_eq!(pick_weighted(&split, 100), None);
+
+        let front = HttpFrontend {
+            cluster_id: Some("v1".to_string()),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "example.com".to_string(),
+            path: CommandPathRule::prefix("/".to_string()),
+            method: None,
+            position: RulePosition::Tree,
+            tags: None,
+            expires_at: None,
+            client_tls_versions: vec![],
+            client_cipher_suites: vec![],
+            timeouts: None,
+            mirror: None,
+            split: split.clone(),
+            headers: vec![],
+            device: None,
+            rate_limit: None,
+        };
+        let mut router = Router::new();
+        router.add_http_front(&front).unwrap();
+        let route = router
+            .lookup("example.com", "/", &Method::Get, None)
+            .unwrap();
+        assert_eq!(route, Route::Split(split));
+        assert!((0..100).all(|_| route.cluster_id().as_deref() != Some("drained")));
+    }
 }
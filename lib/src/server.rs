@@ -12,19 +12,25 @@ use mio::{
     net::{TcpListener as MioTcpListener, TcpStream},
     Events, Interest, Poll, Token,
 };
+use rand::Rng;
 use slab::Slab;
 
 use sozu_command::{
     channel::Channel,
+    config::MAX_DSCP,
     logging,
     proto::command::{
         request::RequestType, response_content::ContentType, ActivateListener, AddBackend,
-        CertificatesWithFingerprints, Cluster, ClusterHashes, ClusterInformations,
-        DeactivateListener, Event, HttpListenerConfig, HttpsListenerConfig, InitialState,
+        BuildInfo, CertificatesWithFingerprints, Cluster, ClusterHashes, ClusterInformation,
+        ClusterInformations, DeactivateListener, DrainBackend, DrainingBackends, ErrorCode,
+        ErrorSubsystem, Event, EventKind, HttpListenerConfig, HttpsListenerConfig, InitialState,
         ListenerType, LoadBalancingAlgorithms, LoadMetric, MetricsConfiguration, RemoveBackend,
-        Request, ResponseStatus, ServerConfig, TcpListenerConfig as CommandTcpListener,
-        WorkerRequest, WorkerResponse,
+        ReplaceBackends, Request, ResponseContent, ResponseError, ResponseStatus, SequenceGap,
+        ServerConfig, SessionAudit, SetAcceptShare, SetBackendHealth, SetBackendWeight,
+        SetLoadBalancing, StickyEntry, TcpListenerConfig as CommandTcpListener, WorkerRequest,
+        WorkerResponse,
     },
+    proto::PROTOCOL_VERSION,
     ready::Ready,
     scm_socket::{Listeners, ScmSocket, ScmSocketError},
     state::ConfigState,
@@ -32,18 +38,32 @@ use sozu_command::{
 
 use crate::{
     backends::{Backend, BackendMap},
+    capture::CAPTURES,
     features::FEATURES,
     http, https,
     metrics::METRICS,
     pool::Pool,
+    socket::FdReserve,
     tcp,
-    timer::Timer,
+    timer::{Timeout, Timer},
     AcceptError, Protocol, ProxyConfiguration, ProxySession, SessionIsToBeClosed,
 };
 
 // Number of retries to perform on a server after a connection failure
 pub const CONN_RETRIES: u8 = 3;
 
+/// how often the connection counts of removed backends are reported to the main process
+const DRAINING_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// how often outlier detection evaluates the backends of the clusters
+const OUTLIER_DETECTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// file descriptors kept for the command channel and the metrics, released when the worker runs out of them
+const FD_RESERVE_SIZE: usize = 4;
+
+/// how long the listeners stop accepting connections after running out of file descriptors
+const FD_EXHAUSTION_PAUSE: Duration = Duration::from_millis(500);
+
 pub type ProxyChannel = Channel<WorkerResponse, WorkerRequest>;
 
 thread_local! {
@@ -67,6 +87,20 @@ pub fn push_event(event: Event) {
             message: String::new(),
             status: ResponseStatus::Processing.into(),
             content: Some(ContentType::Event(event).into()),
+            error: None,
+        });
+    });
+}
+
+/// send the backend chosen for a client to the main process, which shares it with the other workers
+pub fn push_sticky_entry(entry: StickyEntry) {
+    QUEUE.with(|queue| {
+        (*queue.borrow_mut()).push_back(WorkerResponse {
+            id: "STICKY".to_string(),
+            message: String::new(),
+            status: ResponseStatus::Processing.into(),
+            content: Some(ContentType::StickyEntry(entry).into()),
+            error: None,
         });
     });
 }
@@ -177,6 +211,59 @@ impl SessionManager {
             self.can_accept = true;
         }
     }
+
+    /// Number of sessions stored under their frontend token, listeners excluded.
+    /// It should always equal `nb_connections`
+    pub fn session_count(&self) -> usize {
+        self.slab
+            .iter()
+            .filter(|(key, session)| {
+                let session = session.borrow();
+                is_client_session(session.protocol()) && session.frontend_token().0 == *key
+            })
+            .count()
+    }
+
+    /// Keys of the slab entries whose session is not stored under its frontend
+    /// token anymore. Those are left behind when a session is removed without
+    /// removing the tokens of its backend connections
+    pub fn orphan_entries(&self) -> Vec<usize> {
+        self.slab
+            .iter()
+            .filter(|(key, session)| {
+                let frontend_token = {
+                    let session = session.borrow();
+                    if !is_client_session(session.protocol()) {
+                        return false;
+                    }
+                    session.frontend_token().0
+                };
+                if frontend_token == *key {
+                    return false;
+                }
+                match self.slab.get(frontend_token) {
+                    Some(owner) => !Rc::ptr_eq(owner, session),
+                    None => true,
+                }
+            })
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Replaces a connection count that drifted from the sessions actually stored
+    pub fn reset_connections(&mut self, nb_connections: usize) {
+        self.nb_connections = nb_connections;
+        gauge!("client.connections", self.nb_connections);
+
+        if !self.can_accept && self.nb_connections < self.max_connections * 90 / 100 {
+            gauge!("accept_queue.backpressure", 0);
+            self.can_accept = true;
+        }
+    }
+}
+
+fn is_client_session(protocol: Protocol) -> bool {
+    matches!(protocol, Protocol::HTTP | Protocol::HTTPS | Protocol::TCP)
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -211,25 +298,44 @@ pub enum ServerError {
 /// Listeners and sessions are all stored in a slab structure to index them
 /// by a [Token], they all have to implement the [ProxySession] trait.
 pub struct Server {
+    /// set while accepting is paused because the worker ran out of file descriptors
+    accept_paused_until: Option<Instant>,
     accept_queue_timeout: Duration,
     accept_queue: VecDeque<(TcpStream, ListenToken, Protocol, Instant)>,
     accept_ready: HashSet<ListenToken>,
+    /// percentage of the new connections accepted on the listeners, lowered by the main
+    /// process while a new worker takes over the same sockets during an upgrade
+    accept_share: u32,
     backends: Rc<RefCell<BackendMap>>,
     base_sessions_count: usize,
+    /// reported to the main process, set by the executable running the worker
+    build_info: BuildInfo,
     channel: ProxyChannel,
     config_state: ConfigState,
     current_poll_errors: i32,
+    /// set after a request was received out of order, until the main process resynchronizes the worker
+    desynchronized: bool,
+    fd_reserve: FdReserve,
     http: Rc<RefCell<http::HttpProxy>>,
     https: Rc<RefCell<https::HttpsProxy>>,
     last_sessions_len: usize,
     last_shutting_down_message: Option<Instant>,
     last_zombie_check: Instant,
+    last_draining_report: Instant,
+    last_outlier_detection: Instant,
+    /// sequence number of the last request of the main process, zero if it does not number them
+    last_sequence: u64,
+    last_session_audit: Instant,
     loop_start: Instant,
     max_poll_errors: i32, // TODO: make this configurable? this defaults to 10000 for now
     pub poll: Poll,
     poll_timeout: Option<Duration>, // TODO: make this configurable? this defaults to 1000 milliseconds for now
     scm_listeners: Option<Listeners>,
     scm: ScmSocket,
+    /// remove the orphans found by the periodic audits
+    session_audit_reclaim: bool,
+    /// zero if the periodic audits are disabled
+    session_audit_interval: Duration,
     sessions: Rc<RefCell<SessionManager>>,
     should_poll_at: Option<Instant>,
     shutting_down: Option<String>,
@@ -374,27 +480,44 @@ impl Server {
         }));
 
         let mut server = Server {
+            accept_paused_until: None,
             accept_queue_timeout: Duration::from_secs(u64::from(
                 server_config.accept_queue_timeout,
             )),
             accept_queue: VecDeque::new(),
             accept_ready: HashSet::new(),
+            accept_share: 100,
             backends,
             base_sessions_count,
+            build_info: BuildInfo {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                protocol_version: PROTOCOL_VERSION,
+                ..Default::default()
+            },
             channel,
             config_state: ConfigState::new(),
             current_poll_errors: 0,
+            desynchronized: false,
+            fd_reserve: FdReserve::new(FD_RESERVE_SIZE),
             http,
             https,
             last_sessions_len: 0, // to be reset on server run
             last_shutting_down_message: None,
             last_zombie_check: Instant::now(), // to be reset on server run
-            loop_start: Instant::now(),        // to be reset on server run
-            max_poll_errors: 10000,            // TODO: make it configurable?
+            last_draining_report: Instant::now(),
+            last_outlier_detection: Instant::now(),
+            last_sequence: 0,
+            last_session_audit: Instant::now(),
+            loop_start: Instant::now(), // to be reset on server run
+            max_poll_errors: 10000,     // TODO: make it configurable?
             poll_timeout: Some(Duration::from_millis(1000)), // TODO: make it configurable?
             poll,
             scm_listeners: None,
             scm,
+            session_audit_reclaim: server_config.session_audit_reclaim,
+            session_audit_interval: Duration::from_secs(u64::from(
+                server_config.session_audit_interval,
+            )),
             sessions,
             should_poll_at: None,
             shutting_down: None,
@@ -406,10 +529,17 @@ impl Server {
 
         // initialize the worker with the state we got from a file
         if let Some(state) = initial_state {
+            let start = Instant::now();
+            let count = state.requests.len();
             for request in state.requests {
                 trace!("generating initial config request: {:#?}", request);
                 server.notify_proxys(request);
             }
+            info!(
+                "applied the {} requests of the initial state in {:?}",
+                count,
+                start.elapsed()
+            );
 
             // do not send back answers to the initialization messages
             QUEUE.with(|queue| {
@@ -429,9 +559,12 @@ impl Server {
                 content:
                     Request {
                         request_type: Some(RequestType::Status(_)),
+                        ..
                     },
+                sequence,
             }) = msg
             {
+                server.last_sequence = sequence.unwrap_or_default();
                 if let Err(e) = server.channel.write_message(&WorkerResponse::ok(id)) {
                     error!("Could not send an ok to the main process: {}", e);
                 }
@@ -470,6 +603,11 @@ impl Server {
         Ok(server)
     }
 
+    /// describe the executable running the worker, instead of the library
+    pub fn set_build_info(&mut self, build_info: BuildInfo) {
+        self.build_info = build_info;
+    }
+
     /// The server runs in a loop until a shutdown is ordered
     pub fn run(&mut self) {
         let mut events = Events::with_capacity(1024); // TODO: make event capacity configurable?
@@ -565,12 +703,22 @@ impl Server {
                     }
                 }
             }
+            self.resume_accepting();
             self.handle_remaining_readiness();
             self.create_sessions();
 
             self.should_poll_at = TIMER.with(|timer| timer.borrow().next_poll_date());
+            if let Some(paused_until) = self.accept_paused_until {
+                self.should_poll_at = Some(
+                    self.should_poll_at
+                        .map_or(paused_until, |date| date.min(paused_until)),
+                );
+            }
 
             self.zombie_check();
+            self.report_draining_backends();
+            self.detect_outliers();
+            self.periodic_session_audit();
 
             let now = time::OffsetDateTime::now_utc();
             // clear the local metrics drain every plain hour (01:00, 02:00, etc.) to prevent memory overuse
@@ -649,7 +797,9 @@ impl Server {
             let request = self.channel.read_message();
             debug!("Received request {:?}", request);
             match request {
+                Ok(request) if !self.check_sequence(&request) => {}
                 Ok(request) => match request.content.request_type {
+                    Some(RequestType::Resync(state)) => self.resync(request.id, state),
                     Some(RequestType::HardStop(_)) => {
                         let req_id = request.id.clone();
                         self.notify(request);
@@ -666,9 +816,14 @@ impl Server {
                         self.last_sessions_len = self.sessions.borrow().slab.len();
                         self.notify(request);
                     }
-                    Some(RequestType::ReturnListenSockets(_)) => {
+                    Some(RequestType::ReturnListenSockets(ref return_sockets)) => {
                         info!("received ReturnListenSockets order");
-                        match self.return_listen_sockets() {
+                        let sent = if return_sockets.keep_accepting.unwrap_or(false) {
+                            self.share_listen_sockets()
+                        } else {
+                            self.return_listen_sockets()
+                        };
+                        match sent {
                             Ok(_) => push_queue(WorkerResponse::ok(request.id)),
                             Err(error) => push_queue(worker_response_error(
                                 request.id,
@@ -694,7 +849,252 @@ impl Server {
         false
     }
 
+    /// Check that a request follows the previous one sent by the main process.
+    /// Otherwise it is refused and the worker asks for a resync, refusing
+    /// the next requests until it gets it. Stopping the worker is always possible
+    fn check_sequence(&mut self, request: &WorkerRequest) -> bool {
+        let Some(sequence) = request.sequence else {
+            return true;
+        };
+        let expected = self.last_sequence + 1;
+        match request.content.request_type {
+            Some(RequestType::Resync(_)) => {
+                self.last_sequence = sequence;
+                return true;
+            }
+            Some(RequestType::HardStop(_)) | Some(RequestType::SoftStop(_)) => {
+                if sequence == expected {
+                    self.last_sequence = sequence;
+                }
+                return true;
+            }
+            _ => {}
+        }
+        if sequence == expected && !self.desynchronized {
+            self.last_sequence = sequence;
+            return true;
+        }
+
+        if !self.desynchronized {
+            self.desynchronized = true;
+            error!(
+                "request {} has the sequence number {} instead of {}, asking the main process for a resync",
+                request.id, sequence, expected
+            );
+            incr!("worker.sequence_gaps");
+            push_queue(WorkerResponse {
+                id: "RESYNC".to_string(),
+                message: String::new(),
+                status: ResponseStatus::Processing.into(),
+                content: Some(
+                    ContentType::SequenceGap(SequenceGap {
+                        expected,
+                        received: sequence,
+                    })
+                    .into(),
+                ),
+                error: None,
+            });
+        }
+        push_queue(
+            WorkerResponse::error(
+                &request.id,
+                format!("the worker expected the request number {expected}, it waits for a resync"),
+            )
+            .with_error(
+                ResponseError::new(ErrorCode::OutOfOrder, ErrorSubsystem::Worker)
+                    .with_hint("retry once the worker is resynchronized"),
+            ),
+        );
+        false
+    }
+
+    /// apply the difference between the state of the worker and the one of the main process
+    fn resync(&mut self, request_id: String, state: InitialState) {
+        let mut target = ConfigState::new();
+        for request in &state.requests {
+            if let Err(e) = target.dispatch(&request.content) {
+                error!("could not apply {} to the resync state: {}", request.id, e);
+            }
+        }
+
+        let changes = self.config_state.diff(&target);
+        info!(
+            "resynchronizing with the main process at sequence number {}: {} changes",
+            self.last_sequence,
+            changes.len()
+        );
+        for (index, change) in changes.into_iter().enumerate() {
+            self.notify_proxys(WorkerRequest::new(format!("RESYNC-{index}"), change));
+        }
+        // the main process does not wait for answers to the changes
+        QUEUE.with(|queue| {
+            queue
+                .borrow_mut()
+                .retain(|response| !response.id.starts_with("RESYNC-"))
+        });
+
+        self.desynchronized = false;
+        push_queue(WorkerResponse::ok(request_id));
+    }
+
     /// Scans all sessions that have been inactive for longer than the configured interval
+    /// tell the main process how many connections are left on removed backends
+    fn report_draining_backends(&mut self) {
+        if self.last_draining_report.elapsed() < DRAINING_REPORT_INTERVAL {
+            return;
+        }
+        self.last_draining_report = Instant::now();
+
+        let events = self.backends.borrow_mut().draining_events();
+        for event in events {
+            push_event(event);
+        }
+    }
+
+    /// eject the outlier backends, reinstate the ones whose ejection is over
+    fn detect_outliers(&mut self) {
+        if self.last_outlier_detection.elapsed() < OUTLIER_DETECTION_INTERVAL {
+            return;
+        }
+        self.last_outlier_detection = Instant::now();
+
+        let events = self.backends.borrow_mut().detect_outliers();
+        for event in events {
+            push_event(event);
+        }
+    }
+
+    /// stop accepting connections for a while after running out of file descriptors,
+    /// instead of failing on accept() in a loop
+    fn pause_accepting(&mut self) {
+        incr!("accept.fd_exhausted");
+        if self.accept_paused_until.is_some() {
+            return;
+        }
+        self.accept_paused_until = Some(Instant::now() + FD_EXHAUSTION_PAUSE);
+
+        let released = self.fd_reserve.release();
+        let connections = self.sessions.borrow().nb_connections;
+        error!(
+            "out of file descriptors with {} client connections, pausing accept for {}ms and releasing {} reserved file descriptors",
+            connections,
+            FD_EXHAUSTION_PAUSE.as_millis(),
+            released
+        );
+        gauge!("accept.paused", 1);
+        push_event(Event {
+            kind: EventKind::FdExhausted as i32,
+            cluster_id: None,
+            backend_id: None,
+            address: None,
+            alert: None,
+            value: Some(connections as u64),
+        });
+    }
+
+    /// accept connections again once the pause is over and the reserve is full again
+    fn resume_accepting(&mut self) {
+        match self.accept_paused_until {
+            Some(paused_until) if paused_until <= Instant::now() => {}
+            _ => return,
+        }
+        if !self.fd_reserve.refill() {
+            self.accept_paused_until = Some(Instant::now() + FD_EXHAUSTION_PAUSE);
+            return;
+        }
+        self.accept_paused_until = None;
+        info!("file descriptors are available again, accepting connections");
+        gauge!("accept.paused", 0);
+    }
+
+    fn can_accept(&self) -> bool {
+        self.accept_paused_until.is_none() && self.sessions.borrow().can_accept
+    }
+
+    fn periodic_session_audit(&mut self) {
+        if self.session_audit_interval.is_zero()
+            || self.last_session_audit.elapsed() < self.session_audit_interval
+        {
+            return;
+        }
+        self.last_session_audit = Instant::now();
+        self.audit_sessions(self.session_audit_reclaim);
+    }
+
+    /// Look for slab entries and timeouts left behind by closed sessions, and for
+    /// a connection count that does not match the sessions. With `reclaim`, the
+    /// orphans are removed and the count is corrected
+    fn audit_sessions(&mut self, reclaim: bool) -> SessionAudit {
+        let orphan_entries = self.sessions.borrow().orphan_entries();
+        let sessions = self.sessions.borrow().session_count();
+        let connections = self.sessions.borrow().nb_connections;
+
+        if reclaim {
+            for key in &orphan_entries {
+                // the session is dropped outside of the borrow, it may cancel its timeouts
+                let _orphan = self.sessions.borrow_mut().slab.remove(*key);
+            }
+            if connections != sessions {
+                self.sessions.borrow_mut().reset_connections(sessions);
+            }
+        }
+
+        // with reclaim, this includes the timeouts of the entries removed above
+        let (timer_entries, orphan_timers) = TIMER.with(|timer| {
+            let timer = timer.borrow();
+            let sessions = self.sessions.borrow();
+            let orphans: Vec<Timeout> = timer
+                .timeouts()
+                .filter(|(_, token)| !sessions.slab.contains(token.0))
+                .map(|(timeout, _)| timeout)
+                .collect();
+            (timer.len(), orphans)
+        });
+        if reclaim {
+            TIMER.with(|timer| {
+                let mut timer = timer.borrow_mut();
+                for timeout in &orphan_timers {
+                    timer.cancel_timeout(timeout);
+                }
+            });
+        }
+
+        let leaked_connections = connections.abs_diff(sessions);
+        gauge!("sessions.audit.orphan_entries", orphan_entries.len());
+        gauge!("sessions.audit.orphan_timers", orphan_timers.len());
+        gauge!("sessions.audit.leaked_connections", leaked_connections);
+
+        if !orphan_entries.is_empty() || !orphan_timers.is_empty() || leaked_connections > 0 {
+            warn!(
+                "session audit: {} orphan slab entries, {} orphan timeouts, {} connections counted for {} sessions{}",
+                orphan_entries.len(),
+                orphan_timers.len(),
+                connections,
+                sessions,
+                if reclaim { ", reclaimed" } else { "" }
+            );
+            if reclaim {
+                count!(
+                    "sessions.audit.reclaimed",
+                    (orphan_entries.len() + orphan_timers.len()) as i64
+                );
+            }
+        } else {
+            debug!("session audit: no leak found in {} sessions", sessions);
+        }
+
+        SessionAudit {
+            slab_entries: self.sessions.borrow().slab.len() as u64,
+            sessions: sessions as u64,
+            connections: connections as u64,
+            orphan_entries: orphan_entries.len() as u64,
+            timer_entries: timer_entries as u64,
+            orphan_timers: orphan_timers.len() as u64,
+            reclaimed: reclaim,
+        }
+    }
+
     fn zombie_check(&mut self) {
         let now = Instant::now();
         if now - self.last_zombie_check < self.zombie_check_interval {
@@ -895,6 +1295,42 @@ impl Server {
                 });
                 return;
             }
+            Some(RequestType::StartCapture(start)) => {
+                info!(
+                    "{} capturing the requests of cluster {} for {} seconds",
+                    message.id, start.cluster_id, start.duration
+                );
+                CAPTURES.with(|captures| {
+                    captures.borrow_mut().start(start, Instant::now());
+                });
+                push_queue(WorkerResponse::ok(message.id));
+                return;
+            }
+            Some(RequestType::CollectCapture(collect)) => {
+                let captured =
+                    CAPTURES.with(|captures| captures.borrow_mut().collect(&collect.cluster_id));
+                push_queue(WorkerResponse::ok_with_content(
+                    message.id,
+                    ContentType::CapturedRequests(captured).into(),
+                ));
+                return;
+            }
+            Some(RequestType::AuditSessions(audit)) => {
+                info!("{} auditing the sessions", message.id);
+                let report = self.audit_sessions(audit.reclaim);
+                push_queue(WorkerResponse::ok_with_content(
+                    message.id,
+                    ContentType::SessionAudit(report).into(),
+                ));
+                return;
+            }
+            Some(RequestType::QueryBuildInfo(_)) => {
+                push_queue(WorkerResponse::ok_with_content(
+                    message.id,
+                    ContentType::BuildInfo(self.build_info.clone()).into(),
+                ));
+                return;
+            }
             Some(RequestType::Logging(logging_filter)) => {
                 info!(
                     "{} changing logging filter to {}",
@@ -908,6 +1344,37 @@ impl Server {
                 push_queue(WorkerResponse::ok(message.id));
                 return;
             }
+            Some(RequestType::SetLogging(set)) => {
+                info!("{} changing logging to {:?}", message.id, set);
+                let backend = match set.target.as_deref().map(logging::try_target_to_backend) {
+                    Some(Err(error)) => {
+                        push_queue(WorkerResponse::error(
+                            message.id,
+                            format!("invalid log target: {error}"),
+                        ));
+                        return;
+                    }
+                    Some(Ok(backend)) => Some(backend),
+                    None => None,
+                };
+                // the filter was already parsed by the main process
+                let directives = set
+                    .filter
+                    .as_deref()
+                    .map(|filter| logging::parse_logging_spec(filter).0);
+                logging::LOGGER.with(|logger| {
+                    let mut logger = logger.borrow_mut();
+                    if let Some(backend) = backend {
+                        let colored = logger.colored;
+                        logger.set_backend(backend, colored);
+                    }
+                    if let Some(directives) = directives {
+                        logger.set_directives(directives);
+                    }
+                });
+                push_queue(WorkerResponse::ok(message.id));
+                return;
+            }
             Some(RequestType::QueryClustersHashes(_)) => {
                 push_queue(WorkerResponse::ok_with_content(
                     message.id.clone(),
@@ -919,15 +1386,27 @@ impl Server {
                 return;
             }
             Some(RequestType::QueryClusterById(cluster_id)) => {
+                let draining_backends = self
+                    .backends
+                    .borrow_mut()
+                    .draining_backends(Some(cluster_id));
+                let backend_health = self.backends.borrow().backend_health(cluster_id);
+                let vec = match self.config_state.cluster_state(cluster_id) {
+                    Some(cluster) => vec![ClusterInformation {
+                        draining_backends,
+                        backend_health,
+                        ..cluster
+                    }],
+                    // a removed cluster can still have connections to its backends
+                    None if !draining_backends.is_empty() => vec![ClusterInformation {
+                        draining_backends,
+                        ..Default::default()
+                    }],
+                    None => vec![],
+                };
                 push_queue(WorkerResponse::ok_with_content(
                     message.id.clone(),
-                    ContentType::Clusters(ClusterInformations {
-                        vec: self
-                            .config_state
-                            .cluster_state(cluster_id)
-                            .map_or(vec![], |ci| vec![ci]),
-                    })
-                    .into(),
+                    ContentType::Clusters(ClusterInformations { vec }).into(),
                 ));
             }
             Some(RequestType::QueryClustersByDomain(domain)) => {
@@ -936,7 +1415,13 @@ impl Server {
                     .get_cluster_ids_by_domain(domain.hostname.clone(), domain.path.clone());
                 let vec = cluster_ids
                     .iter()
-                    .filter_map(|cluster_id| self.config_state.cluster_state(cluster_id))
+                    .filter_map(|cluster_id| {
+                        let cluster = self.config_state.cluster_state(cluster_id)?;
+                        Some(ClusterInformation {
+                            backend_health: self.backends.borrow().backend_health(cluster_id),
+                            ..cluster
+                        })
+                    })
                     .collect();
 
                 push_queue(WorkerResponse::ok_with_content(
@@ -985,6 +1470,10 @@ impl Server {
                 self.add_cluster(cluster);
                 //not returning because the message must still be handled by each proxy
             }
+            Some(RequestType::RemoveCluster(ref cluster_id)) => {
+                self.backends.borrow_mut().track_removed_cluster(cluster_id);
+                //not returning because the message must still be handled by each proxy
+            }
             Some(RequestType::AddBackend(ref backend)) => {
                 push_queue(self.add_backend(&req_id, backend));
                 return;
@@ -993,6 +1482,50 @@ impl Server {
                 push_queue(self.remove_backend(&req_id, remove_backend));
                 return;
             }
+            Some(RequestType::ReplaceBackends(ref replace_backends)) => {
+                push_queue(self.replace_backends(&req_id, replace_backends));
+                return;
+            }
+            Some(RequestType::SetBackendWeight(ref set)) => {
+                push_queue(self.set_backend_weight(&req_id, set));
+                return;
+            }
+            Some(RequestType::DrainBackend(ref drain)) => {
+                push_queue(self.drain_backend(&req_id, drain));
+                return;
+            }
+            Some(RequestType::SetLoadBalancing(ref set)) => {
+                self.set_load_balancing(set);
+                //not returning because the message must still be handled by each proxy
+            }
+            Some(RequestType::SetStickyEntry(ref entry)) => {
+                self.backends.borrow_mut().set_sticky_entry(
+                    &entry.cluster_id,
+                    &entry.key,
+                    &entry.backend_id,
+                );
+                push_queue(WorkerResponse::ok(&req_id));
+                return;
+            }
+            Some(RequestType::SetBackendHealth(ref set)) => {
+                push_queue(self.set_backend_health(&req_id, set));
+                return;
+            }
+            Some(RequestType::SetAcceptShare(ref share)) => {
+                self.set_accept_share(share);
+                push_queue(WorkerResponse::ok(&req_id));
+                return;
+            }
+            Some(RequestType::SetSigningKeys(ref keys)) => {
+                info!(
+                    "{} signing the sticky sessions with keys {:?}",
+                    req_id,
+                    keys.key_ids()
+                );
+                self.backends.borrow_mut().sticky_signer.set_keys(keys);
+                push_queue(WorkerResponse::ok(&req_id));
+                return;
+            }
             _ => {}
         };
 
@@ -1013,7 +1546,12 @@ impl Server {
                 notify_response = Some(tcp_proxy_response);
             }
         }
-        if let Some(response) = notify_response {
+        if let Some(mut response) = notify_response {
+            if let Some(RequestType::RemoveCluster(cluster_id)) = &request.content.request_type {
+                if !response.is_failure() {
+                    response.content = self.draining_content(cluster_id);
+                }
+            }
             push_queue(response);
         }
 
@@ -1039,6 +1577,21 @@ impl Server {
                 };
                 push_queue(response);
             }
+            Some(RequestType::UpdateListenerAnswers(ref update)) => {
+                debug!(
+                    "{} update answers of {:?} listener {:?}",
+                    req_id, update.proxy, update
+                );
+                let response = match ListenerType::try_from(update.proxy) {
+                    Ok(ListenerType::Http) => self.http.borrow_mut().notify(request),
+                    Ok(ListenerType::Https) => self.https.borrow_mut().notify(request),
+                    Ok(ListenerType::Tcp) => {
+                        WorkerResponse::error(req_id, "TCP listeners have no HTTP answers")
+                    }
+                    Err(_) => WorkerResponse::error(req_id, "Wrong variant ListenerType"),
+                };
+                push_queue(response);
+            }
             Some(RequestType::ActivateListener(ref activate)) => {
                 push_queue(self.notify_activate_listener(&req_id, activate));
             }
@@ -1050,25 +1603,48 @@ impl Server {
     }
 
     fn add_cluster(&mut self, cluster: &Cluster) {
-        self.backends
-            .borrow_mut()
-            .set_load_balancing_policy_for_cluster(
-                &cluster.cluster_id,
-                LoadBalancingAlgorithms::try_from(cluster.load_balancing).unwrap_or_default(),
-                cluster
-                    .load_metric
-                    .and_then(|n| LoadMetric::try_from(n).ok()),
-            );
+        let mut backends = self.backends.borrow_mut();
+        backends.set_load_balancing_policy_for_cluster(
+            &cluster.cluster_id,
+            LoadBalancingAlgorithms::try_from(cluster.load_balancing).unwrap_or_default(),
+            cluster
+                .load_metric
+                .and_then(|n| LoadMetric::try_from(n).ok()),
+            cluster.hash_key.clone(),
+        );
+        backends.set_source_address_for_cluster(
+            &cluster.cluster_id,
+            cluster.source_address.clone().map(Into::into),
+            cluster.transparent,
+        );
+        backends.set_sticky_table_for_cluster(&cluster.cluster_id, cluster.sticky_table);
+
+        let dscp = cluster.dscp.and_then(|dscp| match u8::try_from(dscp) {
+            Ok(dscp) if dscp <= MAX_DSCP => Some(dscp),
+            _ => {
+                error!(
+                    "invalid DSCP value {} for cluster {}, it should be at most {}",
+                    dscp, cluster.cluster_id, MAX_DSCP
+                );
+                None
+            }
+        });
+        backends.set_dscp_for_cluster(&cluster.cluster_id, dscp, cluster.dscp_on_clients);
+        backends.set_outlier_detection_for_cluster(
+            &cluster.cluster_id,
+            cluster.outlier_detection.clone(),
+        );
     }
 
     fn add_backend(&mut self, req_id: &str, add_backend: &AddBackend) -> WorkerResponse {
-        let new_backend = Backend::new(
+        let mut new_backend = Backend::new(
             &add_backend.backend_id,
             add_backend.address.clone().into(),
             add_backend.sticky_id.clone(),
             add_backend.load_balancing_parameters.clone(),
             add_backend.backup,
         );
+        new_backend.draining = add_backend.draining.unwrap_or(false);
         self.backends
             .borrow_mut()
             .add_backend(&add_backend.cluster_id, new_backend);
@@ -1082,7 +1658,129 @@ impl Server {
             .borrow_mut()
             .remove_backend(&backend.cluster_id, &address);
 
-        WorkerResponse::ok(req_id)
+        WorkerResponse {
+            content: self.draining_content(&backend.cluster_id),
+            ..WorkerResponse::ok(req_id)
+        }
+    }
+
+    /// the removed or drained backends of a cluster that still have open connections, if any
+    fn draining_content(&mut self, cluster_id: &str) -> Option<ResponseContent> {
+        let backends = self
+            .backends
+            .borrow_mut()
+            .draining_backends(Some(cluster_id));
+        if backends.is_empty() {
+            return None;
+        }
+        Some(ContentType::DrainingBackends(DrainingBackends { backends }).into())
+    }
+
+    fn replace_backends(&mut self, req_id: &str, replace: &ReplaceBackends) -> WorkerResponse {
+        if let Some(add_backend) = replace
+            .backends
+            .iter()
+            .find(|add_backend| add_backend.cluster_id != replace.cluster_id)
+        {
+            return worker_response_error(
+                req_id,
+                format!(
+                    "backend {} belongs to cluster {}, not to cluster {}",
+                    add_backend.backend_id, add_backend.cluster_id, replace.cluster_id
+                ),
+            );
+        }
+
+        let new_backends = replace
+            .backends
+            .iter()
+            .map(|add_backend| {
+                let mut backend = Backend::new(
+                    &add_backend.backend_id,
+                    add_backend.address.clone().into(),
+                    add_backend.sticky_id.clone(),
+                    add_backend.load_balancing_parameters.clone(),
+                    add_backend.backup,
+                );
+                backend.draining = add_backend.draining.unwrap_or(false);
+                backend
+            })
+            .collect();
+        self.backends
+            .borrow_mut()
+            .replace_backends(&replace.cluster_id, new_backends);
+
+        WorkerResponse {
+            content: self.draining_content(&replace.cluster_id),
+            ..WorkerResponse::ok(req_id)
+        }
+    }
+
+    fn set_backend_weight(&mut self, req_id: &str, set: &SetBackendWeight) -> WorkerResponse {
+        match self.backends.borrow_mut().set_backend_weight(
+            &set.cluster_id,
+            &set.backend_id,
+            set.weight,
+        ) {
+            Ok(()) => WorkerResponse::ok(req_id),
+            Err(error) => worker_response_error(req_id, error.to_string()),
+        }
+    }
+
+    fn drain_backend(&mut self, req_id: &str, drain: &DrainBackend) -> WorkerResponse {
+        if let Err(error) = self.backends.borrow_mut().drain_backend(
+            &drain.cluster_id,
+            &drain.backend_id,
+            !drain.undrain.unwrap_or(false),
+        ) {
+            return worker_response_error(req_id, error.to_string());
+        }
+
+        WorkerResponse {
+            content: self.draining_content(&drain.cluster_id),
+            ..WorkerResponse::ok(req_id)
+        }
+    }
+
+    fn set_backend_health(&mut self, req_id: &str, set: &SetBackendHealth) -> WorkerResponse {
+        match self.backends.borrow_mut().set_backend_health(
+            &set.cluster_id,
+            &set.backend_id,
+            &set.address.clone().into(),
+            set.healthy,
+        ) {
+            Ok(()) => WorkerResponse::ok(req_id),
+            Err(error) => worker_response_error(req_id, error.to_string()),
+        }
+    }
+
+    /// the other worker accepts the connections this one lets through,
+    /// a share of 0 stops accepting new connections
+    fn set_accept_share(&mut self, share: &SetAcceptShare) {
+        self.accept_share = share.percent.min(100);
+        info!("accepting {}% of the new connections", self.accept_share);
+        if self.accept_share == 0 {
+            self.accept_ready.clear();
+        }
+        gauge!("accept.share", self.accept_share as usize);
+    }
+
+    /// apply the load balancing of a cluster, as updated in the config state,
+    /// to its current backends
+    fn set_load_balancing(&mut self, set: &SetLoadBalancing) {
+        let Some(cluster) = self.config_state.clusters.get(&set.cluster_id) else {
+            return;
+        };
+        let mut backends = self.backends.borrow_mut();
+        backends.set_load_balancing_policy_for_cluster(
+            &cluster.cluster_id,
+            LoadBalancingAlgorithms::try_from(cluster.load_balancing).unwrap_or_default(),
+            cluster
+                .load_metric
+                .and_then(|n| LoadMetric::try_from(n).ok()),
+            cluster.hash_key.clone(),
+        );
+        backends.set_sticky_table_for_cluster(&cluster.cluster_id, cluster.sticky_table);
     }
 
     fn notify_add_http_listener(
@@ -1391,6 +2089,23 @@ impl Server {
         }
     }
 
+    /// Send the socket addresses and file descriptors of all proxies via the scm socket,
+    /// while keeping them to accept connections alongside the worker receiving them
+    pub fn share_listen_sockets(&mut self) -> Result<(), ScmSocketError> {
+        self.unblock_scm_socket();
+
+        let listeners = Listeners {
+            http: self.http.borrow().listener_fds(),
+            tls: self.https.borrow().listener_fds(),
+            tcp: self.tcp.borrow().listener_fds(),
+        };
+        info!("sending copies of the listeners: {:?}", listeners);
+        let res = self.scm.send_listeners(&listeners);
+
+        self.block_scm_socket();
+        res
+    }
+
     /// Send all socket addresses and file descriptors of all proxies, via the scm socket
     pub fn return_listen_sockets(&mut self) -> Result<(), ScmSocketError> {
         self.unblock_scm_socket();
@@ -1469,7 +2184,8 @@ impl Server {
     pub fn accept(&mut self, token: ListenToken, protocol: Protocol) {
         match protocol {
             Protocol::TCPListen => loop {
-                match self.tcp.borrow_mut().accept(token) {
+                let accepted = self.tcp.borrow_mut().accept(token);
+                match accepted {
                     Ok(sock) => self.accept_queue.push_back((
                         sock,
                         token,
@@ -1480,6 +2196,11 @@ impl Server {
                         self.accept_ready.remove(&token);
                         break;
                     }
+                    Err(AcceptError::TooManyFiles) => {
+                        // the token stays ready, to accept once the pause is over
+                        self.pause_accepting();
+                        break;
+                    }
                     Err(other) => {
                         error!("error accepting TCP sockets: {:?}", other);
                         self.accept_ready.remove(&token);
@@ -1488,7 +2209,8 @@ impl Server {
                 }
             },
             Protocol::HTTPListen => loop {
-                match self.http.borrow_mut().accept(token) {
+                let accepted = self.http.borrow_mut().accept(token);
+                match accepted {
                     Ok(sock) => self.accept_queue.push_back((
                         sock,
                         token,
@@ -1499,6 +2221,11 @@ impl Server {
                         self.accept_ready.remove(&token);
                         break;
                     }
+                    Err(AcceptError::TooManyFiles) => {
+                        // the token stays ready, to accept once the pause is over
+                        self.pause_accepting();
+                        break;
+                    }
                     Err(other) => {
                         error!("error accepting HTTP sockets: {:?}", other);
                         self.accept_ready.remove(&token);
@@ -1507,7 +2234,8 @@ impl Server {
                 }
             },
             Protocol::HTTPSListen => loop {
-                match self.https.borrow_mut().accept(token) {
+                let accepted = self.https.borrow_mut().accept(token);
+                match accepted {
                     Ok(sock) => self.accept_queue.push_back((
                         sock,
                         token,
@@ -1518,6 +2246,11 @@ impl Server {
                         self.accept_ready.remove(&token);
                         break;
                     }
+                    Err(AcceptError::TooManyFiles) => {
+                        // the token stays ready, to accept once the pause is over
+                        self.pause_accepting();
+                        break;
+                    }
                     Err(other) => {
                         error!("error accepting HTTPS sockets: {:?}", other);
                         self.accept_ready.remove(&token);
@@ -1602,8 +2335,14 @@ impl Server {
                 Protocol::HTTPListen | Protocol::HTTPSListen | Protocol::TCPListen => {
                     //info!("PROTOCOL IS LISTEN");
                     if events.is_readable() {
+                        // leave the connections to the other worker listening on this socket
+                        if self.accept_share < 100
+                            && rand::thread_rng().gen_range(0..100) >= self.accept_share
+                        {
+                            return;
+                        }
                         self.accept_ready.insert(ListenToken(token.0));
-                        if self.sessions.borrow().can_accept {
+                        if self.can_accept() {
                             self.accept(ListenToken(token.0), protocol);
                         }
                         return;
@@ -1650,7 +2389,7 @@ impl Server {
     pub fn handle_remaining_readiness(&mut self) {
         // try to accept again after handling all session events,
         // since we might have released a few session slots
-        if self.sessions.borrow().can_accept && !self.accept_ready.is_empty() {
+        if self.can_accept() && !self.accept_ready.is_empty() {
             while let Some(token) = self
                 .accept_ready
                 .iter()
@@ -1659,7 +2398,7 @@ impl Server {
             {
                 let protocol = self.sessions.borrow().slab[token.0].borrow().protocol();
                 self.accept(token, protocol);
-                if !self.sessions.borrow().can_accept || self.accept_ready.is_empty() {
+                if !self.can_accept() || self.accept_ready.is_empty() {
                     break;
                 }
             }
@@ -1727,3 +2466,60 @@ impl ProxySession for ListenSession {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sozu_command::proto::command::SetLogging;
+
+    use super::*;
+    use crate::testing::TestProxy;
+
+    #[test]
+    fn change_the_logging_of_a_running_worker() {
+        let path = std::env::temp_dir().join(format!("sozu-set-logging-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut proxy = TestProxy::start("SET_LOGGING", &ConfigState::new()).unwrap();
+
+        let mut set_logging = |filter: Option<&str>, target: Option<String>| {
+            proxy
+                .send(RequestType::SetLogging(SetLogging {
+                    filter: filter.map(str::to_owned),
+                    target,
+                    ..Default::default()
+                }))
+                .unwrap()
+        };
+
+        // the worker logs in the file from now on
+        let response = set_logging(Some("info"), Some(format!("file://{}", path.display())));
+        assert_eq!(response.status, ResponseStatus::Ok as i32, "{response:?}");
+        let response = set_logging(Some("error"), None);
+        assert_eq!(response.status, ResponseStatus::Ok as i32, "{response:?}");
+        // not logged, the filter only lets errors through
+        let response = set_logging(Some("info"), None);
+        assert_eq!(response.status, ResponseStatus::Ok as i32, "{response:?}");
+
+        let response = set_logging(None, Some(String::from("nowhere")));
+        assert_eq!(
+            response.status,
+            ResponseStatus::Failure as i32,
+            "{response:?}"
+        );
+        assert!(
+            response.message.contains("invalid log target"),
+            "{}",
+            response.message
+        );
+        proxy.stop().unwrap();
+
+        let logs = std::fs::read_to_string(&path).unwrap();
+        let changes: Vec<&str> = logs
+            .lines()
+            .filter(|line| line.contains("changing logging"))
+            .collect();
+        assert_eq!(changes.len(), 2, "{logs}");
+        assert!(changes[0].contains("filter: Some(\"error\")"), "{logs}");
+        assert!(changes[1].contains("target: Some(\"nowhere\")"), "{logs}");
+        let _ = std::fs::remove_file(&path);
+    }
+}
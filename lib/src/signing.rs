@@ -0,0 +1,110 @@
+//! Signature of the sticky session cookies.
+//!
+//! The value of a signed cookie is `<sticky id>.<key id>.<signature>`, the signature
+//! being an HMAC-SHA256 of the cluster id and of the sticky id, in hexadecimal.
+//! A value signed with any of the keys sent by the main process is accepted, so that
+//! the cookies given before a rotation still work until the next one. Signatures are
+//! deterministic, so those cookies differ from the value signed with the current key,
+//! and are given again to the clients.
+//! Without keys, the values are neither signed nor verified.
+
+use ring::hmac;
+
+use sozu_command::proto::command::SigningKeys;
+
+#[derive(Debug, Default)]
+pub struct StickySigner {
+    /// key id and HMAC key, the current key first
+    keys: Vec<(String, hmac::Key)>,
+}
+
+impl StickySigner {
+    pub fn set_keys(&mut self, signing_keys: &SigningKeys) {
+        self.keys = signing_keys
+            .keys
+            .iter()
+            .map(|key| {
+                (
+                    key.key_id.to_owned(),
+                    hmac::Key::new(hmac::HMAC_SHA256, &key.secret),
+                )
+            })
+            .collect();
+    }
+
+    /// the cookie value pointing to `sticky_id`, signed with the current key
+    pub fn sign(&self, cluster_id: &str, sticky_id: &str) -> String {
+        match self.keys.first() {
+            Some((key_id, key)) => {
+                let tag = hmac::sign(key, &message(cluster_id, sticky_id));
+                format!("{sticky_id}.{key_id}.{}", hex::encode(tag.as_ref()))
+            }
+            None => sticky_id.to_owned(),
+        }
+    }
+
+    /// the sticky id of a cookie value, if it was signed for this cluster with a known key
+    pub fn verify<'a>(&self, cluster_id: &str, value: &'a str) -> Option<&'a str> {
+        if self.keys.is_empty() {
+            return Some(value);
+        }
+        let (sticky_id, key_id, signature) = split(value)?;
+        let (_, key) = self.keys.iter().find(|(id, _)| id == key_id)?;
+        let signature = hex::decode(signature).ok()?;
+        hmac::verify(key, &message(cluster_id, sticky_id), &signature).ok()?;
+        Some(sticky_id)
+    }
+}
+
+/// sticky id, key id and signature. The sticky id may contain dots
+fn split(value: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = value.rsplitn(3, '.');
+    let signature = parts.next()?;
+    let key_id = parts.next()?;
+    let sticky_id = parts.next()?;
+    Some((sticky_id, key_id, signature))
+}
+
+fn message(cluster_id: &str, sticky_id: &str) -> Vec<u8> {
+    [cluster_id.as_bytes(), b"\0", sticky_id.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command::proto::command::SigningKey;
+
+    #[test]
+    fn sign_and_verify_sticky_sessions_across_a_rotation() {
+        let mut signer = StickySigner::default();
+        assert_eq!(signer.sign("cluster_1", "server.1"), "server.1");
+        assert_eq!(signer.verify("cluster_1", "server.1"), Some("server.1"));
+
+        let first = SigningKey::new(vec![1; 32]).unwrap();
+        let second = SigningKey::new(vec![2; 32]).unwrap();
+        let mut keys = SigningKeys::default();
+        keys.rotate(first);
+        signer.set_keys(&keys);
+
+        let old_value = signer.sign("cluster_1", "server.1");
+        assert_eq!(signer.verify("cluster_1", &old_value), Some("server.1"));
+        assert_eq!(signer.verify("cluster_2", &old_value), None);
+        assert_eq!(signer.verify("cluster_1", "server.1"), None);
+        assert_eq!(
+            signer.verify("cluster_1", &old_value.replace("server.1", "server.2")),
+            None
+        );
+
+        keys.rotate(second);
+        signer.set_keys(&keys);
+        assert_eq!(signer.verify("cluster_1", &old_value), Some("server.1"));
+        let new_value = signer.sign("cluster_1", "server.1");
+        assert_ne!(new_value, old_value);
+        assert_eq!(signer.sign("cluster_1", "server.1"), new_value);
+
+        keys.rotate(SigningKey::generate());
+        signer.set_keys(&keys);
+        assert_eq!(signer.verify("cluster_1", &old_value), None);
+        assert_eq!(signer.verify("cluster_1", &new_value), Some("server.1"));
+    }
+}
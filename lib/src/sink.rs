@@ -0,0 +1,121 @@
+//! Local sinks the workers write records to, like the request mirrors and the slow logs
+//!
+//! Files are appended to, unix datagram sockets get one record per datagram.
+//! Writing never blocks the worker: the records over the rate of the sink,
+//! or that can not be written at once, are dropped.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    time::{Duration, Instant},
+};
+
+use sozu_command::proto::command::MirrorSink;
+
+#[derive(Debug)]
+enum Writer {
+    /// opened on the first record, and again after a failed write
+    File(Option<File>),
+    UnixSocket(Option<UnixDatagram>),
+}
+
+impl Writer {
+    fn new(sink: MirrorSink) -> Self {
+        match sink {
+            MirrorSink::File => Writer::File(None),
+            MirrorSink::UnixSocket => Writer::UnixSocket(None),
+        }
+    }
+
+    fn write(&mut self, path: &str, record: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::File(file) => {
+                let result = match file {
+                    Some(file) => file.write_all(record),
+                    None => OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .and_then(|mut opened| {
+                            opened.write_all(record)?;
+                            *file = Some(opened);
+                            Ok(())
+                        }),
+                };
+                if result.is_err() {
+                    *file = None;
+                }
+                result
+            }
+            Writer::UnixSocket(socket) => {
+                if socket.is_none() {
+                    let unbound = UnixDatagram::unbound()?;
+                    unbound.set_nonblocking(true)?;
+                    *socket = Some(unbound);
+                }
+                match socket {
+                    Some(socket) => socket.send_to(record, path).map(|_| ()),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Sink {
+    writer: Writer,
+    window_start: Instant,
+    written_in_window: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Sinks {
+    /// sink kind and path -> sink, shared by the users writing to the same path
+    sinks: HashMap<(i32, String), Sink>,
+}
+
+impl Sinks {
+    /// write a record to a sink, unless it already got `max_per_second` records in
+    /// the current second. `record` is only built if it is written.
+    /// Returns false if it was dropped
+    pub fn write<F>(
+        &mut self,
+        kind: MirrorSink,
+        path: &str,
+        max_per_second: u32,
+        now: Instant,
+        record: F,
+    ) -> bool
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        let sink = self
+            .sinks
+            .entry((kind as i32, path.to_owned()))
+            .or_insert_with(|| Sink {
+                writer: Writer::new(kind),
+                window_start: now,
+                written_in_window: 0,
+            });
+
+        if now.duration_since(sink.window_start) >= Duration::from_secs(1) {
+            sink.window_start = now;
+            sink.written_in_window = 0;
+        }
+        if sink.written_in_window >= max_per_second {
+            return false;
+        }
+        sink.written_in_window += 1;
+
+        match sink.writer.write(path, &record()) {
+            Ok(()) => true,
+            Err(error) => {
+                debug!("could not write a record to {}: {}", path, error);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Log of the slow requests of a cluster to a local sink
+//!
+//! A request whose response time reaches the threshold of its cluster is written as
+//! one line: `SOZU-SLOW <request id> <unix time in ms> <cluster id> <backend id>
+//! <client address> <method> <authority><path> <status>` followed by the breakdown
+//! of its response time in milliseconds (`response_time`, `service_time`, `wait_time`,
+//! `backend_connect`, `backend_response`), its `retries` and its bytes in and out.
+//! Unknown values are written as `-`. Like the mirrors, the records go through
+//! [`crate::sink`], so that logging never blocks the worker.
+
+use std::{cell::RefCell, fmt::Display, net::SocketAddr, time::Duration};
+
+use sozu_command::proto::command::SlowLog;
+
+use crate::sink::Sinks;
+
+thread_local! {
+    pub static SLOW_LOGS: RefCell<Sinks> = RefCell::new(Sinks::default());
+}
+
+/// true if a request answered in `response_time` should be logged
+pub fn is_slow(slow_log: &SlowLog, response_time: Duration) -> bool {
+    response_time >= Duration::from_millis(slow_log.threshold as u64)
+}
+
+/// what is known of a slow request when it is logged
+#[derive(Debug)]
+pub struct SlowRequest<'a> {
+    pub request_id: &'a str,
+    pub unix_time_ms: u128,
+    pub cluster_id: &'a str,
+    pub backend_id: Option<&'a str>,
+    pub client: Option<SocketAddr>,
+    pub method: Option<String>,
+    pub authority: Option<&'a str>,
+    pub path: Option<&'a str>,
+    pub status: Option<u16>,
+    pub response_time: Duration,
+    pub service_time: Duration,
+    pub wait_time: Duration,
+    pub backend_connect: Option<Duration>,
+    pub backend_response: Option<Duration>,
+    pub retries: u8,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+}
+
+impl SlowRequest<'_> {
+    /// the line written to the sink
+    pub fn record(&self) -> Vec<u8> {
+        fn or_dash<T: Display>(value: Option<T>) -> String {
+            value
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| "-".to_owned())
+        }
+        fn millis(duration: Duration) -> String {
+            format!("{:.3}", duration.as_secs_f64() * 1000.0)
+        }
+
+        format!(
+            "SOZU-SLOW {} {} {} {} {} {} {}{} {} response_time={} service_time={} \
+             wait_time={} backend_connect={} backend_response={} retries={} \
+             bytes_in={} bytes_out={}\n",
+            self.request_id,
+            self.unix_time_ms,
+            self.cluster_id,
+            or_dash(self.backend_id),
+            or_dash(self.client),
+            or_dash(self.method.as_ref()),
+            self.authority.unwrap_or("-"),
+            self.path.unwrap_or(""),
+            or_dash(self.status),
+            millis(self.response_time),
+            millis(self.service_time),
+            millis(self.wait_time),
+            or_dash(self.backend_connect.map(millis)),
+            or_dash(self.backend_response.map(millis)),
+            self.retries,
+            self.bytes_in,
+            self.bytes_out,
+        )
+        .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command::proto::command::MirrorSink;
+
+    #[test]
+    fn log_the_requests_over_the_threshold() {
+        let slow_log = SlowLog {
+            threshold: 500,
+            sink: MirrorSink::File as i32,
+            path: "/tmp/slow.log".to_owned(),
+            max_per_second: 10,
+        };
+        assert!(!is_slow(&slow_log, Duration::from_millis(499)));
+        assert!(is_slow(&slow_log, Duration::from_millis(500)));
+
+        let request = SlowRequest {
+            request_id: "01H",
+            unix_time_ms: 1000,
+            cluster_id: "cluster_1",
+            backend_id: Some("cluster_1-0"),
+            client: "127.0.0.1:1234".parse().ok(),
+            method: Some("GET".to_owned()),
+            authority: Some("example.com"),
+            path: Some("/slow"),
+            status: Some(200),
+            response_time: Duration::from_micros(612_500),
+            service_time: Duration::from_micros(1_250),
+            wait_time: Duration::ZERO,
+            backend_connect: Some(Duration::from_millis(1)),
+            backend_response: None,
+            retries: 1,
+            bytes_in: 80,
+            bytes_out: 512,
+        };
+        assert_eq!(
+            String::from_utf8(request.record()).unwrap(),
+            "SOZU-SLOW 01H 1000 cluster_1 cluster_1-0 127.0.0.1:1234 GET example.com/slow 200 \
+             response_time=612.500 service_time=1.250 wait_time=0.000 backend_connect=1.000 \
+             backend_response=- retries=1 bytes_in=80 bytes_out=512\n"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Server name of a TLS connection, read from its ClientHello
+//!
+//! The TCP listeners that route by SNI peek at the first TLS record sent by the
+//! client, without consuming it, and look for the `server_name` extension of the
+//! ClientHello (RFC 6066). The TLS stream is then relayed untouched to the backend,
+//! which terminates TLS. Only a ClientHello that fits in the first record is read,
+//! which is the case of the clients in the wild.
+
+/// maximum size of a TLS record: 5 bytes of header and 16 KiB of payload
+pub const MAX_RECORD_SIZE: usize = 5 + 16384;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// what the first bytes of a connection tell about its server name
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServerName {
+    /// the first TLS record is not complete yet
+    Incomplete,
+    /// the server name asked by the client, in lower case
+    Found(String),
+    /// a ClientHello without the `server_name` extension
+    Missing,
+    /// the connection does not start with a TLS ClientHello
+    NotTls,
+}
+
+/// read the server name from the first bytes sent by a client
+pub fn parse_server_name(data: &[u8]) -> ServerName {
+    if data.is_empty() {
+        return ServerName::Incomplete;
+    }
+    if data[0] != CONTENT_TYPE_HANDSHAKE {
+        return ServerName::NotTls;
+    }
+    if data.len() < 5 {
+        return ServerName::Incomplete;
+    }
+    let record_length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if data.len() < 5 + record_length {
+        return ServerName::Incomplete;
+    }
+
+    let mut reader = Reader(&data[5..5 + record_length]);
+    match client_hello_server_name(&mut reader) {
+        Some(Some(name)) => ServerName::Found(name),
+        Some(None) => ServerName::Missing,
+        None => ServerName::NotTls,
+    }
+}
+
+/// None if the ClientHello is malformed, Some(None) if it has no server name
+fn client_hello_server_name(reader: &mut Reader) -> Option<Option<String>> {
+    if reader.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    // the length of the handshake message, which may exceed the first record
+    reader.skip(3)?;
+    // legacy version and random
+    reader.skip(2 + 32)?;
+    let session_id_length = reader.u8()? as usize;
+    reader.skip(session_id_length)?;
+    let cipher_suites_length = reader.u16()? as usize;
+    reader.skip(cipher_suites_length)?;
+    let compression_methods_length = reader.u8()? as usize;
+    reader.skip(compression_methods_length)?;
+
+    if reader.is_empty() {
+        return Some(None);
+    }
+    let extensions_length = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_length)?);
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_length = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(extension_length)?);
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let list_length = extension.u16()? as usize;
+        let mut names = Reader(extension.take(list_length)?);
+        while !names.is_empty() {
+            let name_type = names.u8()?;
+            let name_length = names.u16()? as usize;
+            let name = names.take(name_length)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.to_lowercase()));
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // supported versions, before the server name
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+            extensions.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+            extensions.push(NAME_TYPE_HOST_NAME);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0x2a; 32]);
+        // session id, one cipher suite, the null compression method
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn read_the_server_name_of_a_client_hello() {
+        let record = client_hello(Some("Example.COM"));
+        assert_eq!(
+            parse_server_name(&record),
+            ServerName::Found("example.com".to_owned())
+        );
+        assert_eq!(
+            parse_server_name(&record[..record.len() - 1]),
+            ServerName::Incomplete
+        );
+        assert_eq!(parse_server_name(&record[..3]), ServerName::Incomplete);
+
+        assert_eq!(parse_server_name(&client_hello(None)), ServerName::Missing);
+        assert_eq!(
+            parse_server_name(b"GET / HTTP/1.1\r\n\r\n"),
+            ServerName::NotTls
+        );
+
+        let mut truncated = client_hello(Some("example.com"));
+        truncated[4] -= 4;
+        truncated.truncate(truncated.len() - 4);
+        assert_eq!(parse_server_name(&truncated), ServerName::NotTls);
+    }
+}
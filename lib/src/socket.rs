@@ -1,12 +1,18 @@
 use std::{
     io::{ErrorKind, Read, Write},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
 };
 
 use mio::net::{TcpListener, TcpStream};
-use rustls::{ProtocolVersion, ServerConnection};
+use rustls::{HandshakeKind, ProtocolVersion, ServerConnection, SupportedCipherSuite};
 use socket2::{Domain, Protocol, Socket, Type};
-use sozu_command::config::MAX_LOOP_ITERATIONS;
+use sozu_command::{config::MAX_LOOP_ITERATIONS, logging::TlsRecord, proto::command::TlsVersion};
+use x509_parser::parse_x509_certificate;
+
+use crate::{
+    https::{rustls_ciphersuite_str, rustls_version_str},
+    router::ClientTls,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ServerBindError {
@@ -20,12 +26,46 @@ pub enum ServerBindError {
     SetReuseAddress(std::io::Error),
     #[error("could not set reuse address: {0}")]
     SetReusePort(std::io::Error),
+    #[error("could not set IPV6_V6ONLY: {0}")]
+    SetOnlyV6(std::io::Error),
     #[error("Could not create socket: {0}")]
     SocketCreationError(std::io::Error),
     #[error("Invalid socket address '{address}': {error}")]
     InvalidSocketAddress { address: String, error: String },
 }
 
+/// Why a listener could not use an address
+#[derive(thiserror::Error, Debug)]
+pub enum AddressCheckError {
+    #[error("address {address} is already in use{}", .owner.as_ref().map(|owner| format!(" by {owner}")).unwrap_or_default())]
+    AddressInUse {
+        address: SocketAddr,
+        owner: Option<SocketOwner>,
+    },
+    #[error("permission denied to bind {address}, ports below 1024 need the CAP_NET_BIND_SERVICE capability")]
+    PermissionDenied { address: SocketAddr },
+    #[error("address {address} is not available on this host")]
+    AddressNotAvailable { address: SocketAddr },
+    #[error("could not bind {address}: {error}")]
+    Bind {
+        address: SocketAddr,
+        error: std::io::Error,
+    },
+}
+
+/// A process listening on a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketOwner {
+    pub pid: u32,
+    pub name: String,
+}
+
+impl std::fmt::Display for SocketOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "process {} (pid {})", self.name, self.pid)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum SocketResult {
     Continue,
@@ -34,6 +74,63 @@ pub enum SocketResult {
     Error,
 }
 
+/// Properties of the TLS handshake of a frontend session, for the access logs and
+/// the metrics of the clusters
+#[derive(Debug, Clone)]
+pub struct TlsProperties<'a> {
+    pub version: ProtocolVersion,
+    pub cipher_suite: Option<SupportedCipherSuite>,
+    pub sni: Option<&'a str>,
+    pub alpn: Option<&'a str>,
+    pub resumed: bool,
+    pub client_certificate_subject: Option<String>,
+}
+
+impl TlsProperties<'_> {
+    pub fn record(&self) -> TlsRecord<'_> {
+        TlsRecord {
+            version: self.version.as_str().unwrap_or("unknown"),
+            cipher: self
+                .cipher_suite
+                .and_then(|cipher_suite| cipher_suite.suite().as_str())
+                .unwrap_or("unknown"),
+            sni: self.sni,
+            alpn: self.alpn,
+            resumed: self.resumed,
+            client_certificate_subject: self.client_certificate_subject.as_deref(),
+        }
+    }
+
+    /// count the version and the cipher suite used by the requests of a cluster
+    pub fn count_for_cluster(&self, cluster_id: &str) {
+        incr!(rustls_version_str(self.version), Some(cluster_id), None);
+        if let Some(cipher_suite) = self.cipher_suite {
+            incr!(rustls_ciphersuite_str(cipher_suite), Some(cluster_id), None);
+        }
+    }
+}
+
+/// Details of the certificate a client authenticated with, on the listeners verifying them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    pub subject: String,
+    pub issuer: String,
+    /// serial number, in hexadecimal
+    pub serial: String,
+}
+
+impl ClientCertificate {
+    /// read the details of a DER encoded certificate
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, certificate) = parse_x509_certificate(der).ok()?;
+        Some(ClientCertificate {
+            subject: certificate.subject().to_string(),
+            issuer: certificate.issuer().to_string(),
+            serial: format!("{:X}", certificate.serial),
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum TransportProtocol {
     Tcp,
@@ -52,6 +149,23 @@ pub trait SocketHandler {
     fn socket_wants_write(&self) -> bool {
         false
     }
+    /// true while the data read from the socket may be TLS 1.3 early data,
+    /// that an attacker could replay
+    fn socket_in_early_data(&self) -> bool {
+        false
+    }
+    /// TLS version and cipher suite negotiated with the client, None for plain TCP
+    fn socket_client_tls(&self) -> Option<ClientTls> {
+        None
+    }
+    /// properties of the TLS handshake with the client, None for plain TCP
+    fn socket_tls_properties(&self) -> Option<TlsProperties> {
+        None
+    }
+    /// certificate the client authenticated with, None if it sent none
+    fn socket_client_certificate(&self) -> Option<ClientCertificate> {
+        None
+    }
     fn socket_ref(&self) -> &TcpStream;
     fn socket_mut(&mut self) -> &mut TcpStream;
     fn protocol(&self) -> TransportProtocol;
@@ -228,6 +342,16 @@ impl SocketHandler for FrontRustls {
                 break;
             }
 
+            // early data is kept apart from the data received after the handshake
+            if let Some(mut early_data) = self.session.early_data() {
+                while size < buf.len() {
+                    match early_data.read(&mut buf[size..]) {
+                        Ok(0) | Err(_) => break,
+                        Ok(sz) => size += sz,
+                    }
+                }
+            }
+
             while !self.session.wants_read() {
                 match self.session.reader().read(&mut buf[size..]) {
                     Ok(0) => break,
@@ -436,6 +560,52 @@ impl SocketHandler for FrontRustls {
         self.session.wants_write()
     }
 
+    fn socket_in_early_data(&self) -> bool {
+        self.session.is_handshaking()
+    }
+
+    fn socket_client_tls(&self) -> Option<ClientTls> {
+        let version = match self.session.protocol_version()? {
+            ProtocolVersion::SSLv2 => TlsVersion::SslV2,
+            ProtocolVersion::SSLv3 => TlsVersion::SslV3,
+            ProtocolVersion::TLSv1_0 => TlsVersion::TlsV10,
+            ProtocolVersion::TLSv1_1 => TlsVersion::TlsV11,
+            ProtocolVersion::TLSv1_2 => TlsVersion::TlsV12,
+            _ => TlsVersion::TlsV13,
+        };
+        let cipher_suite = self.session.negotiated_cipher_suite()?.suite().as_str()?;
+        Some(ClientTls {
+            version,
+            cipher_suite,
+        })
+    }
+
+    fn socket_tls_properties(&self) -> Option<TlsProperties> {
+        Some(TlsProperties {
+            version: self.session.protocol_version()?,
+            cipher_suite: self.session.negotiated_cipher_suite(),
+            sni: self.session.server_name(),
+            alpn: self
+                .session
+                .alpn_protocol()
+                .and_then(|alpn| std::str::from_utf8(alpn).ok()),
+            resumed: self.session.handshake_kind() == Some(HandshakeKind::Resumed),
+            client_certificate_subject: self
+                .session
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .and_then(|certificate| parse_x509_certificate(certificate).ok())
+                .map(|(_, certificate)| certificate.subject().to_string()),
+        })
+    }
+
+    fn socket_client_certificate(&self) -> Option<ClientCertificate> {
+        self.session
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .and_then(|certificate| ClientCertificate::from_der(certificate))
+    }
+
     fn socket_ref(&self) -> &TcpStream {
         &self.stream
     }
@@ -468,10 +638,20 @@ impl SocketHandler for FrontRustls {
     }
 }
 
-pub fn server_bind(addr: SocketAddr) -> Result<TcpListener, ServerBindError> {
+/// Bind and listen on `addr`. For an IPv6 address, `ipv6_only` chooses whether
+/// IPv4 clients can connect too, the system default applies if it is `None`
+pub fn server_bind(
+    addr: SocketAddr,
+    ipv6_only: Option<bool>,
+) -> Result<TcpListener, ServerBindError> {
     let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
         .map_err(ServerBindError::SocketCreationError)?;
 
+    if let (true, Some(only_v6)) = (addr.is_ipv6(), ipv6_only) {
+        sock.set_only_v6(only_v6)
+            .map_err(ServerBindError::SetOnlyV6)?;
+    }
+
     // set so_reuseaddr, but only on unix (mirrors what libstd does)
     if cfg!(unix) {
         sock.set_reuse_address(true)
@@ -494,6 +674,258 @@ pub fn server_bind(addr: SocketAddr) -> Result<TcpListener, ServerBindError> {
     Ok(TcpListener::from_std(sock.into()))
 }
 
+/// The IPv4 address of a client of a dual-stack listener, if `address` is an
+/// IPv4-mapped IPv6 address (`::ffff:a.b.c.d`), or `address` itself
+pub fn unmap_ipv4(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => address,
+        },
+        SocketAddr::V4(_) => address,
+    }
+}
+
+/// Check that a listener could bind `address`, by binding and listening on it
+/// without SO_REUSEPORT, then closing the socket.
+///
+/// If the address is in use, the process listening on it is looked up in `/proc`,
+/// which only works for processes of the same user, or as root.
+pub fn check_listener_address(address: SocketAddr) -> Result<(), AddressCheckError> {
+    let bind_error = |error: std::io::Error| match error.raw_os_error() {
+        Some(libc::EADDRINUSE) => AddressCheckError::AddressInUse {
+            address,
+            owner: find_socket_owner(address),
+        },
+        Some(libc::EACCES) | Some(libc::EPERM) => AddressCheckError::PermissionDenied { address },
+        Some(libc::EADDRNOTAVAIL) => AddressCheckError::AddressNotAvailable { address },
+        _ => AddressCheckError::Bind { address, error },
+    };
+
+    let sock = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )
+    .map_err(bind_error)?;
+    sock.set_reuse_address(true).map_err(bind_error)?;
+    sock.bind(&address.into()).map_err(bind_error)?;
+    sock.listen(1).map_err(bind_error)?;
+    Ok(())
+}
+
+/// Find the process listening on a TCP address, using the socket tables of
+/// `/proc/net` and the file descriptors of the processes
+pub fn find_socket_owner(address: SocketAddr) -> Option<SocketOwner> {
+    let table = if address.is_ipv6() {
+        "/proc/net/tcp6"
+    } else {
+        "/proc/net/tcp"
+    };
+    let content = std::fs::read_to_string(table).ok()?;
+    let inode = content
+        .lines()
+        .skip(1)
+        .find_map(|line| listening_socket_inode(line, address))?;
+    let socket_link = format!("socket:[{inode}]");
+
+    for process in std::fs::read_dir("/proc").ok()?.flatten() {
+        let pid = match process
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let fds = match std::fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            if std::fs::read_link(fd.path())
+                .map(|link| link.as_os_str() == socket_link.as_str())
+                .unwrap_or(false)
+            {
+                let name = std::fs::read_to_string(process.path().join("comm"))
+                    .map(|name| name.trim().to_owned())
+                    .unwrap_or_default();
+                return Some(SocketOwner { pid, name });
+            }
+        }
+    }
+    None
+}
+
+/// parse a line of `/proc/net/tcp` or `/proc/net/tcp6`, and return the inode
+/// of the socket if it listens on an address conflicting with `address`
+fn listening_socket_inode(line: &str, address: SocketAddr) -> Option<u64> {
+    const TCP_LISTEN: &str = "0A";
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (local_address, state, inode) = (fields.get(1)?, fields.get(3)?, fields.get(9)?);
+    if *state != TCP_LISTEN {
+        return None;
+    }
+
+    let (ip, port) = local_address.split_once(':')?;
+    if u16::from_str_radix(port, 16).ok()? != address.port() {
+        return None;
+    }
+
+    // the kernel prints the address as 32 bits words in host byte order
+    let mut octets = Vec::with_capacity(16);
+    for i in (0..ip.len()).step_by(8) {
+        let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match octets.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(octets).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(octets).ok()?),
+        _ => return None,
+    };
+
+    // a wildcard listener conflicts with any address, and the opposite
+    if ip != address.ip() && !ip.is_unspecified() && !address.ip().is_unspecified() {
+        return None;
+    }
+    inode.parse().ok()
+}
+
+/// Start a non blocking connection to `address`, from the local IP `source`.
+/// The local port is chosen by the system.
+///
+/// A transparent socket can use a non local IP as source, like the IP of a client.
+/// This needs the CAP_NET_ADMIN capability, and policy routing so that the
+/// answers of the backend come back to Sōzu.
+pub fn connect_from(
+    address: SocketAddr,
+    source: IpAddr,
+    transparent: bool,
+) -> std::io::Result<TcpStream> {
+    let sock = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    sock.set_nonblocking(true)?;
+    if transparent {
+        set_transparent(&sock, address.is_ipv6())?;
+    }
+    sock.bind(&SocketAddr::new(source, 0).into())?;
+
+    match sock.connect(&address.into()) {
+        Ok(()) => {}
+        Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(e) => return Err(e),
+    }
+
+    Ok(TcpStream::from_std(sock.into()))
+}
+
+#[cfg(target_os = "linux")]
+fn set_transparent(sock: &Socket, ipv6: bool) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if !ipv6 {
+        return sock.set_ip_transparent(true);
+    }
+
+    let enable: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_IPV6,
+            libc::IPV6_TRANSPARENT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_sock: &Socket, _ipv6: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "transparent sockets are only available on Linux",
+    ))
+}
+
+/// Mark the IP packets sent on the socket with a DSCP value, so that the network
+/// can prioritize them. It goes in the TOS field for IPv4 and in the traffic class
+/// for IPv6, the two ECN bits are left to the kernel.
+pub fn set_dscp(socket: &TcpStream, dscp: u8) -> std::io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    let socket = socket2::SockRef::from(socket);
+    match socket.local_addr()?.as_socket() {
+        Some(SocketAddr::V6(_)) => socket.set_tclass_v6(tos),
+        _ => socket.set_tos(tos),
+    }
+}
+
+/// Whether an error means that the process, or the whole system, ran out of file descriptors
+pub fn is_fd_exhaustion(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EMFILE) | Some(libc::ENFILE)
+    )
+}
+
+/// A few file descriptors opened in advance. They are closed when the process
+/// runs out of file descriptors, so that the command channel and the metrics
+/// keep working, and opened again once descriptors are available
+#[derive(Debug)]
+pub struct FdReserve {
+    size: usize,
+    files: Vec<std::fs::File>,
+}
+
+impl FdReserve {
+    pub fn new(size: usize) -> Self {
+        let mut reserve = FdReserve {
+            size,
+            files: Vec::with_capacity(size),
+        };
+        reserve.refill();
+        reserve
+    }
+
+    /// open file descriptors until the reserve is full, returns false if it could not be filled
+    pub fn refill(&mut self) -> bool {
+        while self.files.len() < self.size {
+            match std::fs::File::open("/dev/null") {
+                Ok(file) => self.files.push(file),
+                Err(e) => {
+                    if !is_fd_exhaustion(&e) {
+                        error!("could not reserve a file descriptor: {}", e);
+                    }
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// close the reserved file descriptors, returns how many were released
+    pub fn release(&mut self) -> usize {
+        let released = self.files.len();
+        self.files.clear();
+        released
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
 /// Socket statistics
 pub mod stats {
     use std::{os::fd::AsRawFd, time::Duration};
@@ -641,3 +1073,110 @@ pub mod stats {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_listener_address_in_use() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        match check_listener_address(address) {
+            Err(AddressCheckError::AddressInUse { owner, .. }) => {
+                if let Some(owner) = owner {
+                    assert_eq!(owner.pid, std::process::id());
+                }
+            }
+            other => panic!("expected the address to be in use, got {other:?}"),
+        }
+
+        drop(listener);
+        assert!(check_listener_address(address).is_ok());
+    }
+
+    #[test]
+    fn parse_proc_net_tcp_line() {
+        let line = "   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 123456 1 0000000000000000 100 0 0 10 0";
+
+        assert_eq!(
+            listening_socket_inode(line, "127.0.0.1:8080".parse().unwrap()),
+            Some(123456)
+        );
+        assert_eq!(
+            listening_socket_inode(line, "0.0.0.0:8080".parse().unwrap()),
+            Some(123456)
+        );
+        assert_eq!(
+            listening_socket_inode(line, "127.0.0.2:8080".parse().unwrap()),
+            None
+        );
+        assert_eq!(
+            listening_socket_inode(line, "127.0.0.1:8081".parse().unwrap()),
+            None
+        );
+    }
+    #[test]
+    fn set_dscp_in_the_tos_field() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::from_std(
+            std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+        );
+
+        set_dscp(&stream, 46).unwrap();
+        assert_eq!(socket2::SockRef::from(&stream).tos().unwrap(), 46 << 2);
+    }
+
+    #[test]
+    fn bind_ipv6_only() {
+        let listener = server_bind("[::]:0".parse().unwrap(), Some(true)).unwrap();
+        assert!(socket2::SockRef::from(&listener).only_v6().unwrap());
+
+        let listener = server_bind("[::]:0".parse().unwrap(), Some(false)).unwrap();
+        assert!(!socket2::SockRef::from(&listener).only_v6().unwrap());
+    }
+
+    #[test]
+    fn unmap_ipv4_mapped_addresses() {
+        assert_eq!(
+            unmap_ipv4("[::ffff:192.0.2.1]:1234".parse().unwrap()),
+            "192.0.2.1:1234".parse().unwrap()
+        );
+        assert_eq!(
+            unmap_ipv4("[2001:db8::1]:1234".parse().unwrap()),
+            "[2001:db8::1]:1234".parse().unwrap()
+        );
+        assert_eq!(
+            unmap_ipv4("192.0.2.1:1234".parse().unwrap()),
+            "192.0.2.1:1234".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn release_and_refill_the_fd_reserve() {
+        let mut reserve = FdReserve::new(3);
+        assert_eq!(reserve.len(), 3);
+
+        assert_eq!(reserve.release(), 3);
+        assert!(reserve.is_empty());
+        assert_eq!(reserve.release(), 0);
+
+        assert!(reserve.refill());
+        assert_eq!(reserve.len(), 3);
+    }
+
+    #[test]
+    fn detect_fd_exhaustion() {
+        assert!(is_fd_exhaustion(&std::io::Error::from_raw_os_error(
+            libc::EMFILE
+        )));
+        assert!(is_fd_exhaustion(&std::io::Error::from_raw_os_error(
+            libc::ENFILE
+        )));
+        assert!(!is_fd_exhaustion(&std::io::Error::from_raw_os_error(
+            libc::ECONNREFUSED
+        )));
+        assert!(!is_fd_exhaustion(&ErrorKind::WouldBlock.into()));
+    }
+}
@@ -33,7 +33,8 @@ use crate::{
     },
     retry::RetryPolicy,
     server::{push_event, ListenToken, SessionManager, CONN_RETRIES, TIMER},
-    socket::{server_bind, stats::socket_rtt},
+    sni::{parse_server_name, ServerName, MAX_RECORD_SIZE},
+    socket::{is_fd_exhaustion, server_bind, set_dscp, stats::socket_rtt},
     sozu_command::{
         proto::command::{
             Event, EventKind, ProxyProtocolConfig, RequestTcpFrontend, TcpListenerConfig,
@@ -96,6 +97,10 @@ pub struct TcpSession {
     metrics: SessionMetrics,
     proxy: Rc<RefCell<TcpProxy>>,
     request_id: Ulid,
+    /// the cluster is picked from the ClientHello instead of the listener
+    routes_by_sni: bool,
+    /// server name of the SNI route taken by the session
+    server_name: Option<String>,
     state: TcpStateMachine,
 }
 
@@ -115,11 +120,15 @@ impl TcpSession {
         socket: MioTcpStream,
         wait_time: Duration,
     ) -> TcpSession {
-        let frontend_address = socket.peer_addr().ok();
+        let frontend_address = socket
+            .peer_addr()
+            .ok()
+            .map(|address| listener.borrow().client_address(address));
         let mut frontend_buffer_session = None;
         let mut backend_buffer_session = None;
 
         let request_id = Ulid::generate();
+        let routes_by_sni = !listener.borrow().sni_clusters.is_empty();
 
         let container_frontend_timeout =
             TimeoutContainer::new(configured_frontend_timeout, frontend_token);
@@ -152,12 +161,9 @@ impl TcpSession {
                 frontend_buffer_session = Some(frontend_buffer);
                 backend_buffer_session = Some(backend_buffer);
                 gauge_add!("protocol.proxy.send", 1);
-                TcpStateMachine::SendProxyProtocol(SendProxyProtocol::new(
-                    socket,
-                    frontend_token,
-                    request_id,
-                    None,
-                ))
+                let mut send = SendProxyProtocol::new(socket, frontend_token, request_id, None);
+                send.normalize_ipv4_mapped = listener.borrow().normalizes_ipv4_mapped();
+                TcpStateMachine::SendProxyProtocol(send)
             }
             None => {
                 gauge_add!("protocol.tcp", 1);
@@ -205,6 +211,8 @@ impl TcpSession {
             metrics,
             proxy,
             request_id,
+            routes_by_sni,
+            server_name: None,
             state,
         }
     }
@@ -220,7 +228,8 @@ impl TcpSession {
             backend_address: None,
             protocol: "TCP",
             endpoint: EndpointRecord::Tcp,
-            tags: listener.get_tags(&listener.get_addr().to_string()),
+            tags: listener.get_tags(&self.tags_key()),
+            tls: None,
             client_rtt: socket_rtt(self.state.front_socket()),
             server_rtt: None,
             user_agent: None,
@@ -231,6 +240,14 @@ impl TcpSession {
         );
     }
 
+    /// tags of the SNI route taken by the session, or else of the listener
+    fn tags_key(&self) -> String {
+        match &self.server_name {
+            Some(server_name) => server_name.to_owned(),
+            None => self.listener.borrow().get_addr().to_string(),
+        }
+    }
+
     fn front_hup(&mut self) -> SessionResult {
         match &mut self.state {
             TcpStateMachine::Pipe(pipe) => pipe.frontend_hup(&mut self.metrics),
@@ -498,16 +515,32 @@ impl TcpSession {
                         backend_id: Some(backend.backend_id.to_owned()),
                         address: Some(backend.address.into()),
                         cluster_id: None,
+                        alert: None,
+                        value: None,
                     });
+                    backend.start_slow_start();
                 }
 
                 if let BackendConnectionStatus::Connecting(start) = last {
-                    backend.set_connection_time(Instant::now() - start);
+                    let connection_time = Instant::now() - start;
+                    backend.set_connection_time(connection_time);
+                    time!(
+                        "backend_connection_time",
+                        self.cluster_id.as_deref(),
+                        self.metrics.backend_id.as_deref(),
+                        connection_time.as_millis()
+                    );
                 }
 
                 //successful connection, rest failure counter
                 backend.failures = 0;
+                backend.connect_successes += 1;
                 backend.retry_policy.succeed();
+                incr!(
+                    "backend.connections.success",
+                    self.cluster_id.as_deref(),
+                    self.metrics.backend_id.as_deref()
+                );
             }
         }
     }
@@ -524,6 +557,7 @@ impl TcpSession {
         if let Some(backend) = self.backend.as_ref() {
             let backend = &mut *backend.borrow_mut();
             backend.failures += 1;
+            backend.connect_failures += 1;
 
             let already_unavailable = backend.retry_policy.is_down();
             backend.retry_policy.fail();
@@ -548,6 +582,8 @@ impl TcpSession {
                     backend_id: Some(backend.backend_id.to_owned()),
                     address: Some(backend.address.into()),
                     cluster_id: None,
+                    alert: None,
+                    value: None,
                 });
             }
         }
@@ -611,6 +647,12 @@ impl TcpSession {
                 self.set_back_connected(BackendConnectionStatus::Connected);
             }
         } else if back_connected == BackendConnectionStatus::NotConnected {
+            if self.routes_by_sni && self.cluster_id.is_none() {
+                if let Some(session_result) = self.route_by_sni() {
+                    return session_result;
+                }
+            }
+
             let connection_result = self.connect_to_backend(session.clone());
             if let Err(err) = &connection_result {
                 error!(
@@ -755,6 +797,65 @@ impl TcpSession {
         SessionResult::Continue
     }
 
+    /// pick the cluster from the server name of the ClientHello, peeked at without
+    /// consuming it, or else from the frontend of the listener without SNI.
+    /// Returns a result if the session must wait for the ClientHello or be closed
+    fn route_by_sni(&mut self) -> Option<SessionResult> {
+        let mut buffer = vec![0; MAX_RECORD_SIZE];
+        let server_name = match self.state.front_socket().peek(&mut buffer) {
+            Ok(0) => return Some(SessionResult::Close),
+            Ok(size) => parse_server_name(&buffer[..size]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => ServerName::Incomplete,
+            Err(e) => {
+                error!(
+                    "{} Error reading the ClientHello: {:?}",
+                    log_context!(self),
+                    e
+                );
+                return Some(SessionResult::Close);
+            }
+        };
+
+        if server_name == ServerName::Incomplete {
+            if self.front_readiness().event.is_hup() {
+                return Some(SessionResult::Close);
+            }
+            return Some(SessionResult::Continue);
+        }
+
+        let (cluster_id, route) = {
+            let listener = self.listener.borrow();
+            match &server_name {
+                ServerName::Found(name) if listener.sni_clusters.contains_key(name) => (
+                    listener.sni_clusters.get(name).cloned(),
+                    Some(name.to_owned()),
+                ),
+                _ => (listener.cluster_id.clone(), None),
+            }
+        };
+
+        let cluster_id = match cluster_id {
+            Some(cluster_id) => cluster_id,
+            None => {
+                incr!("tcp.sni.no_route");
+                warn!(
+                    "{} No TCP frontend for the server name of the connection: {:?}",
+                    log_context!(self),
+                    server_name
+                );
+                return Some(SessionResult::Close);
+            }
+        };
+
+        if let TcpStateMachine::Pipe(pipe) = &mut self.state {
+            pipe.set_cluster_id(Some(cluster_id.clone()));
+            pipe.set_tags_key(route.clone());
+        }
+        self.cluster_id = Some(cluster_id);
+        self.server_name = route;
+        None
+    }
+
     /// TCP session closes its backend on its own, without defering this task to the state
     fn close_backend(&mut self) {
         if let (Some(token), Some(fd)) = (
@@ -811,12 +912,12 @@ impl TcpSession {
         &mut self,
         session_rc: Rc<RefCell<dyn ProxySession>>,
     ) -> Result<BackendConnectAction, BackendConnectionError> {
-        let cluster_id = self
-            .listener
-            .borrow()
-            .cluster_id
-            .clone()
-            .ok_or(BackendConnectionError::NotFound(ObjectKind::TcpCluster))?;
+        let cluster_id = if self.routes_by_sni {
+            self.cluster_id.clone()
+        } else {
+            self.listener.borrow().cluster_id.clone()
+        }
+        .ok_or(BackendConnectionError::NotFound(ObjectKind::TcpCluster))?;
 
         self.cluster_id = Some(cluster_id.clone());
 
@@ -840,7 +941,7 @@ impl TcpSession {
             .borrow()
             .backends
             .borrow_mut()
-            .backend_from_cluster_id(&cluster_id)
+            .backend_from_cluster_id(&cluster_id, self.frontend_address)
             .map_err(BackendConnectionError::Backend)?;
 
         /*
@@ -863,6 +964,22 @@ impl TcpSession {
                 e
             );
         }
+        let client_dscp = self
+            .proxy
+            .borrow()
+            .backends
+            .borrow()
+            .client_dscp(&cluster_id);
+        if let Some(dscp) = client_dscp {
+            if let Err(e) = set_dscp(self.state.front_socket(), dscp) {
+                error!(
+                    "{} Error setting DSCP {} on front socket: {:?}",
+                    log_context!(self),
+                    dscp,
+                    e
+                );
+            }
+        }
         self.backend_connected = BackendConnectionStatus::Connecting(Instant::now());
 
         let back_token = {
@@ -899,6 +1016,7 @@ impl TcpSession {
         self.metrics.backend_id = Some(backend.borrow().backend_id.clone());
         self.metrics.backend_start();
         self.set_backend_id(backend.borrow().backend_id.clone());
+        self.backend = Some(backend);
 
         Ok(BackendConnectAction::New)
     }
@@ -1089,6 +1207,8 @@ pub struct TcpListener {
     active: SessionIsToBeClosed,
     address: SocketAddr,
     cluster_id: Option<String>,
+    /// clusters of the frontends routing by SNI, by server name
+    sni_clusters: HashMap<String, ClusterId>,
     config: TcpListenerConfig,
     listener: Option<MioTcpListener>,
     tags: BTreeMap<String, CachedTags>,
@@ -1110,12 +1230,17 @@ impl ListenerHandler for TcpListener {
             None => self.tags.remove(&key),
         };
     }
+
+    fn normalizes_ipv4_mapped(&self) -> bool {
+        self.config.normalize_ipv4_mapped
+    }
 }
 
 impl TcpListener {
     fn new(config: TcpListenerConfig, token: Token) -> Result<TcpListener, ListenerError> {
         Ok(TcpListener {
             cluster_id: None,
+            sni_clusters: HashMap::new(),
             listener: None,
             token,
             address: config.address.clone().into(),
@@ -1138,7 +1263,8 @@ impl TcpListener {
             Some(listener) => listener,
             None => {
                 let address = self.config.address.clone().into();
-                server_bind(address).map_err(|e| ProxyError::BindToSocket(address, e))?
+                server_bind(address, self.config.ipv6_only)
+                    .map_err(|e| ProxyError::BindToSocket(address, e))?
             }
         };
 
@@ -1256,6 +1382,20 @@ impl TcpProxy {
             .collect()
     }
 
+    /// file descriptors of the listeners, which stay registered in this worker
+    pub fn listener_fds(&self) -> Vec<(SocketAddr, i32)> {
+        self.listeners
+            .values()
+            .filter_map(|listener| {
+                let owned = listener.borrow();
+                owned
+                    .listener
+                    .as_ref()
+                    .map(|listener| (owned.address, listener.as_raw_fd()))
+            })
+            .collect()
+    }
+
     pub fn give_back_listener(
         &mut self,
         address: SocketAddr,
@@ -1288,8 +1428,17 @@ impl TcpProxy {
 
         self.fronts
             .insert(front.cluster_id.to_string(), listener.token);
-        listener.set_tags(address.to_string(), Some(front.tags));
-        listener.cluster_id = Some(front.cluster_id);
+        match front.sni {
+            Some(sni) => {
+                let sni = sni.to_lowercase();
+                listener.set_tags(sni.clone(), Some(front.tags));
+                listener.sni_clusters.insert(sni, front.cluster_id);
+            }
+            None => {
+                listener.set_tags(address.to_string(), Some(front.tags));
+                listener.cluster_id = Some(front.cluster_id);
+            }
+        }
         Ok(())
     }
 
@@ -1305,8 +1454,18 @@ impl TcpProxy {
             None => return Err(ProxyError::NoListenerFound(address)),
         };
 
-        listener.set_tags(address.to_string(), None);
-        if let Some(cluster_id) = listener.cluster_id.take() {
+        let cluster_id = match front.sni {
+            Some(sni) => {
+                let sni = sni.to_lowercase();
+                listener.set_tags(sni.clone(), None);
+                listener.sni_clusters.remove(&sni)
+            }
+            None => {
+                listener.set_tags(address.to_string(), None);
+                listener.cluster_id.take()
+            }
+        };
+        if let Some(cluster_id) = cluster_id {
             self.fronts.remove(&cluster_id);
         }
         Ok(())
@@ -1322,14 +1481,16 @@ impl ProxyConfiguration for TcpProxy {
         match request_type {
             RequestType::AddTcpFrontend(front) => {
                 if let Err(err) = self.add_tcp_front(front) {
-                    return WorkerResponse::error(message.id, err);
+                    return WorkerResponse::error(message.id, &err)
+                        .with_error(err.response_error());
                 }
 
                 WorkerResponse::ok(message.id)
             }
             RequestType::RemoveTcpFrontend(front) => {
                 if let Err(err) = self.remove_tcp_front(front) {
-                    return WorkerResponse::error(message.id, err);
+                    return WorkerResponse::error(message.id, &err)
+                        .with_error(err.response_error());
                 }
 
                 WorkerResponse::ok(message.id)
@@ -1403,6 +1564,7 @@ impl ProxyConfiguration for TcpProxy {
                     .map(|(frontend_sock, _)| frontend_sock)
                     .map_err(|e| match e.kind() {
                         ErrorKind::WouldBlock => AcceptError::WouldBlock,
+                        _ if is_fd_exhaustion(&e) => AcceptError::TooManyFiles,
                         _ => {
                             error!("accept() IO error: {:?}", e);
                             AcceptError::IoError
@@ -1447,7 +1609,7 @@ impl ProxyConfiguration for TcpProxy {
             }
         };
 
-        if owned.cluster_id.is_none() {
+        if owned.cluster_id.is_none() && owned.sni_clusters.is_empty() {
             error!(
                 "listener at address {:?} has no linked cluster",
                 owned.address
@@ -1455,10 +1617,17 @@ impl ProxyConfiguration for TcpProxy {
             return Err(AcceptError::IoError);
         }
 
-        let proxy_protocol = self
-            .configs
-            .get(owned.cluster_id.as_ref().unwrap())
-            .and_then(|c| c.proxy_protocol);
+        // the cluster of a connection routed by SNI is only known once the
+        // ClientHello is read, so the connection is relayed without proxy protocol
+        let (cluster_id, proxy_protocol) = if owned.sni_clusters.is_empty() {
+            let proxy_protocol = self
+                .configs
+                .get(owned.cluster_id.as_ref().unwrap())
+                .and_then(|c| c.proxy_protocol);
+            (owned.cluster_id.clone(), proxy_protocol)
+        } else {
+            (None, None)
+        };
 
         if let Err(e) = frontend_sock.set_nodelay(true) {
             error!(
@@ -1486,7 +1655,7 @@ impl ProxyConfiguration for TcpProxy {
         let session = TcpSession::new(
             back_buffer,
             None,
-            owned.cluster_id.clone(),
+            cluster_id,
             Duration::from_secs(owned.config.back_timeout as u64),
             Duration::from_secs(owned.config.front_timeout as u64),
             front_buffer,
@@ -1738,18 +1907,22 @@ mod tests {
                 load_balancing_parameters: Some(LoadBalancingParams::default()),
                 sticky_id: None,
                 backup: None,
+                expires_at: None,
+                draining: None,
             };
 
             command
                 .write_message(&WorkerRequest {
                     id: String::from("ID_YOLO1"),
                     content: RequestType::AddTcpFrontend(front).into(),
+                    sequence: None,
                 })
                 .unwrap();
             command
                 .write_message(&WorkerRequest {
                     id: String::from("ID_YOLO2"),
                     content: RequestType::AddBackend(backend.to_add_backend()).into(),
+                    sequence: None,
                 })
                 .unwrap();
         }
@@ -1766,17 +1939,21 @@ mod tests {
                 load_balancing_parameters: Some(LoadBalancingParams::default()),
                 sticky_id: None,
                 backup: None,
+                expires_at: None,
+                draining: None,
             };
             command
                 .write_message(&WorkerRequest {
                     id: String::from("ID_YOLO3"),
                     content: RequestType::AddTcpFrontend(front).into(),
+                    sequence: None,
                 })
                 .unwrap();
             command
                 .write_message(&WorkerRequest {
                     id: String::from("ID_YOLO4"),
                     content: RequestType::AddBackend(backend.to_add_backend()).into(),
+                    sequence: None,
                 })
                 .unwrap();
         }
@@ -0,0 +1,88 @@
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::read_http_message;
+
+/// An HTTP backend running on detached threads, that answers every request
+/// with the same raw response and keeps the requests it received
+pub struct MockBackend {
+    pub address: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockBackend {
+    /// listen on a free local port and answer with `response`, a raw HTTP response
+    pub fn start<S: Into<String>>(response: S) -> io::Result<Self> {
+        Self::start_with_delay(response, Duration::ZERO)
+    }
+
+    /// wait for `delay` before answering, to trigger the timeouts of the proxy
+    pub fn start_with_delay<S: Into<String>>(response: S, delay: Duration) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let response = Arc::new(response.into());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread_requests = requests.clone();
+        let thread_stopped = stopped.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let response = response.clone();
+                let requests = thread_requests.clone();
+                thread::spawn(move || serve(stream, &response, delay, &requests));
+            }
+        });
+
+        Ok(Self {
+            address,
+            requests,
+            stopped,
+        })
+    }
+
+    /// the raw requests received so far, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn requests_received(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake the accepting thread up so that it sees the flag
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+/// answer the requests of a connection until the peer closes it
+fn serve(mut stream: TcpStream, response: &str, delay: Duration, requests: &Mutex<Vec<String>>) {
+    while let Ok(Some(request)) = read_http_message(&mut stream, false) {
+        requests.lock().unwrap().push(request);
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        if stream.write_all(response.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Utilities to test Sōzu, and programs embedding it.
+//!
+//! [`TestProxy`] runs a worker on its own thread with a given state, and drives it
+//! through its channel like the main process would: requests, metrics and events.
+//! [`MockBackend`] answers the proxied requests on real sockets, and the functions
+//! of this module send raw HTTP requests to the listeners of the proxy.
+//! TLS tests add an HTTPS listener and certificates to the state and bring their own client.
+
+mod backend;
+mod proxy;
+
+pub use std::{cell::RefCell, os::fd::IntoRawFd, rc::Rc};
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+use sozu_command::{
+    config::ListenerBuilder,
+    proto::command::{
+        request::RequestType, ActivateListener, AddBackend, Cluster, ListenerType, PathRule,
+        RequestHttpFrontend, RulePosition,
+    },
+    state::ConfigState,
+};
+
+pub use anyhow::Context;
+pub use mio::{net::UnixStream, Poll, Registry, Token};
+pub use slab::Slab;
+pub use sozu_command::{
+    proto::command::{HttpListenerConfig, HttpsListenerConfig, ServerConfig, TcpListenerConfig},
+    scm_socket::{Listeners, ScmSocket},
+};
+
+pub use crate::{
+    backends::BackendMap,
+    http::HttpProxy,
+    https::HttpsProxy,
+    pool::Pool,
+    server::Server,
+    server::{ListenSession, ProxyChannel, SessionManager},
+    tcp::TcpProxy,
+    Protocol, ProxySession,
+};
+
+pub use self::{backend::MockBackend, proxy::TestProxy};
+
+/// Everything needed to create a Server
+pub struct ServerParts {
+    pub event_loop: Poll,
+    pub registry: Registry,
+    pub sessions: Rc<RefCell<SessionManager>>,
+    pub pool: Rc<RefCell<Pool>>,
+    pub backends: Rc<RefCell<BackendMap>>,
+    pub client_scm_socket: ScmSocket,
+    pub server_scm_socket: ScmSocket,
+    pub server_config: ServerConfig,
+}
+
+/// Setup a standalone server, for testing purposes
+pub fn prebuild_server(
+    max_buffers: usize,
+    buffer_size: usize,
+    send_scm: bool,
+) -> anyhow::Result<ServerParts> {
+    let event_loop = Poll::new().with_context(|| "Failed at creating event loop")?;
+    let backends = Rc::new(RefCell::new(BackendMap::new()));
+    let server_config = ServerConfig {
+        max_connections: max_buffers as u64,
+        ..Default::default()
+    };
+
+    let pool = Rc::new(RefCell::new(Pool::with_capacity(
+        1,
+        max_buffers,
+        buffer_size,
+    )));
+
+    let mut sessions: Slab<Rc<RefCell<dyn ProxySession>>> = Slab::with_capacity(max_buffers);
+    {
+        let entry = sessions.vacant_entry();
+        info!("taking token {:?} for channel", entry.key());
+        entry.insert(Rc::new(RefCell::new(ListenSession {
+            protocol: Protocol::Channel,
+        })));
+    }
+    {
+        let entry = sessions.vacant_entry();
+        info!("taking token {:?} for timer", entry.key());
+        entry.insert(Rc::new(RefCell::new(ListenSession {
+            protocol: Protocol::Timer,
+        })));
+    }
+    {
+        let entry = sessions.vacant_entry();
+        info!("taking token {:?} for metrics", entry.key());
+        entry.insert(Rc::new(RefCell::new(ListenSession {
+            protocol: Protocol::Metrics,
+        })));
+    }
+    let sessions = SessionManager::new(sessions, max_buffers);
+
+    let registry = event_loop
+        .registry()
+        .try_clone()
+        .with_context(|| "Failed at creating a registry")?;
+
+    let (scm_server, scm_client) =
+        UnixStream::pair().with_context(|| "Failed at creating scm unix stream")?;
+    let client_scm_socket = ScmSocket::new(scm_client.into_raw_fd())
+        .with_context(|| "Failed at creating the scm client socket")?;
+    let server_scm_socket = ScmSocket::new(scm_server.into_raw_fd())
+        .with_context(|| "Failed at creating the scm server socket")?;
+    if send_scm {
+        client_scm_socket
+            .send_listeners(&Listeners::default())
+            .with_context(|| "Failed at sending empty listeners")?;
+    }
+
+    Ok(ServerParts {
+        event_loop,
+        registry,
+        sessions,
+        pool,
+        backends,
+        client_scm_socket,
+        server_scm_socket,
+        server_config,
+    })
+}
+
+/// a state with an active HTTP listener on `front`, routing `hostname` to `cluster`,
+/// and the backends of the cluster, named `<cluster id>-<index>`
+pub fn http_state(
+    front: SocketAddr,
+    cluster: Cluster,
+    hostname: &str,
+    backends: &[SocketAddr],
+) -> ConfigState {
+    let cluster_id = cluster.cluster_id.clone();
+    let mut requests = vec![
+        RequestType::AddHttpListener(
+            ListenerBuilder::new_http(front.into())
+                .to_http(None)
+                .expect("could not build the HTTP listener"),
+        ),
+        RequestType::ActivateListener(ActivateListener {
+            address: front.into(),
+            proxy: ListenerType::Http.into(),
+            from_scm: false,
+        }),
+        RequestType::AddCluster(cluster),
+        RequestType::AddHttpFrontend(RequestHttpFrontend {
+            cluster_id: Some(cluster_id.clone()),
+            address: front.into(),
+            hostname: hostname.to_owned(),
+            path: PathRule::prefix(String::from("/")),
+            position: RulePosition::Tree.into(),
+            ..Default::default()
+        }),
+    ];
+    for (index, address) in backends.iter().enumerate() {
+        requests.push(RequestType::AddBackend(AddBackend {
+            cluster_id: cluster_id.clone(),
+            backend_id: format!("{cluster_id}-{index}"),
+            address: (*address).into(),
+            ..Default::default()
+        }));
+    }
+
+    let mut state = ConfigState::new();
+    for request in requests {
+        state
+            .dispatch(&request.into())
+            .expect("could not build the state");
+    }
+    state
+}
+
+/// a local address on a port that was free when this was called
+pub fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("could not find a free port")
+}
+
+/// a raw HTTP/1.1 request
+pub fn http_request(method: &str, host: &str, path: &str, body: &str) -> String {
+    format!(
+        "{method} {path} HTTP/1.1\r\nHost: {host}\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// a raw HTTP/1.1 response with a 200 status
+pub fn http_ok_response(body: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// send a raw request on a new connection and read the response
+pub fn send_request(address: SocketAddr, request: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.write_all(request.as_bytes())?;
+    read_http_message(&mut stream, true)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the connection was closed without a response",
+        )
+    })
+}
+
+/// the status code of a raw response
+pub fn status_code(response: &str) -> Option<u16> {
+    response.split(' ').nth(1)?.parse().ok()
+}
+
+/// read a message whose length is given by its Content-Length header. Without it,
+/// a request has no body, and a response ends when the peer closes the connection.
+/// Returns None if the connection was closed before the message started
+fn read_http_message(stream: &mut TcpStream, is_response: bool) -> io::Result<Option<String>> {
+    let mut message = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            if message.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(String::from_utf8_lossy(&message).into_owned()));
+        }
+        message.extend_from_slice(&buffer[..size]);
+
+        let Some(header_end) = message.windows(4).position(|window| window == b"\r\n\r\n") else {
+            continue;
+        };
+        let header = String::from_utf8_lossy(&message[..header_end]);
+        let content_length = header.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.eq_ignore_ascii_case("content-length") {
+                value.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        });
+        match content_length {
+            Some(length) if message.len() >= header_end + 4 + length => {}
+            None if !is_response => {}
+            _ => continue,
+        }
+        return Ok(Some(String::from_utf8_lossy(&message).into_owned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command::proto::command::{
+        request::RequestType, Cluster, EventKind, RemoveBackend, ResponseStatus,
+    };
+
+    #[test]
+    fn route_requests_through_an_in_process_proxy() {
+        let backend = MockBackend::start(http_ok_response("pong")).unwrap();
+        let front = free_address();
+
+        let state = http_state(
+            front,
+            Cluster {
+                cluster_id: String::from("cluster_1"),
+                ..Default::default()
+            },
+            "example.com",
+            &[backend.address],
+        );
+
+        let mut proxy = TestProxy::start("TEST", &state).unwrap();
+
+        let response =
+            send_request(front, &http_request("GET", "example.com", "/ping", "")).unwrap();
+        assert_eq!(status_code(&response), Some(200));
+        assert!(response.ends_with("pong"));
+        assert!(backend.requests()[0].starts_with("GET /ping HTTP/1.1"));
+
+        let response = send_request(front, &http_request("GET", "unknown.com", "/", "")).unwrap();
+        assert_eq!(status_code(&response), Some(404));
+        assert_eq!(backend.requests_received(), 1);
+
+        assert_eq!(
+            proxy
+                .backend_count("cluster_1", "cluster_1-0", "http.status.2xx")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            proxy
+                .cluster_count("cluster_1", "http.response_body_bytes")
+                .unwrap(),
+            4
+        );
+        assert_eq!(proxy.proxy_count("http.requests").unwrap(), 2);
+
+        let response = proxy
+            .send(RequestType::RemoveBackend(RemoveBackend {
+                cluster_id: String::from("cluster_1"),
+                backend_id: String::from("cluster_1-0"),
+                address: backend.address.into(),
+            }))
+            .unwrap();
+        assert_eq!(response.status, ResponseStatus::Ok as i32);
+        let event = proxy
+            .wait_for_event(
+                EventKind::RemovedBackendHasNoConnections,
+                Duration::from_secs(1),
+            )
+            .unwrap();
+        assert_eq!(event.backend_id.as_deref(), Some("cluster_1-0"));
+        proxy.stop().unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    os::{
+        fd::{FromRawFd, IntoRawFd},
+        unix::net::UnixStream,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use sozu_command::{
+    channel::{Channel, ChannelError},
+    config::{ConfigBuilder, FileConfig},
+    logging::setup_default_logging,
+    proto::command::{
+        filtered_metrics::Inner, request::RequestType, response_content::ContentType, Event,
+        EventKind, FilteredMetrics, HardStop, QueryBuildInfo, QueryMetricsOptions, Request,
+        ResponseStatus, ServerConfig, WorkerMetrics, WorkerRequest, WorkerResponse,
+    },
+    scm_socket::{Listeners, ScmSocket},
+    state::ConfigState,
+};
+
+use crate::server::Server;
+
+/// how long to wait for the answer of the worker to a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A worker running on its own thread with a given state, driven through its
+/// channel like the main process would
+pub struct TestProxy {
+    pub name: String,
+    channel: Channel<WorkerRequest, WorkerResponse>,
+    scm_main_to_worker: ScmSocket,
+    scm_worker_to_main: ScmSocket,
+    server_job: Option<JoinHandle<()>>,
+    /// events received while waiting for responses, not yet returned by `events`
+    events: Vec<Event>,
+    request_counter: usize,
+}
+
+impl TestProxy {
+    /// start a worker with the default configuration. The listeners of the state
+    /// are bound by the worker, those marked as active start accepting at once
+    pub fn start<S: Into<String>>(name: S, state: &ConfigState) -> anyhow::Result<Self> {
+        let config = ConfigBuilder::new(FileConfig::default(), "")
+            .into_config()
+            .with_context(|| "Could not create the default configuration")?;
+        Self::start_with_config(name, ServerConfig::from(&config), state)
+    }
+
+    pub fn start_with_config<S: Into<String>>(
+        name: S,
+        config: ServerConfig,
+        state: &ConfigState,
+    ) -> anyhow::Result<Self> {
+        let name = name.into();
+        let (scm_main_to_worker, scm_worker_to_main) =
+            UnixStream::pair().with_context(|| "Could not create the scm socket pair")?;
+        let scm_main_to_worker = ScmSocket::new(scm_main_to_worker.into_raw_fd())
+            .with_context(|| "Could not create the main scm socket")?;
+        let scm_worker_to_main = ScmSocket::new(scm_worker_to_main.into_raw_fd())
+            .with_context(|| "Could not create the worker scm socket")?;
+        scm_main_to_worker
+            .send_listeners(&Listeners::default())
+            .with_context(|| "Could not send the listeners")?;
+
+        let (channel, worker_channel) =
+            Channel::generate(config.command_buffer_size, config.max_command_buffer_size)
+                .with_context(|| "Could not create the command channel")?;
+
+        let initial_state = state.produce_initial_state();
+        let thread_name = name.clone();
+        let thread_scm_socket = scm_worker_to_main.clone();
+        let server_job = thread::spawn(move || {
+            setup_default_logging(false, "error", &thread_name);
+            let mut server = Server::try_new_from_config(
+                worker_channel,
+                thread_scm_socket,
+                config,
+                initial_state,
+                false,
+            )
+            .expect("could not create the worker");
+            server.run();
+        });
+
+        let mut proxy = Self {
+            name,
+            channel,
+            scm_main_to_worker,
+            scm_worker_to_main,
+            server_job: Some(server_job),
+            events: Vec::new(),
+            request_counter: 0,
+        };
+        // the worker answers once it has applied the initial state and bound its listeners
+        proxy.send(RequestType::QueryBuildInfo(QueryBuildInfo {}))?;
+        Ok(proxy)
+    }
+
+    /// send a request to the worker and wait for its final response.
+    /// Events received meanwhile are kept for `events`
+    pub fn send<R: Into<Request>>(&mut self, request: R) -> anyhow::Result<WorkerResponse> {
+        let id = format!("{}-{}", self.name, self.request_counter);
+        self.request_counter += 1;
+        self.channel
+            .write_message(&WorkerRequest::new(id.clone(), request.into()))
+            .with_context(|| "Could not send the request to the worker")?;
+
+        let start = Instant::now();
+        loop {
+            let remaining = RESPONSE_TIMEOUT.saturating_sub(start.elapsed());
+            let response = self
+                .read_response(remaining)
+                .with_context(|| format!("No response from the worker to request {id}"))?;
+            if response.id == id && response.status != ResponseStatus::Processing as i32 {
+                return Ok(response);
+            }
+        }
+    }
+
+    pub fn query_metrics(&mut self, options: QueryMetricsOptions) -> anyhow::Result<WorkerMetrics> {
+        let response = self.send(RequestType::QueryMetrics(options))?;
+        match response.content.and_then(|content| content.content_type) {
+            Some(ContentType::WorkerMetrics(metrics)) => Ok(metrics),
+            _ => bail!(
+                "The worker did not answer with metrics: {}",
+                response.message
+            ),
+        }
+    }
+
+    /// value of a counter of a cluster, 0 if it was never incremented
+    pub fn cluster_count(&mut self, cluster_id: &str, metric_name: &str) -> anyhow::Result<i64> {
+        let metrics = self.query_metrics(QueryMetricsOptions {
+            cluster_ids: vec![cluster_id.to_owned()],
+            metric_names: vec![metric_name.to_owned()],
+            ..Default::default()
+        })?;
+        Ok(count(
+            metrics
+                .clusters
+                .get(cluster_id)
+                .and_then(|cluster| cluster.cluster.get(metric_name)),
+        ))
+    }
+
+    /// value of a counter or a gauge of a backend, 0 if it was never set
+    pub fn backend_count(
+        &mut self,
+        cluster_id: &str,
+        backend_id: &str,
+        metric_name: &str,
+    ) -> anyhow::Result<i64> {
+        let metrics = self.query_metrics(QueryMetricsOptions {
+            cluster_ids: vec![cluster_id.to_owned()],
+            backend_ids: vec![backend_id.to_owned()],
+            metric_names: vec![metric_name.to_owned()],
+            ..Default::default()
+        })?;
+        // the metrics of a backend may be spread over several entries
+        Ok(metrics
+            .clusters
+            .get(cluster_id)
+            .map(|cluster| {
+                cluster
+                    .backends
+                    .iter()
+                    .filter(|backend| backend.backend_id == backend_id)
+                    .map(|backend| count(backend.metrics.get(metric_name)))
+                    .sum()
+            })
+            .unwrap_or(0))
+    }
+
+    /// value of a counter of the worker, 0 if it was never incremented
+    pub fn proxy_count(&mut self, metric_name: &str) -> anyhow::Result<i64> {
+        let metrics = self.query_metrics(QueryMetricsOptions {
+            metric_names: vec![metric_name.to_owned()],
+            no_clusters: true,
+            ..Default::default()
+        })?;
+        Ok(count(metrics.proxy.get(metric_name)))
+    }
+
+    /// the events received so far, oldest first
+    pub fn events(&mut self) -> Vec<Event> {
+        while self.read_response(Duration::ZERO).is_ok() {}
+        std::mem::take(&mut self.events)
+    }
+
+    /// wait for an event of this kind, and remove it from the received events
+    pub fn wait_for_event(&mut self, kind: EventKind, timeout: Duration) -> Option<Event> {
+        let start = Instant::now();
+        loop {
+            if let Some(index) = self.events.iter().position(|event| event.kind() == kind) {
+                return Some(self.events.remove(index));
+            }
+            let remaining = timeout.checked_sub(start.elapsed())?;
+            match self.read_response(remaining) {
+                Ok(_) | Err(ChannelError::TimeoutReached(_)) => {}
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// stop the worker right away and wait for its thread to end
+    pub fn stop(mut self) -> anyhow::Result<()> {
+        self.send(RequestType::HardStop(HardStop {}))?;
+        self.join()
+    }
+
+    fn join(&mut self) -> anyhow::Result<()> {
+        if let Some(server_job) = self.server_job.take() {
+            if server_job.join().is_err() {
+                bail!("The thread of worker {} panicked", self.name);
+            }
+            // the worker does not close its end of the scm socket
+            for fd in [self.scm_main_to_worker.fd, self.scm_worker_to_main.fd] {
+                drop(unsafe { UnixStream::from_raw_fd(fd) });
+            }
+        }
+        Ok(())
+    }
+
+    /// read the next message of the worker, keeping it if it is an event
+    fn read_response(&mut self, timeout: Duration) -> Result<WorkerResponse, ChannelError> {
+        let response = self
+            .channel
+            .read_message_blocking_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        if let Some(ContentType::Event(event)) = response
+            .content
+            .as_ref()
+            .and_then(|content| content.content_type.as_ref())
+        {
+            self.events.push(event.to_owned());
+        }
+        Ok(response)
+    }
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        if self.server_job.is_some() {
+            let _ = self.send(RequestType::HardStop(HardStop {}));
+            let _ = self.join();
+        }
+    }
+}
+
+fn count(metric: Option<&FilteredMetrics>) -> i64 {
+    match metric.and_then(|metric| metric.inner.as_ref()) {
+        Some(Inner::Count(count)) => *count,
+        Some(Inner::Gauge(gauge)) => *gauge as i64,
+        _ => 0,
+    }
+}
@@ -477,6 +477,29 @@ impl<T> Timer<T> {
         self.wheel.iter().map(|e| e.next_tick).min()
     }
 
+    /// Number of pending timeouts
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Pending timeouts and their state, to look for timeouts that outlived
+    /// what they refer to
+    pub fn timeouts(&self) -> impl Iterator<Item = (Timeout, &T)> {
+        self.entries.iter().map(|(key, entry)| {
+            (
+                Timeout {
+                    token: Token(key),
+                    tick: entry.links.tick,
+                },
+                &entry.state,
+            )
+        })
+    }
+
     pub fn next_poll_date(&self) -> Option<Instant> {
         self.next_tick()
             .map(|tick| self.start + Duration::from_millis(self.tick_ms.saturating_mul(tick)))
@@ -676,6 +699,31 @@ mod test {
         assert_eq!(0, count(&t));
     }
 
+    #[test]
+    pub fn test_cancelling_listed_timeouts() {
+        let mut t = timer();
+
+        t.set_timeout_at(Duration::from_millis(100), "a");
+        t.set_timeout_at(Duration::from_millis(100), "b");
+        t.set_timeout_at(Duration::from_millis(200), "c");
+        assert_eq!(3, t.len());
+
+        let orphans: Vec<Timeout> = t
+            .timeouts()
+            .filter(|(_, state)| **state != "b")
+            .map(|(timeout, _)| timeout)
+            .collect();
+        for timeout in &orphans {
+            assert!(t.cancel_timeout(timeout).is_some());
+        }
+        assert_eq!(1, t.len());
+
+        let tick = ms_to_tick(&t, 200);
+        assert_eq!(Some("b"), t.poll_to(tick));
+        assert_eq!(None, t.poll_to(tick));
+        assert!(t.is_empty());
+    }
+
     const TICK: u64 = 100;
     const SLOTS: usize = 16;
     const CAPACITY: usize = 32;
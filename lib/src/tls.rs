@@ -19,13 +19,17 @@ use rustls::{
     pki_types::{CertificateDer, PrivateKeyDer},
     server::{ClientHello, ResolvesServerCert},
     sign::CertifiedKey,
+    SignatureScheme,
 };
 use sha2::{Digest, Sha256};
 use sozu_command::{
     certificate::{
-        get_cn_and_san_attributes, parse_pem, parse_x509, CertificateError, Fingerprint,
+        get_certificate_details, get_cn_and_san_attributes, parse_pem, parse_x509,
+        CertificateError, Fingerprint,
+    },
+    proto::command::{
+        AddCertificate, CertificateAndKey, CertificateDetails, ReplaceCertificate, SocketAddress,
     },
-    proto::command::{AddCertificate, CertificateAndKey, ReplaceCertificate, SocketAddress},
 };
 
 use crate::router::trie::{Key, KeyValue, TrieNode};
@@ -56,6 +60,8 @@ pub enum CertificateResolverError {
     InvalidPrivateKey(String),
     #[error("empty key")]
     EmptyKeys,
+    #[error("the certificate chain is empty")]
+    EmptyCertificateChain,
     #[error("error parsing x509 cert from bytes: {0}")]
     ParseX509(CertificateError),
     #[error("error parsing pem formated certificate from bytes: {0}")]
@@ -76,6 +82,25 @@ pub struct CertifiedKeyWrapper {
     fingerprint: Fingerprint,
 }
 
+impl CertifiedKeyWrapper {
+    /// SAN, key type, validity and chain length of the certificate
+    pub fn details(&self) -> Result<CertificateDetails, CertificateResolverError> {
+        let (leaf, chain) = self
+            .inner
+            .cert
+            .split_first()
+            .ok_or(CertificateResolverError::EmptyCertificateChain)?;
+
+        let x509 = parse_x509(leaf).map_err(CertificateResolverError::ParseX509)?;
+
+        Ok(get_certificate_details(
+            &x509,
+            chain.len() as u32,
+            self.names.clone(),
+        ))
+    }
+}
+
 /// Convert an AddCertificate request into the Rustls format.
 /// Support RSA and ECDSA certificates.
 impl TryFrom<&AddCertificate> for CertifiedKeyWrapper {
@@ -309,6 +334,33 @@ impl CertificateResolver {
     ) -> Option<&KeyValue<Key, Fingerprint>> {
         self.domains.domain_lookup(domain, accept_wildcard)
     }
+
+    /// Among all the certificates of a domain name, return the longest lived one
+    /// that can sign with one of the signature schemes offered by the client.
+    ///
+    /// This allows serving an ECDSA and an RSA certificate for the same name,
+    /// old clients get the RSA one.
+    /// Falls back to the longest lived certificate if none is compatible.
+    pub fn resolve_for_schemes(
+        &self,
+        domain: &[u8],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<&CertifiedKeyWrapper> {
+        let (name, longest_lived) = self.domains.domain_lookup(domain, true)?;
+
+        let compatible = std::str::from_utf8(name)
+            .ok()
+            .and_then(|name| self.name_fingerprint_idx.get(name))
+            .and_then(|fingerprints| {
+                fingerprints
+                    .iter()
+                    .rev()
+                    .filter_map(|(fingerprint, _)| self.certificates.get(fingerprint))
+                    .find(|cert| cert.inner.key.choose_scheme(sigschemes).is_some())
+            });
+
+        compatible.or_else(|| self.certificates.get(longest_lived))
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -335,20 +387,13 @@ impl ResolvesServerCert for MutexCertificateResolver {
         );
         if let Ok(ref mut resolver) = self.0.try_lock() {
             //resolver.domains.print();
-            if let Some((_, fingerprint)) = resolver.domains.domain_lookup(name.as_bytes(), true) {
+            if let Some(cert) = resolver.resolve_for_schemes(name.as_bytes(), sigschemes) {
                 trace!(
-                    "looking for certificate for {:?} with fingerprint {:?}",
+                    "found certificate for {:?} with fingerprint {:?}",
                     name,
-                    fingerprint
+                    cert.fingerprint
                 );
-
-                let cert = resolver
-                    .certificates
-                    .get(fingerprint)
-                    .map(|cert| cert.inner.clone());
-
-                trace!("Found for fingerprint {}: {}", fingerprint, cert.is_some());
-                return cert;
+                return Some(cert.inner.clone());
             }
         }
 
@@ -378,6 +423,8 @@ mod tests {
         time::{Duration, SystemTime},
     };
 
+    use rustls::SignatureScheme;
+
     use super::CertificateResolver;
 
     // use rand::{seq::SliceRandom, thread_rng};
@@ -425,6 +472,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn certificate_details() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = SocketAddress::new_v4(127, 0, 0, 1, 8080);
+        let mut resolver = CertificateResolver::default();
+        let certificate_and_key = CertificateAndKey {
+            certificate: String::from(include_str!("../assets/certificate.pem")),
+            certificate_chain: vec![String::from(include_str!(
+                "../assets/certificate_chain.pem"
+            ))],
+            key: String::from(include_str!("../assets/key.pem")),
+            ..Default::default()
+        };
+
+        resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: certificate_and_key,
+            expired_at: None,
+        })?;
+
+        let (_, fingerprint) = resolver
+            .domain_lookup("lolcatho.st".as_bytes(), true)
+            .ok_or("no certificate selected for lolcatho.st")?;
+        let details = resolver
+            .get_certificate(fingerprint)
+            .ok_or("failed to retrieve certificate")?
+            .details()?;
+
+        assert_eq!(details.names, vec!["lolcatho.st".to_string()]);
+        assert_eq!(details.key_type, "RSA 4096 bits");
+        assert_eq!(details.chain_length, 1);
+        assert!(details.not_before < details.not_after);
+        Ok(())
+    }
+
     #[test]
     fn name_override() -> Result<(), Box<dyn Error + Send + Sync>> {
         let address = SocketAddress::new_v4(127, 0, 0, 1, 8080);
@@ -678,4 +759,47 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn resolve_according_to_signature_schemes() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = SocketAddress::new_v4(127, 0, 0, 1, 8080);
+        let mut resolver = CertificateResolver::default();
+
+        // both are issued for lolcatho.st, the ECDSA one lives longer
+        let rsa_fingerprint = resolver.add_certificate(&AddCertificate {
+            address: address.clone(),
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                key: String::from(include_str!("../assets/key.pem")),
+                ..Default::default()
+            },
+            expired_at: None,
+        })?;
+        let ecdsa_fingerprint = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/tests/ecdsa-certificate.pem")),
+                key: String::from(include_str!("../assets/tests/ecdsa-key.pem")),
+                ..Default::default()
+            },
+            expired_at: None,
+        })?;
+
+        let modern_client = [
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::RSA_PSS_SHA256,
+        ];
+        let resolved = resolver
+            .resolve_for_schemes("lolcatho.st".as_bytes(), &modern_client)
+            .ok_or("no certificate for a modern client")?;
+        assert_eq!(resolved.fingerprint, ecdsa_fingerprint);
+
+        let rsa_only_client = [SignatureScheme::RSA_PKCS1_SHA256];
+        let resolved = resolver
+            .resolve_for_schemes("lolcatho.st".as_bytes(), &rsa_only_client)
+            .ok_or("no certificate for an RSA-only client")?;
+        assert_eq!(resolved.fingerprint, rsa_fingerprint);
+
+        Ok(())
+    }
 }